@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use k8s_openapi::api::networking::v1::{
+    HTTPIngressPath, HTTPIngressRuleValue, Ingress, IngressBackend, IngressRule,
+    IngressServiceBackend, IngressSpec, ServiceBackendPort,
+};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    canary::CANARY_NAME,
+    error::{bail, Result},
+    healthz::HEALTHZ_INGRESS_NAME,
+    KwpmClient,
+};
+
+const ADMIN_INGRESS_NAME: &str = "wordpress-admin";
+const ADMIN_AJAX_INGRESS_NAME: &str = "wordpress-admin-ajax";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminAccess {
+    /// CIDRs allowed to reach wp-admin and wp-login.php; empty allows everyone not denied.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+pub fn is_valid_cidr(cidr: &str) -> bool {
+    let (addr, prefix) = cidr.split_once('/').unwrap_or((cidr, ""));
+    let Ok(addr) = addr.parse::<IpAddr>() else {
+        return false;
+    };
+    if prefix.is_empty() {
+        return true;
+    }
+
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    prefix
+        .parse::<u8>()
+        .map(|prefix| prefix <= max_prefix)
+        .unwrap_or(false)
+}
+
+impl AdminAccess {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(cidr) = self
+            .allow
+            .iter()
+            .chain(&self.deny)
+            .find(|c| !is_valid_cidr(c))
+        {
+            bail!("invalid CIDR: {}", cidr);
+        }
+        if self.allow.is_empty() && self.deny.is_empty() {
+            bail!("admin access restriction needs at least one allowed or denied CIDR");
+        }
+
+        Ok(())
+    }
+
+    fn annotations(&self) -> BTreeMap<String, String> {
+        let mut annotations = BTreeMap::new();
+        if !self.allow.is_empty() {
+            annotations.insert(
+                "nginx.ingress.kubernetes.io/whitelist-source-range".to_string(),
+                self.allow.join(","),
+            );
+        }
+        if !self.deny.is_empty() {
+            annotations.insert(
+                "nginx.ingress.kubernetes.io/denylist-source-range".to_string(),
+                self.deny.join(","),
+            );
+        }
+        annotations
+    }
+}
+
+pub(crate) fn ingress(
+    name: &str,
+    hosts: &[String],
+    paths: &[(&str, &str)],
+    template: &IngressSpec,
+    annotations: BTreeMap<String, String>,
+) -> Ingress {
+    let paths: Vec<HTTPIngressPath> = paths
+        .iter()
+        .map(|(path, path_type)| HTTPIngressPath {
+            path: Some(path.to_string()),
+            path_type: path_type.to_string(),
+            backend: IngressBackend {
+                service: Some(IngressServiceBackend {
+                    name: "wordpress".to_string(),
+                    port: Some(ServiceBackendPort {
+                        number: Some(80),
+                        ..Default::default()
+                    }),
+                }),
+                ..Default::default()
+            },
+        })
+        .collect();
+
+    Ingress {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            annotations: Some(annotations),
+            ..Default::default()
+        },
+        spec: Some(IngressSpec {
+            ingress_class_name: template.ingress_class_name.clone(),
+            tls: template.tls.clone(),
+            rules: Some(
+                hosts
+                    .iter()
+                    .map(|host| IngressRule {
+                        host: Some(host.clone()),
+                        http: Some(HTTPIngressRuleValue {
+                            paths: paths.clone(),
+                        }),
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Builds the restricted ingress for the admin paths plus an unrestricted one keeping
+/// admin-ajax.php public, since themes and plugins call it from the frontend.
+pub fn admin_ingresses(site_ingress: &Ingress, access: &AdminAccess) -> Result<Vec<Ingress>> {
+    let template = site_ingress.spec.clone().unwrap_or_default();
+    let hosts: Vec<String> = template
+        .rules
+        .iter()
+        .flatten()
+        .filter_map(|rule| rule.host.clone())
+        .collect();
+    if hosts.is_empty() {
+        bail!("site ingress has no hosts to restrict");
+    }
+
+    Ok(vec![
+        ingress(
+            ADMIN_INGRESS_NAME,
+            &hosts,
+            &[("/wp-admin", "Prefix"), ("/wp-login.php", "Exact")],
+            &template,
+            access.annotations(),
+        ),
+        ingress(
+            ADMIN_AJAX_INGRESS_NAME,
+            &hosts,
+            &[("/wp-admin/admin-ajax.php", "Exact")],
+            &template,
+            BTreeMap::new(),
+        ),
+    ])
+}
+
+pub(crate) fn is_site_ingress(ingress: &Ingress) -> bool {
+    let name = ingress.metadata.name.as_deref().unwrap_or_default();
+    ![
+        ADMIN_INGRESS_NAME,
+        ADMIN_AJAX_INGRESS_NAME,
+        CANARY_NAME,
+        HEALTHZ_INGRESS_NAME,
+    ]
+    .contains(&name)
+}
+
+impl KwpmClient {
+    /// Restricts `/wp-admin` and `/wp-login.php` to the given CIDRs at the ingress, or removes
+    /// the restriction with `None`.
+    pub async fn set_admin_access(&self, site: &str, access: Option<AdminAccess>) -> Result<()> {
+        if let Some(access) = &access {
+            access.validate()?;
+        }
+
+        let ingress_api: Api<Ingress> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let Some(access) = access else {
+            let mut spec = self.get_site_spec(site).await?;
+            spec.admin_access = None;
+            self.save_site_spec(site, &spec).await?;
+
+            for name in [ADMIN_INGRESS_NAME, ADMIN_AJAX_INGRESS_NAME] {
+                if ingress_api.get_opt(name).await?.is_some() {
+                    ingress_api.delete(name, &Default::default()).await?;
+                }
+            }
+            self.record_site_revision(site, "set_admin_access").await?;
+            return Ok(());
+        };
+
+        let ingresses = ingress_api.list(&Default::default()).await?;
+        let Some(site_ingress) = ingresses.items.iter().find(|i| is_site_ingress(i)) else {
+            bail!("{} has no ingress to restrict", site);
+        };
+
+        let ingresses = admin_ingresses(site_ingress, &access)?;
+        self.check_policy(
+            "set_admin_access",
+            Some(site),
+            &ingresses
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+        .await?;
+
+        let mut spec = self.get_site_spec(site).await?;
+        spec.admin_access = Some(access);
+        self.save_site_spec(site, &spec).await?;
+
+        for ingress in ingresses {
+            let name = ingress.metadata.name.clone().unwrap_or_default();
+            ingress_api
+                .patch(
+                    &name,
+                    &PatchParams::apply("kwpm").force(),
+                    &Patch::Apply(&self.labeled(&ingress)),
+                )
+                .await?;
+        }
+
+        self.record_site_revision(site, "set_admin_access").await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_cidr() {
+        assert!(is_valid_cidr("10.0.0.0/8"));
+        assert!(is_valid_cidr("203.0.113.7"));
+        assert!(is_valid_cidr("2001:db8::/32"));
+        assert!(!is_valid_cidr("10.0.0.0/33"));
+        assert!(!is_valid_cidr("example.com"));
+        assert!(!is_valid_cidr("10.0.0.0/8,0.0.0.0/0"));
+    }
+
+    #[test]
+    fn test_admin_ingresses() {
+        let site_ingress: Ingress =
+            serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-ingress.yaml"))
+                .unwrap();
+        let access = AdminAccess {
+            allow: vec!["10.0.0.0/8".to_string(), "203.0.113.7/32".to_string()],
+            deny: vec![],
+        };
+
+        let ingresses = admin_ingresses(&site_ingress, &access).unwrap();
+        assert_eq!(ingresses.len(), 2);
+
+        let admin = &ingresses[0];
+        assert_eq!(
+            admin.metadata.annotations.as_ref().unwrap()
+                ["nginx.ingress.kubernetes.io/whitelist-source-range"],
+            "10.0.0.0/8,203.0.113.7/32"
+        );
+        let rules = admin.spec.as_ref().unwrap().rules.as_ref().unwrap();
+        assert_eq!(rules[0].host.as_deref(), Some("wordpress-test.local"));
+
+        let ajax = &ingresses[1];
+        assert!(ajax.metadata.annotations.as_ref().unwrap().is_empty());
+    }
+}
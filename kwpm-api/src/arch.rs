@@ -0,0 +1,204 @@
+use std::collections::BTreeSet;
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{
+        Affinity, Node, NodeAffinity, NodeSelector, NodeSelectorRequirement, NodeSelectorTerm,
+        PodSpec,
+    },
+};
+use kube::Api;
+use serde_json::Value;
+
+use crate::{
+    error::{bail, Result},
+    manifest::deployment_pod_spec_mut,
+    registry::{ImageReference, Registry, MANIFEST_TYPES},
+    KwpmClient,
+};
+
+const ARCH_LABEL: &str = "kubernetes.io/arch";
+
+/// Reads the architectures of an image index, skipping attestation entries.
+pub fn index_architectures(index: &Value) -> Option<BTreeSet<String>> {
+    let manifests = index.get("manifests")?.as_array()?;
+
+    Some(
+        manifests
+            .iter()
+            .filter_map(|m| m.pointer("/platform/architecture")?.as_str())
+            .filter(|arch| *arch != "unknown")
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+pub async fn image_architectures(image: &str) -> Result<BTreeSet<String>> {
+    let image = ImageReference::parse(image)?;
+    let mut registry = Registry::new(&image.registry, &image.repository);
+
+    let manifest: Value =
+        serde_json::from_slice(&registry.manifest(&image.reference, MANIFEST_TYPES).await?)?;
+    if let Some(architectures) = index_architectures(&manifest) {
+        return Ok(architectures);
+    }
+
+    // Single-platform image, the architecture is only recorded in its config.
+    let config_digest = manifest
+        .pointer("/config/digest")
+        .and_then(Value::as_str)
+        .context("image manifest has no config")?;
+    let config: Value = serde_json::from_slice(&registry.blob(config_digest).await?)?;
+    let architecture = config
+        .get("architecture")
+        .and_then(Value::as_str)
+        .context("image config has no architecture")?;
+
+    Ok([architecture.to_string()].into_iter().collect())
+}
+
+/// Requires the pod to run on one of `architectures`, on top of any node affinity it already has.
+pub fn restrict_to_architectures(pod_spec: &mut PodSpec, architectures: &BTreeSet<String>) {
+    let requirement = NodeSelectorRequirement {
+        key: ARCH_LABEL.to_string(),
+        operator: "In".to_string(),
+        values: Some(architectures.iter().cloned().collect()),
+    };
+
+    let required = pod_spec
+        .affinity
+        .get_or_insert_with(Affinity::default)
+        .node_affinity
+        .get_or_insert_with(NodeAffinity::default)
+        .required_during_scheduling_ignored_during_execution
+        .get_or_insert_with(NodeSelector::default);
+    if required.node_selector_terms.is_empty() {
+        required
+            .node_selector_terms
+            .push(NodeSelectorTerm::default());
+    }
+
+    for term in &mut required.node_selector_terms {
+        let expressions = term.match_expressions.get_or_insert_with(Vec::new);
+        expressions.retain(|e| e.key != ARCH_LABEL);
+        expressions.push(requirement.clone());
+    }
+}
+
+impl KwpmClient {
+    pub async fn node_architectures(&self) -> Result<BTreeSet<String>> {
+        let node_api: Api<Node> = Api::all(self.client.clone());
+
+        Ok(node_api
+            .list(&Default::default())
+            .await?
+            .items
+            .into_iter()
+            .filter_map(|node| node.metadata.labels?.remove(ARCH_LABEL))
+            .collect())
+    }
+
+    /// Restricts the pod to node architectures every one of its images is published for. Images
+    /// whose platforms can't be looked up, e.g. in air-gapped clusters, are assumed to fit.
+    pub async fn place_by_architecture(&self, pod_spec: &mut PodSpec) -> Result<()> {
+        let nodes = self.node_architectures().await?;
+        let mut compatible = nodes.clone();
+
+        let images: BTreeSet<String> = pod_spec
+            .containers
+            .iter()
+            .chain(pod_spec.init_containers.iter().flatten())
+            .filter_map(|c| c.image.clone())
+            .collect();
+        for image in &images {
+            if let Ok(architectures) = image_architectures(image).await {
+                compatible.retain(|arch| architectures.contains(arch));
+                if compatible.is_empty() {
+                    bail!(
+                        "no node can run {}: it is published for {:?}, the cluster has {:?} nodes",
+                        image,
+                        architectures,
+                        nodes
+                    );
+                }
+            }
+        }
+
+        if compatible != nodes {
+            restrict_to_architectures(pod_spec, &compatible);
+        }
+
+        Ok(())
+    }
+
+    pub async fn place_site_by_architecture(&self, site: &str) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let mut deployment = deployment_api.get("wordpress").await?;
+        self.place_by_architecture(deployment_pod_spec_mut(&mut deployment)?)
+            .await?;
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_index_architectures() {
+        let index = json!({
+            "manifests": [
+                { "platform": { "architecture": "amd64", "os": "linux" } },
+                { "platform": { "architecture": "arm64", "os": "linux", "variant": "v8" } },
+                { "platform": { "architecture": "unknown", "os": "unknown" } },
+            ]
+        });
+
+        assert_eq!(
+            index_architectures(&index).unwrap(),
+            ["amd64", "arm64"].map(str::to_string).into()
+        );
+        assert_eq!(index_architectures(&json!({ "config": {} })), None);
+    }
+
+    #[test]
+    fn test_restrict_to_architectures() {
+        let mut deployment: Deployment = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap();
+        let pod_spec = deployment_pod_spec_mut(&mut deployment).unwrap();
+        let arm64: BTreeSet<String> = ["arm64".to_string()].into();
+
+        restrict_to_architectures(pod_spec, &arm64);
+        restrict_to_architectures(pod_spec, &arm64);
+
+        let terms = &pod_spec
+            .affinity
+            .as_ref()
+            .unwrap()
+            .node_affinity
+            .as_ref()
+            .unwrap()
+            .required_during_scheduling_ignored_during_execution
+            .as_ref()
+            .unwrap()
+            .node_selector_terms;
+        assert_eq!(terms.len(), 1);
+        let expressions = terms[0].match_expressions.as_ref().unwrap();
+        assert_eq!(expressions.len(), 1);
+        assert_eq!(
+            expressions[0].values.as_deref(),
+            Some(&["arm64".to_string()][..])
+        );
+    }
+}
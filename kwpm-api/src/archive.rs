@@ -0,0 +1,470 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{
+    batch::v1::Job,
+    core::v1::{Namespace, PersistentVolume, PersistentVolumeClaim, Secret},
+};
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind, ObjectMeta},
+    Api,
+};
+use object_store::{path::Path, ObjectStore};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    backup::{backup_job, restore_site_job, BackupMode, BackupStorage},
+    confirm::DestructiveOperation,
+    database_job,
+    error::{bail, Result},
+    gitops::strip_server_fields,
+    job::is_job_succeeded,
+    manifest::{is_local_volume, job_pod_spec_mut},
+    mariadb::{mariadb_namespace, namespace_mariadb_instance},
+    metadata::ensure_managed,
+    provision::SITE_LABEL,
+    secret_value, DatabaseAction, KwpmClient,
+};
+
+const ARCHIVE_MARKER: &str = "archived.json";
+const ARCHIVE_RESOURCES: &str = "resources.json";
+const ARCHIVE_JOB_TIMEOUT: Duration = Duration::from_secs(3600);
+const UNARCHIVE_READY_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    /// S3 storage class of the archived backup. Objects in `GLACIER` or `DEEP_ARCHIVE` have to
+    /// be restored with S3 before `unarchive_site` can read them; `GLACIER_IR` can be read
+    /// immediately.
+    pub storage_class: String,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self {
+            storage_class: "GLACIER_IR".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteArchive {
+    pub site: String,
+    pub backup_id: String,
+    pub storage_class: String,
+    pub url: String,
+    pub archived_at: DateTime<Utc>,
+}
+
+/// Everything besides the backup needed to recreate the site.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchivedResources {
+    namespace: Namespace,
+    persistent_volume: Option<PersistentVolume>,
+    database_secret: Secret,
+    manifests: Vec<Value>,
+}
+
+pub fn archive_id(archived_at: DateTime<Utc>) -> String {
+    format!("archive-{}", archived_at.format("%Y%m%d-%H%M%S"))
+}
+
+/// Full backup job uploading into `storage_class` instead of the bucket's default class.
+pub fn archive_job(
+    site: &str,
+    backup_id: &str,
+    storage: &BackupStorage,
+    storage_class: &str,
+) -> Result<Job> {
+    if storage_class.is_empty()
+        || !storage_class
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c == '_')
+    {
+        bail!("invalid storage class: {}", storage_class);
+    }
+
+    let mut job = backup_job(site, backup_id, BackupMode::Full, storage)?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("app".to_string(), "kwpm-archive".to_string());
+
+    let upload = job_pod_spec_mut(&mut job)?
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "upload")
+        .context("backup job has no upload container")?;
+    upload
+        .args
+        .get_or_insert_with(Vec::new)
+        .extend(["--storage-class".to_string(), storage_class.to_string()]);
+
+    Ok(job)
+}
+
+/// Order in which archived manifests are recreated: volumes before the workloads mounting them.
+fn restore_rank(manifest: &Value) -> usize {
+    match manifest["kind"].as_str() {
+        Some("PersistentVolumeClaim") => 0,
+        Some("ConfigMap") => 1,
+        Some("Service") => 2,
+        Some("Deployment") => 3,
+        Some("Ingress") => 4,
+        _ => 5,
+    }
+}
+
+pub(crate) fn api_resource(manifest: &Value) -> Result<ApiResource> {
+    let api_version = manifest["apiVersion"]
+        .as_str()
+        .context("manifest has no apiVersion")?;
+    let kind = manifest["kind"].as_str().context("manifest has no kind")?;
+    let (group, version) = api_version.split_once('/').unwrap_or(("", api_version));
+
+    Ok(ApiResource::from_gvk(&GroupVersionKind::gvk(
+        group, version, kind,
+    )))
+}
+
+pub(crate) async fn read_json<T: serde::de::DeserializeOwned>(
+    store: &impl ObjectStore,
+    path: &Path,
+) -> Result<Option<T>> {
+    match store.get(path).await {
+        Ok(object) => Ok(Some(serde_json::from_slice(&object.bytes().await?)?)),
+        Err(object_store::Error::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl KwpmClient {
+    /// Where the archive of a site is recorded while it is archived.
+    pub async fn get_site_archive(&self, site: &str) -> Result<Option<SiteArchive>> {
+        let storage = self.backup_storage()?;
+        read_json(
+            &storage.object_store()?,
+            &storage.backup_path(site, ARCHIVE_MARKER),
+        )
+        .await
+    }
+
+    /// Moves a dormant site into cold storage: takes a full backup in `options.storage_class`
+    /// together with the site's resources, then deletes its files, database, namespace and
+    /// volume. `confirmation` is the token of planning [`DestructiveOperation::ArchiveSite`].
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn archive_site(
+        &self,
+        site: &str,
+        options: &ArchiveOptions,
+        confirmation: &str,
+    ) -> Result<SiteArchive> {
+        self.ensure_writable("archive_site")?;
+        if self.get_site_archive(site).await?.is_some() {
+            bail!("{} is already archived", site);
+        }
+        self.confirm(
+            &DestructiveOperation::ArchiveSite {
+                site: site.to_string(),
+            },
+            confirmation,
+        )
+        .await?;
+        let lock = self.lock_site(site, "archive_site").await?;
+        let result: Result<_> = async {
+            let ns_name = self.site_namespace(site);
+            let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+            let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+            let pvc_api: Api<PersistentVolumeClaim> =
+                Api::namespaced(self.client.clone(), &ns_name);
+            let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+
+            let live_namespace = namespace_api.get(&ns_name).await?;
+            ensure_managed(&live_namespace, Some((SITE_LABEL, site)))?;
+            let namespace = Namespace {
+                metadata: ObjectMeta {
+                    name: live_namespace.metadata.name.clone(),
+                    labels: live_namespace.metadata.labels.clone(),
+                    annotations: live_namespace.metadata.annotations.clone(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            let mut database_secret = secret_api.get("mysql-pass").await?;
+            database_secret.metadata = ObjectMeta {
+                name: database_secret.metadata.name.clone(),
+                ..Default::default()
+            };
+
+            let pv_name = pvc_api
+                .get_opt("wp-pv-claim")
+                .await?
+                .and_then(|pvc| pvc.spec.and_then(|spec| spec.volume_name));
+            let persistent_volume = match &pv_name {
+                // Dynamically provisioned volumes are deleted with their claim, so only local ones
+                // are kept to restore the site's files to.
+                Some(name) => pv_api
+                    .get_opt(name)
+                    .await?
+                    .filter(is_local_volume)
+                    .map(|mut pv| {
+                        pv.metadata = ObjectMeta {
+                            name: pv.metadata.name.clone(),
+                            labels: pv.metadata.labels.clone(),
+                            ..Default::default()
+                        };
+                        if let Some(spec) = pv.spec.as_mut() {
+                            spec.claim_ref = None;
+                        }
+                        pv.status = None;
+                        pv
+                    }),
+                None => None,
+            };
+            if let Some(pv) = &persistent_volume {
+                ensure_managed(pv, Some((SITE_LABEL, site)))?;
+            }
+
+            let mut manifests = self.render_site_manifests(site).await?;
+            for manifest in &mut manifests {
+                strip_server_fields(manifest);
+                if let Some(metadata) = manifest["metadata"].as_object_mut() {
+                    metadata.remove("namespace");
+                }
+            }
+
+            self.check_policy("archive_site", Some(site), &manifests)
+                .await?;
+
+            let storage = self.backup_storage()?;
+            let store = storage.object_store()?;
+            let archived_at = Utc::now();
+            let backup_id = archive_id(archived_at);
+
+            let job = archive_job(site, &backup_id, storage, &options.storage_class)?;
+            let job = self.create_backup_job(site, &job).await?;
+            let job = self
+                .wait_for_job(
+                    &ns_name,
+                    &job.metadata.name.unwrap_or_default(),
+                    ARCHIVE_JOB_TIMEOUT,
+                )
+                .await?;
+            if !is_job_succeeded(&job) {
+                bail!(
+                    "archive backup of {} failed, leaving the site in place",
+                    site
+                );
+            }
+
+            let resources = ArchivedResources {
+                namespace,
+                persistent_volume,
+                database_secret: database_secret.clone(),
+                manifests,
+            };
+            store
+                .put(
+                    &storage
+                        .backup_path(site, &backup_id)
+                        .child(ARCHIVE_RESOURCES),
+                    serde_json::to_vec(&resources)?.into(),
+                )
+                .await?;
+
+            let archive = SiteArchive {
+                site: site.to_string(),
+                url: storage.backup_url(site, &backup_id),
+                backup_id,
+                storage_class: options.storage_class.clone(),
+                archived_at,
+            };
+            store
+                .put(
+                    &storage.backup_path(site, ARCHIVE_MARKER),
+                    serde_json::to_vec(&archive)?.into(),
+                )
+                .await?;
+
+            lock.ensure_held()?;
+            let wipe: Job =
+                serde_yaml::from_str(include_str!("../../kubernetes/backup/wipe-site-job.yaml"))?;
+            self.run_job(&ns_name, wipe, ARCHIVE_JOB_TIMEOUT).await?;
+            self.run_job(
+                &mariadb_namespace(&namespace_mariadb_instance(&live_namespace)),
+                database_job(
+                    DatabaseAction::Drop,
+                    &secret_value(&database_secret, "db_name")?,
+                    &secret_value(&database_secret, "user")?,
+                )?,
+                ARCHIVE_JOB_TIMEOUT,
+            )
+            .await?;
+
+            namespace_api.delete(&ns_name, &Default::default()).await?;
+            if let Some(pv_name) = pv_name {
+                pv_api.delete(&pv_name, &Default::default()).await?;
+            }
+
+            Ok(archive)
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Recreates an archived site from its archive and removes the archive marker.
+    pub async fn unarchive_site(&self, site: &str) -> Result<()> {
+        self.ensure_writable("unarchive_site")?;
+        let archive = self
+            .get_site_archive(site)
+            .await?
+            .with_context(|| format!("{} is not archived", site))?;
+
+        let storage = self.backup_storage()?;
+        let store = storage.object_store()?;
+        let resources: ArchivedResources = read_json(
+            &store,
+            &storage
+                .backup_path(site, &archive.backup_id)
+                .child(ARCHIVE_RESOURCES),
+        )
+        .await?
+        .with_context(|| format!("archive {} has no resources", archive.backup_id))?;
+
+        let ns_name = self.site_namespace(site);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+
+        let mut namespace = resources.namespace;
+        self.cluster_version.adapt_namespace(&mut namespace);
+        namespace_api
+            .create(&Default::default(), &self.labeled(&namespace))
+            .await?;
+        if let Some(pv) = &resources.persistent_volume {
+            pv_api
+                .create(&Default::default(), &self.labeled(pv))
+                .await?;
+        }
+        secret_api
+            .create(
+                &Default::default(),
+                &self.labeled(&resources.database_secret),
+            )
+            .await?;
+        self.run_job(
+            &mariadb_namespace(&namespace_mariadb_instance(&namespace)),
+            database_job(
+                DatabaseAction::Create,
+                &secret_value(&resources.database_secret, "db_name")?,
+                &secret_value(&resources.database_secret, "user")?,
+            )?,
+            ARCHIVE_JOB_TIMEOUT,
+        )
+        .await?;
+
+        let mut manifests = resources.manifests;
+        manifests.sort_by_key(restore_rank);
+        let (volumes, workloads): (Vec<_>, Vec<_>) = manifests
+            .into_iter()
+            .partition(|m| m["kind"] == "PersistentVolumeClaim");
+
+        for manifest in volumes {
+            self.create_manifest(&ns_name, manifest).await?;
+        }
+
+        let restore = restore_site_job(site, &archive.backup_id, storage)?;
+        let restore = self.create_backup_job(site, &restore).await?;
+        let restore = self
+            .wait_for_job(
+                &ns_name,
+                &restore.metadata.name.unwrap_or_default(),
+                ARCHIVE_JOB_TIMEOUT,
+            )
+            .await?;
+        if !is_job_succeeded(&restore) {
+            bail!("restoring archive {} of {} failed", archive.backup_id, site);
+        }
+
+        for manifest in workloads {
+            self.create_manifest(&ns_name, manifest).await?;
+        }
+        self.wait_for_deployment(&ns_name, "wordpress", UNARCHIVE_READY_TIMEOUT)
+            .await?;
+
+        store
+            .delete(&storage.backup_path(site, ARCHIVE_MARKER))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_manifest(&self, namespace: &str, manifest: Value) -> Result<()> {
+        let resource = api_resource(&manifest)?;
+        let object: DynamicObject = serde_json::from_value(manifest)?;
+        let api: Api<DynamicObject> =
+            Api::namespaced_with(self.client.clone(), namespace, &resource);
+        api.create(&Default::default(), &self.labeled(&object))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn storage() -> BackupStorage {
+        BackupStorage {
+            endpoint: "http://minio:9000".to_string(),
+            region: "us-east-1".to_string(),
+            bucket: "backups".to_string(),
+            prefix: "kwpm".to_string(),
+            access_key_id: "key".to_string(),
+            secret_access_key: "secret".to_string(),
+            restic_password: None,
+        }
+    }
+
+    #[test]
+    fn test_archive_id() {
+        let archived_at = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(archive_id(archived_at), "archive-20240102-030405");
+    }
+
+    #[test]
+    fn test_archive_job_uses_storage_class() {
+        let job = archive_job("blog", "archive-20240102-030405", &storage(), "GLACIER_IR").unwrap();
+
+        let upload = &job.spec.unwrap().template.spec.unwrap().containers[0];
+        let args = upload.args.as_ref().unwrap();
+        assert_eq!(args[args.len() - 2..], ["--storage-class", "GLACIER_IR"]);
+
+        assert!(archive_job("blog", "archive", &storage(), "glacier; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn test_restore_order_and_api_resource() {
+        let mut manifests = [
+            json!({ "apiVersion": "networking.k8s.io/v1", "kind": "Ingress" }),
+            json!({ "apiVersion": "apps/v1", "kind": "Deployment" }),
+            json!({ "apiVersion": "v1", "kind": "PersistentVolumeClaim" }),
+        ];
+        manifests.sort_by_key(restore_rank);
+        assert_eq!(manifests[0]["kind"], "PersistentVolumeClaim");
+        assert_eq!(manifests[2]["kind"], "Ingress");
+
+        let ingress = api_resource(&manifests[2]).unwrap();
+        assert_eq!(ingress.group, "networking.k8s.io");
+        assert_eq!(ingress.plural, "ingresses");
+        assert_eq!(api_resource(&manifests[0]).unwrap().group, "");
+    }
+}
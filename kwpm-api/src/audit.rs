@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{api::ObjectMeta, Api};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, KwpmClient};
+
+pub(crate) const AUDIT_CONFIG_MAP: &str = "kwpm-audit";
+const AUDIT_KEY: &str = "audit.jsonl";
+/// Oldest entries are dropped beyond this, keeping the config map well below its 1MiB limit.
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// An operation kwpm performed on a site and why.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub action: String,
+    pub message: String,
+}
+
+pub fn parse_audit_log(log: &str) -> Vec<AuditEntry> {
+    log.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Appends `entry` to a JSON lines audit log, dropping the oldest entries beyond the limit.
+pub fn append_audit_entry(log: &str, entry: &AuditEntry) -> Result<String> {
+    let mut lines: Vec<&str> = log.lines().filter(|line| !line.is_empty()).collect();
+    let entry = serde_json::to_string(entry)?;
+    lines.push(&entry);
+
+    let skip = lines.len().saturating_sub(MAX_AUDIT_ENTRIES);
+    Ok(lines[skip..]
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect())
+}
+
+impl KwpmClient {
+    pub(crate) async fn record_audit(&self, site: &str, action: &str, message: &str) -> Result<()> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        let entry = AuditEntry {
+            time: Utc::now(),
+            action: action.to_string(),
+            message: message.to_string(),
+        };
+        tracing::info!(site, action, message, "audit");
+
+        // Replacing with the read resourceVersion fails on concurrent writers, so retry.
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let result = match config_map_api.get_opt(AUDIT_CONFIG_MAP).await? {
+                Some(mut config_map) => {
+                    let data = config_map.data.get_or_insert_with(Default::default);
+                    let log = data.get(AUDIT_KEY).map(String::as_str).unwrap_or_default();
+                    let log = append_audit_entry(log, &entry)?;
+                    data.insert(AUDIT_KEY.to_string(), log);
+                    config_map_api
+                        .replace(AUDIT_CONFIG_MAP, &Default::default(), &config_map)
+                        .await
+                }
+                None => {
+                    let config_map = ConfigMap {
+                        metadata: ObjectMeta {
+                            name: Some(AUDIT_CONFIG_MAP.to_string()),
+                            ..Default::default()
+                        },
+                        data: Some(BTreeMap::from([(
+                            AUDIT_KEY.to_string(),
+                            append_audit_entry("", &entry)?,
+                        )])),
+                        ..Default::default()
+                    };
+                    config_map_api
+                        .create(&Default::default(), &self.labeled(&config_map))
+                        .await
+                }
+            };
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(kube::Error::Api(e)) if e.code == 409 && attempts < 5 => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// The operations kwpm recorded for a site, oldest first.
+    pub async fn get_audit_log(&self, site: &str) -> Result<Vec<AuditEntry>> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        Ok(config_map_api
+            .get_opt(AUDIT_CONFIG_MAP)
+            .await?
+            .and_then(|config_map| config_map.data)
+            .and_then(|data| data.get(AUDIT_KEY).map(|log| parse_audit_log(log)))
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn entry(second: u32) -> AuditEntry {
+        AuditEntry {
+            time: Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, second).unwrap(),
+            action: "restart".to_string(),
+            message: "php.ini changed".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_append_audit_entry() {
+        let log = append_audit_entry("", &entry(0)).unwrap();
+        let log = append_audit_entry(&log, &entry(1)).unwrap();
+
+        assert_eq!(parse_audit_log(&log), vec![entry(0), entry(1)]);
+    }
+
+    #[test]
+    fn test_append_audit_entry_drops_oldest() {
+        let mut log = String::new();
+        for _ in 0..MAX_AUDIT_ENTRIES {
+            log = append_audit_entry(&log, &entry(0)).unwrap();
+        }
+        let log = append_audit_entry(&log, &entry(1)).unwrap();
+        let entries = parse_audit_log(&log);
+
+        assert_eq!(entries.len(), MAX_AUDIT_ENTRIES);
+        assert_eq!(entries.last(), Some(&entry(1)));
+    }
+}
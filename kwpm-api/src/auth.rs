@@ -0,0 +1,160 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http::{request::Parts, Request, Response, StatusCode};
+use hyper::{body::Bytes, Body};
+use tokio::sync::RwLock;
+use tower::{BoxError, Layer, Service};
+
+use crate::{
+    error::Result,
+    metrics::observe_kube_request,
+    ratelimit::{KubeRateLimit, RateLimitLayer},
+};
+
+/// The client built from the kubeconfig, with how often it was rebuilt.
+struct Credentials {
+    generation: u64,
+    client: kube::Client,
+}
+
+/// Sends requests with a client built from the inferred config and, when the API server
+/// rejects its credentials, builds a new one and retries once. Rebuilding runs exec
+/// credential plugins again and re-reads the kubeconfig and token files, so expired static
+/// tokens, certificates from plugins and plugin tokens without an expiry are replaced, using
+/// kwpm's own identity rather than impersonation.
+#[derive(Clone)]
+pub(crate) struct Reauthenticate {
+    credentials: Arc<RwLock<Credentials>>,
+}
+
+impl Reauthenticate {
+    fn new(client: kube::Client) -> Self {
+        Self {
+            credentials: Arc::new(RwLock::new(Credentials {
+                generation: 0,
+                client,
+            })),
+        }
+    }
+
+    /// A client that isn't older than `generation`, rebuilding it unless another request did
+    /// so in the meantime.
+    async fn refreshed(&self, generation: u64) -> Result<kube::Client, BoxError> {
+        let mut credentials = self.credentials.write().await;
+        if credentials.generation == generation {
+            credentials.client = kube::Client::try_from(kube::Config::infer().await?)?;
+            credentials.generation += 1;
+        }
+        Ok(credentials.client.clone())
+    }
+}
+
+impl Reauthenticate {
+    async fn send(&self, parts: &Parts, body: Bytes) -> Result<Response<Body>, BoxError> {
+        let (generation, client) = {
+            let credentials = self.credentials.read().await;
+            (credentials.generation, credentials.client.clone())
+        };
+        let response = client.send(rebuild_request(parts, body.clone())).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let client = self.refreshed(generation).await?;
+        Ok(client.send(rebuild_request(parts, body)).await?)
+    }
+}
+
+/// A copy of a request whose body has been read into `body`, for sending it again.
+pub(crate) fn rebuild_request(parts: &Parts, body: Bytes) -> Request<Body> {
+    let mut request = Request::new(Body::from(body));
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    request
+}
+
+impl Service<Request<Body>> for Reauthenticate {
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+
+            let start = Instant::now();
+            let response = this.send(&parts, body).await;
+            let code = match &response {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            tracing::debug!(
+                verb = %parts.method,
+                path = parts.uri.path(),
+                code = %code,
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "kube request"
+            );
+            observe_kube_request(parts.method.as_str(), &code, start.elapsed());
+            response
+        })
+    }
+}
+
+/// A client for the cluster of the inferred config that renews its credentials when they
+/// expire, throttled to `limit` if given.
+pub(crate) async fn refreshing_client(limit: Option<KubeRateLimit>) -> Result<kube::Client> {
+    let config = kube::Config::infer().await?;
+    let default_namespace = config.default_namespace.clone();
+    let service = Reauthenticate::new(kube::Client::try_from(config)?);
+
+    Ok(match limit {
+        Some(limit) => {
+            kube::Client::new(RateLimitLayer::new(limit).layer(service), default_namespace)
+        }
+        None => kube::Client::new(service, default_namespace),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header, Method};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rebuild_request() {
+        let request = Request::patch("/api/v1/namespaces/kwpm-blog")
+            .header(header::CONTENT_TYPE, "application/merge-patch+json")
+            .body(Body::from(r#"{"metadata":{}}"#))
+            .unwrap();
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+
+        for _ in 0..2 {
+            let request = rebuild_request(&parts, body.clone());
+            assert_eq!(request.method(), Method::PATCH);
+            assert_eq!(request.uri().path(), "/api/v1/namespaces/kwpm-blog");
+            assert_eq!(
+                request.headers()[header::CONTENT_TYPE],
+                "application/merge-patch+json"
+            );
+            let sent = hyper::body::to_bytes(request.into_body()).await.unwrap();
+            assert_eq!(sent, r#"{"metadata":{}}"#);
+        }
+    }
+}
@@ -0,0 +1,1126 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use k8s_openapi::api::{
+    batch::v1::{CronJob, CronJobSpec, Job, JobTemplateSpec},
+    core::v1::{Container, Secret},
+};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use object_store::{aws::AmazonS3Builder, path::Path, ObjectStore};
+use serde::Serialize;
+
+use crate::{
+    confirm::DestructiveOperation,
+    error::{bail, KwpmError, Result},
+    manifest::{cron_job_pod_spec_mut, job_pod_spec_mut, set_env},
+    KwpmClient,
+};
+
+const BACKUP_SECRET_NAME: &str = "kwpm-backup-s3";
+const BACKUP_SCHEDULE_NAME: &str = "kwpm-backup-schedule";
+const BACKUP_ID_FORMAT: &str = "%Y%m%d-%H%M%S";
+const BACKUP_ID_LEN: usize = "20240101-000000".len();
+const DEFAULT_TABLE_PREFIX: &str = "wp_";
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(1800);
+
+#[derive(Clone)]
+pub struct BackupStorage {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub restic_password: Option<String>,
+}
+
+impl std::fmt::Debug for BackupStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackupStorage")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"***")
+            .field(
+                "restic_password",
+                &self.restic_password.as_ref().map(|_| "***"),
+            )
+            .finish()
+    }
+}
+
+impl BackupStorage {
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).with_context(|| format!("{} is not set", name));
+
+        Ok(Self {
+            endpoint: var("KWPM_BACKUP_S3_ENDPOINT")?,
+            region: var("KWPM_BACKUP_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            bucket: var("KWPM_BACKUP_S3_BUCKET")?,
+            prefix: var("KWPM_BACKUP_S3_PREFIX").unwrap_or_else(|_| "kwpm".to_string()),
+            access_key_id: var("KWPM_BACKUP_S3_ACCESS_KEY_ID")?,
+            secret_access_key: var("KWPM_BACKUP_S3_SECRET_ACCESS_KEY")?,
+            restic_password: var("KWPM_BACKUP_RESTIC_PASSWORD").ok(),
+        })
+    }
+
+    /// The directory holding all backups of `site`.
+    pub fn site_url(&self, site: &str) -> String {
+        format!(
+            "s3://{}/{}/{}/",
+            self.bucket,
+            self.prefix.trim_matches('/'),
+            site
+        )
+    }
+
+    pub fn backup_url(&self, site: &str, backup_id: &str) -> String {
+        format!("{}{}/", self.site_url(site), backup_id)
+    }
+
+    pub fn backup_path(&self, site: &str, backup_id: &str) -> Path {
+        Path::from(format!(
+            "{}/{}/{}",
+            self.prefix.trim_matches('/'),
+            site,
+            backup_id
+        ))
+    }
+
+    pub fn object_store(&self) -> Result<impl ObjectStore> {
+        Ok(AmazonS3Builder::new()
+            .with_endpoint(&self.endpoint)
+            .with_region(&self.region)
+            .with_bucket_name(&self.bucket)
+            .with_access_key_id(&self.access_key_id)
+            .with_secret_access_key(&self.secret_access_key)
+            .with_allow_http(true)
+            .build()?)
+    }
+
+    pub fn restic_repository(&self, site: &str) -> String {
+        format!(
+            "s3:{}/{}/{}/{}/restic",
+            self.endpoint.trim_end_matches('/'),
+            self.bucket,
+            self.prefix.trim_matches('/'),
+            site
+        )
+    }
+
+    fn secret(&self) -> Secret {
+        let mut string_data: BTreeMap<String, String> = [
+            ("AWS_ENDPOINT_URL", &self.endpoint),
+            ("AWS_DEFAULT_REGION", &self.region),
+            ("AWS_ACCESS_KEY_ID", &self.access_key_id),
+            ("AWS_SECRET_ACCESS_KEY", &self.secret_access_key),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        if let Some(restic_password) = &self.restic_password {
+            string_data.insert("RESTIC_PASSWORD".to_string(), restic_password.clone());
+        }
+
+        Secret {
+            metadata: ObjectMeta {
+                name: Some(BACKUP_SECRET_NAME.to_string()),
+                ..Default::default()
+            },
+            string_data: Some(string_data),
+            ..Default::default()
+        }
+    }
+}
+
+/// `Full` archives wp-content as a tarball on every run, `Incremental` snapshots it into a
+/// per-site restic repository so unchanged uploads are only stored once. `Files` archives
+/// wp-content like `Full` but skips the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupMode {
+    Full,
+    Incremental,
+    Files,
+}
+
+impl BackupMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackupMode::Full => "full",
+            BackupMode::Incremental => "incremental",
+            BackupMode::Files => "files",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupContents {
+    pub tables: Vec<String>,
+    pub files: Vec<String>,
+    /// The `$table_prefix` the site had when it was backed up.
+    pub table_prefix: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RestoreJob {
+    pub site: String,
+    pub backup_id: String,
+    pub job_name: String,
+}
+
+/// A backup found in the backup storage, named after the time it was taken.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredBackup {
+    pub id: String,
+    pub taken_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Backup {
+    pub site: String,
+    pub id: String,
+    pub mode: BackupMode,
+    pub url: String,
+    pub job_name: String,
+}
+
+/// The time followed by a random suffix, e.g. `20240101-030000-3fa9c2`, so backups started
+/// in the same second don't share a job or a directory.
+pub fn new_backup_id() -> Result<String> {
+    let mut suffix = [0u8; 3];
+    getrandom::getrandom(&mut suffix)?;
+    Ok(format!(
+        "{}-{}",
+        Utc::now().format(BACKUP_ID_FORMAT),
+        suffix
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    ))
+}
+
+/// The time a backup was taken at, for ids with and without the random suffix. Scheduled
+/// backups are named by their CronJob and have none.
+fn backup_time(backup_id: &str) -> Option<DateTime<Utc>> {
+    let (time, suffix) = backup_id.split_at_checked(BACKUP_ID_LEN)?;
+    let suffix_is_valid = suffix.is_empty()
+        || suffix.strip_prefix('-').is_some_and(|suffix| {
+            !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_hexdigit())
+        });
+    if !suffix_is_valid {
+        return None;
+    }
+    NaiveDateTime::parse_from_str(time, BACKUP_ID_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Backup ids become object keys and label values: path components of letters, digits, `-`
+/// and `_`, e.g. a backup id or `snapshots/<snapshot>`.
+fn is_valid_backup_id(backup_id: &str) -> bool {
+    backup_id.len() <= 63
+        && backup_id.split('/').all(|component| {
+            !component.is_empty()
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn check_backup_id(backup_id: &str) -> Result<()> {
+    if !is_valid_backup_id(backup_id) {
+        return Err(KwpmError::invalid_input(format!(
+            "invalid backup id: {}",
+            backup_id
+        )));
+    }
+    Ok(())
+}
+
+/// The backups among the entries of a site's backup directory, newest first. Snapshots and
+/// the restic repository live next to them and are skipped.
+pub fn stored_backups(names: &[String]) -> Vec<StoredBackup> {
+    let mut backups: Vec<_> = names
+        .iter()
+        .filter_map(|name| {
+            backup_time(name).map(|taken_at| StoredBackup {
+                id: name.clone(),
+                taken_at,
+            })
+        })
+        .collect();
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.taken_at));
+    backups
+}
+
+fn labels(site: &str, backup_id: &str) -> BTreeMap<String, String> {
+    [
+        ("kwpm.io/site".to_string(), site.to_string()),
+        ("kwpm.io/backup-id".to_string(), backup_id.to_string()),
+    ]
+    .into_iter()
+    .collect()
+}
+
+fn is_valid_table_name(table: &str) -> bool {
+    !table.is_empty()
+        && table.len() <= 64
+        && table
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+fn is_valid_restore_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && path
+            .split('/')
+            .all(|component| !component.is_empty() && component != "." && component != "..")
+}
+
+pub fn backup_job(
+    site: &str,
+    backup_id: &str,
+    mode: BackupMode,
+    storage: &BackupStorage,
+) -> Result<Job> {
+    let mut job: Job =
+        serde_yaml::from_str(include_str!("../../kubernetes/backup/backup-job.yaml"))?;
+
+    let mut labels = labels(site, backup_id);
+    labels.insert("app".to_string(), "kwpm-backup".to_string());
+    labels.insert("kwpm.io/backup-mode".to_string(), mode.as_str().to_string());
+
+    job.metadata.name = Some(format!("kwpm-backup-{}", backup_id));
+    job.metadata.labels = Some(labels);
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+
+    if mode == BackupMode::Files {
+        if let Some(init_containers) = pod_spec.init_containers.as_mut() {
+            init_containers.retain(|c| c.name != "dump-database");
+        }
+    }
+    if mode == BackupMode::Incremental {
+        if storage.restic_password.is_none() {
+            bail!("incremental backups require a restic password");
+        }
+
+        let snapshot: Container = serde_yaml::from_str(include_str!(
+            "../../kubernetes/backup/backup-restic-container.yaml"
+        ))?;
+
+        let init_containers = pod_spec.init_containers.get_or_insert_with(Vec::new);
+        init_containers.retain(|c| c.name != "archive-wp-content");
+        init_containers.push(snapshot);
+
+        set_env(
+            pod_spec,
+            "snapshot-wp-content",
+            &[
+                ("SITE", site.to_string()),
+                ("BACKUP_ID", backup_id.to_string()),
+                ("RESTIC_REPOSITORY", storage.restic_repository(site)),
+            ],
+        )?;
+    }
+
+    set_env(
+        pod_spec,
+        "upload",
+        &[("S3_URL", storage.backup_url(site, backup_id))],
+    )?;
+
+    Ok(job)
+}
+
+/// CronJob taking a full backup of `site` on `schedule`, with the backup named after the
+/// time the job runs.
+pub fn backup_cron_job(site: &str, schedule: &str, storage: &BackupStorage) -> Result<CronJob> {
+    if schedule.split_whitespace().count() != 5 {
+        bail!("invalid cron schedule: {}", schedule);
+    }
+
+    let mut job = backup_job(site, "scheduled", BackupMode::Full, storage)?;
+    let mut labels = job.metadata.labels.take().unwrap_or_default();
+    labels.remove("kwpm.io/backup-id");
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    let upload = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "upload")
+        .context("backup job has no upload container")?;
+    upload.command = Some(vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        format!(
+            "aws s3 cp --recursive /backup/ \"${{S3_URL}}$(date -u +{})/\"",
+            BACKUP_ID_FORMAT
+        ),
+    ]);
+    upload.args = None;
+    set_env(pod_spec, "upload", &[("S3_URL", storage.site_url(site))])?;
+
+    Ok(CronJob {
+        metadata: ObjectMeta {
+            name: Some(BACKUP_SCHEDULE_NAME.to_string()),
+            labels: Some(labels.clone()),
+            ..Default::default()
+        },
+        spec: Some(CronJobSpec {
+            schedule: schedule.to_string(),
+            concurrency_policy: Some("Forbid".to_string()),
+            successful_jobs_history_limit: Some(1),
+            failed_jobs_history_limit: Some(3),
+            job_template: JobTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    ..Default::default()
+                }),
+                spec: job.spec,
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+async fn has_object(store: &impl ObjectStore, path: &Path) -> Result<bool> {
+    match store.head(path).await {
+        Ok(_) => Ok(true),
+        Err(object_store::Error::NotFound { .. }) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn read_index(store: &impl ObjectStore, path: &Path) -> Result<Vec<String>> {
+    let bytes = store
+        .get(path)
+        .await
+        .with_context(|| format!("failed to read backup index {}", path))?
+        .bytes()
+        .await?;
+
+    Ok(String::from_utf8_lossy(&bytes)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+pub async fn read_backup_contents(
+    store: &impl ObjectStore,
+    backup: &Path,
+) -> Result<BackupContents> {
+    // Backups taken before the prefix was recorded are of sites with the default prefix.
+    let table_prefix = match store.get(&backup.child("table-prefix.txt")).await {
+        Ok(object) => String::from_utf8_lossy(&object.bytes().await?)
+            .trim()
+            .to_string(),
+        Err(object_store::Error::NotFound { .. }) => DEFAULT_TABLE_PREFIX.to_string(),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(BackupContents {
+        tables: read_index(store, &backup.child("tables.txt")).await?,
+        files: read_index(store, &backup.child("files.txt")).await?,
+        table_prefix,
+    })
+}
+
+pub fn restore_table_job(
+    site: &str,
+    backup_id: &str,
+    table: &str,
+    storage: &BackupStorage,
+) -> Result<Job> {
+    if !is_valid_table_name(table) {
+        bail!("invalid table name: {}", table);
+    }
+
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/backup/restore-table-job.yaml"
+    ))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .extend(labels(site, backup_id));
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    set_env(
+        pod_spec,
+        "download",
+        &[("S3_URL", storage.backup_url(site, backup_id))],
+    )?;
+    set_env(pod_spec, "restore-table", &[("TABLE", table.to_string())])?;
+
+    Ok(job)
+}
+
+pub fn restore_path_job(
+    site: &str,
+    backup_id: &str,
+    path: &str,
+    storage: &BackupStorage,
+) -> Result<Job> {
+    let path = path.trim_start_matches("wp-content/");
+    if !is_valid_restore_path(path) {
+        bail!("invalid restore path: {}", path);
+    }
+    wp_content_restore_job(site, backup_id, path, storage)
+}
+
+/// Restores all of wp-content of a full, incremental or files backup of `site` into the
+/// site the job is created for.
+pub fn restore_files_job(site: &str, backup_id: &str, storage: &BackupStorage) -> Result<Job> {
+    wp_content_restore_job(site, backup_id, "", storage)
+}
+
+fn wp_content_restore_job(
+    site: &str,
+    backup_id: &str,
+    path: &str,
+    storage: &BackupStorage,
+) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/backup/restore-path-job.yaml"
+    ))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .extend(labels(site, backup_id));
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    set_env(
+        pod_spec,
+        "download",
+        &[("S3_URL", storage.backup_url(site, backup_id))],
+    )?;
+    set_env(
+        pod_spec,
+        "restore-path",
+        &[
+            ("SITE", site.to_string()),
+            ("BACKUP_ID", backup_id.to_string()),
+            ("RESTORE_PATH", path.to_string()),
+            ("RESTIC_REPOSITORY", storage.restic_repository(site)),
+        ],
+    )?;
+
+    Ok(job)
+}
+
+/// Restores the database dump and wp-content archive of a full backup of `site` into the site
+/// the job is created for, e.g. a clone of it.
+pub fn restore_site_job(site: &str, backup_id: &str, storage: &BackupStorage) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/backup/restore-site-job.yaml"
+    ))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .extend(labels(site, backup_id));
+
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "download",
+        &[("S3_URL", storage.backup_url(site, backup_id))],
+    )?;
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    pub(crate) fn backup_storage(&self) -> Result<&BackupStorage> {
+        Ok(self
+            .backup_storage
+            .as_ref()
+            .context("backup storage is not configured")?)
+    }
+
+    /// Makes the backup storage credentials available to jobs in the site namespace.
+    pub(crate) async fn apply_backup_secret(&self, site: &str) -> Result<()> {
+        let secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        secret_api
+            .patch(
+                BACKUP_SECRET_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&self.backup_storage()?.secret())),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn create_backup_job(&self, site: &str, job: &Job) -> Result<Job> {
+        self.apply_backup_secret(site).await?;
+        self.create_job(&self.site_namespace(site), job.clone())
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(site = %site, mode = ?mode))]
+    pub async fn backup_site(&self, site: &str, mode: BackupMode) -> Result<Backup> {
+        let storage = self.backup_storage()?;
+
+        let backup_id = new_backup_id()?;
+        let job = backup_job(site, &backup_id, mode, storage)?;
+        let job = self.create_backup_job(site, &job).await?;
+
+        Ok(Backup {
+            site: site.to_string(),
+            url: storage.backup_url(site, &backup_id),
+            job_name: job.metadata.name.unwrap_or_default(),
+            id: backup_id,
+            mode,
+        })
+    }
+
+    /// Takes a full backup of `site` on `schedule` (cron syntax), or with `None` stops the
+    /// scheduled backups. Backups taken so far are kept.
+    pub async fn schedule_backups(&self, site: &str, schedule: Option<&str>) -> Result<()> {
+        let cron_job_api: Api<CronJob> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let Some(schedule) = schedule else {
+            if cron_job_api.get_opt(BACKUP_SCHEDULE_NAME).await?.is_some() {
+                cron_job_api
+                    .delete(BACKUP_SCHEDULE_NAME, &Default::default())
+                    .await?;
+            }
+            self.record_site_revision(site, "schedule_backups").await?;
+            return Ok(());
+        };
+
+        let mut cron_job = backup_cron_job(site, schedule, self.backup_storage()?)?;
+        self.adapt_mariadb_host(
+            &self.site_namespace(site),
+            cron_job_pod_spec_mut(&mut cron_job)?,
+        )
+        .await?;
+        self.check_policy(
+            "schedule_backups",
+            Some(site),
+            &[serde_json::to_value(&cron_job)?],
+        )
+        .await?;
+        self.apply_backup_secret(site).await?;
+        cron_job_api
+            .patch(
+                BACKUP_SCHEDULE_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&cron_job)),
+            )
+            .await?;
+
+        self.record_site_revision(site, "schedule_backups").await?;
+        Ok(())
+    }
+
+    /// `schedule_backups` for every site, returning the sites.
+    pub async fn schedule_all_backups(&self, schedule: Option<&str>) -> Result<Vec<String>> {
+        let mut sites = Vec::new();
+        for namespace in self.get_kwpm_namespaces().await? {
+            let Some(site) = namespace
+                .metadata
+                .name
+                .as_deref()
+                .and_then(|ns| self.site_name(ns))
+            else {
+                continue;
+            };
+            self.schedule_backups(site, schedule)
+                .await
+                .with_context(|| format!("scheduling backups of {} failed", site))?;
+            sites.push(site.to_string());
+        }
+
+        Ok(sites)
+    }
+
+    /// The backups of `site` in the backup storage, newest first.
+    pub async fn list_backups(&self, site: &str) -> Result<Vec<StoredBackup>> {
+        let storage = self.backup_storage()?;
+        let store = storage.object_store()?;
+        let listing = store
+            .list_with_delimiter(Some(&Path::from(format!(
+                "{}/{}",
+                storage.prefix.trim_matches('/'),
+                site
+            ))))
+            .await?;
+        let names: Vec<String> = listing
+            .common_prefixes
+            .iter()
+            .filter_map(|prefix| prefix.filename().map(str::to_string))
+            .collect();
+
+        Ok(stored_backups(&names))
+    }
+
+    /// Restores the database and wp-content of the live site from the full backup taken at
+    /// `backup_id`, e.g. `20240101-030000`, and waits for it to finish. `confirmation` is the
+    /// token of planning [`DestructiveOperation::RestoreBackup`].
+    #[tracing::instrument(skip_all, fields(site = %site, backup = %backup_id))]
+    pub async fn restore_backup(
+        &self,
+        site: &str,
+        backup_id: &str,
+        confirmation: &str,
+    ) -> Result<()> {
+        if backup_time(backup_id).is_none() {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid backup id: {}",
+                backup_id
+            )));
+        }
+        self.confirm(
+            &DestructiveOperation::RestoreBackup {
+                site: site.to_string(),
+                backup_id: backup_id.to_string(),
+            },
+            confirmation,
+        )
+        .await?;
+        let storage = self.backup_storage()?;
+        let store = storage.object_store()?;
+        let backup = storage.backup_path(site, backup_id);
+        if !has_object(&store, &backup.child("wp-content.tar.gz")).await?
+            || !has_object(&store, &backup.child("database.sql.gz")).await?
+        {
+            return Err(KwpmError::not_found(format!(
+                "{} has no full backup {}, incremental and files backups are restored with \
+                 restore_files",
+                site, backup_id
+            )));
+        }
+
+        let lock = self.lock_site(site, "restore_backup").await?;
+        let result: Result<_> = async {
+            let job = restore_site_job(site, backup_id, storage)?;
+            self.check_policy("restore_backup", Some(site), &[serde_json::to_value(&job)?])
+                .await?;
+
+            let mut progress = self.start_operation(site, "restore_backup").await;
+            progress
+                .step("restoring database and files", Some(10))
+                .await;
+            self.apply_backup_secret(site).await?;
+            if let Err(e) = self
+                .run_job(&self.site_namespace(site), job, RESTORE_TIMEOUT)
+                .await
+            {
+                progress.fail(&e).await;
+                return Err(e);
+            }
+
+            self.record_audit(
+                site,
+                "restore_backup",
+                &format!("restored backup {}", backup_id),
+            )
+            .await?;
+            progress.succeed().await;
+            Ok(())
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Restores wp-content of `site` from `backup_id` into `target`, which may be `site` itself
+    /// or another site, e.g. one just created to restore a deleted site into. Waits for it to
+    /// finish. `confirmation` is the token of planning [`DestructiveOperation::RestoreFiles`].
+    #[tracing::instrument(skip_all, fields(site = %site, backup = %backup_id, target = %target))]
+    pub async fn restore_files(
+        &self,
+        site: &str,
+        backup_id: &str,
+        target: &str,
+        confirmation: &str,
+    ) -> Result<()> {
+        if backup_time(backup_id).is_none() {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid backup id: {}",
+                backup_id
+            )));
+        }
+        self.confirm(
+            &DestructiveOperation::RestoreFiles {
+                site: site.to_string(),
+                backup_id: backup_id.to_string(),
+                target: target.to_string(),
+            },
+            confirmation,
+        )
+        .await?;
+
+        let lock = self.lock_site(target, "restore_files").await?;
+        let result: Result<_> = async {
+            let job = restore_files_job(site, backup_id, self.backup_storage()?)?;
+            self.check_policy(
+                "restore_files",
+                Some(target),
+                &[serde_json::to_value(&job)?],
+            )
+            .await?;
+
+            let mut progress = self.start_operation(target, "restore_files").await;
+            progress.step("restoring files", Some(10)).await;
+            self.apply_backup_secret(target).await?;
+            if let Err(e) = self
+                .run_job(&self.site_namespace(target), job, RESTORE_TIMEOUT)
+                .await
+            {
+                progress.fail(&e).await;
+                return Err(e);
+            }
+
+            self.record_audit(
+                target,
+                "restore_files",
+                &format!("restored wp-content from backup {} of {}", backup_id, site),
+            )
+            .await?;
+            progress.succeed().await;
+            Ok(())
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    pub async fn list_backup_contents(
+        &self,
+        site: &str,
+        backup_id: &str,
+    ) -> Result<BackupContents> {
+        check_backup_id(backup_id)?;
+        let storage = self.backup_storage()?;
+        let store = storage.object_store()?;
+
+        read_backup_contents(&store, &storage.backup_path(site, backup_id)).await
+    }
+
+    /// Starts restoring `table` of a backup over the live site. `confirmation` is the token of
+    /// planning [`DestructiveOperation::RestoreTable`].
+    pub async fn restore_table(
+        &self,
+        site: &str,
+        backup_id: &str,
+        table: &str,
+        confirmation: &str,
+    ) -> Result<RestoreJob> {
+        check_backup_id(backup_id)?;
+        self.confirm(
+            &DestructiveOperation::RestoreTable {
+                site: site.to_string(),
+                backup_id: backup_id.to_string(),
+                table: table.to_string(),
+            },
+            confirmation,
+        )
+        .await?;
+        let lock = self.lock_site(site, "restore_table").await?;
+        let result: Result<_> = async {
+            let job = restore_table_job(site, backup_id, table, self.backup_storage()?)?;
+            let job = self.create_backup_job(site, &job).await?;
+
+            Ok(RestoreJob {
+                site: site.to_string(),
+                backup_id: backup_id.to_string(),
+                job_name: job.metadata.name.unwrap_or_default(),
+            })
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Starts restoring `path` in wp-content of a backup over the live site. `confirmation` is
+    /// the token of planning [`DestructiveOperation::RestorePath`].
+    pub async fn restore_path(
+        &self,
+        site: &str,
+        backup_id: &str,
+        path: &str,
+        confirmation: &str,
+    ) -> Result<RestoreJob> {
+        check_backup_id(backup_id)?;
+        self.confirm(
+            &DestructiveOperation::RestorePath {
+                site: site.to_string(),
+                backup_id: backup_id.to_string(),
+                path: path.to_string(),
+            },
+            confirmation,
+        )
+        .await?;
+        let lock = self.lock_site(site, "restore_path").await?;
+        let result: Result<_> = async {
+            let job = restore_path_job(site, backup_id, path, self.backup_storage()?)?;
+            let job = self.create_backup_job(site, &job).await?;
+
+            Ok(RestoreJob {
+                site: site.to_string(),
+                backup_id: backup_id.to_string(),
+                job_name: job.metadata.name.unwrap_or_default(),
+            })
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    fn storage() -> BackupStorage {
+        BackupStorage {
+            endpoint: "http://minio:9000".to_string(),
+            region: "us-east-1".to_string(),
+            bucket: "backups".to_string(),
+            prefix: "/kwpm/".to_string(),
+            access_key_id: "key".to_string(),
+            secret_access_key: "secret".to_string(),
+            restic_password: Some("restic".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_backup_url() {
+        assert_eq!(
+            storage().backup_url("blog", "20240101-000000"),
+            "s3://backups/kwpm/blog/20240101-000000/"
+        );
+    }
+
+    #[test]
+    fn test_backup_cron_job() {
+        let cron_job = backup_cron_job("blog", "0 3 * * *", &storage()).unwrap();
+        let labels = cron_job.metadata.labels.as_ref().unwrap();
+        assert_eq!(labels["kwpm.io/site"], "blog");
+        assert!(!labels.contains_key("kwpm.io/backup-id"));
+        let spec = cron_job.spec.unwrap();
+        assert_eq!(spec.schedule, "0 3 * * *");
+
+        let pod_spec = spec.job_template.spec.unwrap().template.spec.unwrap();
+        let upload = &pod_spec.containers[0];
+        assert!(upload.command.as_ref().unwrap()[2].contains("date -u +%Y%m%d-%H%M%S"));
+        assert_eq!(
+            upload.env.as_ref().unwrap()[0].value.as_deref(),
+            Some("s3://backups/kwpm/blog/")
+        );
+
+        assert!(backup_cron_job("blog", "daily", &storage()).is_err());
+    }
+
+    #[test]
+    fn test_stored_backups() {
+        let names: Vec<String> = [
+            "20240101-030000",
+            "restic",
+            "snapshots",
+            "20240102-030000-3fa9c2",
+            "20240103-030000-x",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+        let backups = stored_backups(&names);
+
+        let ids: Vec<_> = backups.iter().map(|backup| backup.id.as_str()).collect();
+        assert_eq!(ids, ["20240102-030000-3fa9c2", "20240101-030000"]);
+        assert_eq!(
+            backups[1].taken_at.to_rfc3339(),
+            "2024-01-01T03:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_backup_ids() {
+        let (first, second) = (new_backup_id().unwrap(), new_backup_id().unwrap());
+        assert_ne!(first, second);
+        assert!(backup_time(&first).is_some());
+
+        assert!(is_valid_backup_id(&first));
+        assert!(is_valid_backup_id(
+            "snapshots/20240102-030405-search-replace"
+        ));
+        assert!(!is_valid_backup_id("../other-site/20240101-030000"));
+        assert!(!is_valid_backup_id("20240101-030000/"));
+        assert!(!is_valid_backup_id("20240101 030000"));
+        assert!(!is_valid_backup_id(""));
+    }
+
+    #[test]
+    fn test_backup_job_archives_wp_content() {
+        let job = backup_job("blog", "20240101-000000", BackupMode::Full, &storage()).unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+
+        let init_containers: Vec<_> = pod_spec
+            .init_containers
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(
+            init_containers,
+            ["dump-database", "archive-wp-content", "index-contents"]
+        );
+
+        let claim = pod_spec
+            .volumes
+            .unwrap()
+            .into_iter()
+            .find_map(|v| v.persistent_volume_claim)
+            .unwrap();
+        assert_eq!(claim.claim_name, "wp-pv-claim");
+
+        let upload_env = pod_spec.containers[0].env.as_ref().unwrap();
+        assert_eq!(
+            upload_env[0].value.as_deref(),
+            Some("s3://backups/kwpm/blog/20240101-000000/")
+        );
+    }
+
+    #[test]
+    fn test_incremental_backup_job_uses_restic() {
+        let job = backup_job(
+            "blog",
+            "20240101-000000",
+            BackupMode::Incremental,
+            &storage(),
+        )
+        .unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+
+        let snapshot = pod_spec
+            .init_containers
+            .unwrap()
+            .into_iter()
+            .find(|c| c.name == "snapshot-wp-content")
+            .unwrap();
+        let repository = snapshot
+            .env
+            .unwrap()
+            .into_iter()
+            .find(|e| e.name == "RESTIC_REPOSITORY")
+            .and_then(|e| e.value);
+        assert_eq!(
+            repository.as_deref(),
+            Some("s3:http://minio:9000/backups/kwpm/blog/restic")
+        );
+    }
+
+    #[test]
+    fn test_incremental_backup_requires_restic_password() {
+        let storage = BackupStorage {
+            restic_password: None,
+            ..storage()
+        };
+        assert!(backup_job("blog", "20240101-000000", BackupMode::Incremental, &storage).is_err());
+    }
+
+    #[test]
+    fn test_restore_table_job_rejects_invalid_names() {
+        assert!(restore_table_job("blog", "20240101-000000", "wp_posts", &storage()).is_ok());
+        assert!(restore_table_job("blog", "20240101-000000", "wp_posts`; --", &storage()).is_err());
+        assert!(restore_table_job("blog", "20240101-000000", "", &storage()).is_err());
+    }
+
+    #[test]
+    fn test_restore_path_job_rejects_escaping_paths() {
+        let job = restore_path_job(
+            "blog",
+            "20240101-000000",
+            "wp-content/uploads/2024",
+            &storage(),
+        )
+        .unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+        let restore_path = pod_spec.containers[0]
+            .env
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|e| e.name == "RESTORE_PATH")
+            .and_then(|e| e.value.clone());
+        assert_eq!(restore_path.as_deref(), Some("uploads/2024"));
+
+        assert!(
+            restore_path_job("blog", "20240101-000000", "../wp-config.php", &storage()).is_err()
+        );
+        assert!(restore_path_job("blog", "20240101-000000", "/etc/passwd", &storage()).is_err());
+    }
+
+    #[test]
+    fn test_files_backup_and_restore() {
+        let job = backup_job("blog", "20240101-000000", BackupMode::Files, &storage()).unwrap();
+        let init_containers: Vec<_> = job
+            .spec
+            .unwrap()
+            .template
+            .spec
+            .unwrap()
+            .init_containers
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(init_containers, ["archive-wp-content", "index-contents"]);
+
+        let job = restore_files_job("blog", "20240101-000000", &storage()).unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+        let restore_path = pod_spec.containers[0]
+            .env
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|e| e.name == "RESTORE_PATH")
+            .and_then(|e| e.value.clone());
+        assert_eq!(restore_path.as_deref(), Some(""));
+    }
+
+    #[tokio::test]
+    async fn test_read_backup_contents() {
+        let store = InMemory::new();
+        let backup = storage().backup_path("blog", "20240101-000000");
+        store
+            .put(&backup.child("tables.txt"), "wp_options\nwp_posts\n".into())
+            .await
+            .unwrap();
+        store
+            .put(
+                &backup.child("files.txt"),
+                "wp-content/index.php\n\nwp-content/uploads/logo.png\n".into(),
+            )
+            .await
+            .unwrap();
+
+        let contents = read_backup_contents(&store, &backup).await.unwrap();
+        assert_eq!(contents.tables, ["wp_options", "wp_posts"]);
+        assert_eq!(
+            contents.files,
+            ["wp-content/index.php", "wp-content/uploads/logo.png"]
+        );
+        assert_eq!(contents.table_prefix, "wp_");
+
+        store
+            .put(&backup.child("table-prefix.txt"), "wp3fa9c1_\n".into())
+            .await
+            .unwrap();
+        let contents = read_backup_contents(&store, &backup).await.unwrap();
+        assert_eq!(contents.table_prefix, "wp3fa9c1_");
+    }
+}
@@ -0,0 +1,584 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+use kwpm_api::{
+    fetch_blueprint, init_logging, mariadb::DEFAULT_MARIADB_INSTANCE, relabel::parse_metadata_arg,
+    AppKind, DestructiveOperation, ExecEvent, Extension, HostingImport, InstalledExtension,
+    KwpmClient, KwpmConfig, LogFormat, ManifestSource, MasterKeys, MetadataChange,
+};
+
+#[derive(Parser)]
+#[command(name = "kwpm", about = "Manage WordPress sites on Kubernetes")]
+struct Cli {
+    /// Directory on the nodes local volumes are created in, overriding the config file
+    /// `KWPM_CONFIG` points to.
+    #[arg(long, env = "KWPM_PV_BASE_PATH")]
+    pv_base_path: Option<String>,
+
+    /// `pretty` or `json`. Only warnings are logged unless `RUST_LOG` says otherwise.
+    #[arg(long, env = "KWPM_LOG_FORMAT", default_value = "pretty")]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the MariaDB instances sites keep their databases on.
+    Mariadb {
+        #[command(subcommand)]
+        command: MariaDbCommand,
+    },
+    /// Manage WordPress sites.
+    Site {
+        #[command(subcommand)]
+        command: SiteCommand,
+    },
+    /// Manage the plugins of WordPress sites, which are kept in the site's spec.
+    Plugin {
+        #[command(subcommand)]
+        command: PluginCommand,
+    },
+    /// Manage the themes of WordPress sites, which are kept in the site's spec.
+    Theme {
+        #[command(subcommand)]
+        command: ThemeCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PluginCommand {
+    /// Lists the plugins of a site with their status, version and available update.
+    List { site: String },
+    /// Installs a plugin from wordpress.org, or changes its version or whether it is active.
+    Install {
+        site: String,
+        slug: String,
+        /// Pin this version instead of installing the latest one.
+        #[arg(long)]
+        version: Option<String>,
+        /// Don't activate the plugin.
+        #[arg(long)]
+        inactive: bool,
+    },
+    /// Deactivates and deletes a plugin, leaving its data in the database.
+    Remove { site: String, slug: String },
+    /// Installs and activates the plugins and themes of the site's spec that are missing or
+    /// differ.
+    Sync { site: String },
+}
+
+#[derive(Subcommand)]
+enum ThemeCommand {
+    /// Lists the themes of a site with their status, version and available update.
+    List { site: String },
+    /// Installs a theme from wordpress.org and activates it, or changes its version.
+    Install {
+        site: String,
+        slug: String,
+        /// Pin this version instead of installing the latest one.
+        #[arg(long)]
+        version: Option<String>,
+        /// Keep the current theme active.
+        #[arg(long)]
+        inactive: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum MariaDbCommand {
+    /// Lists the MariaDB instances.
+    List,
+    /// Installs MariaDB, or completes and updates an existing install.
+    Install {
+        /// The instance to install, the default one if unset.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE)]
+        instance: String,
+        /// Generated and only stored in the instance's secret if unset, or kept from the
+        /// existing instance.
+        #[arg(long, env = "KWPM_MARIADB_ROOT_PASSWORD", hide_env_values = true)]
+        root_password: Option<String>,
+        /// Node the MariaDB volume is created on, picked from the cluster's nodes by default.
+        #[arg(long)]
+        node: Option<String>,
+        /// Seconds to wait for MariaDB to accept connections, not waiting if unset.
+        #[arg(long)]
+        wait: Option<u64>,
+    },
+    /// Moves MariaDB to another version after dumping all databases, restoring the dump into
+    /// the previous version if the upgrade fails.
+    Upgrade {
+        /// The tag of the MariaDB image, e.g. `11.4`.
+        tag: String,
+        /// The instance to upgrade, the default one if unset.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE)]
+        instance: String,
+    },
+    /// Sets the instance the sites of an instance fail over to when it is down. The standby
+    /// has to hold their databases, e.g. as a replica.
+    Standby {
+        /// The standby, removed if unset.
+        standby: Option<String>,
+        /// The instance whose sites fail over, the default one if unset.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE)]
+        instance: String,
+    },
+    /// Promotes the standby of an instance that is down and moves its sites to it.
+    Failover {
+        /// The instance that is down, the default one if unset.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE)]
+        instance: String,
+    },
+    /// Removes MariaDB with every database on it. Without `--confirm` only shows what would
+    /// be removed and the token confirming it.
+    Remove {
+        /// The instance to remove, the default one if unset.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE)]
+        instance: String,
+        /// Remove the finalizers blocking the namespace if it gets stuck terminating.
+        #[arg(long)]
+        force: bool,
+        #[arg(long)]
+        confirm: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SiteCommand {
+    /// Creates a WordPress site with its own database. Sites with a database require
+    /// `KWPM_MASTER_KEYS`.
+    Create {
+        name: String,
+        #[arg(long)]
+        domain: String,
+        /// Run this php-fpm image instead of WordPress, e.g. `matomo:5-fpm-alpine`.
+        #[arg(long, conflicts_with = "static_files")]
+        php_image: Option<String>,
+        /// Serve static files without PHP or a database.
+        #[arg(long = "static")]
+        static_files: bool,
+        /// The MariaDB instance of the site's database.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE, conflicts_with = "static_files")]
+        mariadb: String,
+        /// Pinned `oci://` or `git+https://` blueprint to create the site from, its app taking
+        /// the place of `--php-image` and `--static`.
+        #[arg(long, conflicts_with_all = ["php_image", "static_files"])]
+        blueprint: Option<String>,
+    },
+    /// Creates a site from a cPanel or Softaculous backup archive. Requires `KWPM_MASTER_KEYS`
+    /// for sites with a database.
+    Import {
+        name: String,
+        /// URL of the `.tar.gz` backup the cluster can download.
+        #[arg(long)]
+        archive_url: String,
+        /// The domain of the site, the backup's main domain by default.
+        #[arg(long)]
+        domain: Option<String>,
+        /// The database to import from a cPanel backup of several.
+        #[arg(long)]
+        database: Option<String>,
+        /// php-fpm image running a backup that isn't WordPress.
+        #[arg(long)]
+        php_image: Option<String>,
+        /// The MariaDB instance of the site's database.
+        #[arg(long, default_value = DEFAULT_MARIADB_INSTANCE)]
+        mariadb: String,
+    },
+    /// Lists the names of the sites.
+    List {
+        /// Label selector the site namespaces have to match.
+        #[arg(long, short = 'l', default_value = "")]
+        selector: String,
+        /// Also show each site's domain, pods, database, TLS and volume.
+        #[arg(long)]
+        status: bool,
+    },
+    /// Sets or removes labels and annotations on the objects of all matching sites, e.g.
+    /// `--label cost-center=web --annotation legacy-`.
+    Relabel {
+        /// Label selector the site namespaces have to match, all sites if empty.
+        #[arg(long, short = 'l', default_value = "")]
+        selector: String,
+        /// `key=value` to set, `key-` to remove.
+        #[arg(long = "label")]
+        labels: Vec<String>,
+        /// `key=value` to set, `key-` to remove.
+        #[arg(long = "annotation")]
+        annotations: Vec<String>,
+    },
+    /// Runs WP-CLI in a pod of a site, e.g. `kwpm site wp blog plugin list`, printing its output
+    /// as it comes.
+    Wp {
+        name: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Keeps the operator and remediation away from a site while its objects are edited by
+    /// hand.
+    Pause { name: String },
+    /// Lets the operator converge a paused site again at its next resync.
+    Resume { name: String },
+    /// Upgrades the WordPress image of a site to another tag and its database with it, rolling
+    /// back if the new pods fail.
+    Upgrade {
+        name: String,
+        /// The tag of the WordPress image, e.g. `6.5-fpm-alpine`.
+        tag: String,
+    },
+    /// Removes a site with its database and volume. Without `--confirm` only shows what would
+    /// be removed and the token confirming it.
+    Remove {
+        name: String,
+        #[arg(long)]
+        confirm: Option<String>,
+    },
+}
+
+/// Prints what `operation` would do and how to confirm it.
+async fn print_plan(client: &KwpmClient, operation: &DestructiveOperation) -> Result<()> {
+    let plan = client.plan(operation).await?;
+    println!("{} will:", operation);
+    for effect in &plan.effects {
+        println!("  - {}", effect);
+    }
+    println!(
+        "rerun with --confirm {} before {} to proceed",
+        plan.token,
+        plan.expires_at.to_rfc3339()
+    );
+    Ok(())
+}
+
+fn print_extensions(extensions: &[InstalledExtension]) {
+    println!(
+        "{:<30} {:<10} {:<12} {:<12} {:<8}",
+        "NAME", "STATUS", "VERSION", "UPDATE", "MANAGED"
+    );
+    for extension in extensions {
+        println!(
+            "{:<30} {:<10} {:<12} {:<12} {:<8}",
+            extension.name,
+            extension.status,
+            extension.version,
+            extension.update_version.as_deref().unwrap_or("-"),
+            extension.managed
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_logging(cli.log_format, "warn")?;
+    let mut config = KwpmConfig::from_env()?;
+    if let Some(pv_base_path) = cli.pv_base_path {
+        config.pv_base_path = pv_base_path;
+    }
+    let mut client = KwpmClient::from_config(config)
+        .await?
+        .with_manifest_source(ManifestSource::from_env()?);
+    if std::env::var_os("KWPM_MASTER_KEYS").is_some() {
+        client = client.with_master_keys(MasterKeys::from_env()?);
+    }
+
+    match cli.command {
+        Command::Mariadb { command } => match command {
+            MariaDbCommand::List => {
+                for instance in client.list_mariadb_instances().await? {
+                    println!("{}", instance);
+                }
+            }
+            MariaDbCommand::Install {
+                instance,
+                root_password,
+                node,
+                wait,
+            } => {
+                let secret = client
+                    .create_mariadb_instance(&instance, root_password.as_deref(), node.as_deref())
+                    .await?;
+                println!(
+                    "root password stored in secret {}/{}, key {}",
+                    secret.namespace, secret.name, secret.key
+                );
+                if let Some(wait) = wait {
+                    client
+                        .wait_for_mariadb_instance_ready(&instance, Duration::from_secs(wait))
+                        .await?;
+                }
+                println!("MariaDB {} installed", instance);
+            }
+            MariaDbCommand::Upgrade { tag, instance } => {
+                let upgrade = client.upgrade_mariadb(&instance, &tag).await?;
+                println!(
+                    "MariaDB {} upgraded from {} to {}, dump kept at {}",
+                    instance, upgrade.previous_image, upgrade.image, upgrade.dump
+                );
+            }
+            MariaDbCommand::Standby { standby, instance } => {
+                client
+                    .set_mariadb_standby(&instance, standby.as_deref())
+                    .await?;
+                match standby {
+                    Some(standby) => println!("MariaDB {} fails over to {}", instance, standby),
+                    None => println!("MariaDB {} has no standby", instance),
+                }
+            }
+            MariaDbCommand::Failover { instance } => {
+                let failover = client.fail_over_mariadb(&instance).await?;
+                println!(
+                    "MariaDB {} failed over to {}: {} sites moved",
+                    instance,
+                    failover.standby,
+                    failover.sites.len()
+                );
+                for (site, error) in &failover.failed {
+                    println!("{} is still on {}: {}", site, instance, error);
+                }
+            }
+            MariaDbCommand::Remove {
+                instance,
+                force,
+                confirm,
+            } => match confirm {
+                Some(token) => {
+                    client
+                        .remove_mariadb_instance(&instance, force, &token)
+                        .await?;
+                    println!("MariaDB {} removed", instance);
+                }
+                None => {
+                    let operation = if instance == DEFAULT_MARIADB_INSTANCE {
+                        DestructiveOperation::RemoveMariaDb
+                    } else {
+                        DestructiveOperation::RemoveMariaDbInstance { instance }
+                    };
+                    print_plan(&client, &operation).await?
+                }
+            },
+        },
+        Command::Site { command } => match command {
+            SiteCommand::Create {
+                name,
+                domain,
+                php_image,
+                static_files,
+                mariadb,
+                blueprint,
+            } => {
+                let blueprint = match blueprint {
+                    Some(source) => Some(fetch_blueprint(&source).await?),
+                    None => None,
+                };
+                let app = match (&blueprint, php_image, static_files) {
+                    (Some(blueprint), _, _) => blueprint.spec.app.clone(),
+                    (None, Some(image), _) => AppKind::Php { image },
+                    (None, None, true) => AppKind::Static,
+                    (None, None, false) => AppKind::WordPress,
+                };
+                let database = if app.uses_database() {
+                    client = client.with_master_keys(MasterKeys::from_env()?);
+                    Some(client.database_config_for_site(&name)?.on_instance(mariadb))
+                } else {
+                    None
+                };
+                match &blueprint {
+                    Some(blueprint) => {
+                        client
+                            .create_site_from_blueprint(
+                                &name,
+                                &domain,
+                                blueprint,
+                                database.as_ref(),
+                            )
+                            .await?
+                    }
+                    None => {
+                        client
+                            .create_site(&name, &domain, &app, database.as_ref())
+                            .await?
+                    }
+                }
+                println!("site {} created at https://{}", name, domain);
+            }
+            SiteCommand::Import {
+                name,
+                archive_url,
+                domain,
+                database,
+                php_image,
+                mariadb,
+            } => {
+                let imported = client
+                    .import_hosting_backup(
+                        &name,
+                        &HostingImport {
+                            archive_url,
+                            domain,
+                            database,
+                            php_image,
+                            mariadb: Some(mariadb),
+                        },
+                    )
+                    .await?;
+                println!(
+                    "{:?} backup imported as site {} at https://{}",
+                    imported.backup.kind, imported.site, imported.domain
+                );
+            }
+            SiteCommand::List {
+                selector,
+                status: false,
+            } => {
+                for name in client.list_site_names(&selector).await? {
+                    println!("{}", name);
+                }
+            }
+            SiteCommand::List {
+                selector,
+                status: true,
+            } => {
+                println!(
+                    "{:<20} {:<30} {:<10} {:<10} {:<12} {:<8} {:>10}",
+                    "NAME", "DOMAIN", "PODS", "AVAILABLE", "DATABASE", "TLS", "VOLUME"
+                );
+                for site in client.list_sites(&selector).await? {
+                    let volume = match (site.volume_usage, site.volume_capacity_bytes) {
+                        (Some(usage), _) => format!(
+                            "{}/{}Mi",
+                            usage.used_bytes / (1024 * 1024),
+                            usage.capacity_bytes / (1024 * 1024)
+                        ),
+                        (None, Some(capacity)) => format!("?/{}Mi", capacity / (1024 * 1024)),
+                        (None, None) => "-".to_string(),
+                    };
+                    println!(
+                        "{:<20} {:<30} {:<10} {:<10} {:<12} {:<8} {:>10}",
+                        site.name,
+                        site.domain.as_deref().unwrap_or("-"),
+                        site.pod_phase.as_deref().unwrap_or("-"),
+                        site.available,
+                        format!("{:?}", site.database),
+                        format!("{:?}", site.tls),
+                        volume
+                    );
+                }
+            }
+            SiteCommand::Relabel {
+                selector,
+                labels,
+                annotations,
+            } => {
+                let parse = |args: &[String]| {
+                    args.iter()
+                        .map(|arg| parse_metadata_arg(arg))
+                        .collect::<kwpm_api::error::Result<_>>()
+                };
+                let change = MetadataChange {
+                    labels: parse(&labels)?,
+                    annotations: parse(&annotations)?,
+                };
+                let report = client.relabel(&selector, &change).await?;
+                println!(
+                    "relabeled {} objects of {} sites",
+                    report.objects,
+                    report.relabeled.len()
+                );
+                for (site, error) in &report.failed {
+                    eprintln!("{}: {}", site, error);
+                }
+                if !report.failed.is_empty() {
+                    anyhow::bail!("relabeling {} sites failed", report.failed.len());
+                }
+            }
+            SiteCommand::Wp { name, args } => {
+                let mut events = client.wp_cli(&name, &args).await?;
+                while let Some(event) = events.next().await {
+                    match event? {
+                        ExecEvent::Stdout(line) => println!("{}", line),
+                        ExecEvent::Stderr(line) => eprintln!("{}", line),
+                        ExecEvent::Exit { success: true, .. } => {}
+                        ExecEvent::Exit { message, .. } => anyhow::bail!(
+                            "wp failed on {}{}",
+                            name,
+                            message.map(|m| format!(": {}", m)).unwrap_or_default()
+                        ),
+                    }
+                }
+            }
+            SiteCommand::Pause { name } => {
+                client.set_site_paused(&name, true).await?;
+                println!("site {} paused", name);
+            }
+            SiteCommand::Resume { name } => {
+                client.set_site_paused(&name, false).await?;
+                println!("site {} resumed", name);
+            }
+            SiteCommand::Upgrade { name, tag } => {
+                let upgrade = client.upgrade_site(&name, &tag).await?;
+                println!(
+                    "site {} upgraded from {} to {}",
+                    name, upgrade.previous_image, upgrade.image
+                );
+            }
+            SiteCommand::Remove { name, confirm } => match confirm {
+                Some(token) => {
+                    client.remove_wordpress_site(&name, &token).await?;
+                    println!("site {} removed", name);
+                }
+                None => {
+                    print_plan(&client, &DestructiveOperation::RemoveSite { site: name }).await?
+                }
+            },
+        },
+        Command::Plugin { command } => match command {
+            PluginCommand::List { site } => print_extensions(&client.list_plugins(&site).await?),
+            PluginCommand::Install {
+                site,
+                slug,
+                version,
+                inactive,
+            } => {
+                let plugin = Extension {
+                    slug,
+                    version,
+                    active: !inactive,
+                };
+                client.install_plugin(&site, plugin.clone()).await?;
+                println!("plugin {} installed on {}", plugin.slug, site);
+            }
+            PluginCommand::Remove { site, slug } => {
+                client.remove_plugin(&site, &slug).await?;
+                println!("plugin {} removed from {}", slug, site);
+            }
+            PluginCommand::Sync { site } => {
+                for command in client.sync_site_extensions(&site).await? {
+                    println!("wp {}", command);
+                }
+            }
+        },
+        Command::Theme { command } => match command {
+            ThemeCommand::List { site } => print_extensions(&client.list_themes(&site).await?),
+            ThemeCommand::Install {
+                site,
+                slug,
+                version,
+                inactive,
+            } => {
+                let theme = Extension {
+                    slug,
+                    version,
+                    active: !inactive,
+                };
+                client.install_theme(&site, theme.clone()).await?;
+                println!("theme {} installed on {}", theme.slug, site);
+            }
+        },
+    }
+
+    Ok(())
+}
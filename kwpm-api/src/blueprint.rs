@@ -0,0 +1,426 @@
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{bail, KwpmError, Result},
+    registry::Registry,
+    site::SiteSpec,
+};
+
+const OCI_MANIFEST_TYPES: &str =
+    "application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+
+/// A shareable site template: spec defaults plus overlays patched into the rendered manifests,
+/// see `KwpmClient::create_site_from_blueprint`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Blueprint {
+    pub name: String,
+    pub spec: SiteSpec,
+    pub overlays: Vec<ManifestOverlay>,
+}
+
+/// JSON merge patch (RFC 7386) for the manifest of the given kind and name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManifestOverlay {
+    pub kind: String,
+    pub name: String,
+    pub patch: Value,
+}
+
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+
+    let target = target.as_object_mut().expect("target was made an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+impl Blueprint {
+    /// Applies the overlays matching the manifest's kind and name.
+    pub fn apply_overlays(&self, manifest: &mut Value) {
+        let kind = manifest
+            .get("kind")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let name = manifest
+            .pointer("/metadata/name")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        for overlay in &self.overlays {
+            if kind.as_deref() == Some(overlay.kind.as_str())
+                && name.as_deref() == Some(overlay.name.as_str())
+            {
+                merge_patch(manifest, &overlay.patch);
+            }
+        }
+    }
+
+    /// Like `apply_overlays`, for a typed object.
+    pub fn overlay<T: Serialize + DeserializeOwned>(&self, object: &mut T) -> Result<()> {
+        let mut manifest = serde_json::to_value(&*object)?;
+        self.apply_overlays(&mut manifest);
+        *object = serde_json::from_value(manifest)
+            .with_context(|| format!("an overlay of blueprint {} is invalid", self.name))?;
+        Ok(())
+    }
+
+    /// The spec a site created from the blueprint starts with. Only the settings rendered into
+    /// a new site's manifests may be set, the others are changed on the site once it exists.
+    pub fn site_spec(&self) -> Result<SiteSpec> {
+        let spec = SiteSpec {
+            app: self.spec.app.clone(),
+            upload_limit_mb: self.spec.upload_limit_mb,
+            redirects: self.spec.redirects.clone(),
+            locale: self.spec.locale.clone(),
+            timezone: self.spec.timezone.clone(),
+            ..Default::default()
+        };
+        if spec != self.spec {
+            return Err(KwpmError::invalid_input(format!(
+                "blueprint {} may only set the app, upload limit, redirects, locale and timezone",
+                self.name
+            )));
+        }
+        Ok(spec)
+    }
+}
+
+/// Where a blueprint is fetched from. Only pinned references are accepted, so a blueprint can't
+/// change underneath the sites created from it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlueprintSource {
+    /// `oci://registry/repository@sha256:<digest>`
+    Oci {
+        registry: String,
+        repository: String,
+        digest: String,
+    },
+    /// `git+https://host/repo.git//path/blueprint.yaml?ref=<commit>`
+    Git {
+        url: String,
+        path: String,
+        commit: String,
+    },
+}
+
+fn is_sha256_digest(digest: &str) -> bool {
+    digest
+        .strip_prefix("sha256:")
+        .is_some_and(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+fn verify_digest(bytes: &[u8], digest: &str) -> Result<()> {
+    let actual = sha256_digest(bytes);
+    if actual != digest {
+        bail!("digest mismatch: expected {}, got {}", digest, actual);
+    }
+    Ok(())
+}
+
+/// Whether git may fetch from `url`: `https://` and `ssh://` URLs and `git@host:repo`
+/// locations are allowed. Anything else, in particular locations starting with `-` that git
+/// would take for options like `--upload-pack`, or other transports such as `ext::`, is
+/// refused.
+pub fn is_allowed_git_url(url: &str) -> bool {
+    let host = if let Some(rest) = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("ssh://"))
+    {
+        rest.split('/').next().unwrap_or_default()
+    } else if let Some(rest) = url.strip_prefix("git@") {
+        match rest.split_once(':') {
+            Some((host, repository)) if !repository.is_empty() => host,
+            _ => return false,
+        }
+    } else {
+        return false;
+    };
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    !host.is_empty()
+        && !host.starts_with('-')
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+impl BlueprintSource {
+    pub fn parse(source: &str) -> Result<Self> {
+        if let Some(reference) = source.strip_prefix("oci://") {
+            let Some((name, digest)) = reference.split_once('@') else {
+                bail!(
+                    "OCI blueprint {} must be pinned with @sha256:<digest>",
+                    source
+                );
+            };
+            if !is_sha256_digest(digest) {
+                bail!("invalid blueprint digest: {}", digest);
+            }
+            let Some((registry, repository)) = name.split_once('/') else {
+                bail!("OCI blueprint {} has no repository", source);
+            };
+
+            return Ok(BlueprintSource::Oci {
+                registry: registry.to_string(),
+                repository: repository.to_string(),
+                digest: digest.to_string(),
+            });
+        }
+
+        if let Some(reference) = source.strip_prefix("git+") {
+            let (location, commit) = reference.split_once("?ref=").with_context(|| {
+                format!("git blueprint {} must be pinned with ?ref=<commit>", source)
+            })?;
+            if commit.len() != 40 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!(
+                    "git blueprint ref must be a full commit hash, got {}",
+                    commit
+                );
+            }
+            let scheme_end = location.find("://").map(|i| i + 3).unwrap_or(0);
+            let Some(split) = location[scheme_end..].find("//").map(|i| i + scheme_end) else {
+                bail!(
+                    "git blueprint {} has no //path to the blueprint file",
+                    source
+                );
+            };
+            let path = &location[split + 2..];
+            if path.is_empty() || path.split('/').any(|c| c.is_empty() || c == "..") {
+                bail!("invalid blueprint path: {}", path);
+            }
+
+            let url = &location[..split];
+            if !is_allowed_git_url(url) {
+                bail!(
+                    "git blueprint {} must be fetched over https://, ssh:// or git@host:",
+                    source
+                );
+            }
+
+            return Ok(BlueprintSource::Git {
+                url: url.to_string(),
+                path: path.to_string(),
+                commit: commit.to_lowercase(),
+            });
+        }
+
+        bail!("unsupported blueprint source: {}", source)
+    }
+}
+
+async fn fetch_oci(registry: &str, repository: &str, digest: &str) -> Result<Vec<u8>> {
+    let mut registry = Registry::new(registry, repository);
+
+    let manifest = registry.manifest(digest, OCI_MANIFEST_TYPES).await?;
+    verify_digest(&manifest, digest)?;
+
+    let manifest: Value = serde_json::from_slice(&manifest)?;
+    let layer_digest = manifest
+        .pointer("/layers/0/digest")
+        .and_then(Value::as_str)
+        .context("blueprint artifact has no layers")?
+        .to_string();
+
+    let blob = registry.blob(&layer_digest).await?;
+    verify_digest(&blob, &layer_digest)?;
+
+    Ok(blob)
+}
+
+async fn git(checkout: &Path, args: &[&str]) -> Result<()> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(checkout)
+        .args(args)
+        .output()
+        .await
+        .context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+async fn fetch_git(url: &str, path: &str, commit: &str) -> Result<Vec<u8>> {
+    // Removed when dropped; each fetch gets its own, so concurrent fetches don't collide.
+    let checkout = tempfile::Builder::new()
+        .prefix("kwpm-blueprint-")
+        .tempdir()?;
+    let checkout = checkout.path();
+
+    git(checkout, &["init", "--quiet"]).await?;
+    git(
+        checkout,
+        &[
+            "fetch",
+            "--quiet",
+            "--depth",
+            "1",
+            "--end-of-options",
+            url,
+            commit,
+        ],
+    )
+    .await?;
+    git(checkout, &["checkout", "--quiet", "FETCH_HEAD"]).await?;
+    Ok(tokio::fs::read(checkout.join(path))
+        .await
+        .with_context(|| format!("blueprint {} not found at {}", path, commit))?)
+}
+
+pub async fn fetch_blueprint(source: &str) -> Result<Blueprint> {
+    let bytes = match BlueprintSource::parse(source)? {
+        BlueprintSource::Oci {
+            registry,
+            repository,
+            digest,
+        } => fetch_oci(&registry, &repository, &digest).await?,
+        BlueprintSource::Git { url, path, commit } => fetch_git(&url, &path, &commit).await?,
+    };
+
+    Ok(serde_yaml::from_slice(&bytes)
+        .with_context(|| format!("invalid blueprint at {}", source))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const DIGEST: &str = "sha256:2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+    #[test]
+    fn test_parse_oci_source() {
+        assert_eq!(
+            BlueprintSource::parse(&format!("oci://ghcr.io/acme/blueprints/blog@{}", DIGEST))
+                .unwrap(),
+            BlueprintSource::Oci {
+                registry: "ghcr.io".to_string(),
+                repository: "acme/blueprints/blog".to_string(),
+                digest: DIGEST.to_string(),
+            }
+        );
+        assert!(BlueprintSource::parse("oci://ghcr.io/acme/blueprints/blog:latest").is_err());
+    }
+
+    #[test]
+    fn test_parse_git_source() {
+        assert_eq!(
+            BlueprintSource::parse(
+                "git+https://github.com/acme/blueprints.git//blog/blueprint.yaml?ref=0123456789abcdef0123456789abcdef01234567"
+            )
+            .unwrap(),
+            BlueprintSource::Git {
+                url: "https://github.com/acme/blueprints.git".to_string(),
+                path: "blog/blueprint.yaml".to_string(),
+                commit: "0123456789abcdef0123456789abcdef01234567".to_string(),
+            }
+        );
+        assert!(BlueprintSource::parse(
+            "git+https://github.com/acme/blueprints.git//blog/blueprint.yaml?ref=main"
+        )
+        .is_err());
+        assert!(BlueprintSource::parse(
+            "git+https://github.com/acme/blueprints.git//../etc/passwd?ref=0123456789abcdef0123456789abcdef01234567"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_git_url() {
+        assert!(is_allowed_git_url("https://github.com/acme/blueprints.git"));
+        assert!(is_allowed_git_url(
+            "ssh://git@github.com/acme/blueprints.git"
+        ));
+        assert!(is_allowed_git_url("git@github.com:acme/blueprints.git"));
+        assert!(!is_allowed_git_url("--upload-pack=touch /tmp/pwned"));
+        assert!(!is_allowed_git_url("-uhttps://github.com/acme"));
+        assert!(!is_allowed_git_url("ssh://-oProxyCommand=touch/acme.git"));
+        assert!(!is_allowed_git_url("ext::sh -c touch% /tmp/pwned"));
+        assert!(!is_allowed_git_url("file:///etc"));
+        assert!(!is_allowed_git_url("git@github.com:"));
+        assert!(BlueprintSource::parse(
+            "git+--upload-pack=touch /tmp/pwned//blueprint.yaml?ref=0123456789abcdef0123456789abcdef01234567"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        assert!(verify_digest(b"foo", DIGEST).is_ok());
+        assert!(verify_digest(b"bar", DIGEST).is_err());
+    }
+
+    #[test]
+    fn test_apply_overlays() {
+        let blueprint: Blueprint = serde_yaml::from_str(
+            r#"
+name: blog
+spec:
+  uploadLimitMb: 64
+overlays:
+  - kind: Deployment
+    name: wordpress
+    patch:
+      metadata:
+        labels:
+          team: marketing
+          app: null
+"#,
+        )
+        .unwrap();
+        assert_eq!(blueprint.spec.upload_limit_mb, Some(64));
+
+        let mut manifest = json!({
+            "kind": "Deployment",
+            "metadata": { "name": "wordpress", "labels": { "app": "wordpress" } },
+        });
+        blueprint.apply_overlays(&mut manifest);
+
+        assert_eq!(
+            manifest["metadata"]["labels"],
+            json!({ "team": "marketing" })
+        );
+    }
+
+    #[test]
+    fn test_site_spec() {
+        let mut blueprint = Blueprint {
+            name: "blog".to_string(),
+            spec: SiteSpec {
+                upload_limit_mb: Some(64),
+                locale: Some("de_DE".to_string()),
+                ..Default::default()
+            },
+            overlays: vec![],
+        };
+        assert_eq!(blueprint.site_spec().unwrap(), blueprint.spec);
+
+        blueprint.spec.debug = true;
+        assert!(blueprint.site_spec().is_err());
+    }
+}
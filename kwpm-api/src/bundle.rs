@@ -0,0 +1,612 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{bail, Result},
+    registry::{ImageReference, Registry, RegistryCredentials, MANIFEST_TYPES},
+};
+
+const OCI_INDEX_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// Every manifest template kwpm renders from, keyed by its path below `kubernetes/`.
+pub const MANIFESTS: &[(&str, &str)] = &[
+    (
+        "backup/backup-job.yaml",
+        include_str!("../../kubernetes/backup/backup-job.yaml"),
+    ),
+    (
+        "backup/backup-restic-container.yaml",
+        include_str!("../../kubernetes/backup/backup-restic-container.yaml"),
+    ),
+    (
+        "backup/restore-path-job.yaml",
+        include_str!("../../kubernetes/backup/restore-path-job.yaml"),
+    ),
+    (
+        "backup/restore-site-job.yaml",
+        include_str!("../../kubernetes/backup/restore-site-job.yaml"),
+    ),
+    (
+        "backup/restore-table-job.yaml",
+        include_str!("../../kubernetes/backup/restore-table-job.yaml"),
+    ),
+    (
+        "backup/snapshot-job.yaml",
+        include_str!("../../kubernetes/backup/snapshot-job.yaml"),
+    ),
+    (
+        "backup/wipe-site-job.yaml",
+        include_str!("../../kubernetes/backup/wipe-site-job.yaml"),
+    ),
+    (
+        "cache/cache-warmup-cronjob.yaml",
+        include_str!("../../kubernetes/cache/cache-warmup-cronjob.yaml"),
+    ),
+    (
+        "deploy/code-deploy-job.yaml",
+        include_str!("../../kubernetes/deploy/code-deploy-job.yaml"),
+    ),
+    (
+        "export/static-export-job.yaml",
+        include_str!("../../kubernetes/export/static-export-job.yaml"),
+    ),
+    (
+        "hooks/hook-job.yaml",
+        include_str!("../../kubernetes/hooks/hook-job.yaml"),
+    ),
+    (
+        "import/hosting-import-job.yaml",
+        include_str!("../../kubernetes/import/hosting-import-job.yaml"),
+    ),
+    (
+        "import/hosting-inspect-job.yaml",
+        include_str!("../../kubernetes/import/hosting-inspect-job.yaml"),
+    ),
+    (
+        "library/library-link-container.yaml",
+        include_str!("../../kubernetes/library/library-link-container.yaml"),
+    ),
+    (
+        "library/library-pv.yaml",
+        include_str!("../../kubernetes/library/library-pv.yaml"),
+    ),
+    (
+        "library/library-pvc.yaml",
+        include_str!("../../kubernetes/library/library-pvc.yaml"),
+    ),
+    (
+        "library/library-sync-job.yaml",
+        include_str!("../../kubernetes/library/library-sync-job.yaml"),
+    ),
+    (
+        "mariadb/database-job.yaml",
+        include_str!("../../kubernetes/mariadb/database-job.yaml"),
+    ),
+    (
+        "mariadb/mariadb-deployment.yaml",
+        include_str!("../../kubernetes/mariadb/mariadb-deployment.yaml"),
+    ),
+    (
+        "mariadb/mariadb-pv.yaml",
+        include_str!("../../kubernetes/mariadb/mariadb-pv.yaml"),
+    ),
+    (
+        "mariadb/mariadb-pvc.yaml",
+        include_str!("../../kubernetes/mariadb/mariadb-pvc.yaml"),
+    ),
+    (
+        "mariadb/mariadb-secret.yaml",
+        include_str!("../../kubernetes/mariadb/mariadb-secret.yaml"),
+    ),
+    (
+        "mariadb/mariadb-svc.yaml",
+        include_str!("../../kubernetes/mariadb/mariadb-svc.yaml"),
+    ),
+    (
+        "mariadb/promote-job.yaml",
+        include_str!("../../kubernetes/mariadb/promote-job.yaml"),
+    ),
+    (
+        "media/optimize-images-job.yaml",
+        include_str!("../../kubernetes/media/optimize-images-job.yaml"),
+    ),
+    (
+        "profile/profile-job.yaml",
+        include_str!("../../kubernetes/profile/profile-job.yaml"),
+    ),
+    (
+        "remediation/site-repair-job.yaml",
+        include_str!("../../kubernetes/remediation/site-repair-job.yaml"),
+    ),
+    (
+        "security/malware-scan-cronjob.yaml",
+        include_str!("../../kubernetes/security/malware-scan-cronjob.yaml"),
+    ),
+    (
+        "security/verify-checksums-cronjob.yaml",
+        include_str!("../../kubernetes/security/verify-checksums-cronjob.yaml"),
+    ),
+    (
+        "wordpress/wp-deployment.yaml",
+        include_str!("../../kubernetes/wordpress/wp-deployment.yaml"),
+    ),
+    (
+        "wordpress/wp-healthz-config.yaml",
+        include_str!("../../kubernetes/wordpress/wp-healthz-config.yaml"),
+    ),
+    (
+        "wordpress/wp-ingress.yaml",
+        include_str!("../../kubernetes/wordpress/wp-ingress.yaml"),
+    ),
+    (
+        "wordpress/wp-nginx-config.yaml",
+        include_str!("../../kubernetes/wordpress/wp-nginx-config.yaml"),
+    ),
+    (
+        "wordpress/wp-php-errors-ini-config.yaml",
+        include_str!("../../kubernetes/wordpress/wp-php-errors-ini-config.yaml"),
+    ),
+    (
+        "wordpress/wp-pv.yaml",
+        include_str!("../../kubernetes/wordpress/wp-pv.yaml"),
+    ),
+    (
+        "wordpress/wp-pvc.yaml",
+        include_str!("../../kubernetes/wordpress/wp-pvc.yaml"),
+    ),
+    (
+        "wordpress/wp-secret.yaml",
+        include_str!("../../kubernetes/wordpress/wp-secret.yaml"),
+    ),
+    (
+        "wordpress/wp-service.yaml",
+        include_str!("../../kubernetes/wordpress/wp-service.yaml"),
+    ),
+    (
+        "wordpress/wp-uploads-ini-config.yaml",
+        include_str!("../../kubernetes/wordpress/wp-uploads-ini-config.yaml"),
+    ),
+    (
+        "wp-cli/wp-cli-job.yaml",
+        include_str!("../../kubernetes/wp-cli/wp-cli-job.yaml"),
+    ),
+];
+
+#[derive(Clone, Debug)]
+pub struct BundleOptions {
+    /// Platforms to include from multi-arch images.
+    pub architectures: Vec<String>,
+    /// Images to bundle on top of the ones referenced by the manifests, e.g. hook images.
+    pub extra_images: Vec<String>,
+}
+
+impl Default for BundleOptions {
+    fn default() -> Self {
+        Self {
+            architectures: vec!["amd64".to_string(), "arm64".to_string()],
+            extra_images: Vec::new(),
+        }
+    }
+}
+
+/// Images referenced by the bundled manifests.
+pub fn required_images() -> BTreeSet<String> {
+    MANIFESTS
+        .iter()
+        .flat_map(|(_, manifest)| manifest.lines())
+        .filter_map(|line| {
+            let line = line.trim().trim_start_matches("- ");
+            line.strip_prefix("image:")
+        })
+        .map(|image| image.trim().trim_matches('"').to_string())
+        .filter(|image| !image.is_empty())
+        .collect()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf> {
+    let hex = digest
+        .strip_prefix("sha256:")
+        .filter(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .with_context(|| format!("unsupported digest {}", digest))?;
+    Ok(layout.join("blobs").join("sha256").join(hex))
+}
+
+async fn write_blob(layout: &Path, bytes: &[u8]) -> Result<(String, usize)> {
+    let digest = format!("sha256:{}", sha256_hex(bytes));
+    tokio::fs::write(blob_path(layout, &digest)?, bytes).await?;
+    Ok((digest, bytes.len()))
+}
+
+fn manifest_media_type(manifest: &Value) -> &str {
+    manifest.get("mediaType").and_then(Value::as_str).unwrap_or(
+        if manifest.get("manifests").is_some() {
+            OCI_INDEX_TYPE
+        } else {
+            OCI_MANIFEST_TYPE
+        },
+    )
+}
+
+/// Keeps the index entries for the wanted architectures, dropping attestations.
+pub fn filter_index(index: &Value, architectures: &[String]) -> Value {
+    let mut index = index.clone();
+    if let Some(manifests) = index.get_mut("manifests").and_then(Value::as_array_mut) {
+        manifests.retain(|m| {
+            m.pointer("/platform/architecture")
+                .and_then(Value::as_str)
+                .is_some_and(|arch| architectures.iter().any(|a| a == arch))
+        });
+    }
+    index
+}
+
+async fn copy_image_manifest(
+    registry: &mut Registry,
+    layout: &Path,
+    manifest: &[u8],
+) -> Result<()> {
+    let manifest_json: Value = serde_json::from_slice(manifest)?;
+    let blobs = manifest_json
+        .get("config")
+        .into_iter()
+        .chain(
+            manifest_json
+                .get("layers")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten(),
+        )
+        .filter_map(|descriptor| descriptor.get("digest")?.as_str());
+
+    for digest in blobs {
+        let path = blob_path(layout, digest)?;
+        if tokio::fs::try_exists(&path).await? {
+            continue;
+        }
+        let blob = registry.blob(digest).await?;
+        if format!("sha256:{}", sha256_hex(&blob)) != digest {
+            bail!("blob {} does not match its digest", digest);
+        }
+        tokio::fs::write(path, blob).await?;
+    }
+
+    write_blob(layout, manifest).await?;
+    Ok(())
+}
+
+/// Downloads `image` into the OCI layout and returns its descriptor for `index.json`.
+async fn copy_image(layout: &Path, image: &str, architectures: &[String]) -> Result<Value> {
+    let reference = ImageReference::parse(image)?;
+    let mut registry = Registry::new(&reference.registry, &reference.repository);
+
+    let top = registry
+        .manifest(&reference.reference, MANIFEST_TYPES)
+        .await?;
+    let top_json: Value = serde_json::from_slice(&top)?;
+
+    let top = if top_json.get("manifests").is_some() {
+        let index = filter_index(&top_json, architectures);
+        let manifests = index["manifests"].as_array().cloned().unwrap_or_default();
+        if manifests.is_empty() {
+            bail!("{} is not published for {:?}", image, architectures);
+        }
+        for entry in manifests {
+            let digest = entry["digest"]
+                .as_str()
+                .context("index entry has no digest")?;
+            let manifest = registry.manifest(digest, MANIFEST_TYPES).await?;
+            if format!("sha256:{}", sha256_hex(&manifest)) != digest {
+                bail!("manifest {} does not match its digest", digest);
+            }
+            copy_image_manifest(&mut registry, layout, &manifest).await?;
+        }
+        serde_json::to_vec(&index)?
+    } else {
+        copy_image_manifest(&mut registry, layout, &top).await?;
+        top
+    };
+
+    let (digest, size) = write_blob(layout, &top).await?;
+    Ok(json!({
+        "mediaType": manifest_media_type(&top_json),
+        "digest": digest,
+        "size": size,
+        "annotations": { REF_NAME_ANNOTATION: image },
+    }))
+}
+
+async fn files_below(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Writes manifests, images as an OCI layout and a `SHA256SUMS` file into `dir` and packs it
+/// into `<dir>.tar.gz` for transfer into an air-gapped cluster.
+pub async fn create_bundle(dir: &Path, options: &BundleOptions) -> Result<PathBuf> {
+    if tokio::fs::try_exists(dir).await? {
+        bail!("{} already exists", dir.display());
+    }
+
+    for (path, manifest) in MANIFESTS {
+        let path = dir.join("manifests").join(path);
+        tokio::fs::create_dir_all(path.parent().context("manifest has no parent")?).await?;
+        tokio::fs::write(path, manifest).await?;
+    }
+
+    let layout = dir.join("images");
+    tokio::fs::create_dir_all(layout.join("blobs").join("sha256")).await?;
+    tokio::fs::write(
+        layout.join("oci-layout"),
+        json!({ "imageLayoutVersion": "1.0.0" }).to_string(),
+    )
+    .await?;
+
+    let mut images = required_images();
+    images.extend(options.extra_images.iter().cloned());
+    let mut descriptors = Vec::new();
+    for image in &images {
+        descriptors.push(
+            copy_image(&layout, image, &options.architectures)
+                .await
+                .with_context(|| format!("failed to bundle {}", image))?,
+        );
+    }
+    tokio::fs::write(
+        layout.join("index.json"),
+        serde_json::to_vec_pretty(&json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_INDEX_TYPE,
+            "manifests": descriptors,
+        }))?,
+    )
+    .await?;
+
+    let mut checksums = String::new();
+    for file in files_below(dir).await? {
+        let bytes = tokio::fs::read(&file).await?;
+        let relative = file.strip_prefix(dir)?;
+        checksums.push_str(&format!("{}  {}\n", sha256_hex(&bytes), relative.display()));
+    }
+    tokio::fs::write(dir.join(CHECKSUMS_FILE), checksums).await?;
+
+    let archive = dir.with_extension("tar.gz");
+    let parent = dir
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = dir.file_name().context("bundle directory has no name")?;
+    let status = tokio::process::Command::new("tar")
+        .arg("-czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(parent)
+        .arg(name)
+        .status()
+        .await
+        .context("failed to run tar")?;
+    if !status.success() {
+        bail!("tar exited with {}", status);
+    }
+
+    Ok(archive)
+}
+
+/// Checks the files of an extracted bundle against its `SHA256SUMS`.
+pub async fn verify_bundle(dir: &Path) -> Result<()> {
+    let checksums = tokio::fs::read_to_string(dir.join(CHECKSUMS_FILE))
+        .await
+        .with_context(|| format!("{} has no {}", dir.display(), CHECKSUMS_FILE))?;
+
+    for line in checksums.lines().filter(|line| !line.is_empty()) {
+        let (expected, path) = line
+            .split_once("  ")
+            .with_context(|| format!("invalid checksum line: {}", line))?;
+        if path.split('/').any(|c| c == "..") {
+            bail!("invalid path in checksums: {}", path);
+        }
+        let bytes = tokio::fs::read(dir.join(path))
+            .await
+            .with_context(|| format!("bundle is missing {}", path))?;
+        if sha256_hex(&bytes) != expected {
+            bail!("checksum mismatch for {}", path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Pushes the manifest `digest` of the layout and everything it refers to under `reference`,
+/// and returns the reference it was pushed under. A digest reference is replaced by the digest
+/// of the pushed bytes, which differs from the original one for indexes filtered by
+/// architecture.
+async fn push_manifest_tree(
+    registry: &mut Registry,
+    layout: &Path,
+    digest: &str,
+    reference: &str,
+) -> Result<String> {
+    let manifest = tokio::fs::read(blob_path(layout, digest)?).await?;
+    let manifest_json: Value = serde_json::from_slice(&manifest)?;
+    let pushed_digest = format!("sha256:{}", sha256_hex(&manifest));
+    if pushed_digest != digest {
+        bail!("manifest {} does not match its digest", digest);
+    }
+    let reference = if reference.starts_with("sha256:") {
+        pushed_digest
+    } else {
+        reference.to_string()
+    };
+
+    if let Some(entries) = manifest_json.get("manifests").and_then(Value::as_array) {
+        for entry in entries {
+            let child = entry["digest"]
+                .as_str()
+                .context("index entry has no digest")?;
+            Box::pin(push_manifest_tree(registry, layout, child, child)).await?;
+        }
+    } else {
+        let blobs = manifest_json
+            .get("config")
+            .into_iter()
+            .chain(
+                manifest_json
+                    .get("layers")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten(),
+            )
+            .filter_map(|descriptor| descriptor.get("digest")?.as_str());
+        for blob in blobs {
+            let bytes = tokio::fs::read(blob_path(layout, blob)?).await?;
+            registry.push_blob(blob, bytes).await?;
+        }
+    }
+
+    registry
+        .push_manifest(&reference, manifest_media_type(&manifest_json), manifest)
+        .await?;
+    Ok(reference)
+}
+
+/// Verifies an extracted bundle and pushes its images to `registry` under their original
+/// repository and tag, as expected by a client configured with `with_image_mirror`. Returns
+/// the pushed images, those pinned by digest with the digest of their bundled index.
+pub async fn install_bundle(
+    dir: &Path,
+    registry: &str,
+    credentials: Option<RegistryCredentials>,
+) -> Result<Vec<String>> {
+    verify_bundle(dir).await?;
+
+    let layout = dir.join("images");
+    let index: Value = serde_json::from_slice(&tokio::fs::read(layout.join("index.json")).await?)?;
+
+    let mut pushed = Vec::new();
+    for descriptor in index["manifests"].as_array().into_iter().flatten() {
+        let image = descriptor
+            .pointer(&format!(
+                "/annotations/{}",
+                REF_NAME_ANNOTATION.replace('/', "~1")
+            ))
+            .and_then(Value::as_str)
+            .context("bundled image has no reference name")?;
+        let digest = descriptor["digest"]
+            .as_str()
+            .context("bundled image has no digest")?;
+        let reference = ImageReference::parse(image)?;
+
+        let mut target =
+            Registry::new(registry, &reference.repository).with_credentials(credentials.clone());
+        let pushed_reference =
+            push_manifest_tree(&mut target, &layout, digest, &reference.reference)
+                .await
+                .with_context(|| format!("failed to push {}", image))?;
+        pushed.push(
+            ImageReference {
+                reference: pushed_reference,
+                ..reference
+            }
+            .mirrored(registry),
+        );
+    }
+
+    Ok(pushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_images() {
+        let images = required_images();
+
+        assert!(images.contains("wordpress:6-fpm-alpine"));
+        assert!(images.contains("nginx:alpine"));
+        assert!(images.contains("mariadb:10.11"));
+        assert!(images.iter().all(|image| !image.contains(' ')));
+    }
+
+    #[test]
+    fn test_manifests_cover_all_templates() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../kubernetes");
+        let mut templates = Vec::new();
+        for dir in std::fs::read_dir(&root).unwrap() {
+            let dir = dir.unwrap().path();
+            for file in std::fs::read_dir(&dir).unwrap() {
+                let file = file.unwrap().path();
+                templates.push(file.strip_prefix(&root).unwrap().display().to_string());
+            }
+        }
+        templates.sort();
+
+        let bundled: Vec<String> = MANIFESTS.iter().map(|(path, _)| path.to_string()).collect();
+        assert_eq!(bundled, templates);
+    }
+
+    #[test]
+    fn test_filter_index() {
+        let index = json!({
+            "manifests": [
+                { "digest": "sha256:a", "platform": { "architecture": "amd64" } },
+                { "digest": "sha256:b", "platform": { "architecture": "s390x" } },
+                { "digest": "sha256:c", "platform": { "architecture": "unknown" } },
+            ]
+        });
+
+        let filtered = filter_index(&index, &["amd64".to_string(), "arm64".to_string()]);
+        assert_eq!(filtered["manifests"].as_array().unwrap().len(), 1);
+        assert_eq!(filtered["manifests"][0]["digest"], "sha256:a");
+    }
+
+    #[tokio::test]
+    async fn test_verify_bundle() {
+        let dir = std::env::temp_dir().join(format!("kwpm-bundle-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(dir.join("manifests"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("manifests/a.yaml"), "kind: Service\n")
+            .await
+            .unwrap();
+        tokio::fs::write(
+            dir.join(CHECKSUMS_FILE),
+            format!("{}  manifests/a.yaml\n", sha256_hex(b"kind: Service\n")),
+        )
+        .await
+        .unwrap();
+
+        assert!(verify_bundle(&dir).await.is_ok());
+
+        tokio::fs::write(dir.join("manifests/a.yaml"), "kind: Secret\n")
+            .await
+            .unwrap();
+        assert!(verify_bundle(&dir).await.is_err());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
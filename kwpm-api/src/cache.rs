@@ -0,0 +1,138 @@
+use anyhow::Context;
+use k8s_openapi::api::batch::v1::CronJob;
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+
+use crate::{
+    error::Result,
+    manifest::{cron_job_pod_spec_mut, job_from_cron_job, set_env},
+    KwpmClient,
+};
+
+const CACHE_WARMUP_NAME: &str = "kwpm-cache-warmup";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheWarmup {
+    pub schedule: String,
+    pub sitemap_path: String,
+    pub max_urls: u32,
+    pub concurrency: u32,
+}
+
+impl Default for CacheWarmup {
+    fn default() -> Self {
+        Self {
+            schedule: "0 * * * *".to_string(),
+            sitemap_path: "/wp-sitemap.xml".to_string(),
+            max_urls: 500,
+            concurrency: 4,
+        }
+    }
+}
+
+pub fn cache_warmup_cron_job(site: &str, site_url: &str, warmup: &CacheWarmup) -> Result<CronJob> {
+    let mut cron_job: CronJob = serde_yaml::from_str(include_str!(
+        "../../kubernetes/cache/cache-warmup-cronjob.yaml"
+    ))?;
+
+    cron_job
+        .metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+    if let Some(spec) = cron_job.spec.as_mut() {
+        spec.schedule = warmup.schedule.clone();
+    }
+
+    set_env(
+        cron_job_pod_spec_mut(&mut cron_job)?,
+        "warmup",
+        &[
+            ("SITE_URL", site_url.to_string()),
+            ("SITEMAP_PATH", warmup.sitemap_path.clone()),
+            ("MAX_URLS", warmup.max_urls.to_string()),
+            ("CONCURRENCY", warmup.concurrency.max(1).to_string()),
+        ],
+    )?;
+
+    Ok(cron_job)
+}
+
+impl KwpmClient {
+    /// Installs, updates or (with `None`) removes the periodic sitemap crawl for a site.
+    pub async fn configure_cache_warmup(
+        &self,
+        site: &str,
+        warmup: Option<&CacheWarmup>,
+    ) -> Result<()> {
+        let cron_job_api: Api<CronJob> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let Some(warmup) = warmup else {
+            if cron_job_api.get_opt(CACHE_WARMUP_NAME).await?.is_some() {
+                cron_job_api
+                    .delete(CACHE_WARMUP_NAME, &Default::default())
+                    .await?;
+            }
+            self.record_site_revision(site, "configure_cache_warmup")
+                .await?;
+            return Ok(());
+        };
+
+        let site_url = self.site_url(site).await?;
+        let mut cron_job = cache_warmup_cron_job(site, &site_url, warmup)?;
+        self.prepare_pod_spec(cron_job_pod_spec_mut(&mut cron_job)?);
+        cron_job_api
+            .patch(
+                CACHE_WARMUP_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&cron_job)),
+            )
+            .await?;
+
+        self.record_site_revision(site, "configure_cache_warmup")
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the configured warmup immediately, e.g. right after a deploy or cache purge.
+    pub async fn warm_cache_now(&self, site: &str) -> Result<String> {
+        let ns_name = self.site_namespace(site);
+        let cron_job_api: Api<CronJob> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let cron_job = cron_job_api
+            .get_opt(CACHE_WARMUP_NAME)
+            .await?
+            .with_context(|| format!("cache warmup is not configured for {}", site))?;
+        let job = job_from_cron_job(cron_job)?;
+        let job = self.create_job(&ns_name, job).await?;
+
+        Ok(job.metadata.name.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_warmup_cron_job() {
+        let warmup = CacheWarmup {
+            schedule: "*/30 * * * *".to_string(),
+            ..Default::default()
+        };
+        let mut cron_job =
+            cache_warmup_cron_job("blog", "https://blog.example.com/", &warmup).unwrap();
+
+        assert_eq!(cron_job.spec.as_ref().unwrap().schedule, "*/30 * * * *");
+
+        let env = cron_job_pod_spec_mut(&mut cron_job).unwrap().containers[0]
+            .env
+            .clone()
+            .unwrap();
+        let site_url = env.iter().find(|e| e.name == "SITE_URL").unwrap();
+        assert_eq!(site_url.value.as_deref(), Some("https://blog.example.com/"));
+    }
+}
@@ -0,0 +1,392 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::{
+    api::{
+        apps::v1::{Deployment, DeploymentSpec},
+        core::v1::{Pod, Service},
+        networking::v1::Ingress,
+    },
+    apimachinery::pkg::apis::meta::v1::LabelSelector,
+};
+use kube::{
+    api::{DeleteParams, ListParams, LogParams, ObjectMeta, Patch, PatchParams},
+    Api,
+};
+
+use crate::{
+    access::is_site_ingress,
+    error::{bail, Result},
+    manifest::deployment_pod_spec_mut,
+    KwpmClient,
+};
+
+pub(crate) const CANARY_NAME: &str = "wordpress-canary";
+const CANARY_TIER: &str = "canary";
+const ROLLOUT_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug)]
+pub struct CanaryOptions {
+    /// Percentage of requests routed to the new version.
+    pub weight: u8,
+    pub analysis_duration: Duration,
+    /// Share of 5xx responses from the canary above which the upgrade is aborted.
+    pub max_error_rate: f64,
+    /// Requests the canary has to serve before its error rate is trusted.
+    pub min_requests: usize,
+}
+
+impl Default for CanaryOptions {
+    fn default() -> Self {
+        Self {
+            weight: 10,
+            analysis_duration: Duration::from_secs(300),
+            max_error_rate: 0.05,
+            min_requests: 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CanaryReport {
+    pub requests: usize,
+    pub errors: usize,
+    pub promoted: bool,
+    pub abort_reason: Option<String>,
+}
+
+impl CanaryReport {
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.requests as f64
+    }
+}
+
+/// Counts requests and 5xx responses in nginx access log lines of the default combined format.
+pub fn count_access_log_errors(log: &str) -> (usize, usize) {
+    let statuses = log.lines().filter_map(|line| {
+        let after_request = line.splitn(3, '"').nth(2)?;
+        after_request.split_whitespace().next()?.parse::<u16>().ok()
+    });
+
+    statuses.fold((0, 0), |(requests, errors), status| {
+        (requests + 1, errors + usize::from(status >= 500))
+    })
+}
+
+fn canary_labels() -> BTreeMap<String, String> {
+    [
+        ("app".to_string(), "wordpress".to_string()),
+        ("tier".to_string(), CANARY_TIER.to_string()),
+    ]
+    .into_iter()
+    .collect()
+}
+
+pub(crate) fn set_wordpress_image(deployment: &mut Deployment, image: &str) -> Result<()> {
+    let wordpress = deployment_pod_spec_mut(deployment)?
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "wordpress")
+        .context("deployment has no wordpress container")?;
+    wordpress.image = Some(image.to_string());
+
+    Ok(())
+}
+
+/// Builds a single-replica copy of the site's deployment running `image`, together with the
+/// service and weighted canary ingress routing part of the traffic to it.
+pub fn canary_resources(
+    deployment: &Deployment,
+    ingress: &Ingress,
+    image: &str,
+    weight: u8,
+) -> Result<(Deployment, Service, Ingress)> {
+    if weight == 0 || weight > 100 {
+        bail!("canary weight must be between 1 and 100");
+    }
+
+    let spec = deployment.spec.as_ref().context("deployment has no spec")?;
+    let mut template = spec.template.clone();
+    template
+        .metadata
+        .get_or_insert_with(Default::default)
+        .labels = Some(canary_labels());
+
+    let mut canary = Deployment {
+        metadata: ObjectMeta {
+            name: Some(CANARY_NAME.to_string()),
+            labels: Some(canary_labels()),
+            ..Default::default()
+        },
+        spec: Some(DeploymentSpec {
+            replicas: Some(1),
+            selector: LabelSelector {
+                match_labels: Some(canary_labels()),
+                ..Default::default()
+            },
+            template,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    set_wordpress_image(&mut canary, image)?;
+
+    let mut service: Service =
+        serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-service.yaml"))?;
+    service.metadata.name = Some(CANARY_NAME.to_string());
+    if let Some(service_spec) = service.spec.as_mut() {
+        service_spec.selector = Some(canary_labels());
+        service_spec.type_ = Some("ClusterIP".to_string());
+    }
+
+    let mut annotations = ingress.metadata.annotations.clone().unwrap_or_default();
+    annotations.insert(
+        "nginx.ingress.kubernetes.io/canary".to_string(),
+        "true".to_string(),
+    );
+    annotations.insert(
+        "nginx.ingress.kubernetes.io/canary-weight".to_string(),
+        weight.to_string(),
+    );
+
+    let mut ingress_spec = ingress.spec.clone().context("ingress has no spec")?;
+    for path in ingress_spec
+        .rules
+        .iter_mut()
+        .flatten()
+        .filter_map(|rule| rule.http.as_mut())
+        .flat_map(|http| http.paths.iter_mut())
+    {
+        if let Some(backend) = path.backend.service.as_mut() {
+            backend.name = CANARY_NAME.to_string();
+        }
+    }
+
+    let canary_ingress = Ingress {
+        metadata: ObjectMeta {
+            name: Some(CANARY_NAME.to_string()),
+            annotations: Some(annotations),
+            ..Default::default()
+        },
+        spec: Some(ingress_spec),
+        ..Default::default()
+    };
+
+    Ok((canary, service, canary_ingress))
+}
+
+impl KwpmClient {
+    async fn remove_canary(&self, ns_name: &str) -> Result<()> {
+        let dp = DeleteParams::default();
+
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), ns_name);
+        if ingress_api.get_opt(CANARY_NAME).await?.is_some() {
+            ingress_api.delete(CANARY_NAME, &dp).await?;
+        }
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), ns_name);
+        if service_api.get_opt(CANARY_NAME).await?.is_some() {
+            service_api.delete(CANARY_NAME, &dp).await?;
+        }
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), ns_name);
+        if deployment_api.get_opt(CANARY_NAME).await?.is_some() {
+            deployment_api.delete(CANARY_NAME, &dp).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn analyze_canary(&self, ns_name: &str, since: Duration) -> Result<(usize, usize)> {
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), ns_name);
+        let pods = pod_api
+            .list(&ListParams::default().labels(&format!("app=wordpress,tier={}", CANARY_TIER)))
+            .await?;
+
+        let mut totals = (0, 0);
+        for pod in pods {
+            let log = pod_api
+                .logs(
+                    &pod.metadata.name.unwrap_or_default(),
+                    &LogParams {
+                        container: Some("nginx".to_string()),
+                        since_seconds: Some(since.as_secs() as i64),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            let (requests, errors) = count_access_log_errors(&log);
+            totals = (totals.0 + requests, totals.1 + errors);
+        }
+
+        Ok(totals)
+    }
+
+    /// Upgrades the site's WordPress image through an nginx-ingress canary: a share of the
+    /// traffic is routed to the new image and the upgrade is only promoted when the canary's
+    /// error rate stays under the threshold. Clusters without ingress-nginx are not supported.
+    pub async fn canary_upgrade(
+        &self,
+        site: &str,
+        image: &str,
+        options: &CanaryOptions,
+    ) -> Result<CanaryReport> {
+        let ns_name = self.site_namespace(site);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let deployment = deployment_api.get("wordpress").await?;
+        let ingresses = ingress_api.list(&Default::default()).await?;
+        let Some(ingress) = ingresses.items.iter().find(|i| is_site_ingress(i)) else {
+            bail!("{} has no ingress to split traffic on", site);
+        };
+
+        let (mut canary, service, canary_ingress) =
+            canary_resources(&deployment, ingress, image, options.weight)?;
+        self.prepare_pod_spec(deployment_pod_spec_mut(&mut canary)?);
+        self.check_policy(
+            "canary_upgrade",
+            Some(site),
+            &[
+                serde_json::to_value(&canary)?,
+                serde_json::to_value(&service)?,
+                serde_json::to_value(&canary_ingress)?,
+            ],
+        )
+        .await?;
+
+        // The canary is removed however this ends, so no traffic stays split onto it.
+        let result: Result<_> = async {
+            let pp = PatchParams::apply("kwpm").force();
+            deployment_api
+                .patch(CANARY_NAME, &pp, &Patch::Apply(&self.labeled(&canary)))
+                .await?;
+            service_api
+                .patch(CANARY_NAME, &pp, &Patch::Apply(&self.labeled(&service)))
+                .await?;
+
+            if let Err(e) = self
+                .wait_for_deployment(&ns_name, CANARY_NAME, ROLLOUT_TIMEOUT)
+                .await
+            {
+                return Ok(CanaryReport {
+                    abort_reason: Some(format!("canary did not become available: {:#}", e)),
+                    ..Default::default()
+                });
+            }
+
+            ingress_api
+                .patch(
+                    CANARY_NAME,
+                    &pp,
+                    &Patch::Apply(&self.labeled(&canary_ingress)),
+                )
+                .await?;
+            tokio::time::sleep(options.analysis_duration).await;
+
+            let (requests, errors) = self
+                .analyze_canary(&ns_name, options.analysis_duration)
+                .await?;
+            let mut report = CanaryReport {
+                requests,
+                errors,
+                ..Default::default()
+            };
+
+            if requests < options.min_requests {
+                report.abort_reason = Some(format!(
+                    "canary served {} requests, {} needed for a decision",
+                    requests, options.min_requests
+                ));
+            } else if report.error_rate() > options.max_error_rate {
+                report.abort_reason = Some(format!(
+                    "canary error rate {:.1}% exceeds {:.1}%",
+                    report.error_rate() * 100.0,
+                    options.max_error_rate * 100.0
+                ));
+            }
+
+            if report.abort_reason.is_none() {
+                let mut deployment = deployment_api.get("wordpress").await?;
+                set_wordpress_image(&mut deployment, image)?;
+                deployment_api
+                    .replace("wordpress", &Default::default(), &deployment)
+                    .await?;
+                self.wait_for_deployment(&ns_name, "wordpress", ROLLOUT_TIMEOUT)
+                    .await?;
+                report.promoted = true;
+            }
+
+            Ok(report)
+        }
+        .await;
+
+        let removed = self.remove_canary(&ns_name).await;
+        match result {
+            Ok(report) => removed.map(|()| report),
+            Err(e) => {
+                if let Err(removal) = removed {
+                    tracing::warn!(
+                        site = %site,
+                        error = %format!("{:#}", removal),
+                        "removing the canary failed"
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_access_log_errors() {
+        let log = r#"10.0.0.1 - - [01/Jan/2024:00:00:00 +0000] "GET / HTTP/1.1" 200 512 "-" "curl/8.6.0" "-"
+10.0.0.1 - - [01/Jan/2024:00:00:01 +0000] "GET /wp-admin/ HTTP/1.1" 502 157 "-" "curl/8.6.0" "-"
+10.0.0.1 - - [01/Jan/2024:00:00:02 +0000] "POST /wp-login.php HTTP/1.1" 302 0 "-" "curl/8.6.0" "-"
+2024/01/01 00:00:03 [error] 29#29: *1 connect() failed (111: Connection refused)
+"#;
+
+        assert_eq!(count_access_log_errors(log), (3, 1));
+    }
+
+    #[test]
+    fn test_canary_resources() {
+        let deployment: Deployment = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap();
+        let ingress: Ingress =
+            serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-ingress.yaml"))
+                .unwrap();
+
+        let (canary, service, canary_ingress) =
+            canary_resources(&deployment, &ingress, "wordpress:6.5-fpm-alpine", 20).unwrap();
+
+        let canary_spec = canary.spec.unwrap();
+        assert_eq!(canary_spec.selector.match_labels, Some(canary_labels()));
+        let wordpress = &canary_spec.template.spec.unwrap().containers[0];
+        assert_eq!(wordpress.image.as_deref(), Some("wordpress:6.5-fpm-alpine"));
+        assert_eq!(service.spec.unwrap().selector, Some(canary_labels()));
+
+        let annotations = canary_ingress.metadata.annotations.unwrap();
+        assert_eq!(
+            annotations["nginx.ingress.kubernetes.io/canary-weight"],
+            "20"
+        );
+        let rules = canary_ingress.spec.unwrap().rules.unwrap();
+        let backend = rules[0].http.as_ref().unwrap().paths[0]
+            .backend
+            .service
+            .as_ref()
+            .unwrap();
+        assert_eq!(backend.name, CANARY_NAME);
+
+        assert!(canary_resources(&deployment, &ingress, "wordpress:6.5", 0).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{
+    batch::v1::{CronJob, Job},
+    core::v1::ObjectReference,
+};
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Result,
+    job::job_finished_at,
+    manifest::{cron_job_pod_spec_mut, job_from_cron_job},
+    KwpmClient,
+};
+
+const VERIFY_CHECKSUMS_NAME: &str = "kwpm-verify-checksums";
+const VERIFY_CHECKSUMS_TIMEOUT: Duration = Duration::from_secs(900);
+/// Summary lines wp-cli prints after the individual mismatches.
+const SUMMARY_PREFIXES: [&str; 3] = [
+    "Success: ",
+    "Error: WordPress installation doesn't verify",
+    "Error: Only verified",
+];
+
+/// A file that differs from the checksums published on wordpress.org.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChecksumMismatch {
+    /// `core`, or the slug of the plugin the file belongs to.
+    pub component: String,
+    pub file: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChecksumReport {
+    pub job: String,
+    pub checked_at: Option<DateTime<Utc>>,
+    pub mismatches: Vec<ChecksumMismatch>,
+    /// Problems that kept wp-cli from verifying, e.g. an unreachable database.
+    pub errors: Vec<String>,
+}
+
+impl ChecksumReport {
+    pub fn modified_core_files(&self) -> Vec<&str> {
+        self.mismatches
+            .iter()
+            .filter(|m| m.component == "core")
+            .map(|m| m.file.as_str())
+            .collect()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty() && self.errors.is_empty()
+    }
+}
+
+fn unquote(field: &str) -> String {
+    field.trim().trim_matches('"').to_string()
+}
+
+/// Parses the output of the verification job into mismatches and errors.
+pub fn parse_checksum_output(output: &str) -> (Vec<ChecksumMismatch>, Vec<String>) {
+    let mut mismatches = Vec::new();
+    let mut errors = Vec::new();
+    let mut section = "";
+
+    for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(name) = line.strip_prefix("--- ") {
+            section = name;
+            continue;
+        }
+        if SUMMARY_PREFIXES.iter().any(|p| line.starts_with(p)) {
+            continue;
+        }
+        if let Some(error) = line.strip_prefix("Error: ") {
+            errors.push(error.to_string());
+            continue;
+        }
+
+        match section {
+            // e.g. `Warning: File doesn't verify against checksum: wp-includes/version.php`
+            "core" => {
+                if let Some((message, file)) = line
+                    .strip_prefix("Warning: ")
+                    .and_then(|warning| warning.rsplit_once(": "))
+                {
+                    mismatches.push(ChecksumMismatch {
+                        component: "core".to_string(),
+                        file: file.to_string(),
+                        message: message.to_string(),
+                    });
+                }
+            }
+            // CSV rows of `plugin_name,file,message`; warnings are plugins without published
+            // checksums, which can't be verified.
+            "plugins" if !line.starts_with("Warning: ") && line != "plugin_name,file,message" => {
+                let fields: Vec<&str> = line.splitn(3, ',').collect();
+                if let [plugin, file, message] = fields[..] {
+                    mismatches.push(ChecksumMismatch {
+                        component: unquote(plugin),
+                        file: unquote(file),
+                        message: unquote(message),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (mismatches, errors)
+}
+
+pub fn verify_checksums_cron_job(site: &str, schedule: &str) -> Result<CronJob> {
+    let mut cron_job: CronJob = serde_yaml::from_str(include_str!(
+        "../../kubernetes/security/verify-checksums-cronjob.yaml"
+    ))?;
+
+    cron_job
+        .metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+    if let Some(spec) = cron_job.spec.as_mut() {
+        spec.schedule = schedule.to_string();
+    }
+
+    Ok(cron_job)
+}
+
+impl KwpmClient {
+    /// Installs, updates or (with `None`) removes the recurring core and plugin checksum
+    /// verification of a site.
+    pub async fn configure_checksum_verification(
+        &self,
+        site: &str,
+        schedule: Option<&str>,
+    ) -> Result<()> {
+        let cron_job_api: Api<CronJob> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let Some(schedule) = schedule else {
+            if cron_job_api.get_opt(VERIFY_CHECKSUMS_NAME).await?.is_some() {
+                cron_job_api
+                    .delete(VERIFY_CHECKSUMS_NAME, &Default::default())
+                    .await?;
+            }
+            self.record_site_revision(site, "configure_checksum_verification")
+                .await?;
+            return Ok(());
+        };
+
+        let mut cron_job = verify_checksums_cron_job(site, schedule)?;
+        self.prepare_pod_spec(cron_job_pod_spec_mut(&mut cron_job)?);
+        self.adapt_mariadb_host(
+            &self.site_namespace(site),
+            cron_job_pod_spec_mut(&mut cron_job)?,
+        )
+        .await?;
+        cron_job_api
+            .patch(
+                VERIFY_CHECKSUMS_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&cron_job)),
+            )
+            .await?;
+
+        self.record_site_revision(site, "configure_checksum_verification")
+            .await?;
+        Ok(())
+    }
+
+    async fn checksum_report(&self, namespace: &str, job: &Job) -> Result<ChecksumReport> {
+        let name = job.metadata.name.clone().unwrap_or_default();
+        let output = self.job_logs(namespace, &name).await?;
+        let (mismatches, errors) = parse_checksum_output(&output);
+
+        Ok(ChecksumReport {
+            job: name,
+            checked_at: job_finished_at(job),
+            mismatches,
+            errors,
+        })
+    }
+
+    /// The result of the most recent finished verification, if any.
+    pub async fn get_checksum_report(&self, site: &str) -> Result<Option<ChecksumReport>> {
+        let ns_name = self.site_namespace(site);
+
+        let selector = format!("app={}", VERIFY_CHECKSUMS_NAME);
+        let Some(job) = self.latest_completed_job(&ns_name, &selector).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(self.checksum_report(&ns_name, &job).await?))
+    }
+
+    /// Verifies checksums right away and records a warning event on the site's deployment
+    /// when files were modified.
+    pub async fn verify_checksums_now(&self, site: &str) -> Result<ChecksumReport> {
+        let ns_name = self.site_namespace(site);
+
+        let job = job_from_cron_job(verify_checksums_cron_job(site, "@daily")?)?;
+        let job = self.create_job(&ns_name, job).await?;
+        let job = self
+            .wait_for_job(
+                &ns_name,
+                &job.metadata.name.unwrap_or_default(),
+                VERIFY_CHECKSUMS_TIMEOUT,
+            )
+            .await?;
+        let report = self.checksum_report(&ns_name, &job).await?;
+
+        if !report.mismatches.is_empty() {
+            let files: Vec<String> = report
+                .mismatches
+                .iter()
+                .map(|m| format!("{} ({})", m.file, m.component))
+                .collect();
+            self.record_warning(
+                ObjectReference {
+                    api_version: Some("apps/v1".to_string()),
+                    kind: Some("Deployment".to_string()),
+                    name: Some("wordpress".to_string()),
+                    namespace: Some(ns_name),
+                    ..Default::default()
+                },
+                "ChecksumMismatch",
+                format!("modified files: {}", files.join(", ")),
+            )
+            .await?;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_checksum_output() {
+        let output = "\
+--- core
+Warning: File doesn't verify against checksum: wp-includes/version.php
+Warning: File should not exist: wp-admin/shell.php
+Error: WordPress installation doesn't verify against checksums.
+--- plugins
+Warning: Could not retrieve the checksums for version 1.0 of plugin custom, skipping.
+plugin_name,file,message
+akismet,akismet.php,\"Checksum does not match\"
+Error: Only verified 2 of 3 plugins (1 failed).
+";
+        let (mismatches, errors) = parse_checksum_output(output);
+
+        assert!(errors.is_empty());
+        assert_eq!(
+            mismatches,
+            vec![
+                ChecksumMismatch {
+                    component: "core".to_string(),
+                    file: "wp-includes/version.php".to_string(),
+                    message: "File doesn't verify against checksum".to_string(),
+                },
+                ChecksumMismatch {
+                    component: "core".to_string(),
+                    file: "wp-admin/shell.php".to_string(),
+                    message: "File should not exist".to_string(),
+                },
+                ChecksumMismatch {
+                    component: "akismet".to_string(),
+                    file: "akismet.php".to_string(),
+                    message: "Checksum does not match".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_checksum_output_errors() {
+        let output = "--- core\nError: Error establishing a database connection.\n--- plugins\nSuccess: Verified 0 of 0 plugins.\n";
+        let (mismatches, errors) = parse_checksum_output(output);
+
+        assert!(mismatches.is_empty());
+        assert_eq!(errors, ["Error establishing a database connection."]);
+    }
+}
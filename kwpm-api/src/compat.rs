@@ -0,0 +1,218 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::Context;
+use k8s_openapi::{
+    api::core::v1::{Namespace, PodSecurityContext, PodSpec, SeccompProfile},
+    apimachinery::pkg::version::Info,
+};
+
+use crate::{
+    error::{bail, Result},
+    registry::ImageReference,
+    KwpmClient,
+};
+
+/// Oldest release that serves every API version kwpm generates (batch/v1 CronJob,
+/// networking.k8s.io/v1 Ingress, Job TTLs).
+pub const MIN_SUPPORTED_VERSION: ClusterVersion = ClusterVersion {
+    major: 1,
+    minor: 23,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClusterVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl fmt::Display for ClusterVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl ClusterVersion {
+    /// Reads the apiserver version, tolerating the `28+` style minors of managed clusters.
+    pub fn from_info(info: &Info) -> Result<Self> {
+        let number = |value: &str| -> Option<u32> {
+            value
+                .trim_end_matches(|c: char| !c.is_ascii_digit())
+                .parse()
+                .ok()
+        };
+
+        Ok(Self {
+            major: number(&info.major)
+                .with_context(|| format!("invalid Kubernetes major version {}", info.major))?,
+            minor: number(&info.minor)
+                .with_context(|| format!("invalid Kubernetes minor version {}", info.minor))?,
+        })
+    }
+
+    pub fn ensure_supported(&self) -> Result<()> {
+        if *self < MIN_SUPPORTED_VERSION {
+            bail!(
+                "Kubernetes {} is not supported, kwpm needs {} or newer",
+                self,
+                MIN_SUPPORTED_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    fn at_least(&self, minor: u32) -> bool {
+        *self >= ClusterVersion { major: 1, minor }
+    }
+
+    pub fn supports_pod_security_admission(&self) -> bool {
+        self.at_least(25)
+    }
+
+    /// Init containers with `restartPolicy: Always` run as sidecars.
+    pub fn supports_native_sidecars(&self) -> bool {
+        self.at_least(29)
+    }
+
+    /// Labels the namespace for Pod Security Admission where the cluster enforces it.
+    pub fn adapt_namespace(&self, namespace: &mut Namespace) {
+        if !self.supports_pod_security_admission() {
+            return;
+        }
+
+        let labels: BTreeMap<String, String> = [
+            ("pod-security.kubernetes.io/enforce", "baseline"),
+            ("pod-security.kubernetes.io/warn", "restricted"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        namespace
+            .metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .extend(labels);
+    }
+}
+
+/// Defaults every pod kwpm creates gets, unless its template sets them: the runtime's default
+/// seccomp profile, which every supported version accepts.
+pub fn apply_pod_defaults(pod_spec: &mut PodSpec) {
+    let security_context = pod_spec
+        .security_context
+        .get_or_insert_with(PodSecurityContext::default);
+    if security_context.seccomp_profile.is_none() {
+        security_context.seccomp_profile = Some(SeccompProfile {
+            type_: "RuntimeDefault".to_string(),
+            ..Default::default()
+        });
+    }
+}
+
+impl KwpmClient {
+    /// Prepares a pod spec kwpm is about to create: applies the pod defaults and pulls every
+    /// image through the configured mirror.
+    pub(crate) fn prepare_pod_spec(&self, pod_spec: &mut PodSpec) {
+        apply_pod_defaults(pod_spec);
+
+        let Some(mirror) = &self.image_mirror else {
+            return;
+        };
+        for container in pod_spec
+            .containers
+            .iter_mut()
+            .chain(pod_spec.init_containers.iter_mut().flatten())
+        {
+            if let Some(image) = container.image.as_mut() {
+                if let Ok(reference) = ImageReference::parse(image) {
+                    *image = reference.mirrored(mirror);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(major: &str, minor: &str) -> Info {
+        Info {
+            major: major.to_string(),
+            minor: minor.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_info() {
+        assert_eq!(
+            ClusterVersion::from_info(&info("1", "28+")).unwrap(),
+            ClusterVersion {
+                major: 1,
+                minor: 28
+            }
+        );
+        assert!(ClusterVersion::from_info(&info("1", "")).is_err());
+    }
+
+    #[test]
+    fn test_ensure_supported() {
+        let old = ClusterVersion::from_info(&info("1", "21")).unwrap();
+        let error = old.ensure_supported().unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Kubernetes 1.21 is not supported, kwpm needs 1.23 or newer"
+        );
+
+        assert!(ClusterVersion::from_info(&info("1", "29"))
+            .unwrap()
+            .ensure_supported()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_adapt_namespace() {
+        let mut namespace = Namespace::default();
+        ClusterVersion {
+            major: 1,
+            minor: 24,
+        }
+        .adapt_namespace(&mut namespace);
+        assert!(namespace.metadata.labels.is_none());
+
+        ClusterVersion {
+            major: 1,
+            minor: 25,
+        }
+        .adapt_namespace(&mut namespace);
+        assert_eq!(
+            namespace.metadata.labels.unwrap()["pod-security.kubernetes.io/enforce"],
+            "baseline"
+        );
+    }
+
+    #[test]
+    fn test_apply_pod_defaults() {
+        let mut pod_spec = PodSpec::default();
+        apply_pod_defaults(&mut pod_spec);
+        let profile = pod_spec
+            .security_context
+            .as_mut()
+            .unwrap()
+            .seccomp_profile
+            .as_mut();
+        assert_eq!(profile.as_ref().unwrap().type_, "RuntimeDefault");
+
+        profile.unwrap().type_ = "Unconfined".to_string();
+        apply_pod_defaults(&mut pod_spec);
+        assert_eq!(
+            pod_spec
+                .security_context
+                .unwrap()
+                .seccomp_profile
+                .unwrap()
+                .type_,
+            "Unconfined"
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        core::v1::{PersistentVolume, PersistentVolumeClaim, ResourceRequirements},
+    },
+    apimachinery::pkg::api::resource::Quantity,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    environment::{Environment, EnvironmentProfile},
+    error::{KwpmError, Result},
+    ingress::{parse_annotations, IngressManager},
+    install::{is_valid_locale, is_valid_timezone},
+    manifest::{deployment_pod_spec_mut, set_storage_class},
+    quota::DatabaseQuotaPolicy,
+    site::{is_valid_namespace_prefix, DEFAULT_NAMESPACE_PREFIX},
+    KwpmClient,
+};
+
+/// The `storageClassName` of the volumes and claims kwpm creates unless configured otherwise.
+pub const DEFAULT_STORAGE_CLASS: &str = "local-storage";
+
+/// CPU and memory of a container, as Kubernetes quantities such as `500m` or `1Gi`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResourceLimits {
+    pub cpu_request: Option<String>,
+    pub cpu_limit: Option<String>,
+    pub memory_request: Option<String>,
+    pub memory_limit: Option<String>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn to_requirements(&self) -> ResourceRequirements {
+        let quantities = |cpu: &Option<String>, memory: &Option<String>| {
+            let quantities: BTreeMap<String, Quantity> = [("cpu", cpu), ("memory", memory)]
+                .into_iter()
+                .filter_map(|(name, value)| Some((name.to_string(), Quantity(value.clone()?))))
+                .collect();
+            (!quantities.is_empty()).then_some(quantities)
+        };
+
+        ResourceRequirements {
+            requests: quantities(&self.cpu_request, &self.memory_request),
+            limits: quantities(&self.cpu_limit, &self.memory_limit),
+            ..Default::default()
+        }
+    }
+}
+
+/// Settings of a kwpm client, read from a YAML file and `KWPM_*` variables.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct KwpmConfig {
+    /// Directory on the nodes local volumes are created in.
+    pub pv_base_path: String,
+    /// `storageClassName` of the volumes and claims kwpm creates.
+    pub storage_class: String,
+    /// Leaves creating volumes to the provisioner of `storage_class`, e.g. Longhorn or EBS,
+    /// instead of creating local volumes pinned to a node.
+    pub dynamic_volumes: bool,
+    /// Label selector of the nodes local volumes may be placed on when no node is given.
+    pub node_selector: Option<String>,
+    /// Prefix of site namespaces, `kwpm-<site>` by default.
+    pub namespace_prefix: String,
+    /// Image of MariaDB instances instead of the one of the template.
+    pub mariadb_image: Option<String>,
+    pub mariadb_resources: ResourceLimits,
+    pub ingress_class: Option<String>,
+    pub ingress_annotations: BTreeMap<String, String>,
+    /// Only read from the file.
+    pub database_quotas: DatabaseQuotaPolicy,
+    /// Locale and time zone new WordPress sites are installed with.
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    /// Environment tier new sites are created in, see `set_environment`.
+    pub environment: Option<Environment>,
+    /// Profiles replacing the defaults of environment tiers. Only read from the file.
+    pub environments: BTreeMap<Environment, EnvironmentProfile>,
+}
+
+impl Default for KwpmConfig {
+    fn default() -> Self {
+        Self {
+            pv_base_path: "/data/volumes/kwpm".to_string(),
+            storage_class: DEFAULT_STORAGE_CLASS.to_string(),
+            dynamic_volumes: false,
+            node_selector: None,
+            namespace_prefix: DEFAULT_NAMESPACE_PREFIX.to_string(),
+            mariadb_image: None,
+            mariadb_resources: ResourceLimits::default(),
+            ingress_class: None,
+            ingress_annotations: BTreeMap::new(),
+            database_quotas: DatabaseQuotaPolicy::default(),
+            locale: None,
+            timezone: None,
+            environment: None,
+            environments: BTreeMap::new(),
+        }
+    }
+}
+
+impl KwpmConfig {
+    /// Reads a YAML (or JSON) file with the camelCase keys of this struct, defaulting missing
+    /// ones.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {} failed", path.display()))?;
+        Ok(serde_yaml::from_str(&config)
+            .with_context(|| format!("{} is not a valid kwpm config", path.display()))?)
+    }
+
+    /// The file `KWPM_CONFIG` points to, if set, overridden by the variables set of
+    /// `KWPM_PV_BASE_PATH`, `KWPM_STORAGE_CLASS`, `KWPM_DYNAMIC_VOLUMES`, `KWPM_NODE_SELECTOR`,
+    /// `KWPM_NAMESPACE_PREFIX`, `KWPM_MARIADB_IMAGE`,
+    /// `KWPM_MARIADB_{CPU,MEMORY}_{REQUEST,LIMIT}`, `KWPM_INGRESS_CLASS`,
+    /// `KWPM_INGRESS_ANNOTATIONS`, `KWPM_LOCALE` and `KWPM_TIMEZONE`.
+    pub fn from_env() -> Result<Self> {
+        let mut config = match std::env::var("KWPM_CONFIG") {
+            Ok(path) if !path.is_empty() => Self::from_file(path)?,
+            _ => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+
+    /// Overrides settings with the non-empty variables `var` returns.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        let set = |target: &mut String, name: &str| {
+            if let Some(value) = var(name) {
+                *target = value;
+            }
+        };
+        let set_opt = |target: &mut Option<String>, name: &str| {
+            if let Some(value) = var(name) {
+                *target = Some(value);
+            }
+        };
+
+        set(&mut self.pv_base_path, "KWPM_PV_BASE_PATH");
+        set(&mut self.storage_class, "KWPM_STORAGE_CLASS");
+        if let Some(dynamic_volumes) = var("KWPM_DYNAMIC_VOLUMES") {
+            self.dynamic_volumes = dynamic_volumes.parse().map_err(|_| {
+                KwpmError::invalid_input(format!(
+                    "KWPM_DYNAMIC_VOLUMES must be true or false, got {}",
+                    dynamic_volumes
+                ))
+            })?;
+        }
+        set_opt(&mut self.node_selector, "KWPM_NODE_SELECTOR");
+        set(&mut self.namespace_prefix, "KWPM_NAMESPACE_PREFIX");
+        set_opt(&mut self.mariadb_image, "KWPM_MARIADB_IMAGE");
+        let resources = &mut self.mariadb_resources;
+        set_opt(&mut resources.cpu_request, "KWPM_MARIADB_CPU_REQUEST");
+        set_opt(&mut resources.cpu_limit, "KWPM_MARIADB_CPU_LIMIT");
+        set_opt(&mut resources.memory_request, "KWPM_MARIADB_MEMORY_REQUEST");
+        set_opt(&mut resources.memory_limit, "KWPM_MARIADB_MEMORY_LIMIT");
+        set_opt(&mut self.ingress_class, "KWPM_INGRESS_CLASS");
+        if let Some(annotations) = var("KWPM_INGRESS_ANNOTATIONS") {
+            self.ingress_annotations = parse_annotations(&annotations)?;
+        }
+        set_opt(&mut self.locale, "KWPM_LOCALE");
+        set_opt(&mut self.timezone, "KWPM_TIMEZONE");
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.pv_base_path.starts_with('/') {
+            return Err(KwpmError::invalid_input(format!(
+                "pvBasePath must be absolute: {}",
+                self.pv_base_path
+            )));
+        }
+        if self.storage_class.is_empty() {
+            return Err(KwpmError::invalid_input("storageClass must not be empty"));
+        }
+        if !is_valid_namespace_prefix(&self.namespace_prefix) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid namespacePrefix: {}",
+                self.namespace_prefix
+            )));
+        }
+        if let Some(locale) = self.locale.as_deref().filter(|l| !is_valid_locale(l)) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid locale: {}",
+                locale
+            )));
+        }
+        if let Some(timezone) = self.timezone.as_deref().filter(|t| !is_valid_timezone(t)) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid timezone: {}",
+                timezone
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `mysql` container of a MariaDB deployment with the configured image and resources.
+pub(crate) fn apply_mariadb_config(
+    deployment: &mut Deployment,
+    image: Option<&str>,
+    resources: &ResourceLimits,
+) -> Result<()> {
+    let mysql = deployment_pod_spec_mut(deployment)?
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "mysql")
+        .ok_or_else(|| KwpmError::invalid_manifest("MariaDB deployment has no mysql container"))?;
+    if let Some(image) = image {
+        mysql.image = Some(image.to_string());
+    }
+    if !resources.is_empty() {
+        mysql.resources = Some(resources.to_requirements());
+    }
+    Ok(())
+}
+
+impl KwpmClient {
+    /// Sets the storage class of a volume and its claim. With dynamic volumes the claim isn't
+    /// bound to the volume, which isn't created, but to one the provisioner creates.
+    pub(crate) fn adapt_volume(&self, pv: &mut PersistentVolume, pvc: &mut PersistentVolumeClaim) {
+        set_storage_class(pv, pvc, &self.storage_class);
+        if self.dynamic_volumes {
+            if let Some(spec) = pvc.spec.as_mut() {
+                spec.volume_name = None;
+            }
+        }
+    }
+
+    /// Connects like `new` with the settings of `config`.
+    pub async fn from_config(config: KwpmConfig) -> Result<Self> {
+        config.validate()?;
+        let mut client = Self::new(&config.pv_base_path)
+            .await?
+            .with_database_quotas(config.database_quotas)
+            .with_namespace_prefix(&config.namespace_prefix)?;
+        client.storage_class = config.storage_class;
+        client.dynamic_volumes = config.dynamic_volumes;
+        client.node_selector = config.node_selector;
+        client.mariadb_image = config.mariadb_image;
+        client.mariadb_resources = config.mariadb_resources;
+        client.default_locale = config.locale;
+        client.default_timezone = config.timezone;
+        client.default_environment = config.environment;
+        client.environment_profiles = config.environments;
+        client.ingress_manager = IngressManager {
+            class_name: config.ingress_class,
+            annotations: config.ingress_annotations,
+        };
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::{DatabaseQuota, QuotaMode};
+
+    #[test]
+    fn test_config_from_yaml_and_env() {
+        let mut config: KwpmConfig = serde_yaml::from_str(
+            "pvBasePath: /srv/kwpm\nmariadbImage: mariadb:11.4\nmariadbResources:\n  memoryLimit: 2Gi\n\
+             databaseQuotas:\n  plans:\n    starter: {limitMb: 512, mode: enforce}\n",
+        )
+        .unwrap();
+        assert_eq!(config.storage_class, DEFAULT_STORAGE_CLASS);
+        assert_eq!(config.namespace_prefix, "kwpm-");
+        assert_eq!(
+            config.database_quotas.quota(Some("starter")),
+            Some(DatabaseQuota {
+                limit_mb: 512,
+                mode: QuotaMode::Enforce,
+            })
+        );
+
+        let config_with_tiers: KwpmConfig = serde_yaml::from_str(
+            "environment: staging\nenvironments:\n  staging: {cpuRequest: 200m, memoryRequest: 256Mi, \
+             memoryLimit: 1Gi, debug: false, basicAuth: true, backupSchedule: '0 4 * * *', \
+             clusterIssuer: letsencrypt-prod}\n",
+        )
+        .unwrap();
+        assert_eq!(config_with_tiers.environment, Some(Environment::Staging));
+        let staging = &config_with_tiers.environments[&Environment::Staging];
+        assert_eq!(staging.backup_schedule, "0 4 * * *");
+        assert_eq!(staging.cluster_issuer, "letsencrypt-prod");
+
+        let env = BTreeMap::from([
+            ("KWPM_STORAGE_CLASS", "fast-local"),
+            ("KWPM_DYNAMIC_VOLUMES", "true"),
+            ("KWPM_MARIADB_CPU_REQUEST", "500m"),
+            ("KWPM_INGRESS_CLASS", "traefik"),
+            ("KWPM_LOCALE", "de_DE"),
+            ("KWPM_PV_BASE_PATH", ""),
+        ]);
+        config
+            .apply_env(|name| env.get(name).map(ToString::to_string))
+            .unwrap();
+        assert_eq!(config.pv_base_path, "/srv/kwpm");
+        assert_eq!(config.storage_class, "fast-local");
+        assert!(config.dynamic_volumes);
+        assert_eq!(config.ingress_class.as_deref(), Some("traefik"));
+        assert_eq!(config.locale.as_deref(), Some("de_DE"));
+        assert!(config.validate().is_ok());
+
+        let resources = config.mariadb_resources.to_requirements();
+        assert_eq!(resources.requests.unwrap()["cpu"].0, "500m");
+        let limits = resources.limits.unwrap();
+        assert_eq!(limits["memory"].0, "2Gi");
+        assert!(!limits.contains_key("cpu"));
+
+        config.timezone = Some("Berlin time".to_string());
+        assert!(config.validate().is_err());
+        config.timezone = None;
+        config.namespace_prefix = "wp".to_string();
+        assert!(config.validate().is_err());
+        assert!(config
+            .apply_env(|name| (name == "KWPM_DYNAMIC_VOLUMES").then(|| "yes".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_mariadb_config() {
+        let mut deployment: Deployment = serde_yaml::from_str(include_str!(
+            "../../kubernetes/mariadb/mariadb-deployment.yaml"
+        ))
+        .unwrap();
+        apply_mariadb_config(&mut deployment, None, &ResourceLimits::default()).unwrap();
+        let mysql = &deployment_pod_spec_mut(&mut deployment).unwrap().containers[0];
+        assert_eq!(mysql.image.as_deref(), Some("mariadb:10.11"));
+        assert!(mysql.resources.is_none());
+
+        let resources = ResourceLimits {
+            memory_limit: Some("1Gi".to_string()),
+            ..Default::default()
+        };
+        apply_mariadb_config(&mut deployment, Some("mariadb:11.4"), &resources).unwrap();
+        let mysql = &deployment_pod_spec_mut(&mut deployment).unwrap().containers[0];
+        assert_eq!(mysql.image.as_deref(), Some("mariadb:11.4"));
+        assert_eq!(
+            mysql.resources.as_ref().unwrap().limits.as_ref().unwrap()["memory"].0,
+            "1Gi"
+        );
+    }
+}
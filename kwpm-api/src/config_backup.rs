@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use k8s_openapi::api::{batch::v1::CronJob, core::v1::Namespace};
+use kube::{
+    api::{DynamicObject, ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use object_store::{path::Path, ObjectStore, PutPayload};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    archive::{api_resource, read_json},
+    error::{bail, KwpmError, Result},
+    gitops::strip_server_fields,
+    site::SiteSpec,
+    KwpmClient,
+};
+
+/// Format version of config backups, bumped whenever a newer kwpm can no longer read them.
+pub const CONFIG_BACKUP_VERSION: u32 = 1;
+/// Not a valid site name, so it can't collide with the backups of a site.
+const CONFIG_BACKUP_DIR: &str = "_config";
+
+/// kwpm's view of every site, without site data: the namespace labels that assign it to a
+/// tenant, its spec with credentials still sealed, and its schedules.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteConfig {
+    pub site: String,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    pub spec: SiteSpec,
+    pub cron_jobs: Vec<Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KwpmConfigBackup {
+    pub version: u32,
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub sites: Vec<SiteConfig>,
+}
+
+pub fn config_backup_id(created_at: DateTime<Utc>) -> String {
+    created_at.format("config-%Y%m%d-%H%M%S").to_string()
+}
+
+pub fn check_config_backup_version(backup: &KwpmConfigBackup) -> Result<()> {
+    if backup.version > CONFIG_BACKUP_VERSION {
+        bail!(
+            "config backup {} has version {}, this kwpm reads up to version {}",
+            backup.id,
+            backup.version,
+            CONFIG_BACKUP_VERSION
+        );
+    }
+    Ok(())
+}
+
+impl KwpmClient {
+    fn config_backup_path(&self, id: &str) -> Result<Path> {
+        Ok(self
+            .backup_storage()?
+            .backup_path(CONFIG_BACKUP_DIR, &format!("{}.json", id)))
+    }
+
+    async fn site_config(&self, namespace: &Namespace, site: &str) -> Result<SiteConfig> {
+        let cron_job_api: Api<CronJob> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let mut cron_jobs = Vec::new();
+        for cron_job in cron_job_api.list(&Default::default()).await? {
+            let mut manifest = serde_json::to_value(&cron_job)?;
+            manifest["apiVersion"] = json!("batch/v1");
+            manifest["kind"] = json!("CronJob");
+            strip_server_fields(&mut manifest);
+            cron_jobs.push(manifest);
+        }
+
+        Ok(SiteConfig {
+            site: site.to_string(),
+            labels: namespace.metadata.labels.clone().unwrap_or_default(),
+            annotations: namespace.metadata.annotations.clone().unwrap_or_default(),
+            spec: self.stored_site_spec(site).await?,
+            cron_jobs,
+        })
+    }
+
+    /// Stores the configuration of every site in one versioned artifact next to the site
+    /// backups, to recover kwpm's state after losing the cluster's control plane.
+    pub async fn backup_kwpm_config(&self) -> Result<KwpmConfigBackup> {
+        self.ensure_writable("backup_kwpm_config")?;
+        let created_at = Utc::now();
+        let id = config_backup_id(created_at);
+
+        let mut sites = Vec::new();
+        for namespace in self.get_kwpm_namespaces().await? {
+            let Some(site) = namespace
+                .metadata
+                .name
+                .as_deref()
+                .and_then(|ns| self.site_name(ns))
+            else {
+                continue;
+            };
+            sites.push(
+                self.site_config(&namespace, site)
+                    .await
+                    .with_context(|| format!("failed to read the config of {}", site))?,
+            );
+        }
+
+        let backup = KwpmConfigBackup {
+            version: CONFIG_BACKUP_VERSION,
+            id: id.clone(),
+            created_at,
+            sites,
+        };
+        self.backup_storage()?
+            .object_store()?
+            .put(
+                &self.config_backup_path(&id)?,
+                PutPayload::from(serde_json::to_vec_pretty(&backup)?),
+            )
+            .await?;
+
+        Ok(backup)
+    }
+
+    pub async fn list_kwpm_config_backups(&self) -> Result<Vec<String>> {
+        let storage = self.backup_storage()?;
+        let prefix = storage.backup_path(CONFIG_BACKUP_DIR, "");
+
+        let mut ids: Vec<String> = storage
+            .object_store()?
+            .list(Some(&prefix))
+            .map_ok(|object| object.location.filename().unwrap_or_default().to_string())
+            .try_collect::<Vec<_>>()
+            .await?
+            .into_iter()
+            .filter_map(|name| name.strip_suffix(".json").map(str::to_string))
+            .collect();
+        ids.sort();
+
+        Ok(ids)
+    }
+
+    pub async fn get_kwpm_config_backup(&self, id: &str) -> Result<KwpmConfigBackup> {
+        let backup: KwpmConfigBackup = read_json(
+            &self.backup_storage()?.object_store()?,
+            &self.config_backup_path(id)?,
+        )
+        .await?
+        .ok_or_else(|| KwpmError::not_found(format!("config backup {} not found", id)))?;
+        check_config_backup_version(&backup)?;
+
+        Ok(backup)
+    }
+
+    /// Applies a config backup: recreates the site namespaces with their labels, specs and
+    /// schedules. Site files and databases are restored separately from the site backups.
+    pub async fn restore_kwpm_config(&self, id: &str) -> Result<()> {
+        let backup = self.get_kwpm_config_backup(id).await?;
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let params = PatchParams::apply("kwpm").force();
+
+        for config in &backup.sites {
+            let ns_name = self.site_namespace(&config.site);
+            let namespace = Namespace {
+                metadata: ObjectMeta {
+                    name: Some(ns_name.clone()),
+                    labels: Some(config.labels.clone()),
+                    annotations: Some(config.annotations.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            namespace_api
+                .patch(&ns_name, &params, &Patch::Apply(&self.labeled(&namespace)))
+                .await?;
+
+            self.store_site_spec(&config.site, &config.spec).await?;
+
+            for manifest in &config.cron_jobs {
+                let resource = api_resource(manifest)?;
+                let object: DynamicObject = serde_json::from_value(manifest.clone())?;
+                let name = object
+                    .metadata
+                    .name
+                    .clone()
+                    .context("cron job has no name")?;
+                let api: Api<DynamicObject> =
+                    Api::namespaced_with(self.client.clone(), &ns_name, &resource);
+                api.patch(&name, &params, &Patch::Apply(&self.labeled(&object)))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn backup(version: u32) -> KwpmConfigBackup {
+        KwpmConfigBackup {
+            version,
+            id: "config-20240301-120000".to_string(),
+            created_at: Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap(),
+            sites: vec![SiteConfig {
+                site: "blog".to_string(),
+                labels: BTreeMap::from([("kwpm.io/tenant".to_string(), "acme".to_string())]),
+                annotations: BTreeMap::new(),
+                spec: SiteSpec {
+                    upload_limit_mb: Some(64),
+                    ..Default::default()
+                },
+                cron_jobs: Vec::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_config_backup_id() {
+        assert_eq!(
+            config_backup_id(Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()),
+            "config-20240301-120000"
+        );
+    }
+
+    #[test]
+    fn test_config_backup_round_trip() {
+        let backup = backup(CONFIG_BACKUP_VERSION);
+        let json = serde_json::to_vec(&backup).unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<KwpmConfigBackup>(&json).unwrap(),
+            backup
+        );
+        assert!(check_config_backup_version(&backup).is_ok());
+    }
+
+    #[test]
+    fn test_newer_config_backup_is_rejected() {
+        assert!(check_config_backup_version(&backup(CONFIG_BACKUP_VERSION + 1)).is_err());
+    }
+}
@@ -0,0 +1,404 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use k8s_openapi::api::core::v1::{Namespace, PersistentVolume, Secret};
+use kube::{Api, ResourceExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    error::{KwpmError, Result},
+    mariadb::{mariadb_namespace, mariadb_pv_name, DEFAULT_MARIADB_INSTANCE},
+    secret_value,
+    secrets::MasterKeys,
+    site::prefixed_namespace,
+    KwpmClient, MARIADB_NAMESPACE,
+};
+
+/// How long a token from `plan` can be used to confirm the operation.
+pub const CONFIRMATION_TTL: Duration = Duration::from_secs(600);
+
+/// An operation that can't be undone and therefore has to be confirmed with a token from
+/// [`KwpmClient::plan`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "camelCase")]
+pub enum DestructiveOperation {
+    /// Deletes a site with its database and volume.
+    #[serde(rename_all = "camelCase")]
+    RemoveSite { site: String },
+    /// Deletes MariaDB with every database on it.
+    RemoveMariaDb,
+    /// Deletes a MariaDB instance other than the default one.
+    #[serde(rename_all = "camelCase")]
+    RemoveMariaDbInstance { instance: String },
+    /// Replaces the database and wp-content of a live site with a backup.
+    #[serde(rename_all = "camelCase")]
+    RestoreBackup { site: String, backup_id: String },
+    /// Overwrites wp-content of `target` with that of a backup of `site`.
+    #[serde(rename_all = "camelCase")]
+    RestoreFiles {
+        site: String,
+        backup_id: String,
+        target: String,
+    },
+    /// Replaces one table of a live site with that of a backup.
+    #[serde(rename_all = "camelCase")]
+    RestoreTable {
+        site: String,
+        backup_id: String,
+        table: String,
+    },
+    /// Overwrites a file or directory in wp-content of a live site with that of a backup.
+    #[serde(rename_all = "camelCase")]
+    RestorePath {
+        site: String,
+        backup_id: String,
+        path: String,
+    },
+    /// Backs a site up into cold storage and deletes it.
+    #[serde(rename_all = "camelCase")]
+    ArchiveSite { site: String },
+}
+
+impl DestructiveOperation {
+    /// The namespace whose uid the token is bound to, so it can't confirm the operation on
+    /// a namespace recreated under the same name.
+    fn namespace(&self, namespace_prefix: &str) -> String {
+        match self {
+            DestructiveOperation::RemoveSite { site }
+            | DestructiveOperation::RestoreBackup { site, .. }
+            | DestructiveOperation::RestoreTable { site, .. }
+            | DestructiveOperation::RestorePath { site, .. }
+            | DestructiveOperation::ArchiveSite { site } => {
+                prefixed_namespace(namespace_prefix, site)
+            }
+            DestructiveOperation::RestoreFiles { target, .. } => {
+                prefixed_namespace(namespace_prefix, target)
+            }
+            DestructiveOperation::RemoveMariaDb => MARIADB_NAMESPACE.to_string(),
+            DestructiveOperation::RemoveMariaDbInstance { instance } => mariadb_namespace(instance),
+        }
+    }
+}
+
+impl fmt::Display for DestructiveOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestructiveOperation::RemoveSite { site } => write!(f, "removing site {}", site),
+            DestructiveOperation::RemoveMariaDb => write!(f, "removing MariaDB"),
+            DestructiveOperation::RemoveMariaDbInstance { instance } => {
+                write!(f, "removing MariaDB {}", instance)
+            }
+            DestructiveOperation::RestoreBackup { site, backup_id } => {
+                write!(f, "restoring backup {} over site {}", backup_id, site)
+            }
+            DestructiveOperation::RestoreFiles {
+                site,
+                backup_id,
+                target,
+            } => write!(
+                f,
+                "restoring files of backup {} of {} into site {}",
+                backup_id, site, target
+            ),
+            DestructiveOperation::RestoreTable {
+                site,
+                backup_id,
+                table,
+            } => write!(
+                f,
+                "restoring table {} of backup {} over site {}",
+                table, backup_id, site
+            ),
+            DestructiveOperation::RestorePath {
+                site,
+                backup_id,
+                path,
+            } => write!(
+                f,
+                "restoring {} of backup {} over site {}",
+                path, backup_id, site
+            ),
+            DestructiveOperation::ArchiveSite { site } => write!(f, "archiving site {}", site),
+        }
+    }
+}
+
+/// What a destructive operation would do, with the token confirming it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub operation: DestructiveOperation,
+    pub effects: Vec<String>,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Returned when a destructive operation is attempted without a valid token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfirmationRequired {
+    pub operation: DestructiveOperation,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfirmationRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requires confirmation: {}, plan it first and pass the token",
+            self.operation, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ConfirmationRequired {}
+
+/// `<expiry>.<digest>` of the operation and the uid of its namespace. With master keys the
+/// digest is an HMAC, so tokens can only be obtained from `plan`.
+pub fn confirmation_token(
+    operation: &DestructiveOperation,
+    uid: &str,
+    expires_at: DateTime<Utc>,
+    keys: Option<&MasterKeys>,
+) -> Result<String> {
+    let message = format!(
+        "{}|{}|{}",
+        serde_json::to_string(operation)?,
+        uid,
+        expires_at.timestamp()
+    );
+    let digest = match keys {
+        Some(keys) => keys.derive(&message),
+        None => format!("{:x}", Sha256::digest(message.as_bytes())),
+    };
+
+    Ok(format!("{}.{}", expires_at.timestamp(), &digest[..32]))
+}
+
+/// Checks `token` against the operation as it would be confirmed now, returning why it
+/// doesn't confirm it.
+pub fn verify_confirmation_token(
+    operation: &DestructiveOperation,
+    uid: &str,
+    token: &str,
+    now: DateTime<Utc>,
+    keys: Option<&MasterKeys>,
+) -> std::result::Result<(), ConfirmationRequired> {
+    let required = |reason: &str| ConfirmationRequired {
+        operation: operation.clone(),
+        reason: reason.to_string(),
+    };
+    if token.is_empty() {
+        return Err(required("no confirmation token was given"));
+    }
+    let expires_at = token
+        .split_once('.')
+        .and_then(|(expiry, _)| expiry.parse().ok())
+        .and_then(|expiry| Utc.timestamp_opt(expiry, 0).single())
+        .ok_or_else(|| required("the confirmation token is malformed"))?;
+    if expires_at < now {
+        return Err(required("the confirmation token has expired"));
+    }
+    match confirmation_token(operation, uid, expires_at, keys) {
+        Ok(expected) if bool::from(expected.as_bytes().ct_eq(token.as_bytes())) => Ok(()),
+        _ => Err(required(
+            "the confirmation token was issued for another operation or target",
+        )),
+    }
+}
+
+impl KwpmClient {
+    async fn destructive_target(&self, operation: &DestructiveOperation) -> Result<Namespace> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = operation.namespace(&self.namespace_prefix);
+        namespace_api
+            .get_opt(&namespace)
+            .await?
+            .ok_or_else(|| KwpmError::not_found(format!("{} does not exist", namespace)))
+    }
+
+    async fn planned_effects(&self, operation: &DestructiveOperation) -> Result<Vec<String>> {
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let mut effects = Vec::new();
+        match operation {
+            DestructiveOperation::RemoveSite { site } => {
+                let namespace = self.site_namespace(site);
+                effects.push(format!(
+                    "delete namespace {} with all its objects",
+                    namespace
+                ));
+                let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &namespace);
+                if let Some(secret) = secret_api.get_opt("mysql-pass").await? {
+                    effects.push(format!(
+                        "drop database {}",
+                        secret_value(&secret, "db_name")?
+                    ));
+                }
+                let pv_name = format!("{}-pv", namespace);
+                if pv_api.get_opt(&pv_name).await?.is_some() {
+                    effects.push(format!("delete volume {} with the site's files", pv_name));
+                }
+            }
+            DestructiveOperation::RemoveMariaDb
+            | DestructiveOperation::RemoveMariaDbInstance { .. } => {
+                let instance = match operation {
+                    DestructiveOperation::RemoveMariaDbInstance { instance } => instance,
+                    _ => DEFAULT_MARIADB_INSTANCE,
+                };
+                effects.push(format!(
+                    "delete namespace {} with every site database",
+                    mariadb_namespace(instance)
+                ));
+                let pv_name = mariadb_pv_name(instance);
+                if pv_api.get_opt(&pv_name).await?.is_some() {
+                    effects.push(format!("delete volume {}", pv_name));
+                }
+            }
+            DestructiveOperation::RestoreBackup { site, backup_id } => {
+                effects.push(format!(
+                    "replace the database of {} with backup {}",
+                    site, backup_id
+                ));
+                effects.push(format!(
+                    "overwrite wp-content of {} with backup {}",
+                    site, backup_id
+                ));
+            }
+            DestructiveOperation::RestoreFiles {
+                site,
+                backup_id,
+                target,
+            } => {
+                effects.push(format!(
+                    "overwrite wp-content of {} with backup {} of {}",
+                    target, backup_id, site
+                ));
+            }
+            DestructiveOperation::RestoreTable {
+                site,
+                backup_id,
+                table,
+            } => {
+                effects.push(format!(
+                    "replace table {} of {} with backup {}",
+                    table, site, backup_id
+                ));
+            }
+            DestructiveOperation::RestorePath {
+                site,
+                backup_id,
+                path,
+            } => {
+                effects.push(format!(
+                    "overwrite {} in wp-content of {} with backup {}",
+                    path, site, backup_id
+                ));
+            }
+            DestructiveOperation::ArchiveSite { site } => {
+                effects.push(format!("back up {} into cold storage", site));
+                effects.push(format!(
+                    "delete the files, database and namespace {} of {}",
+                    self.site_namespace(site),
+                    site
+                ));
+            }
+        }
+
+        Ok(effects)
+    }
+
+    /// Describes what `operation` would do and returns the token confirming it, valid for
+    /// `CONFIRMATION_TTL`.
+    pub async fn plan(&self, operation: &DestructiveOperation) -> Result<Plan> {
+        let target = self.destructive_target(operation).await?;
+        let expires_at = Utc::now() + chrono::Duration::from_std(CONFIRMATION_TTL)?;
+        let token = confirmation_token(
+            operation,
+            &target.uid().unwrap_or_default(),
+            expires_at,
+            self.master_keys.as_ref(),
+        )?;
+
+        Ok(Plan {
+            operation: operation.clone(),
+            effects: self.planned_effects(operation).await?,
+            token,
+            expires_at,
+        })
+    }
+
+    /// Fails with [`ConfirmationRequired`] unless `token` came from planning `operation`.
+    pub(crate) async fn confirm(
+        &self,
+        operation: &DestructiveOperation,
+        token: &str,
+    ) -> Result<()> {
+        let target = self.destructive_target(operation).await?;
+        verify_confirmation_token(
+            operation,
+            &target.uid().unwrap_or_default(),
+            token,
+            Utc::now(),
+            self.master_keys.as_ref(),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site::DEFAULT_NAMESPACE_PREFIX;
+
+    #[test]
+    fn test_confirmation_token() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let expires_at = now + chrono::Duration::from_std(CONFIRMATION_TTL).unwrap();
+        let remove = DestructiveOperation::RemoveSite {
+            site: "blog".to_string(),
+        };
+        let token = confirmation_token(&remove, "uid-1", expires_at, None).unwrap();
+
+        assert!(verify_confirmation_token(&remove, "uid-1", &token, now, None).is_ok());
+        assert!(verify_confirmation_token(&remove, "uid-2", &token, now, None).is_err());
+        let expired = verify_confirmation_token(
+            &remove,
+            "uid-1",
+            &token,
+            expires_at + chrono::Duration::from_std(Duration::from_secs(1)).unwrap(),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(expired.reason, "the confirmation token has expired");
+
+        let other = DestructiveOperation::RemoveSite {
+            site: "shop".to_string(),
+        };
+        assert!(verify_confirmation_token(&other, "uid-1", &token, now, None).is_err());
+        assert!(verify_confirmation_token(&remove, "uid-1", "", now, None).is_err());
+        assert!(verify_confirmation_token(&remove, "uid-1", "soon.abc", now, None).is_err());
+    }
+
+    #[test]
+    fn test_destructive_operation_json() {
+        let operation: DestructiveOperation = serde_json::from_str(
+            r#"{"operation":"restoreBackup","site":"blog","backupId":"20240101-030000"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            operation,
+            DestructiveOperation::RestoreBackup {
+                site: "blog".to_string(),
+                backup_id: "20240101-030000".to_string(),
+            }
+        );
+
+        let operation: DestructiveOperation = serde_json::from_str(
+            r#"{"operation":"restoreTable","site":"blog","backupId":"20240101-030000","table":"wp_posts"}"#,
+        )
+        .unwrap();
+        assert_eq!(operation.namespace(DEFAULT_NAMESPACE_PREFIX), "kwpm-blog");
+    }
+}
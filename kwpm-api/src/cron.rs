@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+/// A scheduled WordPress cron event, as listed by `wp cron event list`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CronEvent {
+    pub hook: String,
+    pub next_run: DateTime<Utc>,
+    /// e.g. `12 hours`, or `Non-repeating`.
+    pub recurrence: String,
+}
+
+impl CronEvent {
+    /// Whether WP-Cron should have run the event more than `grace` ago, which points at a
+    /// site without traffic, `DISABLE_WP_CRON` without a system cron, or a hook that fails.
+    pub fn is_overdue(&self, now: DateTime<Utc>, grace: Duration) -> bool {
+        (now - self.next_run).num_seconds() > grace.as_secs() as i64
+    }
+}
+
+#[derive(Deserialize)]
+struct ListedCronEvent {
+    hook: String,
+    next_run_gmt: String,
+    recurrence: String,
+}
+
+pub fn parse_cron_events(output: &str) -> Result<Vec<CronEvent>> {
+    let listed: Vec<ListedCronEvent> =
+        serde_json::from_str(output.trim()).context("unexpected wp cron event list output")?;
+
+    listed
+        .into_iter()
+        .map(|event| {
+            let next_run = NaiveDateTime::parse_from_str(&event.next_run_gmt, "%Y-%m-%d %H:%M:%S")
+                .with_context(|| format!("invalid next run of {}", event.hook))?
+                .and_utc();
+            Ok(CronEvent {
+                hook: event.hook,
+                next_run,
+                recurrence: event.recurrence,
+            })
+        })
+        .collect()
+}
+
+/// Hook names are passed to WP-CLI as arguments, so they must not look like options.
+pub fn is_valid_hook(hook: &str) -> bool {
+    !hook.is_empty()
+        && !hook.starts_with('-')
+        && hook
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:/".contains(c))
+}
+
+pub fn schedule_cron_event_args(
+    hook: &str,
+    next_run: DateTime<Utc>,
+    recurrence: Option<&str>,
+) -> Vec<String> {
+    let mut args = vec![
+        "cron".to_string(),
+        "event".to_string(),
+        "schedule".to_string(),
+        hook.to_string(),
+        next_run.timestamp().to_string(),
+    ];
+    if let Some(recurrence) = recurrence {
+        args.push(recurrence.to_string());
+    }
+    args
+}
+
+impl KwpmClient {
+    pub async fn list_cron_events(&self, site: &str) -> Result<Vec<CronEvent>> {
+        let output = self
+            .run_wp_cli(
+                site,
+                &[
+                    "cron",
+                    "event",
+                    "list",
+                    "--fields=hook,next_run_gmt,recurrence",
+                    "--format=json",
+                ],
+            )
+            .await?;
+
+        parse_cron_events(&output)
+    }
+
+    /// Runs every scheduled event of `hook` now, e.g. one that WP-Cron missed.
+    pub async fn run_cron_event(&self, site: &str, hook: &str) -> Result<String> {
+        if !is_valid_hook(hook) {
+            bail!("invalid cron hook: {}", hook);
+        }
+        self.check_policy("run_cron_event", Some(site), &[hook.into()])
+            .await?;
+
+        let output = self
+            .run_wp_cli(site, &["cron", "event", "run", hook])
+            .await?;
+        self.record_audit(site, "run_cron_event", hook).await?;
+
+        Ok(output)
+    }
+
+    /// Schedules `hook` at `next_run`, repeating on one of the site's cron schedules such as
+    /// `hourly`, or once with `None`.
+    pub async fn schedule_cron_event(
+        &self,
+        site: &str,
+        hook: &str,
+        next_run: DateTime<Utc>,
+        recurrence: Option<&str>,
+    ) -> Result<()> {
+        if !is_valid_hook(hook) {
+            bail!("invalid cron hook: {}", hook);
+        }
+        if let Some(recurrence) = recurrence.filter(|r| !is_valid_hook(r)) {
+            bail!("invalid cron schedule: {}", recurrence);
+        }
+        let args = schedule_cron_event_args(hook, next_run, recurrence);
+        self.check_policy("schedule_cron_event", Some(site), &[args.clone().into()])
+            .await?;
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_wp_cli(site, &args).await?;
+        self.record_audit(
+            site,
+            "schedule_cron_event",
+            &format!(
+                "{} at {} ({})",
+                hook,
+                next_run.to_rfc3339(),
+                recurrence.unwrap_or("once")
+            ),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cron_events() {
+        let output = r#"[{"hook":"wp_version_check","next_run_gmt":"2024-03-01 12:00:00","recurrence":"12 hours"}]"#;
+        let events = parse_cron_events(output).unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 0).unwrap();
+
+        assert_eq!(events[0].hook, "wp_version_check");
+        assert_eq!(
+            events[0].next_run,
+            Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()
+        );
+        assert!(events[0].is_overdue(now, Duration::from_secs(600)));
+        assert!(!events[0].is_overdue(now, Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_is_valid_hook() {
+        assert!(is_valid_hook("woocommerce_cleanup_sessions"));
+        assert!(is_valid_hook("action_scheduler_run_queue"));
+        assert!(!is_valid_hook("--skip-plugins"));
+        assert!(!is_valid_hook("hook; rm -rf /"));
+        assert!(!is_valid_hook(""));
+    }
+
+    #[test]
+    fn test_schedule_cron_event_args() {
+        let next_run = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            schedule_cron_event_args("my_hook", next_run, Some("hourly")),
+            [
+                "cron",
+                "event",
+                "schedule",
+                "my_hook",
+                "1709294400",
+                "hourly"
+            ]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use k8s_openapi::api::apps::v1::Deployment;
+use kube::Api;
+
+use crate::{
+    error::{bail, Result},
+    manifest::{deployment_pod_spec_mut, set_env},
+    site_env::config_extra,
+    KwpmClient,
+};
+
+const DEBUG_ROLLOUT_TIMEOUT: Duration = Duration::from_secs(600);
+/// Evaluated by the wp-config.php of the official image on every request.
+pub(crate) const DEBUG_CONFIG_EXTRA: &str = "define('WP_DEBUG_LOG', true);\ndefine('WP_DEBUG_DISPLAY', false);\n@ini_set('display_errors', '0');";
+
+/// Sets `WP_DEBUG` and logging to `wp-content/debug.log`, without showing errors to visitors.
+/// The site's custom `constants` share the config extra.
+pub fn apply_debug(
+    deployment: &mut Deployment,
+    on: bool,
+    constants: &BTreeMap<String, serde_json::Value>,
+) -> Result<()> {
+    let debug = if on { "1" } else { "" };
+
+    set_env(
+        deployment_pod_spec_mut(deployment)?,
+        "wordpress",
+        &[
+            ("WORDPRESS_DEBUG", debug.to_string()),
+            ("WORDPRESS_CONFIG_EXTRA", config_extra(on, constants)),
+        ],
+    )
+}
+
+/// PHP printing the last `lines` lines of `wp-content/debug.log`.
+pub fn debug_log_tail_php(lines: usize) -> String {
+    format!(
+        "$lines = @file(WP_CONTENT_DIR . '/debug.log'); echo implode('', array_slice($lines ?: [], -{}));",
+        lines
+    )
+}
+
+impl KwpmClient {
+    /// Turns WordPress debug logging on or off and waits for the restarted pods.
+    pub async fn set_debug(&self, site: &str, on: bool) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let mut spec = self.get_site_spec(site).await?;
+        let mut deployment = deployment_api.get("wordpress").await?;
+        apply_debug(&mut deployment, on, &spec.config_constants)?;
+        self.check_policy(
+            "set_debug",
+            Some(site),
+            &[serde_json::to_value(&deployment)?],
+        )
+        .await?;
+
+        spec.debug = on;
+        self.save_site_spec(site, &spec).await?;
+
+        let deployment = deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+        self.record_site_revision(site, "set_debug").await?;
+        self.record_audit(site, "set_debug", if on { "on" } else { "off" })
+            .await?;
+        self.wait_for_rollout(site, &deployment, DEBUG_ROLLOUT_TIMEOUT)
+            .await
+    }
+
+    /// The last `lines` lines of the site's `wp-content/debug.log`, with credentials redacted.
+    pub async fn get_debug_log(&self, site: &str, lines: usize) -> Result<String> {
+        if lines == 0 {
+            bail!("at least one line of the debug log has to be requested");
+        }
+
+        self.run_wp_cli(site, &["eval", &debug_log_tail_php(lines)])
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(deployment: &mut Deployment, name: &str) -> Option<String> {
+        deployment_pod_spec_mut(deployment).unwrap().containers[0]
+            .env
+            .as_ref()?
+            .iter()
+            .find(|e| e.name == name)?
+            .value
+            .clone()
+    }
+
+    #[test]
+    fn test_apply_debug() {
+        let mut deployment: Deployment = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap();
+
+        apply_debug(&mut deployment, true, &BTreeMap::new()).unwrap();
+        assert_eq!(
+            env(&mut deployment, "WORDPRESS_DEBUG").as_deref(),
+            Some("1")
+        );
+        assert!(env(&mut deployment, "WORDPRESS_CONFIG_EXTRA")
+            .unwrap()
+            .contains("WP_DEBUG_LOG"));
+
+        apply_debug(&mut deployment, false, &BTreeMap::new()).unwrap();
+        assert_eq!(env(&mut deployment, "WORDPRESS_DEBUG").as_deref(), Some(""));
+        assert_eq!(
+            env(&mut deployment, "WORDPRESS_CONFIG_EXTRA").as_deref(),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn test_debug_log_tail_php() {
+        assert!(debug_log_tail_php(50).contains("array_slice($lines ?: [], -50)"));
+    }
+}
@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use k8s_openapi::api::batch::v1::Job;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, KwpmError, Result},
+    job::is_job_succeeded,
+    library::{is_valid_slug, LibraryItemKind},
+    manifest::{job_pod_spec_mut, set_env},
+    snapshot::RiskyOperation,
+    KwpmClient,
+};
+
+const CODE_DEPLOY_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CodeArtifact {
+    /// Zip archive as built by `wp dist-archive`, with the code either at the top level or in a
+    /// directory named after the slug.
+    Zip { url: String },
+    /// Image carrying the code at `path`; it needs `sh` and `cp` to copy it out.
+    Image { image: String, path: String },
+}
+
+/// Theme or plugin build to deploy to a site, e.g. from a CI pipeline.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CodeDeploy {
+    pub kind: LibraryItemKind,
+    pub slug: String,
+    pub artifact: CodeArtifact,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SwapAction {
+    Deploy,
+    Rollback,
+}
+
+fn code_deploy_job(site: &str, deploy: &CodeDeploy, action: SwapAction) -> Result<Job> {
+    if !is_valid_slug(&deploy.slug) {
+        bail!("invalid {} slug: {}", deploy.kind.dir(), deploy.slug);
+    }
+
+    let mut job: Job =
+        serde_yaml::from_str(include_str!("../../kubernetes/deploy/code-deploy-job.yaml"))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    set_env(
+        pod_spec,
+        "swap",
+        &[
+            (
+                "ACTION",
+                match action {
+                    SwapAction::Deploy => "deploy",
+                    SwapAction::Rollback => "rollback",
+                }
+                .to_string(),
+            ),
+            ("KIND", deploy.kind.dir().to_string()),
+            ("SLUG", deploy.slug.clone()),
+        ],
+    )?;
+
+    match (&deploy.artifact, action) {
+        (_, SwapAction::Rollback) => pod_spec.init_containers = None,
+        (CodeArtifact::Zip { url }, SwapAction::Deploy) => {
+            set_env(pod_spec, "fetch", &[("ARTIFACT_URL", url.clone())])?;
+        }
+        (CodeArtifact::Image { image, path }, SwapAction::Deploy) => {
+            let fetch = pod_spec
+                .init_containers
+                .iter_mut()
+                .flatten()
+                .find(|c| c.name == "fetch")
+                .context("code deploy job has no fetch container")?;
+            fetch.image = Some(image.clone());
+            fetch.command = Some(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "cp -a \"$ARTIFACT_PATH/.\" /artifact/".to_string(),
+            ]);
+            fetch.env = None;
+            set_env(pod_spec, "fetch", &[("ARTIFACT_PATH", path.clone())])?;
+        }
+    }
+
+    Ok(job)
+}
+
+fn activate_args(deploy: &CodeDeploy) -> Vec<&str> {
+    match deploy.kind {
+        LibraryItemKind::Plugin => vec!["plugin", "activate", &deploy.slug],
+        LibraryItemKind::Theme => vec!["theme", "activate", &deploy.slug],
+    }
+}
+
+/// What to restore if the deploy has to be rolled back.
+enum PreviousState {
+    Plugin { active: bool },
+    Theme { active: String },
+}
+
+impl KwpmClient {
+    async fn run_code_deploy_job(
+        &self,
+        site: &str,
+        deploy: &CodeDeploy,
+        action: SwapAction,
+    ) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let job = self
+            .create_job(&ns_name, code_deploy_job(site, deploy, action)?)
+            .await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+
+        let job = self
+            .wait_for_job(&ns_name, &job_name, CODE_DEPLOY_TIMEOUT)
+            .await?;
+        if !is_job_succeeded(&job) {
+            let logs = self.job_logs(&ns_name, &job_name).await.unwrap_or_default();
+            bail!("code deploy job {} failed:\n{}", job_name, logs);
+        }
+
+        Ok(())
+    }
+
+    async fn activate_code(&self, site: &str, deploy: &CodeDeploy) -> Result<()> {
+        self.run_wp_cli(site, &activate_args(deploy)).await?;
+        self.run_wp_cli(site, &["cache", "flush"]).await?;
+        self.check_site_health(site).await
+    }
+
+    async fn rollback_code(
+        &self,
+        site: &str,
+        deploy: &CodeDeploy,
+        previous: &PreviousState,
+    ) -> Result<()> {
+        if let PreviousState::Plugin { active: false } = previous {
+            self.run_wp_cli(site, &["plugin", "deactivate", &deploy.slug])
+                .await?;
+        }
+        self.run_code_deploy_job(site, deploy, SwapAction::Rollback)
+            .await?;
+        if let PreviousState::Theme { active } = previous {
+            self.run_wp_cli(site, &["theme", "activate", active])
+                .await?;
+        }
+        self.run_wp_cli(site, &["cache", "flush"]).await?;
+
+        Ok(())
+    }
+
+    /// Swaps a theme or plugin build into the site, activates it, flushes the object cache and
+    /// checks the site is healthy. If anything after the swap fails the previous build and
+    /// activation state are restored before the error is returned.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn deploy_code(&self, site: &str, deploy: &CodeDeploy) -> Result<()> {
+        let lock = self.lock_site(site, "deploy_code").await?;
+        let result: Result<_> = async {
+            let mut progress = self.start_operation(site, "deploy_code").await;
+            let job = code_deploy_job(site, deploy, SwapAction::Deploy)?;
+            self.check_policy("deploy_code", Some(site), &[serde_json::to_value(&job)?])
+                .await?;
+            progress.step("taking snapshot", Some(10)).await;
+            self.snapshot_if_configured(site, RiskyOperation::CodeDeploy)
+                .await?;
+
+            let previous = match deploy.kind {
+                LibraryItemKind::Plugin => PreviousState::Plugin {
+                    active: self
+                        .run_wp_cli(site, &["plugin", "is-active", &deploy.slug])
+                        .await
+                        .is_ok(),
+                },
+                LibraryItemKind::Theme => PreviousState::Theme {
+                    active: self
+                        .run_wp_cli(site, &["theme", "list", "--status=active", "--field=name"])
+                        .await?
+                        .trim()
+                        .to_string(),
+                },
+            };
+
+            lock.ensure_held()?;
+            progress.step("swapping build", Some(30)).await;
+            self.run_code_deploy_job(site, deploy, SwapAction::Deploy)
+                .await?;
+
+            progress
+                .step("activating and checking health", Some(70))
+                .await;
+            if let Err(e) = self.activate_code(site, deploy).await {
+                progress.step("rolling back", Some(80)).await;
+                self.rollback_code(site, deploy, &previous)
+                    .await
+                    .with_context(|| format!("rollback after failed deploy ({:#}) failed", e))?;
+                let e: KwpmError = anyhow::Error::from(e)
+                    .context(format!(
+                        "deploy of {} to {} failed and was rolled back",
+                        deploy.slug, site
+                    ))
+                    .into();
+                progress.fail(&e).await;
+                return Err(e);
+            }
+
+            progress.succeed().await;
+            Ok(())
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(job: &Job, container: &str, name: &str) -> Option<String> {
+        let pod_spec = job.spec.as_ref()?.template.spec.as_ref()?;
+        pod_spec
+            .containers
+            .iter()
+            .chain(pod_spec.init_containers.iter().flatten())
+            .find(|c| c.name == container)?
+            .env
+            .as_ref()?
+            .iter()
+            .find(|e| e.name == name)?
+            .value
+            .clone()
+    }
+
+    #[test]
+    fn test_code_deploy_job_from_zip() {
+        let deploy = CodeDeploy {
+            kind: LibraryItemKind::Theme,
+            slug: "acme".to_string(),
+            artifact: CodeArtifact::Zip {
+                url: "https://ci.example.com/acme.zip".to_string(),
+            },
+        };
+
+        let job = code_deploy_job("blog", &deploy, SwapAction::Deploy).unwrap();
+        assert_eq!(env(&job, "swap", "KIND").as_deref(), Some("themes"));
+        assert_eq!(env(&job, "swap", "ACTION").as_deref(), Some("deploy"));
+        assert_eq!(
+            env(&job, "fetch", "ARTIFACT_URL").as_deref(),
+            Some("https://ci.example.com/acme.zip")
+        );
+
+        let rollback = code_deploy_job("blog", &deploy, SwapAction::Rollback).unwrap();
+        assert_eq!(
+            env(&rollback, "swap", "ACTION").as_deref(),
+            Some("rollback")
+        );
+        assert!(rollback
+            .spec
+            .unwrap()
+            .template
+            .spec
+            .unwrap()
+            .init_containers
+            .is_none());
+    }
+
+    #[test]
+    fn test_code_deploy_job_from_image() {
+        let deploy = CodeDeploy {
+            kind: LibraryItemKind::Plugin,
+            slug: "acme-blocks".to_string(),
+            artifact: CodeArtifact::Image {
+                image: "ghcr.io/acme/blocks:1.2.0".to_string(),
+                path: "/plugin".to_string(),
+            },
+        };
+
+        let job = code_deploy_job("blog", &deploy, SwapAction::Deploy).unwrap();
+        let pod_spec = job.spec.as_ref().unwrap().template.spec.as_ref().unwrap();
+        let fetch = &pod_spec.init_containers.as_ref().unwrap()[0];
+        assert_eq!(fetch.image.as_deref(), Some("ghcr.io/acme/blocks:1.2.0"));
+        assert_eq!(
+            env(&job, "fetch", "ARTIFACT_PATH").as_deref(),
+            Some("/plugin")
+        );
+        assert_eq!(env(&job, "fetch", "ARTIFACT_URL"), None);
+    }
+
+    #[test]
+    fn test_code_deploy_job_rejects_invalid_slug() {
+        let deploy = CodeDeploy {
+            kind: LibraryItemKind::Plugin,
+            slug: "../wp-config".to_string(),
+            artifact: CodeArtifact::Zip {
+                url: "https://ci.example.com/x.zip".to_string(),
+            },
+        };
+
+        assert!(code_deploy_job("blog", &deploy, SwapAction::Deploy).is_err());
+    }
+}
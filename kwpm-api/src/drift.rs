@@ -0,0 +1,285 @@
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{ConfigMap, ObjectReference},
+    networking::v1::Ingress,
+};
+use kube::Api;
+use serde_json::{json, Value};
+
+use crate::{
+    access::{admin_ingresses, is_site_ingress},
+    error::Result,
+    nginx::render_nginx_config,
+    rollout::apply_rollout_strategy,
+    uploads::uploads_ini_config,
+    KwpmClient,
+};
+
+/// A kwpm-managed resource whose live state no longer matches what kwpm rendered for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Drift {
+    pub kind: String,
+    pub name: String,
+    /// Changed lines of the managed fields as YAML, `-` for the rendered and `+` for the live
+    /// state. Empty if the resource was deleted.
+    pub diff: String,
+    pub missing: bool,
+}
+
+struct Managed {
+    kind: &'static str,
+    api_version: &'static str,
+    name: String,
+    desired: Value,
+    live: Option<Value>,
+}
+
+/// Narrows `live` down to the fields present in `desired`, so fields defaulted or owned by
+/// other controllers don't show up as drift.
+pub fn project(live: &Value, desired: &Value) -> Value {
+    match (live, desired) {
+        (Value::Object(live), Value::Object(desired)) => Value::Object(
+            desired
+                .iter()
+                .map(|(key, desired)| {
+                    let live = live.get(key).unwrap_or(&Value::Null);
+                    (key.clone(), project(live, desired))
+                })
+                .collect(),
+        ),
+        (Value::Array(live), Value::Array(desired)) if live.len() == desired.len() => Value::Array(
+            live.iter()
+                .zip(desired)
+                .map(|(live, desired)| project(live, desired))
+                .collect(),
+        ),
+        _ => live.clone(),
+    }
+}
+
+/// Line diff of two YAML documents listing only removed (`-`) and added (`+`) lines.
+pub fn line_diff(desired: &str, live: &str) -> String {
+    let a: Vec<&str> = desired.lines().collect();
+    let b: Vec<&str> = live.lines().collect();
+
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("-{}", a[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", b[j]));
+            j += 1;
+        }
+    }
+
+    diff.join("\n")
+}
+
+fn compare(managed: &Managed) -> Result<Option<Drift>> {
+    let Some(live) = &managed.live else {
+        return Ok(Some(Drift {
+            kind: managed.kind.to_string(),
+            name: managed.name.clone(),
+            diff: String::new(),
+            missing: true,
+        }));
+    };
+
+    let desired = serde_yaml::to_string(&managed.desired)?;
+    let live = serde_yaml::to_string(&project(live, &managed.desired))?;
+    if desired == live {
+        return Ok(None);
+    }
+
+    Ok(Some(Drift {
+        kind: managed.kind.to_string(),
+        name: managed.name.clone(),
+        diff: line_diff(&desired, &live),
+        missing: false,
+    }))
+}
+
+impl KwpmClient {
+    async fn managed_resources(&self, site: &str) -> Result<Vec<Managed>> {
+        let ns_name = self.site_namespace(site);
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let spec = self.get_site_spec(site).await?;
+        let mut managed = Vec::new();
+
+        let mut config_maps = vec![render_nginx_config(&spec)?];
+        if let Some(limit_mb) = spec.upload_limit_mb {
+            config_maps.push(uploads_ini_config(limit_mb)?);
+        }
+        for config_map in config_maps {
+            let name = config_map.metadata.name.clone().unwrap_or_default();
+            managed.push(Managed {
+                kind: "ConfigMap",
+                api_version: "v1",
+                live: config_map_api
+                    .get_opt(&name)
+                    .await?
+                    .map(|live| json!({ "data": live.data })),
+                desired: json!({ "data": config_map.data }),
+                name,
+            });
+        }
+
+        if let Some(access) = &spec.admin_access {
+            let ingresses = ingress_api.list(&Default::default()).await?;
+            if let Some(site_ingress) = ingresses.items.iter().find(|i| is_site_ingress(i)) {
+                for ingress in admin_ingresses(site_ingress, access)? {
+                    let name = ingress.metadata.name.clone().unwrap_or_default();
+                    let fields = |ingress: &Ingress| {
+                        json!({
+                            "metadata": { "annotations": ingress.metadata.annotations },
+                            "spec": ingress.spec,
+                        })
+                    };
+                    managed.push(Managed {
+                        kind: "Ingress",
+                        api_version: "networking.k8s.io/v1",
+                        live: ingress_api.get_opt(&name).await?.map(|live| fields(&live)),
+                        desired: fields(&ingress),
+                        name,
+                    });
+                }
+            }
+        }
+
+        if let Some(rollout) = &spec.rollout {
+            if let Some(live) = deployment_api.get_opt("wordpress").await? {
+                let mut desired = live.clone();
+                apply_rollout_strategy(&mut desired, rollout, &self.site_url(site).await?)?;
+
+                let fields = |deployment: &Deployment| {
+                    let spec = deployment.spec.as_ref();
+                    json!({ "spec": {
+                        "strategy": spec.and_then(|s| s.strategy.as_ref()),
+                        "minReadySeconds": spec.and_then(|s| s.min_ready_seconds),
+                        "template": { "spec": { "containers": spec
+                            .and_then(|s| s.template.spec.as_ref())
+                            .map(|s| &s.containers) } },
+                    } })
+                };
+                managed.push(Managed {
+                    kind: "Deployment",
+                    api_version: "apps/v1",
+                    name: "wordpress".to_string(),
+                    desired: fields(&desired),
+                    live: Some(fields(&live)),
+                });
+            }
+        }
+
+        Ok(managed)
+    }
+
+    /// Compares the kwpm-managed resources of a site with what kwpm would render from its spec,
+    /// without changing anything.
+    pub async fn detect_drift(&self, site: &str) -> Result<Vec<Drift>> {
+        let mut drifts = Vec::new();
+        for managed in self.managed_resources(site).await? {
+            drifts.extend(compare(&managed)?);
+        }
+        Ok(drifts)
+    }
+
+    /// Like `detect_drift`, but also records a `ConfigDrift` warning event with the diff on
+    /// every drifted resource, for teams that want visibility without auto-healing.
+    pub async fn alert_on_drift(&self, site: &str) -> Result<Vec<Drift>> {
+        let ns_name = self.site_namespace(site);
+
+        let mut drifts = Vec::new();
+        for managed in self.managed_resources(site).await? {
+            let Some(drift) = compare(&managed)? else {
+                continue;
+            };
+
+            let message = if drift.missing {
+                format!("{} {} was deleted outside of kwpm", drift.kind, drift.name)
+            } else {
+                format!(
+                    "{} {} was modified outside of kwpm:\n{}",
+                    drift.kind, drift.name, drift.diff
+                )
+            };
+            self.record_warning(
+                ObjectReference {
+                    api_version: Some(managed.api_version.to_string()),
+                    kind: Some(managed.kind.to_string()),
+                    name: Some(managed.name.clone()),
+                    namespace: Some(ns_name.clone()),
+                    ..Default::default()
+                },
+                "ConfigDrift",
+                message,
+            )
+            .await?;
+
+            drifts.push(drift);
+        }
+
+        Ok(drifts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_ignores_unmanaged_fields() {
+        let desired = json!({ "spec": { "rules": [{ "host": "blog.example.com" }] } });
+        let live = json!({
+            "metadata": { "resourceVersion": "42" },
+            "spec": { "rules": [{ "host": "blog.example.com", "http": {} }], "tls": [] },
+        });
+
+        assert_eq!(project(&live, &desired), desired);
+    }
+
+    #[test]
+    fn test_line_diff() {
+        let desired = "data:\n  a: 1\n  b: 2\n  c: 3\n";
+        let live = "data:\n  a: 1\n  b: 5\n  c: 3\n";
+
+        assert_eq!(line_diff(desired, live), "-  b: 2\n+  b: 5");
+        assert_eq!(line_diff(desired, desired), "");
+    }
+
+    #[test]
+    fn test_compare_detects_modified_config() {
+        let managed = Managed {
+            kind: "ConfigMap",
+            api_version: "v1",
+            name: "wp-uploads-ini-config".to_string(),
+            desired: json!({ "data": { "uploads.ini": "upload_max_filesize = 64M" } }),
+            live: Some(json!({ "data": { "uploads.ini": "upload_max_filesize = 1G" } })),
+        };
+
+        let drift = compare(&managed).unwrap().unwrap();
+        assert!(!drift.missing);
+        assert!(drift
+            .diff
+            .contains("+  uploads.ini: upload_max_filesize = 1G"));
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        core::v1::{ResourceRequirements, Secret},
+        networking::v1::Ingress,
+    },
+    apimachinery::pkg::api::resource::Quantity,
+};
+use kube::{
+    api::{ObjectMeta, Patch},
+    Api,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    canary::CANARY_NAME, debug::apply_debug, error::Result, healthz::HEALTHZ_INGRESS_NAME,
+    manifest::deployment_pod_spec_mut, KwpmClient,
+};
+
+const BASIC_AUTH_SECRET_NAME: &str = "kwpm-basic-auth";
+const BASIC_AUTH_USER: &str = "kwpm";
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Dev,
+    Staging,
+    #[default]
+    Production,
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Dev => "dev",
+            Environment::Staging => "staging",
+            Environment::Production => "production",
+        }
+    }
+
+    pub fn default_profile(&self) -> EnvironmentProfile {
+        let profile = |cpu: &str, memory: &str, limit: &str, schedule: &str, issuer: &str| {
+            EnvironmentProfile {
+                cpu_request: cpu.to_string(),
+                memory_request: memory.to_string(),
+                memory_limit: limit.to_string(),
+                debug: false,
+                basic_auth: false,
+                backup_schedule: schedule.to_string(),
+                cluster_issuer: issuer.to_string(),
+            }
+        };
+
+        match self {
+            Environment::Dev => EnvironmentProfile {
+                debug: true,
+                basic_auth: true,
+                ..profile("50m", "128Mi", "256Mi", "0 3 * * 0", "letsencrypt-staging")
+            },
+            Environment::Staging => EnvironmentProfile {
+                basic_auth: true,
+                ..profile("100m", "256Mi", "512Mi", "0 3 * * *", "letsencrypt-staging")
+            },
+            Environment::Production => {
+                profile("250m", "512Mi", "1Gi", "0 */6 * * *", "letsencrypt-prod")
+            }
+        }
+    }
+}
+
+/// Defaults a site gets from its environment tier.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvironmentProfile {
+    pub cpu_request: String,
+    pub memory_request: String,
+    pub memory_limit: String,
+    /// WordPress debug logging, see `set_debug`.
+    pub debug: bool,
+    /// Puts the whole site behind HTTP basic auth, e.g. to keep search engines out of staging.
+    pub basic_auth: bool,
+    /// Cron schedule of the site's backups, applied when backup storage is configured.
+    pub backup_schedule: String,
+    /// cert-manager ClusterIssuer of the site's certificates.
+    pub cluster_issuer: String,
+}
+
+/// Sets the resources and debug logging of `profile`. The site's custom `constants` share the
+/// config extra with debug logging, see `apply_debug`.
+pub fn apply_environment_profile(
+    deployment: &mut Deployment,
+    profile: &EnvironmentProfile,
+    constants: &BTreeMap<String, Value>,
+) -> Result<()> {
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+    let wordpress = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "wordpress")
+        .context("deployment has no wordpress container")?;
+    wordpress.resources = Some(ResourceRequirements {
+        requests: Some(BTreeMap::from([
+            ("cpu".to_string(), Quantity(profile.cpu_request.clone())),
+            (
+                "memory".to_string(),
+                Quantity(profile.memory_request.clone()),
+            ),
+        ])),
+        limits: Some(BTreeMap::from([(
+            "memory".to_string(),
+            Quantity(profile.memory_limit.clone()),
+        )])),
+        ..Default::default()
+    });
+
+    apply_debug(deployment, profile.debug, constants)
+}
+
+/// The ingress annotations of `profile`, `None` for those to remove.
+fn environment_annotations(profile: &EnvironmentProfile) -> BTreeMap<&'static str, Option<String>> {
+    let basic_auth = |value: &str| profile.basic_auth.then(|| value.to_string());
+
+    BTreeMap::from([
+        (
+            "cert-manager.io/cluster-issuer",
+            Some(profile.cluster_issuer.clone()),
+        ),
+        ("nginx.ingress.kubernetes.io/auth-type", basic_auth("basic")),
+        (
+            "nginx.ingress.kubernetes.io/auth-secret",
+            basic_auth(BASIC_AUTH_SECRET_NAME),
+        ),
+        (
+            "nginx.ingress.kubernetes.io/auth-realm",
+            basic_auth("Authentication required"),
+        ),
+    ])
+}
+
+/// Merge patch setting the certificate issuer and adding or removing basic auth on an ingress.
+pub fn environment_ingress_patch(profile: &EnvironmentProfile) -> Value {
+    json!({ "metadata": { "annotations": environment_annotations(profile) } })
+}
+
+/// Like `environment_ingress_patch`, for an ingress that is yet to be created.
+pub fn apply_environment_annotations(ingress: &mut Ingress, profile: &EnvironmentProfile) {
+    let annotations = ingress
+        .metadata
+        .annotations
+        .get_or_insert_with(Default::default);
+    for (name, value) in environment_annotations(profile) {
+        match value {
+            Some(value) => annotations.insert(name.to_string(), value),
+            None => annotations.remove(name),
+        };
+    }
+}
+
+/// An htpasswd Secret for ingress-nginx, using nginx's `{PLAIN}` scheme.
+pub fn basic_auth_secret(password: &str) -> Secret {
+    Secret {
+        metadata: ObjectMeta {
+            name: Some(BASIC_AUTH_SECRET_NAME.to_string()),
+            ..Default::default()
+        },
+        string_data: Some(BTreeMap::from([
+            (
+                "auth".to_string(),
+                format!("{}:{{PLAIN}}{}", BASIC_AUTH_USER, password),
+            ),
+            ("user".to_string(), BASIC_AUTH_USER.to_string()),
+            ("password".to_string(), password.to_string()),
+        ])),
+        ..Default::default()
+    }
+}
+
+impl KwpmClient {
+    /// Overrides the defaults of an environment tier.
+    pub fn with_environment_profile(
+        mut self,
+        environment: Environment,
+        profile: EnvironmentProfile,
+    ) -> Self {
+        self.environment_profiles.insert(environment, profile);
+        self
+    }
+
+    pub fn environment_profile(&self, environment: Environment) -> EnvironmentProfile {
+        self.environment_profiles
+            .get(&environment)
+            .cloned()
+            .unwrap_or_else(|| environment.default_profile())
+    }
+
+    /// The tier new sites are created in, none by default, which leaves their resources to
+    /// the templates.
+    pub fn with_default_environment(mut self, environment: Environment) -> Self {
+        self.default_environment = Some(environment);
+        self
+    }
+
+    /// Creates the basic auth secret of a site unless it exists already.
+    pub(crate) async fn ensure_basic_auth_secret(&self, site: &str) -> Result<()> {
+        let secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        if secret_api.get_opt(BASIC_AUTH_SECRET_NAME).await?.is_some() {
+            return Ok(());
+        }
+
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes)?;
+        let password: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        secret_api
+            .create(
+                &Default::default(),
+                &self.labeled(&basic_auth_secret(&password)),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Moves a site to an environment tier and applies the tier's resources, debug flag, basic
+    /// auth, certificate issuer and, with backup storage configured, backup schedule.
+    pub async fn set_environment(&self, site: &str, environment: Environment) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let profile = self.environment_profile(environment);
+        let mut spec = self.get_site_spec(site).await?;
+        let mut deployment = deployment_api.get("wordpress").await?;
+        apply_environment_profile(&mut deployment, &profile, &spec.config_constants)?;
+        let ingress_patch = environment_ingress_patch(&profile);
+        self.check_policy(
+            "set_environment",
+            Some(site),
+            &[serde_json::to_value(&deployment)?, ingress_patch.clone()],
+        )
+        .await?;
+
+        spec.environment = Some(environment);
+        spec.debug = profile.debug;
+        self.save_site_spec(site, &spec).await?;
+
+        if profile.basic_auth {
+            self.ensure_basic_auth_secret(site).await?;
+        }
+
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+        for ingress in ingress_api.list(&Default::default()).await? {
+            let name = ingress.metadata.name.unwrap_or_default();
+            if name != CANARY_NAME && name != HEALTHZ_INGRESS_NAME {
+                ingress_api
+                    .patch(&name, &Default::default(), &Patch::Merge(&ingress_patch))
+                    .await?;
+            }
+        }
+
+        if self.backup_storage.is_some() {
+            self.schedule_backups(site, Some(&profile.backup_schedule))
+                .await?;
+        }
+
+        self.record_audit(site, "set_environment", environment.as_str())
+            .await?;
+        self.record_site_revision(site, "set_environment").await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> Deployment {
+        serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_environment_profile() {
+        let mut deployment = deployment();
+        apply_environment_profile(
+            &mut deployment,
+            &Environment::Dev.default_profile(),
+            &BTreeMap::new(),
+        )
+        .unwrap();
+
+        let pod_spec = deployment_pod_spec_mut(&mut deployment).unwrap();
+        let wordpress = &pod_spec.containers[0];
+        let resources = wordpress.resources.as_ref().unwrap();
+        assert_eq!(resources.requests.as_ref().unwrap()["memory"].0, "128Mi");
+        assert!(wordpress
+            .env
+            .as_ref()
+            .unwrap()
+            .iter()
+            .any(|e| e.name == "WORDPRESS_DEBUG" && e.value.as_deref() == Some("1")));
+    }
+
+    #[test]
+    fn test_environment_ingress_patch() {
+        let staging = environment_ingress_patch(&Environment::Staging.default_profile());
+        let annotations = &staging["metadata"]["annotations"];
+        assert_eq!(
+            annotations["cert-manager.io/cluster-issuer"],
+            "letsencrypt-staging"
+        );
+        assert_eq!(
+            annotations["nginx.ingress.kubernetes.io/auth-secret"],
+            BASIC_AUTH_SECRET_NAME
+        );
+
+        let production = environment_ingress_patch(&Environment::Production.default_profile());
+        let annotations = &production["metadata"]["annotations"];
+        assert_eq!(
+            annotations["cert-manager.io/cluster-issuer"],
+            "letsencrypt-prod"
+        );
+        assert!(annotations["nginx.ingress.kubernetes.io/auth-type"].is_null());
+
+        let mut ingress: Ingress =
+            serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-ingress.yaml"))
+                .unwrap();
+        apply_environment_annotations(&mut ingress, &Environment::Dev.default_profile());
+        apply_environment_annotations(&mut ingress, &Environment::Production.default_profile());
+        let annotations = ingress.metadata.annotations.unwrap();
+        assert_eq!(
+            annotations["cert-manager.io/cluster-issuer"],
+            "letsencrypt-prod"
+        );
+        assert!(!annotations.contains_key("nginx.ingress.kubernetes.io/auth-type"));
+    }
+
+    #[test]
+    fn test_basic_auth_secret() {
+        let secret = basic_auth_secret("s3cret");
+
+        assert_eq!(secret.string_data.unwrap()["auth"], "kwpm:{PLAIN}s3cret");
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+use crate::{confirm::ConfirmationRequired, lock::SiteLockConflict, readonly::ReadOnlyViolation};
+
+/// What went wrong in a kwpm operation, for mapping failures to e.g. HTTP status codes. Each
+/// variant carries the full error with its context; typed causes such as
+/// [`SiteLockConflict`] can be read with [`KwpmError::downcast_ref`].
+#[derive(Debug)]
+pub enum KwpmError {
+    /// The object kwpm was asked to create exists already.
+    AlreadyExists(anyhow::Error),
+    /// The site, backup or object an operation refers to doesn't exist.
+    NotFound(anyhow::Error),
+    /// The request was rejected before anything changed, e.g. an invalid site name.
+    InvalidInput(anyhow::Error),
+    /// A manifest template or generated object couldn't be parsed.
+    InvalidManifest(anyhow::Error),
+    /// Another operation holds the lock of the site, see [`SiteLockConflict`].
+    Conflict(anyhow::Error),
+    /// A destructive operation was attempted without a valid token, see
+    /// [`ConfirmationRequired`].
+    ConfirmationRequired(anyhow::Error),
+    /// A change was attempted through a read-only client, see [`ReadOnlyViolation`].
+    ReadOnly(anyhow::Error),
+    /// The Kubernetes API rejected a request for another reason.
+    KubeApi(anyhow::Error),
+    /// Anything else, e.g. a failed job or an unreachable service.
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = KwpmError> = std::result::Result<T, E>;
+
+/// Like `anyhow::bail!`, but for functions returning either [`Result`].
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(anyhow::anyhow!($($arg)*).into())
+    };
+}
+pub(crate) use bail;
+
+impl KwpmError {
+    pub fn already_exists(message: impl fmt::Display) -> Self {
+        Self::AlreadyExists(anyhow::anyhow!("{}", message))
+    }
+
+    pub fn not_found(message: impl fmt::Display) -> Self {
+        Self::NotFound(anyhow::anyhow!("{}", message))
+    }
+
+    pub fn invalid_input(message: impl fmt::Display) -> Self {
+        Self::InvalidInput(anyhow::anyhow!("{}", message))
+    }
+
+    pub fn invalid_manifest(message: impl fmt::Display) -> Self {
+        Self::InvalidManifest(anyhow::anyhow!("{}", message))
+    }
+
+    fn error(&self) -> &anyhow::Error {
+        match self {
+            KwpmError::AlreadyExists(e)
+            | KwpmError::NotFound(e)
+            | KwpmError::InvalidInput(e)
+            | KwpmError::InvalidManifest(e)
+            | KwpmError::Conflict(e)
+            | KwpmError::ConfirmationRequired(e)
+            | KwpmError::ReadOnly(e)
+            | KwpmError::KubeApi(e)
+            | KwpmError::Other(e) => e,
+        }
+    }
+
+    /// The constructor of this kind of error, for wrapping it with more context.
+    fn variant(&self) -> fn(anyhow::Error) -> KwpmError {
+        match self {
+            KwpmError::AlreadyExists(_) => KwpmError::AlreadyExists,
+            KwpmError::NotFound(_) => KwpmError::NotFound,
+            KwpmError::InvalidInput(_) => KwpmError::InvalidInput,
+            KwpmError::InvalidManifest(_) => KwpmError::InvalidManifest,
+            KwpmError::Conflict(_) => KwpmError::Conflict,
+            KwpmError::ConfirmationRequired(_) => KwpmError::ConfirmationRequired,
+            KwpmError::ReadOnly(_) => KwpmError::ReadOnly,
+            KwpmError::KubeApi(_) => KwpmError::KubeApi,
+            KwpmError::Other(_) => KwpmError::Other,
+        }
+    }
+
+    /// A cause of the error of type `E`, e.g. [`SiteLockConflict`] or `kube::Error`.
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.error()
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<E>())
+    }
+
+    /// The status of the Kubernetes API response that caused the error, if any.
+    pub fn kube_status(&self) -> Option<u16> {
+        match self.downcast_ref::<kube::Error>() {
+            Some(kube::Error::Api(response)) => Some(response.code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KwpmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.error())
+        } else {
+            write!(f, "{}", self.error())
+        }
+    }
+}
+
+impl std::error::Error for KwpmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error().chain().nth(1)
+    }
+}
+
+/// The kind of error `cause` makes the error it is part of.
+fn classify(cause: &(dyn std::error::Error + 'static)) -> Option<fn(anyhow::Error) -> KwpmError> {
+    if let Some(kwpm_error) = cause.downcast_ref::<KwpmError>() {
+        return Some(kwpm_error.variant());
+    }
+    if cause.is::<SiteLockConflict>() {
+        return Some(KwpmError::Conflict);
+    }
+    if cause.is::<ConfirmationRequired>() {
+        return Some(KwpmError::ConfirmationRequired);
+    }
+    if cause.is::<ReadOnlyViolation>() {
+        return Some(KwpmError::ReadOnly);
+    }
+    if cause.is::<serde_yaml::Error>() {
+        return Some(KwpmError::InvalidManifest);
+    }
+    match cause.downcast_ref::<kube::Error>() {
+        Some(kube::Error::Api(response)) => Some(match (response.code, response.reason.as_str()) {
+            (404, _) => KwpmError::NotFound,
+            (409, "AlreadyExists") => KwpmError::AlreadyExists,
+            _ => KwpmError::KubeApi,
+        }),
+        _ => None,
+    }
+}
+
+impl From<anyhow::Error> for KwpmError {
+    fn from(e: anyhow::Error) -> Self {
+        // Only unwrap a `KwpmError` without context: `downcast` would drop the context.
+        let e = match e.chain().next() {
+            Some(cause) if cause.is::<KwpmError>() => match e.downcast::<KwpmError>() {
+                Ok(kwpm_error) => return kwpm_error,
+                Err(e) => e,
+            },
+            _ => e,
+        };
+
+        let variant = e.chain().find_map(classify).unwrap_or(KwpmError::Other);
+        variant(e)
+    }
+}
+
+/// Errors of the libraries kwpm uses, classified like any other error.
+macro_rules! from_error {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for KwpmError {
+                fn from(e: $error) -> Self {
+                    anyhow::Error::from(e).into()
+                }
+            }
+        )*
+    };
+}
+
+from_error!(
+    kube::Error,
+    kube::config::InferConfigError,
+    kube::runtime::wait::Error,
+    serde_json::Error,
+    serde_yaml::Error,
+    object_store::Error,
+    reqwest::Error,
+    http::Error,
+    lettre::address::AddressError,
+    lettre::error::Error,
+    lettre::transport::smtp::Error,
+    getrandom::Error,
+    ring::error::Unspecified,
+    chrono::ParseError,
+    chrono::OutOfRangeError,
+    std::io::Error,
+    std::num::ParseFloatError,
+    std::path::StripPrefixError,
+    std::string::FromUtf8Error,
+    SiteLockConflict,
+    ConfirmationRequired,
+    ReadOnlyViolation,
+);
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    fn api_error(code: u16, reason: &str) -> kube::Error {
+        kube::Error::Api(kube::core::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "namespaces \"kwpm-blog\" ...".to_string(),
+            reason: reason.to_string(),
+            code,
+        })
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        let not_found: KwpmError = Err::<(), _>(api_error(404, "NotFound"))
+            .context("removing blog failed")
+            .unwrap_err()
+            .into();
+        assert!(matches!(not_found, KwpmError::NotFound(_)));
+        assert_eq!(not_found.kube_status(), Some(404));
+        assert_eq!(not_found.to_string(), "removing blog failed");
+
+        let exists: KwpmError = api_error(409, "AlreadyExists").into();
+        assert!(matches!(exists, KwpmError::AlreadyExists(_)));
+        let conflict: KwpmError = api_error(409, "Conflict").into();
+        assert!(matches!(conflict, KwpmError::KubeApi(_)));
+
+        let invalid: anyhow::Error = KwpmError::invalid_input("invalid site name: Blog").into();
+        let invalid: KwpmError = invalid.context("creating Blog failed").into();
+        assert!(matches!(invalid, KwpmError::InvalidInput(_)));
+        assert_eq!(
+            format!("{:#}", invalid),
+            "creating Blog failed: invalid site name: Blog"
+        );
+
+        let locked: KwpmError = SiteLockConflict {
+            site: "blog".to_string(),
+            holder: "kwpm-1".to_string(),
+            operation: "backup_site".to_string(),
+        }
+        .into();
+        assert!(matches!(locked, KwpmError::Conflict(_)));
+        assert_eq!(
+            locked.downcast_ref::<SiteLockConflict>().unwrap().operation,
+            "backup_site"
+        );
+
+        let other: KwpmError = anyhow::anyhow!("job failed").into();
+        assert!(matches!(other, KwpmError::Other(_)));
+    }
+}
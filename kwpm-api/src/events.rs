@@ -0,0 +1,74 @@
+use chrono::Utc;
+use k8s_openapi::{
+    api::core::v1::{Event, EventSource, ObjectReference},
+    apimachinery::pkg::apis::meta::v1::Time,
+};
+use kube::{api::ObjectMeta, Api};
+
+use crate::{error::Result, KwpmClient};
+
+const MAX_EVENT_MESSAGE_LEN: usize = 1024;
+
+pub(crate) fn truncate_message(message: &mut String) {
+    if message.len() > MAX_EVENT_MESSAGE_LEN {
+        let mut end = MAX_EVENT_MESSAGE_LEN;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+}
+
+impl KwpmClient {
+    /// Records a Warning event from kwpm on `involved_object`, so it shows up in
+    /// `kubectl describe` and `kubectl get events`.
+    pub(crate) async fn record_warning(
+        &self,
+        involved_object: ObjectReference,
+        reason: &str,
+        mut message: String,
+    ) -> Result<()> {
+        let namespace = involved_object.namespace.clone().unwrap_or_default();
+        let event_api: Api<Event> = Api::namespaced(self.client.clone(), &namespace);
+        truncate_message(&mut message);
+
+        let now = Time(Utc::now());
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("kwpm-{}-", reason.to_lowercase())),
+                ..Default::default()
+            },
+            involved_object,
+            reason: Some(reason.to_string()),
+            message: Some(message),
+            type_: Some("Warning".to_string()),
+            source: Some(EventSource {
+                component: Some("kwpm".to_string()),
+                ..Default::default()
+            }),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            count: Some(1),
+            ..Default::default()
+        };
+        event_api
+            .create(&Default::default(), &self.labeled(&event))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_message_on_char_boundary() {
+        let mut message = "ä".repeat(MAX_EVENT_MESSAGE_LEN);
+        truncate_message(&mut message);
+
+        assert_eq!(message.len(), MAX_EVENT_MESSAGE_LEN);
+        assert!(message.chars().all(|c| c == 'ä'));
+    }
+}
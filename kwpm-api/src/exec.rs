@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{AttachParams, ListParams},
+    Api, ResourceExt,
+};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+/// What a command run in a pod printed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// A line a streamed command printed, or how it ended.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExecEvent {
+    Stdout(String),
+    Stderr(String),
+    /// The last event, with the reason of a failure.
+    Exit {
+        success: bool,
+        message: Option<String>,
+    },
+}
+
+/// The newest running, ready pod of a deployment that isn't being replaced.
+pub fn pick_exec_pod(pods: &[Pod]) -> Option<String> {
+    pods.iter()
+        .filter(|pod| pod.metadata.deletion_timestamp.is_none())
+        .filter(|pod| {
+            let Some(status) = pod.status.as_ref() else {
+                return false;
+            };
+            status.phase.as_deref() == Some("Running")
+                && status
+                    .conditions
+                    .iter()
+                    .flatten()
+                    .any(|c| c.type_ == "Ready" && c.status == "True")
+        })
+        .max_by_key(|pod| pod.creation_timestamp())
+        .map(|pod| pod.name_any())
+}
+
+async fn read_all(stream: Option<impl AsyncRead + Unpin>) -> std::io::Result<String> {
+    let mut output = String::new();
+    if let Some(mut stream) = stream {
+        stream.read_to_string(&mut output).await?;
+    }
+    Ok(output)
+}
+
+fn lines(
+    stream: Option<impl AsyncRead + Unpin + Send + 'static>,
+) -> BoxStream<'static, std::io::Result<String>> {
+    stream::unfold(
+        stream.map(|stream| BufReader::new(stream).lines()),
+        |lines| async move {
+            let mut lines = lines?;
+            match lines.next_line().await {
+                Ok(Some(line)) => Some((Ok(line), Some(lines))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        },
+    )
+    .boxed()
+}
+
+impl KwpmClient {
+    /// Runs `command` in `container` of a pod of the site through the exec subresource, failing
+    /// with its stderr when it exits unsuccessfully or runs longer than `timeout`.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub(crate) async fn exec_in_site(
+        &self,
+        site: &str,
+        container: &str,
+        command: &[&str],
+        timeout: Duration,
+    ) -> Result<ExecOutput> {
+        self.exec_in_pods(
+            &self.site_namespace(site),
+            "app=wordpress,tier=frontend",
+            container,
+            command,
+            timeout,
+        )
+        .await
+    }
+
+    /// Like `exec_in_site`, in a pod of `namespace` matching `selector`.
+    #[tracing::instrument(skip_all, fields(namespace = %namespace, container = %container, pod = tracing::field::Empty))]
+    pub(crate) async fn exec_in_pods(
+        &self,
+        namespace: &str,
+        selector: &str,
+        container: &str,
+        command: &[&str],
+        timeout: Duration,
+    ) -> Result<ExecOutput> {
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let pods = pod_api
+            .list(&ListParams::default().labels(selector))
+            .await?;
+        let pod = pick_exec_pod(&pods.items)
+            .with_context(|| format!("{} has no ready pod to run commands in", namespace))?;
+        tracing::Span::current().record("pod", pod.as_str());
+
+        let mut process = pod_api
+            .exec(
+                &pod,
+                command.to_vec(),
+                &AttachParams::default()
+                    .container(container)
+                    .stdin(false)
+                    .stdout(true)
+                    .stderr(true),
+            )
+            .await?;
+        let status = process.take_status();
+        let (stdout, stderr) = (process.stdout(), process.stderr());
+        let (stdout, stderr) = tokio::time::timeout(timeout, async {
+            tokio::try_join!(read_all(stdout), read_all(stderr))
+        })
+        .await
+        .with_context(|| format!("{} timed out in {}", command.join(" "), pod))?
+        .context("failed to read the output")?;
+        let status = match status {
+            Some(status) => status.await,
+            None => None,
+        };
+        process.join().await.context("exec failed")?;
+
+        if status.is_none_or(|status| status.status.as_deref() != Some("Success")) {
+            bail!("{} failed in {}: {}", command.join(" "), pod, stderr.trim());
+        }
+        Ok(ExecOutput { stdout, stderr })
+    }
+
+    /// Starts `command` in `container` of `pod` and streams its output line by line as it
+    /// arrives, ending with its exit. Dropping the stream closes the connection.
+    pub(crate) async fn exec_stream(
+        &self,
+        namespace: &str,
+        pod: &str,
+        container: &str,
+        command: Vec<String>,
+    ) -> Result<BoxStream<'static, Result<ExecEvent>>> {
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let mut process = pod_api
+            .exec(
+                pod,
+                command,
+                &AttachParams::default()
+                    .container(container)
+                    .stdin(false)
+                    .stdout(true)
+                    .stderr(true),
+            )
+            .await?;
+        let status = process.take_status();
+        let output = stream::select(
+            lines(process.stdout()).map(|line| line.map(ExecEvent::Stdout)),
+            lines(process.stderr()).map(|line| line.map(ExecEvent::Stderr)),
+        )
+        .map(|event| event.map_err(Into::into));
+        let exit = stream::once(async move {
+            let status = match status {
+                Some(status) => status.await,
+                None => None,
+            };
+            process.join().await.context("exec failed")?;
+            let success = status
+                .as_ref()
+                .is_some_and(|status| status.status.as_deref() == Some("Success"));
+            Ok(ExecEvent::Exit {
+                success,
+                message: status
+                    .and_then(|status| status.message)
+                    .filter(|_| !success),
+            })
+        });
+        Ok(output.chain(exit).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::{
+        api::core::v1::{PodCondition, PodStatus},
+        apimachinery::pkg::apis::meta::v1::Time,
+    };
+
+    use super::*;
+
+    fn pod(name: &str, phase: &str, ready: bool, minute: u32) -> Pod {
+        let mut pod = Pod::default();
+        pod.metadata.name = Some(name.to_string());
+        pod.metadata.creation_timestamp = Some(Time(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, minute, 0).unwrap(),
+        ));
+        pod.status = Some(PodStatus {
+            phase: Some(phase.to_string()),
+            conditions: Some(vec![PodCondition {
+                type_: "Ready".to_string(),
+                status: if ready { "True" } else { "False" }.to_string(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        pod
+    }
+
+    #[test]
+    fn test_pick_exec_pod() {
+        let mut terminating = pod("old", "Running", true, 9);
+        terminating.metadata.deletion_timestamp = terminating.metadata.creation_timestamp.clone();
+        let pods = vec![
+            pod("a", "Running", true, 1),
+            pod("b", "Running", true, 2),
+            pod("c", "Running", false, 3),
+            pod("d", "Pending", false, 4),
+            terminating,
+        ];
+        assert_eq!(pick_exec_pod(&pods).as_deref(), Some("b"));
+        assert_eq!(pick_exec_pod(&pods[2..]), None);
+    }
+}
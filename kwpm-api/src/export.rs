@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use k8s_openapi::api::batch::v1::Job;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, Result},
+    manifest::{job_pod_spec_mut, set_env},
+    site::AppKind,
+    KwpmClient,
+};
+
+const STATIC_EXPORT_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// Where a static export of a site is published.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ExportTarget {
+    /// `s3://bucket/prefix/` on the configured backup storage, e.g. a bucket behind a CDN.
+    S3 { url: String },
+    /// A static site (see [`AppKind::Static`]) whose files are replaced by the export.
+    Site { name: String },
+}
+
+fn s3_export_url(url: &str) -> Result<String> {
+    let bucket = url
+        .strip_prefix("s3://")
+        .and_then(|path| path.split('/').next())
+        .unwrap_or_default();
+    if bucket.is_empty() || url.contains("..") {
+        bail!("invalid S3 export url: {}", url);
+    }
+    Ok(format!("{}/", url.trim_end_matches('/')))
+}
+
+/// Job crawling `site_url` into static HTML with links rewritten to relative ones and
+/// publishing the result to `target`.
+pub fn static_export_job(site: &str, site_url: &str, target: &ExportTarget) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/export/static-export-job.yaml"
+    ))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    set_env(pod_spec, "crawl", &[("SITE_URL", site_url.to_string())])?;
+    match target {
+        ExportTarget::S3 { url } => {
+            pod_spec.containers.retain(|c| c.name == "publish-s3");
+            pod_spec
+                .volumes
+                .iter_mut()
+                .for_each(|volumes| volumes.retain(|v| v.name == "export"));
+            set_env(pod_spec, "publish-s3", &[("S3_URL", s3_export_url(url)?)])?;
+        }
+        ExportTarget::Site { .. } => {
+            pod_spec.containers.retain(|c| c.name == "publish-site");
+        }
+    }
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    /// Crawls `site` into static HTML and publishes it to `target`, so WordPress can be used
+    /// as an editor while the traffic is served statically. Re-run it after content changes.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn export_static(&self, site: &str, target: &ExportTarget) -> Result<()> {
+        let lock = self.lock_site(site, "export_static").await?;
+        let result: Result<_> = async {
+            if self.stored_site_spec(site).await?.app == AppKind::Static {
+                bail!("{} is already a static site", site);
+            }
+            let target_lock = match target {
+                ExportTarget::Site { name } => {
+                    if name == site {
+                        bail!("a site can't be exported to itself");
+                    }
+                    if self.stored_site_spec(name).await?.app != AppKind::Static {
+                        bail!("{} is not a static site", name);
+                    }
+                    Some(self.lock_site(name, "export_static").await?)
+                }
+                ExportTarget::S3 { .. } => None,
+            };
+
+            let result: Result<_> = async {
+                let mut progress = self.start_operation(site, "export_static").await;
+                let job = static_export_job(site, &self.site_url(site).await?, target)?;
+                self.check_policy("export_static", Some(site), &[serde_json::to_value(&job)?])
+                    .await?;
+
+                progress.step("crawling and publishing", Some(10)).await;
+                let (namespace, destination) = match target {
+                    ExportTarget::S3 { url } => {
+                        self.apply_backup_secret(site).await?;
+                        (self.site_namespace(site), url.clone())
+                    }
+                    ExportTarget::Site { name } => {
+                        (self.site_namespace(name), format!("site {}", name))
+                    }
+                };
+                if let Err(e) = self.run_job(&namespace, job, STATIC_EXPORT_TIMEOUT).await {
+                    progress.fail(&e).await;
+                    return Err(e);
+                }
+
+                self.record_audit(
+                    site,
+                    "export_static",
+                    &format!("exported static copy to {}", destination),
+                )
+                .await?;
+                progress.succeed().await;
+                Ok(())
+            }
+            .await;
+            match target_lock {
+                Some(target_lock) => target_lock.release_after(result).await,
+                None => result,
+            }
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_export_job() {
+        let job = static_export_job(
+            "blog",
+            "https://blog.example.com/",
+            &ExportTarget::S3 {
+                url: "s3://static/blog".to_string(),
+            },
+        )
+        .unwrap();
+        let pod_spec = job.spec.as_ref().unwrap().template.spec.as_ref().unwrap();
+        assert_eq!(pod_spec.containers.len(), 1);
+        let env = pod_spec.containers[0].env.as_ref().unwrap();
+        assert_eq!(env[0].value.as_deref(), Some("s3://static/blog/"));
+        assert_eq!(pod_spec.volumes.as_ref().unwrap().len(), 1);
+
+        let job = static_export_job(
+            "blog",
+            "https://blog.example.com/",
+            &ExportTarget::Site {
+                name: "blog-static".to_string(),
+            },
+        )
+        .unwrap();
+        let pod_spec = job.spec.as_ref().unwrap().template.spec.as_ref().unwrap();
+        assert_eq!(pod_spec.containers[0].name, "publish-site");
+        assert_eq!(pod_spec.volumes.as_ref().unwrap().len(), 2);
+
+        assert!(s3_export_url("s3:///blog").is_err());
+        assert!(s3_export_url("https://static/blog").is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::Job,
+    core::v1::{Namespace, ObjectReference, Secret, Service},
+};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api, ResourceExt,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    error::{bail, KwpmError, Result},
+    mariadb::{
+        is_valid_mariadb_instance, mariadb_host, mariadb_namespace, namespace_mariadb_instance,
+    },
+    notify::Notification,
+    KwpmClient, COMPONENT_LABEL, MARIADB_DEPLOYMENT_NAME,
+};
+
+/// On the namespace of an instance, the instance its sites fail over to when it is down.
+pub const STANDBY_ANNOTATION: &str = "kwpm.io/mariadb-standby";
+/// How often the operator checks the instances that have a standby.
+pub const FAILOVER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Checks in a row an instance has to be down for, so restarts don't fail it over.
+pub const FAILOVER_THRESHOLD: u32 = 4;
+const PROMOTE_TIMEOUT: Duration = Duration::from_secs(120);
+/// The DNS domain of the cluster, which `ExternalName` services have to name hosts in.
+const CLUSTER_DOMAIN: &str = "cluster.local";
+
+/// Counts the checks in a row each instance was down for.
+#[derive(Debug)]
+pub struct FailoverDetector {
+    threshold: u32,
+    failures: HashMap<String, u32>,
+}
+
+impl FailoverDetector {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Records a check of `instance`, returning whether it has been down for `threshold`
+    /// checks. The count starts over then, so a failed failover is retried as long as the
+    /// instance stays down.
+    pub fn observe(&mut self, instance: &str, ready: bool) -> bool {
+        if ready {
+            self.failures.remove(instance);
+            return false;
+        }
+        let failures = self.failures.entry(instance.to_string()).or_insert(0);
+        *failures += 1;
+        if *failures >= self.threshold {
+            self.failures.remove(instance);
+            return true;
+        }
+        false
+    }
+}
+
+/// The `mariadb` service of a failed instance, from its template, turned into an alias of
+/// the one of `standby`, so clients addressing the failed instance reach the standby.
+/// Creating the instance again applies the template and undoes it.
+pub fn failover_service(mut service: Service, standby: &str) -> Service {
+    let spec = service.spec.get_or_insert_with(Default::default);
+    spec.type_ = Some("ExternalName".to_string());
+    spec.external_name = Some(format!("{}.svc.{}", mariadb_host(standby), CLUSTER_DOMAIN));
+    spec.selector = None;
+    spec.cluster_ip = None;
+    service
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MariaDbFailover {
+    pub instance: String,
+    pub standby: String,
+    /// The sites now using the standby.
+    pub sites: Vec<String>,
+    /// The sites that couldn't be moved and why, still pointed at the failed instance.
+    pub failed: BTreeMap<String, String>,
+}
+
+impl KwpmClient {
+    /// Sets the instance the sites of `instance` fail over to, or removes it. The standby has
+    /// to hold their databases, e.g. as a MariaDB replica of `instance`.
+    pub async fn set_mariadb_standby(&self, instance: &str, standby: Option<&str>) -> Result<()> {
+        for instance in [Some(instance), standby].into_iter().flatten() {
+            if !is_valid_mariadb_instance(instance) {
+                return Err(KwpmError::invalid_input(format!(
+                    "invalid MariaDB instance: {}",
+                    instance
+                )));
+            }
+        }
+        if standby == Some(instance) {
+            return Err(KwpmError::invalid_input(format!(
+                "MariaDB {} can't be its own standby",
+                instance
+            )));
+        }
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        for instance in [Some(instance), standby].into_iter().flatten() {
+            if namespace_api
+                .get_opt(&mariadb_namespace(instance))
+                .await?
+                .is_none()
+            {
+                return Err(KwpmError::not_found(format!(
+                    "MariaDB {} has not been created",
+                    instance
+                )));
+            }
+        }
+        namespace_api
+            .patch(
+                &mariadb_namespace(instance),
+                &PatchParams::default(),
+                &Patch::Merge(json!({
+                    "metadata": { "annotations": { STANDBY_ANNOTATION: standby } }
+                })),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The instances that have a standby, with it.
+    pub async fn mariadb_standbys(&self) -> Result<BTreeMap<String, String>> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        Ok(namespace_api
+            .list(&ListParams::default().labels(&format!("{}=mariadb", COMPONENT_LABEL)))
+            .await?
+            .iter()
+            .filter_map(|namespace| {
+                let standby = namespace.annotations().get(STANDBY_ANNOTATION)?;
+                Some((namespace_mariadb_instance(namespace), standby.clone()))
+            })
+            .collect())
+    }
+
+    /// Moves the sites of `instance`, which has to be down, to its standby: the standby is
+    /// promoted, the service of `instance` made an alias of the standby's, then each site's
+    /// deployment and cron jobs are pointed at the standby and its namespace labeled with it.
+    /// Sites that fail are reported and left on `instance`. Once every site moved the standby
+    /// is removed from `instance`, so it isn't failed over again; until then failing over
+    /// again retries the remaining sites. The failover is recorded as a warning event on the
+    /// deployment of `instance`, in the audit log of each moved site and as a notification.
+    #[tracing::instrument(skip_all, fields(instance = %instance))]
+    pub async fn fail_over_mariadb(&self, instance: &str) -> Result<MariaDbFailover> {
+        if !is_valid_mariadb_instance(instance) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid MariaDB instance: {}",
+                instance
+            )));
+        }
+        let standby = self
+            .mariadb_standbys()
+            .await?
+            .remove(instance)
+            .ok_or_else(|| {
+                KwpmError::invalid_input(format!("MariaDB {} has no standby", instance))
+            })?;
+        if self.is_mariadb_instance_ready(instance).await? {
+            return Err(KwpmError::invalid_input(format!(
+                "MariaDB {} is ready, only instances that are down are failed over",
+                instance
+            )));
+        }
+        if !self.is_mariadb_instance_ready(&standby).await? {
+            bail!("standby MariaDB {} is not ready", standby);
+        }
+
+        // Stops the standby replicating from `instance` and lets it take writes.
+        let job: Job =
+            serde_yaml::from_str(include_str!("../../kubernetes/mariadb/promote-job.yaml"))?;
+        self.run_job(&mariadb_namespace(&standby), job, PROMOTE_TIMEOUT)
+            .await?;
+        let service = failover_service(
+            self.load_manifest("mariadb/mariadb-svc.yaml").await?,
+            &standby,
+        );
+        let service_api: Api<Service> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace(instance));
+        service_api
+            .patch(
+                &service.name_any(),
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&service)),
+            )
+            .await?;
+
+        let mut failover = MariaDbFailover {
+            instance: instance.to_string(),
+            standby: standby.clone(),
+            ..Default::default()
+        };
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        for namespace in namespace_api.list(&Default::default()).await? {
+            let Some(site) = namespace
+                .metadata
+                .name
+                .as_deref()
+                .and_then(|ns| self.site_name(ns))
+            else {
+                continue;
+            };
+            if namespace_mariadb_instance(&namespace) != instance {
+                continue;
+            }
+            match self.fail_over_site(site, instance, &standby).await {
+                Ok(true) => failover.sites.push(site.to_string()),
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!(site = %site, error = %format!("{:#}", e), "failing over site failed");
+                    failover.failed.insert(site.to_string(), format!("{:#}", e));
+                }
+            }
+        }
+
+        if failover.failed.is_empty() {
+            namespace_api
+                .patch(
+                    &mariadb_namespace(instance),
+                    &PatchParams::default(),
+                    &Patch::Merge(json!({
+                        "metadata": { "annotations": { STANDBY_ANNOTATION: null } }
+                    })),
+                )
+                .await?;
+        }
+
+        let mut message = format!(
+            "MariaDB {} is down, {} sites failed over to {}",
+            instance,
+            failover.sites.len(),
+            standby
+        );
+        if !failover.failed.is_empty() {
+            message.push_str(&format!(
+                ", still on {}: {}",
+                instance,
+                failover
+                    .failed
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        self.record_warning(
+            ObjectReference {
+                api_version: Some("apps/v1".to_string()),
+                kind: Some("Deployment".to_string()),
+                name: Some(MARIADB_DEPLOYMENT_NAME.to_string()),
+                namespace: Some(mariadb_namespace(instance)),
+                ..Default::default()
+            },
+            "MariaDbFailover",
+            message.clone(),
+        )
+        .await?;
+        self.notify(&Notification {
+            subject: format!("MariaDB {} failed over to {}", instance, standby),
+            body: message,
+            data: json!(failover),
+        })
+        .await?;
+
+        Ok(failover)
+    }
+
+    /// Points `site` at `standby` if it has a database, returning whether it had.
+    async fn fail_over_site(&self, site: &str, instance: &str, standby: &str) -> Result<bool> {
+        let ns_name = self.site_namespace(site);
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        if secret_api.get_opt("mysql-pass").await?.is_none() {
+            return Ok(false);
+        }
+        let lock = self.lock_site(site, "fail_over_mariadb").await?;
+        let result: Result<_> = async {
+            let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+            let deployment = deployment_api.get("wordpress").await?;
+            self.switch_site_database(site, &deployment, instance, standby, &mut Vec::new())
+                .await?;
+            self.record_audit(
+                site,
+                "fail_over_mariadb",
+                &format!("{} -> {}", instance, standby),
+            )
+            .await?;
+
+            Ok(true)
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Checks the instances that have a standby every `FAILOVER_CHECK_INTERVAL` and fails
+    /// those down for `FAILOVER_THRESHOLD` checks in a row over, until the task is dropped.
+    pub async fn watch_mariadb_failover(self: Arc<Self>) {
+        let mut detector = FailoverDetector::new(FAILOVER_THRESHOLD);
+        let mut interval = tokio::time::interval(FAILOVER_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let standbys = match self.mariadb_standbys().await {
+                Ok(standbys) => standbys,
+                Err(e) => {
+                    tracing::warn!(error = %format!("{:#}", e), "listing MariaDB standbys failed");
+                    continue;
+                }
+            };
+            for instance in standbys.keys() {
+                // An instance that can't be checked isn't taken for down.
+                let Ok(ready) = self.is_mariadb_instance_ready(instance).await else {
+                    continue;
+                };
+                if !detector.observe(instance, ready) {
+                    continue;
+                }
+                match self.fail_over_mariadb(instance).await {
+                    Ok(failover) => {
+                        tracing::warn!(instance = %instance, standby = %failover.standby, sites = failover.sites.len(), "MariaDB failed over")
+                    }
+                    Err(e) => {
+                        tracing::warn!(instance = %instance, error = %format!("{:#}", e), "MariaDB failover failed")
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover_detector() {
+        let mut detector = FailoverDetector::new(3);
+        assert!(!detector.observe("default", false));
+        assert!(!detector.observe("default", false));
+        assert!(!detector.observe("default", true));
+        assert!(!detector.observe("default", false));
+        assert!(!detector.observe("acme", false));
+        assert!(!detector.observe("default", false));
+        assert!(detector.observe("default", false));
+        // Counted again from zero after firing.
+        assert!(!detector.observe("default", false));
+        assert!(!detector.observe("acme", false));
+        assert!(detector.observe("acme", false));
+    }
+
+    #[test]
+    fn test_failover_service() {
+        let service: Service =
+            serde_yaml::from_str(include_str!("../../kubernetes/mariadb/mariadb-svc.yaml"))
+                .unwrap();
+        let service = failover_service(service, "acme");
+        let spec = service.spec.unwrap();
+        assert_eq!(spec.type_.as_deref(), Some("ExternalName"));
+        assert_eq!(
+            spec.external_name.as_deref(),
+            Some("mariadb.kwpm-mariadb-acme.svc.cluster.local")
+        );
+        assert_eq!(spec.selector, None);
+        assert_eq!(spec.cluster_ip, None);
+        assert_eq!(spec.ports.unwrap()[0].port, 3306);
+    }
+}
@@ -0,0 +1,124 @@
+use futures::{stream, StreamExt};
+
+use crate::{
+    error::{bail, Result},
+    library::is_valid_slug,
+    snapshot::RiskyOperation,
+    KwpmClient,
+};
+
+#[derive(Clone, Debug)]
+pub struct FleetUpdateOptions {
+    pub concurrency: usize,
+    pub batch_size: usize,
+    /// Number of failed sites tolerated before the remaining batches are skipped.
+    pub max_failures: usize,
+}
+
+impl Default for FleetUpdateOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 2,
+            batch_size: 5,
+            max_failures: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FleetUpdateReport {
+    pub updated: Vec<String>,
+    pub failed: Vec<(String, String)>,
+    pub skipped: Vec<String>,
+    pub halted: bool,
+}
+
+pub fn plugin_update_args(plugin: &str, version: Option<&str>) -> Vec<String> {
+    let mut args = vec![
+        "plugin".to_string(),
+        "update".to_string(),
+        plugin.to_string(),
+    ];
+    if let Some(version) = version {
+        args.push(format!("--version={}", version));
+    }
+    args
+}
+
+impl KwpmClient {
+    async fn update_site_plugin(
+        &self,
+        site: &str,
+        plugin: &str,
+        version: Option<&str>,
+    ) -> Result<()> {
+        let args = plugin_update_args(plugin, version);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        self.snapshot_if_configured(site, RiskyOperation::PluginUpdate)
+            .await?;
+        self.run_wp_cli(site, &args).await?;
+        self.check_site_health(site).await
+    }
+
+    pub async fn update_plugin_fleet(
+        &self,
+        plugin: &str,
+        version: Option<&str>,
+        site_selector: &str,
+        options: &FleetUpdateOptions,
+    ) -> Result<FleetUpdateReport> {
+        if !is_valid_slug(plugin) {
+            bail!("invalid plugin slug: {}", plugin);
+        }
+
+        let sites = self.list_site_names(site_selector).await?;
+        let mut report = FleetUpdateReport::default();
+
+        for batch in sites.chunks(options.batch_size.max(1)) {
+            if report.halted {
+                report.skipped.extend(batch.iter().cloned());
+                continue;
+            }
+
+            let results: Vec<(String, Result<()>)> = stream::iter(batch)
+                .map(|site| async move {
+                    (
+                        site.clone(),
+                        self.update_site_plugin(site, plugin, version).await,
+                    )
+                })
+                .buffer_unordered(options.concurrency.max(1))
+                .collect()
+                .await;
+
+            for (site, result) in results {
+                match result {
+                    Ok(()) => report.updated.push(site),
+                    Err(e) => report.failed.push((site, format!("{:#}", e))),
+                }
+            }
+
+            report.halted = report.failed.len() > options.max_failures;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_update_args() {
+        assert_eq!(
+            plugin_update_args("akismet", None),
+            ["plugin", "update", "akismet"]
+        );
+        assert_eq!(
+            plugin_update_args("akismet", Some("5.3")),
+            ["plugin", "update", "akismet", "--version=5.3"]
+        );
+    }
+}
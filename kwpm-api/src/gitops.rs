@@ -0,0 +1,449 @@
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::CronJob,
+    core::v1::{ConfigMap, PersistentVolumeClaim, Service},
+    networking::v1::Ingress,
+};
+use kube::{api::DynamicObject, Api};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    access::admin_ingresses,
+    access::is_site_ingress,
+    audit::AUDIT_CONFIG_MAP,
+    canary::CANARY_NAME,
+    error::{bail, Result},
+    nginx::render_nginx_config,
+    progress::OPERATION_CONFIG_MAP_PREFIX,
+    registry::{Registry, RegistryCredentials},
+    revision::REVISION_CONFIG_MAP_PREFIX,
+    uploads::uploads_ini_config,
+    KwpmClient,
+};
+
+const ARGOCD_NAMESPACE: &str = "argocd";
+const FLUX_NAMESPACE: &str = "flux-system";
+const FLUX_CONFIG_TYPE: &str = "application/vnd.cncf.flux.config.v1+json";
+const FLUX_CONTENT_TYPE: &str = "application/vnd.cncf.flux.content.v1.tar+gzip";
+const OCI_MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Where a GitOps controller pulls the rendered manifests of a site from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GitOpsSource {
+    Git {
+        repo_url: String,
+        revision: String,
+        path: String,
+    },
+    /// `url` is an `oci://` repository the manifests are pushed to as an artifact.
+    Oci { url: String, tag: String },
+}
+
+/// Argo CD Application syncing the rendered manifests of `site` into `namespace`.
+pub fn generate_argocd_app(
+    site: &str,
+    namespace: &str,
+    source: &GitOpsSource,
+) -> Result<DynamicObject> {
+    let source = match source {
+        GitOpsSource::Git {
+            repo_url,
+            revision,
+            path,
+        } => json!({ "repoURL": repo_url, "targetRevision": revision, "path": path }),
+        GitOpsSource::Oci { url, tag } => {
+            json!({ "repoURL": url, "targetRevision": tag, "path": "." })
+        }
+    };
+
+    Ok(serde_json::from_value(json!({
+        "apiVersion": "argoproj.io/v1alpha1",
+        "kind": "Application",
+        "metadata": {
+            "name": namespace,
+            "namespace": ARGOCD_NAMESPACE,
+            "labels": { "kwpm.io/site": site },
+        },
+        "spec": {
+            "project": "default",
+            "source": source,
+            "destination": {
+                "server": "https://kubernetes.default.svc",
+                "namespace": namespace,
+            },
+            "syncPolicy": {
+                "automated": { "prune": true, "selfHeal": true },
+                "syncOptions": ["CreateNamespace=true"],
+            },
+        },
+    }))?)
+}
+
+/// Flux OCIRepository and Kustomization syncing the artifact pushed with `push_site_manifests`
+/// into `namespace`, the one of `site`.
+pub fn generate_flux_resources(
+    site: &str,
+    namespace: &str,
+    url: &str,
+    tag: &str,
+) -> Result<Vec<DynamicObject>> {
+    if !url.starts_with("oci://") {
+        bail!("flux source {} is not an oci:// url", url);
+    }
+
+    let repository = json!({
+        "apiVersion": "source.toolkit.fluxcd.io/v1beta2",
+        "kind": "OCIRepository",
+        "metadata": {
+            "name": namespace,
+            "namespace": FLUX_NAMESPACE,
+            "labels": { "kwpm.io/site": site },
+        },
+        "spec": {
+            "interval": "5m",
+            "url": url,
+            "ref": { "tag": tag },
+        },
+    });
+    let kustomization = json!({
+        "apiVersion": "kustomize.toolkit.fluxcd.io/v1",
+        "kind": "Kustomization",
+        "metadata": {
+            "name": namespace,
+            "namespace": FLUX_NAMESPACE,
+            "labels": { "kwpm.io/site": site },
+        },
+        "spec": {
+            "interval": "10m",
+            "sourceRef": { "kind": "OCIRepository", "name": namespace },
+            "path": "./",
+            "prune": true,
+            "targetNamespace": namespace,
+        },
+    });
+
+    Ok(vec![
+        serde_json::from_value(repository)?,
+        serde_json::from_value(kustomization)?,
+    ])
+}
+
+fn descriptor(media_type: &str, bytes: &[u8]) -> Value {
+    json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{:x}", Sha256::digest(bytes)),
+        "size": bytes.len(),
+    })
+}
+
+/// OCI manifest of a Flux artifact with a single layer holding the gzipped manifests.
+pub fn flux_artifact_manifest(config: &[u8], content: &[u8], source: &str) -> Value {
+    json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST_TYPE,
+        "config": descriptor(FLUX_CONFIG_TYPE, config),
+        "layers": [descriptor(FLUX_CONTENT_TYPE, content)],
+        "annotations": {
+            "org.opencontainers.image.created": chrono::Utc::now().to_rfc3339(),
+            "org.opencontainers.image.source": source,
+        },
+    })
+}
+
+async fn tar_gz(dir: &std::path::Path) -> Result<Vec<u8>> {
+    let output = tokio::process::Command::new("tar")
+        .arg("-czf")
+        .arg("-")
+        .arg("-C")
+        .arg(dir)
+        .arg(".")
+        .output()
+        .await
+        .context("failed to run tar")?;
+    if !output.status.success() {
+        bail!(
+            "tar exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(output.stdout)
+}
+
+const SERVER_METADATA: &[&str] = &[
+    "uid",
+    "resourceVersion",
+    "creationTimestamp",
+    "generation",
+    "managedFields",
+    "selfLink",
+    "ownerReferences",
+];
+
+const SERVER_ANNOTATIONS: &[&str] = &[
+    "kubectl.kubernetes.io/last-applied-configuration",
+    "deployment.kubernetes.io/revision",
+    "pv.kubernetes.io/bind-completed",
+    "pv.kubernetes.io/bound-by-controller",
+    "volume.beta.kubernetes.io/storage-provisioner",
+    "volume.kubernetes.io/storage-provisioner",
+];
+
+/// Removes state the API server fills in, so the manifest can be applied to another cluster.
+pub fn strip_server_fields(manifest: &mut Value) {
+    let Some(object) = manifest.as_object_mut() else {
+        return;
+    };
+    object.remove("status");
+
+    if let Some(metadata) = object.get_mut("metadata").and_then(Value::as_object_mut) {
+        for field in SERVER_METADATA {
+            metadata.remove(*field);
+        }
+        if let Some(annotations) = metadata
+            .get_mut("annotations")
+            .and_then(Value::as_object_mut)
+        {
+            for annotation in SERVER_ANNOTATIONS {
+                annotations.remove(*annotation);
+            }
+            if annotations.is_empty() {
+                metadata.remove("annotations");
+            }
+        }
+    }
+
+    if let Some(spec) = object.get_mut("spec").and_then(Value::as_object_mut) {
+        for field in ["clusterIP", "clusterIPs", "volumeName"] {
+            spec.remove(field);
+        }
+    }
+}
+
+fn to_manifest<K: serde::Serialize>(api_version: &str, kind: &str, object: &K) -> Result<Value> {
+    let mut manifest = serde_json::to_value(object)?;
+    manifest["apiVersion"] = json!(api_version);
+    manifest["kind"] = json!(kind);
+    strip_server_fields(&mut manifest);
+    Ok(manifest)
+}
+
+impl KwpmClient {
+    /// Renders the manifests of a site for a GitOps controller to apply: its workloads as they
+    /// run, with every kwpm-managed setting rendered from the site spec. Secrets are left out
+    /// and have to be provided separately, e.g. through sealed secrets.
+    pub async fn render_site_manifests(&self, site: &str) -> Result<Vec<Value>> {
+        let ns_name = self.site_namespace(site);
+        let spec = self.get_site_spec(site).await?;
+
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), &ns_name);
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let cron_job_api: Api<CronJob> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let mut manifests = Vec::new();
+        let is_kwpm_object = |name: &Option<String>| name.as_deref() != Some(CANARY_NAME);
+
+        for deployment in deployment_api.list(&Default::default()).await? {
+            if is_kwpm_object(&deployment.metadata.name) {
+                manifests.push(to_manifest("apps/v1", "Deployment", &deployment)?);
+            }
+        }
+        for service in service_api.list(&Default::default()).await? {
+            if is_kwpm_object(&service.metadata.name) {
+                manifests.push(to_manifest("v1", "Service", &service)?);
+            }
+        }
+        for pvc in pvc_api.list(&Default::default()).await? {
+            manifests.push(to_manifest("v1", "PersistentVolumeClaim", &pvc)?);
+        }
+        for cron_job in cron_job_api.list(&Default::default()).await? {
+            manifests.push(to_manifest("batch/v1", "CronJob", &cron_job)?);
+        }
+
+        let mut rendered = vec![render_nginx_config(&spec)?];
+        if let Some(limit_mb) = spec.upload_limit_mb {
+            rendered.push(uploads_ini_config(limit_mb)?);
+        }
+        for mut config_map in config_map_api.list(&Default::default()).await? {
+            let name = config_map.metadata.name.as_deref().unwrap_or_default();
+            if name == "kube-root-ca.crt"
+                || name == AUDIT_CONFIG_MAP
+                || name.starts_with(REVISION_CONFIG_MAP_PREFIX)
+                || name.starts_with(OPERATION_CONFIG_MAP_PREFIX)
+            {
+                continue;
+            }
+            if let Some(render) = rendered
+                .iter()
+                .find(|r| r.metadata.name == config_map.metadata.name)
+            {
+                config_map.data = render.data.clone();
+            }
+            manifests.push(to_manifest("v1", "ConfigMap", &config_map)?);
+        }
+
+        let ingresses = ingress_api.list(&Default::default()).await?;
+        let site_ingresses: Vec<&Ingress> = ingresses
+            .items
+            .iter()
+            .filter(|i| is_site_ingress(i))
+            .collect();
+        for ingress in &site_ingresses {
+            manifests.push(to_manifest("networking.k8s.io/v1", "Ingress", ingress)?);
+        }
+        if let (Some(access), Some(site_ingress)) = (&spec.admin_access, site_ingresses.first()) {
+            for ingress in admin_ingresses(site_ingress, access)? {
+                manifests.push(to_manifest("networking.k8s.io/v1", "Ingress", &ingress)?);
+            }
+        }
+
+        Ok(manifests)
+    }
+
+    /// The rendered manifests of a site as a multi-document YAML file.
+    pub async fn render_site_manifests_yaml(&self, site: &str) -> Result<String> {
+        let mut yaml = String::new();
+        for manifest in self.render_site_manifests(site).await? {
+            yaml.push_str("---\n");
+            yaml.push_str(&serde_yaml::to_string(&manifest)?);
+        }
+        Ok(yaml)
+    }
+}
+
+impl KwpmClient {
+    /// Pushes the rendered manifests of a site to `url:tag` as a Flux OCI artifact and returns
+    /// the digest of the pushed manifest.
+    pub async fn push_site_manifests(
+        &self,
+        site: &str,
+        url: &str,
+        tag: &str,
+        credentials: Option<RegistryCredentials>,
+    ) -> Result<String> {
+        self.ensure_writable("push_site_manifests")?;
+        let (registry, repository) = url
+            .strip_prefix("oci://")
+            .and_then(|rest| rest.split_once('/'))
+            .with_context(|| format!("invalid oci url {}", url))?;
+
+        let dir = std::env::temp_dir().join(format!("kwpm-flux-{}-{}", site, std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let content = async {
+            tokio::fs::write(
+                dir.join(format!("{}.yaml", site)),
+                self.render_site_manifests_yaml(site).await?,
+            )
+            .await?;
+            tar_gz(&dir).await
+        }
+        .await;
+        tokio::fs::remove_dir_all(&dir).await?;
+        let content = content?;
+
+        let config = b"{}".to_vec();
+        let manifest = flux_artifact_manifest(&config, &content, url);
+        let manifest_bytes = serde_json::to_vec(&manifest)?;
+
+        let mut target = Registry::new(registry, repository).with_credentials(credentials);
+        for (descriptor, bytes) in [
+            (&manifest["config"], config),
+            (&manifest["layers"][0], content),
+        ] {
+            let digest = descriptor["digest"].as_str().unwrap_or_default();
+            target.push_blob(digest, bytes).await?;
+        }
+        target
+            .push_manifest(tag, OCI_MANIFEST_TYPE, manifest_bytes.clone())
+            .await?;
+
+        Ok(format!("sha256:{:x}", Sha256::digest(&manifest_bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_argocd_app() {
+        let app = generate_argocd_app(
+            "blog",
+            "kwpm-blog",
+            &GitOpsSource::Git {
+                repo_url: "https://git.example.com/sites.git".to_string(),
+                revision: "main".to_string(),
+                path: "sites/blog".to_string(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(app.metadata.name.as_deref(), Some("kwpm-blog"));
+        assert_eq!(app.data["spec"]["source"]["path"], "sites/blog");
+        assert_eq!(app.data["spec"]["destination"]["namespace"], "kwpm-blog");
+        let types = app.types.unwrap();
+        assert_eq!(types.api_version, "argoproj.io/v1alpha1");
+        assert_eq!(types.kind, "Application");
+    }
+
+    #[test]
+    fn test_generate_flux_resources() {
+        let resources = generate_flux_resources(
+            "blog",
+            "kwpm-blog",
+            "oci://ghcr.io/example/sites/blog",
+            "v1",
+        )
+        .unwrap();
+
+        assert_eq!(resources[0].data["spec"]["ref"]["tag"], "v1");
+        assert_eq!(resources[1].data["spec"]["sourceRef"]["name"], "kwpm-blog");
+        assert_eq!(resources[1].data["spec"]["targetNamespace"], "kwpm-blog");
+        assert!(
+            generate_flux_resources("blog", "kwpm-blog", "https://ghcr.io/example", "v1").is_err()
+        );
+    }
+
+    #[test]
+    fn test_flux_artifact_manifest() {
+        let manifest = flux_artifact_manifest(b"{}", b"content", "oci://ghcr.io/example/blog");
+
+        assert_eq!(manifest["config"]["mediaType"], FLUX_CONFIG_TYPE);
+        assert_eq!(
+            manifest["config"]["digest"],
+            "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(manifest["layers"][0]["mediaType"], FLUX_CONTENT_TYPE);
+        assert_eq!(manifest["layers"][0]["size"], 7);
+    }
+
+    #[test]
+    fn test_strip_server_fields() {
+        let mut manifest = json!({
+            "kind": "Service",
+            "metadata": {
+                "name": "wordpress",
+                "uid": "1234",
+                "resourceVersion": "42",
+                "annotations": { "kubectl.kubernetes.io/last-applied-configuration": "{}" },
+            },
+            "spec": { "clusterIP": "10.0.0.1", "ports": [{ "port": 80 }] },
+            "status": {},
+        });
+
+        strip_server_fields(&mut manifest);
+        assert_eq!(
+            manifest,
+            json!({
+                "kind": "Service",
+                "metadata": { "name": "wordpress" },
+                "spec": { "ports": [{ "port": 80 }] },
+            })
+        );
+    }
+}
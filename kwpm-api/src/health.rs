@@ -0,0 +1,286 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use k8s_openapi::api::networking::v1::Ingress;
+use kube::Api;
+use serde::Serialize;
+
+use crate::{
+    access::is_site_ingress, error::Result, healthz::HEALTHZ_PATH, mariadb::mariadb_host,
+    KwpmClient,
+};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(3);
+const MARIADB_PORT: u16 = 3306;
+const PROBE_CONCURRENCY: usize = 8;
+
+/// The outcome of one probe.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeResult {
+    pub healthy: bool,
+    /// The HTTP status or the error.
+    pub detail: String,
+    pub latency_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteHealth {
+    pub site: String,
+    pub healthy: bool,
+    pub http: ProbeResult,
+    /// The probe of the site's MariaDB port, for apps with a database.
+    pub database: Option<ProbeResult>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// The health of all sites, without naming them since `/healthz` needs no token.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthSummary {
+    /// `ok`, or `degraded` when any site is unhealthy.
+    pub status: &'static str,
+    pub sites: usize,
+    pub unhealthy: usize,
+    /// When the last probes ran, unset before the first round.
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+/// Whether a site answering with `status` is up. Sites behind basic auth or an IP allowlist
+/// answer the probe with 401 or 403.
+fn is_healthy_status(status: u16) -> bool {
+    status < 400 || status == 401 || status == 403
+}
+
+pub fn summarize(sites: &[SiteHealth]) -> HealthSummary {
+    let unhealthy = sites.iter().filter(|site| !site.healthy).count();
+    HealthSummary {
+        status: if unhealthy == 0 { "ok" } else { "degraded" },
+        sites: sites.len(),
+        unhealthy,
+        checked_at: sites.iter().map(|site| site.checked_at).max(),
+    }
+}
+
+fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// The latest probe results, shared between the prober and the API.
+#[derive(Clone, Debug, Default)]
+pub struct HealthMonitor {
+    sites: Arc<RwLock<BTreeMap<String, SiteHealth>>>,
+}
+
+impl HealthMonitor {
+    pub fn site(&self, site: &str) -> Option<SiteHealth> {
+        self.sites.read().ok()?.get(site).cloned()
+    }
+
+    pub fn summary(&self) -> HealthSummary {
+        let sites: Vec<SiteHealth> = self
+            .sites
+            .read()
+            .map(|sites| sites.values().cloned().collect())
+            .unwrap_or_default();
+        summarize(&sites)
+    }
+
+    /// Replaces the results, dropping sites that no longer exist.
+    fn replace(&self, results: Vec<SiteHealth>) {
+        if let Ok(mut sites) = self.sites.write() {
+            *sites = results
+                .into_iter()
+                .map(|health| (health.site.clone(), health))
+                .collect();
+        }
+    }
+}
+
+async fn probe_http(url: &str, host: Option<&str>) -> ProbeResult {
+    let start = Instant::now();
+    let client = reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build();
+    let response = match client {
+        Ok(client) => {
+            let mut request = client.get(url);
+            if let Some(host) = host {
+                request = request.header(reqwest::header::HOST, host);
+            }
+            request.send().await
+        }
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(response) => ProbeResult {
+            healthy: is_healthy_status(response.status().as_u16()),
+            detail: response.status().to_string(),
+            latency_ms: elapsed_ms(start),
+        },
+        Err(e) => ProbeResult {
+            healthy: false,
+            detail: e.to_string(),
+            latency_ms: elapsed_ms(start),
+        },
+    }
+}
+
+async fn probe_tcp(host: &str, port: u16) -> ProbeResult {
+    let start = Instant::now();
+    let connected = tokio::time::timeout(
+        DATABASE_TIMEOUT,
+        tokio::net::TcpStream::connect((host, port)),
+    )
+    .await;
+    let (healthy, detail) = match connected {
+        Ok(Ok(_)) => (true, "connected".to_string()),
+        Ok(Err(e)) => (false, e.to_string()),
+        Err(_) => (false, "timed out".to_string()),
+    };
+    ProbeResult {
+        healthy,
+        detail,
+        latency_ms: elapsed_ms(start),
+    }
+}
+
+impl KwpmClient {
+    /// Requests the site through its service, with the host of its ingress, and connects to
+    /// the MariaDB port of its database. Needs to run inside the cluster.
+    pub async fn probe_site_health(&self, site: &str) -> Result<SiteHealth> {
+        let spec = self.get_site_spec(site).await?;
+        let ns_name = self.site_namespace(site);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingresses = ingress_api.list(&Default::default()).await?;
+        let host = ingresses
+            .items
+            .iter()
+            .filter(|ingress| is_site_ingress(ingress))
+            .filter_map(|ingress| ingress.spec.as_ref()?.rules.as_ref())
+            .flatten()
+            .find_map(|rule| rule.host.clone());
+
+        let path = if spec.health_endpoint {
+            HEALTHZ_PATH
+        } else {
+            "/"
+        };
+        let http = probe_http(
+            &format!("http://wordpress.{}.svc{}", ns_name, path),
+            host.as_deref(),
+        )
+        .await;
+        let database = if spec.app.uses_database() {
+            let instance = self.site_mariadb_instance(site).await?;
+            Some(probe_tcp(&mariadb_host(&instance), MARIADB_PORT).await)
+        } else {
+            None
+        };
+
+        Ok(SiteHealth {
+            site: site.to_string(),
+            healthy: http.healthy && database.as_ref().is_none_or(|db| db.healthy),
+            http,
+            database,
+            checked_at: Utc::now(),
+        })
+    }
+
+    /// Probes every site, a failure to probe counting as unhealthy.
+    pub async fn probe_all_sites(&self) -> Result<Vec<SiteHealth>> {
+        let sites = self.list_site_names("").await?;
+        Ok(stream::iter(sites)
+            .map(|site| async move {
+                match self.probe_site_health(&site).await {
+                    Ok(health) => health,
+                    Err(e) => SiteHealth {
+                        http: ProbeResult {
+                            healthy: false,
+                            detail: format!("{:#}", e),
+                            latency_ms: 0,
+                        },
+                        site,
+                        healthy: false,
+                        database: None,
+                        checked_at: Utc::now(),
+                    },
+                }
+            })
+            .buffer_unordered(PROBE_CONCURRENCY)
+            .collect()
+            .await)
+    }
+
+    /// Probes every site each `interval` and stores the results in `monitor`, forever.
+    pub async fn run_health_checks(&self, monitor: &HealthMonitor, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match self.probe_all_sites().await {
+                Ok(results) => monitor.replace(results),
+                Err(e) => tracing::warn!(error = %format!("{:#}", e), "health checks failed"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(site: &str, healthy: bool, minute: u32) -> SiteHealth {
+        let probe = ProbeResult {
+            healthy,
+            detail: "200 OK".to_string(),
+            latency_ms: 12,
+        };
+        SiteHealth {
+            site: site.to_string(),
+            healthy,
+            http: probe.clone(),
+            database: Some(probe),
+            checked_at: DateTime::parse_from_rfc3339(&format!("2024-01-01T00:{:02}:00Z", minute))
+                .unwrap()
+                .with_timezone(&Utc),
+        }
+    }
+
+    #[test]
+    fn test_is_healthy_status() {
+        assert!(is_healthy_status(200));
+        assert!(is_healthy_status(301));
+        assert!(is_healthy_status(401));
+        assert!(!is_healthy_status(404));
+        assert!(!is_healthy_status(502));
+    }
+
+    #[test]
+    fn test_health_monitor() {
+        let monitor = HealthMonitor::default();
+        assert_eq!(monitor.summary().status, "ok");
+        assert_eq!(monitor.summary().checked_at, None);
+
+        monitor.replace(vec![health("blog", true, 1), health("shop", false, 2)]);
+        let summary = monitor.summary();
+        assert_eq!(summary.status, "degraded");
+        assert_eq!((summary.sites, summary.unhealthy), (2, 1));
+        assert_eq!(
+            summary.checked_at,
+            Some(health("shop", false, 2).checked_at)
+        );
+        assert!(!monitor.site("shop").unwrap().healthy);
+
+        monitor.replace(vec![health("blog", true, 3)]);
+        assert_eq!(monitor.site("shop"), None);
+        assert_eq!(monitor.summary().status, "ok");
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        core::v1::{
+            ConfigMap, ConfigMapVolumeSource, HTTPGetAction, Probe, TCPSocketAction, Volume,
+            VolumeMount,
+        },
+        networking::v1::Ingress,
+    },
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use serde_json::json;
+
+use crate::{
+    access::{ingress, is_site_ingress},
+    error::{bail, Result},
+    manifest::deployment_pod_spec_mut,
+    KwpmClient,
+};
+
+pub const HEALTHZ_PATH: &str = "/healthz.php";
+pub(crate) const HEALTHZ_INGRESS_NAME: &str = "wordpress-healthz";
+/// Outside the document root, so the script is only reachable through the nginx location.
+const HEALTHZ_DIR: &str = "/var/www/kwpm";
+const HEALTHZ_VOLUME_NAME: &str = "healthz";
+
+/// The nginx location serving the health check without access logging, so probes don't show
+/// up in analytics built from access logs.
+pub fn healthz_location() -> String {
+    format!(
+        "location = {} {{\n    access_log off;\n    fastcgi_pass 127.0.0.1:9000;\n    include fastcgi_params;\n    fastcgi_param SCRIPT_FILENAME {}/healthz.php;\n}}",
+        HEALTHZ_PATH, HEALTHZ_DIR
+    )
+}
+
+fn healthz_probe() -> Probe {
+    Probe {
+        http_get: Some(HTTPGetAction {
+            path: Some(HEALTHZ_PATH.to_string()),
+            port: IntOrString::Int(80),
+            ..Default::default()
+        }),
+        initial_delay_seconds: Some(5),
+        period_seconds: Some(10),
+        timeout_seconds: Some(3),
+        failure_threshold: Some(3),
+        ..Default::default()
+    }
+}
+
+/// Only checks that nginx accepts connections. The health check needs the database, and a
+/// MariaDB outage must not restart every site's pods.
+fn liveness_probe() -> Probe {
+    Probe {
+        tcp_socket: Some(TCPSocketAction {
+            port: IntOrString::Int(80),
+            ..Default::default()
+        }),
+        initial_delay_seconds: Some(5),
+        period_seconds: Some(10),
+        timeout_seconds: Some(3),
+        failure_threshold: Some(6),
+        ..Default::default()
+    }
+}
+
+/// Mounts the health check into the wordpress container and takes pods that fail it out of
+/// the service.
+pub fn attach_health_endpoint(deployment: &mut Deployment) -> Result<()> {
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+
+    let volumes = pod_spec.volumes.get_or_insert_with(Vec::new);
+    if !volumes.iter().any(|v| v.name == HEALTHZ_VOLUME_NAME) {
+        volumes.push(Volume {
+            name: HEALTHZ_VOLUME_NAME.to_string(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some("wp-healthz".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
+    let wordpress = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "wordpress")
+        .context("deployment has no wordpress container")?;
+    let mounts = wordpress.volume_mounts.get_or_insert_with(Vec::new);
+    if !mounts.iter().any(|m| m.name == HEALTHZ_VOLUME_NAME) {
+        mounts.push(VolumeMount {
+            name: HEALTHZ_VOLUME_NAME.to_string(),
+            mount_path: HEALTHZ_DIR.to_string(),
+            read_only: Some(true),
+            ..Default::default()
+        });
+    }
+
+    let nginx = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "nginx")
+        .context("deployment has no nginx container")?;
+    nginx.readiness_probe = Some(healthz_probe());
+    nginx.liveness_probe = Some(liveness_probe());
+
+    Ok(())
+}
+
+/// An ingress for the health check alone, so load balancers reach it even when the site is
+/// behind basic auth or source range restrictions.
+pub fn healthz_ingress(site_ingress: &Ingress) -> Result<Ingress> {
+    let template = site_ingress.spec.clone().unwrap_or_default();
+    let hosts: Vec<String> = template
+        .rules
+        .iter()
+        .flatten()
+        .filter_map(|rule| rule.host.clone())
+        .collect();
+    if hosts.is_empty() {
+        bail!("site ingress has no hosts");
+    }
+
+    Ok(ingress(
+        HEALTHZ_INGRESS_NAME,
+        &hosts,
+        &[(HEALTHZ_PATH, "Exact")],
+        &template,
+        BTreeMap::new(),
+    ))
+}
+
+impl KwpmClient {
+    /// Serves `/healthz.php` from the site, checks the readiness of its pods against it and
+    /// points load balancer health checks at it instead of the homepage.
+    pub async fn enable_health_endpoint(&self, site: &str) -> Result<()> {
+        let mut spec = self.get_site_spec(site).await?;
+        // The check connects with the WordPress database settings.
+        if !spec.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        let ns_name = self.site_namespace(site);
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let config_map: ConfigMap = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-healthz-config.yaml"
+        ))?;
+        let ingresses = ingress_api.list(&Default::default()).await?;
+        let Some(site_ingress) = ingresses.items.iter().find(|i| is_site_ingress(i)) else {
+            bail!("{} has no ingress", site);
+        };
+        let healthz_ingress = healthz_ingress(site_ingress)?;
+        self.check_policy(
+            "enable_health_endpoint",
+            Some(site),
+            &[
+                serde_json::to_value(&config_map)?,
+                serde_json::to_value(&healthz_ingress)?,
+            ],
+        )
+        .await?;
+
+        config_map_api
+            .patch(
+                "wp-healthz",
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&config_map)),
+            )
+            .await?;
+
+        spec.health_endpoint = true;
+        self.save_site_spec(site, &spec).await?;
+        self.apply_nginx_config(site, &spec).await?;
+
+        let mut deployment = deployment_api.get("wordpress").await?;
+        attach_health_endpoint(&mut deployment)?;
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+
+        ingress_api
+            .patch(
+                HEALTHZ_INGRESS_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&healthz_ingress)),
+            )
+            .await?;
+        let health_check = json!({
+            "metadata": { "annotations": {
+                "alb.ingress.kubernetes.io/healthcheck-path": HEALTHZ_PATH,
+            } }
+        });
+        let name = site_ingress.metadata.name.clone().unwrap_or_default();
+        ingress_api
+            .patch(&name, &Default::default(), &Patch::Merge(&health_check))
+            .await?;
+
+        self.record_site_revision(site, "enable_health_endpoint")
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attach_health_endpoint_is_idempotent() {
+        let mut deployment: Deployment = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap();
+        attach_health_endpoint(&mut deployment).unwrap();
+        attach_health_endpoint(&mut deployment).unwrap();
+
+        let pod_spec = deployment_pod_spec_mut(&mut deployment).unwrap();
+        let volumes = pod_spec.volumes.as_ref().unwrap();
+        assert_eq!(
+            volumes
+                .iter()
+                .filter(|v| v.name == HEALTHZ_VOLUME_NAME)
+                .count(),
+            1
+        );
+        let nginx = pod_spec
+            .containers
+            .iter()
+            .find(|c| c.name == "nginx")
+            .unwrap();
+        let probe = nginx.readiness_probe.as_ref().unwrap();
+        assert_eq!(
+            probe.http_get.as_ref().unwrap().path.as_deref(),
+            Some(HEALTHZ_PATH)
+        );
+        let liveness = nginx.liveness_probe.as_ref().unwrap();
+        assert!(liveness.http_get.is_none());
+        assert!(liveness.tcp_socket.is_some());
+    }
+
+    #[test]
+    fn test_healthz_ingress() {
+        let site_ingress: Ingress =
+            serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-ingress.yaml"))
+                .unwrap();
+        let ingress = healthz_ingress(&site_ingress).unwrap();
+
+        assert!(!is_site_ingress(&ingress));
+        let rule = &ingress.spec.unwrap().rules.unwrap()[0];
+        assert_eq!(rule.host.as_deref(), Some("wordpress-test.local"));
+        let path = &rule.http.as_ref().unwrap().paths[0];
+        assert_eq!(path.path.as_deref(), Some(HEALTHZ_PATH));
+        assert_eq!(path.path_type, "Exact");
+    }
+}
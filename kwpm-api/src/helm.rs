@@ -0,0 +1,278 @@
+use anyhow::Context;
+use serde_yaml::Value;
+
+use crate::{error::Result, manifest::parse_quantity, rollout::RolloutStrategy, site::SiteSpec};
+
+/// Values of an external or bundled database referenced by the chart.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportedDatabase {
+    pub host: Option<String>,
+    pub name: Option<String>,
+    pub user: Option<String>,
+}
+
+/// Result of mapping Bitnami WordPress chart values onto kwpm. Settings without a kwpm
+/// equivalent are listed in `warnings` instead of being dropped silently.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HelmValuesImport {
+    pub spec: SiteSpec,
+    pub hostname: Option<String>,
+    pub tls: bool,
+    pub wordpress_image: Option<String>,
+    pub storage_size: Option<String>,
+    pub database: ImportedDatabase,
+    pub plugins: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+fn get<'a>(values: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(values, |value, key| value.get(key))
+        .filter(|value| !value.is_null())
+}
+
+fn get_str(values: &Value, path: &str) -> Option<String> {
+    match get(values, path)? {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn get_bool(values: &Value, path: &str) -> bool {
+    get(values, path).and_then(Value::as_bool).unwrap_or(false)
+}
+
+/// Size such as `64M` or `64m` (PHP and nginx notation) in MB.
+fn size_mb(size: &str) -> Option<u32> {
+    let size = size.trim();
+    let normalized = match size.chars().last()? {
+        'm' | 'M' => format!("{}Mi", &size[..size.len() - 1]),
+        'g' | 'G' => format!("{}Gi", &size[..size.len() - 1]),
+        'k' | 'K' => format!("{}Ki", &size[..size.len() - 1]),
+        _ => size.to_string(),
+    };
+    let bytes = parse_quantity(&normalized)?;
+    u32::try_from(bytes.div_ceil(1024 * 1024)).ok()
+}
+
+fn extra_env(values: &Value, name: &str) -> Option<String> {
+    get(values, "extraEnvVars")?
+        .as_sequence()?
+        .iter()
+        .find(|env| env.get("name").and_then(Value::as_str) == Some(name))
+        .and_then(|env| get_str(env, "value"))
+}
+
+/// Keys of the Bitnami chart that are understood by the importer; anything else at the top
+/// level is reported.
+const KNOWN_KEYS: &[&str] = &[
+    "image",
+    "ingress",
+    "persistence",
+    "mariadb",
+    "externalDatabase",
+    "wordpressPlugins",
+    "updateStrategy",
+    "extraEnvVars",
+    "replicaCount",
+];
+
+pub fn import_bitnami_values(values_yaml: &str) -> Result<HelmValuesImport> {
+    let values: Value = serde_yaml::from_str(values_yaml).context("invalid Helm values file")?;
+    let mut import = HelmValuesImport::default();
+
+    if let Some(mapping) = values.as_mapping() {
+        for key in mapping.keys().filter_map(Value::as_str) {
+            if !KNOWN_KEYS.contains(&key) {
+                import
+                    .warnings
+                    .push(format!("{} has no kwpm equivalent and was ignored", key));
+            }
+        }
+    }
+
+    if let Some(tag) = get_str(&values, "image.tag") {
+        let repository =
+            get_str(&values, "image.repository").unwrap_or_else(|| "bitnami/wordpress".to_string());
+        import.wordpress_image = Some(format!("{}:{}", repository, tag));
+        import.warnings.push(format!(
+            "image {}:{} is a Bitnami image, kwpm runs the official wordpress fpm image",
+            repository, tag
+        ));
+    }
+
+    if get_bool(&values, "ingress.enabled") {
+        import.hostname = get_str(&values, "ingress.hostname");
+        import.tls = get_bool(&values, "ingress.tls");
+    }
+
+    import.storage_size = get(&values, "persistence.enabled")
+        .and_then(Value::as_bool)
+        .unwrap_or(true)
+        .then(|| get_str(&values, "persistence.size"))
+        .flatten();
+
+    import.database =
+        if get_bool(&values, "mariadb.enabled") || get(&values, "mariadb.enabled").is_none() {
+            ImportedDatabase {
+                host: None,
+                name: get_str(&values, "mariadb.auth.database"),
+                user: get_str(&values, "mariadb.auth.username"),
+            }
+        } else {
+            ImportedDatabase {
+                host: get_str(&values, "externalDatabase.host"),
+                name: get_str(&values, "externalDatabase.database"),
+                user: get_str(&values, "externalDatabase.user"),
+            }
+        };
+
+    import.plugins = match get(&values, "wordpressPlugins") {
+        Some(Value::String(plugins)) if plugins != "none" && plugins != "all" => plugins
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect(),
+        Some(Value::Sequence(plugins)) => plugins
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    // Annotation keys contain dots, so they can't be looked up by path.
+    let upload_limit = get(&values, "ingress.annotations")
+        .and_then(|a| a.get("nginx.ingress.kubernetes.io/proxy-body-size"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .or_else(|| extra_env(&values, "PHP_UPLOAD_MAX_FILESIZE"));
+    if let Some(limit) = upload_limit {
+        match size_mb(&limit) {
+            Some(limit_mb) => import.spec.upload_limit_mb = Some(limit_mb),
+            None => import
+                .warnings
+                .push(format!("upload limit {} could not be parsed", limit)),
+        }
+    }
+
+    match get_str(&values, "updateStrategy.type").as_deref() {
+        Some("RollingUpdate") => {
+            let defaults = RolloutStrategy::default();
+            import.spec.rollout = Some(RolloutStrategy {
+                max_surge: get_str(&values, "updateStrategy.rollingUpdate.maxSurge")
+                    .unwrap_or(defaults.max_surge),
+                max_unavailable: get_str(&values, "updateStrategy.rollingUpdate.maxUnavailable")
+                    .unwrap_or(defaults.max_unavailable),
+                ..defaults
+            });
+        }
+        Some("Recreate") | None => {}
+        Some(other) => import
+            .warnings
+            .push(format!("update strategy {} is not supported", other)),
+    }
+
+    if let Some(replicas) = get(&values, "replicaCount").and_then(Value::as_u64) {
+        if replicas > 1 {
+            import.warnings.push(format!(
+                "replicaCount {} was ignored, kwpm sites run a single replica",
+                replicas
+            ));
+        }
+    }
+
+    Ok(import)
+}
+
+/// Reads the database settings from values of the standalone Bitnami MariaDB chart.
+pub fn import_bitnami_mariadb_values(values_yaml: &str) -> Result<ImportedDatabase> {
+    let values: Value = serde_yaml::from_str(values_yaml).context("invalid Helm values file")?;
+
+    Ok(ImportedDatabase {
+        host: None,
+        name: get_str(&values, "auth.database"),
+        user: get_str(&values, "auth.username"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: &str = r#"
+image:
+  registry: docker.io
+  repository: bitnami/wordpress
+  tag: 6.4.3-debian-12-r0
+wordpressUsername: admin
+wordpressPlugins: akismet, wordpress-seo
+replicaCount: 1
+updateStrategy:
+  type: RollingUpdate
+  rollingUpdate:
+    maxSurge: 25%
+ingress:
+  enabled: true
+  hostname: blog.example.com
+  tls: true
+  annotations:
+    nginx.ingress.kubernetes.io/proxy-body-size: 64m
+persistence:
+  size: 20Gi
+mariadb:
+  enabled: false
+externalDatabase:
+  host: db.example.com
+  user: blog
+  database: blog_wp
+metrics:
+  enabled: true
+"#;
+
+    #[test]
+    fn test_import_bitnami_values() {
+        let import = import_bitnami_values(VALUES).unwrap();
+
+        assert_eq!(import.hostname.as_deref(), Some("blog.example.com"));
+        assert!(import.tls);
+        assert_eq!(import.storage_size.as_deref(), Some("20Gi"));
+        assert_eq!(import.spec.upload_limit_mb, Some(64));
+        assert_eq!(import.spec.rollout.as_ref().unwrap().max_surge, "25%");
+        assert_eq!(import.plugins, ["akismet", "wordpress-seo"]);
+        assert_eq!(import.database.host.as_deref(), Some("db.example.com"));
+        assert_eq!(import.database.name.as_deref(), Some("blog_wp"));
+        assert!(import.warnings.iter().any(|w| w.starts_with("metrics")));
+    }
+
+    #[test]
+    fn test_upload_limit_from_php_env() {
+        let import = import_bitnami_values(
+            "extraEnvVars:\n  - name: PHP_UPLOAD_MAX_FILESIZE\n    value: 1G\n",
+        )
+        .unwrap();
+
+        assert_eq!(import.spec.upload_limit_mb, Some(1024));
+    }
+
+    #[test]
+    fn test_import_bitnami_mariadb_values() {
+        let database = import_bitnami_mariadb_values(
+            "auth:\n  database: blog_wp\n  username: blog\nprimary:\n  persistence:\n    size: 8Gi\n",
+        )
+        .unwrap();
+
+        assert_eq!(database.name.as_deref(), Some("blog_wp"));
+        assert_eq!(database.user.as_deref(), Some("blog"));
+    }
+
+    #[test]
+    fn test_size_mb() {
+        assert_eq!(size_mb("64m"), Some(64));
+        assert_eq!(size_mb("64M"), Some(64));
+        assert_eq!(size_mb("1g"), Some(1024));
+        assert_eq!(size_mb("lots"), None);
+    }
+}
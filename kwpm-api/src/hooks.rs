@@ -0,0 +1,267 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::batch::v1::Job;
+use serde_json::json;
+
+use crate::{
+    error::{bail, Result},
+    job::is_job_succeeded,
+    manifest::{job_pod_spec_mut, set_env},
+    site::AppKind,
+    KwpmClient,
+};
+
+const HOOK_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookStage {
+    /// Runs once the site namespace exists, before any workload is created.
+    PreCreate,
+    PostCreate,
+    PreDelete,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookStage::PreCreate => "pre-create",
+            HookStage::PostCreate => "post-create",
+            HookStage::PreDelete => "pre-delete",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HookAction {
+    /// Runs `command` in `image` as a Job in the site namespace.
+    Job { image: String, command: Vec<String> },
+    /// POSTs the stage and site as JSON, e.g. to register the site in a CMDB.
+    Http { url: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProvisioningHook {
+    pub name: String,
+    pub stage: HookStage,
+    pub action: HookAction,
+}
+
+/// The template hooks of a site running `app` are registered for, see
+/// [`KwpmClient::with_hook`]: `wordpress`, `php` or `static`.
+pub fn site_template(app: &AppKind) -> &'static str {
+    match app {
+        AppKind::WordPress => "wordpress",
+        AppKind::Php { .. } => "php",
+        AppKind::Static => "static",
+    }
+}
+
+/// The hooks registered for `template` at `stage`, in registration order.
+fn registered_hooks<'a>(
+    hooks: &'a BTreeMap<String, Vec<ProvisioningHook>>,
+    template: &str,
+    stage: HookStage,
+) -> impl Iterator<Item = &'a ProvisioningHook> {
+    hooks
+        .get(template)
+        .into_iter()
+        .flatten()
+        .filter(move |hook| hook.stage == stage)
+}
+
+async fn call_http_hook(
+    url: &str,
+    stage: HookStage,
+    template: &str,
+    site: &str,
+    namespace: &str,
+) -> Result<()> {
+    reqwest::Client::new()
+        .post(url)
+        .timeout(HOOK_TIMEOUT)
+        .json(&json!({
+            "stage": stage.as_str(),
+            "template": template,
+            "site": site,
+            "namespace": namespace,
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("hook request to {} failed", url))?;
+    Ok(())
+}
+
+pub fn hook_job(hook: &ProvisioningHook, site: &str, namespace: &str) -> Result<Job> {
+    let HookAction::Job { image, command } = &hook.action else {
+        bail!("hook {} does not run a job", hook.name);
+    };
+    if command.is_empty() {
+        bail!("hook {} has no command", hook.name);
+    }
+
+    let mut job: Job = serde_yaml::from_str(include_str!("../../kubernetes/hooks/hook-job.yaml"))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .extend([
+            ("kwpm.io/site".to_string(), site.to_string()),
+            ("kwpm.io/hook".to_string(), hook.name.clone()),
+        ]);
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    let container = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "hook")
+        .context("hook job has no hook container")?;
+    container.image = Some(image.clone());
+    container.command = Some(command.clone());
+
+    set_env(
+        pod_spec,
+        "hook",
+        &[
+            ("SITE", site.to_string()),
+            ("SITE_NAMESPACE", namespace.to_string()),
+            ("HOOK_STAGE", hook.stage.as_str().to_string()),
+        ],
+    )?;
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    async fn run_hook(&self, hook: &ProvisioningHook, template: &str, site: &str) -> Result<()> {
+        match &hook.action {
+            HookAction::Job { .. } => {
+                let ns_name = self.site_namespace(site);
+                let job = self
+                    .create_job(&ns_name, hook_job(hook, site, &ns_name)?)
+                    .await?;
+                let job_name = job.metadata.name.unwrap_or_default();
+
+                let job = self.wait_for_job(&ns_name, &job_name, HOOK_TIMEOUT).await?;
+                if !is_job_succeeded(&job) {
+                    let logs = self.job_logs(&ns_name, &job_name).await.unwrap_or_default();
+                    bail!("hook job {} failed:\n{}", job_name, logs);
+                }
+            }
+            HookAction::Http { url } => {
+                let ns_name = self.site_namespace(site);
+                call_http_hook(url, hook.stage, template, site, &ns_name).await?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the hooks registered for the site template at the given stage in registration
+    /// order, stopping at the first failure.
+    pub async fn run_provisioning_hooks(
+        &self,
+        template: &str,
+        stage: HookStage,
+        site: &str,
+    ) -> Result<()> {
+        for hook in registered_hooks(&self.hooks, template, stage) {
+            self.run_hook(hook, template, site)
+                .await
+                .with_context(|| format!("{} hook {} failed", stage.as_str(), hook.name))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_job() {
+        let hook = ProvisioningHook {
+            name: "seed-content".to_string(),
+            stage: HookStage::PostCreate,
+            action: HookAction::Job {
+                image: "wordpress:cli-php8.2".to_string(),
+                command: vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    "echo seeded".to_string(),
+                ],
+            },
+        };
+
+        let job = hook_job(&hook, "blog", "kwpm-blog").unwrap();
+        assert_eq!(job.metadata.labels.unwrap()["kwpm.io/hook"], "seed-content");
+
+        let container = &job.spec.unwrap().template.spec.unwrap().containers[0];
+        assert_eq!(container.image.as_deref(), Some("wordpress:cli-php8.2"));
+        let env = container.env.as_ref().unwrap();
+        assert!(env
+            .iter()
+            .any(|e| e.name == "HOOK_STAGE" && e.value.as_deref() == Some("post-create")));
+        assert!(env
+            .iter()
+            .any(|e| e.name == "SITE_NAMESPACE" && e.value.as_deref() == Some("kwpm-blog")));
+    }
+
+    #[test]
+    fn test_http_hook_has_no_job() {
+        let hook = ProvisioningHook {
+            name: "cmdb".to_string(),
+            stage: HookStage::PreDelete,
+            action: HookAction::Http {
+                url: "https://cmdb.example.com/hooks/kwpm".to_string(),
+            },
+        };
+
+        assert!(hook_job(&hook, "blog", "kwpm-blog").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registered_http_hook_is_called() {
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/hooks/kwpm",
+            axum::routing::post(move |axum::Json(body): axum::Json<serde_json::Value>| {
+                let sender = sender.clone();
+                async move {
+                    sender.send(body).unwrap();
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/kwpm", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let hooks = BTreeMap::from([(
+            "wordpress".to_string(),
+            vec![ProvisioningHook {
+                name: "cmdb".to_string(),
+                stage: HookStage::PostCreate,
+                action: HookAction::Http { url },
+            }],
+        )]);
+        let template = site_template(&AppKind::WordPress);
+        assert_eq!(
+            registered_hooks(&hooks, template, HookStage::PreCreate).count(),
+            0
+        );
+        for hook in registered_hooks(&hooks, template, HookStage::PostCreate) {
+            let HookAction::Http { url } = &hook.action else {
+                unreachable!();
+            };
+            call_http_hook(url, hook.stage, template, "blog", "kwpm-blog")
+                .await
+                .unwrap();
+        }
+
+        let body = received.recv().await.unwrap();
+        assert_eq!(body["stage"], "post-create");
+        assert_eq!(body["template"], "wordpress");
+        assert_eq!(body["namespace"], "kwpm-blog");
+    }
+}
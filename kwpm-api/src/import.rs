@@ -0,0 +1,662 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use k8s_openapi::api::batch::v1::Job;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{KwpmError, Result},
+    manifest::{job_pod_spec_mut, set_env},
+    mariadb::{mariadb_namespace, DEFAULT_MARIADB_INSTANCE},
+    provision::{is_valid_domain, is_valid_table_prefix},
+    search_replace::SearchReplace,
+    site::{AppKind, SiteSpec},
+    KwpmClient,
+};
+
+const INSPECT_TIMEOUT: Duration = Duration::from_secs(600);
+const IMPORT_TIMEOUT: Duration = Duration::from_secs(1800);
+const ENTRIES_MARKER: &str = "--- entries ---";
+/// The dump Softaculous puts next to the files of the installation it backed up.
+const SOFTACULOUS_DUMP: &str = "softsql.sql";
+/// What All-in-One WP Migration writes instead of the table prefix.
+const AIO_PREFIX_PLACEHOLDER: &str = "SERVMASK_PREFIX_";
+
+/// A backup taken on shared hosting or with a migration plugin to import as a new site.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostingImport {
+    /// Archive the cluster can download, e.g. a presigned URL: a `.tar.gz`, a zip package or a
+    /// `.wpress` export.
+    pub archive_url: String,
+    /// The domain of the new site, the backup's main domain if unset.
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// The database to import from cPanel backups of several, by name. Defaults to the one in
+    /// `wp-config.php`.
+    #[serde(default)]
+    pub database: Option<String>,
+    /// php-fpm image running sites that aren't WordPress.
+    #[serde(default)]
+    pub php_image: Option<String>,
+    /// The MariaDB instance of the site's database, the default one if unset.
+    #[serde(default)]
+    pub mariadb: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HostingBackupKind {
+    /// A cPanel account backup, with the files under `homedir/` and a dump per database under
+    /// `mysql/`.
+    Cpanel,
+    /// A Softaculous backup of one installation, its files next to `softsql.sql`.
+    Softaculous,
+    /// A Duplicator package archive, its settings and dump under `dup-installer/`.
+    Duplicator,
+    /// An All-in-One WP Migration `.wpress` export of wp-content with `database.sql`, its tables
+    /// prefixed with a placeholder.
+    AllInOneWpMigration,
+}
+
+impl HostingBackupKind {
+    /// Files in the docroot that only belong to the backup tool.
+    fn tool_files(self) -> &'static [&'static str] {
+        match self {
+            HostingBackupKind::Cpanel | HostingBackupKind::Softaculous => &[],
+            HostingBackupKind::Duplicator => {
+                &["dup-installer", "installer.php", "installer-backup.php"]
+            }
+            HostingBackupKind::AllInOneWpMigration => &["package.json", "multisite.json"],
+        }
+    }
+}
+
+/// What an inspection found in a hosting backup.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostingBackup {
+    pub kind: HostingBackupKind,
+    /// The directory in the archive holding the site, empty for the top level.
+    pub docroot: String,
+    /// The SQL dump in the archive to import into the site's database.
+    pub database_dump: Option<String>,
+    /// The domain the site was served at, if the backup tells.
+    pub domain: Option<String>,
+    pub wordpress: bool,
+    /// The `$table_prefix` of a WordPress site, a new one being generated if unknown.
+    pub table_prefix: Option<String>,
+    /// Whether the docroot has an `index.php`.
+    pub has_php: bool,
+}
+
+/// The first quoted string after `key` on one of `lines`, e.g. the value of
+/// `define( 'DB_NAME', 'acme_wp' );` for `DB_NAME` or of `"SiteURL":"https://a.example"` for
+/// `"SiteURL"`.
+fn quoted_value<'a>(lines: &[&'a str], key: &str) -> Option<&'a str> {
+    lines.iter().find_map(|line| {
+        let (_, rest) = line.split_once(key)?;
+        let rest = rest.trim_start_matches('\'').trim_start_matches('"');
+        let quote = rest.find(['\'', '"'])?;
+        let rest = &rest[quote + 1..];
+        Some(&rest[..rest.find(['\'', '"'])?])
+    })
+}
+
+/// The host of a site URL as WordPress stores it, slashes possibly JSON-escaped.
+fn url_host(url: &str) -> Option<String> {
+    let url = url.replace("\\/", "/");
+    let host = url
+        .split_once("://")
+        .map_or(url.as_str(), |(_, rest)| rest)
+        .split(['/', ':'])
+        .next()?;
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// The directory `entry` is in if its path ends with `name`, `""` for the top level.
+fn dir_of<'a>(entry: &'a str, name: &str) -> Option<&'a str> {
+    if entry == name {
+        return Some("");
+    }
+    entry.strip_suffix(name)?.strip_suffix('/')
+}
+
+impl HostingBackup {
+    /// Reads the output of an inspection job: the relevant entries of the archive, then the
+    /// settings found in `userdata/main`, `wp-config.php`, `package.json` and Duplicator's files.
+    pub fn from_inspection(output: &str, database: Option<&str>) -> Result<Self> {
+        let (_, inspection) = output
+            .split_once(&format!("{}\n", ENTRIES_MARKER))
+            .ok_or_else(|| KwpmError::invalid_manifest("the inspection job printed no entries"))?;
+        let mut entries = Vec::new();
+        let mut files: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        let mut file = None;
+        for line in inspection.lines() {
+            if let Some(name) = line
+                .strip_prefix("--- file ")
+                .and_then(|line| line.strip_suffix(" ---"))
+            {
+                let name = name.trim_start_matches("./").to_string();
+                files.insert(name.clone(), Vec::new());
+                file = Some(name);
+            } else if let Some(name) = &file {
+                files.entry(name.clone()).or_default().push(line);
+            } else if !line.is_empty() {
+                entries.push(line.trim_start_matches("./"));
+            }
+        }
+        let find_dir = |name: &str| entries.iter().find_map(|entry| dir_of(entry, name));
+        let has_entry = |path: &str| entries.contains(&path);
+        // Duplicator names its files after the package's hash.
+        let duplicator_file = |prefix: &str| {
+            files
+                .iter()
+                .find(|(name, _)| name.contains(&format!("dup-installer/{}", prefix)))
+                .map(|(_, lines)| lines.as_slice())
+                .unwrap_or_default()
+        };
+
+        let cpanel_root = find_dir("userdata/main");
+        let (kind, docroot) = if let Some(root) = cpanel_root {
+            (
+                HostingBackupKind::Cpanel,
+                join_path(root, "homedir/public_html"),
+            )
+        } else if let Some(dir) = entries
+            .iter()
+            .find_map(|entry| Some(entry.split_once("dup-installer/")?.0))
+        {
+            (
+                HostingBackupKind::Duplicator,
+                dir.trim_end_matches('/').to_string(),
+            )
+        } else if let Some(dir) = find_dir(SOFTACULOUS_DUMP) {
+            (HostingBackupKind::Softaculous, dir.to_string())
+        } else if has_entry("package.json") && has_entry("database.sql") {
+            (HostingBackupKind::AllInOneWpMigration, String::new())
+        } else {
+            return Err(KwpmError::invalid_input(
+                "the archive is neither a cPanel, Softaculous, Duplicator nor All-in-One WP \
+                 Migration backup",
+            ));
+        };
+
+        let wp_config = match files.get(&join_path(&docroot, "wp-config.php")) {
+            Some(lines) => Some(lines.as_slice()),
+            None if kind == HostingBackupKind::Duplicator => {
+                Some(duplicator_file("dup-wp-config-arc__"))
+            }
+            None => None,
+        };
+        let wordpress = wp_config.is_some() || kind == HostingBackupKind::AllInOneWpMigration;
+        let wp_config = wp_config.unwrap_or_default();
+        let table_prefix = quoted_value(wp_config, "$table_prefix")
+            .or_else(|| quoted_value(duplicator_file("dup-archive__"), "\"wp_tableprefix\""))
+            .map(str::to_string);
+        if let Some(prefix) = &table_prefix {
+            if !is_valid_table_prefix(prefix) {
+                return Err(KwpmError::invalid_input(format!(
+                    "unsupported table prefix in wp-config.php: {}",
+                    prefix
+                )));
+            }
+        }
+        let has_php = has_entry(&join_path(&docroot, "index.php"));
+
+        let database_dump = match kind {
+            HostingBackupKind::Softaculous => Some(join_path(&docroot, SOFTACULOUS_DUMP)),
+            HostingBackupKind::AllInOneWpMigration => Some("database.sql".to_string()),
+            HostingBackupKind::Duplicator => entries
+                .iter()
+                .find(|entry| {
+                    entry.contains("dup-installer/dup-database__") && entry.ends_with(".sql")
+                })
+                .map(|entry| entry.to_string())
+                .or_else(|| {
+                    let dump = join_path(&docroot, "database.sql");
+                    has_entry(&dump).then_some(dump)
+                }),
+            HostingBackupKind::Cpanel => {
+                let mysql_dir = join_path(cpanel_root.unwrap_or_default(), "mysql/");
+                let dumps: Vec<&str> = entries
+                    .iter()
+                    .filter_map(|entry| entry.strip_prefix(mysql_dir.as_str()))
+                    .filter_map(|name| name.strip_suffix(".sql"))
+                    .filter(|name| !name.contains('/'))
+                    .collect();
+                let wanted = database.or_else(|| quoted_value(wp_config, "DB_NAME"));
+                match (wanted, dumps.as_slice()) {
+                    (Some(name), _) if dumps.contains(&name) => {
+                        Some(format!("{}{}.sql", mysql_dir, name))
+                    }
+                    (Some(name), _) => {
+                        return Err(KwpmError::invalid_input(format!(
+                            "the backup has no dump of database {}",
+                            name
+                        )))
+                    }
+                    (None, []) => None,
+                    (None, [name]) => Some(format!("{}{}.sql", mysql_dir, name)),
+                    (None, _) => {
+                        return Err(KwpmError::invalid_input(format!(
+                            "the backup has dumps of {}, choose the database to import",
+                            dumps.join(", ")
+                        )))
+                    }
+                }
+            }
+        };
+
+        let domain = match kind {
+            HostingBackupKind::Cpanel => cpanel_root
+                .and_then(|root| files.get(&join_path(root, "userdata/main")))
+                .and_then(|lines| {
+                    lines
+                        .iter()
+                        .find_map(|line| line.strip_prefix("main_domain:"))
+                })
+                .map(|domain| domain.trim().trim_matches('\'').to_string()),
+            HostingBackupKind::Duplicator => {
+                quoted_value(duplicator_file("dup-archive__"), "\"url_old\"").and_then(url_host)
+            }
+            HostingBackupKind::AllInOneWpMigration => files
+                .get("package.json")
+                .and_then(|lines| quoted_value(lines, "\"SiteURL\""))
+                .and_then(url_host),
+            HostingBackupKind::Softaculous => None,
+        };
+
+        Ok(Self {
+            kind,
+            docroot,
+            database_dump,
+            domain,
+            wordpress,
+            table_prefix,
+            has_php,
+        })
+    }
+
+    /// The spec of the site the backup becomes: WordPress if it has a `wp-config.php` or is a
+    /// WordPress export, a PHP app run by `php_image` if it has an `index.php` and otherwise
+    /// static files.
+    pub fn site_spec(&self, php_image: Option<&str>) -> Result<SiteSpec> {
+        let app = match (self.wordpress, self.has_php, php_image) {
+            (true, _, _) => AppKind::WordPress,
+            (false, true, Some(image)) => AppKind::Php {
+                image: image.to_string(),
+            },
+            (false, true, None) => {
+                return Err(KwpmError::invalid_input(
+                    "the backup is a PHP app but not WordPress, it needs a php-fpm image",
+                ))
+            }
+            (false, false, _) => AppKind::Static,
+        };
+        if !app.uses_database() && self.database_dump.is_some() {
+            return Err(KwpmError::invalid_input(
+                "the backup has a database but no PHP app to use it",
+            ));
+        }
+        Ok(SiteSpec {
+            app,
+            ..Default::default()
+        })
+    }
+}
+
+fn hosting_inspect_job(archive_url: &str) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/import/hosting-inspect-job.yaml"
+    ))?;
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "inspect",
+        &[("ARCHIVE_URL", archive_url.to_string())],
+    )?;
+    Ok(job)
+}
+
+fn hosting_import_job(
+    site: &str,
+    archive_url: &str,
+    backup: &HostingBackup,
+    app: &AppKind,
+) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/import/hosting-import-job.yaml"
+    ))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    set_env(
+        pod_spec,
+        "fetch",
+        &[
+            ("ARCHIVE_URL", archive_url.to_string()),
+            (
+                "DATABASE_DUMP",
+                backup.database_dump.clone().unwrap_or_default(),
+            ),
+            ("DOCROOT", backup.docroot.clone()),
+            ("EXCLUDE", backup.kind.tool_files().join(" ")),
+        ],
+    )?;
+    let app = match app {
+        AppKind::WordPress => "wordpress",
+        AppKind::Php { .. } => "php",
+        AppKind::Static => "static",
+    };
+    // All-in-One WP Migration only exports wp-content.
+    let target = match backup.kind {
+        HostingBackupKind::AllInOneWpMigration => "wp-content",
+        _ => "",
+    };
+    set_env(
+        pod_spec,
+        "import-files",
+        &[
+            ("DOCROOT", backup.docroot.clone()),
+            ("TARGET", target.to_string()),
+            ("APP", app.to_string()),
+        ],
+    )?;
+    match &backup.database_dump {
+        Some(_) if backup.kind == HostingBackupKind::AllInOneWpMigration => set_env(
+            pod_spec,
+            "import-database",
+            &[("PREFIX_PLACEHOLDER", AIO_PREFIX_PLACEHOLDER.to_string())],
+        )?,
+        Some(_) => {}
+        None => {
+            // Jobs need a container; the files are imported by then.
+            let files = pod_spec
+                .init_containers
+                .as_mut()
+                .and_then(|containers| containers.pop())
+                .ok_or_else(|| KwpmError::invalid_manifest("import job has no files container"))?;
+            pod_spec.containers = vec![files];
+        }
+    }
+
+    Ok(job)
+}
+
+/// The site an import created.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedSite {
+    pub site: String,
+    pub domain: String,
+    pub backup: HostingBackup,
+}
+
+impl KwpmClient {
+    /// Creates `site` from a cPanel, Softaculous, Duplicator or All-in-One WP Migration backup:
+    /// inspects the archive, creates the site its contents map to, copies the files in, imports
+    /// the database and, for WordPress moving to another domain, replaces the old domain in the
+    /// database. A site that fails to import after it was created is left for inspection and
+    /// can be removed.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn import_hosting_backup(
+        &self,
+        site: &str,
+        import: &HostingImport,
+    ) -> Result<ImportedSite> {
+        let instance = import
+            .mariadb
+            .as_deref()
+            .unwrap_or(DEFAULT_MARIADB_INSTANCE);
+        let output = self
+            .run_job_with_output(
+                &mariadb_namespace(instance),
+                hosting_inspect_job(&import.archive_url)?,
+                INSPECT_TIMEOUT,
+            )
+            .await?;
+        let backup = HostingBackup::from_inspection(&output, import.database.as_deref())?;
+        let spec = backup.site_spec(import.php_image.as_deref())?;
+        let domain = match (&import.domain, &backup.domain) {
+            (Some(domain), _) | (None, Some(domain)) => domain.clone(),
+            (None, None) => {
+                return Err(KwpmError::invalid_input(
+                    "the backup names no domain, one has to be given",
+                ))
+            }
+        };
+        if !is_valid_domain(&domain) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid domain: {}",
+                domain
+            )));
+        }
+
+        let database = if spec.app.uses_database() {
+            let mut database = self.database_config_for_site(site)?.on_instance(instance);
+            if let Some(prefix) = &backup.table_prefix {
+                database.table_prefix = prefix.clone();
+            }
+            Some(database)
+        } else {
+            None
+        };
+        let job = hosting_import_job(site, &import.archive_url, &backup, &spec.app)?;
+        self.check_policy("import_site", Some(site), &[serde_json::to_value(&job)?])
+            .await?;
+        self.create_site(site, &domain, &spec.app, database.as_ref())
+            .await?;
+
+        let lock = self.lock_site(site, "import_site").await?;
+        let mut progress = self.start_operation(site, "import_site").await;
+        progress
+            .step("importing files and database", Some(10))
+            .await;
+        let result = self
+            .run_job(&self.site_namespace(site), job, IMPORT_TIMEOUT)
+            .await;
+        match &result {
+            Ok(_) => progress.succeed().await,
+            Err(e) => progress.fail(e).await,
+        }
+        // Released before the search-replace and restart below take the lock again.
+        lock.release_after(result).await?;
+
+        if let Some(old_domain) = backup.domain.as_ref().filter(|old| **old != domain) {
+            if spec.app.is_wordpress() {
+                self.search_replace(
+                    site,
+                    &SearchReplace {
+                        search: format!("//{}", old_domain),
+                        replace: format!("//{}", domain),
+                        dry_run: false,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            }
+        }
+        self.restart_site(site, "imported from a hosting backup")
+            .await?;
+        self.record_audit(
+            site,
+            "import_site",
+            &format!("imported {:?} backup into {}", backup.kind, domain),
+        )
+        .await?;
+
+        Ok(ImportedSite {
+            site: site.to_string(),
+            domain,
+            backup,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPANEL: &str = "\
+--- entries ---
+backup-3.1.2024_02-00-00_acme/userdata/main
+backup-3.1.2024_02-00-00_acme/mysql/acme_wp.sql
+backup-3.1.2024_02-00-00_acme/mysql/acme_shop.sql
+backup-3.1.2024_02-00-00_acme/homedir/public_html/index.php
+backup-3.1.2024_02-00-00_acme/homedir/public_html/wp-config.php
+--- file backup-3.1.2024_02-00-00_acme/userdata/main ---
+main_domain: acme.example
+--- file backup-3.1.2024_02-00-00_acme/homedir/public_html/wp-config.php ---
+define( 'DB_NAME', 'acme_wp' );
+$table_prefix = 'wpx_';
+";
+
+    #[test]
+    fn test_cpanel_backup() {
+        let backup = HostingBackup::from_inspection(CPANEL, None).unwrap();
+        assert_eq!(
+            backup,
+            HostingBackup {
+                kind: HostingBackupKind::Cpanel,
+                docroot: "backup-3.1.2024_02-00-00_acme/homedir/public_html".to_string(),
+                database_dump: Some("backup-3.1.2024_02-00-00_acme/mysql/acme_wp.sql".to_string()),
+                domain: Some("acme.example".to_string()),
+                wordpress: true,
+                table_prefix: Some("wpx_".to_string()),
+                has_php: true,
+            }
+        );
+        assert_eq!(backup.site_spec(None).unwrap().app, AppKind::WordPress);
+
+        let shop = HostingBackup::from_inspection(CPANEL, Some("acme_shop")).unwrap();
+        assert_eq!(
+            shop.database_dump.as_deref(),
+            Some("backup-3.1.2024_02-00-00_acme/mysql/acme_shop.sql")
+        );
+        assert!(HostingBackup::from_inspection(CPANEL, Some("other")).is_err());
+
+        let without_wordpress = "--- entries ---\nuserdata/main\nmysql/a.sql\nmysql/b.sql\n";
+        assert!(HostingBackup::from_inspection(without_wordpress, None).is_err());
+    }
+
+    #[test]
+    fn test_softaculous_backup() {
+        let output = "--- entries ---\n./softsql.sql\n./index.php\n";
+        let backup = HostingBackup::from_inspection(output, None).unwrap();
+        assert_eq!(backup.kind, HostingBackupKind::Softaculous);
+        assert_eq!(backup.docroot, "");
+        assert_eq!(backup.database_dump.as_deref(), Some("softsql.sql"));
+        assert_eq!(backup.domain, None);
+        assert!(backup.site_spec(None).is_err());
+        assert_eq!(
+            backup.site_spec(Some("matomo:5-fpm-alpine")).unwrap().app,
+            AppKind::Php {
+                image: "matomo:5-fpm-alpine".to_string()
+            }
+        );
+
+        assert!(HostingBackup::from_inspection("--- entries ---\nindex.html\n", None).is_err());
+        assert!(HostingBackup::from_inspection("wget: not found", None).is_err());
+    }
+
+    #[test]
+    fn test_duplicator_backup() {
+        let output = "\
+--- entries ---
+index.php
+dup-installer/dup-archive__9f2e1c.txt
+dup-installer/dup-wp-config-arc__9f2e1c.txt
+dup-installer/dup-database__9f2e1c.sql
+--- file dup-installer/dup-archive__9f2e1c.txt ---
+\"url_old\":\"https:\\/\\/Shop.example.org\"
+\"wp_tableprefix\":\"shop_\"
+--- file dup-installer/dup-wp-config-arc__9f2e1c.txt ---
+define( 'DB_NAME', 'shop' );
+";
+        let backup = HostingBackup::from_inspection(output, None).unwrap();
+        assert_eq!(backup.kind, HostingBackupKind::Duplicator);
+        assert_eq!(backup.docroot, "");
+        assert_eq!(
+            backup.database_dump.as_deref(),
+            Some("dup-installer/dup-database__9f2e1c.sql")
+        );
+        assert_eq!(backup.domain.as_deref(), Some("shop.example.org"));
+        assert_eq!(backup.table_prefix.as_deref(), Some("shop_"));
+        assert!(backup.wordpress);
+    }
+
+    #[test]
+    fn test_all_in_one_wp_migration_backup() {
+        let output = "\
+--- entries ---
+package.json
+database.sql
+--- file package.json ---
+\"SiteURL\":\"https://blog.example.net/\"
+";
+        let backup = HostingBackup::from_inspection(output, None).unwrap();
+        assert_eq!(backup.kind, HostingBackupKind::AllInOneWpMigration);
+        assert_eq!(backup.database_dump.as_deref(), Some("database.sql"));
+        assert_eq!(backup.domain.as_deref(), Some("blog.example.net"));
+        assert_eq!(backup.table_prefix, None);
+        assert_eq!(backup.site_spec(None).unwrap().app, AppKind::WordPress);
+
+        let job =
+            hosting_import_job("blog", "https://x/b.wpress", &backup, &AppKind::WordPress).unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+        let env = |container: &str, name: &str| {
+            pod_spec
+                .init_containers
+                .iter()
+                .flatten()
+                .chain(&pod_spec.containers)
+                .find(|c| c.name == container)
+                .and_then(|c| {
+                    c.env
+                        .as_ref()?
+                        .iter()
+                        .find(|e| e.name == name)?
+                        .value
+                        .clone()
+                })
+        };
+        assert_eq!(env("import-files", "TARGET").as_deref(), Some("wp-content"));
+        assert_eq!(
+            env("fetch", "EXCLUDE").as_deref(),
+            Some("package.json multisite.json")
+        );
+        assert_eq!(
+            env("import-database", "PREFIX_PLACEHOLDER").as_deref(),
+            Some(AIO_PREFIX_PLACEHOLDER)
+        );
+    }
+
+    #[test]
+    fn test_hosting_import_job() {
+        let backup = HostingBackup::from_inspection(CPANEL, None).unwrap();
+        let job =
+            hosting_import_job("acme", "https://x/b.tar.gz", &backup, &AppKind::WordPress).unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod_spec.containers[0].name, "import-database");
+
+        let backup = HostingBackup {
+            database_dump: None,
+            ..backup
+        };
+        let job =
+            hosting_import_job("acme", "https://x/b.tar.gz", &backup, &AppKind::Static).unwrap();
+        let pod_spec = job.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod_spec.containers[0].name, "import-files");
+        assert_eq!(pod_spec.init_containers.unwrap().len(), 1);
+    }
+}
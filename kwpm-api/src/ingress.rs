@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use k8s_openapi::api::networking::v1::Ingress;
+use kube::Api;
+
+use crate::{
+    error::{bail, Result},
+    provision::is_valid_domain,
+    uploads::ingress_body_size_annotations,
+    KwpmClient,
+};
+
+pub const SITE_INGRESS_NAME: &str = "wordpress-ingress";
+
+/// Ingress class and annotations every site ingress is created and updated with, e.g.
+/// `traefik` or cert-manager's `cert-manager.io/cluster-issuer`. The annotations take
+/// precedence over the defaults of the ingress template.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IngressManager {
+    pub class_name: Option<String>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl IngressManager {
+    /// Reads the class from `KWPM_INGRESS_CLASS` and the annotations from
+    /// `KWPM_INGRESS_ANNOTATIONS` as comma separated `key=value` pairs, both optional.
+    pub fn from_env() -> Result<Self> {
+        let annotations = match std::env::var("KWPM_INGRESS_ANNOTATIONS") {
+            Ok(annotations) => parse_annotations(&annotations)?,
+            Err(_) => BTreeMap::new(),
+        };
+
+        Ok(Self {
+            class_name: std::env::var("KWPM_INGRESS_CLASS")
+                .ok()
+                .filter(|class| !class.is_empty()),
+            annotations,
+        })
+    }
+
+    /// Points `ingress` at `host` and applies the class and annotations.
+    pub fn apply_to(&self, ingress: &mut Ingress, host: &str) {
+        if !self.annotations.is_empty() {
+            ingress
+                .metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .extend(self.annotations.clone());
+        }
+
+        let Some(spec) = ingress.spec.as_mut() else {
+            return;
+        };
+        if self.class_name.is_some() {
+            spec.ingress_class_name = self.class_name.clone();
+        }
+        for rule in spec.rules.iter_mut().flatten() {
+            rule.host = Some(host.to_string());
+        }
+        for tls in spec.tls.iter_mut().flatten() {
+            tls.hosts = Some(vec![host.to_string()]);
+        }
+    }
+
+    /// The ingress routing `host` to the site service.
+    pub fn site_ingress(&self, host: &str) -> Result<Ingress> {
+        let mut ingress: Ingress =
+            serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-ingress.yaml"))?;
+        self.apply_to(&mut ingress, host);
+        Ok(ingress)
+    }
+}
+
+pub(crate) fn parse_annotations(annotations: &str) -> Result<BTreeMap<String, String>> {
+    annotations
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            }
+            _ => bail!("invalid ingress annotation {}, expected key=value", pair),
+        })
+        .collect()
+}
+
+impl KwpmClient {
+    pub fn with_ingress_manager(mut self, ingress_manager: IngressManager) -> Self {
+        self.ingress_manager = ingress_manager;
+        self
+    }
+
+    /// Creates the site's ingress for `host`, or updates the existing one to `host` and the
+    /// configured class and annotations. Other changes to the ingress, e.g. by
+    /// `set_environment`, are kept. WordPress' own site URL isn't changed.
+    pub async fn apply_site_ingress(&self, site: &str, host: &str) -> Result<()> {
+        if !is_valid_domain(host) {
+            bail!("invalid domain: {}", host);
+        }
+        let lock = self.lock_site(site, "apply_site_ingress").await?;
+        let result: Result<_> = async {
+            let ingress_api: Api<Ingress> =
+                Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+            let existing = ingress_api.get_opt(SITE_INGRESS_NAME).await?;
+            let ingress = match &existing {
+                Some(ingress) => {
+                    let mut ingress = ingress.clone();
+                    self.ingress_manager.apply_to(&mut ingress, host);
+                    ingress
+                }
+                None => {
+                    let mut ingress = self.ingress_manager.site_ingress(host)?;
+                    if let Some(limit_mb) = self.stored_site_spec(site).await?.upload_limit_mb {
+                        ingress
+                            .metadata
+                            .annotations
+                            .get_or_insert_with(Default::default)
+                            .extend(ingress_body_size_annotations(limit_mb));
+                    }
+                    ingress
+                }
+            };
+            self.check_policy(
+                "apply_site_ingress",
+                Some(site),
+                &[serde_json::to_value(&ingress)?],
+            )
+            .await?;
+
+            if existing.is_some() {
+                ingress_api
+                    .replace(SITE_INGRESS_NAME, &Default::default(), &ingress)
+                    .await
+                    .with_context(|| format!("updating the ingress of {} failed", site))?;
+            } else {
+                ingress_api
+                    .create(&Default::default(), &self.labeled(&ingress))
+                    .await?;
+            }
+
+            self.record_site_revision(site, "apply_site_ingress")
+                .await?;
+            Ok(())
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_site_ingress() {
+        let manager = IngressManager {
+            class_name: Some("traefik".to_string()),
+            annotations: BTreeMap::from([
+                (
+                    "cert-manager.io/cluster-issuer".to_string(),
+                    "letsencrypt".to_string(),
+                ),
+                (
+                    "nginx.org/client-max-body-size".to_string(),
+                    "64m".to_string(),
+                ),
+            ]),
+        };
+        let ingress = manager.site_ingress("blog.example.com").unwrap();
+
+        let annotations = ingress.metadata.annotations.as_ref().unwrap();
+        assert_eq!(annotations["cert-manager.io/cluster-issuer"], "letsencrypt");
+        assert_eq!(annotations["nginx.org/client-max-body-size"], "64m");
+        let spec = ingress.spec.unwrap();
+        assert_eq!(spec.ingress_class_name.as_deref(), Some("traefik"));
+        assert_eq!(
+            spec.rules.unwrap()[0].host.as_deref(),
+            Some("blog.example.com")
+        );
+
+        let ingress = IngressManager::default()
+            .site_ingress("blog.example.com")
+            .unwrap();
+        assert_eq!(ingress.spec.unwrap().ingress_class_name, None);
+    }
+
+    #[test]
+    fn test_parse_annotations() {
+        assert_eq!(
+            parse_annotations("a.io/x=1, b.io/y = two,").unwrap(),
+            BTreeMap::from([
+                ("a.io/x".to_string(), "1".to_string()),
+                ("b.io/y".to_string(), "two".to_string()),
+            ])
+        );
+        assert!(parse_annotations("novalue").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use k8s_openapi::api::{batch::v1::Job, core::v1::Secret};
+use kube::Api;
+use serde::Serialize;
+
+use crate::{
+    database_job,
+    error::{KwpmError, Result},
+    mariadb::mariadb_namespace,
+    secret_value, DatabaseAction, KwpmClient,
+};
+
+const ENGINE_JOB_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// A table of a site database that doesn't use InnoDB.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableEngine {
+    pub table: String,
+    pub engine: String,
+}
+
+/// Whether a site's database can be recovered after a crash: MyISAM tables, often brought in
+/// by imported sites, aren't transactional and may be corrupted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageEngineReport {
+    pub site: String,
+    /// Whether MariaDB keeps every InnoDB table in its own file.
+    pub file_per_table: bool,
+    /// The tables not using InnoDB.
+    pub tables: Vec<TableEngine>,
+}
+
+impl StorageEngineReport {
+    /// Reads the output of a `list-engines` database job.
+    pub fn from_output(site: &str, output: &str) -> Self {
+        let mut report = Self {
+            site: site.to_string(),
+            file_per_table: false,
+            tables: Vec::new(),
+        };
+        for line in output.lines() {
+            if let Some(value) = line.trim().strip_prefix("innodb_file_per_table=") {
+                report.file_per_table = matches!(value, "1" | "ON");
+            } else if let Some((table, engine)) = line.split_once('\t') {
+                report.tables.push(TableEngine {
+                    table: table.to_string(),
+                    engine: engine.trim().to_string(),
+                });
+            }
+        }
+        report
+    }
+
+    pub fn myisam_tables(&self) -> Vec<&str> {
+        self.tables
+            .iter()
+            .filter(|table| table.engine.eq_ignore_ascii_case("MyISAM"))
+            .map(|table| table.table.as_str())
+            .collect()
+    }
+
+    pub fn is_crash_safe(&self) -> bool {
+        self.file_per_table && self.tables.is_empty()
+    }
+}
+
+impl KwpmClient {
+    /// The job running `action` on the site's database.
+    pub(crate) async fn site_database_job(
+        &self,
+        site: &str,
+        action: DatabaseAction,
+    ) -> Result<Job> {
+        let secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        let secret = secret_api
+            .get_opt("mysql-pass")
+            .await?
+            .ok_or_else(|| KwpmError::invalid_input(format!("{} has no database", site)))?;
+        database_job(
+            action,
+            &secret_value(&secret, "db_name")?,
+            &secret_value(&secret, "user")?,
+        )
+    }
+
+    /// Runs `job` on the MariaDB instance of the site and returns its output.
+    pub(crate) async fn run_site_job_on_mariadb(
+        &self,
+        site: &str,
+        job: Job,
+        timeout: Duration,
+    ) -> Result<String> {
+        let instance = self.site_mariadb_instance(site).await?;
+        self.run_job_with_output(&mariadb_namespace(&instance), job, timeout)
+            .await
+    }
+
+    /// Runs `action` on the site's database and returns the output of the job.
+    pub(crate) async fn run_site_database_job(
+        &self,
+        site: &str,
+        action: DatabaseAction,
+        timeout: Duration,
+    ) -> Result<String> {
+        let job = self.site_database_job(site, action).await?;
+        self.run_site_job_on_mariadb(site, job, timeout).await
+    }
+
+    /// The tables of the site's database that don't use InnoDB.
+    pub async fn storage_engine_report(&self, site: &str) -> Result<StorageEngineReport> {
+        let output = self
+            .run_site_database_job(site, DatabaseAction::ListEngines, ENGINE_JOB_TIMEOUT)
+            .await?;
+        Ok(StorageEngineReport::from_output(site, &output))
+    }
+
+    /// Converts the MyISAM tables of the site's database to InnoDB and returns the report of
+    /// the converted database. Tables are locked while they are converted.
+    pub async fn convert_myisam_tables(&self, site: &str) -> Result<StorageEngineReport> {
+        let report = self.storage_engine_report(site).await?;
+        let myisam_tables = report.myisam_tables();
+        if myisam_tables.is_empty() {
+            return Ok(report);
+        }
+
+        let lock = self.lock_site(site, "convert_myisam_tables").await?;
+        let result: Result<_> = async {
+            self.run_site_database_job(site, DatabaseAction::ConvertMyIsam, ENGINE_JOB_TIMEOUT)
+                .await?;
+            self.record_audit(site, "convert_myisam_tables", &myisam_tables.join(", "))
+                .await?;
+
+            self.storage_engine_report(site).await
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_engine_report() {
+        let report = StorageEngineReport::from_output(
+            "blog",
+            "innodb_file_per_table=1\nwp_posts\tMyISAM\nwp_stats\tAria\n",
+        );
+        assert!(report.file_per_table);
+        assert_eq!(report.tables.len(), 2);
+        assert_eq!(report.myisam_tables(), ["wp_posts"]);
+        assert!(!report.is_crash_safe());
+
+        let clean = StorageEngineReport::from_output("blog", "innodb_file_per_table=1\n");
+        assert!(clean.is_crash_safe());
+        let shared = StorageEngineReport::from_output("blog", "innodb_file_per_table=0\n");
+        assert!(!shared.is_crash_safe());
+    }
+}
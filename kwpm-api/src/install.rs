@@ -0,0 +1,224 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::{bail, Result},
+    users::{generate_user_password, is_valid_user, AdminUser},
+    KwpmClient,
+};
+
+/// The locale WordPress ships with, which has no language pack to install.
+const DEFAULT_LOCALE: &str = "en_US";
+
+/// WordPress locales like `de_DE`, `pt_BR` or `de_CH_informal`.
+pub fn is_valid_locale(locale: &str) -> bool {
+    let parts: Vec<&str> = locale.split('_').collect();
+    parts.len() <= 3
+        && (2..=3).contains(&parts[0].len())
+        && parts[0].chars().all(|c| c.is_ascii_lowercase())
+        && parts[1..]
+            .iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// IANA time zone names like `Europe/Berlin` or `UTC`, which WordPress stores as
+/// `timezone_string`.
+pub fn is_valid_timezone(timezone: &str) -> bool {
+    !timezone.is_empty()
+        && timezone.len() <= 64
+        && timezone.starts_with(|c: char| c.is_ascii_alphabetic())
+        && timezone
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/_-+".contains(c))
+}
+
+fn check_locale(locale: Option<&str>, timezone: Option<&str>) -> Result<()> {
+    if let Some(locale) = locale.filter(|locale| !is_valid_locale(locale)) {
+        bail!("invalid locale: {}", locale);
+    }
+    if let Some(timezone) = timezone.filter(|timezone| !is_valid_timezone(timezone)) {
+        bail!("invalid timezone: {}", timezone);
+    }
+    Ok(())
+}
+
+/// The WP-CLI commands switching an installed site to `locale` and `timezone`.
+fn locale_commands(locale: Option<&str>, timezone: Option<&str>) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    match locale {
+        Some(DEFAULT_LOCALE) => {
+            commands.push(vec!["language", "core", "activate", DEFAULT_LOCALE]);
+        }
+        Some(locale) => commands.push(vec!["language", "core", "install", locale, "--activate"]),
+        None => {}
+    }
+    if let Some(timezone) = timezone {
+        commands.push(vec!["option", "update", "timezone_string", timezone]);
+    }
+    commands
+        .into_iter()
+        .map(|command| command.into_iter().map(str::to_string).collect())
+        .collect()
+}
+
+/// What the WordPress installer would otherwise ask on the first visit.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WordPressInstall {
+    pub title: String,
+    pub admin_user: String,
+    pub admin_email: String,
+}
+
+impl KwpmClient {
+    async fn apply_locale(
+        &self,
+        site: &str,
+        locale: Option<&str>,
+        timezone: Option<&str>,
+    ) -> Result<()> {
+        for command in locale_commands(locale, timezone) {
+            let args: Vec<&str> = command.iter().map(String::as_str).collect();
+            self.run_wp_cli(site, &args).await?;
+        }
+        Ok(())
+    }
+
+    /// Installs WordPress without the web installer, in the locale and timezone and with the
+    /// plugins and themes of the site's spec, and returns the administrator with its generated
+    /// password.
+    pub async fn install_wordpress(
+        &self,
+        site: &str,
+        install: &WordPressInstall,
+    ) -> Result<AdminUser> {
+        for user in [&install.admin_user, &install.admin_email] {
+            if !is_valid_user(user) {
+                bail!("invalid user: {}", user);
+            }
+        }
+        let spec = self.get_site_spec(site).await?;
+        check_locale(spec.locale.as_deref(), spec.timezone.as_deref())?;
+        self.check_policy(
+            "install_wordpress",
+            Some(site),
+            &[json!({
+                "adminUser": install.admin_user,
+                "locale": spec.locale,
+                "timezone": spec.timezone,
+            })],
+        )
+        .await?;
+
+        let url = self.site_url(site).await?;
+        let password = generate_user_password()?;
+        self.run_wp_cli(
+            site,
+            &[
+                "core",
+                "install",
+                &format!("--url={}", url.trim_end_matches('/')),
+                &format!("--title={}", install.title),
+                &format!("--admin_user={}", install.admin_user),
+                &format!("--admin_email={}", install.admin_email),
+                &format!("--admin_password={}", password),
+                &format!(
+                    "--locale={}",
+                    spec.locale.as_deref().unwrap_or(DEFAULT_LOCALE)
+                ),
+                "--skip-email",
+            ],
+        )
+        .await?;
+        self.apply_locale(site, spec.locale.as_deref(), spec.timezone.as_deref())
+            .await?;
+        self.sync_site_extensions(site).await?;
+        self.record_audit(site, "install_wordpress", &install.admin_user)
+            .await?;
+
+        Ok(AdminUser {
+            login: install.admin_user.clone(),
+            email: install.admin_email.clone(),
+            password,
+        })
+    }
+
+    /// Changes the locale and timezone of the site's spec and, once WordPress is installed,
+    /// switches the site to them, downloading the language pack. `None` keeps the current one.
+    pub async fn set_site_locale(
+        &self,
+        site: &str,
+        locale: Option<&str>,
+        timezone: Option<&str>,
+    ) -> Result<()> {
+        check_locale(locale, timezone)?;
+        let mut spec = self.get_site_spec(site).await?;
+        if !spec.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        if let Some(locale) = locale {
+            spec.locale = Some(locale.to_string());
+        }
+        if let Some(timezone) = timezone {
+            spec.timezone = Some(timezone.to_string());
+        }
+        self.save_site_spec(site, &spec).await?;
+
+        // Not installed yet, `install_wordpress` or the first visit picks the spec up.
+        if self
+            .run_wp_cli(site, &["core", "is-installed"])
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+        self.apply_locale(site, locale, timezone).await?;
+        self.record_audit(
+            site,
+            "set_site_locale",
+            &format!(
+                "locale {}, timezone {}",
+                spec.locale.as_deref().unwrap_or(DEFAULT_LOCALE),
+                spec.timezone.as_deref().unwrap_or("UTC")
+            ),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locale_and_timezone() {
+        assert!(is_valid_locale("de_DE"));
+        assert!(is_valid_locale("fr"));
+        assert!(is_valid_locale("de_CH_informal"));
+        assert!(!is_valid_locale("DE"));
+        assert!(!is_valid_locale("de_DE; rm"));
+        assert!(!is_valid_locale("de__DE"));
+
+        assert!(is_valid_timezone("Europe/Berlin"));
+        assert!(is_valid_timezone("America/Argentina/Buenos_Aires"));
+        assert!(is_valid_timezone("UTC"));
+        assert!(!is_valid_timezone("--help"));
+        assert!(!is_valid_timezone("Europe/Berlin now"));
+    }
+
+    #[test]
+    fn test_locale_commands() {
+        assert!(locale_commands(None, None).is_empty());
+        assert_eq!(
+            locale_commands(Some("de_DE"), Some("Europe/Berlin")),
+            [
+                vec!["language", "core", "install", "de_DE", "--activate"],
+                vec!["option", "update", "timezone_string", "Europe/Berlin"],
+            ]
+        );
+        assert_eq!(
+            locale_commands(Some("en_US"), None),
+            [vec!["language", "core", "activate", "en_US"]]
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::cmp::Ordering;
+
+use anyhow::Context;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, library::LibraryItemKind, KwpmClient};
+
+const INVENTORY_CONCURRENCY: usize = 4;
+
+/// A plugin or theme installed on a site.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InventoryItem {
+    pub site: String,
+    pub kind: LibraryItemKind,
+    pub slug: String,
+    pub version: String,
+    /// e.g. `active`, `inactive` or `must-use`.
+    pub status: String,
+    /// The newer version wordpress.org offers, if any.
+    pub update_version: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Inventory {
+    pub items: Vec<InventoryItem>,
+    /// Sites that couldn't be listed, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+impl Inventory {
+    /// Installations of `slug`, only those older than `below` if given, e.g. the version a
+    /// vulnerability was fixed in.
+    pub fn find(
+        &self,
+        kind: LibraryItemKind,
+        slug: &str,
+        below: Option<&str>,
+    ) -> Vec<&InventoryItem> {
+        self.items
+            .iter()
+            .filter(|item| item.kind == kind && item.slug == slug)
+            .filter(|item| {
+                below.is_none_or(|below| compare_versions(&item.version, below) == Ordering::Less)
+            })
+            .collect()
+    }
+}
+
+/// Compares WordPress style versions numerically part by part, so `5.10` is newer than `5.9`
+/// and `6.4` equals `6.4.0`. A pre-release suffix like `-beta1` sorts before the release.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn split(version: &str) -> (Vec<u64>, Option<&str>) {
+        let (release, pre) = match version.trim().split_once('-') {
+            Some((release, pre)) => (release, Some(pre)),
+            None => (version.trim(), None),
+        };
+        let mut parts: Vec<u64> = release
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect();
+        while parts.last() == Some(&0) {
+            parts.pop();
+        }
+        (parts, pre)
+    }
+
+    let (a_parts, a_pre) = split(a);
+    let (b_parts, b_pre) = split(b);
+    a_parts.cmp(&b_parts).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => a.cmp(b),
+    })
+}
+
+#[derive(Deserialize)]
+struct ListedItem {
+    name: String,
+    status: String,
+    version: String,
+    #[serde(default)]
+    update_version: String,
+}
+
+pub fn parse_listed_items(
+    site: &str,
+    kind: LibraryItemKind,
+    output: &str,
+) -> Result<Vec<InventoryItem>> {
+    let listed: Vec<ListedItem> = serde_json::from_str(output.trim())
+        .with_context(|| format!("unexpected wp {} list output", kind.dir()))?;
+
+    Ok(listed
+        .into_iter()
+        .map(|item| InventoryItem {
+            site: site.to_string(),
+            kind,
+            slug: item.name,
+            version: item.version,
+            status: item.status,
+            update_version: Some(item.update_version).filter(|v| !v.is_empty()),
+        })
+        .collect())
+}
+
+impl KwpmClient {
+    async fn site_inventory(&self, site: &str) -> Result<Vec<InventoryItem>> {
+        let mut items = Vec::new();
+        for (kind, command) in [
+            (LibraryItemKind::Plugin, "plugin"),
+            (LibraryItemKind::Theme, "theme"),
+        ] {
+            let output = self
+                .run_wp_cli(
+                    site,
+                    &[
+                        command,
+                        "list",
+                        "--fields=name,status,version,update_version",
+                        "--format=json",
+                    ],
+                )
+                .await?;
+            items.extend(parse_listed_items(site, kind, &output)?);
+        }
+
+        Ok(items)
+    }
+
+    /// Lists the plugins and themes installed on every site matching `site_selector`.
+    pub async fn inventory(&self, site_selector: &str) -> Result<Inventory> {
+        let sites = self.list_site_names(site_selector).await?;
+
+        let results: Vec<(String, Result<Vec<InventoryItem>>)> = stream::iter(&sites)
+            .map(|site| async move { (site.clone(), self.site_inventory(site).await) })
+            .buffer_unordered(INVENTORY_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut inventory = Inventory::default();
+        for (site, result) in results {
+            match result {
+                Ok(items) => inventory.items.extend(items),
+                Err(e) => inventory.failed.push((site, format!("{:#}", e))),
+            }
+        }
+        inventory
+            .items
+            .sort_by(|a, b| (&a.site, &a.slug).cmp(&(&b.site, &b.slug)));
+        inventory.failed.sort();
+
+        Ok(inventory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("5.10", "5.9"), Ordering::Greater);
+        assert_eq!(compare_versions("6.4", "6.4.0"), Ordering::Equal);
+        assert_eq!(compare_versions("6.5-beta1", "6.5"), Ordering::Less);
+        assert_eq!(compare_versions("2.3.1", "2.3.2"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_find_vulnerable_installations() {
+        let plugins = r#"[
+            {"name":"akismet","status":"active","version":"5.2","update_version":"5.3"},
+            {"name":"hello","status":"inactive","version":"1.7.2","update_version":""}
+        ]"#;
+        let inventory = Inventory {
+            items: [
+                parse_listed_items("blog", LibraryItemKind::Plugin, plugins).unwrap(),
+                parse_listed_items(
+                    "shop",
+                    LibraryItemKind::Plugin,
+                    r#"[{"name":"akismet","status":"active","version":"5.3"}]"#,
+                )
+                .unwrap(),
+            ]
+            .concat(),
+            failed: Vec::new(),
+        };
+
+        let vulnerable = inventory.find(LibraryItemKind::Plugin, "akismet", Some("5.3"));
+        assert_eq!(vulnerable.len(), 1);
+        assert_eq!(vulnerable[0].site, "blog");
+        assert_eq!(vulnerable[0].update_version.as_deref(), Some("5.3"));
+        assert_eq!(
+            inventory
+                .find(LibraryItemKind::Plugin, "akismet", None)
+                .len(),
+            2
+        );
+        assert!(inventory
+            .find(LibraryItemKind::Theme, "akismet", None)
+            .is_empty());
+    }
+}
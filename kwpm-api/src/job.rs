@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{batch::v1::Job, core::v1::Pod};
+use kube::{
+    api::{ListParams, LogParams},
+    runtime::wait::await_condition,
+    Api,
+};
+
+use crate::{
+    error::{bail, Result},
+    manifest::job_pod_spec_mut,
+    KwpmClient,
+};
+
+/// Whether a finished job succeeded, and when it finished.
+pub(crate) fn job_outcome(job: &Job) -> Option<(bool, DateTime<Utc>)> {
+    job.status
+        .as_ref()?
+        .conditions
+        .as_ref()?
+        .iter()
+        .find(|c| (c.type_ == "Complete" || c.type_ == "Failed") && c.status == "True")
+        .map(|c| {
+            (
+                c.type_ == "Complete",
+                c.last_transition_time
+                    .as_ref()
+                    .map_or_else(Utc::now, |time| time.0),
+            )
+        })
+}
+
+fn is_job_finished(job: Option<&Job>) -> bool {
+    job.and_then(job_outcome).is_some()
+}
+
+pub(crate) fn job_finished_at(job: &Job) -> Option<DateTime<Utc>> {
+    job.status
+        .as_ref()
+        .and_then(|status| status.completion_time.as_ref())
+        .map(|time| time.0)
+}
+
+pub(crate) fn is_job_succeeded(job: &Job) -> bool {
+    job.status
+        .as_ref()
+        .and_then(|status| status.succeeded)
+        .unwrap_or(0)
+        > 0
+}
+
+impl KwpmClient {
+    pub(crate) async fn wait_for_job(
+        &self,
+        namespace: &str,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<Job> {
+        let job_api: Api<Job> = Api::namespaced(self.client.clone(), namespace);
+
+        let job = tokio::time::timeout(timeout, await_condition(job_api, name, is_job_finished))
+            .await
+            .with_context(|| format!("timed out waiting for job {}/{}", namespace, name))??;
+
+        Ok(job.with_context(|| format!("job {}/{} was deleted", namespace, name))?)
+    }
+
+    pub(crate) async fn create_job(&self, namespace: &str, mut job: Job) -> Result<Job> {
+        job.metadata.namespace = Some(namespace.to_string());
+        self.prepare_pod_spec(job_pod_spec_mut(&mut job)?);
+        self.adapt_mariadb_host(namespace, job_pod_spec_mut(&mut job)?)
+            .await?;
+
+        let job_api: Api<Job> = Api::namespaced(self.client.clone(), namespace);
+        Ok(job_api
+            .create(&Default::default(), &self.labeled(&job))
+            .await?)
+    }
+
+    /// Creates the job, waits for it and fails with its logs if it did not succeed.
+    pub(crate) async fn run_job(&self, namespace: &str, job: Job, timeout: Duration) -> Result<()> {
+        self.run_job_to_completion(namespace, job, timeout).await?;
+        Ok(())
+    }
+
+    /// Like `run_job`, returning the logs of the succeeded job.
+    pub(crate) async fn run_job_with_output(
+        &self,
+        namespace: &str,
+        job: Job,
+        timeout: Duration,
+    ) -> Result<String> {
+        let job_name = self.run_job_to_completion(namespace, job, timeout).await?;
+        self.job_logs(namespace, &job_name).await
+    }
+
+    /// `run_job`, returning the name of the job.
+    #[tracing::instrument(skip_all, fields(namespace = %namespace, job = tracing::field::Empty))]
+    async fn run_job_to_completion(
+        &self,
+        namespace: &str,
+        job: Job,
+        timeout: Duration,
+    ) -> Result<String> {
+        let job = self.create_job(namespace, job).await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+        tracing::Span::current().record("job", job_name.as_str());
+
+        let job = self.wait_for_job(namespace, &job_name, timeout).await?;
+        if !is_job_succeeded(&job) {
+            let logs = self
+                .job_logs(namespace, &job_name)
+                .await
+                .unwrap_or_default();
+            bail!("job {}/{} failed:\n{}", namespace, job_name, logs);
+        }
+
+        Ok(job_name)
+    }
+
+    /// The most recently completed job matching `selector`, e.g. the last run of a cron job.
+    pub(crate) async fn latest_completed_job(
+        &self,
+        namespace: &str,
+        selector: &str,
+    ) -> Result<Option<Job>> {
+        let job_api: Api<Job> = Api::namespaced(self.client.clone(), namespace);
+
+        let jobs = job_api
+            .list(&ListParams::default().labels(selector))
+            .await?;
+        Ok(jobs
+            .items
+            .into_iter()
+            .filter(|job| job_finished_at(job).is_some())
+            .max_by_key(job_finished_at))
+    }
+
+    pub(crate) async fn job_logs(&self, namespace: &str, name: &str) -> Result<String> {
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+
+        let pods = pod_api
+            .list(&ListParams::default().labels(&format!("job-name={}", name)))
+            .await?;
+        let Some(pod_name) = pods.items.last().and_then(|pod| pod.metadata.name.clone()) else {
+            bail!("job {}/{} has no pods", namespace, name);
+        };
+
+        let logs = pod_api.logs(&pod_name, &LogParams::default()).await?;
+        Ok(self.redact(&logs))
+    }
+}
@@ -0,0 +1,510 @@
+pub mod access;
+pub mod arch;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod blueprint;
+pub mod bundle;
+pub mod cache;
+pub mod canary;
+pub mod checksums;
+pub mod compat;
+pub mod config;
+pub mod config_backup;
+pub mod confirm;
+pub mod credentials;
+pub mod cron;
+pub mod debug;
+pub mod deploy;
+pub mod drift;
+pub mod environment;
+pub mod error;
+mod events;
+pub mod exec;
+pub mod export;
+pub mod failover;
+pub mod fleet;
+pub mod gitops;
+pub mod health;
+pub mod healthz;
+pub mod helm;
+pub mod hooks;
+pub mod import;
+pub mod ingress;
+pub mod innodb;
+pub mod install;
+pub mod inventory;
+mod job;
+pub mod library;
+pub mod lock;
+pub mod logging;
+pub mod logs;
+pub mod malware;
+mod manifest;
+pub mod mariadb;
+pub mod mariadb_upgrade;
+pub mod media;
+pub mod metadata;
+pub mod metrics;
+pub mod namespace;
+pub mod nginx;
+pub mod nodes;
+pub mod notify;
+pub mod operator;
+pub mod pause;
+pub mod placement;
+pub mod plugins;
+pub mod policy;
+pub mod preview;
+pub mod profile;
+pub mod progress;
+pub mod prometheus;
+pub mod provision;
+pub mod quota;
+pub mod ratelimit;
+pub mod readonly;
+pub mod redirect;
+pub mod registry;
+pub mod relabel;
+pub mod remediation;
+pub mod report;
+pub mod restart;
+pub mod revision;
+mod rollback;
+pub mod rollout;
+pub mod search_replace;
+pub mod secrets;
+pub mod server;
+pub mod sidecar;
+pub mod site;
+pub mod site_env;
+pub mod slo;
+pub mod snapshot;
+pub mod sql;
+pub mod status;
+pub mod storage;
+pub mod templates;
+pub mod tenant;
+pub mod timeline;
+pub mod traffic;
+pub mod upgrade;
+pub mod uploads;
+pub mod users;
+pub mod wp_cli;
+
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    batch::v1::Job,
+    core::v1::{
+        Namespace, NodeSelector, NodeSelectorRequirement, NodeSelectorTerm, Secret,
+        VolumeNodeAffinity,
+    },
+};
+use kube::Api;
+
+use error::{bail, Result};
+use manifest::{job_pod_spec_mut, set_env};
+use mariadb::DEFAULT_MARIADB_INSTANCE;
+
+pub use access::AdminAccess;
+pub use archive::{ArchiveOptions, SiteArchive};
+pub use audit::AuditEntry;
+pub use backup::{Backup, BackupContents, BackupMode, BackupStorage, RestoreJob, StoredBackup};
+pub use blueprint::{fetch_blueprint, Blueprint, BlueprintSource, ManifestOverlay};
+pub use bundle::{create_bundle, install_bundle, BundleOptions};
+pub use cache::CacheWarmup;
+pub use canary::{CanaryOptions, CanaryReport};
+pub use compat::ClusterVersion;
+pub use config::{KwpmConfig, ResourceLimits};
+pub use config_backup::{KwpmConfigBackup, SiteConfig};
+pub use confirm::{ConfirmationRequired, DestructiveOperation, Plan};
+pub use credentials::{redact_credentials, CredentialsToken, DatabaseCredentials};
+pub use deploy::{CodeArtifact, CodeDeploy};
+pub use drift::Drift;
+pub use environment::{Environment, EnvironmentProfile};
+pub use error::KwpmError;
+pub use exec::ExecEvent;
+pub use fleet::{FleetUpdateOptions, FleetUpdateReport};
+pub use gitops::{
+    flux_artifact_manifest, generate_argocd_app, generate_flux_resources, GitOpsSource,
+};
+pub use helm::{
+    import_bitnami_mariadb_values, import_bitnami_values, HelmValuesImport, ImportedDatabase,
+};
+pub use hooks::{HookAction, HookStage, ProvisioningHook};
+pub use import::{HostingBackup, HostingBackupKind, HostingImport, ImportedSite};
+pub use ingress::IngressManager;
+pub use innodb::{StorageEngineReport, TableEngine};
+pub use lock::{SiteLock, SiteLockConflict};
+pub use logging::{init_logging, LogFormat};
+pub use logs::PhpError;
+pub use mariadb::RootPasswordSecret;
+pub use mariadb_upgrade::MariaDbUpgrade;
+pub use media::ImageOptimizationReport;
+pub use metadata::DefaultMetadata;
+pub use namespace::StuckNamespace;
+pub use notify::{Notification, NotificationChannel};
+pub use operator::{WordPressSite, WordPressSiteSpec, WordPressSiteStatus};
+pub use pause::PAUSED_ANNOTATION;
+pub use placement::{DatabasePlacement, DatabasePlacementPolicy, SitePlan};
+pub use plugins::{Extension, ExtensionKind, InstalledExtension};
+pub use policy::PolicyDecision;
+pub use preview::Preview;
+pub use profile::SiteProfile;
+pub use progress::{OperationRecord, OperationStatus, ProgressEvent};
+pub use provision::DatabaseConfig;
+pub use quota::{DatabaseQuota, DatabaseQuotaPolicy, DatabaseUsage, QuotaMode};
+pub use ratelimit::KubeRateLimit;
+pub use readonly::ReadOnlyViolation;
+pub use redirect::Redirect;
+pub use registry::{ImageReference, RegistryCredentials};
+pub use relabel::{MetadataChange, RelabelReport};
+pub use report::{ReportPeriod, ReportSchedule, SiteReport, TenantReport};
+pub use revision::SiteRevision;
+pub use rollout::RolloutStrategy;
+pub use secrets::MasterKeys;
+pub use sidecar::Sidecars;
+pub use site::{AppKind, SiteSpec};
+pub use slo::{SloStatus, SloTarget};
+pub use snapshot::{RiskyOperation, Snapshot};
+pub use sql::QueryResult;
+pub use status::{DatabaseState, SiteStatus, TlsState};
+pub use storage::VolumeUsage;
+pub use templates::ManifestSource;
+pub use tenant::{SiteTransfer, TenantOwnership, TenantTransfer};
+pub use timeline::{TimelineEntry, TimelineSource};
+pub use traffic::SiteTraffic;
+pub use upgrade::SiteUpgrade;
+
+pub(crate) fn local_node_affinity(node_hostname: &str) -> VolumeNodeAffinity {
+    VolumeNodeAffinity {
+        required: Some(NodeSelector {
+            node_selector_terms: vec![NodeSelectorTerm {
+                match_expressions: Some(vec![NodeSelectorRequirement {
+                    key: "kubernetes.io/hostname".to_string(),
+                    operator: "In".to_string(),
+                    values: Some(vec![node_hostname.to_string()]),
+                }]),
+                ..Default::default()
+            }],
+        }),
+    }
+}
+
+pub(crate) const MARIADB_NAMESPACE: &str = "kwpm-mariadb";
+pub(crate) const MARIADB_PV_NAME: &str = "kwpm-mariadb-pv";
+pub(crate) const MARIADB_DEPLOYMENT_NAME: &str = "mariadb";
+/// Marks the shared objects kwpm creates outside of sites, e.g. `mariadb`.
+pub(crate) const COMPONENT_LABEL: &str = "kwpm.io/component";
+
+pub(crate) const MAX_DATABASE_NAME_LEN: usize = 64;
+
+pub(crate) fn is_valid_database_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_DATABASE_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DatabaseAction {
+    /// Creates the database if needed and grants `user` all privileges on it.
+    Create,
+    /// Like `Create`, and sets the user's password to the one in the password secret.
+    ResetPassword,
+    /// Drops the database, keeping the user for other databases it may have, e.g. previews.
+    Drop,
+    /// Drops the database and its user, when the site owning both is removed.
+    DropWithUser,
+    /// Copies the database from the MariaDB at `SOURCE_HOST` into the created one, dumping it
+    /// with the user's password from the password secret.
+    Copy,
+    /// Prints `innodb_file_per_table=<0|1>` and a `<table>\t<engine>` line for every table
+    /// not using InnoDB.
+    ListEngines,
+    /// Converts the MyISAM tables of the database to InnoDB.
+    ConvertMyIsam,
+    /// Prints the bytes the tables and indexes of the database take.
+    Size,
+    /// Limits the user to reading and deleting, until `Create` grants all privileges again.
+    RestrictWrites,
+    /// Runs the read-only `QUERY` as a user that can only `SELECT`, created for the job and
+    /// dropped when it exits, printing at most `MAX_ROWS` rows below a `--- result ---` line.
+    Query,
+}
+
+/// Job run in the MariaDB namespace with the root password to manage a site database.
+pub(crate) fn database_job(action: DatabaseAction, database: &str, user: &str) -> Result<Job> {
+    if !is_valid_database_identifier(database) || !is_valid_database_identifier(user) {
+        bail!("invalid database {} or user {}", database, user);
+    }
+
+    let mut job: Job =
+        serde_yaml::from_str(include_str!("../../kubernetes/mariadb/database-job.yaml"))?;
+    let action = match action {
+        DatabaseAction::Create => "create",
+        DatabaseAction::ResetPassword => "reset-password",
+        DatabaseAction::Drop => "drop",
+        DatabaseAction::DropWithUser => "drop-with-user",
+        DatabaseAction::Copy => "copy",
+        DatabaseAction::ListEngines => "list-engines",
+        DatabaseAction::ConvertMyIsam => "convert-myisam",
+        DatabaseAction::Size => "size",
+        DatabaseAction::RestrictWrites => "restrict-writes",
+        DatabaseAction::Query => "query",
+    };
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "database",
+        &[
+            ("ACTION", action.to_string()),
+            ("DB_NAME", database.to_string()),
+            ("DB_USER", user.to_string()),
+        ],
+    )?;
+
+    Ok(job)
+}
+
+pub(crate) fn secret_value(secret: &Secret, key: &str) -> Result<String> {
+    Ok(secret
+        .data
+        .as_ref()
+        .and_then(|data| data.get(key))
+        .map(|value| String::from_utf8_lossy(&value.0).into_owned())
+        .with_context(|| format!("secret has no {}", key))?)
+}
+
+pub struct KwpmClient {
+    client: kube::Client,
+    cluster_version: ClusterVersion,
+    pv_base_path: String,
+    namespace_prefix: String,
+    backup_storage: Option<BackupStorage>,
+    policy_endpoint: Option<String>,
+    image_mirror: Option<String>,
+    hooks: BTreeMap<String, Vec<ProvisioningHook>>,
+    preview_domain: Option<String>,
+    prometheus_url: Option<String>,
+    notification_channels: Vec<NotificationChannel>,
+    report_schedules: Vec<ReportSchedule>,
+    master_keys: Option<MasterKeys>,
+    default_metadata: DefaultMetadata,
+    environment_profiles: BTreeMap<Environment, EnvironmentProfile>,
+    default_environment: Option<Environment>,
+    ingress_manager: IngressManager,
+    database_placement: DatabasePlacementPolicy,
+    database_quotas: DatabaseQuotaPolicy,
+    manifest_source: ManifestSource,
+    storage_class: String,
+    dynamic_volumes: bool,
+    node_selector: Option<String>,
+    mariadb_image: Option<String>,
+    mariadb_resources: ResourceLimits,
+    default_locale: Option<String>,
+    default_timezone: Option<String>,
+    read_only: bool,
+}
+
+impl KwpmClient {
+    /// Connects with the inferred kubeconfig or in-cluster config. Credentials the API server
+    /// rejects as expired are renewed, so long-running processes keep working.
+    pub async fn new(pv_base_path: impl ToString) -> Result<Self> {
+        Self::from_client(auth::refreshing_client(None).await?, pv_base_path).await
+    }
+
+    /// Like `new`, but throttles the requests kwpm sends to the API server to `limit`.
+    pub async fn new_rate_limited(
+        pv_base_path: impl ToString,
+        limit: KubeRateLimit,
+    ) -> Result<Self> {
+        Self::from_client(auth::refreshing_client(Some(limit)).await?, pv_base_path).await
+    }
+
+    async fn from_client(client: kube::Client, pv_base_path: impl ToString) -> Result<Self> {
+        let cluster_version = ClusterVersion::from_info(&client.apiserver_version().await?)?;
+        cluster_version.ensure_supported()?;
+
+        Ok(Self {
+            client,
+            cluster_version,
+            pv_base_path: pv_base_path.to_string(),
+            namespace_prefix: site::DEFAULT_NAMESPACE_PREFIX.to_string(),
+            backup_storage: None,
+            policy_endpoint: None,
+            image_mirror: None,
+            hooks: BTreeMap::new(),
+            preview_domain: None,
+            prometheus_url: None,
+            notification_channels: Vec::new(),
+            report_schedules: Vec::new(),
+            master_keys: None,
+            default_metadata: DefaultMetadata::default(),
+            environment_profiles: BTreeMap::new(),
+            default_environment: None,
+            ingress_manager: IngressManager::default(),
+            database_placement: DatabasePlacementPolicy::default(),
+            database_quotas: DatabaseQuotaPolicy::default(),
+            manifest_source: ManifestSource::default(),
+            storage_class: config::DEFAULT_STORAGE_CLASS.to_string(),
+            dynamic_volumes: false,
+            node_selector: None,
+            mariadb_image: None,
+            mariadb_resources: ResourceLimits::default(),
+            default_locale: None,
+            default_timezone: None,
+            read_only: false,
+        })
+    }
+
+    pub fn with_backup_storage(mut self, backup_storage: BackupStorage) -> Self {
+        self.backup_storage = Some(backup_storage);
+        self
+    }
+
+    /// Sends every provisioning operation to an OPA-compatible data API URL such as
+    /// `http://opa:8181/v1/data/kwpm/allow` for approval.
+    pub fn with_policy_endpoint(mut self, url: impl ToString) -> Self {
+        self.policy_endpoint = Some(url.to_string());
+        self
+    }
+
+    /// Pulls every image kwpm deploys from `registry`, e.g. one loaded with `install_bundle`.
+    pub fn with_image_mirror(mut self, registry: impl ToString) -> Self {
+        self.image_mirror = Some(registry.to_string());
+        self
+    }
+
+    /// Reads site metrics such as traffic from the Prometheus at `url` scraping ingress-nginx.
+    pub fn with_prometheus(mut self, url: impl ToString) -> Self {
+        self.prometheus_url = Some(url.to_string());
+        self
+    }
+
+    /// Registers a provisioning hook for sites created from `template`, see
+    /// [`hooks::site_template`]. Hooks run in registration order when sites are created and
+    /// removed, and a failing hook fails the operation.
+    pub fn with_hook(mut self, template: impl ToString, hook: ProvisioningHook) -> Self {
+        self.hooks
+            .entry(template.to_string())
+            .or_default()
+            .push(hook);
+        self
+    }
+
+    pub async fn get_namespaces(&self) -> Result<Vec<Namespace>> {
+        let namespaces: Api<Namespace> = Api::all(self.client.clone());
+        let ns_list = namespaces.list(&Default::default()).await?;
+        Ok(ns_list.items)
+    }
+
+    pub async fn get_kwpm_namespaces(&self) -> Result<Vec<Namespace>> {
+        Ok(self
+            .get_namespaces()
+            .await?
+            .into_iter()
+            .filter(|ns| {
+                let name = ns.metadata.name.as_deref().unwrap_or_default();
+                name.starts_with("kwpm-") || self.site_name(name).is_some()
+            })
+            .collect())
+    }
+
+    pub async fn is_mariadb_created(&self) -> Result<bool> {
+        let kwpm_namespaces = self.get_kwpm_namespaces().await?;
+        Ok(kwpm_namespaces.iter().any(|ns| {
+            ns.metadata
+                .name
+                .as_ref()
+                .unwrap_or(&"".to_string())
+                .ends_with("-mariadb")
+        }))
+    }
+
+    /// Whether the default MariaDB deployment exists and all of its replicas are available.
+    pub async fn is_mariadb_ready(&self) -> Result<bool> {
+        self.is_mariadb_instance_ready(DEFAULT_MARIADB_INSTANCE)
+            .await
+    }
+
+    /// Waits until MariaDB accepts connections, e.g. after `create_mariadb_if_not_exists`
+    /// which returns as soon as the objects are created.
+    pub async fn wait_for_mariadb_ready(&self, timeout: Duration) -> Result<()> {
+        self.wait_for_mariadb_instance_ready(DEFAULT_MARIADB_INSTANCE, timeout)
+            .await
+    }
+
+    /// `create_mariadb_instance` for the default instance.
+    pub async fn create_mariadb_if_not_exists(
+        &self,
+        mysql_root_password: Option<&str>,
+        node_hostname: Option<&str>,
+    ) -> Result<RootPasswordSecret> {
+        self.create_mariadb_instance(DEFAULT_MARIADB_INSTANCE, mysql_root_password, node_hostname)
+            .await
+    }
+
+    /// `remove_mariadb_instance` for the default instance.
+    pub async fn remove_mariadb(&self, force: bool, confirmation: &str) -> Result<()> {
+        self.remove_mariadb_instance(DEFAULT_MARIADB_INSTANCE, force, confirmation)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gethostname::gethostname;
+
+    use super::*;
+
+    async fn client() -> KwpmClient {
+        KwpmClient::new("/data/volumes/kwpm").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_namespaces() {
+        let client = client().await;
+        let namespaces = client.get_namespaces().await.unwrap();
+        assert!(!namespaces.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_kwpm_namespaces() {
+        let client = client().await;
+        let _namespaces = client.get_kwpm_namespaces().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_mariadb() {
+        let client = client().await;
+
+        if client.is_mariadb_created().await.unwrap() {
+            return;
+        }
+
+        let hostname = gethostname();
+
+        let mysql_root_password = "password";
+        client
+            .create_mariadb_if_not_exists(Some(mysql_root_password), hostname.to_str())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_mariadb() {
+        let client = client().await;
+
+        if !client.is_mariadb_created().await.unwrap() {
+            return;
+        }
+
+        let plan = client
+            .plan(&DestructiveOperation::RemoveMariaDb)
+            .await
+            .unwrap();
+        client.remove_mariadb(false, &plan.token).await.unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::Job,
+    core::v1::{
+        Container, Namespace, ObjectReference, PersistentVolume, PersistentVolumeClaim,
+        PersistentVolumeClaimVolumeSource, Volume, VolumeMount,
+    },
+};
+use kube::{api::ObjectMeta, Api};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, Result},
+    local_node_affinity,
+    manifest::{deployment_pod_spec_mut, job_pod_spec_mut, set_env, set_storage_class},
+    KwpmClient,
+};
+
+pub const LIBRARY_NAMESPACE: &str = "kwpm-library";
+const LIBRARY_VOLUME_NAME: &str = "kwpm-library";
+const LIBRARY_MOUNT_PATH: &str = "/var/www/kwpm-library";
+const SITE_LIBRARY_PVC_NAME: &str = "wp-library-claim";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LibraryItemKind {
+    Plugin,
+    Theme,
+}
+
+impl LibraryItemKind {
+    pub fn dir(&self) -> &'static str {
+        match self {
+            LibraryItemKind::Plugin => "plugins",
+            LibraryItemKind::Theme => "themes",
+        }
+    }
+
+    pub fn download_url(&self, slug: &str, version: Option<&str>) -> String {
+        let kind = match self {
+            LibraryItemKind::Plugin => "plugin",
+            LibraryItemKind::Theme => "theme",
+        };
+
+        match version {
+            Some(version) => format!(
+                "https://downloads.wordpress.org/{}/{}.{}.zip",
+                kind, slug, version
+            ),
+            None => format!("https://downloads.wordpress.org/{}/{}.zip", kind, slug),
+        }
+    }
+}
+
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+pub fn library_sync_job(kind: LibraryItemKind, slug: &str, version: Option<&str>) -> Result<Job> {
+    if !is_valid_slug(slug) {
+        bail!("invalid {} slug: {}", kind.dir(), slug);
+    }
+
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/library/library-sync-job.yaml"
+    ))?;
+
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "sync",
+        &[
+            ("KIND", kind.dir().to_string()),
+            ("SLUG", slug.to_string()),
+            ("DOWNLOAD_URL", kind.download_url(slug, version)),
+        ],
+    )?;
+
+    Ok(job)
+}
+
+/// Mounts the shared library into every container of the site's pod and (re)links the given
+/// plugins and themes into wp-content. Anything not listed keeps its per-site copy.
+pub fn attach_library(
+    deployment: &mut Deployment,
+    plugins: &[String],
+    themes: &[String],
+) -> Result<()> {
+    if let Some(slug) = plugins.iter().chain(themes).find(|s| !is_valid_slug(s)) {
+        bail!("invalid library slug: {}", slug);
+    }
+
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+
+    let volumes = pod_spec.volumes.get_or_insert_with(Vec::new);
+    volumes.retain(|v| v.name != LIBRARY_VOLUME_NAME);
+    volumes.push(Volume {
+        name: LIBRARY_VOLUME_NAME.to_string(),
+        persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+            claim_name: SITE_LIBRARY_PVC_NAME.to_string(),
+            read_only: Some(true),
+        }),
+        ..Default::default()
+    });
+
+    let link: Container = serde_yaml::from_str(include_str!(
+        "../../kubernetes/library/library-link-container.yaml"
+    ))?;
+
+    let init_containers = pod_spec.init_containers.get_or_insert_with(Vec::new);
+    init_containers.retain(|c| c.name != "link-library");
+    init_containers.push(link);
+
+    for container in pod_spec
+        .containers
+        .iter_mut()
+        .chain(pod_spec.init_containers.iter_mut().flatten())
+    {
+        let mounts = container.volume_mounts.get_or_insert_with(Vec::new);
+        mounts.retain(|m| m.name != LIBRARY_VOLUME_NAME);
+        mounts.push(VolumeMount {
+            name: LIBRARY_VOLUME_NAME.to_string(),
+            mount_path: LIBRARY_MOUNT_PATH.to_string(),
+            read_only: Some(true),
+            ..Default::default()
+        });
+    }
+
+    set_env(
+        pod_spec,
+        "link-library",
+        &[
+            ("LIBRARY_PLUGINS", plugins.join(" ")),
+            ("LIBRARY_THEMES", themes.join(" ")),
+        ],
+    )
+}
+
+fn ignore_conflict<T>(result: kube::Result<T>) -> Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl KwpmClient {
+    /// Creates the library on a local volume even with dynamic volumes, as sites mount its
+    /// directory on the node read-only.
+    pub async fn create_shared_library(&self, node_hostname: &str) -> Result<()> {
+        let mut namespace = Namespace {
+            metadata: ObjectMeta {
+                name: Some(LIBRARY_NAMESPACE.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        self.cluster_version.adapt_namespace(&mut namespace);
+
+        let mut pv: PersistentVolume =
+            serde_yaml::from_str(include_str!("../../kubernetes/library/library-pv.yaml"))?;
+        if let Some(pv_spec) = pv.spec.as_mut() {
+            if let Some(local) = pv_spec.local.as_mut() {
+                local.path = format!("{}/library", self.pv_base_path);
+            }
+            pv_spec.node_affinity = Some(local_node_affinity(node_hostname));
+        }
+
+        let mut pvc: PersistentVolumeClaim =
+            serde_yaml::from_str(include_str!("../../kubernetes/library/library-pvc.yaml"))?;
+        set_storage_class(&mut pv, &mut pvc, &self.storage_class);
+
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let pvc_api: Api<PersistentVolumeClaim> =
+            Api::namespaced(self.client.clone(), LIBRARY_NAMESPACE);
+
+        ignore_conflict(
+            namespace_api
+                .create(&Default::default(), &self.labeled(&namespace))
+                .await,
+        )?;
+        ignore_conflict(pv_api.create(&Default::default(), &self.labeled(&pv)).await)?;
+        ignore_conflict(
+            pvc_api
+                .create(&Default::default(), &self.labeled(&pvc))
+                .await,
+        )?;
+
+        Ok(())
+    }
+
+    pub async fn sync_library_item(
+        &self,
+        kind: LibraryItemKind,
+        slug: &str,
+        version: Option<&str>,
+    ) -> Result<String> {
+        let job = self
+            .create_job(LIBRARY_NAMESPACE, library_sync_job(kind, slug, version)?)
+            .await?;
+
+        Ok(job.metadata.name.unwrap_or_default())
+    }
+
+    pub async fn enable_shared_library(
+        &self,
+        site: &str,
+        node_hostname: &str,
+        plugins: &[String],
+        themes: &[String],
+    ) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let pv_name = format!("kwpm-library-{}-pv", site);
+
+        let mut pv: PersistentVolume =
+            serde_yaml::from_str(include_str!("../../kubernetes/library/library-pv.yaml"))?;
+        pv.metadata.name = Some(pv_name.clone());
+        if let Some(pv_spec) = pv.spec.as_mut() {
+            pv_spec.access_modes = Some(vec!["ReadOnlyMany".to_string()]);
+            if let Some(local) = pv_spec.local.as_mut() {
+                local.path = format!("{}/library", self.pv_base_path);
+            }
+            pv_spec.node_affinity = Some(local_node_affinity(node_hostname));
+            pv_spec.claim_ref = Some(ObjectReference {
+                namespace: Some(ns_name.clone()),
+                name: Some(SITE_LIBRARY_PVC_NAME.to_string()),
+                ..Default::default()
+            });
+        }
+
+        let mut pvc: PersistentVolumeClaim =
+            serde_yaml::from_str(include_str!("../../kubernetes/library/library-pvc.yaml"))?;
+        pvc.metadata.name = Some(SITE_LIBRARY_PVC_NAME.to_string());
+        if let Some(pvc_spec) = pvc.spec.as_mut() {
+            pvc_spec.volume_name = Some(pv_name);
+            pvc_spec.access_modes = Some(vec!["ReadOnlyMany".to_string()]);
+        }
+        set_storage_class(&mut pv, &mut pvc, &self.storage_class);
+
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let mut deployment = deployment_api.get("wordpress").await?;
+        attach_library(&mut deployment, plugins, themes)?;
+        self.check_policy(
+            "enable_shared_library",
+            Some(site),
+            &[
+                serde_json::to_value(&pv)?,
+                serde_json::to_value(&pvc)?,
+                serde_json::to_value(&deployment)?,
+            ],
+        )
+        .await?;
+
+        ignore_conflict(pv_api.create(&Default::default(), &self.labeled(&pv)).await)?;
+        ignore_conflict(
+            pvc_api
+                .create(&Default::default(), &self.labeled(&pvc))
+                .await,
+        )?;
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> Deployment {
+        serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_download_url() {
+        assert_eq!(
+            LibraryItemKind::Plugin.download_url("akismet", Some("5.3")),
+            "https://downloads.wordpress.org/plugin/akismet.5.3.zip"
+        );
+        assert_eq!(
+            LibraryItemKind::Theme.download_url("twentytwentyfour", None),
+            "https://downloads.wordpress.org/theme/twentytwentyfour.zip"
+        );
+    }
+
+    #[test]
+    fn test_attach_library() {
+        let mut deployment = deployment();
+        let plugins = vec!["akismet".to_string(), "wordpress-seo".to_string()];
+
+        attach_library(&mut deployment, &plugins, &[]).unwrap();
+        attach_library(&mut deployment, &plugins, &[]).unwrap();
+
+        let pod_spec = deployment.spec.unwrap().template.spec.unwrap();
+        let volumes = pod_spec.volumes.unwrap();
+        assert_eq!(
+            volumes
+                .iter()
+                .filter(|v| v.name == LIBRARY_VOLUME_NAME)
+                .count(),
+            1
+        );
+
+        for container in &pod_spec.containers {
+            assert!(container
+                .volume_mounts
+                .as_ref()
+                .unwrap()
+                .iter()
+                .any(|m| m.mount_path == LIBRARY_MOUNT_PATH && m.read_only == Some(true)));
+        }
+
+        let init_containers = pod_spec.init_containers.unwrap();
+        assert_eq!(init_containers.len(), 1);
+        let plugins_env = init_containers[0]
+            .env
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|e| e.name == "LIBRARY_PLUGINS")
+            .and_then(|e| e.value.clone());
+        assert_eq!(plugins_env.as_deref(), Some("akismet wordpress-seo"));
+    }
+
+    #[test]
+    fn test_attach_library_rejects_invalid_slugs() {
+        let mut deployment = deployment();
+        let plugins = vec!["../../wp-config.php".to_string()];
+
+        assert!(attach_library(&mut deployment, &plugins, &[]).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+};
+use kube::{
+    api::{DeleteParams, ObjectMeta, Preconditions},
+    Api,
+};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::Instrument;
+
+use crate::{
+    error::{KwpmError, Result},
+    KwpmClient,
+};
+
+const LOCK_LEASE_NAME: &str = "kwpm-lock";
+const LOCK_OPERATION_ANNOTATION: &str = "kwpm.io/lock-operation";
+/// A holder that stops renewing, e.g. because kwpm crashed, loses the lock after this.
+const LOCK_LEASE_DURATION: Duration = Duration::from_secs(60);
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(20);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returned when another operation holds the lock of a site.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SiteLockConflict {
+    pub site: String,
+    pub holder: String,
+    pub operation: String,
+}
+
+impl fmt::Display for SiteLockConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "conflict: {} is locked by {} ({})",
+            self.site, self.operation, self.holder
+        )
+    }
+}
+
+impl std::error::Error for SiteLockConflict {}
+
+/// Whether nobody holds `lease`, or its holder stopped renewing it.
+pub fn is_lease_free(lease: &Lease, now: DateTime<Utc>) -> bool {
+    let Some(spec) = lease.spec.as_ref() else {
+        return true;
+    };
+    if spec
+        .holder_identity
+        .as_deref()
+        .unwrap_or_default()
+        .is_empty()
+    {
+        return true;
+    }
+
+    let duration = i64::from(spec.lease_duration_seconds.unwrap_or(0));
+    match spec.renew_time.as_ref().or(spec.acquire_time.as_ref()) {
+        Some(MicroTime(renewed)) => (now - *renewed).num_seconds() > duration,
+        None => true,
+    }
+}
+
+fn lock_lease(holder: &str, operation: &str, now: DateTime<Utc>) -> Lease {
+    Lease {
+        metadata: ObjectMeta {
+            name: Some(LOCK_LEASE_NAME.to_string()),
+            annotations: Some(
+                [(LOCK_OPERATION_ANNOTATION.to_string(), operation.to_string())].into(),
+            ),
+            ..Default::default()
+        },
+        spec: Some(LeaseSpec {
+            holder_identity: Some(holder.to_string()),
+            lease_duration_seconds: Some(LOCK_LEASE_DURATION.as_secs() as i32),
+            acquire_time: Some(MicroTime(now)),
+            renew_time: Some(MicroTime(now)),
+            ..Default::default()
+        }),
+    }
+}
+
+fn conflict(site: &str, lease: &Lease) -> SiteLockConflict {
+    SiteLockConflict {
+        site: site.to_string(),
+        holder: lease
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.holder_identity.clone())
+            .unwrap_or_default(),
+        operation: lease
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(LOCK_OPERATION_ANNOTATION).cloned())
+            .unwrap_or_default(),
+    }
+}
+
+fn is_conflict(error: &kube::Error) -> bool {
+    matches!(error, kube::Error::Api(e) if e.code == 409)
+}
+
+fn is_held_by(lease: &Lease, holder: &str) -> bool {
+    lease
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.holder_identity.as_deref())
+        == Some(holder)
+}
+
+/// Deletes the lock Lease if `holder` still holds it.
+async fn delete_lease(lease_api: &Api<Lease>, holder: &str) -> Result<()> {
+    let Some(lease) = lease_api.get_opt(LOCK_LEASE_NAME).await? else {
+        return Ok(());
+    };
+    if !is_held_by(&lease, holder) {
+        return Ok(());
+    }
+    let params = DeleteParams {
+        preconditions: Some(Preconditions {
+            resource_version: lease.metadata.resource_version,
+            uid: None,
+        }),
+        ..Default::default()
+    };
+    match lease_api.delete(LOCK_LEASE_NAME, &params).await {
+        // Gone already, or renewed or taken over since it was read.
+        Err(e) if is_conflict(&e) || matches!(&e, kube::Error::Api(e) if e.code == 404) => Ok(()),
+        result => result.map(|_| ()).map_err(Into::into),
+    }
+}
+
+/// Renews the lock Lease, returning whether `holder` still held it.
+async fn renew_lease(lease_api: &Api<Lease>, holder: &str) -> Result<bool> {
+    let mut lease = lease_api.get(LOCK_LEASE_NAME).await?;
+    if !is_held_by(&lease, holder) {
+        return Ok(false);
+    }
+    if let Some(spec) = lease.spec.as_mut() {
+        spec.renew_time = Some(MicroTime(Utc::now()));
+    }
+    lease_api
+        .replace(LOCK_LEASE_NAME, &Default::default(), &lease)
+        .await?;
+    Ok(true)
+}
+
+/// Holds the lock of a site, renewing its Lease in the background, until it is released.
+/// Dropping it releases the lock in a background task, which is only a fallback: the next
+/// operation on the site may still find it locked.
+pub struct SiteLock {
+    lease_api: Api<Lease>,
+    site: String,
+    operation: String,
+    holder: String,
+    renewal: JoinHandle<()>,
+    lost: watch::Receiver<bool>,
+    released: bool,
+}
+
+impl SiteLock {
+    /// Changes to `true` once the lock is lost, because renewing it failed for longer than
+    /// the lease lasts or another holder took it over.
+    pub fn lost(&self) -> watch::Receiver<bool> {
+        self.lost.clone()
+    }
+
+    /// Fails once the lock is lost, for operations to check before their next step.
+    pub fn ensure_held(&self) -> Result<()> {
+        if *self.lost.borrow() {
+            return Err(KwpmError::Conflict(anyhow::anyhow!(
+                "{} lost the lock of {}",
+                self.operation,
+                self.site
+            )));
+        }
+        Ok(())
+    }
+
+    /// Releases the lock and waits until the Lease is gone, failing if the lock was lost
+    /// while it was held.
+    pub async fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.renewal.abort();
+        delete_lease(&self.lease_api, &self.holder).await?;
+        self.ensure_held()
+    }
+
+    /// Releases the lock after the operation it guarded finished with `result`, which is
+    /// returned unless the operation succeeded but the lock was lost or couldn't be released.
+    pub async fn release_after<T>(self, result: Result<T>) -> Result<T> {
+        let released = self.release().await;
+        let value = result?;
+        released?;
+        Ok(value)
+    }
+}
+
+impl Drop for SiteLock {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        self.renewal.abort();
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let lease_api = self.lease_api.clone();
+        let holder = self.holder.clone();
+        runtime.spawn(async move {
+            let _ = delete_lease(&lease_api, &holder).await;
+        });
+    }
+}
+
+impl KwpmClient {
+    /// Takes the advisory lock of a site for `operation`, failing with a `SiteLockConflict`
+    /// while another operation holds it.
+    #[tracing::instrument(skip_all, fields(site = %site, operation = %operation))]
+    pub async fn lock_site(&self, site: &str, operation: &str) -> Result<SiteLock> {
+        let lease_api: Api<Lease> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        let mut nonce = [0u8; 4];
+        getrandom::getrandom(&mut nonce)?;
+        let holder = format!(
+            "{}-{}-{}",
+            gethostname::gethostname().to_string_lossy(),
+            std::process::id(),
+            nonce
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        );
+
+        let now = Utc::now();
+        let mut lease = lock_lease(&holder, operation, now);
+        let result = match lease_api.get_opt(LOCK_LEASE_NAME).await? {
+            None => {
+                lease_api
+                    .create(&Default::default(), &self.labeled(&lease))
+                    .await
+            }
+            Some(current) if is_lease_free(&current, now) => {
+                lease.metadata.resource_version = current.metadata.resource_version;
+                lease_api
+                    .replace(LOCK_LEASE_NAME, &Default::default(), &self.labeled(&lease))
+                    .await
+            }
+            Some(current) => return Err(conflict(site, &current).into()),
+        };
+        match result {
+            Ok(_) => {}
+            // Someone else took it between reading and writing the lease.
+            Err(e) if is_conflict(&e) => {
+                let current = lease_api.get(LOCK_LEASE_NAME).await?;
+                return Err(conflict(site, &current).into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let (lost_sender, lost) = watch::channel(false);
+        let renewal = tokio::spawn(
+            {
+                let lease_api = lease_api.clone();
+                let holder = holder.clone();
+                async move {
+                    let mut renewed = tokio::time::Instant::now();
+                    loop {
+                        tokio::time::sleep(LOCK_RENEW_INTERVAL).await;
+                        match renew_lease(&lease_api, &holder).await {
+                            Ok(true) => renewed = tokio::time::Instant::now(),
+                            Ok(false) => break,
+                            // Retried until the lease may have expired for the other holders.
+                            Err(e) if renewed.elapsed() < LOCK_LEASE_DURATION => {
+                                tracing::warn!(error = %e, "renewing the site lock failed");
+                            }
+                            Err(e) => {
+                                tracing::warn!(error = %e, "renewing the site lock failed");
+                                break;
+                            }
+                        }
+                    }
+                    tracing::warn!("lost the site lock");
+                    let _ = lost_sender.send(true);
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+
+        Ok(SiteLock {
+            lease_api,
+            site: site.to_string(),
+            operation: operation.to_string(),
+            holder,
+            renewal,
+            lost,
+            released: false,
+        })
+    }
+
+    /// Like `lock_site`, but queues behind the current holder for up to `timeout`.
+    pub async fn wait_for_site_lock(
+        &self,
+        site: &str,
+        operation: &str,
+        timeout: Duration,
+    ) -> Result<SiteLock> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match self.lock_site(site, operation).await {
+                Err(e)
+                    if matches!(e, KwpmError::Conflict(_))
+                        && tokio::time::Instant::now() + LOCK_RETRY_INTERVAL < deadline =>
+                {
+                    tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_is_lease_free() {
+        let acquired = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let lease = lock_lease("kwpm-1", "restore_table", acquired);
+
+        assert!(!is_lease_free(
+            &lease,
+            Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 30).unwrap()
+        ));
+        assert!(is_lease_free(
+            &lease,
+            Utc.with_ymd_and_hms(2024, 3, 1, 12, 1, 1).unwrap()
+        ));
+        assert!(is_lease_free(&Lease::default(), acquired));
+    }
+
+    #[test]
+    fn test_is_held_by() {
+        let lease = lock_lease("kwpm-1", "restore_table", Utc::now());
+
+        assert!(is_held_by(&lease, "kwpm-1"));
+        assert!(!is_held_by(&lease, "kwpm-2"));
+        assert!(!is_held_by(&Lease::default(), "kwpm-1"));
+    }
+
+    #[test]
+    fn test_conflict_names_holder_operation() {
+        let lease = lock_lease("kwpm-1", "restore_table", Utc::now());
+        let error = anyhow::Error::from(conflict("blog", &lease));
+
+        assert!(error.is::<SiteLockConflict>());
+        assert_eq!(
+            error.to_string(),
+            "conflict: blog is locked by restore_table (kwpm-1)"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::error::{bail, KwpmError, Result};
+
+/// How log lines are written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, for terminals.
+    #[default]
+    Pretty,
+    /// One JSON object per line with the span fields, for log collectors in the cluster.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = KwpmError;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("log format must be pretty or json, got {}", format),
+        }
+    }
+}
+
+impl LogFormat {
+    /// `KWPM_LOG_FORMAT`, pretty if unset.
+    pub fn from_env() -> Result<Self> {
+        std::env::var("KWPM_LOG_FORMAT")
+            .ok()
+            .map_or(Ok(LogFormat::Pretty), |format| format.parse())
+    }
+}
+
+/// Installs the global subscriber. `RUST_LOG` selects what is logged, `default_filter` if unset,
+/// e.g. `info` or `kwpm_api=debug`.
+pub fn init_logging(format: LogFormat, default_filter: &str) -> Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match format {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    if let Err(e) = result {
+        bail!("failed to install the log subscriber: {}", e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("pretty".parse::<LogFormat>().unwrap(), LogFormat::Pretty);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
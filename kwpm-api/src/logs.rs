@@ -0,0 +1,211 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{ConfigMap, ConfigMapVolumeSource, Pod, Volume, VolumeMount},
+};
+use kube::{
+    api::{ListParams, LogParams, Patch, PatchParams},
+    Api,
+};
+
+use crate::{error::Result, manifest::deployment_pod_spec_mut, KwpmClient};
+
+const PHP_ERRORS_VOLUME_NAME: &str = "php-errors-ini-conf";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhpError {
+    pub timestamp: DateTime<Utc>,
+    pub level: String,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Parses a timestamped container log line (as returned with `timestamps: true`) into a PHP
+/// error, accepting both plain lines and ones decorated by php-fpm ("child 7 said into stderr").
+pub fn parse_php_error(log_line: &str) -> Option<PhpError> {
+    let (timestamp, rest) = log_line.split_once(' ')?;
+    let timestamp = DateTime::parse_from_rfc3339(timestamp)
+        .ok()?
+        .with_timezone(&Utc);
+
+    let rest = &rest[rest.find("PHP ")? + "PHP ".len()..];
+    let rest = rest.trim_end().trim_end_matches('"');
+    let (level, message) = rest.split_once(':')?;
+    let message = message.trim();
+
+    let (message, file, line) = match message
+        .rsplit_once(" on line ")
+        .and_then(|(head, line)| Some((head.rsplit_once(" in ")?, line.parse().ok()?)))
+    {
+        Some(((message, file), line)) => (message, Some(file.to_string()), Some(line)),
+        None => (message, None, None),
+    };
+
+    Some(PhpError {
+        timestamp,
+        level: level.to_string(),
+        message: message.to_string(),
+        file,
+        line,
+    })
+}
+
+pub fn attach_php_error_log(deployment: &mut Deployment) -> Result<()> {
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+
+    let volumes = pod_spec.volumes.get_or_insert_with(Vec::new);
+    if !volumes.iter().any(|v| v.name == PHP_ERRORS_VOLUME_NAME) {
+        volumes.push(Volume {
+            name: PHP_ERRORS_VOLUME_NAME.to_string(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some("wp-php-errors-ini-config".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
+    let wordpress = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "wordpress")
+        .context("deployment has no wordpress container")?;
+    let mounts = wordpress.volume_mounts.get_or_insert_with(Vec::new);
+    if !mounts.iter().any(|m| m.name == PHP_ERRORS_VOLUME_NAME) {
+        mounts.push(VolumeMount {
+            name: PHP_ERRORS_VOLUME_NAME.to_string(),
+            mount_path: "/usr/local/etc/php/conf.d/errors.ini".to_string(),
+            sub_path: Some("errors.ini".to_string()),
+            read_only: Some(true),
+            ..Default::default()
+        });
+    }
+
+    Ok(())
+}
+
+impl KwpmClient {
+    pub async fn configure_php_error_log(&self, site: &str) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+
+        let config_map: ConfigMap = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-php-errors-ini-config.yaml"
+        ))?;
+
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+
+        config_map_api
+            .patch(
+                "wp-php-errors-ini-config",
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&config_map)),
+            )
+            .await?;
+
+        let mut deployment = deployment_api.get("wordpress").await?;
+        attach_php_error_log(&mut deployment)?;
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_php_errors(&self, site: &str, since: DateTime<Utc>) -> Result<Vec<PhpError>> {
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let pods = pod_api
+            .list(&ListParams::default().labels("app=wordpress"))
+            .await?;
+
+        let mut errors = Vec::new();
+        for pod in pods.items {
+            let Some(pod_name) = pod.metadata.name else {
+                continue;
+            };
+
+            let logs = pod_api
+                .logs(
+                    &pod_name,
+                    &LogParams {
+                        container: Some("wordpress".to_string()),
+                        since_time: Some(since),
+                        timestamps: true,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            errors.extend(self.redact(&logs).lines().filter_map(parse_php_error));
+        }
+
+        errors.sort_by_key(|e| e.timestamp);
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_php_error() {
+        let error = parse_php_error(
+            "2024-01-01T12:00:00.123456789Z PHP Fatal error:  Uncaught Error: Call to undefined function foo() in /var/www/html/wp-content/plugins/x/x.php on line 12",
+        )
+        .unwrap();
+
+        assert_eq!(error.level, "Fatal error");
+        assert_eq!(
+            error.message,
+            "Uncaught Error: Call to undefined function foo()"
+        );
+        assert_eq!(
+            error.file.as_deref(),
+            Some("/var/www/html/wp-content/plugins/x/x.php")
+        );
+        assert_eq!(error.line, Some(12));
+    }
+
+    #[test]
+    fn test_parse_decorated_php_error() {
+        let error = parse_php_error(
+            "2024-01-01T12:00:00Z [01-Jan-2024 12:00:00] WARNING: [pool www] child 7 said into stderr: \"PHP Warning:  Undefined variable $x\"",
+        )
+        .unwrap();
+
+        assert_eq!(error.level, "Warning");
+        assert_eq!(error.message, "Undefined variable $x");
+        assert_eq!(error.file, None);
+    }
+
+    #[test]
+    fn test_parse_ignores_access_logs() {
+        assert_eq!(
+            parse_php_error("2024-01-01T12:00:00Z 10.0.0.1 -  01/Jan/2024:12:00:00 +0000 \"GET /index.php\" 200"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_attach_php_error_log_is_idempotent() {
+        let mut deployment: Deployment = serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap();
+
+        attach_php_error_log(&mut deployment).unwrap();
+
+        let pod_spec = deployment.spec.unwrap().template.spec.unwrap();
+        let volumes = pod_spec.volumes.unwrap();
+        assert_eq!(
+            volumes
+                .iter()
+                .filter(|v| v.name == PHP_ERRORS_VOLUME_NAME)
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{
+    batch::v1::{CronJob, Job},
+    core::v1::{ObjectReference, PodSpec},
+};
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::Result,
+    job::job_finished_at,
+    manifest::{cron_job_pod_spec_mut, job_from_cron_job, set_env},
+    notify::Notification,
+    KwpmClient,
+};
+
+const MALWARE_SCAN_NAME: &str = "kwpm-malware-scan";
+const MALWARE_SCAN_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Set on a scan job once its findings were sent, so repeated alerting doesn't resend them.
+const NOTIFIED_ANNOTATION: &str = "kwpm.io/notified";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MalwareScan {
+    pub schedule: String,
+    /// Moves infected files out of wp-content into a directory only root can read, which
+    /// needs the site volume mounted writable.
+    pub quarantine: bool,
+}
+
+impl Default for MalwareScan {
+    fn default() -> Self {
+        Self {
+            schedule: "0 4 * * *".to_string(),
+            quarantine: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MalwareFinding {
+    pub file: String,
+    pub signature: String,
+    pub quarantined: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MalwareReport {
+    pub job: String,
+    pub scanned_at: Option<DateTime<Utc>>,
+    pub findings: Vec<MalwareFinding>,
+}
+
+/// Parses the `clamscan -i` output of a scan job, e.g.
+/// `/var/www/html/wp-content/x.php: Php.Webshell-1 FOUND` followed by
+/// `/var/www/html/wp-content/x.php: moved to '/var/www/html/.kwpm-quarantine/x.php'` when
+/// quarantining.
+pub fn parse_scan_output(output: &str) -> Vec<MalwareFinding> {
+    let mut findings: Vec<MalwareFinding> = Vec::new();
+
+    for line in output.lines().map(str::trim) {
+        if let Some((file, signature)) = line
+            .strip_suffix(" FOUND")
+            .and_then(|line| line.rsplit_once(": "))
+        {
+            findings.push(MalwareFinding {
+                file: file.to_string(),
+                signature: signature.to_string(),
+                quarantined: false,
+            });
+        } else if let Some((file, _)) = line.split_once(": moved to '") {
+            if let Some(finding) = findings.iter_mut().find(|f| f.file == file) {
+                finding.quarantined = true;
+            }
+        }
+    }
+
+    findings
+}
+
+fn set_quarantine(pod_spec: &mut PodSpec, quarantine: bool) -> Result<()> {
+    set_env(pod_spec, "scan", &[("QUARANTINE", quarantine.to_string())])?;
+
+    let mount = pod_spec
+        .containers
+        .iter_mut()
+        .flat_map(|c| c.volume_mounts.iter_mut().flatten())
+        .find(|m| m.name == "wordpress-persistent-storage")
+        .context("malware scan has no site volume")?;
+    mount.read_only = Some(!quarantine);
+
+    Ok(())
+}
+
+pub fn malware_scan_cron_job(site: &str, scan: &MalwareScan) -> Result<CronJob> {
+    let mut cron_job: CronJob = serde_yaml::from_str(include_str!(
+        "../../kubernetes/security/malware-scan-cronjob.yaml"
+    ))?;
+
+    cron_job
+        .metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+    if let Some(spec) = cron_job.spec.as_mut() {
+        spec.schedule = scan.schedule.clone();
+    }
+    set_quarantine(cron_job_pod_spec_mut(&mut cron_job)?, scan.quarantine)?;
+
+    Ok(cron_job)
+}
+
+impl KwpmClient {
+    /// Installs, updates or (with `None`) removes the scheduled malware scan of a site.
+    pub async fn configure_malware_scan(
+        &self,
+        site: &str,
+        scan: Option<&MalwareScan>,
+    ) -> Result<()> {
+        let cron_job_api: Api<CronJob> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let Some(scan) = scan else {
+            if cron_job_api.get_opt(MALWARE_SCAN_NAME).await?.is_some() {
+                cron_job_api
+                    .delete(MALWARE_SCAN_NAME, &Default::default())
+                    .await?;
+            }
+            self.record_site_revision(site, "configure_malware_scan")
+                .await?;
+            return Ok(());
+        };
+
+        let mut cron_job = malware_scan_cron_job(site, scan)?;
+        self.check_policy(
+            "configure_malware_scan",
+            Some(site),
+            &[serde_json::to_value(&cron_job)?],
+        )
+        .await?;
+        self.prepare_pod_spec(cron_job_pod_spec_mut(&mut cron_job)?);
+        cron_job_api
+            .patch(
+                MALWARE_SCAN_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&cron_job)),
+            )
+            .await?;
+
+        self.record_site_revision(site, "configure_malware_scan")
+            .await?;
+        Ok(())
+    }
+
+    async fn malware_report(&self, namespace: &str, job: &Job) -> Result<MalwareReport> {
+        let name = job.metadata.name.clone().unwrap_or_default();
+        let output = self.job_logs(namespace, &name).await?;
+
+        Ok(MalwareReport {
+            job: name,
+            scanned_at: job_finished_at(job),
+            findings: parse_scan_output(&output),
+        })
+    }
+
+    /// The result of the most recent finished scan, if any.
+    pub async fn get_malware_report(&self, site: &str) -> Result<Option<MalwareReport>> {
+        let ns_name = self.site_namespace(site);
+
+        let selector = format!("app={}", MALWARE_SCAN_NAME);
+        let Some(job) = self.latest_completed_job(&ns_name, &selector).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(self.malware_report(&ns_name, &job).await?))
+    }
+
+    /// Scans wp-content right away and alerts on findings like `alert_on_malware`.
+    pub async fn scan_for_malware_now(
+        &self,
+        site: &str,
+        quarantine: bool,
+    ) -> Result<MalwareReport> {
+        let ns_name = self.site_namespace(site);
+        let scan = MalwareScan {
+            quarantine,
+            ..Default::default()
+        };
+
+        let job = job_from_cron_job(malware_scan_cron_job(site, &scan)?)?;
+        self.check_policy(
+            "scan_for_malware",
+            Some(site),
+            &[serde_json::to_value(&job)?],
+        )
+        .await?;
+        let job = self.create_job(&ns_name, job).await?;
+        let job = self
+            .wait_for_job(
+                &ns_name,
+                &job.metadata.name.unwrap_or_default(),
+                MALWARE_SCAN_TIMEOUT,
+            )
+            .await?;
+
+        let report = self.malware_report(&ns_name, &job).await?;
+        self.alert_on_malware_report(site, &job, &report).await?;
+        Ok(report)
+    }
+
+    /// Raises a `MalwareFound` warning event and notifies the client's channels about the
+    /// findings of the latest scan, once per scan. Meant to be called periodically.
+    pub async fn alert_on_malware(&self, site: &str) -> Result<Option<MalwareReport>> {
+        let ns_name = self.site_namespace(site);
+
+        let selector = format!("app={}", MALWARE_SCAN_NAME);
+        let Some(job) = self.latest_completed_job(&ns_name, &selector).await? else {
+            return Ok(None);
+        };
+        let report = self.malware_report(&ns_name, &job).await?;
+        self.alert_on_malware_report(site, &job, &report).await?;
+
+        Ok(Some(report))
+    }
+
+    async fn alert_on_malware_report(
+        &self,
+        site: &str,
+        job: &Job,
+        report: &MalwareReport,
+    ) -> Result<()> {
+        let already_notified = job
+            .metadata
+            .annotations
+            .as_ref()
+            .is_some_and(|a| a.contains_key(NOTIFIED_ANNOTATION));
+        if report.findings.is_empty() || already_notified {
+            return Ok(());
+        }
+
+        let ns_name = self.site_namespace(site);
+        let lines: Vec<String> = report
+            .findings
+            .iter()
+            .map(|f| {
+                format!(
+                    "{}: {}{}",
+                    f.file,
+                    f.signature,
+                    if f.quarantined { " (quarantined)" } else { "" }
+                )
+            })
+            .collect();
+
+        self.record_warning(
+            ObjectReference {
+                api_version: Some("apps/v1".to_string()),
+                kind: Some("Deployment".to_string()),
+                name: Some("wordpress".to_string()),
+                namespace: Some(ns_name.clone()),
+                ..Default::default()
+            },
+            "MalwareFound",
+            lines.join(", "),
+        )
+        .await?;
+        self.notify(&Notification {
+            subject: format!("Malware found on {}", site),
+            body: lines.join("\n"),
+            data: json!({ "site": site, "findings": report.findings }),
+        })
+        .await?;
+
+        let job_api: Api<Job> = Api::namespaced(self.client.clone(), &ns_name);
+        let patch = json!({
+            "metadata": { "annotations": { NOTIFIED_ANNOTATION: Utc::now().to_rfc3339() } }
+        });
+        job_api
+            .patch(&report.job, &Default::default(), &Patch::Merge(&patch))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scan_output() {
+        let output = "\
+Warning: could not update signatures
+/var/www/html/wp-content/uploads/x.php: Php.Webshell-1 FOUND
+/var/www/html/wp-content/uploads/x.php: moved to '/var/www/html/.kwpm-quarantine/x.php'
+/var/www/html/wp-content/themes/t/functions.php: YARA.obfuscated_eval.UNOFFICIAL FOUND
+";
+
+        assert_eq!(
+            parse_scan_output(output),
+            vec![
+                MalwareFinding {
+                    file: "/var/www/html/wp-content/uploads/x.php".to_string(),
+                    signature: "Php.Webshell-1".to_string(),
+                    quarantined: true,
+                },
+                MalwareFinding {
+                    file: "/var/www/html/wp-content/themes/t/functions.php".to_string(),
+                    signature: "YARA.obfuscated_eval.UNOFFICIAL".to_string(),
+                    quarantined: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quarantine_mounts_site_writable() {
+        let scan = MalwareScan {
+            quarantine: true,
+            ..Default::default()
+        };
+        let mut cron_job = malware_scan_cron_job("blog", &scan).unwrap();
+        let container = &cron_job_pod_spec_mut(&mut cron_job).unwrap().containers[0];
+
+        let mount = &container.volume_mounts.as_ref().unwrap()[0];
+        assert_eq!(mount.read_only, Some(false));
+        let env = container.env.as_ref().unwrap();
+        let quarantine = env.iter().find(|e| e.name == "QUARANTINE").unwrap();
+        assert_eq!(quarantine.value.as_deref(), Some("true"));
+    }
+}
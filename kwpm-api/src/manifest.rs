@@ -0,0 +1,140 @@
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::{CronJob, Job},
+    core::v1::{EnvVar, PersistentVolume, PersistentVolumeClaim, PodSpec},
+};
+use kube::api::ObjectMeta;
+
+use crate::error::{KwpmError, Result};
+
+pub(crate) fn job_pod_spec_mut(job: &mut Job) -> Result<&mut PodSpec> {
+    job.spec
+        .as_mut()
+        .and_then(|spec| spec.template.spec.as_mut())
+        .ok_or_else(|| KwpmError::invalid_manifest("job template has no pod spec"))
+}
+
+pub(crate) fn cron_job_pod_spec_mut(cron_job: &mut CronJob) -> Result<&mut PodSpec> {
+    cron_job
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.job_template.spec.as_mut())
+        .and_then(|spec| spec.template.spec.as_mut())
+        .ok_or_else(|| KwpmError::invalid_manifest("cron job template has no pod spec"))
+}
+
+/// A one-off run of a cron job, like `kubectl create job --from=cronjob/...`.
+pub(crate) fn job_from_cron_job(cron_job: CronJob) -> Result<Job> {
+    let name = cron_job.metadata.name.clone().unwrap_or_default();
+    let job_template = cron_job
+        .spec
+        .map(|spec| spec.job_template)
+        .with_context(|| format!("cron job {} has no spec", name))?;
+
+    Ok(Job {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}-", name)),
+            labels: job_template.metadata.and_then(|m| m.labels),
+            ..Default::default()
+        },
+        spec: job_template.spec,
+        ..Default::default()
+    })
+}
+
+pub(crate) fn deployment_pod_spec_mut(deployment: &mut Deployment) -> Result<&mut PodSpec> {
+    deployment
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.template.spec.as_mut())
+        .ok_or_else(|| KwpmError::invalid_manifest("deployment has no pod spec"))
+}
+
+/// Whether `pv` is a local volume kwpm created, not one a provisioner created for a claim.
+pub(crate) fn is_local_volume(pv: &PersistentVolume) -> bool {
+    pv.spec.as_ref().is_some_and(|spec| spec.local.is_some())
+}
+
+/// Binds a volume and its claim through `storage_class` instead of the one of the templates.
+pub(crate) fn set_storage_class(
+    pv: &mut PersistentVolume,
+    pvc: &mut PersistentVolumeClaim,
+    storage_class: &str,
+) {
+    if let Some(spec) = pv.spec.as_mut() {
+        spec.storage_class_name = Some(storage_class.to_string());
+    }
+    if let Some(spec) = pvc.spec.as_mut() {
+        spec.storage_class_name = Some(storage_class.to_string());
+    }
+}
+
+pub(crate) fn set_env(
+    pod_spec: &mut PodSpec,
+    container: &str,
+    vars: &[(&str, String)],
+) -> Result<()> {
+    let container = pod_spec
+        .containers
+        .iter_mut()
+        .chain(pod_spec.init_containers.iter_mut().flatten())
+        .find(|c| c.name == container)
+        .with_context(|| format!("pod template has no {} container", container))?;
+
+    let env = container.env.get_or_insert_with(Vec::new);
+    for (name, value) in vars {
+        env.retain(|e| e.name != *name);
+        env.push(EnvVar {
+            name: name.to_string(),
+            value: Some(value.clone()),
+            ..Default::default()
+        });
+    }
+
+    Ok(())
+}
+
+/// Parses a Kubernetes resource quantity such as `3Gi`, `500M` or `1.5Ti` into bytes.
+pub(crate) fn parse_quantity(quantity: &str) -> Option<u64> {
+    let quantity = quantity.trim();
+    let split = quantity
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(quantity.len());
+    let (number, suffix) = quantity.split_at(split);
+
+    let multiplier: u64 = match suffix {
+        "" => 1,
+        "k" => 1000,
+        "M" => 1000u64.pow(2),
+        "G" => 1000u64.pow(3),
+        "T" => 1000u64.pow(4),
+        "P" => 1000u64.pow(5),
+        "E" => 1000u64.pow(6),
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        "Pi" => 1 << 50,
+        "Ei" => 1 << 60,
+        _ => return None,
+    };
+
+    let number: f64 = number.parse().ok()?;
+    Some((number * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quantity() {
+        assert_eq!(parse_quantity("20Gi"), Some(20 * 1024 * 1024 * 1024));
+        assert_eq!(parse_quantity("1.5Ki"), Some(1536));
+        assert_eq!(parse_quantity("500M"), Some(500_000_000));
+        assert_eq!(parse_quantity("1024"), Some(1024));
+        assert_eq!(parse_quantity("100m"), None);
+        assert_eq!(parse_quantity("Gi"), None);
+    }
+}
@@ -0,0 +1,579 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{Namespace, PersistentVolume, PersistentVolumeClaim, PodSpec, Secret, Service},
+};
+use kube::{
+    api::{ListParams, ObjectMeta, Patch, PatchParams},
+    runtime::wait::await_condition,
+    Api, ResourceExt,
+};
+use serde::Serialize;
+
+use crate::{
+    config::apply_mariadb_config,
+    confirm::DestructiveOperation,
+    error::{bail, KwpmError, Result},
+    local_node_affinity,
+    manifest::deployment_pod_spec_mut,
+    metadata::ensure_managed,
+    nodes::volume_node,
+    secret_value, site,
+    upgrade::container_image,
+    KwpmClient, COMPONENT_LABEL, MARIADB_DEPLOYMENT_NAME, MARIADB_NAMESPACE, MARIADB_PV_NAME,
+};
+
+/// The instance in `kwpm-mariadb`, used by sites that don't select another one.
+pub const DEFAULT_MARIADB_INSTANCE: &str = "default";
+/// The instance of a MariaDB namespace, and of the database of a site on its namespace.
+pub(crate) const MARIADB_INSTANCE_LABEL: &str = "kwpm.io/mariadb";
+/// Namespaces of instances other than the default one, so site names can't start with
+/// `mariadb-`.
+pub(crate) const MARIADB_INSTANCE_NAMESPACE_PREFIX: &str = "kwpm-mariadb-";
+/// The secret in the namespace of an instance holding its root password.
+const ROOT_PASSWORD_SECRET: &str = "mysql-pass";
+const ROOT_PASSWORD_KEY: &str = "password";
+
+pub fn is_valid_mariadb_instance(instance: &str) -> bool {
+    !instance.is_empty()
+        && instance.len() <= 40
+        && !instance.starts_with('-')
+        && !instance.ends_with('-')
+        && instance
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// `kwpm-mariadb` for the default instance, `kwpm-mariadb-<instance>` otherwise.
+pub fn mariadb_namespace(instance: &str) -> String {
+    if instance == DEFAULT_MARIADB_INSTANCE {
+        MARIADB_NAMESPACE.to_string()
+    } else {
+        format!("{}{}", MARIADB_INSTANCE_NAMESPACE_PREFIX, instance)
+    }
+}
+
+/// Where the root password of a MariaDB instance is stored, so it can be read from the
+/// cluster instead of being passed around.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RootPasswordSecret {
+    pub namespace: String,
+    pub name: String,
+    pub key: String,
+}
+
+impl RootPasswordSecret {
+    pub fn for_instance(instance: &str) -> Self {
+        Self {
+            namespace: mariadb_namespace(instance),
+            name: ROOT_PASSWORD_SECRET.to_string(),
+            key: ROOT_PASSWORD_KEY.to_string(),
+        }
+    }
+}
+
+/// A random root password of 32 bytes, hex encoded.
+fn generate_root_password() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).context("failed to generate a password")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub(crate) fn mariadb_pv_name(instance: &str) -> String {
+    if instance == DEFAULT_MARIADB_INSTANCE {
+        MARIADB_PV_NAME.to_string()
+    } else {
+        format!("{}-pv", mariadb_namespace(instance))
+    }
+}
+
+/// The host sites connect to for databases on `instance`.
+pub fn mariadb_host(instance: &str) -> String {
+    format!("mariadb.{}", mariadb_namespace(instance))
+}
+
+/// The instance a MariaDB or site namespace is labeled with. Namespaces created before
+/// instances existed belong to the default one.
+pub(crate) fn namespace_mariadb_instance(namespace: &Namespace) -> String {
+    namespace
+        .labels()
+        .get(MARIADB_INSTANCE_LABEL)
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_MARIADB_INSTANCE.to_string())
+}
+
+/// Whether `deployment` is the MariaDB of an instance rather than a site's deployment.
+pub(crate) fn is_mariadb_deployment(deployment: &Deployment) -> bool {
+    deployment.name_any() == MARIADB_DEPLOYMENT_NAME
+        && deployment.namespace().is_some_and(|namespace| {
+            namespace == MARIADB_NAMESPACE
+                || namespace.starts_with(MARIADB_INSTANCE_NAMESPACE_PREFIX)
+        })
+}
+
+/// Points the variables of `pod_spec` holding the host of the default instance, as the
+/// templates do, at `instance`.
+pub(crate) fn set_mariadb_host(pod_spec: &mut PodSpec, instance: &str) {
+    replace_mariadb_host(pod_spec, DEFAULT_MARIADB_INSTANCE, instance);
+}
+
+/// Points the variables of `pod_spec` holding the host of instance `from` at `to`, returning
+/// whether there were any.
+pub(crate) fn replace_mariadb_host(pod_spec: &mut PodSpec, from: &str, to: &str) -> bool {
+    if from == to {
+        return false;
+    }
+    let from_host = mariadb_host(from);
+    let mut replaced = false;
+    for var in pod_spec
+        .containers
+        .iter_mut()
+        .chain(pod_spec.init_containers.iter_mut().flatten())
+        .flat_map(|container| container.env.iter_mut().flatten())
+    {
+        if var.value.as_deref() == Some(from_host.as_str()) {
+            var.value = Some(mariadb_host(to));
+            replaced = true;
+        }
+    }
+    replaced
+}
+
+/// The operation confirming the removal of `instance`.
+fn mariadb_removal(instance: &str) -> DestructiveOperation {
+    if instance == DEFAULT_MARIADB_INSTANCE {
+        DestructiveOperation::RemoveMariaDb
+    } else {
+        DestructiveOperation::RemoveMariaDbInstance {
+            instance: instance.to_string(),
+        }
+    }
+}
+
+impl KwpmClient {
+    /// The instances that have been created, the default one included.
+    pub async fn list_mariadb_instances(&self) -> Result<Vec<String>> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let mut instances: Vec<String> = namespace_api
+            .list(&ListParams::default().labels(&format!("{}=mariadb", COMPONENT_LABEL)))
+            .await?
+            .iter()
+            .map(namespace_mariadb_instance)
+            .collect();
+        instances.sort();
+
+        Ok(instances)
+    }
+
+    /// The instance holding the database of `site`.
+    pub async fn site_mariadb_instance(&self, site: &str) -> Result<String> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = namespace_api
+            .get_opt(&self.site_namespace(site))
+            .await?
+            .ok_or_else(|| KwpmError::not_found(format!("site {} does not exist", site)))?;
+
+        Ok(namespace_mariadb_instance(&namespace))
+    }
+
+    /// `set_mariadb_host` for a pod running in `namespace`, if it is a site namespace.
+    pub(crate) async fn adapt_mariadb_host(
+        &self,
+        namespace: &str,
+        pod_spec: &mut PodSpec,
+    ) -> Result<()> {
+        let Some(site) = self.site_name(namespace) else {
+            return Ok(());
+        };
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        if let Some(namespace) = namespace_api.get_opt(namespace).await? {
+            set_mariadb_host(pod_spec, &namespace_mariadb_instance(&namespace));
+        } else {
+            set_mariadb_host(pod_spec, &self.site_mariadb_instance(site).await?);
+        }
+
+        Ok(())
+    }
+
+    /// The local volume of `instance`, which site volumes are pinned to the node of. With
+    /// dynamic volumes there is none.
+    pub(crate) async fn mariadb_volume(&self, instance: &str) -> Result<Option<PersistentVolume>> {
+        if self.dynamic_volumes {
+            return Ok(None);
+        }
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        Ok(Some(
+            pv_api
+                .get_opt(&mariadb_pv_name(instance))
+                .await?
+                .with_context(|| format!("MariaDB {} has not been created", instance))?,
+        ))
+    }
+
+    /// Whether the deployment of `instance` exists and all of its replicas are available.
+    pub async fn is_mariadb_instance_ready(&self, instance: &str) -> Result<bool> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace(instance));
+        Ok(deployment_api
+            .get_opt(MARIADB_DEPLOYMENT_NAME)
+            .await?
+            .is_some_and(|deployment| site::is_deployment_available(&deployment)))
+    }
+
+    /// Waits until `instance` accepts connections.
+    pub async fn wait_for_mariadb_instance_ready(
+        &self,
+        instance: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace(instance));
+
+        tokio::time::timeout(
+            timeout,
+            await_condition(
+                deployment_api,
+                MARIADB_DEPLOYMENT_NAME,
+                |deployment: Option<&Deployment>| {
+                    deployment.is_some_and(site::is_deployment_available)
+                },
+            ),
+        )
+        .await
+        .with_context(|| format!("timed out waiting for MariaDB {} to become ready", instance))??;
+
+        Ok(())
+    }
+
+    /// Creates MariaDB `instance` with its data on `node_hostname`, or on the node
+    /// `pick_placement_node` picks if unset, or completes and updates its objects if it exists,
+    /// so running it again after a partial failure converges. The node is ignored with dynamic
+    /// volumes.
+    ///
+    /// Without `mysql_root_password` the root password is read from the secret of an existing
+    /// instance, or generated, and only stored in that secret. It can't be changed this way
+    /// since MariaDB only reads it when initializing its data directory. Returns where the
+    /// password is stored.
+    pub async fn create_mariadb_instance(
+        &self,
+        instance: &str,
+        mysql_root_password: Option<&str>,
+        node_hostname: Option<&str>,
+    ) -> Result<RootPasswordSecret> {
+        if !is_valid_mariadb_instance(instance) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid MariaDB instance: {}",
+                instance
+            )));
+        }
+        let ns_name = mariadb_namespace(instance);
+        let pv_name = mariadb_pv_name(instance);
+        let identity = Some((COMPONENT_LABEL, "mariadb"));
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        let mut existing_password = None;
+        if let Some(existing) = namespace_api.get_opt(&ns_name).await? {
+            ensure_managed(&existing, identity)?;
+            if let Some(secret) = secret_api.get_opt(ROOT_PASSWORD_SECRET).await? {
+                existing_password = Some(secret_value(&secret, ROOT_PASSWORD_KEY)?);
+            }
+        }
+        let root_password = match (mysql_root_password, existing_password) {
+            (Some(given), Some(existing)) if given != existing => {
+                return Err(KwpmError::invalid_input(format!(
+                    "MariaDB {} exists with another root password",
+                    instance
+                )));
+            }
+            (Some(given), _) => given.to_string(),
+            (None, Some(existing)) => existing,
+            (None, None) => generate_root_password()?,
+        };
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let existing_node = match pv_api.get_opt(&pv_name).await? {
+            Some(existing) => {
+                ensure_managed(&existing, identity)?;
+                volume_node(&existing)
+            }
+            None => None,
+        };
+        let node = match (node_hostname, existing_node) {
+            _ if self.dynamic_volumes => None,
+            (Some(node), Some(existing)) if node != existing => {
+                return Err(KwpmError::invalid_input(format!(
+                    "the data of MariaDB {} is on {}, not {}",
+                    instance, existing, node
+                )));
+            }
+            (Some(node), _) => Some(node.to_string()),
+            (None, Some(existing)) => Some(existing),
+            (None, None) => Some(self.pick_placement_node().await?),
+        };
+
+        let mut namespace: Namespace = Namespace {
+            metadata: ObjectMeta {
+                name: Some(ns_name.clone()),
+                labels: Some(BTreeMap::from([
+                    (COMPONENT_LABEL.to_string(), "mariadb".to_string()),
+                    (MARIADB_INSTANCE_LABEL.to_string(), instance.to_string()),
+                ])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut deployment: Deployment = self
+            .load_manifest("mariadb/mariadb-deployment.yaml")
+            .await?;
+        self.place_by_architecture(deployment_pod_spec_mut(&mut deployment)?)
+            .await?;
+        self.prepare_pod_spec(deployment_pod_spec_mut(&mut deployment)?);
+        // An image set by `upgrade_mariadb` stays unless one is configured.
+        let existing_image = match &self.mariadb_image {
+            Some(_) => None,
+            None => Api::<Deployment>::namespaced(self.client.clone(), &ns_name)
+                .get_opt(MARIADB_DEPLOYMENT_NAME)
+                .await?
+                .and_then(|existing| container_image(&existing, "mysql").map(str::to_string)),
+        };
+        apply_mariadb_config(
+            &mut deployment,
+            self.mariadb_image.as_deref().or(existing_image.as_deref()),
+            &self.mariadb_resources,
+        )?;
+        self.cluster_version.adapt_namespace(&mut namespace);
+        let mut pv: PersistentVolume = self.load_manifest("mariadb/mariadb-pv.yaml").await?;
+        pv.metadata.name = Some(pv_name.clone());
+
+        if let Some(pv_spec) = pv.spec.as_mut() {
+            if let Some(local) = pv_spec.local.as_mut() {
+                local.path = if instance == DEFAULT_MARIADB_INSTANCE {
+                    format!("{}/mariadb", self.pv_base_path)
+                } else {
+                    format!("{}/mariadb-{}", self.pv_base_path, instance)
+                };
+            }
+
+            pv_spec.node_affinity = node.as_deref().map(local_node_affinity);
+        }
+        pv.metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(COMPONENT_LABEL.to_string(), "mariadb".to_string());
+
+        let mut pvc: PersistentVolumeClaim = self.load_manifest("mariadb/mariadb-pvc.yaml").await?;
+        if let Some(pvc_spec) = pvc.spec.as_mut() {
+            pvc_spec.volume_name = Some(pv_name.clone());
+        }
+        self.adapt_volume(&mut pv, &mut pvc);
+        let svc: Service = self.load_manifest("mariadb/mariadb-svc.yaml").await?;
+
+        let secret = Secret {
+            metadata: ObjectMeta {
+                name: Some(ROOT_PASSWORD_SECRET.to_string()),
+                ..Default::default()
+            },
+            string_data: Some(BTreeMap::from([(
+                ROOT_PASSWORD_KEY.to_string(),
+                root_password,
+            )])),
+            ..Default::default()
+        };
+
+        let mut objects = vec![
+            serde_json::to_value(&namespace)?,
+            serde_json::to_value(&deployment)?,
+            serde_json::to_value(&pvc)?,
+            serde_json::to_value(&svc)?,
+        ];
+        if !self.dynamic_volumes {
+            objects.push(serde_json::to_value(&pv)?);
+        }
+        self.check_policy("create_mariadb", None, &objects).await?;
+
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), &ns_name);
+        let svc_api: Api<Service> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let params = PatchParams::apply("kwpm").force();
+        namespace_api
+            .patch(&ns_name, &params, &Patch::Apply(&self.labeled(&namespace)))
+            .await?;
+        if !self.dynamic_volumes {
+            pv_api
+                .patch(&pv_name, &params, &Patch::Apply(&self.labeled(&pv)))
+                .await?;
+        }
+        pvc_api
+            .patch(&pvc.name_any(), &params, &Patch::Apply(&self.labeled(&pvc)))
+            .await?;
+        svc_api
+            .patch(&svc.name_any(), &params, &Patch::Apply(&self.labeled(&svc)))
+            .await?;
+        secret_api
+            .patch(
+                ROOT_PASSWORD_SECRET,
+                &params,
+                &Patch::Apply(&self.labeled(&secret)),
+            )
+            .await?;
+        deployment_api
+            .patch(
+                MARIADB_DEPLOYMENT_NAME,
+                &params,
+                &Patch::Apply(&self.labeled(&deployment)),
+            )
+            .await?;
+
+        Ok(RootPasswordSecret::for_instance(instance))
+    }
+
+    /// The root password of MariaDB `instance`, read from its secret.
+    pub async fn mariadb_root_password(&self, instance: &str) -> Result<String> {
+        let secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace(instance));
+        let secret = secret_api
+            .get_opt(ROOT_PASSWORD_SECRET)
+            .await?
+            .ok_or_else(|| {
+                KwpmError::not_found(format!("MariaDB {} has no root password secret", instance))
+            })?;
+        secret_value(&secret, ROOT_PASSWORD_KEY)
+    }
+
+    /// Removes MariaDB `instance` and waits for its namespace to be gone. Fails while sites
+    /// still have their database on it. If the namespace gets stuck terminating, `force`
+    /// removes the finalizers blocking it, see `delete_namespace`. `confirmation` is the token
+    /// of planning [`DestructiveOperation::RemoveMariaDbInstance`], or
+    /// [`DestructiveOperation::RemoveMariaDb`] for the default instance.
+    pub async fn remove_mariadb_instance(
+        &self,
+        instance: &str,
+        force: bool,
+        confirmation: &str,
+    ) -> Result<()> {
+        self.confirm(&mariadb_removal(instance), confirmation)
+            .await?;
+        let pv_name = mariadb_pv_name(instance);
+        let ns_name = mariadb_namespace(instance);
+        let identity = Some((COMPONENT_LABEL, "mariadb"));
+
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let sites: Vec<String> = namespace_api
+            .list(
+                &ListParams::default().labels(&format!("{}={}", MARIADB_INSTANCE_LABEL, instance)),
+            )
+            .await?
+            .iter()
+            .filter_map(|namespace| self.site_name(&namespace.name_any()).map(str::to_string))
+            .collect();
+        if !sites.is_empty() {
+            bail!(
+                "MariaDB {} still has the databases of {}",
+                instance,
+                sites.join(", ")
+            );
+        }
+        if let Some(namespace) = namespace_api.get_opt(&ns_name).await? {
+            ensure_managed(&namespace, identity)?;
+        }
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let pv = pv_api.get_opt(&pv_name).await?;
+        if let Some(pv) = &pv {
+            ensure_managed(pv, identity)?;
+        }
+
+        self.delete_namespace(&ns_name, force).await?;
+        if pv.is_some() {
+            pv_api.delete(&pv_name, &Default::default()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{Container, EnvVar};
+
+    use super::*;
+
+    #[test]
+    fn test_mariadb_instance_names() {
+        assert_eq!(mariadb_namespace(DEFAULT_MARIADB_INSTANCE), "kwpm-mariadb");
+        assert_eq!(mariadb_pv_name(DEFAULT_MARIADB_INSTANCE), "kwpm-mariadb-pv");
+        assert_eq!(mariadb_namespace("acme"), "kwpm-mariadb-acme");
+        assert_eq!(mariadb_pv_name("acme"), "kwpm-mariadb-acme-pv");
+        assert_eq!(mariadb_host("acme"), "mariadb.kwpm-mariadb-acme");
+        assert!(mariadb_namespace("acme").starts_with(MARIADB_INSTANCE_NAMESPACE_PREFIX));
+        assert!(is_valid_mariadb_instance("tenant-1"));
+        assert!(!is_valid_mariadb_instance("Tenant"));
+        assert!(!is_valid_mariadb_instance("-a"));
+        assert!(!is_valid_mariadb_instance(""));
+    }
+
+    #[test]
+    fn test_root_password() {
+        let password = generate_root_password().unwrap();
+        assert_eq!(password.len(), 64);
+        assert!(password.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(password, generate_root_password().unwrap());
+
+        assert_eq!(
+            RootPasswordSecret::for_instance("acme"),
+            RootPasswordSecret {
+                namespace: "kwpm-mariadb-acme".to_string(),
+                name: "mysql-pass".to_string(),
+                key: "password".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_set_mariadb_host() {
+        let env = |name: &str, value: &str| EnvVar {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+            ..Default::default()
+        };
+        let mut pod_spec = PodSpec {
+            containers: vec![Container {
+                name: "wp-cli".to_string(),
+                env: Some(vec![
+                    env("WORDPRESS_DB_HOST", "mariadb.kwpm-mariadb"),
+                    env("WORDPRESS_DB_NAME", "wp_blog"),
+                ]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        set_mariadb_host(&mut pod_spec, DEFAULT_MARIADB_INSTANCE);
+        assert_eq!(
+            pod_spec.containers[0].env.as_ref().unwrap()[0]
+                .value
+                .as_deref(),
+            Some("mariadb.kwpm-mariadb")
+        );
+        set_mariadb_host(&mut pod_spec, "acme");
+        let env = pod_spec.containers[0].env.as_ref().unwrap();
+        assert_eq!(env[0].value.as_deref(), Some("mariadb.kwpm-mariadb-acme"));
+        assert_eq!(env[1].value.as_deref(), Some("wp_blog"));
+
+        assert!(!replace_mariadb_host(
+            &mut pod_spec,
+            DEFAULT_MARIADB_INSTANCE,
+            "beta"
+        ));
+        assert!(replace_mariadb_host(&mut pod_spec, "acme", "beta"));
+        assert_eq!(
+            pod_spec.containers[0].env.as_ref().unwrap()[0]
+                .value
+                .as_deref(),
+            Some("mariadb.kwpm-mariadb-beta")
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::Pod};
+use kube::{
+    api::{ListParams, PostParams},
+    Api,
+};
+use serde::Serialize;
+
+use crate::{
+    error::{bail, KwpmError, Result},
+    manifest::deployment_pod_spec_mut,
+    mariadb::{is_valid_mariadb_instance, mariadb_namespace},
+    restart::is_rollout_complete,
+    upgrade::{container_image, failed_rollout_reason, image_with_tag, is_valid_image_tag},
+    KwpmClient, MARIADB_DEPLOYMENT_NAME,
+};
+
+const MARIADB_SELECTOR: &str = "app=mariadb,tier=mysql";
+const DUMP_TIMEOUT: Duration = Duration::from_secs(1800);
+const ROLLOUT_TIMEOUT: Duration = Duration::from_secs(600);
+const ROLLOUT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MARIADB_UPGRADE_TIMEOUT: Duration = Duration::from_secs(1800);
+/// On the data volume, so the dump outlives the pods and a failed restore can be retried by
+/// hand. Files in the data directory that aren't directories aren't taken for databases.
+const DUMP_DIR: &str = "/var/lib/mysql";
+/// The client tools lost their `mysql` names in MariaDB 11, which older images lack the
+/// `mariadb` names of. All of them run as root over the socket, `$1` is the dump.
+const DUMP_SCRIPT: &str = r#"set -o pipefail
+dump=$(command -v mariadb-dump || command -v mysqldump)
+"$dump" -uroot -p"$MYSQL_ROOT_PASSWORD" --all-databases --single-transaction --routines --events --triggers | gzip > "$1.tmp"
+mv "$1.tmp" "$1""#;
+const UPGRADE_SCRIPT: &str = r#"upgrade=$(command -v mariadb-upgrade || command -v mysql_upgrade)
+"$upgrade" -uroot -p"$MYSQL_ROOT_PASSWORD""#;
+const RESTORE_SCRIPT: &str = r#"set -o pipefail
+client=$(command -v mariadb || command -v mysql)
+gunzip -c "$1" | "$client" -uroot -p"$MYSQL_ROOT_PASSWORD""#;
+
+/// The major and minor version of a MariaDB image tag like `11.4`, `10.11.7-jammy` or
+/// `mariadb:11.4`, `None` for tags like `lts`.
+pub fn mariadb_version(tag: &str) -> Option<(u32, u32)> {
+    let tag = tag.rsplit_once(':').map_or(tag, |(_, tag)| tag);
+    let mut parts = tag.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// The image `tag` selects for a MariaDB running `current`. MariaDB can't read the data of a
+/// newer version, so downgrades are refused when both versions are known.
+pub fn mariadb_upgrade_image(current: &str, tag: &str) -> Result<String> {
+    if !is_valid_image_tag(tag) {
+        return Err(KwpmError::invalid_input(format!(
+            "invalid image tag: {}",
+            tag
+        )));
+    }
+    let image = image_with_tag(current, tag);
+    if image == current {
+        bail!("MariaDB runs {} already", image);
+    }
+    if let (Some(from), Some(to)) = (mariadb_version(current), mariadb_version(tag)) {
+        if to < from {
+            return Err(KwpmError::invalid_input(format!(
+                "{} is older than {}, MariaDB can't be downgraded",
+                image, current
+            )));
+        }
+    }
+    Ok(image)
+}
+
+pub fn upgrade_dump_path(taken_at: DateTime<Utc>) -> String {
+    format!(
+        "{}/kwpm-upgrade-{}.sql.gz",
+        DUMP_DIR,
+        taken_at.format("%Y%m%d-%H%M%S")
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MariaDbUpgrade {
+    pub instance: String,
+    pub previous_image: String,
+    pub image: String,
+    /// The dump of all databases taken before, in the data directory of the instance.
+    pub dump: String,
+    /// What `mariadb-upgrade` printed.
+    pub output: String,
+}
+
+fn set_mysql_image(deployment: &mut Deployment, image: &str) -> Result<()> {
+    deployment_pod_spec_mut(deployment)?
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "mysql")
+        .context("deployment has no mysql container")?
+        .image = Some(image.to_string());
+    Ok(())
+}
+
+impl KwpmClient {
+    /// Replaces the image of the instance's deployment and waits until it rolled out, or
+    /// returns why its pods running `image` fail.
+    async fn roll_out_mariadb_image(&self, instance: &str, image: &str) -> Result<Option<String>> {
+        let ns_name = mariadb_namespace(instance);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let mut deployment = deployment_api.get(MARIADB_DEPLOYMENT_NAME).await?;
+        set_mysql_image(&mut deployment, image)?;
+        let deployment = deployment_api
+            .replace(MARIADB_DEPLOYMENT_NAME, &PostParams::default(), &deployment)
+            .await?;
+        let generation = deployment.metadata.generation.unwrap_or(0);
+        let deadline = tokio::time::Instant::now() + ROLLOUT_TIMEOUT;
+
+        loop {
+            if is_rollout_complete(
+                &deployment_api.get(MARIADB_DEPLOYMENT_NAME).await?,
+                generation,
+            ) {
+                return Ok(None);
+            }
+            let pods = pod_api
+                .list(&ListParams::default().labels(MARIADB_SELECTOR))
+                .await?;
+            if let Some(reason) = failed_rollout_reason(&pods.items, "mysql", image) {
+                return Ok(Some(reason));
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Ok(Some(format!(
+                    "not ready within {} seconds",
+                    ROLLOUT_TIMEOUT.as_secs()
+                )));
+            }
+            tokio::time::sleep(ROLLOUT_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn exec_in_mariadb(
+        &self,
+        instance: &str,
+        script: &str,
+        dump: &str,
+        timeout: Duration,
+    ) -> Result<String> {
+        let output = self
+            .exec_in_pods(
+                &mariadb_namespace(instance),
+                MARIADB_SELECTOR,
+                "mysql",
+                &["bash", "-c", script, "bash", dump],
+                timeout,
+            )
+            .await?;
+        Ok(output.stdout.trim().to_string())
+    }
+
+    /// Goes back to `previous_image` and loads `dump` into it after a failed upgrade.
+    async fn restore_mariadb_dump(
+        &self,
+        instance: &str,
+        previous_image: &str,
+        dump: &str,
+    ) -> Result<()> {
+        if let Some(reason) = self
+            .roll_out_mariadb_image(instance, previous_image)
+            .await?
+        {
+            bail!("{} doesn't start again: {}", previous_image, reason);
+        }
+        self.exec_in_mariadb(instance, RESTORE_SCRIPT, dump, DUMP_TIMEOUT)
+            .await?;
+        Ok(())
+    }
+
+    /// Dumps all databases of `instance`, moves it to the MariaDB image tagged `tag` and runs
+    /// `mariadb-upgrade`. If the new version doesn't become ready or the upgrade fails, the
+    /// previous image is rolled out again and the dump loaded into it. Sites lose their
+    /// database while the deployment is recreated.
+    #[tracing::instrument(skip_all, fields(instance = %instance, tag = %tag))]
+    pub async fn upgrade_mariadb(&self, instance: &str, tag: &str) -> Result<MariaDbUpgrade> {
+        if !is_valid_mariadb_instance(instance) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid MariaDB instance: {}",
+                instance
+            )));
+        }
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace(instance));
+        let mut deployment = deployment_api
+            .get_opt(MARIADB_DEPLOYMENT_NAME)
+            .await?
+            .ok_or_else(|| {
+                KwpmError::not_found(format!("MariaDB {} has not been created", instance))
+            })?;
+        let previous_image = container_image(&deployment, "mysql")
+            .context("deployment has no mysql image")?
+            .to_string();
+        let image = mariadb_upgrade_image(&previous_image, tag)?;
+        if !self.is_mariadb_instance_ready(instance).await? {
+            bail!("MariaDB {} is not ready, not upgrading it", instance);
+        }
+        set_mysql_image(&mut deployment, &image)?;
+        self.check_policy(
+            "upgrade_mariadb",
+            None,
+            &[serde_json::to_value(&deployment)?],
+        )
+        .await?;
+
+        let dump = upgrade_dump_path(Utc::now());
+        tracing::info!(dump = %dump, "dumping all databases");
+        self.exec_in_mariadb(instance, DUMP_SCRIPT, &dump, DUMP_TIMEOUT)
+            .await
+            .context("the dump failed, MariaDB was not touched")?;
+
+        tracing::info!(image = %image, "rolling out");
+        let upgraded = match self.roll_out_mariadb_image(instance, &image).await {
+            Ok(None) => self
+                .exec_in_mariadb(instance, UPGRADE_SCRIPT, &dump, MARIADB_UPGRADE_TIMEOUT)
+                .await
+                .map_err(|e| format!("{:#}", e)),
+            Ok(Some(reason)) => Err(reason),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        let output = match upgraded {
+            Ok(output) => output,
+            Err(reason) => {
+                tracing::warn!(reason = %reason, "upgrade failed, restoring the dump");
+                if let Err(e) = self
+                    .restore_mariadb_dump(instance, &previous_image, &dump)
+                    .await
+                {
+                    bail!(
+                        "upgrading MariaDB {} to {} failed: {}; restoring {} failed too, restore it by hand: {:#}",
+                        instance,
+                        image,
+                        reason,
+                        dump,
+                        e
+                    );
+                }
+                bail!(
+                    "upgrading MariaDB {} to {} failed, {} was restored from {}: {}",
+                    instance,
+                    image,
+                    previous_image,
+                    dump,
+                    reason
+                );
+            }
+        };
+        tracing::info!(previous_image = %previous_image, image = %image, "upgraded");
+
+        Ok(MariaDbUpgrade {
+            instance: instance.to_string(),
+            previous_image,
+            image,
+            dump,
+            output,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_mariadb_version() {
+        assert_eq!(mariadb_version("11.4"), Some((11, 4)));
+        assert_eq!(mariadb_version("mariadb:10.11.7-jammy"), Some((10, 11)));
+        assert_eq!(mariadb_version("lts"), None);
+        assert_eq!(mariadb_version("11"), None);
+    }
+
+    #[test]
+    fn test_mariadb_upgrade_image() {
+        assert_eq!(
+            mariadb_upgrade_image("mariadb:10.11", "11.4").unwrap(),
+            "mariadb:11.4"
+        );
+        assert_eq!(
+            mariadb_upgrade_image("mariadb:10.11", "lts").unwrap(),
+            "mariadb:lts"
+        );
+        assert!(mariadb_upgrade_image("mariadb:10.11", "10.11").is_err());
+        assert!(mariadb_upgrade_image("mariadb:10.11", "10.6").is_err());
+        assert!(mariadb_upgrade_image("mariadb:10.11", "11 4").is_err());
+    }
+
+    #[test]
+    fn test_upgrade_dump_path() {
+        let taken_at = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            upgrade_dump_path(taken_at),
+            "/var/lib/mysql/kwpm-upgrade-20240102-030405.sql.gz"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use k8s_openapi::api::batch::v1::{CronJob, CronJobSpec, Job, JobTemplateSpec};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api,
+};
+
+use crate::{
+    error::{bail, Result},
+    job::is_job_succeeded,
+    manifest::{job_pod_spec_mut, set_env},
+    KwpmClient,
+};
+
+const OPTIMIZE_IMAGES_NAME: &str = "kwpm-optimize-images";
+const OPTIMIZE_IMAGES_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageSavings {
+    pub file: String,
+    pub original_bytes: u64,
+    pub optimized_bytes: u64,
+    pub webp_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageOptimizationReport {
+    pub dry_run: bool,
+    pub images: Vec<ImageSavings>,
+}
+
+impl ImageOptimizationReport {
+    pub fn parse(output: &str, dry_run: bool) -> Self {
+        let images = output
+            .lines()
+            .filter_map(|line| {
+                let line = line.strip_prefix("file=")?;
+                let (file, sizes) = line.rsplit_once(" original=")?;
+                let (original, sizes) = sizes.split_once(" optimized=")?;
+                let (optimized, webp) = sizes.split_once(" webp=")?;
+
+                Some(ImageSavings {
+                    file: file.to_string(),
+                    original_bytes: original.parse().ok()?,
+                    optimized_bytes: optimized.parse().ok()?,
+                    webp_bytes: webp.trim().parse().ok()?,
+                })
+            })
+            .collect();
+
+        Self { dry_run, images }
+    }
+
+    pub fn original_bytes(&self) -> u64 {
+        self.images.iter().map(|i| i.original_bytes).sum()
+    }
+
+    /// Bytes saved by recompressing originals in place (never negative per image, since larger
+    /// results are discarded).
+    pub fn compression_savings(&self) -> u64 {
+        self.images
+            .iter()
+            .map(|i| i.original_bytes.saturating_sub(i.optimized_bytes))
+            .sum()
+    }
+
+    /// Bytes saved per request for clients that are served the WebP variant instead.
+    pub fn webp_savings(&self) -> u64 {
+        self.images
+            .iter()
+            .map(|i| i.original_bytes.saturating_sub(i.webp_bytes))
+            .sum()
+    }
+}
+
+pub fn optimize_images_job(site: &str, dry_run: bool) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/media/optimize-images-job.yaml"
+    ))?;
+
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "optimize",
+        &[("DRY_RUN", dry_run.to_string())],
+    )?;
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    /// Optimizes images uploaded since the last non-dry run. With `dry_run` nothing is written and
+    /// the report only estimates the potential savings.
+    pub async fn optimize_images(
+        &self,
+        site: &str,
+        dry_run: bool,
+    ) -> Result<ImageOptimizationReport> {
+        let ns_name = self.site_namespace(site);
+
+        let job = self
+            .create_job(&ns_name, optimize_images_job(site, dry_run)?)
+            .await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+
+        let job = self
+            .wait_for_job(&ns_name, &job_name, OPTIMIZE_IMAGES_TIMEOUT)
+            .await?;
+        let output = self.job_logs(&ns_name, &job_name).await?;
+        if !is_job_succeeded(&job) {
+            bail!("image optimization failed on {}: {}", site, output.trim());
+        }
+
+        Ok(ImageOptimizationReport::parse(&output, dry_run))
+    }
+
+    /// Schedules (or with `None` removes) a recurring optimization of newly uploaded images.
+    pub async fn schedule_image_optimization(
+        &self,
+        site: &str,
+        schedule: Option<&str>,
+    ) -> Result<()> {
+        let cron_job_api: Api<CronJob> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let Some(schedule) = schedule else {
+            if cron_job_api.get_opt(OPTIMIZE_IMAGES_NAME).await?.is_some() {
+                cron_job_api
+                    .delete(OPTIMIZE_IMAGES_NAME, &Default::default())
+                    .await?;
+            }
+            return Ok(());
+        };
+
+        let mut job = optimize_images_job(site, false)?;
+        self.prepare_pod_spec(job_pod_spec_mut(&mut job)?);
+        let cron_job = CronJob {
+            metadata: ObjectMeta {
+                name: Some(OPTIMIZE_IMAGES_NAME.to_string()),
+                labels: job.metadata.labels.clone(),
+                ..Default::default()
+            },
+            spec: Some(CronJobSpec {
+                schedule: schedule.to_string(),
+                concurrency_policy: Some("Forbid".to_string()),
+                job_template: JobTemplateSpec {
+                    metadata: Some(ObjectMeta {
+                        labels: job.metadata.labels,
+                        ..Default::default()
+                    }),
+                    spec: job.spec,
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        cron_job_api
+            .patch(
+                OPTIMIZE_IMAGES_NAME,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&cron_job)),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_report() {
+        let report = ImageOptimizationReport::parse(
+            "OK: installing\nfile=wp-content/uploads/2024/01/my photo.jpg original=1000 optimized=800 webp=500\nfile=wp-content/uploads/logo.png original=200 optimized=250 webp=100\n",
+            true,
+        );
+
+        assert_eq!(report.images.len(), 2);
+        assert_eq!(
+            report.images[0].file,
+            "wp-content/uploads/2024/01/my photo.jpg"
+        );
+        assert_eq!(report.original_bytes(), 1200);
+        assert_eq!(report.compression_savings(), 200);
+        assert_eq!(report.webp_savings(), 600);
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+
+use kube::{api::ObjectMeta, Resource, ResourceExt};
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+/// Set on every object kwpm creates, so deletes can tell them from objects that merely have
+/// a kwpm-like name.
+pub const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+pub(crate) const MANAGED_BY: &str = "kwpm";
+
+/// Labels and annotations added to every object kwpm creates, e.g. `cost-center` or `team`
+/// for governance tooling. Labels and annotations kwpm sets itself take precedence.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DefaultMetadata {
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl DefaultMetadata {
+    pub fn apply(&self, metadata: &mut ObjectMeta) {
+        for (defaults, values) in [
+            (&self.labels, &mut metadata.labels),
+            (&self.annotations, &mut metadata.annotations),
+        ] {
+            if defaults.is_empty() {
+                continue;
+            }
+            let values = values.get_or_insert_with(Default::default);
+            for (key, value) in defaults {
+                values.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    /// Like `apply`, also adding the managed-by label.
+    pub(crate) fn apply_managed(&self, metadata: &mut ObjectMeta) {
+        self.apply(metadata);
+        metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(MANAGED_BY_LABEL.to_string(), MANAGED_BY.to_string());
+    }
+}
+
+/// Fails unless `object` was created by kwpm and, if given, carries the `identity` label,
+/// e.g. the site it belongs to. Checked before deleting anything.
+pub fn ensure_managed<K: Resource<DynamicType = ()>>(
+    object: &K,
+    identity: Option<(&str, &str)>,
+) -> Result<()> {
+    let labels = object.labels();
+    if labels.get(MANAGED_BY_LABEL).map(String::as_str) != Some(MANAGED_BY) {
+        bail!(
+            "refusing to delete {} {}: it lacks the {}={} label",
+            K::kind(&()),
+            object.name_any(),
+            MANAGED_BY_LABEL,
+            MANAGED_BY
+        );
+    }
+    if let Some((key, value)) = identity {
+        if labels.get(key).map(String::as_str) != Some(value) {
+            bail!(
+                "refusing to delete {} {}: it lacks the {}={} label",
+                K::kind(&()),
+                object.name_any(),
+                key,
+                value
+            );
+        }
+    }
+
+    Ok(())
+}
+
+impl KwpmClient {
+    pub fn with_default_labels(mut self, labels: BTreeMap<String, String>) -> Self {
+        self.default_metadata.labels.extend(labels);
+        self
+    }
+
+    pub fn with_default_annotations(mut self, annotations: BTreeMap<String, String>) -> Self {
+        self.default_metadata.annotations.extend(annotations);
+        self
+    }
+
+    /// A copy of `object` with the default labels and annotations and the managed-by label
+    /// added.
+    pub(crate) fn labeled<K: Resource + Clone>(&self, object: &K) -> K {
+        let mut object = object.clone();
+        self.default_metadata.apply_managed(object.meta_mut());
+        object
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_keeps_existing_values() {
+        let defaults = DefaultMetadata {
+            labels: BTreeMap::from([
+                ("team".to_string(), "web".to_string()),
+                ("app".to_string(), "default".to_string()),
+            ]),
+            annotations: BTreeMap::new(),
+        };
+        let mut metadata = ObjectMeta {
+            labels: Some(BTreeMap::from([(
+                "app".to_string(),
+                "wordpress".to_string(),
+            )])),
+            ..Default::default()
+        };
+        defaults.apply(&mut metadata);
+
+        let labels = metadata.labels.unwrap();
+        assert_eq!(labels["team"], "web");
+        assert_eq!(labels["app"], "wordpress");
+        assert!(metadata.annotations.is_none());
+    }
+
+    #[test]
+    fn test_ensure_managed() {
+        use k8s_openapi::api::core::v1::Namespace;
+
+        let mut namespace = Namespace {
+            metadata: ObjectMeta {
+                name: Some("kwpm-mariadb".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(ensure_managed(&namespace, None).is_err());
+
+        namespace.metadata.labels = Some(BTreeMap::from([
+            (MANAGED_BY_LABEL.to_string(), MANAGED_BY.to_string()),
+            ("kwpm.io/site".to_string(), "blog".to_string()),
+        ]));
+        assert!(ensure_managed(&namespace, None).is_ok());
+        assert!(ensure_managed(&namespace, Some(("kwpm.io/site", "blog"))).is_ok());
+        assert!(ensure_managed(&namespace, Some(("kwpm.io/site", "shop"))).is_err());
+    }
+}
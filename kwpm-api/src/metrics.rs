@@ -0,0 +1,263 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+/// Upper bounds of the Kubernetes API request latency buckets, in seconds.
+const KUBE_REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Upper bounds of the operation duration buckets, in seconds. Creating a site waits for its
+/// deployment, which takes minutes on a cold node.
+const OPERATION_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative counts per bound.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The metrics of this process, collected where the events happen and rendered on scrape.
+#[derive(Debug)]
+struct Registry {
+    /// By operation and result.
+    operations: BTreeMap<(String, &'static str), Histogram>,
+    reconcile_errors: u64,
+    backups_succeeded: u64,
+    backups_failed: u64,
+    /// By verb and status code.
+    kube_requests: BTreeMap<(String, String), Histogram>,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            operations: BTreeMap::new(),
+            reconcile_errors: 0,
+            backups_succeeded: 0,
+            backups_failed: 0,
+            kube_requests: BTreeMap::new(),
+        }
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+fn with_registry(f: impl FnOnce(&mut Registry)) {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        f(&mut registry);
+    }
+}
+
+pub(crate) fn observe_operation(operation: &str, succeeded: bool, duration: Duration) {
+    let result = if succeeded { "succeeded" } else { "failed" };
+    with_registry(|registry| {
+        registry
+            .operations
+            .entry((operation.to_string(), result))
+            .or_insert_with(|| Histogram::new(OPERATION_BUCKETS))
+            .observe(duration.as_secs_f64());
+    });
+}
+
+pub(crate) fn record_reconcile_error() {
+    with_registry(|registry| registry.reconcile_errors += 1);
+}
+
+pub(crate) fn record_backup(succeeded: bool) {
+    with_registry(|registry| {
+        if succeeded {
+            registry.backups_succeeded += 1;
+        } else {
+            registry.backups_failed += 1;
+        }
+    });
+}
+
+/// `code` is the HTTP status, or `error` when no response arrived.
+pub(crate) fn observe_kube_request(verb: &str, code: &str, duration: Duration) {
+    with_registry(|registry| {
+        registry
+            .kube_requests
+            .entry((verb.to_lowercase(), code.to_string()))
+            .or_insert_with(|| Histogram::new(KUBE_REQUEST_BUCKETS))
+            .observe(duration.as_secs_f64());
+    });
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+    let labels: String = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\",", key, escape_label(value)))
+        .collect();
+    for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name, labels, bound, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{}le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+    let labels = labels.trim_end_matches(',');
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+}
+
+fn render(registry: &Registry, sites: Option<usize>) -> String {
+    let mut out = String::new();
+    if let Some(sites) = sites {
+        header(&mut out, "kwpm_sites", "gauge", "Sites managed by kwpm.");
+        let _ = writeln!(out, "kwpm_sites {}", sites);
+    }
+
+    header(
+        &mut out,
+        "kwpm_operation_duration_seconds",
+        "histogram",
+        "Duration of site operations such as create_site.",
+    );
+    for ((operation, result), histogram) in &registry.operations {
+        write_histogram(
+            &mut out,
+            "kwpm_operation_duration_seconds",
+            &[("operation", operation), ("result", result)],
+            histogram,
+        );
+    }
+
+    header(
+        &mut out,
+        "kwpm_reconcile_errors_total",
+        "counter",
+        "Failed reconciles of WordPressSites.",
+    );
+    let _ = writeln!(
+        out,
+        "kwpm_reconcile_errors_total {}",
+        registry.reconcile_errors
+    );
+
+    header(
+        &mut out,
+        "kwpm_backups_total",
+        "counter",
+        "Backup jobs that finished, by result.",
+    );
+    let _ = writeln!(
+        out,
+        "kwpm_backups_total{{result=\"succeeded\"}} {}",
+        registry.backups_succeeded
+    );
+    let _ = writeln!(
+        out,
+        "kwpm_backups_total{{result=\"failed\"}} {}",
+        registry.backups_failed
+    );
+
+    header(
+        &mut out,
+        "kwpm_kube_request_duration_seconds",
+        "histogram",
+        "Latency of Kubernetes API requests.",
+    );
+    for ((verb, code), histogram) in &registry.kube_requests {
+        write_histogram(
+            &mut out,
+            "kwpm_kube_request_duration_seconds",
+            &[("verb", verb), ("code", code)],
+            histogram,
+        );
+    }
+
+    out
+}
+
+/// The metrics of this process in the Prometheus text format, with the number of managed
+/// sites if known.
+pub fn render_metrics(sites: Option<usize>) -> String {
+    REGISTRY
+        .lock()
+        .map(|registry| render(&registry, sites))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+        histogram.observe(60.0);
+        assert_eq!(histogram.counts, [1, 2]);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.sum, 63.5);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut registry = Registry::new();
+        registry.reconcile_errors = 2;
+        registry.backups_failed = 1;
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(3.0);
+        registry
+            .operations
+            .insert(("create_site".to_string(), "succeeded"), histogram);
+
+        let out = render(&registry, Some(4));
+        assert!(out.contains("# TYPE kwpm_sites gauge\nkwpm_sites 4\n"));
+        assert!(out.contains(
+            "kwpm_operation_duration_seconds_bucket{operation=\"create_site\",result=\"succeeded\",le=\"1\"} 0\n"
+        ));
+        assert!(out.contains(
+            "kwpm_operation_duration_seconds_bucket{operation=\"create_site\",result=\"succeeded\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(out.contains(
+            "kwpm_operation_duration_seconds_sum{operation=\"create_site\",result=\"succeeded\"} 3\n"
+        ));
+        assert!(out.contains("kwpm_reconcile_errors_total 2\n"));
+        assert!(out.contains("kwpm_backups_total{result=\"failed\"} 1\n"));
+        assert!(!render(&registry, None).contains("kwpm_sites"));
+        assert_eq!(escape_label("a\"b"), "a\\\"b");
+    }
+}
@@ -0,0 +1,317 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        batch::v1::Job,
+        coordination::v1::Lease,
+        core::v1::{ConfigMap, Namespace, PersistentVolumeClaim, Pod, Secret, Service},
+        networking::v1::Ingress,
+    },
+    NamespaceResourceScope,
+};
+use kube::{
+    api::{Patch, PostParams},
+    runtime::wait::await_condition,
+    Api, Resource, ResourceExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+/// How long a namespace may take to terminate before it counts as stuck.
+pub const NAMESPACE_DELETION_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long a forced cleanup waits for the namespace after removing finalizers of its
+/// objects, before finalizing the namespace itself.
+const FORCED_DELETION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A namespace that has been terminating for too long, with what keeps it from going away.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StuckNamespace {
+    pub name: String,
+    pub terminating_since: Option<DateTime<Utc>>,
+    /// Finalizers of the namespace itself, e.g. `kubernetes`.
+    pub finalizers: Vec<String>,
+    /// Messages of the namespace's deletion conditions, e.g. remaining content.
+    pub reasons: Vec<String>,
+    /// Objects in the namespace holding finalizers, as `Kind/name: finalizer, ...`.
+    pub blocking: Vec<String>,
+}
+
+impl fmt::Display for StuckNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "namespace {} is stuck terminating", self.name)?;
+        if !self.reasons.is_empty() {
+            write!(f, ": {}", self.reasons.join("; "))?;
+        }
+        if !self.blocking.is_empty() {
+            write!(f, "; blocked by {}", self.blocking.join(", "))?;
+        }
+        if !self.finalizers.is_empty() {
+            write!(f, "; namespace finalizers {}", self.finalizers.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Messages of the conditions a terminating namespace reports as true, such as
+/// `NamespaceContentRemaining` or `NamespaceFinalizersRemaining`.
+pub fn namespace_deletion_reasons(namespace: &Namespace) -> Vec<String> {
+    namespace
+        .status
+        .iter()
+        .flat_map(|status| status.conditions.iter().flatten())
+        .filter(|condition| condition.status == "True")
+        .map(|condition| {
+            condition
+                .message
+                .clone()
+                .unwrap_or_else(|| condition.type_.clone())
+        })
+        .collect()
+}
+
+fn namespace_finalizers(namespace: &Namespace) -> Vec<String> {
+    namespace
+        .spec
+        .iter()
+        .flat_map(|spec| spec.finalizers.iter().flatten())
+        .chain(namespace.metadata.finalizers.iter().flatten())
+        .cloned()
+        .collect()
+}
+
+impl KwpmClient {
+    /// Lists the objects of kind `K` in `namespace` holding finalizers, removing the
+    /// finalizers if `clear` is set.
+    async fn objects_with_finalizers<K>(&self, namespace: &str, clear: bool) -> Result<Vec<String>>
+    where
+        K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>
+            + Clone
+            + DeserializeOwned
+            + fmt::Debug,
+    {
+        let api: Api<K> = Api::namespaced(self.client.clone(), namespace);
+        let mut blocking = Vec::new();
+        for object in api.list(&Default::default()).await? {
+            if object.finalizers().is_empty() {
+                continue;
+            }
+            blocking.push(format!(
+                "{}/{}: {}",
+                K::kind(&()),
+                object.name_any(),
+                object.finalizers().join(", ")
+            ));
+            if clear {
+                api.patch(
+                    &object.name_any(),
+                    &Default::default(),
+                    &Patch::Merge(json!({ "metadata": { "finalizers": null } })),
+                )
+                .await?;
+            }
+        }
+
+        Ok(blocking)
+    }
+
+    /// The objects kwpm creates in site and MariaDB namespaces that hold finalizers.
+    async fn blocking_objects(&self, namespace: &str, clear: bool) -> Result<Vec<String>> {
+        let mut blocking = Vec::new();
+        blocking.extend(
+            self.objects_with_finalizers::<Pod>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<PersistentVolumeClaim>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<Deployment>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<Job>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<Service>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<Ingress>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<ConfigMap>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<Secret>(namespace, clear)
+                .await?,
+        );
+        blocking.extend(
+            self.objects_with_finalizers::<Lease>(namespace, clear)
+                .await?,
+        );
+
+        Ok(blocking)
+    }
+
+    async fn diagnose_stuck_namespace(&self, namespace: &Namespace) -> Result<StuckNamespace> {
+        let name = namespace.name_any();
+        Ok(StuckNamespace {
+            terminating_since: namespace.metadata.deletion_timestamp.as_ref().map(|t| t.0),
+            finalizers: namespace_finalizers(namespace),
+            reasons: namespace_deletion_reasons(namespace),
+            blocking: self.blocking_objects(&name, false).await?,
+            name,
+        })
+    }
+
+    /// kwpm namespaces that have been terminating for longer than `older_than`, with what
+    /// blocks them.
+    pub async fn find_stuck_namespaces(&self, older_than: Duration) -> Result<Vec<StuckNamespace>> {
+        let now = Utc::now();
+        let mut stuck = Vec::new();
+        for namespace in self.get_kwpm_namespaces().await? {
+            let Some(deleted_at) = namespace.metadata.deletion_timestamp.as_ref() else {
+                continue;
+            };
+            if (now - deleted_at.0).to_std().unwrap_or_default() >= older_than {
+                stuck.push(self.diagnose_stuck_namespace(&namespace).await?);
+            }
+        }
+
+        Ok(stuck)
+    }
+
+    async fn wait_for_namespace_deletion(&self, name: &str, timeout: Duration) -> bool {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        tokio::time::timeout(
+            timeout,
+            await_condition(namespace_api, name, |ns: Option<&Namespace>| ns.is_none()),
+        )
+        .await
+        .is_ok_and(|result| result.is_ok())
+    }
+
+    /// Deletes a namespace and waits until it is gone. A namespace still terminating after
+    /// `NAMESPACE_DELETION_TIMEOUT` fails with what blocks it, unless `force` is set: then
+    /// the finalizers of the blocking objects are removed and, as a last resort, the
+    /// namespace is finalized without waiting for its own finalizers.
+    pub async fn delete_namespace(&self, name: &str, force: bool) -> Result<()> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        if namespace_api.get_opt(name).await?.is_none() {
+            return Ok(());
+        }
+        namespace_api.delete(name, &Default::default()).await?;
+        if self
+            .wait_for_namespace_deletion(name, NAMESPACE_DELETION_TIMEOUT)
+            .await
+        {
+            return Ok(());
+        }
+
+        let Some(namespace) = namespace_api.get_opt(name).await? else {
+            return Ok(());
+        };
+        let stuck = self.diagnose_stuck_namespace(&namespace).await?;
+        if !force {
+            bail!("{}; delete it with force to remove the finalizers", stuck);
+        }
+
+        self.blocking_objects(name, true).await?;
+        if self
+            .wait_for_namespace_deletion(name, FORCED_DELETION_TIMEOUT)
+            .await
+        {
+            return Ok(());
+        }
+
+        let Some(mut namespace) = namespace_api.get_opt(name).await? else {
+            return Ok(());
+        };
+        if let Some(spec) = namespace.spec.as_mut() {
+            spec.finalizers = Some(Vec::new());
+        }
+        namespace_api
+            .replace_subresource(
+                "finalize",
+                name,
+                &PostParams::default(),
+                serde_json::to_vec(&namespace)?,
+            )
+            .await?;
+        if !self
+            .wait_for_namespace_deletion(name, FORCED_DELETION_TIMEOUT)
+            .await
+        {
+            bail!("{} even after removing its finalizers", stuck);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{NamespaceCondition, NamespaceSpec, NamespaceStatus};
+
+    use super::*;
+
+    #[test]
+    fn test_stuck_namespace_report() {
+        let namespace = Namespace {
+            spec: Some(NamespaceSpec {
+                finalizers: Some(vec!["kubernetes".to_string()]),
+            }),
+            status: Some(NamespaceStatus {
+                phase: Some("Terminating".to_string()),
+                conditions: Some(vec![
+                    NamespaceCondition {
+                        type_: "NamespaceDeletionDiscoveryFailure".to_string(),
+                        status: "False".to_string(),
+                        message: Some("All resources successfully discovered".to_string()),
+                        ..Default::default()
+                    },
+                    NamespaceCondition {
+                        type_: "NamespaceFinalizersRemaining".to_string(),
+                        status: "True".to_string(),
+                        message: Some(
+                            "Some content in the namespace has finalizers remaining: \
+                             kubernetes.io/pvc-protection in 1 resource instances"
+                                .to_string(),
+                        ),
+                        ..Default::default()
+                    },
+                ]),
+            }),
+            ..Default::default()
+        };
+
+        let stuck = StuckNamespace {
+            name: "kwpm-mariadb".to_string(),
+            finalizers: namespace_finalizers(&namespace),
+            reasons: namespace_deletion_reasons(&namespace),
+            blocking: vec![
+                "PersistentVolumeClaim/mysql-pv-claim: kubernetes.io/pvc-protection".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            stuck.to_string(),
+            "namespace kwpm-mariadb is stuck terminating: Some content in the namespace has \
+             finalizers remaining: kubernetes.io/pvc-protection in 1 resource instances; \
+             blocked by PersistentVolumeClaim/mysql-pv-claim: kubernetes.io/pvc-protection; \
+             namespace finalizers kubernetes"
+        );
+    }
+}
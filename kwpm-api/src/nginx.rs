@@ -0,0 +1,194 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use anyhow::Context;
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::ConfigMap};
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use serde_json::json;
+
+use crate::{error::Result, healthz::healthz_location, site::SiteSpec, KwpmClient};
+
+const NGINX_CONFIG_HASH_ANNOTATION: &str = "kwpm.io/nginx-config-hash";
+
+fn indent(block: &str, prefix: &str) -> String {
+    block
+        .lines()
+        .map(|line| format!("{}{}", prefix, line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders the site's nginx server config from the template, applying the spec's upload limit
+/// and redirects.
+pub fn render_nginx_config(spec: &SiteSpec) -> Result<ConfigMap> {
+    let mut config_map: ConfigMap = serde_yaml::from_str(include_str!(
+        "../../kubernetes/wordpress/wp-nginx-config.yaml"
+    ))?;
+
+    let default_conf = config_map
+        .data
+        .as_mut()
+        .and_then(|data| data.get_mut("default.conf"))
+        .context("nginx config has no default.conf")?;
+
+    let mut lines = Vec::new();
+    let mut in_php_location = false;
+    for line in default_conf.lines() {
+        let indentation = &line[..line.len() - line.trim_start().len()];
+
+        if !spec.app.uses_php() {
+            if line.trim_start().starts_with("location ~ \\.php$") {
+                in_php_location = true;
+            }
+            if in_php_location {
+                in_php_location = line.trim() != "}";
+                continue;
+            }
+            if line.trim_start().starts_with("index ") {
+                lines.push(format!("{}index index.html;", indentation));
+                continue;
+            }
+            if line.trim_start().starts_with("try_files ") {
+                lines.push(format!("{}try_files $uri $uri/ =404;", indentation));
+                continue;
+            }
+        }
+
+        if let (Some(limit_mb), true) = (
+            spec.upload_limit_mb,
+            line.trim_start().starts_with("client_max_body_size"),
+        ) {
+            lines.push(format!(
+                "{}client_max_body_size {}m;",
+                indentation, limit_mb
+            ));
+            continue;
+        }
+
+        if line.trim_start().starts_with("location / {") {
+            if spec.health_endpoint {
+                lines.push(indent(&healthz_location(), indentation));
+                lines.push(String::new());
+            }
+            for redirect in &spec.redirects {
+                lines.push(indent(&redirect.to_nginx(), indentation));
+                lines.push(String::new());
+            }
+        }
+
+        lines.push(line.to_string());
+    }
+    *default_conf = lines.join("\n");
+
+    Ok(config_map)
+}
+
+fn config_hash(config_map: &ConfigMap) -> String {
+    let mut hasher = DefaultHasher::new();
+    config_map.data.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+impl KwpmClient {
+    /// Applies the rendered nginx config and rolls the site's pods if it changed, since nginx only
+    /// reads it on start.
+    pub(crate) async fn apply_nginx_config(&self, site: &str, spec: &SiteSpec) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let config_map = render_nginx_config(spec)?;
+        let name = config_map.metadata.name.clone().unwrap_or_default();
+        config_map_api
+            .patch(
+                &name,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&config_map)),
+            )
+            .await?;
+
+        let restart = json!({
+            "spec": { "template": { "metadata": { "annotations": {
+                NGINX_CONFIG_HASH_ANNOTATION: config_hash(&config_map)
+            } } } }
+        });
+        deployment_api
+            .patch("wordpress", &Default::default(), &Patch::Merge(&restart))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{redirect::Redirect, site::AppKind};
+
+    fn default_conf(spec: &SiteSpec) -> String {
+        render_nginx_config(spec).unwrap().data.unwrap()["default.conf"].clone()
+    }
+
+    #[test]
+    fn test_render_default_config() {
+        let default_conf = default_conf(&SiteSpec::default());
+
+        assert!(default_conf.contains("client_max_body_size 256m;"));
+    }
+
+    #[test]
+    fn test_render_upload_limit_and_redirects() {
+        let spec = SiteSpec {
+            upload_limit_mb: Some(64),
+            redirects: vec![Redirect {
+                source_host: None,
+                source_path: Some("/old".to_string()),
+                target: "/new".to_string(),
+                status: 308,
+            }],
+            ..Default::default()
+        };
+        let default_conf = default_conf(&spec);
+
+        assert!(default_conf.contains("client_max_body_size 64m;\n"));
+        assert!(!default_conf.contains("256m"));
+        let redirect = default_conf.find("location = /old {").unwrap();
+        let root_location = default_conf.find("location / {").unwrap();
+        assert!(redirect < root_location);
+        assert!(default_conf.contains("return 308 /new;"));
+    }
+
+    #[test]
+    fn test_render_static_site() {
+        let spec = SiteSpec {
+            app: AppKind::Static,
+            ..Default::default()
+        };
+        let conf = default_conf(&spec);
+
+        assert!(!conf.contains("fastcgi"));
+        assert!(conf.contains("index index.html;"));
+        assert!(conf.contains("try_files $uri $uri/ =404;"));
+        assert!(conf.trim_end().ends_with('}'));
+        assert!(default_conf(&SiteSpec::default()).contains("fastcgi_pass"));
+    }
+
+    #[test]
+    fn test_render_health_endpoint() {
+        let spec = SiteSpec {
+            health_endpoint: true,
+            ..Default::default()
+        };
+        let conf = default_conf(&spec);
+
+        let healthz = conf.find("location = /healthz.php {").unwrap();
+        let root_location = conf.find("location / {").unwrap();
+        assert!(healthz < root_location);
+        assert!(!default_conf(&SiteSpec::default()).contains("healthz"));
+    }
+}
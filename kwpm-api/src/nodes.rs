@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{Node, PersistentVolume, Pod};
+use kube::{api::ListParams, Api, ResourceExt};
+
+use crate::{
+    error::{bail, Result},
+    manifest::parse_quantity,
+    KwpmClient,
+};
+
+/// Whether pods without tolerations can be scheduled on the node.
+fn is_schedulable(node: &Node) -> bool {
+    let ready = node
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .is_some_and(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Ready" && c.status == "True")
+        });
+    let spec = node.spec.as_ref();
+    let cordoned = spec.and_then(|spec| spec.unschedulable).unwrap_or(false);
+    let tainted = spec
+        .and_then(|spec| spec.taints.as_ref())
+        .is_some_and(|taints| {
+            taints
+                .iter()
+                .any(|taint| matches!(taint.effect.as_str(), "NoSchedule" | "NoExecute"))
+        });
+
+    ready && !cordoned && !tainted
+}
+
+/// The memory requested by the running pods of every node, in bytes.
+fn requested_memory(pods: &[Pod]) -> BTreeMap<String, u64> {
+    let mut requested = BTreeMap::new();
+    for pod in pods {
+        let finished = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref())
+            .is_some_and(|phase| matches!(phase, "Succeeded" | "Failed"));
+        let Some(spec) = pod.spec.as_ref().filter(|_| !finished) else {
+            continue;
+        };
+        let Some(node) = &spec.node_name else {
+            continue;
+        };
+        let memory: u64 = spec
+            .containers
+            .iter()
+            .filter_map(|c| c.resources.as_ref()?.requests.as_ref()?.get("memory"))
+            .filter_map(|quantity| parse_quantity(&quantity.0))
+            .sum();
+        *requested.entry(node.clone()).or_default() += memory;
+    }
+    requested
+}
+
+/// The schedulable node with the most memory not requested by its pods, the name breaking
+/// ties so the choice is stable.
+pub fn pick_node(nodes: &[Node], pods: &[Pod]) -> Option<String> {
+    let requested = requested_memory(pods);
+    nodes
+        .iter()
+        .filter(|node| is_schedulable(node))
+        .map(|node| {
+            let allocatable = node
+                .status
+                .as_ref()
+                .and_then(|status| status.allocatable.as_ref())
+                .and_then(|allocatable| allocatable.get("memory"))
+                .and_then(|quantity| parse_quantity(&quantity.0))
+                .unwrap_or(0);
+            let name = node.name_any();
+            let used = requested.get(&name).copied().unwrap_or(0);
+            (allocatable.saturating_sub(used), name)
+        })
+        .max_by(|(a_free, a_name), (b_free, b_name)| {
+            a_free.cmp(b_free).then_with(|| b_name.cmp(a_name))
+        })
+        .map(|(_, name)| name)
+}
+
+/// The node a local volume is pinned to.
+pub(crate) fn volume_node(pv: &PersistentVolume) -> Option<String> {
+    pv.spec
+        .as_ref()?
+        .node_affinity
+        .as_ref()?
+        .required
+        .as_ref()?
+        .node_selector_terms
+        .iter()
+        .flat_map(|term| term.match_expressions.iter().flatten())
+        .find(|requirement| {
+            requirement.key == "kubernetes.io/hostname" && requirement.operator == "In"
+        })?
+        .values
+        .as_ref()?
+        .first()
+        .cloned()
+}
+
+impl KwpmClient {
+    /// Only places local volumes on nodes matching the label selector, e.g.
+    /// `kwpm.io/storage=true`.
+    pub fn with_node_selector(mut self, selector: impl ToString) -> Self {
+        self.node_selector = Some(selector.to_string());
+        self
+    }
+
+    /// Picks the node to create a local volume on: the schedulable node matching the node
+    /// selector with the most memory left.
+    pub async fn pick_placement_node(&self) -> Result<String> {
+        let node_api: Api<Node> = Api::all(self.client.clone());
+        let pod_api: Api<Pod> = Api::all(self.client.clone());
+        let mut params = ListParams::default();
+        if let Some(selector) = &self.node_selector {
+            params = params.labels(selector);
+        }
+        let nodes = node_api.list(&params).await?.items;
+        let pods = pod_api.list(&Default::default()).await?.items;
+
+        match pick_node(&nodes, &pods) {
+            Some(node) => Ok(node),
+            None => bail!(
+                "no schedulable node{} to place the volume on",
+                self.node_selector
+                    .as_ref()
+                    .map(|selector| format!(" matches {}", selector))
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_node_affinity;
+
+    fn node(name: &str, memory: &str, ready: bool, taint: Option<&str>) -> Node {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name },
+            "spec": {
+                "taints": taint.map(|effect| vec![serde_json::json!({
+                    "key": "node-role.kubernetes.io/control-plane",
+                    "effect": effect,
+                })]),
+            },
+            "status": {
+                "allocatable": { "memory": memory },
+                "conditions": [{ "type": "Ready", "status": if ready { "True" } else { "False" } }],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn pod(node: &str, memory: &str, phase: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "spec": {
+                "nodeName": node,
+                "containers": [{ "name": "app", "resources": { "requests": { "memory": memory } } }],
+            },
+            "status": { "phase": phase },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pick_node() {
+        let nodes = [
+            node("control", "64Gi", true, Some("NoSchedule")),
+            node("worker-1", "8Gi", true, None),
+            node("worker-2", "8Gi", true, None),
+            node("worker-3", "32Gi", false, None),
+        ];
+        assert_eq!(pick_node(&nodes, &[]).as_deref(), Some("worker-1"));
+
+        let pods = [
+            pod("worker-1", "2Gi", "Running"),
+            pod("worker-2", "6Gi", "Succeeded"),
+        ];
+        assert_eq!(pick_node(&nodes, &pods).as_deref(), Some("worker-2"));
+        assert_eq!(pick_node(&nodes[..1], &pods), None);
+    }
+
+    #[test]
+    fn test_volume_node() {
+        let pv = PersistentVolume {
+            spec: Some(k8s_openapi::api::core::v1::PersistentVolumeSpec {
+                node_affinity: Some(local_node_affinity("worker-2")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(volume_node(&pv).as_deref(), Some("worker-2"));
+        assert_eq!(volume_node(&PersistentVolume::default()), None);
+    }
+}
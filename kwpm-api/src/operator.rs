@@ -0,0 +1,670 @@
+use std::{collections::BTreeSet, fmt, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures::StreamExt;
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        batch::v1::Job,
+        core::v1::{ConfigMap, Namespace},
+    },
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition,
+    NamespaceResourceScope,
+};
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::{
+        controller::{Action, Controller},
+        reflector::ObjectRef,
+        watcher, WatchStreamExt,
+    },
+    Api, CustomResource, CustomResourceExt, Resource, ResourceExt,
+};
+use schemars::{schema::Schema, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{bail, KwpmError, Result},
+    healthz::attach_health_endpoint,
+    job::job_outcome,
+    lock::SiteLockConflict,
+    manifest::deployment_pod_spec_mut,
+    mariadb::{
+        is_mariadb_deployment, namespace_mariadb_instance, set_mariadb_host,
+        DEFAULT_MARIADB_INSTANCE,
+    },
+    metrics::{record_backup, record_reconcile_error},
+    nginx::render_nginx_config,
+    pause::{is_paused, PAUSED_ANNOTATION},
+    provision::site_manifests,
+    quota::{DatabaseUsage, PLAN_LABEL},
+    rollout::apply_rollout_strategy,
+    sidecar::apply_sidecars,
+    site::{is_deployment_available, AppKind},
+    site_env::{apply_site_env, site_env_secret},
+    uploads::uploads_ini_config,
+    KwpmClient, MARIADB_DEPLOYMENT_NAME,
+};
+
+const FINALIZER: &str = "kwpm.io/cleanup";
+/// How often sites are converged without a change, so drift is also healed when no event
+/// triggers a reconcile.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(300);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A site declared as a cluster-scoped resource named after the site. The operator creates it
+/// like `create_site`, recreates its objects when they are deleted and removes it with the
+/// resource.
+#[derive(CustomResource, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[kube(
+    group = "kwpm.io",
+    version = "v1alpha1",
+    kind = "WordPressSite",
+    status = "WordPressSiteStatus",
+    shortname = "wps",
+    printcolumn = r#"{"name":"Domain","type":"string","jsonPath":".spec.domain"}"#,
+    printcolumn = r#"{"name":"Phase","type":"string","jsonPath":".status.phase"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct WordPressSiteSpec {
+    /// Only used when the site is created.
+    pub domain: String,
+    #[serde(default)]
+    #[schemars(schema_with = "app_kind_schema")]
+    pub app: AppKind,
+    /// The MariaDB instance of the site's database, the default one if unset. Only used when
+    /// the site is created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mariadb: Option<String>,
+    /// The plan deciding the database quota of the site, see `DatabaseQuotaPolicy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<String>,
+}
+
+impl WordPressSiteSpec {
+    fn mariadb_instance(&self) -> &str {
+        self.mariadb.as_deref().unwrap_or(DEFAULT_MARIADB_INSTANCE)
+    }
+}
+
+/// kube can't hoist the variants of an internally tagged enum into a structural schema, as
+/// they all define `kind`.
+fn app_kind_schema(_: &mut schemars::gen::SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "object",
+        "properties": {
+            "kind": { "type": "string", "enum": ["wordpress", "php", "static"] },
+            "image": { "type": "string", "description": "The php-fpm image of `php` apps." },
+        },
+        "required": ["kind"],
+    }))
+    .expect("valid schema")
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WordPressSiteStatus {
+    /// `Pending` while waiting for MariaDB to provision the site, `Degraded` while MariaDB
+    /// isn't ready for a provisioned site, `Paused` while `kwpm.io/paused` is set, `Ready` or
+    /// `Failed`.
+    pub phase: String,
+    pub message: Option<String>,
+    pub observed_generation: Option<i64>,
+    /// The size of the site's database as last measured, in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database_used_mb: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database_quota_mb: Option<u64>,
+}
+
+/// `Controller` needs a `std::error::Error`.
+#[derive(Debug)]
+pub struct ReconcileError(KwpmError);
+
+impl fmt::Display for ReconcileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for ReconcileError {}
+
+impl<E: Into<KwpmError>> From<E> for ReconcileError {
+    fn from(e: E) -> Self {
+        Self(e.into())
+    }
+}
+
+pub fn wordpress_site_crd() -> CustomResourceDefinition {
+    WordPressSite::crd()
+}
+
+/// `result` is `None` if provisioning is held back until MariaDB is ready.
+fn status(
+    generation: Option<i64>,
+    mariadb_ready: bool,
+    result: &Result<Option<Vec<String>>>,
+    database_usage: Option<&DatabaseUsage>,
+) -> WordPressSiteStatus {
+    let mut status = match result {
+        Ok(None) => WordPressSiteStatus {
+            phase: "Pending".to_string(),
+            message: Some("waiting for MariaDB to become ready".to_string()),
+            observed_generation: generation,
+            ..Default::default()
+        },
+        Ok(Some(_)) if !mariadb_ready => WordPressSiteStatus {
+            phase: "Degraded".to_string(),
+            message: Some("MariaDB is not ready".to_string()),
+            observed_generation: generation,
+            ..Default::default()
+        },
+        Ok(Some(healed)) => WordPressSiteStatus {
+            phase: "Ready".to_string(),
+            message: (!healed.is_empty()).then(|| format!("recreated {}", healed.join(", "))),
+            observed_generation: generation,
+            ..Default::default()
+        },
+        Err(e) => WordPressSiteStatus {
+            phase: "Failed".to_string(),
+            message: Some(format!("{:#}", e)),
+            observed_generation: generation,
+            ..Default::default()
+        },
+    };
+
+    if let Some(usage) = database_usage {
+        status.database_used_mb = Some(usage.used_mb());
+        status.database_quota_mb = usage.quota.map(|quota| quota.limit_mb);
+        if usage.exceeded() && status.phase == "Ready" {
+            let mut message = format!("database exceeds its quota at {} MiB", usage.used_mb());
+            if usage.writes_restricted {
+                message.push_str(", writes are restricted");
+            }
+            status.message = Some(match status.message {
+                Some(healed) => format!("{}; {}", healed, message),
+                None => message,
+            });
+        }
+    }
+    status
+}
+
+/// The lock another operation holds if that kept the site from being converged, in which case
+/// the site isn't failing but has to be converged later.
+fn lock_conflict(result: &Result<Option<Vec<String>>>) -> Option<&SiteLockConflict> {
+    result.as_ref().err()?.downcast_ref::<SiteLockConflict>()
+}
+
+/// Keeps the last measured database usage, which isn't measured while paused.
+fn paused_status(
+    generation: Option<i64>,
+    previous: Option<&WordPressSiteStatus>,
+) -> WordPressSiteStatus {
+    WordPressSiteStatus {
+        phase: "Paused".to_string(),
+        message: Some(format!("{} is set, not reconciling", PAUSED_ANNOTATION)),
+        observed_generation: generation,
+        database_used_mb: previous.and_then(|status| status.database_used_mb),
+        database_quota_mb: previous.and_then(|status| status.database_quota_mb),
+    }
+}
+
+async fn reconcile(
+    site: Arc<WordPressSite>,
+    client: Arc<KwpmClient>,
+) -> Result<Action, ReconcileError> {
+    let name = site.name_any();
+    let api: Api<WordPressSite> = Api::all(client.client.clone());
+    let finalizers = site.finalizers();
+
+    if site.meta().deletion_timestamp.is_some() {
+        if finalizers.iter().any(|f| f == FINALIZER) {
+            let namespace_api: Api<Namespace> = Api::all(client.client.clone());
+            if namespace_api
+                .get_opt(&client.site_namespace(&name))
+                .await?
+                .is_some()
+            {
+                client.delete_site(&name).await?;
+            }
+            let remaining: Vec<&String> = finalizers.iter().filter(|f| *f != FINALIZER).collect();
+            api.patch(
+                &name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "metadata": { "finalizers": remaining } })),
+            )
+            .await?;
+        }
+        return Ok(Action::await_change());
+    }
+
+    if !finalizers.iter().any(|f| f == FINALIZER) {
+        let mut finalizers = finalizers.to_vec();
+        finalizers.push(FINALIZER.to_string());
+        api.patch(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "metadata": { "finalizers": finalizers } })),
+        )
+        .await?;
+    }
+
+    let namespace_api: Api<Namespace> = Api::all(client.client.clone());
+    let namespace = namespace_api.get_opt(&client.site_namespace(&name)).await?;
+    // Deletion still goes ahead, removing the resource is an explicit request.
+    if is_paused(site.meta()) || namespace.as_ref().is_some_and(|ns| is_paused(ns.meta())) {
+        let status = paused_status(site.meta().generation, site.status.as_ref());
+        if site.status.as_ref() != Some(&status) {
+            api.patch_status(
+                &name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "status": status })),
+            )
+            .await?;
+        }
+        return Ok(Action::requeue(RESYNC_INTERVAL));
+    }
+    let provisioned = namespace.is_some();
+    let instance = namespace.as_ref().map_or_else(
+        || site.spec.mariadb_instance().to_string(),
+        namespace_mariadb_instance,
+    );
+    let mariadb_ready =
+        !site.spec.app.uses_database() || client.is_mariadb_instance_ready(&instance).await?;
+    let result = if mariadb_ready || provisioned {
+        client.converge_site(&name, &site.spec).await.map(Some)
+    } else {
+        Ok(None)
+    };
+    if let Some(conflict) = lock_conflict(&result) {
+        tracing::info!(
+            site = %name,
+            operation = %conflict.operation,
+            "site is locked, converging it later"
+        );
+        return Ok(Action::requeue(RETRY_INTERVAL));
+    }
+    // A failed measurement only leaves the usage out of the status.
+    let database_usage = match &result {
+        Ok(Some(_)) if mariadb_ready && site.spec.app.uses_database() => {
+            client.enforce_database_quota(&name).await.ok()
+        }
+        _ => None,
+    };
+    let status = status(
+        site.meta().generation,
+        mariadb_ready,
+        &result,
+        database_usage.as_ref(),
+    );
+    if site.status.as_ref() != Some(&status) {
+        api.patch_status(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await?;
+    }
+    if result?.is_none() || !mariadb_ready {
+        return Ok(Action::requeue(RETRY_INTERVAL));
+    }
+
+    Ok(Action::requeue(RESYNC_INTERVAL))
+}
+
+fn error_policy(site: Arc<WordPressSite>, error: &ReconcileError, _: Arc<KwpmClient>) -> Action {
+    tracing::warn!(site = %site.name_any(), error = %format!("{:#}", error), "reconcile failed");
+    record_reconcile_error();
+    Action::requeue(RETRY_INTERVAL)
+}
+
+impl KwpmClient {
+    pub async fn install_wordpress_site_crd(&self) -> Result<()> {
+        let crd_api: Api<CustomResourceDefinition> = Api::all(self.client.clone());
+        let crd = wordpress_site_crd();
+        crd_api
+            .patch(
+                &crd.name_any(),
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&crd)),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_if_missing<K>(&self, namespace: &str, object: &K) -> Result<Option<String>>
+    where
+        K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>
+            + Clone
+            + Serialize
+            + DeserializeOwned
+            + fmt::Debug,
+    {
+        let api: Api<K> = Api::namespaced(self.client.clone(), namespace);
+        let name = object.meta().name.clone().unwrap_or_default();
+        if api.get_opt(&name).await?.is_some() {
+            return Ok(None);
+        }
+        api.create(&Default::default(), &self.labeled(object))
+            .await?;
+
+        Ok(Some(format!("{} {}", K::kind(&()), name)))
+    }
+
+    /// Creates the site if its namespace doesn't exist. Otherwise recreates the objects that
+    /// were deleted and reverts changes to the nginx config, returning what was recreated.
+    /// Changing the domain or app of an existing site isn't supported.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn converge_site(&self, site: &str, spec: &WordPressSiteSpec) -> Result<Vec<String>> {
+        let ns_name = self.site_namespace(site);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let Some(namespace) = namespace_api.get_opt(&ns_name).await? else {
+            let database = if spec.app.uses_database() {
+                Some(
+                    self.database_config_for_site(site)?
+                        .on_instance(spec.mariadb_instance()),
+                )
+            } else {
+                None
+            };
+            self.create_site(site, &spec.domain, &spec.app, database.as_ref())
+                .await?;
+            if let Some(plan) = &spec.plan {
+                self.set_site_plan(site, Some(plan)).await?;
+            }
+            return Ok(Vec::new());
+        };
+        if spec.plan.is_some() && namespace.labels().get(PLAN_LABEL) != spec.plan.as_ref() {
+            self.set_site_plan(site, spec.plan.as_deref()).await?;
+        }
+
+        let stored = self.stored_site_spec(site).await?;
+        if stored.app != spec.app {
+            bail!("the app of {} can't be changed to {:?}", site, spec.app);
+        }
+        let instance = namespace_mariadb_instance(&namespace);
+        let mariadb_pv = self.mariadb_volume(&instance).await?;
+        let mut manifests = site_manifests(
+            site,
+            &self.site_namespace(site),
+            &spec.domain,
+            &spec.app,
+            &self.pv_base_path,
+            mariadb_pv.as_ref(),
+        )?;
+        self.adapt_volume(&mut manifests.pv, &mut manifests.pvc);
+        self.ingress_manager
+            .apply_to(&mut manifests.ingress, &spec.domain);
+
+        let lock = self.lock_site(site, "converge_site").await?;
+        let result: Result<_> = async {
+            let mut healed = Vec::new();
+            healed.extend(self.create_if_missing(&ns_name, &manifests.pvc).await?);
+            healed.extend(self.create_if_missing(&ns_name, &manifests.service).await?);
+            healed.extend(self.create_if_missing(&ns_name, &manifests.ingress).await?);
+
+            let nginx_config = render_nginx_config(&stored)?;
+            let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+            let live_nginx_config = config_map_api
+                .get_opt(nginx_config.metadata.name.as_deref().unwrap_or_default())
+                .await?;
+            if live_nginx_config.is_none_or(|live| live.data != nginx_config.data) {
+                self.apply_nginx_config(site, &stored).await?;
+                healed.push(format!(
+                    "ConfigMap {}",
+                    nginx_config.metadata.name.unwrap_or_default()
+                ));
+            }
+            let uploads_ini = stored.upload_limit_mb.map(uploads_ini_config).transpose()?;
+            for config_map in manifests.config_maps.iter().skip(1) {
+                let config_map = uploads_ini
+                    .as_ref()
+                    .filter(|uploads_ini| uploads_ini.metadata.name == config_map.metadata.name)
+                    .unwrap_or(config_map);
+                healed.extend(self.create_if_missing(&ns_name, config_map).await?);
+            }
+
+            if !stored.env.is_empty() || !stored.config_constants.is_empty() {
+                let spec = self.get_site_spec(site).await?;
+                let secret = site_env_secret(&spec.env, &spec.config_constants);
+                healed.extend(self.create_if_missing(&ns_name, &secret).await?);
+            }
+
+            let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+            if deployment_api.get_opt("wordpress").await?.is_none() {
+                let deployment = &mut manifests.deployment;
+                let pod_spec = deployment_pod_spec_mut(deployment)?;
+                set_mariadb_host(pod_spec, &instance);
+                self.place_by_architecture(pod_spec).await?;
+                self.prepare_pod_spec(pod_spec);
+                if stored.health_endpoint {
+                    attach_health_endpoint(deployment)?;
+                }
+                if let Some(rollout) = &stored.rollout {
+                    apply_rollout_strategy(deployment, rollout, &self.site_url(site).await?)?;
+                }
+                if !stored.env.is_empty() || !stored.config_constants.is_empty() {
+                    apply_site_env(deployment, &self.get_site_spec(site).await?)?;
+                }
+                apply_sidecars(deployment, &stored.sidecars)?;
+                healed.extend(self.create_if_missing(&ns_name, &*deployment).await?);
+            }
+
+            if !healed.is_empty() {
+                self.record_audit(
+                    site,
+                    "converge_site",
+                    &format!("recreated {}", healed.join(", ")),
+                )
+                .await?;
+            }
+            Ok(healed)
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Runs the `WordPressSite` controller until the watch ends. Deleted deployments trigger
+    /// a reconcile of their site right away, other drift is healed within `RESYNC_INTERVAL`.
+    /// Any MariaDB instance becoming ready reconciles every site, so held back sites are
+    /// provisioned. Backup jobs finishing meanwhile are counted for the metrics, and instances
+    /// with a standby are failed over when they stay down, see `watch_mariadb_failover`, and
+    /// expired previews are deleted.
+    pub async fn run_operator(self: Arc<Self>) -> Result<()> {
+        let sites: Api<WordPressSite> = Api::all(self.client.clone());
+        let deployments: Api<Deployment> = Api::all(self.client.clone());
+        let mariadb: Api<Deployment> = Api::all(self.client.clone());
+        // `reconcile_all_on` needs a `Sync` stream, which the watcher isn't.
+        let (mariadb_ready_tx, mariadb_ready) = futures::channel::mpsc::unbounded();
+        let mariadb_watch = watcher(
+            mariadb,
+            watcher::Config::default()
+                .fields(&format!("metadata.name={}", MARIADB_DEPLOYMENT_NAME)),
+        )
+        .applied_objects()
+        .filter_map(|deployment| {
+            futures::future::ready(
+                deployment
+                    .ok()
+                    .filter(is_mariadb_deployment)
+                    .filter(is_deployment_available)
+                    .map(|_| Ok(())),
+            )
+        })
+        .forward(mariadb_ready_tx);
+        tokio::spawn(mariadb_watch);
+        tokio::spawn(self.clone().watch_mariadb_failover());
+        tokio::spawn(self.clone().clean_up_expired_previews_periodically());
+        tokio::spawn(self.clone().fail_stale_operations_periodically());
+
+        let started_at = Utc::now();
+        let mut backups = watcher(
+            Api::<Job>::all(self.client.clone()),
+            watcher::Config::default().labels("app=kwpm-backup"),
+        )
+        .applied_objects()
+        .boxed();
+        tokio::spawn(async move {
+            // Watches restart with every job listed again, so each is only counted once.
+            let mut counted = BTreeSet::new();
+            while let Some(job) = backups.next().await {
+                let Ok(job) = job else {
+                    continue;
+                };
+                if let Some((succeeded, finished_at)) = job_outcome(&job) {
+                    if finished_at >= started_at && counted.insert(job.uid().unwrap_or_default()) {
+                        record_backup(succeeded);
+                    }
+                }
+            }
+        });
+
+        let client = self.clone();
+        Controller::new(sites, watcher::Config::default())
+            .watches(
+                deployments,
+                watcher::Config::default().labels("app=wordpress"),
+                move |deployment| {
+                    deployment
+                        .namespace()
+                        .as_deref()
+                        .and_then(|ns| client.site_name(ns))
+                        .map(ObjectRef::new)
+                },
+            )
+            .reconcile_all_on(mariadb_ready)
+            .run(reconcile, error_policy, self)
+            .for_each(|_| futures::future::ready(()))
+            .await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::{DatabaseQuota, QuotaMode};
+
+    #[test]
+    fn test_wordpress_site_crd() {
+        let crd = wordpress_site_crd();
+
+        assert_eq!(crd.name_any(), "wordpresssites.kwpm.io");
+        assert_eq!(crd.spec.scope, "Cluster");
+        let version = &crd.spec.versions[0];
+        assert!(version.subresources.as_ref().unwrap().status.is_some());
+
+        let site: WordPressSite = serde_yaml::from_str(
+            "apiVersion: kwpm.io/v1alpha1
+kind: WordPressSite
+metadata:
+  name: stats
+spec:
+  domain: stats.example.com
+  app:
+    kind: php
+    image: matomo:5-fpm-alpine
+",
+        )
+        .unwrap();
+        assert_eq!(
+            site.spec.app,
+            AppKind::Php {
+                image: "matomo:5-fpm-alpine".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        let ready = status(
+            Some(3),
+            true,
+            &Ok(Some(vec!["Deployment wordpress".to_string()])),
+            None,
+        );
+        assert_eq!(ready.phase, "Ready");
+        assert_eq!(
+            ready.message.as_deref(),
+            Some("recreated Deployment wordpress")
+        );
+        assert_eq!(ready.observed_generation, Some(3));
+
+        let failed = status(
+            Some(3),
+            true,
+            &Err(anyhow::anyhow!("MariaDB has not been created").into()),
+            None,
+        );
+        assert_eq!(failed.phase, "Failed");
+        assert_eq!(
+            failed.message.as_deref(),
+            Some("MariaDB has not been created")
+        );
+    }
+
+    #[test]
+    fn test_lock_conflict() {
+        let locked: Result<Option<Vec<String>>> = Err(SiteLockConflict {
+            site: "blog".to_string(),
+            holder: "kwpm-1".to_string(),
+            operation: "upgrade_site".to_string(),
+        }
+        .into());
+        assert_eq!(lock_conflict(&locked).unwrap().operation, "upgrade_site");
+        assert!(lock_conflict(&Err(anyhow::anyhow!("job failed").into())).is_none());
+        assert!(lock_conflict(&Ok(Some(Vec::new()))).is_none());
+    }
+
+    #[test]
+    fn test_status_waits_for_mariadb() {
+        let pending = status(Some(1), false, &Ok(None), None);
+        assert_eq!(pending.phase, "Pending");
+
+        let degraded = status(Some(1), false, &Ok(Some(Vec::new())), None);
+        assert_eq!(degraded.phase, "Degraded");
+        assert_eq!(degraded.message.as_deref(), Some("MariaDB is not ready"));
+    }
+
+    #[test]
+    fn test_status_database_usage() {
+        let usage = DatabaseUsage {
+            site: "blog".to_string(),
+            plan: None,
+            used_bytes: 300 * 1024 * 1024,
+            quota: Some(DatabaseQuota {
+                limit_mb: 256,
+                mode: QuotaMode::Enforce,
+            }),
+            writes_restricted: true,
+        };
+        let ready = status(Some(2), true, &Ok(Some(Vec::new())), Some(&usage));
+        assert_eq!(ready.phase, "Ready");
+        assert_eq!(ready.database_used_mb, Some(300));
+        assert_eq!(ready.database_quota_mb, Some(256));
+        assert_eq!(
+            ready.message.as_deref(),
+            Some("database exceeds its quota at 300 MiB, writes are restricted")
+        );
+    }
+
+    #[test]
+    fn test_paused_status() {
+        let ready = WordPressSiteStatus {
+            phase: "Ready".to_string(),
+            database_used_mb: Some(120),
+            database_quota_mb: Some(256),
+            ..Default::default()
+        };
+        let paused = paused_status(Some(4), Some(&ready));
+        assert_eq!(paused.phase, "Paused");
+        assert_eq!(paused.observed_generation, Some(4));
+        assert_eq!(paused.database_used_mb, Some(120));
+        assert_eq!(paused.database_quota_mb, Some(256));
+        assert_eq!(paused_status(Some(4), None).database_used_mb, None);
+    }
+}
@@ -0,0 +1,77 @@
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api, Resource,
+};
+use serde_json::json;
+
+use crate::{error::Result, KwpmClient};
+
+/// Set to `true` on a `WordPressSite` or a site namespace, it keeps the operator and
+/// remediation from touching the site, so its objects can be edited by hand during an
+/// incident.
+pub const PAUSED_ANNOTATION: &str = "kwpm.io/paused";
+
+pub fn is_paused(metadata: &ObjectMeta) -> bool {
+    metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(PAUSED_ANNOTATION))
+        .is_some_and(|value| value == "true")
+}
+
+impl KwpmClient {
+    /// Whether the site's namespace is annotated as paused.
+    pub async fn is_site_paused(&self, site: &str) -> Result<bool> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = namespace_api.get(&self.site_namespace(site)).await?;
+        Ok(is_paused(namespace.meta()))
+    }
+
+    /// Annotates the site's namespace as paused or removes the annotation. Resumed sites are
+    /// converged again at the operator's next resync.
+    #[tracing::instrument(skip_all, fields(site = %site, paused))]
+    pub async fn set_site_paused(&self, site: &str, paused: bool) -> Result<()> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let value = paused.then_some("true");
+        namespace_api
+            .patch(
+                &self.site_namespace(site),
+                &PatchParams::default(),
+                &Patch::Merge(json!({
+                    "metadata": { "annotations": { PAUSED_ANNOTATION: value } }
+                })),
+            )
+            .await?;
+        self.record_audit(
+            site,
+            "set_site_paused",
+            match paused {
+                true => "paused reconciliation",
+                false => "resumed reconciliation",
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_is_paused() {
+        let metadata = |value: &str| ObjectMeta {
+            annotations: Some(BTreeMap::from([(
+                PAUSED_ANNOTATION.to_string(),
+                value.to_string(),
+            )])),
+            ..Default::default()
+        };
+        assert!(is_paused(&metadata("true")));
+        assert!(!is_paused(&metadata("false")));
+        assert!(!is_paused(&ObjectMeta::default()));
+    }
+}
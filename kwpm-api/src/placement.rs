@@ -0,0 +1,437 @@
+use std::collections::BTreeSet;
+
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::CronJob,
+    core::v1::{Namespace, Secret},
+};
+use kube::{
+    api::{Patch, PatchParams},
+    Api, ResourceExt,
+};
+use serde_json::json;
+
+use crate::{
+    database_job,
+    error::{bail, KwpmError, Result},
+    manifest::{cron_job_pod_spec_mut, deployment_pod_spec_mut, job_pod_spec_mut, set_env},
+    mariadb::{
+        is_valid_mariadb_instance, mariadb_host, mariadb_namespace, namespace_mariadb_instance,
+        replace_mariadb_host, DEFAULT_MARIADB_INSTANCE, MARIADB_INSTANCE_LABEL,
+    },
+    metadata::ensure_managed,
+    provision::{
+        database_password_secret_name, with_password_secret, DatabaseConfig, DATABASE_JOB_TIMEOUT,
+        SITE_LABEL,
+    },
+    rollback::Rollback,
+    secret_value, DatabaseAction, KwpmClient,
+};
+
+/// What a new site is sold and sized as, deciding where its database goes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SitePlan {
+    /// The plan the site is on, e.g. `starter` or `enterprise`.
+    pub name: String,
+    /// How large the site's database is expected to grow, in MiB.
+    pub expected_database_mb: Option<u64>,
+}
+
+/// Where a site's database is placed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DatabasePlacement {
+    /// A schema on an instance other sites use as well.
+    Shared(String),
+    /// An instance of its own, named after the site.
+    Dedicated(String),
+}
+
+impl DatabasePlacement {
+    pub fn instance(&self) -> &str {
+        match self {
+            DatabasePlacement::Shared(instance) | DatabasePlacement::Dedicated(instance) => {
+                instance
+            }
+        }
+    }
+}
+
+/// Decides whether new sites get a schema on the shared MariaDB or an instance of their own.
+/// By default every site shares the default instance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabasePlacementPolicy {
+    /// The instance sites share.
+    pub shared_instance: String,
+    /// Plans whose sites get a dedicated instance.
+    pub dedicated_plans: BTreeSet<String>,
+    /// Sites expecting a larger database than this get a dedicated instance, in MiB.
+    pub dedicated_above_mb: Option<u64>,
+}
+
+impl Default for DatabasePlacementPolicy {
+    fn default() -> Self {
+        Self {
+            shared_instance: DEFAULT_MARIADB_INSTANCE.to_string(),
+            dedicated_plans: BTreeSet::new(),
+            dedicated_above_mb: None,
+        }
+    }
+}
+
+impl DatabasePlacementPolicy {
+    pub fn place(&self, site: &str, plan: &SitePlan) -> DatabasePlacement {
+        let large = match (plan.expected_database_mb, self.dedicated_above_mb) {
+            (Some(expected), Some(limit)) => expected > limit,
+            _ => false,
+        };
+        if large || self.dedicated_plans.contains(&plan.name) {
+            DatabasePlacement::Dedicated(site.to_string())
+        } else {
+            DatabasePlacement::Shared(self.shared_instance.clone())
+        }
+    }
+}
+
+impl KwpmClient {
+    pub fn with_database_placement(mut self, policy: DatabasePlacementPolicy) -> Self {
+        self.database_placement = policy;
+        self
+    }
+
+    pub fn database_placement(&self, site: &str, plan: &SitePlan) -> DatabasePlacement {
+        self.database_placement.place(site, plan)
+    }
+
+    /// Like `database_config_for_site`, with the database on the instance the placement
+    /// policy chooses for `plan`. Dedicated instances have to be created with
+    /// `create_mariadb_instance` before the site.
+    pub fn database_config_for_plan(&self, site: &str, plan: &SitePlan) -> Result<DatabaseConfig> {
+        Ok(self
+            .database_config_for_site(site)?
+            .on_instance(self.database_placement(site, plan).instance()))
+    }
+
+    /// Moves the database of `site` to MariaDB `target_instance`, e.g. onto a dedicated one
+    /// when it outgrew the shared instance. The site is stopped while the database is copied,
+    /// and if copying or switching the site over fails, the site is pointed back at the source
+    /// and the copy dropped again. The source database is only dropped once the site runs on
+    /// the target.
+    #[tracing::instrument(skip_all, fields(site = %site, instance = %target_instance))]
+    pub async fn migrate_database(&self, site: &str, target_instance: &str) -> Result<()> {
+        if !is_valid_mariadb_instance(target_instance) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid MariaDB instance: {}",
+                target_instance
+            )));
+        }
+        let ns_name = self.site_namespace(site);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = namespace_api
+            .get_opt(&ns_name)
+            .await?
+            .ok_or_else(|| KwpmError::not_found(format!("site {} does not exist", site)))?;
+        ensure_managed(&namespace, Some((SITE_LABEL, site)))?;
+        let source_instance = namespace_mariadb_instance(&namespace);
+        if source_instance == target_instance {
+            return Err(KwpmError::invalid_input(format!(
+                "the database of {} is on MariaDB {} already",
+                site, target_instance
+            )));
+        }
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        let secret = secret_api
+            .get_opt("mysql-pass")
+            .await?
+            .ok_or_else(|| KwpmError::invalid_input(format!("{} has no database", site)))?;
+        if !self.is_mariadb_instance_ready(target_instance).await? {
+            bail!("MariaDB {} is not ready", target_instance);
+        }
+        let database = DatabaseConfig {
+            database: secret_value(&secret, "db_name")?,
+            user: secret_value(&secret, "user")?,
+            password: secret_value(&secret, "password")?,
+            table_prefix: secret_value(&secret, "table_prefix").unwrap_or_else(|_| "wp_".into()),
+            instance: target_instance.to_string(),
+        };
+
+        let lock = self.lock_site(site, "migrate_database").await?;
+        let result: Result<_> = async {
+            let mut progress = self.start_operation(site, "migrate_database").await;
+            // Every failure ends up here, so the operation never stays running.
+            let result: Result<_> = async {
+                let deployment_api: Api<Deployment> =
+                    Api::namespaced(self.client.clone(), &ns_name);
+                let original = deployment_api.get("wordpress").await?;
+
+                progress.step("stopping the site", Some(10)).await;
+                deployment_api
+                    .patch(
+                        "wordpress",
+                        &Default::default(),
+                        &Patch::Merge(json!({ "spec": { "replicas": 0 } })),
+                    )
+                    .await?;
+
+                progress.step("copying the database", Some(30)).await;
+                let operation = format!("migrating the database of {}", site);
+                let mut rollback = Rollback::default();
+                if let Err(e) = self
+                    .copy_site_database(site, &source_instance, &database, &mut rollback)
+                    .await
+                {
+                    let replicas = original.spec.as_ref().and_then(|spec| spec.replicas);
+                    if let Err(restart) = deployment_api
+                        .patch(
+                            "wordpress",
+                            &Default::default(),
+                            &Patch::Merge(json!({ "spec": { "replicas": replicas } })),
+                        )
+                        .await
+                    {
+                        tracing::warn!(
+                            site = %site,
+                            error = %format!("{:#}", restart),
+                            "restarting the site failed"
+                        );
+                    }
+                    return Err(self.roll_back(&operation, rollback, e).await);
+                }
+
+                progress.step("switching the site", Some(70)).await;
+                let mut switched = Vec::new();
+                if let Err(e) = self
+                    .switch_site_database(
+                        site,
+                        &original,
+                        &source_instance,
+                        target_instance,
+                        &mut switched,
+                    )
+                    .await
+                {
+                    let e = match self
+                        .undo_database_switch(site, &original, &switched, &source_instance)
+                        .await
+                    {
+                        Ok(()) => e,
+                        Err(undo) => anyhow::Error::from(e)
+                            .context(format!(
+                                "switching back to MariaDB {} failed too, check the site's \
+                                 deployment, cron jobs and {} label: {:#}",
+                                source_instance, MARIADB_INSTANCE_LABEL, undo
+                            ))
+                            .into(),
+                    };
+                    return Err(self.roll_back(&operation, rollback, e).await);
+                }
+
+                lock.ensure_held()?;
+                progress
+                    .step("dropping the source database", Some(90))
+                    .await;
+                self.drop_site_database(site, &source_instance, &secret)
+                    .await?;
+                self.record_audit(
+                    site,
+                    "migrate_database",
+                    &format!("{} -> {}", source_instance, target_instance),
+                )
+                .await?;
+                self.record_site_revision(site, "migrate_database").await?;
+                Ok(())
+            }
+            .await;
+            match &result {
+                Ok(()) => progress.succeed().await,
+                Err(e) => progress.fail(e).await,
+            }
+            result
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Points the site's deployment, with the replicas of `original`, and its cron jobs at
+    /// `target_instance` and labels its namespace with it. The cron jobs replaced so far are
+    /// added to `switched` as they were.
+    pub(crate) async fn switch_site_database(
+        &self,
+        site: &str,
+        original: &Deployment,
+        source_instance: &str,
+        target_instance: &str,
+        switched: &mut Vec<CronJob>,
+    ) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let mut deployment = deployment_api.get("wordpress").await?;
+        replace_mariadb_host(
+            deployment_pod_spec_mut(&mut deployment)?,
+            source_instance,
+            target_instance,
+        );
+        if let Some(spec) = deployment.spec.as_mut() {
+            spec.replicas = original.spec.as_ref().and_then(|spec| spec.replicas);
+        }
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+
+        let cron_job_api: Api<CronJob> = Api::namespaced(self.client.clone(), &ns_name);
+        for cron_job in cron_job_api.list(&Default::default()).await? {
+            let mut switched_cron_job = cron_job.clone();
+            if replace_mariadb_host(
+                cron_job_pod_spec_mut(&mut switched_cron_job)?,
+                source_instance,
+                target_instance,
+            ) {
+                cron_job_api
+                    .replace(
+                        &cron_job.name_any(),
+                        &Default::default(),
+                        &switched_cron_job,
+                    )
+                    .await?;
+                switched.push(cron_job);
+            }
+        }
+
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        namespace_api
+            .patch(
+                &ns_name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({
+                    "metadata": { "labels": { MARIADB_INSTANCE_LABEL: target_instance } }
+                })),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Puts back the spec of the site's deployment and of the `switched` cron jobs and the
+    /// namespace label naming `source_instance` after `switch_site_database` failed. Every step
+    /// is attempted, failing with the ones that failed.
+    async fn undo_database_switch(
+        &self,
+        site: &str,
+        original: &Deployment,
+        switched: &[CronJob],
+        source_instance: &str,
+    ) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let cron_job_api: Api<CronJob> = Api::namespaced(self.client.clone(), &ns_name);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let mut failed = Vec::new();
+
+        let deployment = async {
+            let mut deployment = deployment_api.get("wordpress").await?;
+            deployment.spec = original.spec.clone();
+            deployment_api
+                .replace("wordpress", &Default::default(), &deployment)
+                .await?;
+            Ok::<_, KwpmError>(())
+        };
+        if let Err(e) = deployment.await {
+            failed.push(format!("deployment wordpress ({:#})", e));
+        }
+        for original in switched {
+            let name = original.name_any();
+            let cron_job = async {
+                let mut cron_job = cron_job_api.get(&name).await?;
+                cron_job.spec = original.spec.clone();
+                cron_job_api
+                    .replace(&name, &Default::default(), &cron_job)
+                    .await?;
+                Ok::<_, KwpmError>(())
+            };
+            if let Err(e) = cron_job.await {
+                failed.push(format!("cron job {} ({:#})", name, e));
+            }
+        }
+        if let Err(e) = namespace_api
+            .patch(
+                &ns_name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({
+                    "metadata": { "labels": { MARIADB_INSTANCE_LABEL: source_instance } }
+                })),
+            )
+            .await
+        {
+            failed.push(format!("namespace label ({:#})", e));
+        }
+
+        if !failed.is_empty() {
+            bail!("restoring {} failed", failed.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Creates `database` on its instance and copies the data of the site's database on
+    /// `source_instance` into it.
+    async fn copy_site_database(
+        &self,
+        site: &str,
+        source_instance: &str,
+        database: &DatabaseConfig,
+        rollback: &mut Rollback,
+    ) -> Result<()> {
+        self.create_site_database(site, database, rollback).await?;
+
+        let mut job = database_job(DatabaseAction::Copy, &database.database, &database.user)?;
+        set_env(
+            job_pod_spec_mut(&mut job)?,
+            "database",
+            &[("SOURCE_HOST", mariadb_host(source_instance))],
+        )?;
+        self.run_job(
+            &mariadb_namespace(&database.instance),
+            with_password_secret(job, &database_password_secret_name(site))?,
+            DATABASE_JOB_TIMEOUT,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_placement() {
+        let policy = DatabasePlacementPolicy {
+            dedicated_plans: BTreeSet::from(["enterprise".to_string()]),
+            dedicated_above_mb: Some(2048),
+            ..Default::default()
+        };
+        let plan = |name: &str, expected_database_mb| SitePlan {
+            name: name.to_string(),
+            expected_database_mb,
+        };
+
+        assert_eq!(
+            policy.place("blog", &plan("starter", None)),
+            DatabasePlacement::Shared("default".to_string())
+        );
+        assert_eq!(
+            policy.place("blog", &plan("starter", Some(2048))),
+            DatabasePlacement::Shared("default".to_string())
+        );
+        assert_eq!(
+            policy.place("shop", &plan("starter", Some(4096))),
+            DatabasePlacement::Dedicated("shop".to_string())
+        );
+        assert_eq!(
+            policy.place("acme", &plan("enterprise", None)).instance(),
+            "acme"
+        );
+        assert_eq!(
+            DatabasePlacementPolicy::default().place("shop", &plan("enterprise", Some(4096))),
+            DatabasePlacement::Shared("default".to_string())
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{bail, KwpmError, Result},
+    KwpmClient,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExtensionKind {
+    Plugin,
+    Theme,
+}
+
+impl ExtensionKind {
+    /// The WP-CLI command managing this kind.
+    fn command(self) -> &'static str {
+        match self {
+            ExtensionKind::Plugin => "plugin",
+            ExtensionKind::Theme => "theme",
+        }
+    }
+}
+
+fn default_active() -> bool {
+    true
+}
+
+/// A plugin or theme from wordpress.org a site should have, kept in its spec.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Extension {
+    /// The wordpress.org slug like `akismet`.
+    pub slug: String,
+    /// Pinned version, the latest one when installed if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+impl Extension {
+    pub fn check(&self) -> Result<()> {
+        if !is_valid_slug(&self.slug) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid slug: {}",
+                self.slug
+            )));
+        }
+        if let Some(version) = self.version.as_deref().filter(|v| !is_valid_version(v)) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid version of {}: {}",
+                self.slug, version
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A plugin or theme as `wp plugin list` or `wp theme list` report it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledExtension {
+    pub name: String,
+    /// `active`, `inactive`, `must-use` or `dropin` for plugins, `active`, `inactive` or
+    /// `parent` for themes.
+    pub status: String,
+    pub version: String,
+    /// The newer version available, if any.
+    #[serde(default, alias = "update_version", deserialize_with = "empty_as_none")]
+    pub update_version: Option<String>,
+    /// Whether the site's spec declares it.
+    #[serde(default)]
+    pub managed: bool,
+}
+
+impl InstalledExtension {
+    fn is_active(&self) -> bool {
+        self.status == "active" || self.status == "active-network"
+    }
+}
+
+fn empty_as_none<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.filter(|value| !value.is_empty()))
+}
+
+/// wordpress.org slugs like `wordpress-seo`.
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 200
+        && slug.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_'))
+}
+
+/// Versions like `6.5.2` or `2.0-beta1`.
+pub fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version.len() <= 64
+        && version.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Parses `wp plugin list --format=json` or `wp theme list --format=json`.
+pub fn parse_extension_list(output: &str) -> Result<Vec<InstalledExtension>> {
+    Ok(serde_json::from_str(output.trim())?)
+}
+
+/// Adds `extension` to `declared` or replaces the one with its slug. Only one theme can be
+/// active, so activating a theme deactivates the others.
+pub fn declare_extension(kind: ExtensionKind, declared: &mut Vec<Extension>, extension: Extension) {
+    if kind == ExtensionKind::Theme && extension.active {
+        for other in declared.iter_mut() {
+            other.active = false;
+        }
+    }
+    match declared.iter_mut().find(|e| e.slug == extension.slug) {
+        Some(existing) => *existing = extension,
+        None => declared.push(extension),
+    }
+}
+
+/// The WP-CLI commands bringing `installed` to `declared`. Extensions that aren't declared are
+/// left alone, and an active theme can only be replaced by activating another.
+pub fn extension_commands(
+    kind: ExtensionKind,
+    declared: &[Extension],
+    installed: &[InstalledExtension],
+) -> Vec<Vec<String>> {
+    let command = kind.command();
+    let mut commands = Vec::new();
+    for extension in declared {
+        let current = installed.iter().find(|i| i.name == extension.slug);
+        let reinstall = current.is_some_and(|current| {
+            extension
+                .version
+                .as_ref()
+                .is_some_and(|version| *version != current.version)
+        });
+        if current.is_none() || reinstall {
+            let mut args = vec![
+                command.to_string(),
+                "install".to_string(),
+                extension.slug.clone(),
+            ];
+            if let Some(version) = &extension.version {
+                args.push(format!("--version={}", version));
+            }
+            if reinstall {
+                args.push("--force".to_string());
+            }
+            if extension.active {
+                args.push("--activate".to_string());
+            }
+            commands.push(args);
+            continue;
+        }
+        let active = current.is_some_and(InstalledExtension::is_active);
+        let change = match (extension.active, active, kind) {
+            (true, false, _) => "activate",
+            (false, true, ExtensionKind::Plugin) => "deactivate",
+            _ => continue,
+        };
+        commands.push(vec![
+            command.to_string(),
+            change.to_string(),
+            extension.slug.clone(),
+        ]);
+    }
+    commands
+}
+
+fn check_declared(kind: ExtensionKind, declared: &[Extension]) -> Result<()> {
+    for extension in declared {
+        extension.check()?;
+    }
+    if kind == ExtensionKind::Theme && declared.iter().filter(|e| e.active).count() > 1 {
+        return Err(KwpmError::invalid_input("only one theme can be active"));
+    }
+    Ok(())
+}
+
+fn describe(extension: &Extension) -> String {
+    match &extension.version {
+        Some(version) => format!("{} {}", extension.slug, version),
+        None => extension.slug.clone(),
+    }
+}
+
+impl KwpmClient {
+    async fn list_extensions(
+        &self,
+        site: &str,
+        kind: ExtensionKind,
+    ) -> Result<Vec<InstalledExtension>> {
+        let spec = self.stored_site_spec(site).await?;
+        if !spec.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        let declared = match kind {
+            ExtensionKind::Plugin => &spec.plugins,
+            ExtensionKind::Theme => &spec.themes,
+        };
+        let args: Vec<String> = [
+            kind.command(),
+            "list",
+            "--format=json",
+            "--fields=name,status,version,update_version",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let output = self.wp_cli_output_unaudited(site, &args).await?;
+        let mut installed = parse_extension_list(&output)?;
+        for extension in &mut installed {
+            extension.managed = declared.iter().any(|e| e.slug == extension.name);
+        }
+        Ok(installed)
+    }
+
+    /// The site's plugins with their status, version and available update.
+    pub async fn list_plugins(&self, site: &str) -> Result<Vec<InstalledExtension>> {
+        self.list_extensions(site, ExtensionKind::Plugin).await
+    }
+
+    pub async fn list_themes(&self, site: &str) -> Result<Vec<InstalledExtension>> {
+        self.list_extensions(site, ExtensionKind::Theme).await
+    }
+
+    async fn run_extension_commands(&self, site: &str, commands: &[Vec<String>]) -> Result<()> {
+        for command in commands {
+            self.wp_cli_output_unaudited(site, command).await?;
+        }
+        Ok(())
+    }
+
+    async fn install_extension(
+        &self,
+        site: &str,
+        kind: ExtensionKind,
+        extension: Extension,
+    ) -> Result<()> {
+        extension.check()?;
+        let operation = format!("install_{}", kind.command());
+        let mut spec = self.get_site_spec(site).await?;
+        if !spec.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        self.check_policy(&operation, Some(site), &[json!(extension)])
+            .await?;
+
+        let installed = self.list_extensions(site, kind).await?;
+        let commands = extension_commands(kind, std::slice::from_ref(&extension), &installed);
+        self.run_extension_commands(site, &commands).await?;
+
+        let message = describe(&extension);
+        let declared = match kind {
+            ExtensionKind::Plugin => &mut spec.plugins,
+            ExtensionKind::Theme => &mut spec.themes,
+        };
+        declare_extension(kind, declared, extension);
+        self.save_site_spec(site, &spec).await?;
+        self.record_audit(site, &operation, &message).await
+    }
+
+    /// Installs a plugin from wordpress.org, or moves it to the pinned version, activates or
+    /// deactivates it and adds it to the site's spec so `sync_site_extensions` keeps it.
+    #[tracing::instrument(skip_all, fields(site = %site, plugin = %plugin.slug))]
+    pub async fn install_plugin(&self, site: &str, plugin: Extension) -> Result<()> {
+        self.install_extension(site, ExtensionKind::Plugin, plugin)
+            .await
+    }
+
+    /// Like `install_plugin` for a theme. Activating it deactivates the previous theme.
+    #[tracing::instrument(skip_all, fields(site = %site, theme = %theme.slug))]
+    pub async fn install_theme(&self, site: &str, theme: Extension) -> Result<()> {
+        self.install_extension(site, ExtensionKind::Theme, theme)
+            .await
+    }
+
+    /// Deactivates and deletes a plugin and removes it from the site's spec. Its data stays in
+    /// the database, as its uninstall hook isn't run.
+    #[tracing::instrument(skip_all, fields(site = %site, plugin = %slug))]
+    pub async fn remove_plugin(&self, site: &str, slug: &str) -> Result<()> {
+        if !is_valid_slug(slug) {
+            return Err(KwpmError::invalid_input(format!("invalid slug: {}", slug)));
+        }
+        let mut spec = self.get_site_spec(site).await?;
+        if !spec.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        self.check_policy("remove_plugin", Some(site), &[json!({ "slug": slug })])
+            .await?;
+
+        let installed = self.list_extensions(site, ExtensionKind::Plugin).await?;
+        let Some(plugin) = installed.iter().find(|p| p.name == slug) else {
+            if !spec.plugins.iter().any(|p| p.slug == slug) {
+                return Err(KwpmError::not_found(format!(
+                    "{} has no plugin {}",
+                    site, slug
+                )));
+            }
+            spec.plugins.retain(|p| p.slug != slug);
+            self.save_site_spec(site, &spec).await?;
+            return self.record_audit(site, "remove_plugin", slug).await;
+        };
+        let mut commands = Vec::new();
+        if plugin.is_active() {
+            commands.push(vec!["plugin", "deactivate", slug]);
+        }
+        commands.push(vec!["plugin", "delete", slug]);
+        let commands: Vec<Vec<String>> = commands
+            .into_iter()
+            .map(|command| command.into_iter().map(str::to_string).collect())
+            .collect();
+        self.run_extension_commands(site, &commands).await?;
+
+        spec.plugins.retain(|p| p.slug != slug);
+        self.save_site_spec(site, &spec).await?;
+        self.record_audit(site, "remove_plugin", slug).await
+    }
+
+    /// Installs, upgrades, activates and deactivates the plugins and themes of the site's spec
+    /// until the site matches it, returning the WP-CLI commands run. Plugins and themes that
+    /// aren't in the spec are left alone.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn sync_site_extensions(&self, site: &str) -> Result<Vec<String>> {
+        let spec = self.get_site_spec(site).await?;
+        if !spec.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        check_declared(ExtensionKind::Plugin, &spec.plugins)?;
+        check_declared(ExtensionKind::Theme, &spec.themes)?;
+        if spec.plugins.is_empty() && spec.themes.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.check_policy(
+            "sync_site_extensions",
+            Some(site),
+            &[json!({ "plugins": spec.plugins, "themes": spec.themes })],
+        )
+        .await?;
+
+        let mut commands = extension_commands(
+            ExtensionKind::Plugin,
+            &spec.plugins,
+            &self.list_plugins(site).await?,
+        );
+        commands.extend(extension_commands(
+            ExtensionKind::Theme,
+            &spec.themes,
+            &self.list_themes(site).await?,
+        ));
+        if commands.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.run_extension_commands(site, &commands).await?;
+
+        let commands: Vec<String> = commands.iter().map(|command| command.join(" ")).collect();
+        self.record_audit(site, "sync_site_extensions", &commands.join("; "))
+            .await?;
+        Ok(commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extension(slug: &str, version: Option<&str>, active: bool) -> Extension {
+        Extension {
+            slug: slug.to_string(),
+            version: version.map(str::to_string),
+            active,
+        }
+    }
+
+    #[test]
+    fn test_check_extension() {
+        assert!(extension("wordpress-seo", Some("22.5"), true)
+            .check()
+            .is_ok());
+        assert!(extension("akismet", None, true).check().is_ok());
+        assert!(extension("Akismet", None, true).check().is_err());
+        assert!(extension("--help", None, true).check().is_err());
+        assert!(extension("../akismet", None, true).check().is_err());
+        assert!(extension("akismet", Some("5.3 --force"), true)
+            .check()
+            .is_err());
+        assert!(check_declared(
+            ExtensionKind::Theme,
+            &[
+                extension("twentytwentyfour", None, true),
+                extension("astra", None, true)
+            ]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_extension_list() {
+        let installed = parse_extension_list(
+            r#"[{"name":"akismet","status":"inactive","version":"5.3","update_version":"5.3.1"},
+                {"name":"hello","status":"active","version":"1.7.2","update_version":""}]"#,
+        )
+        .unwrap();
+        assert_eq!(installed[0].update_version.as_deref(), Some("5.3.1"));
+        assert_eq!(installed[1].update_version, None);
+        assert!(installed[1].is_active());
+        assert!(!installed[1].managed);
+    }
+
+    #[test]
+    fn test_extension_commands() {
+        let installed = parse_extension_list(
+            r#"[{"name":"akismet","status":"inactive","version":"5.3"},
+                {"name":"hello","status":"active","version":"1.7.2"},
+                {"name":"jetpack","status":"active","version":"13.0"}]"#,
+        )
+        .unwrap();
+        let declared = [
+            extension("akismet", None, true),
+            extension("hello", None, false),
+            extension("jetpack", Some("13.3"), true),
+            extension("wordpress-seo", Some("22.5"), false),
+        ];
+        assert_eq!(
+            extension_commands(ExtensionKind::Plugin, &declared, &installed),
+            [
+                vec!["plugin", "activate", "akismet"],
+                vec!["plugin", "deactivate", "hello"],
+                vec![
+                    "plugin",
+                    "install",
+                    "jetpack",
+                    "--version=13.3",
+                    "--force",
+                    "--activate"
+                ],
+                vec!["plugin", "install", "wordpress-seo", "--version=22.5"],
+            ]
+        );
+        assert!(extension_commands(ExtensionKind::Theme, &declared[1..2], &installed).is_empty());
+        assert_eq!(
+            extension_commands(ExtensionKind::Plugin, &declared[..1], &[]),
+            [vec!["plugin", "install", "akismet", "--activate"]]
+        );
+    }
+
+    #[test]
+    fn test_declare_extension() {
+        let mut themes = vec![extension("twentytwentyfour", None, true)];
+        declare_extension(
+            ExtensionKind::Theme,
+            &mut themes,
+            extension("astra", Some("4.6"), true),
+        );
+        assert_eq!(
+            themes,
+            [
+                extension("twentytwentyfour", None, false),
+                extension("astra", Some("4.6"), true)
+            ]
+        );
+        declare_extension(
+            ExtensionKind::Theme,
+            &mut themes,
+            extension("astra", None, true),
+        );
+        assert_eq!(themes.len(), 2);
+        assert_eq!(themes[1].version, None);
+    }
+}
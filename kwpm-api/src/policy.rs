@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+const POLICY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub allowed: bool,
+    pub violations: Vec<String>,
+}
+
+impl PolicyDecision {
+    /// Reads an OPA data API response. The policy may return a plain boolean or an object with
+    /// `allow` and a `deny` list of messages; an undefined result denies the operation.
+    pub fn from_response(response: &Value) -> Result<Self> {
+        match response.get("result") {
+            Some(Value::Bool(allowed)) => Ok(Self {
+                allowed: *allowed,
+                violations: Vec::new(),
+            }),
+            Some(Value::Object(result)) => {
+                let violations: Vec<String> = result
+                    .get("deny")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .map(|v| match v {
+                        Value::String(message) => message.clone(),
+                        other => other.to_string(),
+                    })
+                    .collect();
+                let allowed = result
+                    .get("allow")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(violations.is_empty());
+
+                Ok(Self {
+                    allowed: allowed && violations.is_empty(),
+                    violations,
+                })
+            }
+            None => bail!("policy returned no result, is the policy path defined?"),
+            Some(other) => bail!("unexpected policy result: {}", other),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PolicyInput<'a> {
+    operation: &'a str,
+    site: Option<&'a str>,
+    resources: &'a [Value],
+}
+
+impl KwpmClient {
+    /// Evaluates an operation against the external policy endpoint, if one is configured, before
+    /// anything is applied. The rendered resources are passed as `input.resources`.
+    pub(crate) async fn check_policy(
+        &self,
+        operation: &str,
+        site: Option<&str>,
+        resources: &[Value],
+    ) -> Result<()> {
+        let Some(endpoint) = &self.policy_endpoint else {
+            return Ok(());
+        };
+
+        let input = PolicyInput {
+            operation,
+            site,
+            resources,
+        };
+        let response: Value = reqwest::Client::new()
+            .post(endpoint)
+            .timeout(POLICY_TIMEOUT)
+            .json(&json!({ "input": input }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("policy endpoint {} is unreachable", endpoint))?
+            .json()
+            .await
+            .context("policy endpoint returned invalid JSON")?;
+
+        let decision = PolicyDecision::from_response(&response)?;
+        if !decision.allowed {
+            bail!(
+                "{} denied by policy: {}",
+                operation,
+                if decision.violations.is_empty() {
+                    "not allowed".to_string()
+                } else {
+                    decision.violations.join("; ")
+                }
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boolean_decision() {
+        let decision = PolicyDecision::from_response(&json!({ "result": true })).unwrap();
+        assert!(decision.allowed);
+
+        let decision = PolicyDecision::from_response(&json!({ "result": false })).unwrap();
+        assert!(!decision.allowed);
+    }
+
+    #[test]
+    fn test_deny_messages() {
+        let decision = PolicyDecision::from_response(&json!({
+            "result": { "allow": true, "deny": ["image wordpress:latest is not pinned"] }
+        }))
+        .unwrap();
+
+        assert!(!decision.allowed);
+        assert_eq!(
+            decision.violations,
+            ["image wordpress:latest is not pinned"]
+        );
+    }
+
+    #[test]
+    fn test_undefined_policy_denies() {
+        assert!(PolicyDecision::from_response(&json!({})).is_err());
+    }
+}
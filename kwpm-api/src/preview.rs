@@ -0,0 +1,636 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{ConfigMap, Namespace, PersistentVolume, PersistentVolumeClaim, Secret, Service},
+    networking::v1::Ingress,
+};
+use kube::{
+    api::{ListParams, ObjectMeta, Patch},
+    Api, Resource, ResourceExt,
+};
+
+use crate::{
+    backup::{backup_job, new_backup_id, restore_site_job, BackupMode},
+    canary::CANARY_NAME,
+    error::{bail, Result},
+    job::is_job_succeeded,
+    manifest::{deployment_pod_spec_mut, is_local_volume},
+    mariadb::{namespace_mariadb_instance, MARIADB_INSTANCE_LABEL},
+    metadata::ensure_managed,
+    provision::{database_secret, generate_database_password, DatabaseConfig, SITE_LABEL},
+    rollback::{CreatedResource, Rollback},
+    secret_value, KwpmClient, MAX_DATABASE_NAME_LEN,
+};
+
+pub(crate) const PREVIEW_OF_LABEL: &str = "kwpm.io/preview-of";
+const PREVIEW_REF_ANNOTATION: &str = "kwpm.io/preview-ref";
+const EXPIRES_AT_ANNOTATION: &str = "kwpm.io/expires-at";
+const PREVIEW_JOB_TIMEOUT: Duration = Duration::from_secs(1800);
+const PREVIEW_READY_TIMEOUT: Duration = Duration::from_secs(600);
+const PREVIEW_CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+const MAX_NAMESPACE_LEN: usize = 63;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preview {
+    pub site: String,
+    /// Site name of the preview, usable with every other site operation.
+    pub name: String,
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Short DNS-safe name for a git ref: `pr-42` for pull request refs, otherwise the ref with
+/// every other character replaced by `-`.
+pub fn ref_slug(git_ref: &str) -> String {
+    let pull = git_ref
+        .strip_prefix("refs/pull/")
+        .and_then(|rest| rest.split('/').next())
+        .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+    if let Some(number) = pull {
+        return format!("pr-{}", number);
+    }
+
+    let slug: String = git_ref
+        .trim_start_matches("refs/heads/")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    slug.split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Site name of the preview of `site` for `git_ref`, e.g. `blog-pr-42`, short enough for a
+/// namespace starting with `namespace_prefix`.
+pub fn preview_name(site: &str, git_ref: &str, namespace_prefix: &str) -> Result<String> {
+    let slug = ref_slug(git_ref);
+    if slug.is_empty() {
+        bail!("cannot derive a preview name from ref {:?}", git_ref);
+    }
+
+    let max_len = MAX_NAMESPACE_LEN - namespace_prefix.len();
+    let mut name = format!("{}-{}", site, slug);
+    if name.len() > max_len {
+        name.truncate(max_len);
+        name = name.trim_end_matches('-').to_string();
+    }
+    Ok(name)
+}
+
+/// `<preview>.<preview_domain>`, or `<ref slug>.preview.<site host>` without a preview domain.
+pub fn preview_host(preview: &str, site: &str, site_host: &str, domain: Option<&str>) -> String {
+    match domain {
+        Some(domain) => format!("{}.{}", preview, domain),
+        None => format!(
+            "{}.preview.{}",
+            preview
+                .strip_prefix(site)
+                .map(|slug| slug.trim_start_matches('-'))
+                .filter(|slug| !slug.is_empty())
+                .unwrap_or(preview),
+            site_host
+        ),
+    }
+}
+
+pub fn preview_database_name(database: &str, preview: &str) -> String {
+    let slug = ref_slug(preview).replace('-', "_");
+    let mut name = format!("{}_{}", database, slug);
+    name.truncate(MAX_DATABASE_NAME_LEN);
+    name
+}
+
+/// Points the ingress at `host` and moves its certificate into a preview-specific secret.
+pub fn rewrite_ingress_host(ingress: &mut Ingress, host: &str) {
+    let Some(spec) = ingress.spec.as_mut() else {
+        return;
+    };
+    for rule in spec.rules.iter_mut().flatten() {
+        rule.host = Some(host.to_string());
+    }
+    for tls in spec.tls.iter_mut().flatten() {
+        tls.hosts = Some(vec![host.to_string()]);
+        tls.secret_name = Some("wordpress-preview-tls".to_string());
+    }
+}
+
+pub fn is_preview_expired(namespace: &Namespace, now: DateTime<Utc>) -> bool {
+    namespace
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(EXPIRES_AT_ANNOTATION))
+        .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+        .is_some_and(|expires_at| expires_at < now)
+}
+
+/// Keeps name, labels and annotations so the copy can be created in another namespace.
+fn copy_metadata<K: Resource>(object: &K, namespace: &str) -> ObjectMeta {
+    let meta = object.meta();
+    ObjectMeta {
+        name: meta.name.clone(),
+        namespace: Some(namespace.to_string()),
+        labels: meta.labels.clone(),
+        annotations: meta.annotations.clone().map(|annotations| {
+            annotations
+                .into_iter()
+                .filter(|(key, _)| !key.starts_with("kubectl.kubernetes.io/"))
+                .filter(|(key, _)| !key.starts_with("deployment.kubernetes.io/"))
+                .collect()
+        }),
+        ..Default::default()
+    }
+}
+
+impl KwpmClient {
+    /// Sets the base domain of preview hostnames, e.g. `preview.example.com` with a wildcard
+    /// DNS record and certificate.
+    pub fn with_preview_domain(mut self, domain: impl ToString) -> Self {
+        self.preview_domain = Some(domain.to_string());
+        self
+    }
+
+    /// Clones `site` into a preview site for `git_ref` that is deleted once `ttl` has passed.
+    /// Calling it again for the same ref extends the lifetime of the existing preview. The
+    /// preview gets a database user of its own, and is deleted again if creating it fails.
+    pub async fn create_preview(
+        &self,
+        site: &str,
+        git_ref: &str,
+        ttl: Duration,
+    ) -> Result<Preview> {
+        self.cleanup_expired_previews().await?;
+
+        let name = preview_name(site, git_ref, &self.namespace_prefix)?;
+        let ns_name = self.site_namespace(&name);
+        let site_ns = self.site_namespace(site);
+        let expires_at = Utc::now() + chrono::Duration::from_std(ttl)?;
+
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let site_url = self.site_url(site).await?;
+        let site_host = site_url
+            .split("://")
+            .nth(1)
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let host = preview_host(&name, site, site_host, self.preview_domain.as_deref());
+        let scheme = site_url.split("://").next().unwrap_or("http");
+        let preview = Preview {
+            site: site.to_string(),
+            url: format!("{}://{}/", scheme, host),
+            name: name.clone(),
+            expires_at,
+        };
+
+        let expiry = serde_json::json!({ "metadata": { "annotations": {
+            EXPIRES_AT_ANNOTATION: expires_at.to_rfc3339(),
+        } } });
+        if namespace_api.get_opt(&ns_name).await?.is_some() {
+            namespace_api
+                .patch(&ns_name, &Default::default(), &Patch::Merge(&expiry))
+                .await?;
+            return Ok(preview);
+        }
+
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &site_ns);
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), &site_ns);
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &site_ns);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &site_ns);
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &site_ns);
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), &site_ns);
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+
+        let mut deployment = deployment_api.get("wordpress").await?;
+        deployment.metadata = copy_metadata(&deployment, &ns_name);
+        deployment.status = None;
+        if let Some(spec) = deployment.spec.as_mut() {
+            spec.replicas = Some(1);
+        }
+        self.prepare_pod_spec(deployment_pod_spec_mut(&mut deployment)?);
+
+        let mut service = service_api.get("wordpress").await?;
+        service.metadata = copy_metadata(&service, &ns_name);
+        service.status = None;
+        if let Some(spec) = service.spec.as_mut() {
+            spec.cluster_ip = None;
+            spec.cluster_ips = None;
+        }
+
+        let config_maps: Vec<ConfigMap> = config_map_api
+            .list(&Default::default())
+            .await?
+            .into_iter()
+            .filter(|c| c.metadata.name.as_deref() != Some("kube-root-ca.crt"))
+            .map(|c| ConfigMap {
+                metadata: copy_metadata(&c, &ns_name),
+                ..c
+            })
+            .collect();
+
+        let ingresses: Vec<Ingress> = ingress_api
+            .list(&Default::default())
+            .await?
+            .into_iter()
+            .filter(|i| i.metadata.name.as_deref() != Some(CANARY_NAME))
+            .map(|mut i| {
+                i.metadata = copy_metadata(&i, &ns_name);
+                i.status = None;
+                rewrite_ingress_host(&mut i, &host);
+                i
+            })
+            .collect();
+
+        let instance = self.site_mariadb_instance(site).await?;
+        let site_secret = secret_api.get("mysql-pass").await?;
+        let database = DatabaseConfig {
+            database: preview_database_name(&secret_value(&site_secret, "db_name")?, &name),
+            user: preview_database_name(&secret_value(&site_secret, "user")?, &name),
+            password: generate_database_password()?,
+            table_prefix: secret_value(&site_secret, "table_prefix")
+                .unwrap_or_else(|_| "wp_".into()),
+            instance: instance.clone(),
+        };
+        database.validate()?;
+        let mut secret = database_secret(&database);
+        secret.metadata.namespace = Some(ns_name.clone());
+
+        let site_pvc = pvc_api.get("wp-pv-claim").await?;
+        let site_pv = match site_pvc
+            .spec
+            .as_ref()
+            .and_then(|s| s.volume_name.as_deref())
+        {
+            Some(volume) => pv_api.get_opt(volume).await?,
+            None => None,
+        };
+        let pv = site_pv.filter(is_local_volume).map(|pv| {
+            let mut spec = pv.spec.unwrap_or_default();
+            spec.claim_ref = None;
+            if let Some(local) = spec.local.as_mut() {
+                local.path = format!("{}/{}", self.pv_base_path, name);
+            }
+            PersistentVolume {
+                metadata: ObjectMeta {
+                    name: Some(format!("{}-pv", ns_name)),
+                    labels: Some(BTreeMap::from([
+                        (PREVIEW_OF_LABEL.to_string(), site.to_string()),
+                        (SITE_LABEL.to_string(), name.to_string()),
+                    ])),
+                    ..Default::default()
+                },
+                spec: Some(spec),
+                ..Default::default()
+            }
+        });
+        let mut pvc = PersistentVolumeClaim {
+            metadata: copy_metadata(&site_pvc, &ns_name),
+            spec: site_pvc.spec.clone(),
+            ..Default::default()
+        };
+        if let Some(spec) = pvc.spec.as_mut() {
+            spec.volume_name = pv.as_ref().and_then(|pv| pv.metadata.name.clone());
+        }
+
+        let mut namespace = Namespace {
+            metadata: ObjectMeta {
+                name: Some(ns_name.clone()),
+                labels: Some(BTreeMap::from([
+                    (PREVIEW_OF_LABEL.to_string(), site.to_string()),
+                    (SITE_LABEL.to_string(), name.to_string()),
+                    (MARIADB_INSTANCE_LABEL.to_string(), instance.clone()),
+                ])),
+                annotations: Some(BTreeMap::from([
+                    (PREVIEW_REF_ANNOTATION.to_string(), git_ref.to_string()),
+                    (EXPIRES_AT_ANNOTATION.to_string(), expires_at.to_rfc3339()),
+                ])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        self.cluster_version.adapt_namespace(&mut namespace);
+
+        let mut resources = vec![
+            serde_json::to_value(&namespace)?,
+            serde_json::to_value(&deployment)?,
+            serde_json::to_value(&service)?,
+            serde_json::to_value(&pvc)?,
+        ];
+        resources.extend(
+            ingresses
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        self.check_policy("create_preview", Some(&name), &resources)
+            .await?;
+
+        let storage = self.backup_storage()?;
+        let backup_id = new_backup_id()?;
+        let backup = self
+            .create_backup_job(
+                site,
+                &backup_job(site, &backup_id, BackupMode::Full, storage)?,
+            )
+            .await?;
+        let backup = self
+            .wait_for_job(
+                &site_ns,
+                &backup.metadata.name.unwrap_or_default(),
+                PREVIEW_JOB_TIMEOUT,
+            )
+            .await?;
+        if !is_job_succeeded(&backup) {
+            bail!("backing up {} for preview {} failed", site, name);
+        }
+
+        namespace_api
+            .create(&Default::default(), &self.labeled(&namespace))
+            .await?;
+        let mut rollback = Rollback::default();
+        rollback.record(CreatedResource::Namespace(ns_name.clone()));
+        let result: Result<()> = async {
+            self.create_site_database(&name, &database, &mut rollback)
+                .await?;
+
+            let preview_secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+            let preview_pvc_api: Api<PersistentVolumeClaim> =
+                Api::namespaced(self.client.clone(), &ns_name);
+            let preview_config_map_api: Api<ConfigMap> =
+                Api::namespaced(self.client.clone(), &ns_name);
+            let preview_service_api: Api<Service> = Api::namespaced(self.client.clone(), &ns_name);
+            let preview_deployment_api: Api<Deployment> =
+                Api::namespaced(self.client.clone(), &ns_name);
+            let preview_ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+
+            preview_secret_api
+                .create(&Default::default(), &self.labeled(&secret))
+                .await?;
+            if let Some(pv) = &pv {
+                pv_api
+                    .create(&Default::default(), &self.labeled(pv))
+                    .await?;
+                rollback.record(CreatedResource::PersistentVolume(pv.name_any()));
+            }
+            preview_pvc_api
+                .create(&Default::default(), &self.labeled(&pvc))
+                .await?;
+
+            let restore = restore_site_job(site, &backup_id, storage)?;
+            let restore = self.create_backup_job(&name, &restore).await?;
+            let restore = self
+                .wait_for_job(
+                    &ns_name,
+                    &restore.metadata.name.unwrap_or_default(),
+                    PREVIEW_JOB_TIMEOUT,
+                )
+                .await?;
+            if !is_job_succeeded(&restore) {
+                bail!("restoring {} into preview {} failed", site, name);
+            }
+
+            for config_map in &config_maps {
+                preview_config_map_api
+                    .create(&Default::default(), &self.labeled(config_map))
+                    .await?;
+            }
+            preview_service_api
+                .create(&Default::default(), &self.labeled(&service))
+                .await?;
+            preview_deployment_api
+                .create(&Default::default(), &self.labeled(&deployment))
+                .await?;
+            for ingress in &ingresses {
+                preview_ingress_api
+                    .create(&Default::default(), &self.labeled(ingress))
+                    .await?;
+            }
+
+            self.wait_for_deployment(&ns_name, "wordpress", PREVIEW_READY_TIMEOUT)
+                .await?;
+            self.run_wp_cli(
+                &name,
+                &[
+                    "search-replace",
+                    site_url.trim_end_matches('/'),
+                    preview.url.trim_end_matches('/'),
+                    "--all-tables",
+                    "--skip-columns=guid",
+                ],
+            )
+            .await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            let operation = format!("creating preview {}", name);
+            return Err(self.roll_back(&operation, rollback, e).await);
+        }
+
+        Ok(preview)
+    }
+
+    /// Deletes a preview site with its database and volume.
+    pub async fn delete_preview(&self, name: &str) -> Result<()> {
+        let ns_name = self.site_namespace(name);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = namespace_api.get(&ns_name).await?;
+        let is_preview = namespace
+            .metadata
+            .labels
+            .as_ref()
+            .is_some_and(|labels| labels.contains_key(PREVIEW_OF_LABEL));
+        if !is_preview {
+            bail!("{} is not a preview site", name);
+        }
+        ensure_managed(&namespace, Some((SITE_LABEL, name)))?;
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let pv = pv_api.get_opt(&format!("{}-pv", ns_name)).await?;
+        if let Some(pv) = &pv {
+            ensure_managed(pv, Some((SITE_LABEL, name)))?;
+        }
+
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        if let Some(secret) = secret_api.get_opt("mysql-pass").await? {
+            // Drops the preview's own user too, older previews shared the site's.
+            self.drop_site_database(name, &namespace_mariadb_instance(&namespace), &secret)
+                .await?;
+        }
+
+        namespace_api.delete(&ns_name, &Default::default()).await?;
+        if let Some(pv) = pv {
+            pv_api.delete(&pv.name_any(), &Default::default()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes every preview whose lifetime has passed and returns their names.
+    pub async fn cleanup_expired_previews(&self) -> Result<Vec<String>> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespaces = namespace_api
+            .list(&ListParams::default().labels(PREVIEW_OF_LABEL))
+            .await?;
+
+        let now = Utc::now();
+        let mut deleted = Vec::new();
+        for namespace in namespaces {
+            let Some(name) = namespace
+                .metadata
+                .name
+                .as_deref()
+                .and_then(|ns| self.site_name(ns))
+            else {
+                continue;
+            };
+            if is_preview_expired(&namespace, now)
+                && namespace.metadata.deletion_timestamp.is_none()
+            {
+                self.delete_preview(name).await?;
+                deleted.push(name.to_string());
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    /// Deletes expired previews every `PREVIEW_CLEANUP_INTERVAL`, until the task is dropped.
+    pub async fn clean_up_expired_previews_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PREVIEW_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.cleanup_expired_previews().await {
+                Ok(deleted) if !deleted.is_empty() => {
+                    tracing::info!(previews = ?deleted, "deleted expired previews")
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(error = %format!("{:#}", e), "deleting expired previews failed")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::networking::v1::{IngressRule, IngressSpec, IngressTLS};
+
+    use super::*;
+    use crate::{is_valid_database_identifier, site::DEFAULT_NAMESPACE_PREFIX};
+
+    #[test]
+    fn test_preview_name() {
+        assert_eq!(
+            preview_name("blog", "refs/pull/42/merge", DEFAULT_NAMESPACE_PREFIX).unwrap(),
+            "blog-pr-42"
+        );
+        assert_eq!(
+            preview_name(
+                "blog",
+                "refs/heads/Feature/New_Header",
+                DEFAULT_NAMESPACE_PREFIX
+            )
+            .unwrap(),
+            "blog-feature-new-header"
+        );
+        assert!(preview_name("blog", "///", DEFAULT_NAMESPACE_PREFIX).is_err());
+
+        let name = preview_name("blog", &"x".repeat(100), DEFAULT_NAMESPACE_PREFIX).unwrap();
+        assert_eq!(
+            DEFAULT_NAMESPACE_PREFIX.len() + name.len(),
+            MAX_NAMESPACE_LEN
+        );
+    }
+
+    #[test]
+    fn test_preview_host() {
+        assert_eq!(
+            preview_host("blog-pr-42", "blog", "example.com", None),
+            "pr-42.preview.example.com"
+        );
+        assert_eq!(
+            preview_host(
+                "blog-pr-42",
+                "blog",
+                "example.com",
+                Some("preview.example.net")
+            ),
+            "blog-pr-42.preview.example.net"
+        );
+    }
+
+    #[test]
+    fn test_preview_database_name() {
+        assert_eq!(
+            preview_database_name("wordpress", "blog-pr-42"),
+            "wordpress_blog_pr_42"
+        );
+        assert!(is_valid_database_identifier(&preview_database_name(
+            "wordpress",
+            &"x".repeat(80)
+        )));
+    }
+
+    #[test]
+    fn test_rewrite_ingress_host() {
+        let mut ingress = Ingress {
+            spec: Some(IngressSpec {
+                rules: Some(vec![IngressRule {
+                    host: Some("example.com".to_string()),
+                    ..Default::default()
+                }]),
+                tls: Some(vec![IngressTLS {
+                    hosts: Some(vec!["example.com".to_string()]),
+                    secret_name: Some("example-com-tls".to_string()),
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        rewrite_ingress_host(&mut ingress, "pr-42.preview.example.com");
+        let spec = ingress.spec.unwrap();
+        assert_eq!(
+            spec.rules.unwrap()[0].host.as_deref(),
+            Some("pr-42.preview.example.com")
+        );
+        let tls = &spec.tls.unwrap()[0];
+        assert_eq!(tls.hosts.as_deref().unwrap(), ["pr-42.preview.example.com"]);
+        assert_eq!(tls.secret_name.as_deref(), Some("wordpress-preview-tls"));
+    }
+
+    #[test]
+    fn test_is_preview_expired() {
+        let now = Utc::now();
+        let hour = chrono::Duration::from_std(Duration::from_secs(3600)).unwrap();
+        let namespace = |expires_at: &str| Namespace {
+            metadata: ObjectMeta {
+                annotations: Some(BTreeMap::from([(
+                    EXPIRES_AT_ANNOTATION.to_string(),
+                    expires_at.to_string(),
+                )])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(is_preview_expired(
+            &namespace(&(now - hour).to_rfc3339()),
+            now
+        ));
+        assert!(!is_preview_expired(
+            &namespace(&(now + hour).to_rfc3339()),
+            now
+        ));
+        assert!(!is_preview_expired(&Namespace::default(), now));
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use k8s_openapi::api::{batch::v1::Job, core::v1::Service};
+use kube::Api;
+
+use crate::{
+    error::{bail, Result},
+    job::is_job_succeeded,
+    manifest::{job_pod_spec_mut, set_env},
+    KwpmClient,
+};
+
+const PROFILE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SiteProfile {
+    pub ttfb_ms: Option<f64>,
+    pub db_query_ms: Option<f64>,
+    pub autoload_bytes: Option<u64>,
+    pub cache_hit_ratio: Option<f64>,
+    pub score: u8,
+    pub suggestions: Vec<String>,
+}
+
+impl SiteProfile {
+    /// Builds a scored profile from the `key=value` lines printed by the profile job.
+    pub fn from_measurements(output: &str) -> Self {
+        let values = |key: &str| -> Vec<f64> {
+            output
+                .lines()
+                .filter_map(|line| line.trim().split_once('='))
+                .filter(|(k, _)| *k == key)
+                .filter_map(|(_, v)| v.trim().parse().ok())
+                .collect()
+        };
+        let first = |key: &str| values(key).into_iter().next();
+
+        let mut ttfb = values("ttfb_seconds");
+        ttfb.retain(|t| *t > 0.0);
+        ttfb.sort_by(|a, b| a.total_cmp(b));
+        let ttfb_ms = ttfb.get(ttfb.len() / 2).map(|t| t * 1000.0);
+
+        let cache_hit_ratio = match (first("redis_hits"), first("redis_misses")) {
+            (Some(hits), Some(misses)) if hits + misses > 0.0 => Some(hits / (hits + misses)),
+            _ => None,
+        };
+
+        let mut profile = Self {
+            ttfb_ms,
+            db_query_ms: first("db_query_seconds").map(|t| t * 1000.0),
+            autoload_bytes: first("autoload_bytes").map(|b| b as u64),
+            cache_hit_ratio,
+            ..Default::default()
+        };
+        profile.score();
+        profile
+    }
+
+    fn score(&mut self) {
+        let mut score: i32 = 100;
+        let mut suggestions = Vec::new();
+        let mut suggest = |penalty: i32, suggestion: &str| {
+            score -= penalty;
+            suggestions.push(suggestion.to_string());
+        };
+
+        match self.ttfb_ms {
+            None => suggest(
+                40,
+                "The site did not respond through its ingress; check the ingress host and TLS.",
+            ),
+            Some(ttfb) if ttfb > 1500.0 => suggest(
+                40,
+                "Time to first byte is above 1.5s; enable full-page caching.",
+            ),
+            Some(ttfb) if ttfb > 600.0 => suggest(
+                25,
+                "Time to first byte is above 600ms; add page caching or more PHP workers.",
+            ),
+            Some(ttfb) if ttfb > 200.0 => suggest(
+                10,
+                "Time to first byte is above 200ms; review slow plugins.",
+            ),
+            _ => {}
+        }
+
+        match self.db_query_ms {
+            None => suggest(
+                20,
+                "The database could not be queried with the site's credentials.",
+            ),
+            Some(query) if query > 200.0 => suggest(
+                20,
+                "Database round trips are above 200ms; check MariaDB load and placement.",
+            ),
+            Some(query) if query > 50.0 => suggest(
+                10,
+                "Database round trips are above 50ms; check MariaDB load.",
+            ),
+            _ => {}
+        }
+
+        if self.autoload_bytes.unwrap_or(0) > 800 * 1024 {
+            suggest(
+                15,
+                "Autoloaded options exceed 800KB; clean up options left behind by removed plugins.",
+            );
+        }
+
+        match self.cache_hit_ratio {
+            None => suggest(
+                5,
+                "No Redis object cache detected; enabling one reduces database load.",
+            ),
+            Some(ratio) if ratio < 0.8 => suggest(
+                10,
+                "Object cache hit ratio is below 80%; check cache sizing and eviction.",
+            ),
+            _ => {}
+        }
+
+        self.suggestions = suggestions;
+        self.score = score.clamp(0, 100) as u8;
+    }
+}
+
+pub fn profile_job(site_url: &str, redis_host: Option<&str>) -> Result<Job> {
+    let mut job: Job =
+        serde_yaml::from_str(include_str!("../../kubernetes/profile/profile-job.yaml"))?;
+
+    let pod_spec = job_pod_spec_mut(&mut job)?;
+    set_env(pod_spec, "ttfb", &[("SITE_URL", site_url.to_string())])?;
+    set_env(
+        pod_spec,
+        "redis",
+        &[("REDIS_HOST", redis_host.unwrap_or_default().to_string())],
+    )?;
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    pub async fn profile_site(&self, site: &str) -> Result<SiteProfile> {
+        let ns_name = self.site_namespace(site);
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let site_url = self.site_url(site).await?;
+        let redis_host = service_api
+            .get_opt("redis")
+            .await?
+            .map(|_| format!("redis.{}.svc", ns_name));
+
+        let job = self
+            .create_job(&ns_name, profile_job(&site_url, redis_host.as_deref())?)
+            .await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+
+        let job = self
+            .wait_for_job(&ns_name, &job_name, PROFILE_TIMEOUT)
+            .await?;
+        if !is_job_succeeded(&job) {
+            bail!("profiling job {} for {} failed", job_name, site);
+        }
+
+        let output = self.job_logs(&ns_name, &job_name).await?;
+        Ok(SiteProfile::from_measurements(&output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fast_site_scores_high() {
+        let profile = SiteProfile::from_measurements(
+            "ttfb_seconds=0.120\nttfb_seconds=0.090\nttfb_seconds=0.400\ndb_query_seconds=0.002\nautoload_bytes=120000\nredis_hits=900\nredis_misses=100\n",
+        );
+
+        assert_eq!(profile.ttfb_ms, Some(120.0));
+        assert_eq!(profile.cache_hit_ratio, Some(0.9));
+        assert_eq!(profile.score, 100);
+        assert!(profile.suggestions.is_empty());
+    }
+
+    #[test]
+    fn test_slow_site_gets_suggestions() {
+        let profile = SiteProfile::from_measurements(
+            "ttfb_seconds=2.1\nttfb_seconds=1.9\nttfb_seconds=2.4\ndb_query_seconds=0.3\nautoload_bytes=2000000\n",
+        );
+
+        assert_eq!(profile.score, 100 - 40 - 20 - 15 - 5);
+        assert_eq!(profile.suggestions.len(), 4);
+    }
+
+    #[test]
+    fn test_unreachable_site() {
+        let profile = SiteProfile::from_measurements("ttfb_seconds=0.000000\n");
+
+        assert_eq!(profile.ttfb_ms, None);
+        assert_eq!(profile.db_query_ms, None);
+        assert_eq!(profile.score, 100 - 40 - 20 - 5);
+    }
+}
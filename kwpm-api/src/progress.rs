@@ -0,0 +1,400 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{ListParams, ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{KwpmError, Result},
+    metadata::DefaultMetadata,
+    metrics::observe_operation,
+    KwpmClient,
+};
+
+pub(crate) const OPERATION_CONFIG_MAP_PREFIX: &str = "kwpm-operation-";
+const OPERATION_LABEL: &str = "kwpm.io/operation";
+const OPERATION_KEY: &str = "operation.json";
+const MAX_OPERATIONS: usize = 50;
+/// Well above the longest job timeout, so only operations whose process is gone are affected.
+const STALE_OPERATION_AGE: Duration = Duration::from_secs(12 * 3600);
+const STALE_OPERATION_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgressEvent {
+    pub time: DateTime<Utc>,
+    pub step: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u8>,
+}
+
+/// A long-running operation on a site and the steps it went through, persisted in the site
+/// namespace so its history outlives the kwpm process running it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationRecord {
+    pub id: String,
+    pub site: String,
+    pub operation: String,
+    pub status: OperationStatus,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    pub events: Vec<ProgressEvent>,
+}
+
+pub fn operation_id(operation: &str, started_at: DateTime<Utc>, nonce: u16) -> String {
+    format!(
+        "{}-{}-{:04x}",
+        operation.replace('_', "-"),
+        started_at.format("%Y%m%d-%H%M%S"),
+        nonce
+    )
+}
+
+pub fn operation_config_map(record: &OperationRecord) -> Result<ConfigMap> {
+    Ok(ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(format!("{}{}", OPERATION_CONFIG_MAP_PREFIX, record.id)),
+            labels: Some(BTreeMap::from([(
+                OPERATION_LABEL.to_string(),
+                record.operation.replace('_', "-"),
+            )])),
+            ..Default::default()
+        },
+        data: Some(BTreeMap::from([(
+            OPERATION_KEY.to_string(),
+            serde_json::to_string(record)?,
+        )])),
+        ..Default::default()
+    })
+}
+
+pub fn parse_operation_config_map(config_map: &ConfigMap) -> Result<OperationRecord> {
+    let record = config_map
+        .data
+        .as_ref()
+        .and_then(|data| data.get(OPERATION_KEY))
+        .context("operation config map has no record")?;
+    Ok(serde_json::from_str(record)?)
+}
+
+/// Marks `record` failed if it is still running without a step for `STALE_OPERATION_AGE`, as
+/// it is when the kwpm process running it crashed. Returns whether it did.
+pub fn fail_if_stale(record: &mut OperationRecord, now: DateTime<Utc>) -> bool {
+    let last_step = record.events.last().map_or(record.started_at, |e| e.time);
+    if record.status != OperationStatus::Running
+        || (now - last_step).to_std().unwrap_or_default() < STALE_OPERATION_AGE
+    {
+        return false;
+    }
+
+    record.status = OperationStatus::Failed;
+    record.finished_at = Some(now);
+    record.error = Some(format!(
+        "abandoned without progress since {}",
+        last_step.to_rfc3339()
+    ));
+    true
+}
+
+async fn save_operation(
+    config_map_api: &Api<ConfigMap>,
+    default_metadata: &DefaultMetadata,
+    record: &OperationRecord,
+) -> Result<()> {
+    let mut config_map = operation_config_map(record)?;
+    default_metadata.apply_managed(&mut config_map.metadata);
+    config_map_api
+        .patch(
+            &format!("{}{}", OPERATION_CONFIG_MAP_PREFIX, record.id),
+            &PatchParams::apply("kwpm").force(),
+            &Patch::Apply(&config_map),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Records the steps of an operation. Persisting progress is best effort and never fails the
+/// operation itself. Dropping it before `succeed` marks the operation as failed.
+pub struct OperationProgress {
+    config_map_api: Api<ConfigMap>,
+    default_metadata: DefaultMetadata,
+    record: OperationRecord,
+}
+
+impl OperationProgress {
+    async fn save(&self) {
+        let _ = save_operation(&self.config_map_api, &self.default_metadata, &self.record).await;
+    }
+
+    pub async fn step(&mut self, step: impl ToString, percent: Option<u8>) {
+        let event = ProgressEvent {
+            time: Utc::now(),
+            step: step.to_string(),
+            percent: percent.map(|p| p.min(100)),
+        };
+        tracing::info!(
+            site = %self.record.site,
+            operation = %self.record.operation,
+            id = %self.record.id,
+            step = %event.step,
+            percent = event.percent,
+            "operation step"
+        );
+        self.record.events.push(event);
+        self.save().await;
+    }
+
+    fn finish(&mut self, status: OperationStatus) {
+        let finished_at = Utc::now();
+        let duration = (finished_at - self.record.started_at)
+            .to_std()
+            .unwrap_or_default();
+        self.record.status = status;
+        self.record.finished_at = Some(finished_at);
+        observe_operation(
+            &self.record.operation,
+            status == OperationStatus::Succeeded,
+            duration,
+        );
+        tracing::info!(
+            site = %self.record.site,
+            operation = %self.record.operation,
+            id = %self.record.id,
+            status = ?status,
+            duration_secs = duration.as_secs_f64(),
+            "operation finished"
+        );
+    }
+
+    pub async fn succeed(mut self) {
+        self.finish(OperationStatus::Succeeded);
+        self.save().await;
+    }
+
+    pub async fn fail(mut self, error: &KwpmError) {
+        self.record.error = Some(format!("{:#}", error));
+        self.finish(OperationStatus::Failed);
+        self.save().await;
+    }
+}
+
+impl Drop for OperationProgress {
+    fn drop(&mut self) {
+        if self.record.status != OperationStatus::Running {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let mut progress = OperationProgress {
+            config_map_api: self.config_map_api.clone(),
+            default_metadata: self.default_metadata.clone(),
+            record: self.record.clone(),
+        };
+        progress.finish(OperationStatus::Failed);
+        runtime.spawn(async move { progress.save().await });
+    }
+}
+
+impl KwpmClient {
+    pub(crate) async fn start_operation(&self, site: &str, operation: &str) -> OperationProgress {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        let started_at = Utc::now();
+        let mut nonce = [0u8; 2];
+        let _ = getrandom::getrandom(&mut nonce);
+
+        let mut progress = OperationProgress {
+            config_map_api,
+            default_metadata: self.default_metadata.clone(),
+            record: OperationRecord {
+                id: operation_id(operation, started_at, u16::from_be_bytes(nonce)),
+                site: site.to_string(),
+                operation: operation.to_string(),
+                status: OperationStatus::Running,
+                started_at,
+                finished_at: None,
+                error: None,
+                events: Vec::new(),
+            },
+        };
+        progress.step("started", Some(0)).await;
+
+        if let Ok(mut operations) = self.list_operations(site).await {
+            operations.retain(|o| o.status != OperationStatus::Running);
+            let prune = operations.len().saturating_sub(MAX_OPERATIONS);
+            for old in &operations[..prune] {
+                let _ = progress
+                    .config_map_api
+                    .delete(
+                        &format!("{}{}", OPERATION_CONFIG_MAP_PREFIX, old.id),
+                        &Default::default(),
+                    )
+                    .await;
+            }
+        }
+
+        progress
+    }
+
+    /// The recorded operations of a site, oldest first.
+    pub async fn list_operations(&self, site: &str) -> Result<Vec<OperationRecord>> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let mut operations = config_map_api
+            .list(&ListParams::default().labels(OPERATION_LABEL))
+            .await?
+            .iter()
+            .map(parse_operation_config_map)
+            .collect::<Result<Vec<_>>>()?;
+        operations.sort_by_key(|o| o.started_at);
+
+        Ok(operations)
+    }
+
+    pub async fn get_operation(&self, site: &str, id: &str) -> Result<OperationRecord> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let config_map = config_map_api
+            .get_opt(&format!("{}{}", OPERATION_CONFIG_MAP_PREFIX, id))
+            .await?
+            .with_context(|| format!("{} has no operation {}", site, id))?;
+        parse_operation_config_map(&config_map)
+    }
+
+    /// Marks the operations of every site left running by a crashed kwpm process as failed, so
+    /// they are pruned like finished ones, and returns their ids.
+    pub async fn fail_stale_operations(&self) -> Result<Vec<String>> {
+        let config_maps = Api::<ConfigMap>::all(self.client.clone())
+            .list(&ListParams::default().labels(OPERATION_LABEL))
+            .await?;
+
+        let now = Utc::now();
+        let mut failed = Vec::new();
+        for config_map in config_maps {
+            let Some(namespace) = config_map.metadata.namespace.as_deref() else {
+                continue;
+            };
+            if self.site_name(namespace).is_none() {
+                continue;
+            }
+            let Ok(mut record) = parse_operation_config_map(&config_map) else {
+                continue;
+            };
+            if fail_if_stale(&mut record, now) {
+                let config_map_api = Api::namespaced(self.client.clone(), namespace);
+                save_operation(&config_map_api, &self.default_metadata, &record).await?;
+                failed.push(record.id);
+            }
+        }
+
+        Ok(failed)
+    }
+
+    /// Runs `fail_stale_operations` every `STALE_OPERATION_SWEEP_INTERVAL`, until the task is
+    /// dropped.
+    pub async fn fail_stale_operations_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(STALE_OPERATION_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.fail_stale_operations().await {
+                Ok(failed) if !failed.is_empty() => {
+                    tracing::warn!(operations = ?failed, "marked abandoned operations failed")
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(error = %format!("{:#}", e), "sweeping stale operations failed")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_operation_id() {
+        let started_at = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            operation_id("deploy_code", started_at, 0xab),
+            "deploy-code-20240301-120000-00ab"
+        );
+    }
+
+    #[test]
+    fn test_operation_config_map_round_trip() {
+        let record = OperationRecord {
+            id: "archive-site-20240301-120000-0001".to_string(),
+            site: "blog".to_string(),
+            operation: "archive_site".to_string(),
+            status: OperationStatus::Running,
+            started_at: Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap(),
+            finished_at: None,
+            error: None,
+            events: vec![ProgressEvent {
+                time: Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 5).unwrap(),
+                step: "backup 40% complete".to_string(),
+                percent: Some(40),
+            }],
+        };
+        let config_map = operation_config_map(&record).unwrap();
+
+        assert_eq!(
+            config_map.metadata.labels.as_ref().unwrap()[OPERATION_LABEL],
+            "archive-site"
+        );
+        assert_eq!(parse_operation_config_map(&config_map).unwrap(), record);
+    }
+
+    #[test]
+    fn test_fail_if_stale() {
+        let started_at = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut record = OperationRecord {
+            id: "archive-site-20240301-120000-0001".to_string(),
+            site: "blog".to_string(),
+            operation: "archive_site".to_string(),
+            status: OperationStatus::Running,
+            started_at,
+            finished_at: None,
+            error: None,
+            events: vec![ProgressEvent {
+                time: Utc.with_ymd_and_hms(2024, 3, 1, 13, 0, 0).unwrap(),
+                step: "archiving".to_string(),
+                percent: Some(10),
+            }],
+        };
+
+        let now = Utc.with_ymd_and_hms(2024, 3, 2, 0, 30, 0).unwrap();
+        assert!(!fail_if_stale(&mut record, now));
+        assert_eq!(record.status, OperationStatus::Running);
+
+        let now = Utc.with_ymd_and_hms(2024, 3, 2, 1, 30, 0).unwrap();
+        assert!(fail_if_stale(&mut record, now));
+        assert_eq!(record.status, OperationStatus::Failed);
+        assert_eq!(record.finished_at, Some(now));
+        assert!(!fail_if_stale(&mut record, now));
+    }
+}
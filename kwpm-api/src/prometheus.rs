@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Selector matching ingress-nginx series of a namespace, whether the scrape kept the
+/// controller's `namespace` label or renamed it to `exported_namespace`.
+pub(crate) fn ingress_selector(metric: &str, namespace: &str, extra: &str) -> String {
+    let extra = if extra.is_empty() {
+        String::new()
+    } else {
+        format!(",{}", extra)
+    };
+    format!(
+        "{metric}{{exported_namespace=\"{namespace}\"{extra}}} or {metric}{{namespace=\"{namespace}\",exported_namespace=\"\"{extra}}}",
+        metric = metric,
+        namespace = namespace,
+        extra = extra
+    )
+}
+
+/// Reads the value of an instant query returning a single-element vector or a scalar. An empty
+/// vector, e.g. for a site without traffic, reads as zero.
+pub fn instant_value(response: &Value) -> Result<f64> {
+    if response["status"] != "success" {
+        bail!(
+            "prometheus query failed: {}",
+            response["error"].as_str().unwrap_or("unknown error")
+        );
+    }
+
+    let data = &response["data"];
+    let sample = match data["resultType"].as_str() {
+        Some("vector") => match data["result"].as_array().map(Vec::as_slice) {
+            Some([]) => return Ok(0.0),
+            Some([series]) => &series["value"],
+            _ => bail!("prometheus query returned more than one series"),
+        },
+        Some("scalar") => &data["result"],
+        other => bail!("unexpected prometheus result type {:?}", other),
+    };
+
+    let value = sample[1]
+        .as_str()
+        .context("prometheus sample has no value")?;
+    match value {
+        "NaN" => Ok(0.0),
+        value => Ok(value.parse()?),
+    }
+}
+
+impl KwpmClient {
+    pub(crate) async fn query_prometheus(&self, query: &str, time: DateTime<Utc>) -> Result<f64> {
+        let url = self
+            .prometheus_url
+            .as_ref()
+            .context("prometheus is not configured")?;
+
+        let response: Value = reqwest::Client::new()
+            .get(format!("{}/api/v1/query", url.trim_end_matches('/')))
+            .query(&[("query", query), ("time", &time.timestamp().to_string())])
+            .timeout(QUERY_TIMEOUT)
+            .send()
+            .await
+            .with_context(|| format!("failed to query prometheus at {}", url))?
+            .json()
+            .await?;
+
+        Ok(instant_value(&response).with_context(|| format!("query {} failed", query))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_instant_value() {
+        let vector = |result: Value| json!({ "status": "success", "data": { "resultType": "vector", "result": result } });
+
+        assert_eq!(
+            instant_value(&vector(
+                json!([{ "metric": {}, "value": [1700000000, "42.5"] }])
+            ))
+            .unwrap(),
+            42.5
+        );
+        assert_eq!(instant_value(&vector(json!([]))).unwrap(), 0.0);
+        assert!(instant_value(&vector(json!([
+            { "metric": {}, "value": [0, "1"] },
+            { "metric": {}, "value": [0, "2"] },
+        ])))
+        .is_err());
+        assert!(instant_value(&json!({ "status": "error", "error": "bad query" })).is_err());
+    }
+
+    #[test]
+    fn test_ingress_selector() {
+        assert_eq!(
+            ingress_selector("nginx_ingress_controller_requests", "kwpm-blog", ""),
+            "nginx_ingress_controller_requests{exported_namespace=\"kwpm-blog\"} or nginx_ingress_controller_requests{namespace=\"kwpm-blog\",exported_namespace=\"\"}"
+        );
+    }
+}
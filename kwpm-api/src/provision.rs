@@ -0,0 +1,967 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::Job,
+    core::v1::{ConfigMap, Namespace, PersistentVolume, PersistentVolumeClaim, Secret, Service},
+    networking::v1::Ingress,
+};
+use kube::{api::ObjectMeta, Api, ResourceExt};
+
+use crate::{
+    blueprint::Blueprint,
+    confirm::DestructiveOperation,
+    database_job,
+    environment::{apply_environment_annotations, apply_environment_profile},
+    error::{bail, KwpmError, Result},
+    hooks::{site_template, HookStage},
+    manifest::{deployment_pod_spec_mut, job_pod_spec_mut, set_env},
+    mariadb::{
+        is_valid_mariadb_instance, mariadb_host, mariadb_namespace, namespace_mariadb_instance,
+        set_mariadb_host, DEFAULT_MARIADB_INSTANCE, MARIADB_INSTANCE_LABEL,
+    },
+    metadata::ensure_managed,
+    nginx::render_nginx_config,
+    preview::{rewrite_ingress_host, PREVIEW_OF_LABEL},
+    registry::ImageReference,
+    rollback::{CreatedResource, Rollback},
+    secret_value,
+    secrets::MasterKeys,
+    site::{AppKind, SiteSpec},
+    uploads::uploads_ini_config,
+    wp_cli::attach_wp_cli,
+    DatabaseAction, KwpmClient,
+};
+
+pub const SITE_LABEL: &str = "kwpm.io/site";
+const SITE_READY_TIMEOUT: Duration = Duration::from_secs(600);
+pub(crate) const DATABASE_JOB_TIMEOUT: Duration = Duration::from_secs(300);
+/// Volumes of the deployment template only the PHP container mounts.
+const PHP_CONFIG_VOLUMES: [&str; 2] = ["uploads-ini-conf", "php-errors-ini-conf"];
+
+/// The database and user a site gets on a MariaDB instance.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DatabaseConfig {
+    pub database: String,
+    pub user: String,
+    pub password: String,
+    /// WordPress' `$table_prefix`, kept with the credentials so backups restore into tables
+    /// the site reads.
+    pub table_prefix: String,
+    /// The MariaDB instance the database is created on, `DEFAULT_MARIADB_INSTANCE` unless the
+    /// site selects another one.
+    pub instance: String,
+}
+
+impl fmt::Debug for DatabaseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DatabaseConfig")
+            .field("database", &self.database)
+            .field("user", &self.user)
+            .field("password", &"***")
+            .field("table_prefix", &self.table_prefix)
+            .field("instance", &self.instance)
+            .finish()
+    }
+}
+
+impl DatabaseConfig {
+    /// Database, user and table prefix derived from the site name with `keys`, so they are the
+    /// same when a failed creation is retried but can't be guessed from the site name, and a
+    /// generated password.
+    pub fn for_site(site: &str, keys: &MasterKeys) -> Result<Self> {
+        let derived = keys.derive(&format!("database:{}", site));
+        let name = format!("wp_{}", &derived[..16]);
+
+        Ok(Self {
+            database: name.clone(),
+            user: name,
+            password: generate_database_password()?,
+            table_prefix: format!("wp{}_", &derived[16..22]),
+            instance: DEFAULT_MARIADB_INSTANCE.to_string(),
+        })
+    }
+
+    /// The config with the database on MariaDB `instance`.
+    pub fn on_instance(mut self, instance: impl ToString) -> Self {
+        self.instance = instance.to_string();
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !crate::is_valid_database_identifier(&self.database)
+            || !crate::is_valid_database_identifier(&self.user)
+        {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid database {} or user {}",
+                self.database, self.user
+            )));
+        }
+        // The password ends up in a quoted SQL string.
+        if self.password.len() < 16 || !self.password.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(KwpmError::invalid_input(
+                "database password must be at least 16 alphanumeric characters",
+            ));
+        }
+        if !is_valid_table_prefix(&self.table_prefix) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid table prefix: {}",
+                self.table_prefix
+            )));
+        }
+        if !is_valid_mariadb_instance(&self.instance) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid MariaDB instance: {}",
+                self.instance
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// WordPress only allows letters, digits and underscores in the prefix. Table names are
+/// limited to 64 characters, so long prefixes leave no room for plugin tables.
+pub fn is_valid_table_prefix(prefix: &str) -> bool {
+    !prefix.is_empty()
+        && prefix.len() <= 20
+        && prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// A random password for a database user, hex as `DatabaseConfig::validate` requires.
+pub(crate) fn generate_database_password() -> Result<String> {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes).context("failed to generate a password")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub(crate) fn database_password_secret_name(site: &str) -> String {
+    format!("kwpm-db-{}", site)
+}
+
+/// The objects making up a site, before they are created.
+pub struct SiteManifests {
+    pub namespace: Namespace,
+    pub pv: PersistentVolume,
+    pub pvc: PersistentVolumeClaim,
+    pub config_maps: Vec<ConfigMap>,
+    pub service: Service,
+    pub deployment: Deployment,
+    pub ingress: Ingress,
+}
+
+impl SiteManifests {
+    fn to_values(&self) -> Result<Vec<serde_json::Value>> {
+        let mut values = vec![
+            serde_json::to_value(&self.namespace)?,
+            serde_json::to_value(&self.pv)?,
+            serde_json::to_value(&self.pvc)?,
+            serde_json::to_value(&self.service)?,
+            serde_json::to_value(&self.deployment)?,
+            serde_json::to_value(&self.ingress)?,
+        ];
+        for config_map in &self.config_maps {
+            values.push(serde_json::to_value(config_map)?);
+        }
+        Ok(values)
+    }
+}
+
+/// The `mysql-pass` secret the site's pods read their database settings from.
+pub fn database_secret(database: &DatabaseConfig) -> Secret {
+    Secret {
+        metadata: ObjectMeta {
+            name: Some("mysql-pass".to_string()),
+            ..Default::default()
+        },
+        string_data: Some(BTreeMap::from([
+            ("user".to_string(), database.user.clone()),
+            ("password".to_string(), database.password.clone()),
+            ("db_name".to_string(), database.database.clone()),
+            ("table_prefix".to_string(), database.table_prefix.clone()),
+        ])),
+        ..Default::default()
+    }
+}
+
+/// Turns the WordPress deployment template into one running `app`.
+fn adapt_deployment(deployment: &mut Deployment, app: &AppKind) -> Result<()> {
+    let database_host = mariadb_host(DEFAULT_MARIADB_INSTANCE);
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+    if app.uses_database() {
+        set_env(
+            pod_spec,
+            "wordpress",
+            &[("WORDPRESS_DB_HOST", database_host)],
+        )?;
+    }
+
+    match app {
+        AppKind::WordPress => attach_wp_cli(pod_spec)?,
+        AppKind::Php { image } => {
+            ImageReference::parse(image)?;
+            let container = pod_spec
+                .containers
+                .iter_mut()
+                .find(|c| c.name == "wordpress")
+                .context("deployment has no wordpress container")?;
+            container.image = Some(image.clone());
+            let env = container.env.get_or_insert_with(Vec::new);
+            env.retain(|e| e.name != "WORDPRESS_TABLE_PREFIX");
+            for var in env.iter_mut() {
+                if let Some(name) = var.name.strip_prefix("WORDPRESS_") {
+                    var.name = name.to_string();
+                }
+            }
+        }
+        AppKind::Static => {
+            pod_spec.containers.retain(|c| c.name != "wordpress");
+            if let Some(volumes) = pod_spec.volumes.as_mut() {
+                volumes.retain(|v| !PHP_CONFIG_VOLUMES.contains(&v.name.as_str()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders the objects of a site running `app` from the bundled templates. The volume is a
+/// local one under `pv_base_path` on the node the MariaDB volume, if any, is pinned to.
+pub fn site_manifests(
+    site: &str,
+    ns_name: &str,
+    domain: &str,
+    app: &AppKind,
+    pv_base_path: &str,
+    mariadb_pv: Option<&PersistentVolume>,
+) -> Result<SiteManifests> {
+    let namespace = Namespace {
+        metadata: ObjectMeta {
+            name: Some(ns_name.to_string()),
+            labels: Some(BTreeMap::from([(SITE_LABEL.to_string(), site.to_string())])),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut pv: PersistentVolume =
+        serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-pv.yaml"))?;
+    pv.metadata.name = Some(format!("{}-pv", ns_name));
+    pv.metadata.labels = Some(BTreeMap::from([(SITE_LABEL.to_string(), site.to_string())]));
+    if let Some(spec) = pv.spec.as_mut() {
+        if let Some(local) = spec.local.as_mut() {
+            local.path = format!("{}/{}", pv_base_path, site);
+        }
+        spec.node_affinity = mariadb_pv
+            .and_then(|pv| pv.spec.as_ref())
+            .and_then(|spec| spec.node_affinity.clone());
+    }
+
+    let mut pvc: PersistentVolumeClaim =
+        serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-pvc.yaml"))?;
+    if let Some(spec) = pvc.spec.as_mut() {
+        spec.volume_name = pv.metadata.name.clone();
+    }
+
+    let mut config_maps = vec![render_nginx_config(&SiteSpec {
+        app: app.clone(),
+        ..Default::default()
+    })?];
+    if app.uses_php() {
+        config_maps.push(serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-uploads-ini-config.yaml"
+        ))?);
+        config_maps.push(serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-php-errors-ini-config.yaml"
+        ))?);
+    }
+
+    let mut service: Service =
+        serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-service.yaml"))?;
+    // Traffic reaches the site through the ingress.
+    if let Some(spec) = service.spec.as_mut() {
+        spec.type_ = Some("ClusterIP".to_string());
+    }
+
+    let mut deployment: Deployment = serde_yaml::from_str(include_str!(
+        "../../kubernetes/wordpress/wp-deployment.yaml"
+    ))?;
+    adapt_deployment(&mut deployment, app)?;
+
+    let mut ingress: Ingress =
+        serde_yaml::from_str(include_str!("../../kubernetes/wordpress/wp-ingress.yaml"))?;
+    rewrite_ingress_host(&mut ingress, domain);
+
+    Ok(SiteManifests {
+        namespace,
+        pv,
+        pvc,
+        config_maps,
+        service,
+        deployment,
+        ingress,
+    })
+}
+
+/// Renders the config maps of a site created from `blueprint` from its `spec` and patches the
+/// blueprint's overlays into every manifest.
+fn apply_blueprint(
+    manifests: &mut SiteManifests,
+    blueprint: &Blueprint,
+    spec: &SiteSpec,
+) -> Result<()> {
+    let mut rendered = vec![render_nginx_config(spec)?];
+    if let Some(limit_mb) = spec.upload_limit_mb {
+        rendered.push(uploads_ini_config(limit_mb)?);
+    }
+    for config_map in &mut manifests.config_maps {
+        if let Some(render) = rendered
+            .iter()
+            .find(|r| r.metadata.name == config_map.metadata.name)
+        {
+            *config_map = render.clone();
+        }
+        blueprint.overlay(config_map)?;
+    }
+
+    blueprint.overlay(&mut manifests.namespace)?;
+    blueprint.overlay(&mut manifests.pv)?;
+    blueprint.overlay(&mut manifests.pvc)?;
+    blueprint.overlay(&mut manifests.service)?;
+    blueprint.overlay(&mut manifests.deployment)?;
+    blueprint.overlay(&mut manifests.ingress)
+}
+
+/// Makes the database job create its user with the password in `secret`, a secret in the
+/// MariaDB namespace.
+pub(crate) fn with_password_secret(mut job: Job, secret: &str) -> Result<Job> {
+    let password = job_pod_spec_mut(&mut job)?
+        .containers
+        .iter_mut()
+        .flat_map(|c| c.env.iter_mut().flatten())
+        .find(|e| e.name == "DB_PASSWORD")
+        .and_then(|e| e.value_from.as_mut())
+        .and_then(|v| v.secret_key_ref.as_mut())
+        .context("database job has no DB_PASSWORD")?;
+    password.name = Some(secret.to_string());
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    /// The database config `create_wordpress_site` should get for `site`, derived with the
+    /// current master key.
+    pub fn database_config_for_site(&self, site: &str) -> Result<DatabaseConfig> {
+        DatabaseConfig::for_site(
+            site,
+            self.master_keys
+                .as_ref()
+                .context("deriving database names requires master keys")?,
+        )
+    }
+
+    /// Creates the `kwpm-<site>` namespace with a WordPress served at `domain`, backed by a new
+    /// database and user on the shared MariaDB, and waits until it is available. If any step
+    /// fails, what was created is deleted again so the creation can be retried.
+    pub async fn create_wordpress_site(
+        &self,
+        site: &str,
+        domain: &str,
+        database: &DatabaseConfig,
+    ) -> Result<()> {
+        self.create_site(site, domain, &AppKind::WordPress, Some(database))
+            .await
+    }
+
+    /// Like `create_wordpress_site` for any kind of app. `database` must be given exactly for
+    /// apps with a database.
+    pub async fn create_site(
+        &self,
+        site: &str,
+        domain: &str,
+        app: &AppKind,
+        database: Option<&DatabaseConfig>,
+    ) -> Result<()> {
+        self.create_site_with(site, domain, app, database, None)
+            .await
+    }
+
+    /// Like `create_site` for the app of `blueprint`, e.g. one from `fetch_blueprint`, starting
+    /// with its spec and with its overlays patched into the site's manifests.
+    pub async fn create_site_from_blueprint(
+        &self,
+        site: &str,
+        domain: &str,
+        blueprint: &Blueprint,
+        database: Option<&DatabaseConfig>,
+    ) -> Result<()> {
+        self.create_site_with(site, domain, &blueprint.spec.app, database, Some(blueprint))
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(site = %site, domain = %domain))]
+    async fn create_site_with(
+        &self,
+        site: &str,
+        domain: &str,
+        app: &AppKind,
+        database: Option<&DatabaseConfig>,
+        blueprint: Option<&Blueprint>,
+    ) -> Result<()> {
+        if !self.is_valid_site_name(site) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid site name: {}",
+                site
+            )));
+        }
+        if !is_valid_domain(domain) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid domain: {}",
+                domain
+            )));
+        }
+        if app.uses_database() != database.is_some() {
+            return Err(KwpmError::invalid_input(
+                "a database config is required exactly for apps with a database",
+            ));
+        }
+        if let Some(database) = database {
+            database.validate()?;
+        }
+        let spec = match blueprint {
+            Some(blueprint) => blueprint.site_spec()?,
+            None => SiteSpec {
+                app: app.clone(),
+                ..Default::default()
+            },
+        };
+
+        let ns_name = self.site_namespace(site);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        if namespace_api.get_opt(&ns_name).await?.is_some() {
+            return Err(KwpmError::already_exists(format!(
+                "site {} already exists",
+                site
+            )));
+        }
+        let instance = database.map_or(DEFAULT_MARIADB_INSTANCE, |database| {
+            database.instance.as_str()
+        });
+        let mariadb_pv = self.mariadb_volume(instance).await?;
+        if database.is_some() && !self.is_mariadb_instance_ready(instance).await? {
+            bail!(
+                "MariaDB {} is not ready, {} can be created once it is",
+                instance,
+                site
+            );
+        }
+
+        let mut manifests = site_manifests(
+            site,
+            &self.site_namespace(site),
+            domain,
+            app,
+            &self.pv_base_path,
+            mariadb_pv.as_ref(),
+        )?;
+        if database.is_some() {
+            manifests
+                .namespace
+                .labels_mut()
+                .insert(MARIADB_INSTANCE_LABEL.to_string(), instance.to_string());
+        }
+        self.adapt_volume(&mut manifests.pv, &mut manifests.pvc);
+        self.ingress_manager
+            .apply_to(&mut manifests.ingress, domain);
+        self.cluster_version
+            .adapt_namespace(&mut manifests.namespace);
+        let pod_spec = deployment_pod_spec_mut(&mut manifests.deployment)?;
+        set_mariadb_host(pod_spec, instance);
+        self.place_by_architecture(pod_spec).await?;
+        self.prepare_pod_spec(pod_spec);
+        if let Some(environment) = self.default_environment {
+            let profile = self.environment_profile(environment);
+            apply_environment_profile(&mut manifests.deployment, &profile, &BTreeMap::new())?;
+            apply_environment_annotations(&mut manifests.ingress, &profile);
+        }
+        if let Some(blueprint) = blueprint {
+            apply_blueprint(&mut manifests, blueprint, &spec)?;
+        }
+        self.check_policy("create_site", Some(site), &manifests.to_values()?)
+            .await?;
+
+        namespace_api
+            .create(&Default::default(), &self.labeled(&manifests.namespace))
+            .await?;
+        let mut rollback = Rollback::default();
+        rollback.record(CreatedResource::Namespace(ns_name));
+        if let Err(e) = self
+            .provision_site(site, domain, spec, database, &manifests, &mut rollback)
+            .await
+        {
+            let operation = format!("creating site {}", site);
+            return Err(self.roll_back(&operation, rollback, e).await);
+        }
+
+        Ok(())
+    }
+
+    /// The objects of a site in its new namespace, recording each in `rollback`.
+    async fn provision_site(
+        &self,
+        site: &str,
+        domain: &str,
+        spec: SiteSpec,
+        database: Option<&DatabaseConfig>,
+        manifests: &SiteManifests,
+        rollback: &mut Rollback,
+    ) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let lock = self.lock_site(site, "create_site").await?;
+        let result: Result<_> = async {
+            let mut progress = self.start_operation(site, "create_site").await;
+            let template = site_template(&spec.app);
+            self.run_provisioning_hooks(template, HookStage::PreCreate, site)
+                .await?;
+
+            if let Some(database) = database {
+                progress.step("creating database", Some(10)).await;
+                self.create_site_database(site, database, rollback).await?;
+            }
+
+            progress.step("creating workload", Some(40)).await;
+            self.create_site_workload(site, database, manifests, rollback)
+                .await?;
+            let profile = self
+                .default_environment
+                .map(|environment| self.environment_profile(environment));
+            if profile.as_ref().is_some_and(|profile| profile.basic_auth) {
+                self.ensure_basic_auth_secret(site).await?;
+            }
+            let is_wordpress = spec.app.is_wordpress();
+            let spec = SiteSpec {
+                environment: self.default_environment,
+                debug: profile.as_ref().is_some_and(|profile| profile.debug),
+                locale: spec
+                    .locale
+                    .clone()
+                    .or_else(|| is_wordpress.then(|| self.default_locale.clone()).flatten()),
+                timezone: spec.timezone.clone().or_else(|| {
+                    is_wordpress
+                        .then(|| self.default_timezone.clone())
+                        .flatten()
+                }),
+                ..spec
+            };
+            self.save_site_spec(site, &spec).await?;
+
+            progress.step("waiting for the site", Some(70)).await;
+            self.wait_for_deployment(&ns_name, "wordpress", SITE_READY_TIMEOUT)
+                .await?;
+            if let (Some(profile), Some(_)) = (&profile, &self.backup_storage) {
+                self.schedule_backups(site, Some(&profile.backup_schedule))
+                    .await?;
+            }
+            self.run_provisioning_hooks(template, HookStage::PostCreate, site)
+                .await?;
+            self.record_audit(site, "create_site", domain).await?;
+            self.record_site_revision(site, "create_site").await?;
+
+            progress.succeed().await;
+            Ok(())
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// The secret, volume, config maps, service, deployment and ingress of a site.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    async fn create_site_workload(
+        &self,
+        site: &str,
+        database: Option<&DatabaseConfig>,
+        manifests: &SiteManifests,
+        rollback: &mut Rollback,
+    ) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), &ns_name);
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let service_api: Api<Service> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+
+        if let Some(database) = database {
+            secret_api
+                .create(
+                    &Default::default(),
+                    &self.labeled(&database_secret(database)),
+                )
+                .await?;
+        }
+        if !self.dynamic_volumes {
+            let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+            pv_api
+                .create(&Default::default(), &self.labeled(&manifests.pv))
+                .await?;
+            rollback.record(CreatedResource::PersistentVolume(manifests.pv.name_any()));
+        }
+        pvc_api
+            .create(&Default::default(), &self.labeled(&manifests.pvc))
+            .await?;
+        for config_map in &manifests.config_maps {
+            config_map_api
+                .create(&Default::default(), &self.labeled(config_map))
+                .await?;
+        }
+        service_api
+            .create(&Default::default(), &self.labeled(&manifests.service))
+            .await?;
+        deployment_api
+            .create(&Default::default(), &self.labeled(&manifests.deployment))
+            .await?;
+        ingress_api
+            .create(&Default::default(), &self.labeled(&manifests.ingress))
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(site = %site, instance = %database.instance, database = %database.database))]
+    pub(crate) async fn create_site_database(
+        &self,
+        site: &str,
+        database: &DatabaseConfig,
+        rollback: &mut Rollback,
+    ) -> Result<()> {
+        let password_secret = database_password_secret_name(site);
+        let mariadb_namespace = mariadb_namespace(&database.instance);
+        let mariadb_secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace);
+        let password = Secret {
+            metadata: ObjectMeta {
+                name: Some(password_secret.clone()),
+                labels: Some(BTreeMap::from([(SITE_LABEL.to_string(), site.to_string())])),
+                ..Default::default()
+            },
+            string_data: Some(BTreeMap::from([(
+                "password".to_string(),
+                database.password.clone(),
+            )])),
+            ..Default::default()
+        };
+        mariadb_secret_api
+            .create(&Default::default(), &self.labeled(&password))
+            .await?;
+        rollback.record(CreatedResource::Secret {
+            namespace: mariadb_namespace.clone(),
+            name: password_secret.clone(),
+        });
+        rollback.record(CreatedResource::Database {
+            instance: database.instance.clone(),
+            database: database.database.clone(),
+            user: database.user.clone(),
+        });
+        self.run_job(
+            &mariadb_namespace,
+            with_password_secret(
+                database_job(DatabaseAction::Create, &database.database, &database.user)?,
+                &password_secret,
+            )?,
+            DATABASE_JOB_TIMEOUT,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Drops the database of `site` described by its `mysql-pass` secret from `instance`, with
+    /// its user and password secret if kwpm created them for the site.
+    pub(crate) async fn drop_site_database(
+        &self,
+        site: &str,
+        instance: &str,
+        secret: &Secret,
+    ) -> Result<()> {
+        let mariadb_namespace = mariadb_namespace(instance);
+        let mariadb_secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace);
+        let password_secret = database_password_secret_name(site);
+        // Only users kwpm created for the site are dropped, older sites may share theirs.
+        let own_user = mariadb_secret_api
+            .get_opt(&password_secret)
+            .await?
+            .is_some_and(|secret| ensure_managed(&secret, Some((SITE_LABEL, site))).is_ok());
+        self.run_job(
+            &mariadb_namespace,
+            database_job(
+                if own_user {
+                    DatabaseAction::DropWithUser
+                } else {
+                    DatabaseAction::Drop
+                },
+                &secret_value(secret, "db_name")?,
+                &secret_value(secret, "user")?,
+            )?,
+            DATABASE_JOB_TIMEOUT,
+        )
+        .await?;
+        if own_user {
+            mariadb_secret_api
+                .delete(&password_secret, &Default::default())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a site of any app kind with its namespace, database and volume. Files on the volume's host path
+    /// are kept, as the volume is retained. `confirmation` is the token of planning
+    /// [`DestructiveOperation::RemoveSite`].
+    pub async fn remove_wordpress_site(&self, site: &str, confirmation: &str) -> Result<()> {
+        self.confirm(
+            &DestructiveOperation::RemoveSite {
+                site: site.to_string(),
+            },
+            confirmation,
+        )
+        .await?;
+        self.delete_site(site).await
+    }
+
+    /// `remove_wordpress_site` for deletions confirmed otherwise, e.g. by deleting the
+    /// site's `WordPressSite`.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub(crate) async fn delete_site(&self, site: &str) -> Result<()> {
+        let ns_name = self.site_namespace(site);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = namespace_api
+            .get_opt(&ns_name)
+            .await?
+            .ok_or_else(|| KwpmError::not_found(format!("site {} does not exist", site)))?;
+        let is_preview = namespace
+            .metadata
+            .labels
+            .as_ref()
+            .is_some_and(|labels| labels.contains_key(PREVIEW_OF_LABEL));
+        if is_preview {
+            bail!("{} is a preview site, use delete_preview", site);
+        }
+        self.check_policy(
+            "remove_wordpress_site",
+            Some(site),
+            &[serde_json::to_value(&namespace)?],
+        )
+        .await?;
+        ensure_managed(&namespace, Some((SITE_LABEL, site)))?;
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let pv = pv_api.get_opt(&format!("{}-pv", ns_name)).await?;
+        if let Some(pv) = &pv {
+            ensure_managed(pv, Some((SITE_LABEL, site)))?;
+        }
+        let lock = self.lock_site(site, "remove_wordpress_site").await?;
+
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        let result: Result<_> = async {
+            let template = site_template(&self.stored_site_spec(site).await?.app);
+            self.run_provisioning_hooks(template, HookStage::PreDelete, site)
+                .await?;
+            if let Some(secret) = secret_api.get_opt("mysql-pass").await? {
+                self.drop_site_database(site, &namespace_mariadb_instance(&namespace), &secret)
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+
+        // Released before the namespace, and the lease with it, is deleted.
+        lock.release_after(result).await?;
+        namespace_api.delete(&ns_name, &Default::default()).await?;
+        if let Some(pv) = pv {
+            pv_api.delete(&pv.name_any(), &Default::default()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+    use crate::local_node_affinity;
+
+    fn keys() -> MasterKeys {
+        MasterKeys::parse(&format!("k1={}", STANDARD.encode([7u8; 32]))).unwrap()
+    }
+
+    #[test]
+    fn test_database_config_for_site() {
+        let database = DatabaseConfig::for_site("my-blog", &keys()).unwrap();
+
+        assert!(database.validate().is_ok());
+        assert!(database.database.starts_with("wp_"));
+        assert!(!database.database.contains("blog"));
+        assert_ne!(database.table_prefix, "wp_");
+        assert!(database.table_prefix.ends_with('_'));
+        let retried = DatabaseConfig::for_site("my-blog", &keys()).unwrap();
+        assert_eq!(retried.database, database.database);
+        assert_eq!(retried.table_prefix, database.table_prefix);
+        assert_ne!(retried.password, database.password);
+        assert_ne!(
+            DatabaseConfig::for_site("shop", &keys()).unwrap().database,
+            database.database
+        );
+        assert!(DatabaseConfig {
+            table_prefix: "wp-".to_string(),
+            ..database
+        }
+        .validate()
+        .is_err());
+    }
+
+    fn mariadb_pv() -> PersistentVolume {
+        PersistentVolume {
+            spec: Some(k8s_openapi::api::core::v1::PersistentVolumeSpec {
+                node_affinity: Some(local_node_affinity("node-1")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_site_manifests() {
+        let database = DatabaseConfig::for_site("my-blog", &keys()).unwrap();
+        let mariadb_pv = mariadb_pv();
+        let manifests = site_manifests(
+            "my-blog",
+            "kwpm-my-blog",
+            "blog.example.com",
+            &AppKind::WordPress,
+            "/data/volumes/kwpm",
+            Some(&mariadb_pv),
+        )
+        .unwrap();
+
+        assert_eq!(
+            database_secret(&database).string_data.unwrap()["table_prefix"],
+            database.table_prefix
+        );
+        let pv_spec = manifests.pv.spec.as_ref().unwrap();
+        assert_eq!(
+            pv_spec.local.as_ref().unwrap().path,
+            "/data/volumes/kwpm/my-blog"
+        );
+        assert_eq!(pv_spec.node_affinity, Some(local_node_affinity("node-1")));
+        assert_eq!(
+            manifests.pvc.spec.unwrap().volume_name.as_deref(),
+            Some("kwpm-my-blog-pv")
+        );
+        let rule = &manifests.ingress.spec.unwrap().rules.unwrap()[0];
+        assert_eq!(rule.host.as_deref(), Some("blog.example.com"));
+        let mut deployment = manifests.deployment;
+        let env = deployment_pod_spec_mut(&mut deployment).unwrap().containers[0]
+            .env
+            .clone()
+            .unwrap();
+        let host = env.iter().find(|e| e.name == "WORDPRESS_DB_HOST").unwrap();
+        assert_eq!(host.value.as_deref(), Some("mariadb.kwpm-mariadb"));
+    }
+
+    #[test]
+    fn test_site_manifests_for_other_apps() {
+        let php = AppKind::Php {
+            image: "matomo:5-fpm-alpine".to_string(),
+        };
+        let mut manifests = site_manifests(
+            "stats",
+            "kwpm-stats",
+            "stats.example.com",
+            &php,
+            "/data/volumes/kwpm",
+            Some(&mariadb_pv()),
+        )
+        .unwrap();
+        let container = &deployment_pod_spec_mut(&mut manifests.deployment)
+            .unwrap()
+            .containers[0];
+        let mut env: Vec<&str> = container
+            .env
+            .iter()
+            .flatten()
+            .map(|e| e.name.as_str())
+            .collect();
+        env.sort();
+        assert_eq!(container.image.as_deref(), Some("matomo:5-fpm-alpine"));
+        assert_eq!(env, ["DB_HOST", "DB_NAME", "DB_PASSWORD", "DB_USER"]);
+
+        let mut manifests = site_manifests(
+            "docs",
+            "kwpm-docs",
+            "docs.example.com",
+            &AppKind::Static,
+            "/data/volumes/kwpm",
+            None,
+        )
+        .unwrap();
+        assert_eq!(manifests.config_maps.len(), 1);
+        let pod_spec = deployment_pod_spec_mut(&mut manifests.deployment).unwrap();
+        assert_eq!(pod_spec.containers.len(), 1);
+        assert_eq!(pod_spec.containers[0].name, "nginx");
+        assert_eq!(pod_spec.volumes.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_is_valid_domain() {
+        assert!(is_valid_domain("blog.example.com"));
+        assert!(!is_valid_domain("localhost"));
+        assert!(!is_valid_domain("-blog.example.com"));
+        assert!(!is_valid_domain("Blog.example.com"));
+    }
+
+    #[test]
+    fn test_with_password_secret() {
+        let job = with_password_secret(
+            database_job(DatabaseAction::Create, "wp_blog", "wp_blog").unwrap(),
+            "kwpm-db-blog",
+        )
+        .unwrap();
+        let container = &job.spec.unwrap().template.spec.unwrap().containers[0];
+        let password = container
+            .env
+            .iter()
+            .flatten()
+            .find(|e| e.name == "DB_PASSWORD")
+            .unwrap();
+
+        assert_eq!(
+            password
+                .value_from
+                .as_ref()
+                .unwrap()
+                .secret_key_ref
+                .as_ref()
+                .unwrap()
+                .name
+                .as_deref(),
+            Some("kwpm-db-blog")
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, ResourceExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{KwpmError, Result},
+    metadata::ensure_managed,
+    notify::Notification,
+    provision::SITE_LABEL,
+    DatabaseAction, KwpmClient,
+};
+
+/// Namespace label naming the plan of a site, which decides its database quota.
+pub const PLAN_LABEL: &str = "kwpm.io/plan";
+/// Namespace annotation with the quota state a site was last put in, `warned` or `restricted`.
+const QUOTA_STATE_ANNOTATION: &str = "kwpm.io/database-quota";
+const QUOTA_JOB_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QuotaMode {
+    /// Only notifies when the database outgrows its quota.
+    Warn,
+    /// Also takes all privileges but reading and deleting from the site's database user until
+    /// it is back under its quota.
+    Enforce,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseQuota {
+    pub limit_mb: u64,
+    pub mode: QuotaMode,
+}
+
+/// The database quotas of the plans sites are on, so one runaway site can't fill the volume
+/// of a shared MariaDB. Sites without a quota are only measured.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DatabaseQuotaPolicy {
+    /// The quota of sites without a plan or on a plan not listed in `plans`.
+    pub default: Option<DatabaseQuota>,
+    pub plans: BTreeMap<String, DatabaseQuota>,
+}
+
+impl DatabaseQuotaPolicy {
+    pub fn quota(&self, plan: Option<&str>) -> Option<DatabaseQuota> {
+        plan.and_then(|plan| self.plans.get(plan))
+            .or(self.default.as_ref())
+            .copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum QuotaState {
+    Warned,
+    Restricted,
+}
+
+impl QuotaState {
+    fn as_str(self) -> &'static str {
+        match self {
+            QuotaState::Warned => "warned",
+            QuotaState::Restricted => "restricted",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "warned" => Some(QuotaState::Warned),
+            "restricted" => Some(QuotaState::Restricted),
+            _ => None,
+        }
+    }
+}
+
+/// The state a database of `used_bytes` should be in under `quota`.
+fn quota_state(used_bytes: u64, quota: Option<DatabaseQuota>) -> Option<QuotaState> {
+    let quota = quota?;
+    if used_bytes <= quota.limit_mb * 1024 * 1024 {
+        return None;
+    }
+    Some(match quota.mode {
+        QuotaMode::Warn => QuotaState::Warned,
+        QuotaMode::Enforce => QuotaState::Restricted,
+    })
+}
+
+/// Reads the output of a `size` database job.
+fn parse_database_size(output: &str) -> Result<u64> {
+    output
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| KwpmError::invalid_manifest(format!("unexpected size output: {}", output)))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseUsage {
+    pub site: String,
+    pub plan: Option<String>,
+    pub used_bytes: u64,
+    pub quota: Option<DatabaseQuota>,
+    /// Whether the site's database user can only read and delete.
+    pub writes_restricted: bool,
+}
+
+impl DatabaseUsage {
+    pub fn exceeded(&self) -> bool {
+        quota_state(self.used_bytes, self.quota).is_some()
+    }
+
+    pub fn used_mb(&self) -> u64 {
+        self.used_bytes.div_ceil(1024 * 1024)
+    }
+}
+
+impl KwpmClient {
+    pub fn with_database_quotas(mut self, policy: DatabaseQuotaPolicy) -> Self {
+        self.database_quotas = policy;
+        self
+    }
+
+    pub(crate) async fn managed_site_namespace(&self, site: &str) -> Result<Namespace> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespace = namespace_api
+            .get_opt(&self.site_namespace(site))
+            .await?
+            .ok_or_else(|| KwpmError::not_found(format!("site {} does not exist", site)))?;
+        ensure_managed(&namespace, Some((SITE_LABEL, site)))?;
+        Ok(namespace)
+    }
+
+    /// Puts the site on `plan`, or on none, for its database quota.
+    pub async fn set_site_plan(&self, site: &str, plan: Option<&str>) -> Result<()> {
+        self.managed_site_namespace(site).await?;
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        namespace_api
+            .patch(
+                &self.site_namespace(site),
+                &PatchParams::default(),
+                &Patch::Merge(json!({ "metadata": { "labels": { PLAN_LABEL: plan } } })),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The site's database usage and the quota state it was last put in.
+    async fn measure_database(&self, site: &str) -> Result<(DatabaseUsage, Option<QuotaState>)> {
+        let namespace = self.managed_site_namespace(site).await?;
+        let output = self
+            .run_site_database_job(site, DatabaseAction::Size, QUOTA_JOB_TIMEOUT)
+            .await?;
+        let plan = namespace.labels().get(PLAN_LABEL).cloned();
+        let state = namespace
+            .annotations()
+            .get(QUOTA_STATE_ANNOTATION)
+            .and_then(|state| QuotaState::parse(state));
+
+        let usage = DatabaseUsage {
+            site: site.to_string(),
+            quota: self.database_quotas.quota(plan.as_deref()),
+            plan,
+            used_bytes: parse_database_size(&output)?,
+            writes_restricted: state == Some(QuotaState::Restricted),
+        };
+        Ok((usage, state))
+    }
+
+    /// Measures the site's database without changing its quota state.
+    pub async fn database_usage(&self, site: &str) -> Result<DatabaseUsage> {
+        Ok(self.measure_database(site).await?.0)
+    }
+
+    /// Measures the site's database and applies its quota: notifies when it's exceeded,
+    /// restricts writes in `Enforce` mode and lifts the restriction once the database is back
+    /// under its quota.
+    pub async fn enforce_database_quota(&self, site: &str) -> Result<DatabaseUsage> {
+        let (usage, current) = self.measure_database(site).await?;
+        if quota_state(usage.used_bytes, usage.quota) == current {
+            return Ok(usage);
+        }
+
+        let lock = self.lock_site(site, "enforce_database_quota").await?;
+        let result = self.apply_database_quota(site, usage, current).await;
+        lock.release_after(result).await
+    }
+
+    /// `enforce_database_quota` for callers already holding the site's lock.
+    pub(crate) async fn enforce_database_quota_locked(&self, site: &str) -> Result<DatabaseUsage> {
+        let (usage, current) = self.measure_database(site).await?;
+        self.apply_database_quota(site, usage, current).await
+    }
+
+    async fn apply_database_quota(
+        &self,
+        site: &str,
+        mut usage: DatabaseUsage,
+        current: Option<QuotaState>,
+    ) -> Result<DatabaseUsage> {
+        let target = quota_state(usage.used_bytes, usage.quota);
+        if target == current {
+            return Ok(usage);
+        }
+
+        if current == Some(QuotaState::Restricted) {
+            self.run_site_database_job(site, DatabaseAction::Create, QUOTA_JOB_TIMEOUT)
+                .await?;
+        }
+        if target == Some(QuotaState::Restricted) {
+            self.run_site_database_job(site, DatabaseAction::RestrictWrites, QUOTA_JOB_TIMEOUT)
+                .await?;
+        }
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        namespace_api
+            .patch(
+                &self.site_namespace(site),
+                &PatchParams::default(),
+                &Patch::Merge(json!({
+                    "metadata": {
+                        "annotations": { QUOTA_STATE_ANNOTATION: target.map(QuotaState::as_str) }
+                    }
+                })),
+            )
+            .await?;
+        usage.writes_restricted = target == Some(QuotaState::Restricted);
+
+        let limit_mb = usage.quota.map_or(0, |quota| quota.limit_mb);
+        let message = match target {
+            Some(QuotaState::Warned) => format!(
+                "database uses {} MiB of its {} MiB quota",
+                usage.used_mb(),
+                limit_mb
+            ),
+            Some(QuotaState::Restricted) => format!(
+                "database uses {} MiB of its {} MiB quota, writes are restricted",
+                usage.used_mb(),
+                limit_mb
+            ),
+            None => format!(
+                "database is back under its quota at {} MiB",
+                usage.used_mb()
+            ),
+        };
+        self.record_audit(site, "enforce_database_quota", &message)
+            .await?;
+        if target.is_some() {
+            self.notify(&Notification {
+                subject: format!("Database quota exceeded by {}", site),
+                body: message,
+                data: json!({ "site": site, "usage": usage }),
+            })
+            .await?;
+        }
+
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_quota() {
+        let warn = DatabaseQuota {
+            limit_mb: 100,
+            mode: QuotaMode::Warn,
+        };
+        let enforce = DatabaseQuota {
+            limit_mb: 500,
+            mode: QuotaMode::Enforce,
+        };
+        let policy = DatabaseQuotaPolicy {
+            default: Some(warn),
+            plans: BTreeMap::from([("business".to_string(), enforce)]),
+        };
+        assert_eq!(policy.quota(None), Some(warn));
+        assert_eq!(policy.quota(Some("starter")), Some(warn));
+        assert_eq!(policy.quota(Some("business")), Some(enforce));
+        assert_eq!(DatabaseQuotaPolicy::default().quota(Some("business")), None);
+
+        let mb = 1024 * 1024;
+        assert_eq!(quota_state(100 * mb, Some(warn)), None);
+        assert_eq!(
+            quota_state(100 * mb + 1, Some(warn)),
+            Some(QuotaState::Warned)
+        );
+        assert_eq!(
+            quota_state(600 * mb, Some(enforce)),
+            Some(QuotaState::Restricted)
+        );
+        assert_eq!(quota_state(600 * mb, None), None);
+        assert_eq!(
+            QuotaState::parse(QuotaState::Warned.as_str()),
+            Some(QuotaState::Warned)
+        );
+
+        assert_eq!(parse_database_size("1048576\n").unwrap(), mb);
+        assert!(parse_database_size("ERROR 1045").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::time::Sleep;
+use tower::{Layer, Service};
+
+/// Client-side limit on Kubernetes API requests, so fleet-wide operations stay below the API
+/// server's priority and fairness thresholds. Requests beyond it wait instead of failing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KubeRateLimit {
+    /// Sustained requests per second.
+    pub qps: f64,
+    /// Requests that can be sent at once after being idle.
+    pub burst: u32,
+}
+
+impl Default for KubeRateLimit {
+    fn default() -> Self {
+        Self {
+            qps: 20.0,
+            burst: 40,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    qps: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: KubeRateLimit, now: Instant) -> Self {
+        let burst = f64::from(limit.burst.max(1));
+        Self {
+            qps: limit.qps,
+            burst,
+            tokens: burst,
+            refilled_at: now,
+        }
+    }
+
+    /// Takes a token, or returns how long to wait until one is available.
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.qps).min(self.burst);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.qps.max(f64::EPSILON),
+            ))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimitLayer {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(limit: KubeRateLimit) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket::new(limit, Instant::now()))),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            bucket: self.bucket.clone(),
+            sleep: None,
+            acquired: false,
+        }
+    }
+}
+
+pub(crate) struct RateLimit<S> {
+    inner: S,
+    bucket: Arc<Mutex<TokenBucket>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    acquired: bool,
+}
+
+impl<S, R> Service<R> for RateLimit<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        while !self.acquired {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            let acquired = self
+                .bucket
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .try_acquire(Instant::now());
+            match acquired {
+                Ok(()) => self.acquired = true,
+                Err(wait) => self.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.acquired = false;
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_allows_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(KubeRateLimit { qps: 2.0, burst: 3 }, start);
+
+        for _ in 0..3 {
+            assert!(bucket.try_acquire(start).is_ok());
+        }
+        assert_eq!(bucket.try_acquire(start), Err(Duration::from_millis(500)));
+
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_caps_idle_tokens_at_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(
+            KubeRateLimit {
+                qps: 10.0,
+                burst: 2,
+            },
+            start,
+        );
+        let later = start + Duration::from_secs(60);
+
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::{Method, Request};
+use tower::{BoxError, Service};
+
+use crate::{error::Result, KwpmClient};
+
+/// Returned for any change attempted through a read-only client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadOnlyViolation {
+    /// The operation or `METHOD path` of the rejected request.
+    pub operation: String,
+}
+
+impl fmt::Display for ReadOnlyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "read-only: {} is not allowed", self.operation)
+    }
+}
+
+impl std::error::Error for ReadOnlyViolation {}
+
+/// Gets, lists, watches and logs. Exec and attach are GETs as well but run commands.
+pub(crate) fn is_read_request(method: &Method, path: &str) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+        && !path
+            .rsplit('/')
+            .next()
+            .is_some_and(|subresource| matches!(subresource, "exec" | "attach" | "portforward"))
+}
+
+/// Passes read requests on to `send` and rejects everything else.
+pub(crate) struct ReadOnly<F> {
+    send: F,
+}
+
+impl<F, Fut, B, R, E> Service<Request<B>> for ReadOnly<F>
+where
+    F: Fn(Request<B>) -> Fut,
+    Fut: Future<Output = Result<R, E>> + Send + 'static,
+    E: Into<BoxError>,
+{
+    type Response = R;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<R, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        if !is_read_request(request.method(), request.uri().path()) {
+            let violation = ReadOnlyViolation {
+                operation: format!("{} {}", request.method(), request.uri().path()),
+            };
+            return Box::pin(async move { Err(violation.into()) });
+        }
+
+        let response = (self.send)(request);
+        Box::pin(async move { response.await.map_err(Into::into) })
+    }
+}
+
+impl KwpmClient {
+    /// Permits every get, list and status operation but fails any change to the cluster with
+    /// [`ReadOnlyViolation`], e.g. for dashboards and untrusted viewers. Needs a Tokio runtime.
+    pub fn read_only(mut self) -> Self {
+        let client = self.client.clone();
+        let send = move |request| {
+            let client = client.clone();
+            async move { client.send(request).await }
+        };
+        self.client = kube::Client::new(ReadOnly { send }, self.client.default_namespace());
+        self.read_only = true;
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails for changes kwpm makes outside of the cluster, e.g. to the backup storage.
+    pub(crate) fn ensure_writable(&self, operation: &str) -> Result<()> {
+        if self.read_only {
+            return Err(ReadOnlyViolation {
+                operation: operation.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_read_request() {
+        assert!(is_read_request(&Method::GET, "/api/v1/namespaces"));
+        assert!(is_read_request(
+            &Method::GET,
+            "/api/v1/namespaces/kwpm-blog/pods/wordpress-0/log"
+        ));
+        assert!(!is_read_request(
+            &Method::DELETE,
+            "/api/v1/namespaces/kwpm-blog"
+        ));
+        assert!(!is_read_request(
+            &Method::PATCH,
+            "/apis/apps/v1/namespaces/kwpm-blog/deployments/wordpress"
+        ));
+        assert!(!is_read_request(
+            &Method::GET,
+            "/api/v1/namespaces/kwpm-blog/pods/wordpress-0/exec"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_mutations() {
+        let mut service = ReadOnly {
+            send: |_request: Request<()>| async { Ok::<_, BoxError>("ok") },
+        };
+
+        let get = Request::get("/api/v1/namespaces").body(()).unwrap();
+        assert_eq!(service.call(get).await.unwrap(), "ok");
+
+        let delete = Request::delete("/api/v1/namespaces/kwpm-blog")
+            .body(())
+            .unwrap();
+        let error = service.call(delete).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "read-only: DELETE /api/v1/namespaces/kwpm-blog is not allowed"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, Result},
+    nginx::render_nginx_config,
+    KwpmClient,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Redirect {
+    /// Only redirect requests for this host, e.g. `www.example.com` for www → apex redirects.
+    pub source_host: Option<String>,
+    /// Exact path to redirect. Without a path every request to `source_host` is redirected and
+    /// the request URI is appended to `target`.
+    pub source_path: Option<String>,
+    pub target: String,
+    pub status: u16,
+}
+
+fn is_safe_nginx_token(value: &str) -> bool {
+    !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | ';' | '{' | '}' | '\\' | '$'))
+}
+
+impl Redirect {
+    pub fn validate(&self) -> Result<()> {
+        if ![301, 302, 307, 308].contains(&self.status) {
+            bail!("unsupported redirect status {}", self.status);
+        }
+        if self.source_host.is_none() && self.source_path.is_none() {
+            bail!("redirect to {} needs a source host or path", self.target);
+        }
+        if let Some(host) = &self.source_host {
+            if host.is_empty()
+                || !host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+            {
+                bail!("invalid redirect source host: {}", host);
+            }
+        }
+        if let Some(path) = &self.source_path {
+            if !path.starts_with('/') || !is_safe_nginx_token(path) {
+                bail!("invalid redirect source path: {}", path);
+            }
+        }
+        if !is_safe_nginx_token(&self.target) {
+            bail!("invalid redirect target: {}", self.target);
+        }
+
+        Ok(())
+    }
+
+    /// Renders the redirect as nginx directives for the site's server block.
+    pub fn to_nginx(&self) -> String {
+        let target = match self.source_path {
+            Some(_) => self.target.clone(),
+            None => format!("{}$request_uri", self.target.trim_end_matches('/')),
+        };
+        let redirect = format!("return {} {};", self.status, target);
+
+        match (&self.source_host, &self.source_path) {
+            (Some(host), Some(path)) => format!(
+                "location = {} {{\n    if ($host = \"{}\") {{\n        {}\n    }}\n    try_files $uri $uri/ /index.php?$args;\n}}",
+                path, host, redirect
+            ),
+            (None, Some(path)) => format!("location = {} {{\n    {}\n}}", path, redirect),
+            (Some(host), None) => format!("if ($host = \"{}\") {{\n    {}\n}}", host, redirect),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+impl KwpmClient {
+    pub async fn set_redirects(&self, site: &str, redirects: Vec<Redirect>) -> Result<()> {
+        for redirect in &redirects {
+            redirect.validate()?;
+        }
+
+        let mut spec = self.get_site_spec(site).await?;
+        spec.redirects = redirects;
+        self.check_policy(
+            "set_redirects",
+            Some(site),
+            &[serde_json::to_value(render_nginx_config(&spec)?)?],
+        )
+        .await?;
+        self.save_site_spec(site, &spec).await?;
+
+        self.apply_nginx_config(site, &spec).await?;
+        self.record_site_revision(site, "set_redirects").await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(host: Option<&str>, path: Option<&str>, target: &str) -> Redirect {
+        Redirect {
+            source_host: host.map(str::to_string),
+            source_path: path.map(str::to_string),
+            target: target.to_string(),
+            status: 301,
+        }
+    }
+
+    #[test]
+    fn test_host_redirect_keeps_request_uri() {
+        let redirect = redirect(Some("www.example.com"), None, "https://example.com/");
+
+        assert!(redirect.validate().is_ok());
+        assert_eq!(
+            redirect.to_nginx(),
+            "if ($host = \"www.example.com\") {\n    return 301 https://example.com$request_uri;\n}"
+        );
+    }
+
+    #[test]
+    fn test_path_redirect() {
+        let redirect = redirect(None, Some("/old-page"), "/new-page/");
+
+        assert!(redirect.validate().is_ok());
+        assert_eq!(
+            redirect.to_nginx(),
+            "location = /old-page {\n    return 301 /new-page/;\n}"
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_injection() {
+        assert!(redirect(None, Some("/a; }"), "/b").validate().is_err());
+        assert!(redirect(None, Some("/a"), "/b $host").validate().is_err());
+        assert!(redirect(Some("a\" || \"b"), None, "/b").validate().is_err());
+        assert!(redirect(None, None, "/b").validate().is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::Context;
+use reqwest::{header, StatusCode};
+use serde_json::Value;
+
+use crate::error::{bail, Result};
+
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+
+/// An image reference split into registry, repository and tag or digest, with Docker Hub
+/// defaults applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub reference: String,
+}
+
+impl ImageReference {
+    pub fn parse(image: &str) -> Result<Self> {
+        if image.is_empty() {
+            bail!("empty image reference");
+        }
+
+        let (name, reference) = match image.split_once('@') {
+            Some((name, digest)) => (name, digest.to_string()),
+            None => match image.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+                _ => (image, "latest".to_string()),
+            },
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), rest.to_string())
+            }
+            Some(_) => (DOCKER_HUB_REGISTRY.to_string(), name.to_string()),
+            None => (DOCKER_HUB_REGISTRY.to_string(), format!("library/{}", name)),
+        };
+        let registry = if registry == "docker.io" {
+            DOCKER_HUB_REGISTRY.to_string()
+        } else {
+            registry
+        };
+
+        Ok(Self {
+            registry,
+            repository,
+            reference,
+        })
+    }
+
+    pub fn is_digest(&self) -> bool {
+        self.reference.starts_with("sha256:")
+    }
+
+    /// The same image served from `mirror`, keeping repository and tag or digest.
+    pub fn mirrored(&self, mirror: &str) -> String {
+        let mirror = mirror
+            .split_once("://")
+            .map(|(_, host)| host)
+            .unwrap_or(mirror)
+            .trim_end_matches('/');
+        let separator = if self.is_digest() { '@' } else { ':' };
+
+        format!(
+            "{}/{}{}{}",
+            mirror, self.repository, separator, self.reference
+        )
+    }
+}
+
+pub(crate) const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+
+#[derive(Clone)]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryCredentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Minimal client for the OCI distribution API. Registries asking for bearer tokens get an
+/// anonymous one, or one for the configured credentials.
+pub(crate) struct Registry {
+    http: reqwest::Client,
+    origin: String,
+    base: String,
+    credentials: Option<RegistryCredentials>,
+    token: Option<String>,
+}
+
+impl Registry {
+    /// `registry` is a host such as `ghcr.io`, or a URL like `http://registry.local:5000` for
+    /// registries without TLS.
+    pub(crate) fn new(registry: &str, repository: &str) -> Self {
+        let origin = if registry.contains("://") {
+            registry.trim_end_matches('/').to_string()
+        } else {
+            format!("https://{}", registry)
+        };
+
+        Self {
+            http: reqwest::Client::new(),
+            base: format!("{}/v2/{}", origin, repository),
+            origin,
+            credentials: None,
+            token: None,
+        }
+    }
+
+    pub(crate) fn with_credentials(mut self, credentials: Option<RegistryCredentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub(crate) async fn manifest(&mut self, reference: &str, accept: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/manifests/{}", self.base, reference);
+        let accept = accept.to_string();
+        let response = self
+            .send(move |http| http.get(&url).header(header::ACCEPT, &accept))
+            .await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub(crate) async fn blob(&mut self, digest: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/blobs/{}", self.base, digest);
+        let response = self.send(move |http| http.get(&url)).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub(crate) async fn push_blob(&mut self, digest: &str, bytes: Vec<u8>) -> Result<()> {
+        let url = format!("{}/blobs/{}", self.base, digest);
+        let exists = self.send(move |http| http.head(&url)).await.is_ok();
+        if exists {
+            return Ok(());
+        }
+
+        let url = format!("{}/blobs/uploads/", self.base);
+        let response = self.send(move |http| http.post(&url)).await?;
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .context("registry returned no upload location")?;
+        let location = if location.starts_with('/') {
+            format!("{}{}", self.origin, location)
+        } else {
+            location.to_string()
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}digest={}", location, separator, digest);
+
+        self.send(move |http| {
+            http.put(&url)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(bytes.clone())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn push_manifest(
+        &mut self,
+        reference: &str,
+        media_type: &str,
+        bytes: Vec<u8>,
+    ) -> Result<()> {
+        let url = format!("{}/manifests/{}", self.base, reference);
+        let media_type = media_type.to_string();
+        self.send(move |http| {
+            http.put(&url)
+                .header(header::CONTENT_TYPE, &media_type)
+                .body(bytes.clone())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        for _ in 0..2 {
+            let mut builder = request(&self.http);
+            if let Some(token) = self.token.as_ref() {
+                builder = builder.bearer_auth(token);
+            } else if let Some(credentials) = &self.credentials {
+                builder = builder.basic_auth(&credentials.username, Some(&credentials.password));
+            }
+            let response = builder.send().await?;
+
+            if response.status() == StatusCode::UNAUTHORIZED && self.token.is_none() {
+                let challenge = response
+                    .headers()
+                    .get(header::WWW_AUTHENTICATE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                if !challenge.starts_with("Bearer ") {
+                    bail!("registry at {} rejected the credentials", self.origin);
+                }
+                self.token =
+                    Some(bearer_token(&self.http, &challenge, self.credentials.as_ref()).await?);
+                continue;
+            }
+
+            return Ok(response.error_for_status()?);
+        }
+
+        bail!("registry at {} rejected the bearer token", self.origin)
+    }
+}
+
+/// Fetches a token for a `Bearer realm=...,service=...,scope=...` challenge, anonymously unless
+/// credentials are given.
+async fn bearer_token(
+    http: &reqwest::Client,
+    challenge: &str,
+    credentials: Option<&RegistryCredentials>,
+) -> Result<String> {
+    let params = challenge
+        .strip_prefix("Bearer ")
+        .with_context(|| format!("unsupported registry auth challenge: {}", challenge))?;
+    let param = |key: &str| {
+        params.split(',').find_map(|p| {
+            let (k, v) = p.trim().split_once('=')?;
+            (k == key).then(|| v.trim_matches('"').to_string())
+        })
+    };
+
+    let realm = param("realm").context("registry auth challenge has no realm")?;
+    let query: Vec<(&str, String)> = [("service", param("service")), ("scope", param("scope"))]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k, v)))
+        .collect();
+
+    let mut request = http.get(realm).query(&query);
+    if let Some(credentials) = credentials {
+        request = request.basic_auth(&credentials.username, Some(&credentials.password));
+    }
+    let response: Value = request.send().await?.error_for_status()?.json().await?;
+
+    Ok(response
+        .get("token")
+        .or_else(|| response.get("access_token"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .context("registry returned no token")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_reference() {
+        assert_eq!(
+            ImageReference::parse("nginx:alpine").unwrap(),
+            ImageReference {
+                registry: DOCKER_HUB_REGISTRY.to_string(),
+                repository: "library/nginx".to_string(),
+                reference: "alpine".to_string(),
+            }
+        );
+        assert_eq!(
+            ImageReference::parse("bitnami/wordpress")
+                .unwrap()
+                .reference,
+            "latest"
+        );
+        assert_eq!(
+            ImageReference::parse("localhost:5000/acme/wordpress@sha256:abc").unwrap(),
+            ImageReference {
+                registry: "localhost:5000".to_string(),
+                repository: "acme/wordpress".to_string(),
+                reference: "sha256:abc".to_string(),
+            }
+        );
+    }
+}
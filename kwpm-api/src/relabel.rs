@@ -0,0 +1,346 @@
+use std::{collections::BTreeMap, fmt};
+
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        batch::v1::CronJob,
+        core::v1::{
+            ConfigMap, Namespace, PersistentVolume, PersistentVolumeClaim, Secret, Service,
+        },
+        networking::v1::Ingress,
+    },
+    NamespaceResourceScope,
+};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api, Resource, ResourceExt,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{KwpmError, Result},
+    metadata::{MANAGED_BY, MANAGED_BY_LABEL},
+    provision::SITE_LABEL,
+    KwpmClient,
+};
+
+/// Labels and annotations to set on the objects of sites, `null` removing one.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetadataChange {
+    pub labels: BTreeMap<String, Option<String>>,
+    pub annotations: BTreeMap<String, Option<String>>,
+}
+
+impl MetadataChange {
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.annotations.is_empty()
+    }
+
+    /// Rejects keys and label values Kubernetes doesn't accept, and keys kwpm relies on.
+    pub fn check(&self) -> Result<()> {
+        if self.is_empty() {
+            return Err(KwpmError::invalid_input(
+                "no labels or annotations to change",
+            ));
+        }
+        for (key, value) in &self.labels {
+            check_key(key)?;
+            if let Some(value) = value {
+                if !is_valid_label_value(value) {
+                    return Err(KwpmError::invalid_input(format!(
+                        "invalid value of label {}: {}",
+                        key, value
+                    )));
+                }
+            }
+        }
+        for key in self.annotations.keys() {
+            check_key(key)?;
+        }
+        Ok(())
+    }
+
+    /// The merge patch making the change.
+    pub fn to_patch(&self) -> Value {
+        let mut metadata = serde_json::Map::new();
+        if !self.labels.is_empty() {
+            metadata.insert("labels".to_string(), json!(self.labels));
+        }
+        if !self.annotations.is_empty() {
+            metadata.insert("annotations".to_string(), json!(self.annotations));
+        }
+        json!({ "metadata": metadata })
+    }
+}
+
+/// `a=1, -b` for the audit log.
+impl fmt::Display for MetadataChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |changes: &BTreeMap<String, Option<String>>| -> Vec<String> {
+            changes
+                .iter()
+                .map(|(key, value)| match value {
+                    Some(value) => format!("{}={}", key, value),
+                    None => format!("-{}", key),
+                })
+                .collect()
+        };
+        let mut parts = Vec::new();
+        if !self.labels.is_empty() {
+            parts.push(format!("labels {}", describe(&self.labels).join(", ")));
+        }
+        if !self.annotations.is_empty() {
+            parts.push(format!(
+                "annotations {}",
+                describe(&self.annotations).join(", ")
+            ));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+/// `key=value` sets a label or annotation and `key-` removes it, like `kubectl label`.
+pub fn parse_metadata_arg(arg: &str) -> Result<(String, Option<String>)> {
+    match (arg.split_once('='), arg.strip_suffix('-')) {
+        (Some((key, value)), _) => Ok((key.to_string(), Some(value.to_string()))),
+        (None, Some(key)) => Ok((key.to_string(), None)),
+        (None, None) => Err(KwpmError::invalid_input(format!(
+            "expected key=value or key-, got {}",
+            arg
+        ))),
+    }
+}
+
+/// Up to 63 alphanumerics, `-`, `_` and `.`, starting and ending with an alphanumeric.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub fn is_valid_label_value(value: &str) -> bool {
+    value.is_empty() || is_valid_name(value)
+}
+
+/// Label and annotation keys, a name with an optional DNS subdomain prefix.
+pub fn is_valid_metadata_key(key: &str) -> bool {
+    let (prefix, name) = key
+        .rsplit_once('/')
+        .map_or((None, key), |(p, n)| (Some(p), n));
+    is_valid_name(name)
+        && prefix.is_none_or(|prefix| {
+            !prefix.is_empty()
+                && prefix.len() <= 253
+                && prefix.split('.').all(|part| {
+                    !part.is_empty()
+                        && !part.starts_with('-')
+                        && !part.ends_with('-')
+                        && part
+                            .chars()
+                            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                })
+        })
+}
+
+fn check_key(key: &str) -> Result<()> {
+    if !is_valid_metadata_key(key) {
+        return Err(KwpmError::invalid_input(format!("invalid key: {}", key)));
+    }
+    if key.starts_with("kwpm.io/") || key == MANAGED_BY_LABEL {
+        return Err(KwpmError::invalid_input(format!(
+            "{} is managed by kwpm",
+            key
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelabelReport {
+    pub relabeled: Vec<String>,
+    /// Objects changed across the relabeled sites.
+    pub objects: usize,
+    pub failed: Vec<(String, String)>,
+}
+
+impl KwpmClient {
+    async fn relabel_namespaced<K>(&self, namespace: &str, patch: &Value) -> Result<usize>
+    where
+        K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>
+            + Clone
+            + DeserializeOwned
+            + fmt::Debug,
+    {
+        let api: Api<K> = Api::namespaced(self.client.clone(), namespace);
+        let objects = api
+            .list(&ListParams::default().labels(&format!("{}={}", MANAGED_BY_LABEL, MANAGED_BY)))
+            .await?;
+        for object in &objects.items {
+            api.patch(
+                &object.name_any(),
+                &PatchParams::default(),
+                &Patch::Merge(patch),
+            )
+            .await?;
+        }
+        Ok(objects.items.len())
+    }
+
+    /// Changes the namespace and the kwpm-managed objects of one site, returning how many.
+    async fn relabel_site(&self, site: &str, change: &MetadataChange) -> Result<usize> {
+        let ns_name = self.site_namespace(site);
+        let patch = change.to_patch();
+
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        namespace_api
+            .patch(&ns_name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await?;
+        let mut objects = 1;
+        objects += self
+            .relabel_namespaced::<Deployment>(&ns_name, &patch)
+            .await?;
+        objects += self.relabel_namespaced::<Service>(&ns_name, &patch).await?;
+        objects += self.relabel_namespaced::<Ingress>(&ns_name, &patch).await?;
+        objects += self
+            .relabel_namespaced::<PersistentVolumeClaim>(&ns_name, &patch)
+            .await?;
+        objects += self
+            .relabel_namespaced::<ConfigMap>(&ns_name, &patch)
+            .await?;
+        objects += self.relabel_namespaced::<Secret>(&ns_name, &patch).await?;
+        objects += self.relabel_namespaced::<CronJob>(&ns_name, &patch).await?;
+
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let pvs = pv_api
+            .list(&ListParams::default().labels(&format!("{}={}", SITE_LABEL, site)))
+            .await?;
+        for pv in &pvs.items {
+            pv_api
+                .patch(
+                    &pv.name_any(),
+                    &PatchParams::default(),
+                    &Patch::Merge(&patch),
+                )
+                .await?;
+        }
+        objects += pvs.items.len();
+
+        self.record_audit(site, "relabel", &change.to_string())
+            .await?;
+        Ok(objects)
+    }
+
+    /// Applies `change` to the namespace, volume and kwpm-managed objects of every site whose
+    /// namespace matches `selector`, all sites if empty. Pod templates aren't changed, so no
+    /// pods restart. A failing site doesn't stop the others.
+    #[tracing::instrument(skip_all, fields(selector = %selector))]
+    pub async fn relabel(&self, selector: &str, change: &MetadataChange) -> Result<RelabelReport> {
+        change.check()?;
+        self.check_policy(
+            "relabel",
+            None,
+            &[json!({ "selector": selector, "change": change })],
+        )
+        .await?;
+
+        let mut report = RelabelReport::default();
+        for site in self.list_site_names(selector).await? {
+            match self.relabel_site(&site, change).await {
+                Ok(objects) => {
+                    report.objects += objects;
+                    report.relabeled.push(site);
+                }
+                Err(e) => {
+                    tracing::warn!(site = %site, error = %format!("{:#}", e), "relabeling failed");
+                    report.failed.push((site, format!("{:#}", e)));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(labels: &[(&str, Option<&str>)]) -> MetadataChange {
+        MetadataChange {
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.map(str::to_string)))
+                .collect(),
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_metadata_key() {
+        assert!(is_valid_metadata_key("cost-center"));
+        assert!(is_valid_metadata_key("example.com/cost-center"));
+        assert!(!is_valid_metadata_key("Example.com/cost-center"));
+        assert!(!is_valid_metadata_key("/cost-center"));
+        assert!(!is_valid_metadata_key("cost center"));
+        assert!(!is_valid_metadata_key(&"a".repeat(64)));
+        assert!(is_valid_label_value(""));
+        assert!(!is_valid_label_value("-web"));
+    }
+
+    #[test]
+    fn test_parse_metadata_arg() {
+        assert_eq!(
+            parse_metadata_arg("team=web").unwrap(),
+            ("team".to_string(), Some("web".to_string()))
+        );
+        assert_eq!(
+            parse_metadata_arg("team=").unwrap(),
+            ("team".to_string(), Some(String::new()))
+        );
+        assert_eq!(
+            parse_metadata_arg("team-").unwrap(),
+            ("team".to_string(), None)
+        );
+        assert!(parse_metadata_arg("team").is_err());
+    }
+
+    #[test]
+    fn test_check_metadata_change() {
+        assert!(change(&[("example.com/cost-center", Some("web"))])
+            .check()
+            .is_ok());
+        assert!(change(&[("team", None)]).check().is_ok());
+        assert!(change(&[]).check().is_err());
+        assert!(change(&[("kwpm.io/plan", Some("pro"))]).check().is_err());
+        assert!(change(&[(MANAGED_BY_LABEL, None)]).check().is_err());
+        assert!(change(&[("team", Some("web team"))]).check().is_err());
+    }
+
+    #[test]
+    fn test_metadata_change_patch() {
+        let mut change = change(&[("team", Some("web")), ("legacy", None)]);
+        change.annotations.insert(
+            "example.com/owner".to_string(),
+            Some("web@example.com".to_string()),
+        );
+        assert_eq!(
+            change.to_patch(),
+            json!({
+                "metadata": {
+                    "labels": { "team": "web", "legacy": null },
+                    "annotations": { "example.com/owner": "web@example.com" },
+                }
+            })
+        );
+        assert_eq!(
+            change.to_string(),
+            "labels -legacy, team=web; annotations example.com/owner=web@example.com"
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::Job,
+    core::v1::{ObjectReference, PersistentVolumeClaim, Pod, Secret},
+};
+use kube::{
+    api::{DeleteParams, ListParams, LogParams, ObjectMeta, Patch, PatchParams},
+    runtime::{wait::await_condition, watcher, WatchStreamExt},
+    Api, ResourceExt,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    database_job,
+    error::{bail, Result},
+    job::is_job_succeeded,
+    manifest::{job_pod_spec_mut, set_env},
+    mariadb::mariadb_namespace,
+    notify::Notification,
+    provision::{database_password_secret_name, with_password_secret, SITE_LABEL},
+    secret_value,
+    site::is_deployment_available,
+    DatabaseAction, KwpmClient,
+};
+
+const REPAIR_JOB_TIMEOUT: Duration = Duration::from_secs(300);
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(300);
+/// A site isn't remediated again within this period, so fixes that don't stick escalate
+/// instead of being retried forever.
+const REMEDIATION_COOLDOWN: Duration = Duration::from_secs(1800);
+const CRASH_LOG_LINES: i64 = 200;
+
+/// A cause of a crash loop the diagnostics can recognize.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Diagnosis {
+    /// MariaDB rejects the site's user or its database is missing.
+    DatabaseAccess,
+    /// MariaDB isn't reachable or not ready.
+    DatabaseUnreachable,
+    /// The site volume claim isn't bound.
+    VolumeUnbound,
+    /// The web server can't write to the site volume.
+    VolumeNotWritable,
+    /// `.htaccess` contains binary data or PHP.
+    CorruptHtaccess,
+}
+
+impl Diagnosis {
+    fn describe(self) -> &'static str {
+        match self {
+            Diagnosis::DatabaseAccess => "database credentials are rejected",
+            Diagnosis::DatabaseUnreachable => "MariaDB is unreachable",
+            Diagnosis::VolumeUnbound => "the volume claim is not bound",
+            Diagnosis::VolumeNotWritable => "the volume is not writable",
+            Diagnosis::CorruptHtaccess => ".htaccess is corrupt",
+        }
+    }
+}
+
+/// What `remediate_site` found and did.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Remediation {
+    pub site: String,
+    pub diagnoses: Vec<Diagnosis>,
+    pub fixes: Vec<String>,
+    pub recovered: bool,
+}
+
+/// Containers of the pod waiting in `CrashLoopBackOff`.
+pub fn crash_looping_containers(pod: &Pod) -> Vec<String> {
+    pod.status
+        .iter()
+        .flat_map(|status| status.container_statuses.iter().flatten())
+        .filter(|status| {
+            status
+                .state
+                .as_ref()
+                .and_then(|state| state.waiting.as_ref())
+                .and_then(|waiting| waiting.reason.as_deref())
+                == Some("CrashLoopBackOff")
+        })
+        .map(|status| status.name.clone())
+        .collect()
+}
+
+/// Diagnoses from the logs of crashed containers.
+pub fn diagnose_logs(logs: &str) -> Vec<Diagnosis> {
+    const PATTERNS: &[(&str, Diagnosis)] = &[
+        ("Access denied for user", Diagnosis::DatabaseAccess),
+        ("Unknown database", Diagnosis::DatabaseAccess),
+        (
+            "Can't connect to MySQL server",
+            Diagnosis::DatabaseUnreachable,
+        ),
+        ("Unknown MySQL server host", Diagnosis::DatabaseUnreachable),
+        ("php_network_getaddresses", Diagnosis::DatabaseUnreachable),
+        ("Permission denied", Diagnosis::VolumeNotWritable),
+        ("Read-only file system", Diagnosis::VolumeNotWritable),
+    ];
+
+    let mut diagnoses: Vec<Diagnosis> = PATTERNS
+        .iter()
+        .filter(|(pattern, _)| logs.contains(pattern))
+        .map(|(_, diagnosis)| *diagnosis)
+        .collect();
+    diagnoses.sort();
+    diagnoses.dedup();
+    diagnoses
+}
+
+/// Diagnoses from the output of the repair job's `diagnose` action.
+pub fn parse_diagnostics_output(output: &str) -> Vec<Diagnosis> {
+    output
+        .lines()
+        .filter_map(|line| match line.trim() {
+            "volume: not-writable" => Some(Diagnosis::VolumeNotWritable),
+            "htaccess: corrupt" => Some(Diagnosis::CorruptHtaccess),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn site_repair_job(site: &str, action: &str) -> Result<Job> {
+    let mut job: Job = serde_yaml::from_str(include_str!(
+        "../../kubernetes/remediation/site-repair-job.yaml"
+    ))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert(SITE_LABEL.to_string(), site.to_string());
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "repair",
+        &[("ACTION", action.to_string())],
+    )?;
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    async fn run_site_repair_job(&self, site: &str, action: &str) -> Result<String> {
+        let ns_name = self.site_namespace(site);
+        let job = self
+            .create_job(&ns_name, site_repair_job(site, action)?)
+            .await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+
+        let job = self
+            .wait_for_job(&ns_name, &job_name, REPAIR_JOB_TIMEOUT)
+            .await?;
+        let output = self.job_logs(&ns_name, &job_name).await?;
+        if !is_job_succeeded(&job) {
+            bail!("{} of {} failed: {}", action, site, output.trim());
+        }
+
+        Ok(output)
+    }
+
+    async fn diagnose_site(&self, site: &str, pods: &[Pod]) -> Result<Vec<Diagnosis>> {
+        let ns_name = self.site_namespace(site);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let mut logs = String::new();
+        for pod in pods {
+            for container in crash_looping_containers(pod) {
+                let params = LogParams {
+                    container: Some(container),
+                    previous: true,
+                    tail_lines: Some(CRASH_LOG_LINES),
+                    ..Default::default()
+                };
+                if let Ok(container_logs) = pod_api.logs(&pod.name_any(), &params).await {
+                    logs.push_str(&container_logs);
+                }
+            }
+        }
+        let mut diagnoses = diagnose_logs(&logs);
+
+        let uses_database = self.stored_site_spec(site).await?.app.uses_database();
+        if uses_database
+            && !self
+                .is_mariadb_instance_ready(&self.site_mariadb_instance(site).await?)
+                .await?
+        {
+            diagnoses.push(Diagnosis::DatabaseUnreachable);
+        }
+
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), &ns_name);
+        let bound = pvc_api
+            .get_opt("wp-pv-claim")
+            .await?
+            .and_then(|pvc| pvc.status)
+            .and_then(|status| status.phase)
+            .is_some_and(|phase| phase == "Bound");
+        if bound {
+            let output = self.run_site_repair_job(site, "diagnose").await?;
+            diagnoses.extend(parse_diagnostics_output(&output));
+        } else {
+            diagnoses.push(Diagnosis::VolumeUnbound);
+        }
+
+        diagnoses.sort();
+        diagnoses.dedup();
+        Ok(diagnoses)
+    }
+
+    /// Sets the password of the site's database user to the one the site uses, creating the
+    /// database and user if they are missing.
+    async fn reset_database_access(&self, site: &str) -> Result<()> {
+        let secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        let secret = secret_api.get("mysql-pass").await?;
+        let password_secret_name = database_password_secret_name(site);
+
+        let password_secret = Secret {
+            metadata: ObjectMeta {
+                name: Some(password_secret_name.clone()),
+                labels: Some(BTreeMap::from([(SITE_LABEL.to_string(), site.to_string())])),
+                ..Default::default()
+            },
+            string_data: Some(BTreeMap::from([(
+                "password".to_string(),
+                secret_value(&secret, "password")?,
+            )])),
+            ..Default::default()
+        };
+        let mariadb_namespace = mariadb_namespace(&self.site_mariadb_instance(site).await?);
+        let mariadb_secret_api: Api<Secret> =
+            Api::namespaced(self.client.clone(), &mariadb_namespace);
+        mariadb_secret_api
+            .patch(
+                &password_secret_name,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&password_secret)),
+            )
+            .await?;
+
+        self.run_job(
+            &mariadb_namespace,
+            with_password_secret(
+                database_job(
+                    DatabaseAction::ResetPassword,
+                    &secret_value(&secret, "db_name")?,
+                    &secret_value(&secret, "user")?,
+                )?,
+                &password_secret_name,
+            )?,
+            REPAIR_JOB_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Applies the known-safe fix for `diagnosis`, returning what was done, or `None` if
+    /// there is none.
+    async fn fix(&self, site: &str, diagnosis: Diagnosis) -> Result<Option<String>> {
+        let fix = match diagnosis {
+            Diagnosis::DatabaseAccess => {
+                self.reset_database_access(site).await?;
+                "reset the database user's password and grants"
+            }
+            Diagnosis::VolumeNotWritable => {
+                self.run_site_repair_job(site, "fix-volume").await?;
+                "reset the ownership of the site files"
+            }
+            Diagnosis::CorruptHtaccess => {
+                self.run_site_repair_job(site, "fix-htaccess").await?;
+                "moved the corrupt .htaccess aside"
+            }
+            Diagnosis::DatabaseUnreachable | Diagnosis::VolumeUnbound => return Ok(None),
+        };
+
+        Ok(Some(fix.to_string()))
+    }
+
+    async fn escalate_remediation(&self, remediation: &Remediation) -> Result<()> {
+        let causes: Vec<&str> = remediation
+            .diagnoses
+            .iter()
+            .map(|diagnosis| diagnosis.describe())
+            .collect();
+        let causes = if causes.is_empty() {
+            "no known cause".to_string()
+        } else {
+            causes.join(", ")
+        };
+        let mut message = format!(
+            "{} is crash looping and did not recover. Diagnosis: {}.",
+            remediation.site, causes
+        );
+        if !remediation.fixes.is_empty() {
+            message.push_str(&format!(" Tried: {}.", remediation.fixes.join(", ")));
+        }
+
+        self.record_warning(
+            ObjectReference {
+                api_version: Some("apps/v1".to_string()),
+                kind: Some("Deployment".to_string()),
+                name: Some("wordpress".to_string()),
+                namespace: Some(self.site_namespace(&remediation.site)),
+                ..Default::default()
+            },
+            "CrashLoopUnresolved",
+            message.clone(),
+        )
+        .await?;
+        self.notify(&Notification {
+            subject: format!("{} is crash looping", remediation.site),
+            body: message,
+            data: json!(remediation),
+        })
+        .await
+    }
+
+    /// Diagnoses a site whose pods are in `CrashLoopBackOff`, applies the known-safe fixes for
+    /// what was found, restarts the crashed pods and waits for the site to recover. Sites that
+    /// don't recover are escalated with a warning event and a notification.
+    pub async fn remediate_site(&self, site: &str) -> Result<Remediation> {
+        let lock = self.lock_site(site, "remediate_site").await?;
+        let result: Result<_> = async {
+            let ns_name = self.site_namespace(site);
+            let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns_name);
+            let crashing: Vec<Pod> = pod_api
+                .list(&ListParams::default().labels("app=wordpress"))
+                .await?
+                .items
+                .into_iter()
+                .filter(|pod| !crash_looping_containers(pod).is_empty())
+                .collect();
+
+            let mut remediation = Remediation {
+                site: site.to_string(),
+                ..Default::default()
+            };
+            if crashing.is_empty() {
+                remediation.recovered = true;
+                return Ok(remediation);
+            }
+
+            remediation.diagnoses = self.diagnose_site(site, &crashing).await?;
+            for diagnosis in &remediation.diagnoses {
+                match self.fix(site, *diagnosis).await {
+                    Ok(Some(fix)) => remediation.fixes.push(fix),
+                    Ok(None) => {}
+                    Err(e) => remediation.fixes.push(format!(
+                        "fixing that {} failed: {:#}",
+                        diagnosis.describe(),
+                        e
+                    )),
+                }
+            }
+
+            if !remediation.fixes.is_empty() {
+                self.record_audit(site, "remediate_site", &remediation.fixes.join(", "))
+                    .await?;
+                for pod in &crashing {
+                    pod_api
+                        .delete(&pod.name_any(), &DeleteParams::default())
+                        .await?;
+                }
+                let deployment_api: Api<Deployment> =
+                    Api::namespaced(self.client.clone(), &ns_name);
+                remediation.recovered = tokio::time::timeout(
+                    RECOVERY_TIMEOUT,
+                    await_condition(deployment_api, "wordpress", |d: Option<&Deployment>| {
+                        d.is_some_and(is_deployment_available)
+                    }),
+                )
+                .await
+                .is_ok_and(|result| result.is_ok());
+            }
+
+            if !remediation.recovered {
+                self.escalate_remediation(&remediation).await?;
+            }
+            Ok(remediation)
+        }
+        .await;
+        lock.release_after(result).await
+    }
+
+    /// Watches the pods of all sites and remediates sites that start crash looping, one at
+    /// a time, until the watch ends. Paused sites are skipped.
+    pub async fn run_remediation(self) -> Result<()> {
+        let pods: Api<Pod> = Api::all(self.client.clone());
+        let mut crash_looping = watcher(pods, watcher::Config::default().labels("app=wordpress"))
+            .applied_objects()
+            .boxed();
+        let mut last_remediated: HashMap<String, Instant> = HashMap::new();
+
+        while let Some(pod) = crash_looping.next().await {
+            let Ok(pod) = pod else { continue };
+            if crash_looping_containers(&pod).is_empty() {
+                continue;
+            }
+            let Some(site) = pod
+                .namespace()
+                .as_deref()
+                .and_then(|ns| self.site_name(ns))
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            // The pod is seen again on its next change if the lookup failed.
+            if !matches!(self.is_site_paused(&site).await, Ok(false)) {
+                continue;
+            }
+            if last_remediated
+                .get(&site)
+                .is_some_and(|at| at.elapsed() < REMEDIATION_COOLDOWN)
+            {
+                continue;
+            }
+            last_remediated.insert(site.clone(), Instant::now());
+
+            if let Err(e) = self.remediate_site(&site).await {
+                tracing::warn!(site = %site, error = %format!("{:#}", e), "remediation failed");
+                let remediation = Remediation {
+                    site,
+                    fixes: vec![format!("remediation failed: {:#}", e)],
+                    ..Default::default()
+                };
+                // Failing to escalate must not stop remediating other sites; the site is
+                // picked up again after the cooldown if it's still crash looping.
+                self.escalate_remediation(&remediation).await.ok();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{
+        ContainerState, ContainerStateWaiting, ContainerStatus, PodStatus,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_crash_looping_containers() {
+        let waiting = |name: &str, reason: &str| ContainerStatus {
+            name: name.to_string(),
+            state: Some(ContainerState {
+                waiting: Some(ContainerStateWaiting {
+                    reason: Some(reason.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let pod = Pod {
+            status: Some(PodStatus {
+                container_statuses: Some(vec![
+                    waiting("wordpress", "CrashLoopBackOff"),
+                    waiting("nginx", "ContainerCreating"),
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(crash_looping_containers(&pod), vec!["wordpress"]);
+        assert!(crash_looping_containers(&Pod::default()).is_empty());
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let logs = "\
+PHP Warning:  mysqli_real_connect(): (HY000/1045): Access denied for user 'wp_1'@'10.0.0.3'
+touch: /var/www/html/wp-content/uploads: Permission denied
+Access denied for user 'wp_1'@'10.0.0.3'
+";
+        assert_eq!(
+            diagnose_logs(logs),
+            vec![Diagnosis::DatabaseAccess, Diagnosis::VolumeNotWritable]
+        );
+        assert!(diagnose_logs("NOTICE: ready to handle connections").is_empty());
+
+        assert_eq!(
+            parse_diagnostics_output("volume: ok\nhtaccess: corrupt\n"),
+            vec![Diagnosis::CorruptHtaccess]
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use chrono::{DateTime, Datelike, Days, NaiveDateTime, TimeZone, Utc, Weekday};
+use serde_json::json;
+
+use crate::{
+    backup::BackupStorage,
+    error::{bail, Result},
+    notify::{send_to_all, Notification, NotificationChannel},
+    slo::error_ratio_query,
+    storage::VolumeUsage,
+    traffic::SiteTraffic,
+    KwpmClient,
+};
+
+const BACKUP_ID_FORMAT: &str = "%Y%m%d-%H%M%S";
+const BACKUP_ID_LEN: usize = "20240101-000000".len();
+const UPDATE_OPERATIONS: [&str; 3] = ["upgrade", "plugin-update", "code-deploy"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportPeriod {
+    Weekly,
+    Monthly,
+}
+
+impl ReportPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportPeriod::Weekly => "weekly",
+            ReportPeriod::Monthly => "monthly",
+        }
+    }
+
+    /// The last complete week (Monday to Monday) or calendar month before `now`.
+    pub fn previous(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date_naive();
+        match self {
+            ReportPeriod::Weekly => {
+                let monday = Days::new(today.weekday().num_days_from_monday().into());
+                let end = today.checked_sub_days(monday).unwrap_or(today);
+                let start = end.checked_sub_days(Days::new(7)).unwrap_or(end);
+                (midnight(start), midnight(end))
+            }
+            ReportPeriod::Monthly => {
+                let end = today.with_day(1).unwrap_or(today);
+                let start = end.pred_opt().and_then(|d| d.with_day(1)).unwrap_or(end);
+                (midnight(start), midnight(end))
+            }
+        }
+    }
+
+    /// Whether `now` falls on the first day of a new period, i.e. a report is due.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        match self {
+            ReportPeriod::Weekly => now.weekday() == Weekday::Mon,
+            ReportPeriod::Monthly => now.day() == 1,
+        }
+    }
+}
+
+fn midnight(date: chrono::NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_time(chrono::NaiveTime::MIN))
+}
+
+/// Sites of a tenant, selected by namespace labels, and where their reports go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportSchedule {
+    pub tenant: String,
+    pub site_selector: String,
+    pub period: ReportPeriod,
+    pub channels: Vec<NotificationChannel>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SiteReport {
+    pub site: String,
+    /// Percentage of requests answered without a 5xx status.
+    pub availability: Option<f64>,
+    pub traffic: Option<SiteTraffic>,
+    pub storage: Option<VolumeUsage>,
+    pub backups: Vec<String>,
+    pub updates: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TenantReport {
+    pub tenant: String,
+    pub period: ReportPeriod,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub sites: Vec<SiteReport>,
+}
+
+fn parse_backup_time(name: &str) -> Option<DateTime<Utc>> {
+    let timestamp = name.get(..BACKUP_ID_LEN)?;
+    NaiveDateTime::parse_from_str(timestamp, BACKUP_ID_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// Splits backup ids and snapshot names taken within the range into backups and the updates
+/// that snapshots were taken for.
+pub fn backups_in_range(
+    backup_ids: &[String],
+    snapshot_names: &[String],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> (Vec<String>, Vec<String>) {
+    let in_range = |name: &str| {
+        parse_backup_time(name.trim_start_matches("archive-"))
+            .is_some_and(|taken_at| taken_at >= start && taken_at < end)
+    };
+
+    let backups = backup_ids
+        .iter()
+        .filter(|id| in_range(id))
+        .cloned()
+        .collect();
+    let updates = snapshot_names
+        .iter()
+        .filter(|name| in_range(name))
+        .filter(|name| {
+            UPDATE_OPERATIONS
+                .iter()
+                .any(|operation| name.ends_with(&format!("-{}", operation)))
+        })
+        .cloned()
+        .collect();
+
+    (backups, updates)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn render_report(report: &TenantReport) -> String {
+    let unknown = || "n/a".to_string();
+    let mut text = format!(
+        "{} report for {}\n{} to {}\n",
+        report.period.as_str(),
+        report.tenant,
+        report.start.format("%Y-%m-%d"),
+        report.end.format("%Y-%m-%d"),
+    );
+
+    for site in &report.sites {
+        text.push_str(&format!("\n{}\n", site.site));
+        text.push_str(&format!(
+            "  Availability: {}\n",
+            site.availability
+                .map(|a| format!("{:.3}%", a))
+                .unwrap_or_else(unknown)
+        ));
+        text.push_str(&format!(
+            "  Traffic: {}\n",
+            site.traffic
+                .as_ref()
+                .map(|t| format!(
+                    "{} requests, {} sent",
+                    t.requests,
+                    format_bytes(t.bytes_sent)
+                ))
+                .unwrap_or_else(unknown)
+        ));
+        text.push_str(&format!(
+            "  Storage: {}\n",
+            site.storage
+                .map(|s| format!(
+                    "{} of {} used",
+                    format_bytes(s.used_bytes),
+                    format_bytes(s.capacity_bytes)
+                ))
+                .unwrap_or_else(unknown)
+        ));
+        text.push_str(&format!("  Backups taken: {}\n", site.backups.len()));
+        text.push_str(&format!("  Updates applied: {}\n", site.updates.len()));
+        for update in &site.updates {
+            text.push_str(&format!("    - {}\n", update));
+        }
+    }
+
+    text
+}
+
+async fn list_names(storage: &BackupStorage, site: &str, dir: &str) -> Result<Vec<String>> {
+    let store = storage.object_store()?;
+    let path = storage.backup_path(site, dir);
+    crate::snapshot::list_snapshot_names(&store, &path).await
+}
+
+impl KwpmClient {
+    /// Sends the tenant's reports on the first day of every period when called daily, e.g. from
+    /// a CronJob running kwpm.
+    pub fn with_report_schedule(mut self, schedule: ReportSchedule) -> Self {
+        self.report_schedules.push(schedule);
+        self
+    }
+
+    async fn site_report(
+        &self,
+        site: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<SiteReport> {
+        let seconds = (end - start).num_seconds();
+        let availability = self
+            .query_prometheus(
+                &error_ratio_query(&self.site_namespace(site), &format!("{}s", seconds)),
+                end,
+            )
+            .await
+            .ok()
+            .map(|error_ratio| (1.0 - error_ratio) * 100.0);
+
+        let (backups, updates) = match &self.backup_storage {
+            Some(storage) => backups_in_range(
+                &list_names(storage, site, "").await?,
+                &list_names(storage, site, "snapshots").await?,
+                start,
+                end,
+            ),
+            None => Default::default(),
+        };
+
+        Ok(SiteReport {
+            site: site.to_string(),
+            availability,
+            traffic: self.get_site_traffic(site, start, end).await.ok(),
+            storage: self
+                .pvc_usage(&self.site_namespace(site), "wp-pv-claim")
+                .await?,
+            backups,
+            updates,
+        })
+    }
+
+    /// Collects uptime, traffic, storage, backups and updates of the tenant's sites over the
+    /// last complete period. Metrics that are unavailable are left empty.
+    pub async fn generate_tenant_report(
+        &self,
+        tenant: &str,
+        site_selector: &str,
+        period: ReportPeriod,
+        now: DateTime<Utc>,
+    ) -> Result<TenantReport> {
+        let (start, end) = period.previous(now);
+
+        let mut sites = Vec::new();
+        for site in self.list_site_names(site_selector).await? {
+            sites.push(self.site_report(&site, start, end).await?);
+        }
+
+        Ok(TenantReport {
+            tenant: tenant.to_string(),
+            period,
+            start,
+            end,
+            sites,
+        })
+    }
+
+    /// Generates and delivers every scheduled report due at `now`, returning the tenants that
+    /// were sent one.
+    pub async fn send_scheduled_reports(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let mut sent = Vec::new();
+        let mut errors = Vec::new();
+
+        for schedule in self
+            .report_schedules
+            .iter()
+            .filter(|s| s.period.is_due(now))
+        {
+            let result = async {
+                let report = self
+                    .generate_tenant_report(
+                        &schedule.tenant,
+                        &schedule.site_selector,
+                        schedule.period,
+                        now,
+                    )
+                    .await?;
+                let notification = Notification {
+                    subject: format!(
+                        "Your {} site report ({} to {})",
+                        schedule.period.as_str(),
+                        report.start.format("%Y-%m-%d"),
+                        report.end.format("%Y-%m-%d")
+                    ),
+                    body: render_report(&report),
+                    data: json!({
+                        "tenant": report.tenant,
+                        "period": schedule.period.as_str(),
+                        "start": report.start,
+                        "end": report.end,
+                        "sites": report.sites.iter().map(|s| json!({
+                            "site": s.site,
+                            "availability": s.availability,
+                            "requests": s.traffic.as_ref().map(|t| t.requests),
+                            "bytesSent": s.traffic.as_ref().map(|t| t.bytes_sent),
+                            "storageUsedBytes": s.storage.map(|u| u.used_bytes),
+                            "backups": s.backups,
+                            "updates": s.updates,
+                        })).collect::<Vec<_>>(),
+                    }),
+                };
+                send_to_all(&schedule.channels, &notification).await
+            }
+            .await;
+
+            match result {
+                Ok(()) => sent.push(schedule.tenant.clone()),
+                Err(e) => errors.push(format!("{}: {:#}", schedule.tenant, e)),
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("failed to send reports: {}", errors.join("; "));
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, 6, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_previous_period() {
+        // 2024-03-06 is a Wednesday.
+        let (start, end) = ReportPeriod::Weekly.previous(at(2024, 3, 6));
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 26, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 3, 4, 0, 0, 0).unwrap());
+
+        let (start, end) = ReportPeriod::Monthly.previous(at(2024, 3, 1));
+        assert_eq!(start, Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap());
+        assert_eq!(end, Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap());
+
+        assert!(ReportPeriod::Weekly.is_due(at(2024, 3, 4)));
+        assert!(!ReportPeriod::Monthly.is_due(at(2024, 3, 4)));
+    }
+
+    #[test]
+    fn test_backups_in_range() {
+        let backups = vec![
+            "20240227-010000".to_string(),
+            "archive-20240228-010000".to_string(),
+            "20240310-010000".to_string(),
+            "restic".to_string(),
+        ];
+        let snapshots = vec![
+            "20240227-020000-plugin-update".to_string(),
+            "20240228-020000-search-replace".to_string(),
+        ];
+
+        let (backups, updates) = backups_in_range(
+            &backups,
+            &snapshots,
+            Utc.with_ymd_and_hms(2024, 2, 26, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 3, 4, 0, 0, 0).unwrap(),
+        );
+        assert_eq!(backups, ["20240227-010000", "archive-20240228-010000"]);
+        assert_eq!(updates, ["20240227-020000-plugin-update"]);
+    }
+
+    #[test]
+    fn test_render_report() {
+        let report = TenantReport {
+            tenant: "acme".to_string(),
+            period: ReportPeriod::Weekly,
+            start: Utc.with_ymd_and_hms(2024, 2, 26, 0, 0, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2024, 3, 4, 0, 0, 0).unwrap(),
+            sites: vec![SiteReport {
+                site: "blog".to_string(),
+                availability: Some(99.95),
+                storage: Some(VolumeUsage {
+                    capacity_bytes: 3 << 30,
+                    used_bytes: 512 << 20,
+                    available_bytes: (3 << 30) - (512 << 20),
+                }),
+                ..Default::default()
+            }],
+        };
+
+        let text = render_report(&report);
+        assert!(text.starts_with("weekly report for acme\n2024-02-26 to 2024-03-04\n"));
+        assert!(text.contains("  Availability: 99.950%\n"));
+        assert!(text.contains("  Traffic: n/a\n"));
+        assert!(text.contains("  Storage: 512.0 MiB of 3.0 GiB used\n"));
+    }
+}
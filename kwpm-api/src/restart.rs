@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::apps::v1::Deployment;
+use kube::{api::Patch, runtime::wait::await_condition, Api};
+use serde_json::{json, Value};
+
+use crate::{
+    error::{bail, Result},
+    site::is_deployment_available,
+    KwpmClient,
+};
+
+const RESTART_TIMEOUT: Duration = Duration::from_secs(600);
+/// The annotation `kubectl rollout restart` sets, so both restart the same way.
+const RESTARTED_AT_ANNOTATION: &str = "kubectl.kubernetes.io/restartedAt";
+const RESTART_REASON_ANNOTATION: &str = "kwpm.io/restart-reason";
+
+pub fn restart_patch(reason: &str, restarted_at: DateTime<Utc>) -> Value {
+    json!({
+        "spec": { "template": { "metadata": { "annotations": {
+            RESTARTED_AT_ANNOTATION: restarted_at.to_rfc3339(),
+            RESTART_REASON_ANNOTATION: reason,
+        } } } }
+    })
+}
+
+/// Whether the controller has rolled out `generation` and no pods of earlier ones are left.
+pub fn is_rollout_complete(deployment: &Deployment, generation: i64) -> bool {
+    let Some(status) = deployment.status.as_ref() else {
+        return false;
+    };
+
+    status.observed_generation.unwrap_or(0) >= generation
+        && is_deployment_available(deployment)
+        && status.replicas.unwrap_or(0) == status.updated_replicas.unwrap_or(0)
+}
+
+impl KwpmClient {
+    /// Waits until the generation of `deployment` has fully rolled out.
+    #[tracing::instrument(skip_all, fields(site = %site, deployment = deployment.metadata.name.as_deref()))]
+    pub(crate) async fn wait_for_rollout(
+        &self,
+        site: &str,
+        deployment: &Deployment,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+        let name = deployment.metadata.name.clone().unwrap_or_default();
+        let generation = deployment.metadata.generation.unwrap_or(0);
+
+        tokio::time::timeout(
+            timeout,
+            await_condition(deployment_api, &name, move |d: Option<&Deployment>| {
+                d.is_some_and(|d| is_rollout_complete(d, generation))
+            }),
+        )
+        .await
+        .with_context(|| format!("timed out waiting for {} to roll out", site))??;
+
+        Ok(())
+    }
+
+    /// Replaces the site's pods one by one, recording `reason` in the site's audit log, and
+    /// waits until the rollout has finished.
+    #[tracing::instrument(skip_all, fields(site = %site, reason = %reason))]
+    pub async fn restart_site(&self, site: &str, reason: &str) -> Result<()> {
+        if reason.trim().is_empty() {
+            bail!("restarting {} needs a reason", site);
+        }
+        let lock = self.lock_site(site, "restart_site").await?;
+        let result: Result<_> = async {
+            let mut progress = self.start_operation(site, "restart_site").await;
+
+            let ns_name = self.site_namespace(site);
+            let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+            let patch = restart_patch(reason, Utc::now());
+            self.check_policy("restart_site", Some(site), std::slice::from_ref(&patch))
+                .await?;
+
+            let deployment = deployment_api
+                .patch("wordpress", &Default::default(), &Patch::Merge(&patch))
+                .await?;
+            self.record_audit(site, "restart", reason).await?;
+            progress.step("waiting for rollout", Some(20)).await;
+
+            self.wait_for_rollout(site, &deployment, RESTART_TIMEOUT)
+                .await?;
+
+            progress.succeed().await;
+            Ok(())
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use k8s_openapi::api::apps::v1::{DeploymentSpec, DeploymentStatus};
+
+    use super::*;
+
+    fn deployment(observed_generation: i64, replicas: i32, updated: i32) -> Deployment {
+        Deployment {
+            spec: Some(DeploymentSpec {
+                replicas: Some(2),
+                ..Default::default()
+            }),
+            status: Some(DeploymentStatus {
+                observed_generation: Some(observed_generation),
+                replicas: Some(replicas),
+                updated_replicas: Some(updated),
+                available_replicas: Some(replicas),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_restart_patch() {
+        let patch = restart_patch(
+            "php.ini changed",
+            Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap(),
+        );
+        let annotations = &patch["spec"]["template"]["metadata"]["annotations"];
+
+        assert_eq!(
+            annotations[RESTARTED_AT_ANNOTATION],
+            "2024-03-01T12:00:00+00:00"
+        );
+        assert_eq!(annotations[RESTART_REASON_ANNOTATION], "php.ini changed");
+    }
+
+    #[test]
+    fn test_is_rollout_complete() {
+        assert!(!is_rollout_complete(&deployment(1, 2, 2), 2));
+        assert!(!is_rollout_complete(&deployment(2, 3, 2), 2));
+        assert!(is_rollout_complete(&deployment(2, 2, 2), 2));
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{DynamicObject, ListParams, ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{archive::api_resource, error::Result, site::SiteSpec, KwpmClient};
+
+pub(crate) const REVISION_CONFIG_MAP_PREFIX: &str = "kwpm-revision-";
+const REVISION_LABEL: &str = "kwpm.io/site-revision";
+const REVISION_KEY: &str = "revision.json";
+const MAX_REVISIONS: usize = 20;
+/// The kinds a rollback applies and deletes. Volume claims hold site data, and CronJobs are
+/// the schedules of backups, checksum verification, malware scans and cache warmup, which
+/// their own settings own.
+const REVISION_KINDS: [&str; 4] = ["Deployment", "Service", "ConfigMap", "Ingress"];
+
+/// The manifests kwpm applied for a site at one point, with the spec they were rendered from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteRevision {
+    pub revision: u32,
+    pub created_at: DateTime<Utc>,
+    /// The operation that produced the revision, e.g. `set_redirects`.
+    pub cause: String,
+    /// With credentials still sealed.
+    pub spec: SiteSpec,
+    pub manifests: Vec<Value>,
+}
+
+pub fn revision_config_map(revision: &SiteRevision) -> Result<ConfigMap> {
+    Ok(ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(format!(
+                "{}{}",
+                REVISION_CONFIG_MAP_PREFIX, revision.revision
+            )),
+            labels: Some(BTreeMap::from([(
+                REVISION_LABEL.to_string(),
+                revision.revision.to_string(),
+            )])),
+            ..Default::default()
+        },
+        data: Some(BTreeMap::from([(
+            REVISION_KEY.to_string(),
+            serde_json::to_string(revision)?,
+        )])),
+        ..Default::default()
+    })
+}
+
+pub fn parse_revision_config_map(config_map: &ConfigMap) -> Result<SiteRevision> {
+    let revision = config_map
+        .data
+        .as_ref()
+        .and_then(|data| data.get(REVISION_KEY))
+        .context("revision config map has no revision")?;
+    Ok(serde_json::from_str(revision)?)
+}
+
+fn object_key(manifest: &Value) -> (String, String) {
+    (
+        manifest["kind"].as_str().unwrap_or_default().to_string(),
+        manifest["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+    )
+}
+
+fn is_revision_object(manifest: &Value) -> bool {
+    manifest["kind"]
+        .as_str()
+        .is_some_and(|kind| REVISION_KINDS.contains(&kind))
+}
+
+/// Objects in `current` that `target` doesn't have, of the kinds revisions own.
+pub fn objects_to_delete<'a>(current: &'a [Value], target: &[Value]) -> Vec<&'a Value> {
+    let target: Vec<_> = target.iter().map(object_key).collect();
+    current
+        .iter()
+        .filter(|manifest| is_revision_object(manifest))
+        .filter(|manifest| !target.contains(&object_key(manifest)))
+        .collect()
+}
+
+impl KwpmClient {
+    /// The recorded revisions of a site, oldest first.
+    pub async fn list_site_revisions(&self, site: &str) -> Result<Vec<SiteRevision>> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let mut revisions = config_map_api
+            .list(&ListParams::default().labels(REVISION_LABEL))
+            .await?
+            .iter()
+            .map(parse_revision_config_map)
+            .collect::<Result<Vec<_>>>()?;
+        revisions.sort_by_key(|r| r.revision);
+
+        Ok(revisions)
+    }
+
+    pub async fn get_site_revision(&self, site: &str, revision: u32) -> Result<SiteRevision> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let config_map = config_map_api
+            .get_opt(&format!("{}{}", REVISION_CONFIG_MAP_PREFIX, revision))
+            .await?
+            .with_context(|| format!("{} has no revision {}", site, revision))?;
+        parse_revision_config_map(&config_map)
+    }
+
+    /// Records the site's manifests as they are now as a new revision, unless they match the
+    /// latest one, and returns its number.
+    pub(crate) async fn record_site_revision(&self, site: &str, cause: &str) -> Result<u32> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let revisions = self.list_site_revisions(site).await?;
+        let spec = self.stored_site_spec(site).await?;
+        let manifests = self.render_site_manifests(site).await?;
+        if let Some(latest) = revisions.last() {
+            if latest.spec == spec && latest.manifests == manifests {
+                return Ok(latest.revision);
+            }
+        }
+
+        let revision = SiteRevision {
+            revision: revisions.last().map_or(1, |r| r.revision + 1),
+            created_at: Utc::now(),
+            cause: cause.to_string(),
+            spec,
+            manifests,
+        };
+        config_map_api
+            .create(
+                &Default::default(),
+                &self.labeled(&revision_config_map(&revision)?),
+            )
+            .await?;
+
+        let prune = (revisions.len() + 1).saturating_sub(MAX_REVISIONS);
+        for old in &revisions[..prune] {
+            config_map_api
+                .delete(
+                    &format!("{}{}", REVISION_CONFIG_MAP_PREFIX, old.revision),
+                    &Default::default(),
+                )
+                .await?;
+        }
+
+        Ok(revision.revision)
+    }
+
+    /// Reapplies the spec and manifests of an earlier revision and deletes objects added since,
+    /// recording the result as a new revision. Images roll back with it; site files, the
+    /// database and schedules such as backups don't.
+    #[tracing::instrument(skip_all, fields(site = %site, revision))]
+    pub async fn rollback_site(&self, site: &str, revision: u32) -> Result<u32> {
+        let lock = self.lock_site(site, "rollback_site").await?;
+        let result: Result<_> = async {
+            let mut progress = self.start_operation(site, "rollback_site").await;
+            let ns_name = self.site_namespace(site);
+            let target = self.get_site_revision(site, revision).await?;
+            self.check_policy("rollback_site", Some(site), &target.manifests)
+                .await?;
+
+            let current = self.render_site_manifests(site).await?;
+            self.store_site_spec(site, &target.spec).await?;
+
+            progress.step("applying manifests", Some(20)).await;
+            let params = PatchParams::apply("kwpm").force();
+            for manifest in target.manifests.iter().filter(|m| is_revision_object(m)) {
+                let resource = api_resource(manifest)?;
+                let object: DynamicObject = serde_json::from_value(manifest.clone())?;
+                let name = object
+                    .metadata
+                    .name
+                    .clone()
+                    .context("manifest has no name")?;
+                let api: Api<DynamicObject> =
+                    Api::namespaced_with(self.client.clone(), &ns_name, &resource);
+                api.patch(&name, &params, &Patch::Apply(&self.labeled(&object)))
+                    .await?;
+            }
+            progress
+                .step("deleting objects added since", Some(70))
+                .await;
+            for manifest in objects_to_delete(&current, &target.manifests) {
+                let resource = api_resource(manifest)?;
+                let (_, name) = object_key(manifest);
+                let api: Api<DynamicObject> =
+                    Api::namespaced_with(self.client.clone(), &ns_name, &resource);
+                api.delete(&name, &Default::default()).await?;
+            }
+
+            let cause = format!("rollback to revision {}", revision);
+            self.record_audit(site, "rollback", &cause).await?;
+            let revision = self.record_site_revision(site, &cause).await?;
+
+            progress.succeed().await;
+            Ok(revision)
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn manifest(kind: &str, name: &str) -> Value {
+        json!({ "apiVersion": "v1", "kind": kind, "metadata": { "name": name } })
+    }
+
+    #[test]
+    fn test_revision_config_map_round_trip() {
+        let revision = SiteRevision {
+            revision: 3,
+            created_at: Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap(),
+            cause: "set_redirects".to_string(),
+            spec: SiteSpec::default(),
+            manifests: vec![manifest("Service", "wordpress")],
+        };
+        let config_map = revision_config_map(&revision).unwrap();
+
+        assert_eq!(config_map.metadata.name.as_deref(), Some("kwpm-revision-3"));
+        assert_eq!(parse_revision_config_map(&config_map).unwrap(), revision);
+    }
+
+    #[test]
+    fn test_objects_to_delete_keeps_volume_claims_and_schedules() {
+        let current = vec![
+            manifest("Service", "wordpress"),
+            manifest("Ingress", "wordpress-admin"),
+            manifest("PersistentVolumeClaim", "wp-cache"),
+            manifest("CronJob", "kwpm-backup"),
+            manifest("CronJob", "malware-scan"),
+        ];
+        let target = vec![manifest("Service", "wordpress")];
+
+        assert_eq!(
+            objects_to_delete(&current, &target),
+            vec![&manifest("Ingress", "wordpress-admin")]
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+
+use k8s_openapi::api::core::v1::{Namespace, PersistentVolume, Secret};
+use kube::Api;
+
+use crate::{
+    database_job,
+    error::{KwpmError, Result},
+    mariadb::mariadb_namespace,
+    provision::DATABASE_JOB_TIMEOUT,
+    DatabaseAction, KwpmClient,
+};
+
+/// An object an operation created and deletes again if a later step fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CreatedResource {
+    /// A namespace, with every object created in it.
+    Namespace(String),
+    PersistentVolume(String),
+    Secret {
+        namespace: String,
+        name: String,
+    },
+    /// A database and its user on MariaDB `instance`.
+    Database {
+        instance: String,
+        database: String,
+        user: String,
+    },
+}
+
+impl fmt::Display for CreatedResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreatedResource::Namespace(name) => write!(f, "namespace {}", name),
+            CreatedResource::PersistentVolume(name) => write!(f, "volume {}", name),
+            CreatedResource::Secret { namespace, name } => {
+                write!(f, "secret {} in {}", name, namespace)
+            }
+            CreatedResource::Database {
+                instance, database, ..
+            } => write!(f, "database {} on MariaDB {}", database, instance),
+        }
+    }
+}
+
+/// The objects an operation created so far, so a failure doesn't leave a half-created site
+/// that blocks retrying it.
+#[derive(Debug, Default)]
+pub(crate) struct Rollback {
+    created: Vec<CreatedResource>,
+}
+
+impl Rollback {
+    pub(crate) fn record(&mut self, resource: CreatedResource) {
+        self.created.push(resource);
+    }
+
+    /// The objects to delete, newest first. Secrets in a created namespace go away with it.
+    fn steps(&self) -> Vec<&CreatedResource> {
+        self.created
+            .iter()
+            .rev()
+            .filter(|resource| match resource {
+                CreatedResource::Secret { namespace, .. } => !self
+                    .created
+                    .contains(&CreatedResource::Namespace(namespace.clone())),
+                _ => true,
+            })
+            .collect()
+    }
+}
+
+/// Context for the error of a failed operation, listing what was rolled back and what is left
+/// behind because deleting it failed as well.
+fn rollback_report(operation: &str, rolled_back: &[String], left_behind: &[String]) -> String {
+    let mut report = format!("{} failed", operation);
+    if !rolled_back.is_empty() {
+        report.push_str(&format!(", rolled back {}", rolled_back.join(", ")));
+    }
+    if !left_behind.is_empty() {
+        report.push_str(&format!(
+            ", left behind {}, delete them before retrying",
+            left_behind.join(", ")
+        ));
+    }
+    report
+}
+
+impl KwpmClient {
+    async fn delete_created(&self, resource: &CreatedResource) -> Result<()> {
+        match resource {
+            CreatedResource::Namespace(name) => {
+                let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+                namespace_api.delete(name, &Default::default()).await?;
+            }
+            CreatedResource::PersistentVolume(name) => {
+                let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+                pv_api.delete(name, &Default::default()).await?;
+            }
+            CreatedResource::Secret { namespace, name } => {
+                let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), namespace);
+                secret_api.delete(name, &Default::default()).await?;
+            }
+            CreatedResource::Database {
+                instance,
+                database,
+                user,
+            } => {
+                self.run_job(
+                    &mariadb_namespace(instance),
+                    database_job(DatabaseAction::DropWithUser, database, user)?,
+                    DATABASE_JOB_TIMEOUT,
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes what `rollback` recorded and returns `error` with what was rolled back or left
+    /// behind, keeping its kind.
+    #[tracing::instrument(skip_all, fields(operation = %operation))]
+    pub(crate) async fn roll_back(
+        &self,
+        operation: &str,
+        rollback: Rollback,
+        error: KwpmError,
+    ) -> KwpmError {
+        let mut rolled_back = Vec::new();
+        let mut left_behind = Vec::new();
+        for resource in rollback.steps() {
+            match self.delete_created(resource).await {
+                Ok(()) => rolled_back.push(resource.to_string()),
+                Err(e) => left_behind.push(format!("{} ({:#})", resource, e)),
+            }
+        }
+
+        anyhow::Error::from(error)
+            .context(rollback_report(operation, &rolled_back, &left_behind))
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollback_steps() {
+        let mut rollback = Rollback::default();
+        rollback.record(CreatedResource::Namespace("kwpm-blog".to_string()));
+        rollback.record(CreatedResource::Secret {
+            namespace: "kwpm-mariadb".to_string(),
+            name: "kwpm-db-blog".to_string(),
+        });
+        rollback.record(CreatedResource::Database {
+            instance: "default".to_string(),
+            database: "wp_blog".to_string(),
+            user: "wp_blog".to_string(),
+        });
+        rollback.record(CreatedResource::Secret {
+            namespace: "kwpm-blog".to_string(),
+            name: "mysql-pass".to_string(),
+        });
+        rollback.record(CreatedResource::PersistentVolume(
+            "kwpm-blog-pv".to_string(),
+        ));
+
+        let steps: Vec<String> = rollback.steps().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            steps,
+            [
+                "volume kwpm-blog-pv",
+                "database wp_blog on MariaDB default",
+                "secret kwpm-db-blog in kwpm-mariadb",
+                "namespace kwpm-blog",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rollback_report() {
+        assert_eq!(
+            rollback_report(
+                "creating site blog",
+                &["volume kwpm-blog-pv".to_string()],
+                &[]
+            ),
+            "creating site blog failed, rolled back volume kwpm-blog-pv"
+        );
+        assert_eq!(
+            rollback_report(
+                "creating site blog",
+                &[],
+                &["namespace kwpm-blog (forbidden)".to_string()]
+            ),
+            "creating site blog failed, left behind namespace kwpm-blog (forbidden), \
+             delete them before retrying"
+        );
+    }
+}
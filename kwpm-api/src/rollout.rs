@@ -0,0 +1,221 @@
+use anyhow::Context;
+use k8s_openapi::{
+    api::{
+        apps::v1::{Deployment, DeploymentStrategy, RollingUpdateDeployment},
+        core::v1::{HTTPGetAction, HTTPHeader, Probe},
+    },
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+use kube::Api;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, Result},
+    manifest::deployment_pod_spec_mut,
+    KwpmClient,
+};
+
+/// Rolling update settings of a site's deployment. Values of `max_surge` and `max_unavailable`
+/// are either a pod count or a percentage like `25%`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RolloutStrategy {
+    pub max_surge: String,
+    pub max_unavailable: String,
+    pub min_ready_seconds: i32,
+    /// Only mark new pods ready once the homepage answers, so broken images never take traffic.
+    pub homepage_check: bool,
+}
+
+impl Default for RolloutStrategy {
+    fn default() -> Self {
+        Self {
+            max_surge: "1".to_string(),
+            max_unavailable: "0".to_string(),
+            min_ready_seconds: 10,
+            homepage_check: true,
+        }
+    }
+}
+
+fn int_or_string(value: &str) -> Result<IntOrString> {
+    if let Some(percent) = value.strip_suffix('%') {
+        if percent.parse::<u8>().ok().filter(|p| *p <= 100).is_none() {
+            bail!("invalid percentage: {}", value);
+        }
+        return Ok(IntOrString::String(value.to_string()));
+    }
+
+    match value.parse::<i32>() {
+        Ok(count) if count >= 0 => Ok(IntOrString::Int(count)),
+        _ => bail!("invalid pod count: {}", value),
+    }
+}
+
+fn is_zero(value: &IntOrString) -> bool {
+    match value {
+        IntOrString::Int(count) => *count == 0,
+        IntOrString::String(percent) => percent == "0%",
+    }
+}
+
+/// Readiness probe requesting the homepage through nginx with the site's public host, so
+/// WordPress answers instead of redirecting to its canonical URL.
+pub fn homepage_probe(site_url: &str) -> Probe {
+    let (scheme, rest) = site_url.split_once("://").unwrap_or(("http", site_url));
+    let host = rest.split('/').next().unwrap_or_default();
+
+    Probe {
+        http_get: Some(HTTPGetAction {
+            path: Some("/".to_string()),
+            port: IntOrString::Int(80),
+            http_headers: Some(vec![
+                HTTPHeader {
+                    name: "Host".to_string(),
+                    value: host.to_string(),
+                },
+                HTTPHeader {
+                    name: "X-Forwarded-Proto".to_string(),
+                    value: scheme.to_string(),
+                },
+            ]),
+            ..Default::default()
+        }),
+        initial_delay_seconds: Some(5),
+        period_seconds: Some(10),
+        timeout_seconds: Some(5),
+        failure_threshold: Some(3),
+        ..Default::default()
+    }
+}
+
+pub fn apply_rollout_strategy(
+    deployment: &mut Deployment,
+    strategy: &RolloutStrategy,
+    site_url: &str,
+) -> Result<()> {
+    let max_surge = int_or_string(&strategy.max_surge)?;
+    let max_unavailable = int_or_string(&strategy.max_unavailable)?;
+    if is_zero(&max_surge) && is_zero(&max_unavailable) {
+        bail!("maxSurge and maxUnavailable cannot both be zero");
+    }
+    if strategy.min_ready_seconds < 0 {
+        bail!("minReadySeconds cannot be negative");
+    }
+
+    let spec = deployment.spec.as_mut().context("deployment has no spec")?;
+    spec.strategy = Some(DeploymentStrategy {
+        type_: Some("RollingUpdate".to_string()),
+        rolling_update: Some(RollingUpdateDeployment {
+            max_surge: Some(max_surge),
+            max_unavailable: Some(max_unavailable),
+        }),
+    });
+    spec.min_ready_seconds = Some(strategy.min_ready_seconds);
+
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+    let nginx = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "nginx")
+        .context("deployment has no nginx container")?;
+    nginx.readiness_probe = strategy.homepage_check.then(|| homepage_probe(site_url));
+
+    Ok(())
+}
+
+impl KwpmClient {
+    pub async fn set_rollout_strategy(&self, site: &str, strategy: RolloutStrategy) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let site_url = self.site_url(site).await?;
+        let mut deployment = deployment_api.get("wordpress").await?;
+        apply_rollout_strategy(&mut deployment, &strategy, &site_url)?;
+        self.check_policy(
+            "set_rollout_strategy",
+            Some(site),
+            &[serde_json::to_value(&deployment)?],
+        )
+        .await?;
+
+        let mut spec = self.get_site_spec(site).await?;
+        spec.rollout = Some(strategy);
+        self.save_site_spec(site, &spec).await?;
+
+        deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+
+        self.record_site_revision(site, "set_rollout_strategy")
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> Deployment {
+        serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply_rollout_strategy() {
+        let mut deployment = deployment();
+        let strategy = RolloutStrategy {
+            max_surge: "25%".to_string(),
+            ..Default::default()
+        };
+
+        apply_rollout_strategy(&mut deployment, &strategy, "https://blog.example.com/").unwrap();
+
+        let spec = deployment.spec.unwrap();
+        let rolling_update = spec.strategy.unwrap().rolling_update.unwrap();
+        assert_eq!(
+            rolling_update.max_surge,
+            Some(IntOrString::String("25%".to_string()))
+        );
+        assert_eq!(rolling_update.max_unavailable, Some(IntOrString::Int(0)));
+        assert_eq!(spec.min_ready_seconds, Some(10));
+
+        let nginx = spec
+            .template
+            .spec
+            .unwrap()
+            .containers
+            .into_iter()
+            .find(|c| c.name == "nginx")
+            .unwrap();
+        let headers = nginx
+            .readiness_probe
+            .unwrap()
+            .http_get
+            .unwrap()
+            .http_headers
+            .unwrap();
+        assert_eq!(headers[0].value, "blog.example.com");
+        assert_eq!(headers[1].value, "https");
+    }
+
+    #[test]
+    fn test_apply_rollout_strategy_rejects_invalid_values() {
+        let mut deployment = deployment();
+        let stalled = RolloutStrategy {
+            max_surge: "0".to_string(),
+            max_unavailable: "0%".to_string(),
+            ..Default::default()
+        };
+        let invalid = RolloutStrategy {
+            max_surge: "150%".to_string(),
+            ..Default::default()
+        };
+
+        assert!(apply_rollout_strategy(&mut deployment, &stalled, "http://blog/").is_err());
+        assert!(apply_rollout_strategy(&mut deployment, &invalid, "http://blog/").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{bail, Result},
+    snapshot::RiskyOperation,
+    KwpmClient,
+};
+
+/// A `wp search-replace` run. WP-CLI unserializes PHP-serialized values before replacing in
+/// them, so string lengths in options and post meta stay consistent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SearchReplace {
+    pub search: String,
+    pub replace: String,
+    /// Only these tables. By default the tables registered with WordPress are searched.
+    pub tables: Vec<String>,
+    /// Every table with the site's table prefix, including those of plugins.
+    pub all_tables: bool,
+    pub skip_tables: Vec<String>,
+    /// Defaults to `guid`, which must not change when a site moves.
+    pub skip_columns: Vec<String>,
+    /// Only count what would change. Defaults to true; the caller has to opt into writing.
+    pub dry_run: bool,
+}
+
+impl Default for SearchReplace {
+    fn default() -> Self {
+        Self {
+            search: String::new(),
+            replace: String::new(),
+            tables: Vec::new(),
+            all_tables: false,
+            skip_tables: Vec::new(),
+            skip_columns: vec!["guid".to_string()],
+            dry_run: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchReplaceResult {
+    pub replacements: u64,
+    pub dry_run: bool,
+    /// Backup id of the snapshot taken before writing, if backup storage is configured.
+    pub snapshot: Option<String>,
+}
+
+fn is_valid_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+impl SearchReplace {
+    pub fn validate(&self) -> Result<()> {
+        if self.search.is_empty() {
+            bail!("search string must not be empty");
+        }
+        if self.search == self.replace {
+            bail!("search and replace strings are the same");
+        }
+        // WP-CLI would parse these as options instead of positional arguments.
+        if self.search.starts_with('-') || self.replace.starts_with('-') {
+            bail!("search and replace strings must not start with -");
+        }
+        if self.all_tables && !self.tables.is_empty() {
+            bail!("tables and all tables are mutually exclusive");
+        }
+        for name in self
+            .tables
+            .iter()
+            .chain(&self.skip_tables)
+            .chain(&self.skip_columns)
+        {
+            if !is_valid_identifier(name) {
+                bail!("invalid table or column name: {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "search-replace".to_string(),
+            self.search.clone(),
+            self.replace.clone(),
+        ];
+        args.extend(self.tables.iter().cloned());
+        if self.all_tables {
+            args.push("--all-tables-with-prefix".to_string());
+        }
+        if !self.skip_tables.is_empty() {
+            args.push(format!("--skip-tables={}", self.skip_tables.join(",")));
+        }
+        if !self.skip_columns.is_empty() {
+            args.push(format!("--skip-columns={}", self.skip_columns.join(",")));
+        }
+        if self.dry_run {
+            args.push("--dry-run".to_string());
+        }
+        args.push("--format=count".to_string());
+        args
+    }
+}
+
+fn parse_count(output: &str) -> Result<u64> {
+    let count = output.lines().rev().find(|l| !l.trim().is_empty());
+    Ok(count
+        .and_then(|count| count.trim().parse().ok())
+        .with_context(|| format!("unexpected wp search-replace output: {}", output.trim()))?)
+}
+
+impl KwpmClient {
+    /// Runs `wp search-replace` on a site. Unless it's a dry run, the site is locked and a
+    /// database snapshot is taken first.
+    #[tracing::instrument(skip_all, fields(site = %site, dry_run = search_replace.dry_run))]
+    pub async fn search_replace(
+        &self,
+        site: &str,
+        search_replace: &SearchReplace,
+    ) -> Result<SearchReplaceResult> {
+        search_replace.validate()?;
+        self.check_policy(
+            "search_replace",
+            Some(site),
+            &[serde_json::to_value(search_replace)?],
+        )
+        .await?;
+        let args = search_replace.args();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        if search_replace.dry_run {
+            let output = self.run_wp_cli(site, &args).await?;
+            return Ok(SearchReplaceResult {
+                replacements: parse_count(&output)?,
+                dry_run: true,
+                snapshot: None,
+            });
+        }
+
+        let lock = self.lock_site(site, "search_replace").await?;
+        let result: Result<_> = async {
+            let mut progress = self.start_operation(site, "search_replace").await;
+            progress.step("taking snapshot", Some(10)).await;
+            let snapshot = self
+                .snapshot_if_configured(site, RiskyOperation::SearchReplace)
+                .await?;
+
+            progress.step("replacing", Some(40)).await;
+            let output = self.run_wp_cli(site, &args).await?;
+            let replacements = parse_count(&output)?;
+            self.run_wp_cli(site, &["cache", "flush"]).await?;
+            self.record_audit(
+                site,
+                "search_replace",
+                &format!(
+                    "{} -> {} ({} replacements)",
+                    search_replace.search, search_replace.replace, replacements
+                ),
+            )
+            .await?;
+
+            progress.succeed().await;
+            Ok(SearchReplaceResult {
+                replacements,
+                dry_run: false,
+                snapshot: snapshot.map(|s| s.backup_id),
+            })
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search_replace() -> SearchReplace {
+        SearchReplace {
+            search: "http://old.example.com".to_string(),
+            replace: "https://new.example.com".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_args_default_to_dry_run() {
+        assert_eq!(
+            search_replace().args(),
+            [
+                "search-replace",
+                "http://old.example.com",
+                "https://new.example.com",
+                "--skip-columns=guid",
+                "--dry-run",
+                "--format=count",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(search_replace().validate().is_ok());
+        assert!(SearchReplace {
+            search: "--url=evil".to_string(),
+            ..search_replace()
+        }
+        .validate()
+        .is_err());
+        assert!(SearchReplace {
+            tables: vec!["wp_posts; DROP TABLE wp_users".to_string()],
+            ..search_replace()
+        }
+        .validate()
+        .is_err());
+        assert!(SearchReplace {
+            search: String::new(),
+            ..search_replace()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count("42\n").unwrap(), 42);
+        assert!(parse_count("Error: no tables\n").is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use k8s_openapi::api::core::v1::Secret;
+use kube::Api;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hmac,
+};
+
+use crate::{
+    error::{bail, Result},
+    secret_value, KwpmClient,
+};
+
+const SEALED_PREFIX: &str = "kwpm:v1:";
+const MASTER_KEYS_SECRET_KEY: &str = "keys";
+
+/// AES-256-GCM keys for values kwpm persists in its metadata, by key id. New values are sealed
+/// with the current key, older keys are kept to open values sealed before a rotation.
+#[derive(Clone)]
+pub struct MasterKeys {
+    current: String,
+    keys: BTreeMap<String, [u8; 32]>,
+}
+
+impl fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl MasterKeys {
+    /// Parses `id=base64key` pairs separated by commas, the first being the current key.
+    pub fn parse(keys: &str) -> Result<Self> {
+        let mut current = None;
+        let mut parsed = BTreeMap::new();
+
+        for entry in keys.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, key) = entry
+                .split_once('=')
+                .with_context(|| format!("master key {} has no id", entry))?;
+            if id.is_empty() || id.contains(':') {
+                bail!("invalid master key id: {}", id);
+            }
+            let key: [u8; 32] = STANDARD
+                .decode(key)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .with_context(|| format!("master key {} is not 32 base64-encoded bytes", id))?;
+            if parsed.insert(id.to_string(), key).is_some() {
+                bail!("duplicate master key id: {}", id);
+            }
+            current.get_or_insert_with(|| id.to_string());
+        }
+
+        Ok(Self {
+            current: current.context("no master keys given")?,
+            keys: parsed,
+        })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        Self::parse(&std::env::var("KWPM_MASTER_KEYS").context("KWPM_MASTER_KEYS is not set")?)
+    }
+
+    /// Reads the keys from the `keys` entry of a Secret, e.g. one synced from a KMS by
+    /// external-secrets.
+    pub async fn from_secret(client: kube::Client, namespace: &str, name: &str) -> Result<Self> {
+        let secret_api: Api<Secret> = Api::namespaced(client, namespace);
+        let secret = secret_api.get(name).await?;
+        Self::parse(&secret_value(&secret, MASTER_KEYS_SECRET_KEY)?)
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    fn key(&self, id: &str) -> Result<LessSafeKey> {
+        let key = self
+            .keys
+            .get(id)
+            .with_context(|| format!("unknown master key {}", id))?;
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow::anyhow!("invalid key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    /// Encrypts `plaintext` as `kwpm:v1:<key id>:<base64 nonce and ciphertext>`.
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).context("failed to generate a nonce")?;
+
+        let mut sealed = plaintext.as_bytes().to_vec();
+        self.key(&self.current)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.current.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow::anyhow!("failed to encrypt value"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(sealed);
+        Ok(format!(
+            "{}{}:{}",
+            SEALED_PREFIX,
+            self.current,
+            STANDARD.encode(payload)
+        ))
+    }
+
+    /// Hex HMAC-SHA256 of `message` with the current key, for values that have to be stable
+    /// but must not be guessable from `message`.
+    pub fn derive(&self, message: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.keys[&self.current]);
+        hmac::sign(&key, message.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<String> {
+        let (id, payload) = sealed
+            .strip_prefix(SEALED_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .context("value is not sealed")?;
+        let payload = STANDARD.decode(payload).context("invalid sealed value")?;
+        if payload.len() < NONCE_LEN {
+            bail!("invalid sealed value");
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+
+        let mut plaintext = ciphertext.to_vec();
+        let plaintext = self
+            .key(id)?
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce)?,
+                Aad::from(id.as_bytes()),
+                &mut plaintext,
+            )
+            .map_err(|_| anyhow::anyhow!("failed to decrypt value sealed with key {}", id))?;
+
+        Ok(String::from_utf8(plaintext.to_vec())?)
+    }
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+/// The id of the master key `value` was sealed with.
+pub fn sealed_key_id(value: &str) -> Option<&str> {
+    value
+        .strip_prefix(SEALED_PREFIX)
+        .and_then(|rest| rest.split_once(':'))
+        .map(|(id, _)| id)
+}
+
+impl KwpmClient {
+    /// Encrypts credentials kwpm stores in site specs with `keys`. Without master keys sites
+    /// can't be given such credentials.
+    pub fn with_master_keys(mut self, keys: MasterKeys) -> Self {
+        self.master_keys = Some(keys);
+        self
+    }
+
+    pub(crate) fn seal(&self, plaintext: &str) -> Result<String> {
+        self.master_keys
+            .as_ref()
+            .context("storing credentials requires master keys")?
+            .seal(plaintext)
+    }
+
+    pub(crate) fn open(&self, sealed: &str) -> Result<String> {
+        self.master_keys
+            .as_ref()
+            .context("reading stored credentials requires master keys")?
+            .open(sealed)
+    }
+
+    /// Re-encrypts the credentials of every site matching `selector` that were sealed with an
+    /// older master key, returning the rewritten sites. Older keys can be dropped afterwards.
+    pub async fn rotate_master_key(&self, selector: &str) -> Result<Vec<String>> {
+        let current = self
+            .master_keys
+            .as_ref()
+            .context("rotating credentials requires master keys")?
+            .current_key_id()
+            .to_string();
+
+        let mut rotated = Vec::new();
+        for site in self.list_site_names(selector).await? {
+            let mut stored = self.stored_site_spec(&site).await?;
+            let is_stale = stored
+                .sensitive_values_mut()
+                .iter()
+                .any(|value| sealed_key_id(value) != Some(current.as_str()));
+            if is_stale {
+                self.save_site_spec(&site, &self.get_site_spec(&site).await?)
+                    .await
+                    .with_context(|| format!("failed to rotate credentials of {}", site))?;
+                rotated.push(site);
+            }
+        }
+
+        Ok(rotated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[(&str, u8)]) -> MasterKeys {
+        MasterKeys::parse(
+            &keys
+                .iter()
+                .map(|(id, byte)| format!("{}={}", id, STANDARD.encode([*byte; 32])))
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let keys = keys(&[("2024", 1)]);
+        let sealed = keys.seal("hunter2").unwrap();
+
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(sealed_key_id(&sealed), Some("2024"));
+        assert_ne!(keys.seal("hunter2").unwrap(), sealed);
+        assert_eq!(keys.open(&sealed).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn test_open_after_rotation() {
+        let sealed = keys(&[("old", 1)]).seal("hunter2").unwrap();
+        let rotated = keys(&[("new", 2), ("old", 1)]);
+
+        assert_eq!(rotated.current_key_id(), "new");
+        assert_eq!(rotated.open(&sealed).unwrap(), "hunter2");
+        assert_eq!(
+            sealed_key_id(&rotated.seal("hunter2").unwrap()),
+            Some("new")
+        );
+        assert!(keys(&[("new", 2)]).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_open_rejects_tampering() {
+        let keys = keys(&[("a", 1), ("b", 1)]);
+        let sealed = keys.seal("hunter2").unwrap();
+
+        assert!(keys.open(&sealed.replacen(":a:", ":b:", 1)).is_err());
+        assert!(keys.open("hunter2").is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_keys() {
+        assert!(MasterKeys::parse("").is_err());
+        assert!(MasterKeys::parse("a=c2hvcnQ=").is_err());
+        assert!(MasterKeys::parse(&STANDARD.encode([0u8; 32])).is_err());
+    }
+}
@@ -0,0 +1,805 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::Instrument;
+
+use crate::{
+    blueprint::fetch_blueprint,
+    confirm::{DestructiveOperation, Plan},
+    credentials::token_hash,
+    error::{bail, KwpmError, Result},
+    exec::ExecEvent,
+    failover::MariaDbFailover,
+    health::{HealthMonitor, HealthSummary, SiteHealth},
+    install::WordPressInstall,
+    mariadb::DEFAULT_MARIADB_INSTANCE,
+    metrics::render_metrics,
+    plugins::{Extension, InstalledExtension},
+    sidecar::Sidecars,
+    site::AppKind,
+    timeline::TimelineEntry,
+    users::AdminUser,
+    HostingImport, ImportedSite, KwpmClient, MariaDbUpgrade, MetadataChange, QueryResult,
+    RelabelReport, RootPasswordSecret, SiteStatus, SiteTransfer, SiteUpgrade, TenantTransfer,
+};
+
+#[derive(Clone)]
+struct AppState {
+    client: Arc<KwpmClient>,
+    /// Hash of the bearer token every request has to carry.
+    token_hash: String,
+    health: HealthMonitor,
+}
+
+/// How often the server probes every site for `/healthz`.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSiteRequest {
+    pub name: String,
+    pub domain: String,
+    #[serde(default)]
+    pub app: AppKind,
+    /// The MariaDB instance of the site's database, the default one if unset.
+    #[serde(default)]
+    pub mariadb: Option<String>,
+    /// Pinned `oci://` or `git+https://` source of a blueprint to create the site from. Its app
+    /// takes the place of `app`.
+    #[serde(default)]
+    pub blueprint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateMariaDbRequest {
+    /// Generated if unset, or kept from the existing instance.
+    #[serde(default)]
+    pub root_password: Option<String>,
+    /// The node the data goes on, picked from the cluster's nodes if unset.
+    #[serde(default)]
+    pub node_hostname: Option<String>,
+    /// The instance to create, the default one if unset.
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ListSitesQuery {
+    /// Label selector the site namespaces have to match.
+    #[serde(default)]
+    pub selector: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RemoveSiteQuery {
+    /// Token of the plan of the removal, see `POST /plans`.
+    #[serde(default)]
+    pub confirm: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MariaDbStandbyRequest {
+    /// The instance whose sites fail over, the default one if unset.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Removes the standby if unset.
+    #[serde(default)]
+    pub standby: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MariaDbFailoverRequest {
+    /// The instance that is down, the default one if unset.
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RemoveMariaDbQuery {
+    /// The instance to remove, the default one if unset.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Remove the finalizers blocking the namespace if it gets stuck terminating.
+    #[serde(default)]
+    pub force: bool,
+    /// Token of the plan of the removal, see `POST /plans`.
+    #[serde(default)]
+    pub confirm: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SqlQueryRequest {
+    pub query: String,
+    /// Rows to return at most, see `KwpmClient::run_sql_query`.
+    #[serde(default)]
+    pub max_rows: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SiteResponse {
+    pub name: String,
+}
+
+/// Errors are returned as `{"error": "..."}` with the status matching the [`KwpmError`], e.g.
+/// 404 for missing sites, 403 for changes through a read-only client or 428 for unconfirmed
+/// destructive operations.
+struct ApiError(KwpmError);
+
+impl<E: Into<KwpmError>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        Self(e.into())
+    }
+}
+
+fn error_status(e: &KwpmError) -> StatusCode {
+    match e {
+        KwpmError::NotFound(_) => StatusCode::NOT_FOUND,
+        KwpmError::AlreadyExists(_) | KwpmError::Conflict(_) => StatusCode::CONFLICT,
+        KwpmError::InvalidInput(_) | KwpmError::InvalidManifest(_) => StatusCode::BAD_REQUEST,
+        KwpmError::ReadOnly(_) => StatusCode::FORBIDDEN,
+        KwpmError::ConfirmationRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+        KwpmError::KubeApi(_) | KwpmError::Other(_) => e
+            .kube_status()
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = error_status(&self.0);
+        if status.is_server_error() {
+            tracing::error!(status = status.as_u16(), error = %format!("{:#}", self.0), "request failed");
+        }
+        (status, Json(json!({ "error": format!("{:#}", self.0) }))).into_response()
+    }
+}
+
+fn is_authorized(headers: &HeaderMap, expected_hash: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token_hash(token) == expected_hash)
+}
+
+async fn authorize(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !is_authorized(request.headers(), &state.token_hash) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "missing or invalid bearer token" })),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Runs the request in a span with its method and path, so the operation's logs carry them.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+    );
+    async move {
+        let start = std::time::Instant::now();
+        let response = next.run(request).await;
+        tracing::info!(
+            status = response.status().as_u16(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "request finished"
+        );
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+async fn list_sites(
+    State(state): State<AppState>,
+    Query(query): Query<ListSitesQuery>,
+) -> Result<Json<Vec<SiteResponse>>, ApiError> {
+    let names = state.client.list_site_names(&query.selector).await?;
+    Ok(Json(
+        names
+            .into_iter()
+            .map(|name| SiteResponse { name })
+            .collect(),
+    ))
+}
+
+async fn list_site_statuses(
+    State(state): State<AppState>,
+    Query(query): Query<ListSitesQuery>,
+) -> Result<Json<Vec<SiteStatus>>, ApiError> {
+    Ok(Json(state.client.list_sites(&query.selector).await?))
+}
+
+async fn relabel_sites(
+    State(state): State<AppState>,
+    Query(query): Query<ListSitesQuery>,
+    Json(change): Json<MetadataChange>,
+) -> Result<Json<RelabelReport>, ApiError> {
+    Ok(Json(state.client.relabel(&query.selector, &change).await?))
+}
+
+async fn create_site(
+    State(state): State<AppState>,
+    Json(request): Json<CreateSiteRequest>,
+) -> Result<(StatusCode, Json<SiteResponse>), ApiError> {
+    let blueprint = match &request.blueprint {
+        Some(source) => Some(fetch_blueprint(source).await?),
+        None => None,
+    };
+    let app = blueprint.as_ref().map_or(&request.app, |b| &b.spec.app);
+    let database = if app.uses_database() {
+        let database = state.client.database_config_for_site(&request.name)?;
+        Some(match &request.mariadb {
+            Some(instance) => database.on_instance(instance),
+            None => database,
+        })
+    } else {
+        None
+    };
+    match &blueprint {
+        Some(blueprint) => {
+            state
+                .client
+                .create_site_from_blueprint(
+                    &request.name,
+                    &request.domain,
+                    blueprint,
+                    database.as_ref(),
+                )
+                .await?
+        }
+        None => {
+            state
+                .client
+                .create_site(&request.name, &request.domain, app, database.as_ref())
+                .await?
+        }
+    }
+    Ok((
+        StatusCode::CREATED,
+        Json(SiteResponse { name: request.name }),
+    ))
+}
+
+async fn plan(
+    State(state): State<AppState>,
+    Json(operation): Json<DestructiveOperation>,
+) -> Result<Json<Plan>, ApiError> {
+    Ok(Json(state.client.plan(&operation).await?))
+}
+
+async fn remove_site(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<RemoveSiteQuery>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .client
+        .remove_wordpress_site(&name, &query.confirm)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn run_sql_query(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<SqlQueryRequest>,
+) -> Result<Json<QueryResult>, ApiError> {
+    Ok(Json(
+        state
+            .client
+            .run_sql_query(&name, &request.query, request.max_rows)
+            .await?,
+    ))
+}
+
+async fn import_site(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(import): Json<HostingImport>,
+) -> Result<(StatusCode, Json<ImportedSite>), ApiError> {
+    Ok((
+        StatusCode::CREATED,
+        Json(state.client.import_hosting_backup(&name, &import).await?),
+    ))
+}
+
+async fn install_wordpress(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(install): Json<WordPressInstall>,
+) -> Result<(StatusCode, Json<AdminUser>), ApiError> {
+    Ok((
+        StatusCode::CREATED,
+        Json(state.client.install_wordpress(&name, &install).await?),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SiteLocaleRequest {
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+async fn set_site_locale(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<SiteLocaleRequest>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .client
+        .set_site_locale(
+            &name,
+            request.locale.as_deref(),
+            request.timezone.as_deref(),
+        )
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteEnvRequest {
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub config_constants: BTreeMap<String, serde_json::Value>,
+}
+
+async fn set_site_env(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<SiteEnvRequest>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .client
+        .set_site_env(&name, request.env, request.config_constants)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_site_sidecars(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(sidecars): Json<Sidecars>,
+) -> Result<StatusCode, ApiError> {
+    state.client.set_site_sidecars(&name, sidecars).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WpCliRequest {
+    pub args: Vec<String>,
+}
+
+/// Streams the output as newline-delimited JSON `ExecEvent`s, e.g. `{"stdout":"..."}`, ending
+/// with `{"exit":{...}}`. Errors after the command started end the stream as a failed exit.
+async fn wp_cli(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<WpCliRequest>,
+) -> Result<Response, ApiError> {
+    let events = state.client.wp_cli(&name, &request.args).await?;
+    let lines = events.map(|event| {
+        let event = event.unwrap_or_else(|e| ExecEvent::Exit {
+            success: false,
+            message: Some(format!("{:#}", e)),
+        });
+        serde_json::to_string(&event).map(|line| line + "\n")
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+async fn list_plugins(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<InstalledExtension>>, ApiError> {
+    Ok(Json(state.client.list_plugins(&name).await?))
+}
+
+async fn install_plugin(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(plugin): Json<Extension>,
+) -> Result<StatusCode, ApiError> {
+    state.client.install_plugin(&name, plugin).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_plugin(
+    State(state): State<AppState>,
+    Path((name, slug)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    state.client.remove_plugin(&name, &slug).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_themes(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<InstalledExtension>>, ApiError> {
+    Ok(Json(state.client.list_themes(&name).await?))
+}
+
+async fn install_theme(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(theme): Json<Extension>,
+) -> Result<StatusCode, ApiError> {
+    state.client.install_theme(&name, theme).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncExtensionsResponse {
+    pub commands: Vec<String>,
+}
+
+async fn sync_site_extensions(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<SyncExtensionsResponse>, ApiError> {
+    let commands = state.client.sync_site_extensions(&name).await?;
+    Ok(Json(SyncExtensionsResponse { commands }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PausedRequest {
+    pub paused: bool,
+}
+
+async fn set_site_paused(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<PausedRequest>,
+) -> Result<StatusCode, ApiError> {
+    state.client.set_site_paused(&name, request.paused).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpgradeRequest {
+    pub tag: String,
+}
+
+async fn upgrade_site(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<UpgradeRequest>,
+) -> Result<Json<SiteUpgrade>, ApiError> {
+    Ok(Json(state.client.upgrade_site(&name, &request.tag).await?))
+}
+
+async fn transfer_site(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(transfer): Json<TenantTransfer>,
+) -> Result<Json<SiteTransfer>, ApiError> {
+    Ok(Json(state.client.transfer_site(&name, &transfer).await?))
+}
+
+/// The metrics of this process, with the number of sites unless listing them failed.
+async fn metrics(State(client): State<Arc<KwpmClient>>) -> impl IntoResponse {
+    let sites = client
+        .list_site_names("")
+        .await
+        .ok()
+        .map(|sites| sites.len());
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(sites),
+    )
+}
+
+async fn health_summary(State(state): State<AppState>) -> Json<HealthSummary> {
+    Json(state.health.summary())
+}
+
+/// The last probe of the site, or a fresh one before the first round reached it.
+async fn site_health(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<SiteHealth>, ApiError> {
+    if let Some(health) = state.health.site(&name) {
+        return Ok(Json(health));
+    }
+    Ok(Json(state.client.probe_site_health(&name).await?))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TimelineQuery {
+    /// Leave out entries before this RFC 3339 time.
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+}
+
+async fn site_timeline(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Vec<TimelineEntry>>, ApiError> {
+    Ok(Json(state.client.site_timeline(&name, query.since).await?))
+}
+
+async fn list_mariadb_instances(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    Ok(Json(state.client.list_mariadb_instances().await?))
+}
+
+async fn create_mariadb(
+    State(state): State<AppState>,
+    Json(request): Json<CreateMariaDbRequest>,
+) -> Result<(StatusCode, Json<RootPasswordSecret>), ApiError> {
+    let instance = request
+        .instance
+        .as_deref()
+        .unwrap_or(DEFAULT_MARIADB_INSTANCE);
+    let secret = state
+        .client
+        .create_mariadb_instance(
+            instance,
+            request.root_password.as_deref(),
+            request.node_hostname.as_deref(),
+        )
+        .await?;
+    Ok((StatusCode::CREATED, Json(secret)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpgradeMariaDbRequest {
+    /// The instance to upgrade, the default one if unset.
+    #[serde(default)]
+    pub instance: Option<String>,
+    pub tag: String,
+}
+
+async fn upgrade_mariadb(
+    State(state): State<AppState>,
+    Json(request): Json<UpgradeMariaDbRequest>,
+) -> Result<Json<MariaDbUpgrade>, ApiError> {
+    let instance = request
+        .instance
+        .as_deref()
+        .unwrap_or(DEFAULT_MARIADB_INSTANCE);
+    Ok(Json(
+        state.client.upgrade_mariadb(instance, &request.tag).await?,
+    ))
+}
+
+async fn set_mariadb_standby(
+    State(state): State<AppState>,
+    Json(request): Json<MariaDbStandbyRequest>,
+) -> Result<StatusCode, ApiError> {
+    let instance = request
+        .instance
+        .as_deref()
+        .unwrap_or(DEFAULT_MARIADB_INSTANCE);
+    state
+        .client
+        .set_mariadb_standby(instance, request.standby.as_deref())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn fail_over_mariadb(
+    State(state): State<AppState>,
+    Json(request): Json<MariaDbFailoverRequest>,
+) -> Result<Json<MariaDbFailover>, ApiError> {
+    let instance = request
+        .instance
+        .as_deref()
+        .unwrap_or(DEFAULT_MARIADB_INSTANCE);
+    Ok(Json(state.client.fail_over_mariadb(instance).await?))
+}
+
+async fn remove_mariadb(
+    State(state): State<AppState>,
+    Query(query): Query<RemoveMariaDbQuery>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .client
+        .remove_mariadb_instance(
+            query
+                .instance
+                .as_deref()
+                .unwrap_or(DEFAULT_MARIADB_INSTANCE),
+            query.force,
+            &query.confirm,
+        )
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The REST API, requiring `Authorization: Bearer <token>` on every route but `/healthz`, which
+/// reports the results `health` holds, and `/metrics`.
+pub fn router(client: Arc<KwpmClient>, health: HealthMonitor, token: &str) -> Result<Router> {
+    if token.len() < 16 {
+        bail!("the API token must be at least 16 characters");
+    }
+    let state = AppState {
+        client,
+        token_hash: token_hash(token),
+        health,
+    };
+
+    let api = Router::new()
+        .route("/sites", get(list_sites).post(create_site))
+        .route("/sites/status", get(list_site_statuses))
+        .route("/sites/relabel", post(relabel_sites))
+        .route("/sites/:name", delete(remove_site))
+        .route("/sites/:name/health", get(site_health))
+        .route("/sites/:name/timeline", get(site_timeline))
+        .route("/sites/:name/import", post(import_site))
+        .route("/sites/:name/install", post(install_wordpress))
+        .route("/sites/:name/locale", put(set_site_locale))
+        .route("/sites/:name/env", put(set_site_env))
+        .route("/sites/:name/sidecars", put(set_site_sidecars))
+        .route("/sites/:name/paused", put(set_site_paused))
+        .route("/sites/:name/wp-cli", post(wp_cli))
+        .route(
+            "/sites/:name/plugins",
+            get(list_plugins).post(install_plugin),
+        )
+        .route("/sites/:name/plugins/:slug", delete(remove_plugin))
+        .route("/sites/:name/themes", get(list_themes).post(install_theme))
+        .route("/sites/:name/extensions/sync", post(sync_site_extensions))
+        .route("/sites/:name/upgrade", post(upgrade_site))
+        .route("/sites/:name/sql", post(run_sql_query))
+        .route("/sites/:name/transfer", post(transfer_site))
+        .route("/plans", post(plan))
+        .route(
+            "/mariadb",
+            get(list_mariadb_instances)
+                .post(create_mariadb)
+                .delete(remove_mariadb),
+        )
+        .route("/mariadb/upgrade", post(upgrade_mariadb))
+        .route("/mariadb/standby", put(set_mariadb_standby))
+        .route("/mariadb/failover", post(fail_over_mariadb))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize));
+
+    Ok(Router::new()
+        .route("/healthz", get(health_summary))
+        .route(
+            "/metrics",
+            get(|State(state): State<AppState>| metrics(State(state.client))),
+        )
+        .merge(api)
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state))
+}
+
+/// Serves only `/metrics`, for processes without the API such as the operator.
+pub async fn serve_metrics(client: Arc<KwpmClient>, addr: SocketAddr) -> Result<()> {
+    let router = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(client);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+pub async fn serve(client: KwpmClient, token: &str, addr: SocketAddr) -> Result<()> {
+    let client = Arc::new(client);
+    let health = HealthMonitor::default();
+    let router = router(client.clone(), health.clone(), token)?;
+    tokio::spawn(async move {
+        client
+            .run_health_checks(&health, HEALTH_CHECK_INTERVAL)
+            .await
+    });
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!(%addr, "serving the API");
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{ConfirmationRequired, ReadOnlyViolation};
+
+    #[test]
+    fn test_is_authorized() {
+        let expected = token_hash("0123456789abcdef");
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, &expected));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer 0123456789abcdef"),
+        );
+        assert!(is_authorized(&headers, &expected));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic 0123456789abcdef"),
+        );
+        assert!(!is_authorized(&headers, &expected));
+    }
+
+    #[test]
+    fn test_create_site_request_defaults_to_wordpress() {
+        let request: CreateSiteRequest =
+            serde_json::from_str(r#"{"name":"blog","domain":"blog.example.com"}"#).unwrap();
+        assert_eq!(request.app, AppKind::WordPress);
+
+        let request: CreateSiteRequest = serde_json::from_str(
+            r#"{"name":"stats","domain":"stats.example.com","app":{"kind":"php","image":"matomo:5-fpm-alpine"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            request.app,
+            AppKind::Php {
+                image: "matomo:5-fpm-alpine".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_error_status() {
+        let not_found = kube::Error::Api(kube::core::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "namespaces \"kwpm-blog\" not found".to_string(),
+            reason: "NotFound".to_string(),
+            code: 404,
+        });
+
+        assert_eq!(
+            error_status(
+                &anyhow::Error::new(not_found)
+                    .context("failed to remove site")
+                    .into()
+            ),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            error_status(&anyhow::anyhow!("job failed").into()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            error_status(&KwpmError::invalid_input("invalid site name")),
+            StatusCode::BAD_REQUEST
+        );
+        let unconfirmed = ConfirmationRequired {
+            operation: DestructiveOperation::RemoveMariaDb,
+            reason: "no confirmation token was given".to_string(),
+        };
+        assert_eq!(
+            error_status(&unconfirmed.into()),
+            StatusCode::PRECONDITION_REQUIRED
+        );
+        let read_only = kube::Error::Service(Box::new(ReadOnlyViolation {
+            operation: "DELETE /api/v1/namespaces/kwpm-blog".to_string(),
+        }));
+        assert_eq!(error_status(&read_only.into()), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,351 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{Container, PodSpec, Volume},
+};
+use kube::Api;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{KwpmError, Result},
+    manifest::deployment_pod_spec_mut,
+    KwpmClient,
+};
+
+/// Pod template annotation with the names of the containers and volumes kwpm injected, so
+/// changing the sidecars removes the previous ones and nothing else.
+const SIDECARS_ANNOTATION: &str = "kwpm.io/sidecars";
+/// php-fpm listens on it in the WordPress container without declaring it.
+const PHP_FPM_PORT: i32 = 9000;
+const SIDECAR_ROLLOUT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Extra containers running next to a site, such as log shippers, exporters or cron runners,
+/// and the volumes they need. They may mount the site's own volumes too.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Sidecars {
+    pub containers: Vec<Container>,
+    pub volumes: Vec<Volume>,
+}
+
+impl Sidecars {
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty() && self.volumes.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+struct InjectedNames {
+    containers: Vec<String>,
+    volumes: Vec<String>,
+}
+
+fn is_dns_label(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn container_ports(container: &Container) -> impl Iterator<Item = i32> + '_ {
+    container
+        .ports
+        .iter()
+        .flatten()
+        .map(|port| port.container_port)
+}
+
+/// Rejects sidecars whose containers, volumes or ports clash with each other or with the pod
+/// they join, which holds no injected sidecars at this point.
+fn check_sidecars(pod_spec: &PodSpec, sidecars: &Sidecars) -> Result<()> {
+    let mut containers: BTreeSet<&str> = pod_spec
+        .containers
+        .iter()
+        .chain(pod_spec.init_containers.iter().flatten())
+        .map(|c| c.name.as_str())
+        .collect();
+    let mut volumes: BTreeSet<&str> = pod_spec
+        .volumes
+        .iter()
+        .flatten()
+        .map(|v| v.name.as_str())
+        .collect();
+    let mut ports: BTreeSet<i32> = pod_spec
+        .containers
+        .iter()
+        .flat_map(container_ports)
+        .collect();
+    if containers.contains("wordpress") {
+        ports.insert(PHP_FPM_PORT);
+    }
+
+    for volume in &sidecars.volumes {
+        if !is_dns_label(&volume.name) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid volume name: {}",
+                volume.name
+            )));
+        }
+        if !volumes.insert(&volume.name) {
+            return Err(KwpmError::invalid_input(format!(
+                "volume {} exists in the pod already",
+                volume.name
+            )));
+        }
+    }
+    for container in &sidecars.containers {
+        if !is_dns_label(&container.name) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid container name: {}",
+                container.name
+            )));
+        }
+        if !containers.insert(&container.name) {
+            return Err(KwpmError::invalid_input(format!(
+                "container {} exists in the pod already",
+                container.name
+            )));
+        }
+        if container.image.as_deref().unwrap_or_default().is_empty() {
+            return Err(KwpmError::invalid_input(format!(
+                "container {} has no image",
+                container.name
+            )));
+        }
+        for port in container_ports(container) {
+            if !ports.insert(port) {
+                return Err(KwpmError::invalid_input(format!(
+                    "port {} of {} is taken in the pod",
+                    port, container.name
+                )));
+            }
+        }
+        for mount in container.volume_mounts.iter().flatten() {
+            if !volumes.contains(mount.name.as_str()) {
+                return Err(KwpmError::invalid_input(format!(
+                    "{} mounts the unknown volume {}",
+                    container.name, mount.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the sidecars injected before with `sidecars`, leaving kwpm's containers and
+/// volumes untouched.
+pub fn apply_sidecars(deployment: &mut Deployment, sidecars: &Sidecars) -> Result<()> {
+    let annotations = deployment
+        .spec
+        .as_mut()
+        .context("deployment has no spec")?
+        .template
+        .metadata
+        .get_or_insert_with(Default::default)
+        .annotations
+        .get_or_insert_with(Default::default);
+    let injected: InjectedNames = annotations
+        .remove(SIDECARS_ANNOTATION)
+        .and_then(|names| serde_json::from_str(&names).ok())
+        .unwrap_or_default();
+    if !sidecars.is_empty() {
+        annotations.insert(
+            SIDECARS_ANNOTATION.to_string(),
+            serde_json::to_string(&InjectedNames {
+                containers: sidecars.containers.iter().map(|c| c.name.clone()).collect(),
+                volumes: sidecars.volumes.iter().map(|v| v.name.clone()).collect(),
+            })?,
+        );
+    }
+
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+    pod_spec
+        .containers
+        .retain(|c| !injected.containers.contains(&c.name));
+    if let Some(volumes) = pod_spec.volumes.as_mut() {
+        volumes.retain(|v| !injected.volumes.contains(&v.name));
+    }
+    check_sidecars(pod_spec, sidecars)?;
+    pod_spec
+        .containers
+        .extend(sidecars.containers.iter().cloned());
+    if !sidecars.volumes.is_empty() {
+        pod_spec
+            .volumes
+            .get_or_insert_with(Vec::new)
+            .extend(sidecars.volumes.iter().cloned());
+    }
+    Ok(())
+}
+
+impl KwpmClient {
+    /// Replaces the sidecars of a site and waits for the restarted pods.
+    pub async fn set_site_sidecars(&self, site: &str, sidecars: Sidecars) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let mut spec = self.get_site_spec(site).await?;
+        let mut deployment = deployment_api.get("wordpress").await?;
+        apply_sidecars(&mut deployment, &sidecars)?;
+        self.check_policy(
+            "set_site_sidecars",
+            Some(site),
+            &[serde_json::to_value(&deployment)?],
+        )
+        .await?;
+
+        let names: Vec<&str> = sidecars
+            .containers
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        spec.sidecars = sidecars.clone();
+        self.save_site_spec(site, &spec).await?;
+
+        let deployment = deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+        self.record_site_revision(site, "set_site_sidecars").await?;
+        self.record_audit(
+            site,
+            "set_site_sidecars",
+            &match names.is_empty() {
+                true => "removed the sidecars".to_string(),
+                false => names.join(", "),
+            },
+        )
+        .await?;
+        self.wait_for_rollout(site, &deployment, SIDECAR_ROLLOUT_TIMEOUT)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{ContainerPort, EmptyDirVolumeSource, VolumeMount};
+
+    use super::*;
+
+    fn deployment() -> Deployment {
+        serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap()
+    }
+
+    fn container(name: &str, port: Option<i32>, mounts: &[&str]) -> Container {
+        Container {
+            name: name.to_string(),
+            image: Some("fluent/fluent-bit:3".to_string()),
+            ports: port.map(|port| {
+                vec![ContainerPort {
+                    container_port: port,
+                    ..Default::default()
+                }]
+            }),
+            volume_mounts: Some(
+                mounts
+                    .iter()
+                    .map(|name| VolumeMount {
+                        name: name.to_string(),
+                        mount_path: format!("/mnt/{}", name),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn volume(name: &str) -> Volume {
+        Volume {
+            name: name.to_string(),
+            empty_dir: Some(EmptyDirVolumeSource::default()),
+            ..Default::default()
+        }
+    }
+
+    fn names(deployment: &mut Deployment) -> (Vec<String>, Vec<String>) {
+        let pod_spec = deployment_pod_spec_mut(deployment).unwrap();
+        (
+            pod_spec.containers.iter().map(|c| c.name.clone()).collect(),
+            pod_spec
+                .volumes
+                .iter()
+                .flatten()
+                .map(|v| v.name.clone())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_apply_sidecars() {
+        let mut deployment = deployment();
+        let sidecars = Sidecars {
+            containers: vec![container(
+                "log-shipper",
+                Some(2020),
+                &["wordpress-persistent-storage", "buffer"],
+            )],
+            volumes: vec![volume("buffer")],
+        };
+        apply_sidecars(&mut deployment, &sidecars).unwrap();
+        apply_sidecars(&mut deployment, &sidecars).unwrap();
+        let (containers, volumes) = names(&mut deployment);
+        assert_eq!(containers, ["wordpress", "nginx", "log-shipper"]);
+        assert_eq!(volumes.iter().filter(|v| *v == "buffer").count(), 1);
+
+        let exporter = Sidecars {
+            containers: vec![container("exporter", Some(9253), &[])],
+            volumes: Vec::new(),
+        };
+        apply_sidecars(&mut deployment, &exporter).unwrap();
+        let (containers, volumes) = names(&mut deployment);
+        assert_eq!(containers, ["wordpress", "nginx", "exporter"]);
+        assert!(!volumes.contains(&"buffer".to_string()));
+
+        apply_sidecars(&mut deployment, &Sidecars::default()).unwrap();
+        assert_eq!(names(&mut deployment).0, ["wordpress", "nginx"]);
+        assert_eq!(names(&mut deployment).1.len(), 4);
+    }
+
+    #[test]
+    fn test_sidecar_conflicts() {
+        let conflict = |containers: Vec<Container>, volumes: Vec<Volume>| {
+            apply_sidecars(
+                &mut deployment(),
+                &Sidecars {
+                    containers,
+                    volumes,
+                },
+            )
+            .is_err()
+        };
+        assert!(conflict(vec![container("nginx", None, &[])], Vec::new()));
+        assert!(conflict(
+            vec![container("exporter", Some(80), &[])],
+            Vec::new()
+        ));
+        assert!(conflict(
+            vec![container("exporter", Some(9000), &[])],
+            Vec::new()
+        ));
+        assert!(conflict(
+            vec![container("exporter", None, &["missing"])],
+            Vec::new()
+        ));
+        assert!(conflict(Vec::new(), vec![volume("nginxconf")]));
+        assert!(conflict(
+            vec![container("cron", None, &[]), container("cron", None, &[])],
+            Vec::new()
+        ));
+        assert!(conflict(vec![container("Cron", None, &[])], Vec::new()));
+        assert!(!conflict(vec![container("cron", None, &[])], Vec::new()));
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{ConfigMap, Namespace},
+    networking::v1::Ingress,
+};
+use kube::{
+    api::{ListParams, ObjectMeta, Patch, PatchParams},
+    runtime::wait::await_condition,
+    Api,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::AdminAccess,
+    environment::Environment,
+    error::{bail, KwpmError, Result},
+    library::LIBRARY_NAMESPACE,
+    mariadb::MARIADB_INSTANCE_NAMESPACE_PREFIX,
+    plugins::Extension,
+    redirect::Redirect,
+    rollout::RolloutStrategy,
+    secrets::is_sealed,
+    sidecar::Sidecars,
+    slo::SloTarget,
+    KwpmClient,
+};
+
+const RESERVED_NAMESPACES: [&str; 2] = ["kwpm-mariadb", LIBRARY_NAMESPACE];
+const SITE_SPEC_CONFIG_MAP: &str = "kwpm-site";
+const SITE_SPEC_KEY: &str = "spec.yaml";
+
+/// The application a site runs. Every kind gets the same volume, service and ingress.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum AppKind {
+    #[default]
+    #[serde(rename = "wordpress")]
+    WordPress,
+    /// A PHP application such as Matomo or phpBB, run by the php-fpm `image` with a database
+    /// passed as `DB_HOST`, `DB_USER`, `DB_PASSWORD` and `DB_NAME`.
+    Php { image: String },
+    /// Files served by nginx alone, without PHP or a database.
+    Static,
+}
+
+impl AppKind {
+    pub fn is_wordpress(&self) -> bool {
+        *self == AppKind::WordPress
+    }
+
+    pub fn uses_php(&self) -> bool {
+        *self != AppKind::Static
+    }
+
+    pub fn uses_database(&self) -> bool {
+        self.uses_php()
+    }
+}
+
+/// Settings kwpm manages for a site, persisted next to it so that every renderer works from the
+/// same desired state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SiteSpec {
+    pub app: AppKind,
+    pub upload_limit_mb: Option<u32>,
+    pub redirects: Vec<Redirect>,
+    pub admin_access: Option<AdminAccess>,
+    pub rollout: Option<RolloutStrategy>,
+    pub slo: Option<SloTarget>,
+    pub environment: Option<Environment>,
+    /// WordPress debug logging, see `set_debug`.
+    pub debug: bool,
+    /// Serve `/healthz.php`, see `enable_health_endpoint`.
+    pub health_endpoint: bool,
+    /// WordPress locale like `de_DE`, see `set_site_locale`.
+    pub locale: Option<String>,
+    /// IANA time zone like `Europe/Berlin`.
+    pub timezone: Option<String>,
+    /// Environment variables for the app container, see `set_site_env`.
+    pub env: BTreeMap<String, String>,
+    /// Constants WordPress defines before loading plugins, see `set_site_env`.
+    pub config_constants: BTreeMap<String, serde_json::Value>,
+    /// Extra containers and volumes in the site's pods, see `set_site_sidecars`.
+    pub sidecars: Sidecars,
+    /// Plugins from wordpress.org, see `install_plugin` and `sync_site_extensions`.
+    pub plugins: Vec<Extension>,
+    pub themes: Vec<Extension>,
+}
+
+impl SiteSpec {
+    /// Credentials in the spec, which are encrypted with the master key when persisted.
+    pub(crate) fn sensitive_values_mut(&mut self) -> Vec<&mut String> {
+        self.slo
+            .iter_mut()
+            .filter_map(|slo| slo.notify_url.as_mut())
+            .chain(self.env.values_mut())
+            .chain(
+                self.config_constants
+                    .values_mut()
+                    .filter_map(|value| match value {
+                        serde_json::Value::String(value) => Some(value),
+                        _ => None,
+                    }),
+            )
+            .collect()
+    }
+
+    fn to_config_map(&self) -> Result<ConfigMap> {
+        Ok(ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(SITE_SPEC_CONFIG_MAP.to_string()),
+                ..Default::default()
+            },
+            data: Some(
+                [(SITE_SPEC_KEY.to_string(), serde_yaml::to_string(self)?)]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        })
+    }
+
+    fn from_config_map(config_map: &ConfigMap) -> Result<Self> {
+        let spec = config_map
+            .data
+            .as_ref()
+            .and_then(|data| data.get(SITE_SPEC_KEY))
+            .context("site spec config map has no spec")?;
+
+        Ok(serde_yaml::from_str(spec)?)
+    }
+}
+
+/// The prefix of site namespaces unless configured otherwise, see
+/// `KwpmClient::with_namespace_prefix`.
+pub const DEFAULT_NAMESPACE_PREFIX: &str = "kwpm-";
+
+/// A DNS label prefix ending in `-`, short enough to leave room for site names.
+pub fn is_valid_namespace_prefix(prefix: &str) -> bool {
+    (2..=20).contains(&prefix.len())
+        && prefix.ends_with('-')
+        && prefix.starts_with(|c: char| c.is_ascii_lowercase())
+        && prefix
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Namespaces kwpm uses for itself, which no site may end up in whatever the prefix.
+fn is_reserved_namespace(namespace: &str) -> bool {
+    RESERVED_NAMESPACES.contains(&namespace)
+        || namespace.starts_with(MARIADB_INSTANCE_NAMESPACE_PREFIX)
+}
+
+pub(crate) fn prefixed_namespace(prefix: &str, site: &str) -> String {
+    format!("{}{}", prefix, site)
+}
+
+fn site_name_with_prefix<'a>(prefix: &str, namespace: &'a str) -> Option<&'a str> {
+    if is_reserved_namespace(namespace) {
+        return None;
+    }
+
+    namespace
+        .strip_prefix(prefix)
+        .filter(|site| !site.is_empty())
+}
+
+/// Site names become part of the namespace name, so they must be DNS labels, and the prefixed
+/// namespace must not be one kwpm reserves.
+fn is_valid_site_name_with_prefix(prefix: &str, site: &str) -> bool {
+    let ns_name = prefixed_namespace(prefix, site);
+    ns_name.len() <= 63
+        && !site.starts_with('-')
+        && !site.ends_with('-')
+        && site
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && site_name_with_prefix(prefix, &ns_name) == Some(site)
+}
+
+impl KwpmClient {
+    /// Sets the prefix of the namespaces of the sites this client manages, see
+    /// [`DEFAULT_NAMESPACE_PREFIX`]. Clients with different prefixes manage different sites.
+    pub fn with_namespace_prefix(mut self, prefix: &str) -> Result<Self> {
+        if !is_valid_namespace_prefix(prefix) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid namespace prefix: {}",
+                prefix
+            )));
+        }
+        self.namespace_prefix = prefix.to_string();
+        Ok(self)
+    }
+
+    pub fn site_namespace(&self, site: &str) -> String {
+        prefixed_namespace(&self.namespace_prefix, site)
+    }
+
+    pub fn is_valid_site_name(&self, site: &str) -> bool {
+        is_valid_site_name_with_prefix(&self.namespace_prefix, site)
+    }
+
+    /// The site living in `namespace`, if it is a site namespace of this client.
+    pub fn site_name<'a>(&self, namespace: &'a str) -> Option<&'a str> {
+        site_name_with_prefix(&self.namespace_prefix, namespace)
+    }
+}
+
+pub(crate) fn is_deployment_available(deployment: &Deployment) -> bool {
+    let replicas = deployment
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.replicas)
+        .unwrap_or(1);
+    let Some(status) = deployment.status.as_ref() else {
+        return false;
+    };
+
+    status.updated_replicas.unwrap_or(0) >= replicas
+        && status.available_replicas.unwrap_or(0) >= replicas
+}
+
+impl KwpmClient {
+    /// The spec as persisted, with credentials still sealed.
+    pub(crate) async fn stored_site_spec(&self, site: &str) -> Result<SiteSpec> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        match config_map_api.get_opt(SITE_SPEC_CONFIG_MAP).await? {
+            Some(config_map) => SiteSpec::from_config_map(&config_map),
+            None => Ok(SiteSpec::default()),
+        }
+    }
+
+    pub async fn get_site_spec(&self, site: &str) -> Result<SiteSpec> {
+        let mut spec = self.stored_site_spec(site).await?;
+        for value in spec.sensitive_values_mut() {
+            if is_sealed(value) {
+                *value = self.open(value)?;
+            }
+        }
+
+        Ok(spec)
+    }
+
+    pub async fn save_site_spec(&self, site: &str, spec: &SiteSpec) -> Result<()> {
+        let mut spec = spec.clone();
+        for value in spec.sensitive_values_mut() {
+            *value = self.seal(value)?;
+        }
+
+        self.store_site_spec(site, &spec).await
+    }
+
+    /// Persists a spec whose credentials are already sealed.
+    pub(crate) async fn store_site_spec(&self, site: &str, spec: &SiteSpec) -> Result<()> {
+        let config_map_api: Api<ConfigMap> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        config_map_api
+            .patch(
+                SITE_SPEC_CONFIG_MAP,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&spec.to_config_map()?)),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn list_site_names(&self, selector: &str) -> Result<Vec<String>> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespaces = namespace_api
+            .list(&ListParams::default().labels(selector))
+            .await?;
+
+        Ok(namespaces
+            .items
+            .iter()
+            .filter_map(|ns| {
+                ns.metadata
+                    .name
+                    .as_deref()
+                    .and_then(|ns| self.site_name(ns))
+            })
+            .map(str::to_string)
+            .collect())
+    }
+
+    pub(crate) async fn site_url(&self, site: &str) -> Result<String> {
+        let ns_name = self.site_namespace(site);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let ingresses = ingress_api.list(&Default::default()).await?;
+        let ingress = ingresses.items.iter().find_map(|ingress| {
+            let spec = ingress.spec.as_ref()?;
+            let host = spec.rules.as_ref()?.iter().find_map(|r| r.host.clone())?;
+            let tls = spec
+                .tls
+                .as_ref()
+                .map(|tls| {
+                    tls.iter()
+                        .any(|t| t.hosts.iter().flatten().any(|h| *h == host))
+                })
+                .unwrap_or(false);
+            Some((host, tls))
+        });
+
+        Ok(match ingress {
+            Some((host, true)) => format!("https://{}/", host),
+            Some((host, false)) => format!("http://{}/", host),
+            None => format!("http://wordpress.{}.svc/", ns_name),
+        })
+    }
+
+    #[tracing::instrument(skip_all, fields(namespace = %namespace, deployment = %name))]
+    pub(crate) async fn wait_for_deployment(
+        &self,
+        namespace: &str,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), namespace);
+
+        tokio::time::timeout(
+            timeout,
+            await_condition(deployment_api, name, |deployment: Option<&Deployment>| {
+                deployment.is_some_and(is_deployment_available)
+            }),
+        )
+        .await
+        .with_context(|| format!("timed out waiting for deployment {}/{}", namespace, name))??;
+
+        Ok(())
+    }
+
+    pub async fn check_site_health(&self, site: &str) -> Result<()> {
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let deployment = deployment_api.get("wordpress").await?;
+        if !is_deployment_available(&deployment) {
+            bail!("wordpress deployment of {} is not available", site);
+        }
+
+        self.run_wp_cli(site, &["eval", "echo 'ok';"]).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_site_name() {
+        let site_name = |namespace| site_name_with_prefix(DEFAULT_NAMESPACE_PREFIX, namespace);
+        assert_eq!(site_name("kwpm-blog"), Some("blog"));
+        assert_eq!(site_name("kwpm-mariadb"), None);
+        assert_eq!(site_name("kwpm-mariadb-acme"), None);
+        assert_eq!(site_name("kwpm-library"), None);
+        assert_eq!(site_name("kwpm-"), None);
+        assert_eq!(site_name("default"), None);
+        assert_eq!(site_name_with_prefix("wp-", "wp-blog"), Some("blog"));
+        assert_eq!(site_name_with_prefix("wp-", "kwpm-blog"), None);
+    }
+
+    #[test]
+    fn test_is_valid_namespace_prefix() {
+        assert!(is_valid_namespace_prefix(DEFAULT_NAMESPACE_PREFIX));
+        assert!(is_valid_namespace_prefix("wp-"));
+        assert!(!is_valid_namespace_prefix("wp"));
+        assert!(!is_valid_namespace_prefix("-"));
+        assert!(!is_valid_namespace_prefix("WP-"));
+        assert!(!is_valid_namespace_prefix("1wp-"));
+    }
+
+    #[test]
+    fn test_is_valid_site_name() {
+        let is_valid_site_name =
+            |site| is_valid_site_name_with_prefix(DEFAULT_NAMESPACE_PREFIX, site);
+        assert!(is_valid_site_name("my-blog"));
+        assert!(!is_valid_site_name("mariadb"));
+        assert!(!is_valid_site_name("My_Blog"));
+        assert!(!is_valid_site_name(""));
+        assert!(!is_valid_site_name(&"a".repeat(59)));
+
+        // Reserved namespaces are checked with the prefix applied.
+        assert!(is_valid_site_name_with_prefix("wp-", "mariadb"));
+        assert!(is_valid_site_name_with_prefix("wp-", "library"));
+        assert!(!is_valid_site_name_with_prefix("kwpm-maria", "db"));
+        assert!(!is_valid_site_name_with_prefix("kwpm-mariadb-", "blog"));
+        assert!(is_valid_site_name_with_prefix("wp-", &"a".repeat(60)));
+    }
+
+    #[test]
+    fn test_site_spec_round_trip() {
+        let spec = SiteSpec {
+            upload_limit_mb: Some(64),
+            ..Default::default()
+        };
+
+        let config_map = spec.to_config_map().unwrap();
+        assert_eq!(SiteSpec::from_config_map(&config_map).unwrap(), spec);
+    }
+}
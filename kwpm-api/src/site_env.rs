@@ -0,0 +1,331 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::Context;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{EnvFromSource, Secret, SecretEnvSource},
+};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    debug::DEBUG_CONFIG_EXTRA,
+    error::{bail, Result},
+    manifest::{deployment_pod_spec_mut, set_env},
+    site::SiteSpec,
+    KwpmClient,
+};
+
+/// Holds the custom variables and constant values, so API keys stay out of the deployment.
+pub const SITE_ENV_SECRET: &str = "kwpm-site-env";
+/// Prefix of the variables carrying constant values as JSON.
+const CONSTANT_ENV_PREFIX: &str = "KWPM_CONSTANT_";
+const SITE_ENV_HASH_ANNOTATION: &str = "kwpm.io/site-env-hash";
+const SITE_ENV_ROLLOUT_TIMEOUT: Duration = Duration::from_secs(600);
+/// Constants the wp-config.php of the official image or kwpm's debug logging define.
+const RESERVED_CONSTANTS: &[&str] = &[
+    "ABSPATH",
+    "DB_NAME",
+    "DB_USER",
+    "DB_PASSWORD",
+    "DB_HOST",
+    "DB_CHARSET",
+    "DB_COLLATE",
+    "AUTH_KEY",
+    "SECURE_AUTH_KEY",
+    "LOGGED_IN_KEY",
+    "NONCE_KEY",
+    "AUTH_SALT",
+    "SECURE_AUTH_SALT",
+    "LOGGED_IN_SALT",
+    "NONCE_SALT",
+    "WP_DEBUG",
+    "WP_DEBUG_LOG",
+    "WP_DEBUG_DISPLAY",
+];
+
+/// Names usable as both environment variables and PHP constants.
+pub fn is_valid_env_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Rejects names kwpm or the image set, so custom ones never replace them.
+pub fn check_site_env(
+    env: &BTreeMap<String, String>,
+    constants: &BTreeMap<String, Value>,
+) -> Result<()> {
+    for name in env.keys() {
+        if !is_valid_env_name(name) {
+            bail!("invalid environment variable name: {}", name);
+        }
+        if name.starts_with("WORDPRESS_") || name.starts_with("KWPM_") {
+            bail!("{} is managed by kwpm", name);
+        }
+    }
+    for (name, value) in constants {
+        if !is_valid_env_name(name) {
+            bail!("invalid constant name: {}", name);
+        }
+        if RESERVED_CONSTANTS.contains(&name.as_str()) {
+            bail!("{} is managed by kwpm", name);
+        }
+        if !matches!(value, Value::String(_) | Value::Number(_) | Value::Bool(_)) {
+            bail!("constant {} must be a string, number or boolean", name);
+        }
+    }
+    Ok(())
+}
+
+/// PHP for `WORDPRESS_CONFIG_EXTRA`, defining the constants from their variables unless
+/// wp-config.php already did, followed by the debug logging settings if on.
+pub fn config_extra(debug: bool, constants: &BTreeMap<String, Value>) -> String {
+    let mut extra: Vec<String> = constants
+        .keys()
+        .map(|name| {
+            format!(
+                "if (!defined('{name}')) {{ define('{name}', json_decode(getenv('{prefix}{name}'), true)); }}",
+                name = name,
+                prefix = CONSTANT_ENV_PREFIX
+            )
+        })
+        .collect();
+    if debug {
+        extra.push(DEBUG_CONFIG_EXTRA.to_string());
+    }
+    extra.join("\n")
+}
+
+pub fn site_env_secret(
+    env: &BTreeMap<String, String>,
+    constants: &BTreeMap<String, Value>,
+) -> Secret {
+    let data = env
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .chain(constants.iter().map(|(name, value)| {
+            (
+                format!("{}{}", CONSTANT_ENV_PREFIX, name),
+                value.to_string(),
+            )
+        }))
+        .collect();
+    Secret {
+        metadata: ObjectMeta {
+            name: Some(SITE_ENV_SECRET.to_string()),
+            ..Default::default()
+        },
+        string_data: Some(data),
+        ..Default::default()
+    }
+}
+
+/// Loads the secret into the app container and defines the constants for WordPress, rolling
+/// the pods when the secret's content changes.
+pub fn apply_site_env(deployment: &mut Deployment, spec: &SiteSpec) -> Result<()> {
+    let secret = site_env_secret(&spec.env, &spec.config_constants);
+    let hash = Sha256::digest(serde_json::to_vec(&secret.string_data)?);
+    deployment
+        .spec
+        .as_mut()
+        .context("deployment has no spec")?
+        .template
+        .metadata
+        .get_or_insert_with(Default::default)
+        .annotations
+        .get_or_insert_with(Default::default)
+        .insert(
+            SITE_ENV_HASH_ANNOTATION.to_string(),
+            hash.iter().take(8).map(|b| format!("{:02x}", b)).collect(),
+        );
+
+    let pod_spec = deployment_pod_spec_mut(deployment)?;
+    if spec.app.is_wordpress() {
+        set_env(
+            pod_spec,
+            "wordpress",
+            &[(
+                "WORDPRESS_CONFIG_EXTRA",
+                config_extra(spec.debug, &spec.config_constants),
+            )],
+        )?;
+    } else if !spec.config_constants.is_empty() {
+        bail!("wp-config constants need a WordPress site");
+    }
+    let wordpress = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "wordpress")
+        .context("deployment has no wordpress container")?;
+    // Explicit variables would shadow the secret's, so a name kwpm sets can't be taken.
+    if let Some(var) = wordpress
+        .env
+        .iter()
+        .flatten()
+        .find(|var| spec.env.contains_key(&var.name))
+    {
+        bail!("{} is managed by kwpm", var.name);
+    }
+    let env_from = wordpress.env_from.get_or_insert_with(Vec::new);
+    let loads_secret = |source: &EnvFromSource| {
+        source
+            .secret_ref
+            .as_ref()
+            .is_some_and(|secret| secret.name.as_deref() == Some(SITE_ENV_SECRET))
+    };
+    if !env_from.iter().any(loads_secret) {
+        env_from.push(EnvFromSource {
+            secret_ref: Some(SecretEnvSource {
+                name: Some(SITE_ENV_SECRET.to_string()),
+                optional: Some(true),
+            }),
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+impl KwpmClient {
+    /// Replaces the site's custom environment variables and wp-config constants and waits for
+    /// the restarted pods. Values are sealed in the spec and kept in a secret.
+    pub async fn set_site_env(
+        &self,
+        site: &str,
+        env: BTreeMap<String, String>,
+        constants: BTreeMap<String, Value>,
+    ) -> Result<()> {
+        check_site_env(&env, &constants)?;
+        let ns_name = self.site_namespace(site);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let mut spec = self.get_site_spec(site).await?;
+        if !spec.app.uses_php() {
+            bail!("{} serves static files, which can't read variables", site);
+        }
+        spec.env = env;
+        spec.config_constants = constants;
+        let mut deployment = deployment_api.get("wordpress").await?;
+        apply_site_env(&mut deployment, &spec)?;
+        // Only names go to the policy, the values may be credentials.
+        self.check_policy(
+            "set_site_env",
+            Some(site),
+            &[json!({
+                "env": spec.env.keys().collect::<Vec<_>>(),
+                "constants": spec.config_constants.keys().collect::<Vec<_>>(),
+            })],
+        )
+        .await?;
+
+        self.save_site_spec(site, &spec).await?;
+        secret_api
+            .patch(
+                SITE_ENV_SECRET,
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&site_env_secret(&spec.env, &spec.config_constants))),
+            )
+            .await?;
+        let deployment = deployment_api
+            .replace("wordpress", &Default::default(), &deployment)
+            .await?;
+        self.record_site_revision(site, "set_site_env").await?;
+        let names: Vec<&str> = spec
+            .env
+            .keys()
+            .chain(spec.config_constants.keys())
+            .map(String::as_str)
+            .collect();
+        self.record_audit(site, "set_site_env", &names.join(", "))
+            .await?;
+        self.wait_for_rollout(site, &deployment, SITE_ENV_ROLLOUT_TIMEOUT)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> Deployment {
+        serde_yaml::from_str(include_str!(
+            "../../kubernetes/wordpress/wp-deployment.yaml"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_site_env() {
+        let env = |name: &str| BTreeMap::from([(name.to_string(), "value".to_string())]);
+        let constant = |name: &str, value: Value| BTreeMap::from([(name.to_string(), value)]);
+        assert!(check_site_env(
+            &env("STRIPE_API_KEY"),
+            &constant("WP_MEMORY_LIMIT", json!("256M"))
+        )
+        .is_ok());
+        assert!(check_site_env(&env("WORDPRESS_DB_HOST"), &BTreeMap::new()).is_err());
+        assert!(check_site_env(&env("1KEY"), &BTreeMap::new()).is_err());
+        assert!(check_site_env(&env("API-KEY"), &BTreeMap::new()).is_err());
+        assert!(check_site_env(&BTreeMap::new(), &constant("DB_PASSWORD", json!("x"))).is_err());
+        assert!(check_site_env(&BTreeMap::new(), &constant("FEATURES", json!(["a"]))).is_err());
+    }
+
+    #[test]
+    fn test_apply_site_env() {
+        let mut spec = SiteSpec {
+            env: BTreeMap::from([("STRIPE_API_KEY".to_string(), "sk_live_1".to_string())]),
+            config_constants: BTreeMap::from([
+                ("DISABLE_WP_CRON".to_string(), json!(true)),
+                ("WP_MEMORY_LIMIT".to_string(), json!("256M")),
+            ]),
+            debug: true,
+            ..Default::default()
+        };
+        let secret = site_env_secret(&spec.env, &spec.config_constants);
+        let data = secret.string_data.unwrap();
+        assert_eq!(data["STRIPE_API_KEY"], "sk_live_1");
+        assert_eq!(data["KWPM_CONSTANT_DISABLE_WP_CRON"], "true");
+        assert_eq!(data["KWPM_CONSTANT_WP_MEMORY_LIMIT"], "\"256M\"");
+
+        let mut deployment = deployment();
+        apply_site_env(&mut deployment, &spec).unwrap();
+        apply_site_env(&mut deployment, &spec).unwrap();
+        let template = &deployment.spec.as_ref().unwrap().template;
+        let hash = template
+            .metadata
+            .as_ref()
+            .unwrap()
+            .annotations
+            .as_ref()
+            .unwrap()[SITE_ENV_HASH_ANNOTATION]
+            .clone();
+        let wordpress = &template.spec.as_ref().unwrap().containers[0];
+        assert_eq!(wordpress.env_from.as_ref().unwrap().len(), 1);
+        let extra = wordpress
+            .env
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|e| e.name == "WORDPRESS_CONFIG_EXTRA")
+            .and_then(|e| e.value.clone())
+            .unwrap();
+        assert!(extra.contains(
+            "if (!defined('WP_MEMORY_LIMIT')) { define('WP_MEMORY_LIMIT', json_decode(getenv('KWPM_CONSTANT_WP_MEMORY_LIMIT'), true)); }"
+        ));
+        assert!(extra.contains("WP_DEBUG_LOG"));
+        assert!(!extra.contains("sk_live_1"));
+
+        spec.env
+            .insert("STRIPE_API_KEY".to_string(), "sk_live_2".to_string());
+        apply_site_env(&mut deployment, &spec).unwrap();
+        let template = deployment.spec.unwrap().template;
+        assert_ne!(
+            template.metadata.unwrap().annotations.unwrap()[SITE_ENV_HASH_ANNOTATION],
+            hash
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use k8s_openapi::api::core::v1::ObjectReference;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{bail, Result},
+    notify::Notification,
+    prometheus::ingress_selector,
+    KwpmClient,
+};
+
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Service level objectives of a site, measured at the ingress.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SloTarget {
+    /// Percentage of requests that must not fail with a 5xx status, e.g. `99.9`.
+    pub availability: f64,
+    /// Latency the `latency_percentile` of requests must stay below.
+    pub latency_ms: Option<u32>,
+    pub latency_percentile: f64,
+    /// Webhook receiving burn rate alerts as JSON, in addition to the Kubernetes events.
+    pub notify_url: Option<String>,
+}
+
+impl Default for SloTarget {
+    fn default() -> Self {
+        Self {
+            availability: 99.9,
+            latency_ms: None,
+            latency_percentile: 95.0,
+            notify_url: None,
+        }
+    }
+}
+
+impl SloTarget {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.availability > 0.0 && self.availability < 100.0) {
+            bail!("availability target must be between 0 and 100");
+        }
+        if !(self.latency_percentile > 0.0 && self.latency_percentile < 100.0) {
+            bail!("latency percentile must be between 0 and 100");
+        }
+        Ok(())
+    }
+
+    pub fn error_budget(&self) -> f64 {
+        1.0 - self.availability / 100.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertSeverity {
+    Page,
+    Ticket,
+}
+
+/// Multiwindow burn rate alert: fires when both windows consume the error budget at least
+/// `burn_rate` times faster than sustainable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BurnRateRule {
+    pub severity: AlertSeverity,
+    pub long_window: &'static str,
+    pub short_window: &'static str,
+    pub burn_rate: f64,
+}
+
+/// The rules recommended by the SRE workbook for a 30 day SLO period.
+pub const BURN_RATE_RULES: [BurnRateRule; 3] = [
+    BurnRateRule {
+        severity: AlertSeverity::Page,
+        long_window: "1h",
+        short_window: "5m",
+        burn_rate: 14.4,
+    },
+    BurnRateRule {
+        severity: AlertSeverity::Page,
+        long_window: "6h",
+        short_window: "30m",
+        burn_rate: 6.0,
+    },
+    BurnRateRule {
+        severity: AlertSeverity::Ticket,
+        long_window: "3d",
+        short_window: "6h",
+        burn_rate: 1.0,
+    },
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BurnRateAlert {
+    pub rule: BurnRateRule,
+    /// Burn rates over the long and short window.
+    pub burn_rates: (f64, f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloStatus {
+    pub site: String,
+    /// Ratio of 5xx responses over the last 30 days.
+    pub error_ratio: f64,
+    pub error_budget_remaining: f64,
+    pub latency_ms: Option<f64>,
+    pub latency_violated: bool,
+    pub alerts: Vec<BurnRateAlert>,
+}
+
+pub fn error_ratio_query(namespace: &str, window: &str) -> String {
+    format!(
+        "sum(rate(({})[{window}:])) / sum(rate(({})[{window}:]))",
+        ingress_selector(
+            "nginx_ingress_controller_requests",
+            namespace,
+            "status=~\"5..\""
+        ),
+        ingress_selector("nginx_ingress_controller_requests", namespace, ""),
+        window = window
+    )
+}
+
+pub fn latency_query(namespace: &str, percentile: f64, window: &str) -> String {
+    format!(
+        "histogram_quantile({}, sum by (le) (rate(({})[{}:]))) * 1000",
+        percentile / 100.0,
+        ingress_selector(
+            "nginx_ingress_controller_request_duration_seconds_bucket",
+            namespace,
+            ""
+        ),
+        window
+    )
+}
+
+/// Rules whose long and short window both burn the budget at least at the rule's rate.
+pub fn firing_alerts(target: &SloTarget, error_ratio: impl Fn(&str) -> f64) -> Vec<BurnRateAlert> {
+    let budget = target.error_budget();
+    BURN_RATE_RULES
+        .iter()
+        .filter_map(|rule| {
+            let long = error_ratio(rule.long_window) / budget;
+            let short = error_ratio(rule.short_window) / budget;
+            (long >= rule.burn_rate && short >= rule.burn_rate).then_some(BurnRateAlert {
+                rule: *rule,
+                burn_rates: (long, short),
+            })
+        })
+        .collect()
+}
+
+impl KwpmClient {
+    pub async fn set_slo(&self, site: &str, slo: Option<SloTarget>) -> Result<()> {
+        if let Some(slo) = &slo {
+            slo.validate()?;
+        }
+
+        let mut spec = self.get_site_spec(site).await?;
+        spec.slo = slo;
+        self.save_site_spec(site, &spec).await
+    }
+
+    /// Measures the site against the SLO in its spec.
+    pub async fn check_slo(&self, site: &str) -> Result<SloStatus> {
+        let target = self
+            .get_site_spec(site)
+            .await?
+            .slo
+            .with_context(|| format!("{} has no SLO", site))?;
+        let ns_name = self.site_namespace(site);
+        let now = Utc::now();
+
+        let mut windows: Vec<&str> = BURN_RATE_RULES
+            .iter()
+            .flat_map(|rule| [rule.long_window, rule.short_window])
+            .chain(["30d"])
+            .collect();
+        windows.sort();
+        windows.dedup();
+        let mut ratios = Vec::new();
+        for window in windows {
+            let ratio = self
+                .query_prometheus(&error_ratio_query(&ns_name, window), now)
+                .await?;
+            ratios.push((window, ratio));
+        }
+        let ratio = |window: &str| {
+            ratios
+                .iter()
+                .find(|(w, _)| *w == window)
+                .map(|(_, ratio)| *ratio)
+                .unwrap_or(0.0)
+        };
+
+        let latency_ms = match target.latency_ms {
+            Some(_) => Some(
+                self.query_prometheus(
+                    &latency_query(&ns_name, target.latency_percentile, "1h"),
+                    now,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+
+        Ok(SloStatus {
+            site: site.to_string(),
+            error_ratio: ratio("30d"),
+            error_budget_remaining: 1.0 - ratio("30d") / target.error_budget(),
+            latency_violated: matches!(
+                (latency_ms, target.latency_ms),
+                (Some(measured), Some(limit)) if measured > f64::from(limit)
+            ),
+            latency_ms,
+            alerts: firing_alerts(&target, ratio),
+        })
+    }
+
+    /// Like `check_slo`, but raises a `SloBurnRate` warning event for every firing alert, posts
+    /// them to the SLO's webhook and sends them to the client's notification channels.
+    pub async fn alert_on_slo(&self, site: &str) -> Result<SloStatus> {
+        let status = self.check_slo(site).await?;
+        let notify_url = self
+            .get_site_spec(site)
+            .await?
+            .slo
+            .and_then(|slo| slo.notify_url);
+
+        let mut messages: Vec<String> = status
+            .alerts
+            .iter()
+            .map(|alert| {
+                format!(
+                    "{:?}: error budget burning {:.1}x over {} and {:.1}x over {} (threshold {}x)",
+                    alert.rule.severity,
+                    alert.burn_rates.0,
+                    alert.rule.long_window,
+                    alert.burn_rates.1,
+                    alert.rule.short_window,
+                    alert.rule.burn_rate
+                )
+            })
+            .collect();
+        if let (true, Some(latency_ms)) = (status.latency_violated, status.latency_ms) {
+            messages.push(format!("latency target missed at {:.0}ms", latency_ms));
+        }
+
+        for message in &messages {
+            self.record_warning(
+                ObjectReference {
+                    api_version: Some("networking.k8s.io/v1".to_string()),
+                    kind: Some("Ingress".to_string()),
+                    name: Some("wordpress".to_string()),
+                    namespace: Some(self.site_namespace(site)),
+                    ..Default::default()
+                },
+                "SloBurnRate",
+                message.clone(),
+            )
+            .await?;
+        }
+
+        if messages.is_empty() {
+            return Ok(status);
+        }
+
+        self.notify(&Notification {
+            subject: format!("SLO alert for {}", site),
+            body: messages.join("\n"),
+            data: json!({ "site": site, "alerts": messages }),
+        })
+        .await?;
+
+        if let Some(url) = notify_url {
+            reqwest::Client::new()
+                .post(&url)
+                .timeout(NOTIFY_TIMEOUT)
+                .json(&json!({
+                    "site": site,
+                    "errorRatio": status.error_ratio,
+                    "errorBudgetRemaining": status.error_budget_remaining,
+                    "alerts": messages,
+                }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("SLO notification to {} failed", url))?;
+        }
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_firing_alerts() {
+        let target = SloTarget::default();
+
+        let alerts = firing_alerts(&target, |window| match window {
+            "1h" => 0.02,
+            "5m" => 0.03,
+            _ => 0.0001,
+        });
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule.severity, AlertSeverity::Page);
+        assert_eq!(alerts[0].rule.long_window, "1h");
+
+        let recovered = firing_alerts(&target, |window| match window {
+            "1h" => 0.02,
+            _ => 0.0,
+        });
+        assert!(recovered.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(SloTarget::default().validate().is_ok());
+        assert!(SloTarget {
+            availability: 100.0,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_latency_query() {
+        assert!(latency_query("kwpm-blog", 95.0, "1h").starts_with("histogram_quantile(0.95, "));
+    }
+}
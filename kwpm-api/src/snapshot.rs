@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use k8s_openapi::api::batch::v1::Job;
+use object_store::{path::Path, ObjectStore};
+
+use crate::{
+    backup::BackupStorage,
+    error::{bail, Result},
+    job::is_job_succeeded,
+    manifest::{job_pod_spec_mut, set_env},
+    KwpmClient,
+};
+
+const SNAPSHOT_DIR: &str = "snapshots";
+const SNAPSHOT_ID_FORMAT: &str = "%Y%m%d-%H%M%S";
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(600);
+const SNAPSHOT_RETENTION_HOURS: i64 = 72;
+
+/// Operations that get a database snapshot taken right before they touch a site.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RiskyOperation {
+    Upgrade,
+    PluginUpdate,
+    SearchReplace,
+    CodeDeploy,
+}
+
+impl RiskyOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskyOperation::Upgrade => "upgrade",
+            RiskyOperation::PluginUpdate => "plugin-update",
+            RiskyOperation::SearchReplace => "search-replace",
+            RiskyOperation::CodeDeploy => "code-deploy",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub site: String,
+    /// Backup id of the snapshot, usable with `restore_table`.
+    pub backup_id: String,
+    pub operation: RiskyOperation,
+    pub url: String,
+}
+
+fn snapshot_id(taken_at: DateTime<Utc>, operation: RiskyOperation) -> String {
+    format!(
+        "{}/{}-{}",
+        SNAPSHOT_DIR,
+        taken_at.format(SNAPSHOT_ID_FORMAT),
+        operation.as_str()
+    )
+}
+
+fn snapshot_taken_at(name: &str) -> Option<DateTime<Utc>> {
+    let (date, rest) = name.split_once('-')?;
+    let time = rest.split('-').next()?;
+    NaiveDateTime::parse_from_str(&format!("{}-{}", date, time), SNAPSHOT_ID_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// Returns the snapshot names older than the retention window; unparseable names are kept.
+pub fn expired_snapshots(names: &[String], now: DateTime<Utc>) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| {
+            snapshot_taken_at(name)
+                .is_some_and(|t| (now - t).num_hours() >= SNAPSHOT_RETENTION_HOURS)
+        })
+        .cloned()
+        .collect()
+}
+
+pub fn snapshot_job(
+    site: &str,
+    backup_id: &str,
+    operation: RiskyOperation,
+    storage: &BackupStorage,
+) -> Result<Job> {
+    let mut job: Job =
+        serde_yaml::from_str(include_str!("../../kubernetes/backup/snapshot-job.yaml"))?;
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .extend([
+            ("kwpm.io/site".to_string(), site.to_string()),
+            (
+                "kwpm.io/snapshot-operation".to_string(),
+                operation.as_str().to_string(),
+            ),
+        ]);
+
+    set_env(
+        job_pod_spec_mut(&mut job)?,
+        "upload",
+        &[("S3_URL", storage.backup_url(site, backup_id))],
+    )?;
+
+    Ok(job)
+}
+
+pub async fn list_snapshot_names(
+    store: &impl ObjectStore,
+    snapshots: &Path,
+) -> Result<Vec<String>> {
+    let listing = store.list_with_delimiter(Some(snapshots)).await?;
+
+    Ok(listing
+        .common_prefixes
+        .iter()
+        .filter_map(|prefix| prefix.filename().map(str::to_string))
+        .collect())
+}
+
+async fn delete_prefix(store: &impl ObjectStore, prefix: &Path) -> Result<()> {
+    let objects: Vec<_> = store.list(Some(prefix)).try_collect().await?;
+    for object in objects {
+        store.delete(&object.location).await?;
+    }
+    Ok(())
+}
+
+impl KwpmClient {
+    /// Takes a database snapshot of the site and waits for it to be uploaded, pruning snapshots
+    /// that fell out of the retention window.
+    pub async fn snapshot_before(&self, site: &str, operation: RiskyOperation) -> Result<Snapshot> {
+        self.ensure_writable("snapshot_before")?;
+        let storage = self.backup_storage()?;
+        let store = storage.object_store()?;
+        let snapshots = storage.backup_path(site, SNAPSHOT_DIR);
+
+        let names = list_snapshot_names(&store, &snapshots).await?;
+        for name in expired_snapshots(&names, Utc::now()) {
+            delete_prefix(&store, &snapshots.child(name)).await?;
+        }
+
+        let backup_id = snapshot_id(Utc::now(), operation);
+        let job = snapshot_job(site, &backup_id, operation, storage)?;
+        let job = self.create_backup_job(site, &job).await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+
+        let job = self
+            .wait_for_job(&self.site_namespace(site), &job_name, SNAPSHOT_TIMEOUT)
+            .await?;
+        if !is_job_succeeded(&job) {
+            bail!(
+                "snapshot job {} for {} failed, not starting {}",
+                job_name,
+                site,
+                operation.as_str()
+            );
+        }
+
+        Ok(Snapshot {
+            site: site.to_string(),
+            url: storage.backup_url(site, &backup_id),
+            backup_id,
+            operation,
+        })
+    }
+
+    /// Like `snapshot_before`, but a no-op when no backup storage is configured.
+    pub(crate) async fn snapshot_if_configured(
+        &self,
+        site: &str,
+        operation: RiskyOperation,
+    ) -> Result<Option<Snapshot>> {
+        if self.backup_storage.is_none() {
+            return Ok(None);
+        }
+        self.snapshot_before(site, operation).await.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_id() {
+        let taken_at = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(
+            snapshot_id(taken_at, RiskyOperation::SearchReplace),
+            "snapshots/20240102-030405-search-replace"
+        );
+    }
+
+    #[test]
+    fn test_expired_snapshots() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 0, 0, 0).unwrap();
+        let names = vec![
+            "20240101-000000-upgrade".to_string(),
+            "20240109-120000-plugin-update".to_string(),
+            "manual".to_string(),
+        ];
+
+        assert_eq!(expired_snapshots(&names, now), ["20240101-000000-upgrade"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_snapshot_names() {
+        let store = InMemory::new();
+        let snapshots = Path::from("kwpm/blog/snapshots");
+        for name in ["20240101-000000-upgrade", "20240102-000000-upgrade"] {
+            store
+                .put(
+                    &snapshots.child(name).child("database.sql.gz"),
+                    "dump".into(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let names = list_snapshot_names(&store, &snapshots).await.unwrap();
+        assert_eq!(
+            names,
+            ["20240101-000000-upgrade", "20240102-000000-upgrade"]
+        );
+    }
+}
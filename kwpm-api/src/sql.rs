@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{
+    error::{KwpmError, Result},
+    manifest::{job_pod_spec_mut, set_env},
+    DatabaseAction, KwpmClient,
+};
+
+pub const DEFAULT_MAX_ROWS: usize = 100;
+pub const MAX_ROWS: usize = 1000;
+const MAX_QUERY_LEN: usize = 10_000;
+/// How long MariaDB lets a query run.
+const QUERY_TIMEOUT_SECS: u64 = 30;
+const QUERY_JOB_TIMEOUT: Duration = Duration::from_secs(120);
+const RESULT_MARKER: &str = "--- result ---";
+
+/// Statements support staff may run, all of them reading only.
+const READ_STATEMENTS: [&str; 6] = ["SELECT", "WITH", "SHOW", "DESCRIBE", "DESC", "EXPLAIN"];
+
+/// Checks that `query` is a single reading statement and returns it without a trailing `;`.
+/// The read-only database user enforces this anyway; rejecting other queries up front gives a
+/// clearer error. `;` can't appear in string literals either.
+pub fn validate_read_query(query: &str) -> Result<&str> {
+    let query = query.trim().trim_end_matches(';').trim_end();
+    if query.is_empty() {
+        return Err(KwpmError::invalid_input("the query is empty"));
+    }
+    if query.len() > MAX_QUERY_LEN {
+        return Err(KwpmError::invalid_input(format!(
+            "the query is longer than {} characters",
+            MAX_QUERY_LEN
+        )));
+    }
+    if query.contains(';') {
+        return Err(KwpmError::invalid_input(
+            "only a single statement can be run",
+        ));
+    }
+    let statement = query
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if !READ_STATEMENTS.contains(&statement.as_str()) {
+        return Err(KwpmError::invalid_input(format!(
+            "only {} statements can be run",
+            READ_STATEMENTS.join(", ")
+        )));
+    }
+    Ok(query)
+}
+
+/// Reverses the escaping of `mysql --batch`.
+fn unescape_field(field: &str) -> Option<String> {
+    if field == "NULL" {
+        return None;
+    }
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => value.push('\t'),
+            Some('n') => value.push('\n'),
+            Some('0') => value.push('\0'),
+            Some(c) => value.push(c),
+            None => value.push('\\'),
+        }
+    }
+    Some(value)
+}
+
+/// The rows a query returned, `NULL` values being `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+    /// Whether the query returned more than the requested rows.
+    pub truncated: bool,
+}
+
+impl QueryResult {
+    /// Reads the output of a `query` database job, which asked for `max_rows + 1` rows to tell
+    /// whether there are more.
+    pub fn from_output(output: &str, max_rows: usize) -> Result<Self> {
+        let (_, result) = output
+            .split_once(&format!("{}\n", RESULT_MARKER))
+            .ok_or_else(|| KwpmError::invalid_manifest("the query job printed no result"))?;
+        let mut lines = result.lines();
+        let Some(header) = lines.next() else {
+            // Statements without a result set print nothing.
+            return Ok(Self::default());
+        };
+
+        let columns = header.split('\t').map(str::to_string).collect();
+        let mut rows: Vec<Vec<Option<String>>> = lines
+            .map(|line| line.split('\t').map(unescape_field).collect())
+            .collect();
+        let truncated = rows.len() > max_rows;
+        rows.truncate(max_rows);
+
+        Ok(Self {
+            columns,
+            rows,
+            truncated,
+        })
+    }
+}
+
+impl KwpmClient {
+    /// Runs a reading query on the site's database as a user that can only `SELECT`, so
+    /// support staff can investigate content without the site's credentials. At most
+    /// `max_rows` rows, `DEFAULT_MAX_ROWS` if unset and up to `MAX_ROWS`, are returned, and
+    /// the query is cancelled after 30 seconds. Every query is recorded in the audit log.
+    pub async fn run_sql_query(
+        &self,
+        site: &str,
+        query: &str,
+        max_rows: Option<usize>,
+    ) -> Result<QueryResult> {
+        let query = validate_read_query(query)?;
+        let max_rows = max_rows.unwrap_or(DEFAULT_MAX_ROWS).clamp(1, MAX_ROWS);
+
+        let mut job = self.site_database_job(site, DatabaseAction::Query).await?;
+        set_env(
+            job_pod_spec_mut(&mut job)?,
+            "database",
+            &[
+                ("QUERY", query.to_string()),
+                ("MAX_ROWS", (max_rows + 1).to_string()),
+                ("QUERY_TIMEOUT", QUERY_TIMEOUT_SECS.to_string()),
+            ],
+        )?;
+        self.record_audit(site, "sql_query", query).await?;
+        let output = self
+            .run_site_job_on_mariadb(site, job, QUERY_JOB_TIMEOUT)
+            .await?;
+
+        QueryResult::from_output(&output, max_rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_read_query() {
+        assert_eq!(
+            validate_read_query("  select ID from wp_posts; ").unwrap(),
+            "select ID from wp_posts"
+        );
+        assert!(validate_read_query("SHOW TABLES").is_ok());
+        assert!(validate_read_query("with p as (select 1) select * from p").is_ok());
+        assert!(validate_read_query("").is_err());
+        assert!(validate_read_query("DELETE FROM wp_posts").is_err());
+        assert!(validate_read_query("SELECT 1; DROP TABLE wp_posts").is_err());
+        assert!(validate_read_query("SELECTED").is_err());
+    }
+
+    #[test]
+    fn test_query_result_from_output() {
+        let output = "--- result ---\nID\tpost_title\tpost_excerpt\n\
+                      1\tHello\\tworld\tNULL\n2\tSecond\\nline\t\n3\tThird\tx\n";
+        let result = QueryResult::from_output(output, 2).unwrap();
+        assert_eq!(result.columns, ["ID", "post_title", "post_excerpt"]);
+        assert_eq!(
+            result.rows,
+            [
+                vec![Some("1".into()), Some("Hello\tworld".into()), None],
+                vec![
+                    Some("2".into()),
+                    Some("Second\nline".into()),
+                    Some("".into())
+                ],
+            ]
+        );
+        assert!(result.truncated);
+
+        let empty = QueryResult::from_output("--- result ---\n", 10).unwrap();
+        assert_eq!(empty, QueryResult::default());
+        assert!(QueryResult::from_output("ERROR 1142", 10).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    core::v1::{Namespace, Pod, Secret},
+    networking::v1::Ingress,
+};
+use kube::{api::ListParams, Api, ResourceExt};
+use serde::Serialize;
+
+use crate::{
+    error::Result, mariadb::namespace_mariadb_instance, site::is_deployment_available,
+    storage::VolumeUsage, KwpmClient,
+};
+
+const STATUS_CONCURRENCY: usize = 8;
+const SITE_PVC_NAME: &str = "wp-pv-claim";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DatabaseState {
+    /// The site's app doesn't use a database.
+    None,
+    /// The MariaDB instance holding the database passes its readiness check.
+    Reachable,
+    Unreachable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TlsState {
+    /// The ingress serves plain HTTP only.
+    Disabled,
+    /// TLS is configured but its certificate secret doesn't exist yet, e.g. while cert-manager
+    /// is issuing it.
+    Pending,
+    Ready,
+}
+
+/// How a site is doing, as far as the cluster can tell without running anything in it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteStatus {
+    pub name: String,
+    pub namespace: String,
+    /// The host of the site's own ingress.
+    pub domain: Option<String>,
+    /// Every host the site's ingresses serve, e.g. also redirects and the health endpoint.
+    pub ingress_hosts: Vec<String>,
+    /// `Running` when every pod runs, otherwise the phase of a pod that doesn't.
+    pub pod_phase: Option<String>,
+    /// Whether the deployment has all its replicas available.
+    pub available: bool,
+    pub database: DatabaseState,
+    pub volume_capacity_bytes: Option<u64>,
+    /// Live usage of the volume, unknown while no pod mounts it.
+    pub volume_usage: Option<VolumeUsage>,
+    pub tls: TlsState,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// The phase summing up `pods`: the first phase other than `Running`, so a pending or failed
+/// pod isn't hidden by a running one.
+fn pods_phase(pods: &[Pod]) -> Option<String> {
+    let phases: Vec<&str> = pods
+        .iter()
+        .filter_map(|pod| pod.status.as_ref()?.phase.as_deref())
+        .collect();
+    phases
+        .iter()
+        .find(|phase| **phase != "Running")
+        .or(phases.first())
+        .map(|phase| phase.to_string())
+}
+
+fn ingress_hosts(ingresses: &[Ingress]) -> Vec<String> {
+    let mut hosts: Vec<String> = ingresses
+        .iter()
+        .filter_map(|ingress| ingress.spec.as_ref()?.rules.as_ref())
+        .flatten()
+        .filter_map(|rule| rule.host.clone())
+        .collect();
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+/// TLS is ready once every secret the ingresses name holds a certificate.
+fn tls_state(ingresses: &[Ingress], secrets: &[Secret]) -> TlsState {
+    let secret_names: Vec<&str> = ingresses
+        .iter()
+        .filter_map(|ingress| ingress.spec.as_ref()?.tls.as_ref())
+        .flatten()
+        .filter_map(|tls| tls.secret_name.as_deref())
+        .collect();
+    if secret_names.is_empty() {
+        return TlsState::Disabled;
+    }
+    let has_certificate = |name: &str| {
+        secrets.iter().any(|secret| {
+            secret.name_any() == name
+                && secret
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.get("tls.crt"))
+                    .is_some_and(|crt| !crt.0.is_empty())
+        })
+    };
+    if secret_names.into_iter().all(has_certificate) {
+        TlsState::Ready
+    } else {
+        TlsState::Pending
+    }
+}
+
+impl KwpmClient {
+    async fn site_status(
+        &self,
+        namespace: &Namespace,
+        database_ready: &BTreeMap<String, bool>,
+    ) -> Result<SiteStatus> {
+        let ns_name = namespace.name_any();
+        let name = self.site_name(&ns_name).unwrap_or_default().to_string();
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+        let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let pods = pod_api
+            .list(&ListParams::default().labels("app=wordpress"))
+            .await?
+            .items;
+        let ingresses = ingress_api.list(&Default::default()).await?.items;
+        let secrets = secret_api.list(&Default::default()).await?.items;
+        let available = deployment_api
+            .get_opt("wordpress")
+            .await?
+            .is_some_and(|deployment| is_deployment_available(&deployment));
+
+        let has_database = secrets.iter().any(|s| s.name_any() == "mysql-pass");
+        let database = if !has_database {
+            DatabaseState::None
+        } else if database_ready
+            .get(&namespace_mariadb_instance(namespace))
+            .copied()
+            .unwrap_or(false)
+        {
+            DatabaseState::Reachable
+        } else {
+            DatabaseState::Unreachable
+        };
+        let domain = ingresses
+            .iter()
+            .find(|ingress| ingress.name_any() == "wordpress-ingress")
+            .and_then(|ingress| ingress_hosts(std::slice::from_ref(ingress)).pop());
+
+        Ok(SiteStatus {
+            name,
+            domain,
+            ingress_hosts: ingress_hosts(&ingresses),
+            pod_phase: pods_phase(&pods),
+            available,
+            database,
+            volume_capacity_bytes: self
+                .pvc_capacity(&ns_name, SITE_PVC_NAME)
+                .await
+                .ok()
+                .flatten(),
+            // Reading usage needs access to the kubelet, which a listing shouldn't fail on.
+            volume_usage: self.pvc_usage(&ns_name, SITE_PVC_NAME).await.ok().flatten(),
+            tls: tls_state(&ingresses, &secrets),
+            created_at: namespace.creation_timestamp().map(|time| time.0),
+            namespace: ns_name,
+        })
+    }
+
+    /// The status of every site matching the label selector, sorted by name.
+    pub async fn list_sites(&self, selector: &str) -> Result<Vec<SiteStatus>> {
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        let namespaces: Vec<Namespace> = namespace_api
+            .list(&ListParams::default().labels(selector))
+            .await?
+            .items
+            .into_iter()
+            .filter(|ns| {
+                ns.metadata
+                    .name
+                    .as_deref()
+                    .and_then(|ns| self.site_name(ns))
+                    .is_some()
+            })
+            .collect();
+
+        let instances: BTreeSet<String> =
+            namespaces.iter().map(namespace_mariadb_instance).collect();
+        let mut database_ready = BTreeMap::new();
+        for instance in instances {
+            let ready = self.is_mariadb_instance_ready(&instance).await?;
+            database_ready.insert(instance, ready);
+        }
+
+        let database_ready = &database_ready;
+        let mut statuses: Vec<SiteStatus> = stream::iter(namespaces)
+            .map(|namespace| async move { self.site_status(&namespace, database_ready).await })
+            .buffer_unordered(STATUS_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(statuses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(phase: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({ "status": { "phase": phase } })).unwrap()
+    }
+
+    fn ingress(name: &str, host: &str, tls_secret: Option<&str>) -> Ingress {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name },
+            "spec": {
+                "rules": [{ "host": host }],
+                "tls": tls_secret.map(|secret| vec![serde_json::json!({
+                    "hosts": [host],
+                    "secretName": secret,
+                })]),
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pods_phase() {
+        assert_eq!(pods_phase(&[]), None);
+        assert_eq!(
+            pods_phase(&[pod("Running"), pod("Running")]).as_deref(),
+            Some("Running")
+        );
+        assert_eq!(
+            pods_phase(&[pod("Running"), pod("Pending")]).as_deref(),
+            Some("Pending")
+        );
+    }
+
+    #[test]
+    fn test_tls_state() {
+        let site = ingress("wordpress-ingress", "blog.example.com", Some("blog-tls"));
+        let redirect = ingress("redirects", "www.blog.example.com", None);
+        assert_eq!(
+            ingress_hosts(&[site.clone(), redirect.clone()]),
+            ["blog.example.com", "www.blog.example.com"]
+        );
+        assert_eq!(
+            tls_state(std::slice::from_ref(&redirect), &[]),
+            TlsState::Disabled
+        );
+        assert_eq!(tls_state(&[site.clone(), redirect], &[]), TlsState::Pending);
+
+        let certificate: Secret = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "blog-tls" },
+            "data": { "tls.crt": "Y2VydA==", "tls.key": "a2V5" },
+        }))
+        .unwrap();
+        assert_eq!(tls_state(&[site], &[certificate]), TlsState::Ready);
+    }
+}
@@ -0,0 +1,160 @@
+use k8s_openapi::api::core::v1::{PersistentVolumeClaim, Pod};
+use kube::Api;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, manifest::parse_quantity, KwpmClient};
+
+#[derive(Deserialize)]
+struct StatsSummary {
+    #[serde(default)]
+    pods: Vec<PodStats>,
+}
+
+#[derive(Deserialize)]
+struct PodStats {
+    #[serde(default)]
+    volume: Vec<VolumeStats>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VolumeStats {
+    pvc_ref: Option<PvcRef>,
+    capacity_bytes: Option<u64>,
+    used_bytes: Option<u64>,
+    available_bytes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PvcRef {
+    name: String,
+    namespace: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeUsage {
+    pub capacity_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Finds the usage of a PVC in a kubelet `/stats/summary` response.
+pub fn find_pvc_usage(
+    summary: &str,
+    namespace: &str,
+    pvc_name: &str,
+) -> Result<Option<VolumeUsage>> {
+    let summary: StatsSummary = serde_json::from_str(summary)?;
+
+    Ok(summary
+        .pods
+        .iter()
+        .flat_map(|pod| &pod.volume)
+        .find(|volume| {
+            volume
+                .pvc_ref
+                .as_ref()
+                .map(|r| r.name == pvc_name && r.namespace == namespace)
+                .unwrap_or(false)
+        })
+        .and_then(|volume| {
+            Some(VolumeUsage {
+                capacity_bytes: volume.capacity_bytes?,
+                used_bytes: volume.used_bytes?,
+                available_bytes: volume.available_bytes?,
+            })
+        }))
+}
+
+fn mounts_claim(pod: &Pod, pvc_name: &str) -> bool {
+    pod.spec
+        .as_ref()
+        .and_then(|spec| spec.volumes.as_ref())
+        .map(|volumes| {
+            volumes.iter().any(|v| {
+                v.persistent_volume_claim
+                    .as_ref()
+                    .map(|c| c.claim_name == pvc_name)
+                    .unwrap_or(false)
+            })
+        })
+        .unwrap_or(false)
+}
+
+impl KwpmClient {
+    /// Reads live filesystem usage of a PVC from the kubelet of a node running a pod that mounts
+    /// it. Returns `None` when no running pod mounts the claim.
+    pub async fn pvc_usage(&self, namespace: &str, pvc_name: &str) -> Result<Option<VolumeUsage>> {
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+
+        let pods = pod_api.list(&Default::default()).await?;
+        let Some(node_name) = pods
+            .items
+            .iter()
+            .filter(|pod| mounts_claim(pod, pvc_name))
+            .find_map(|pod| pod.spec.as_ref()?.node_name.clone())
+        else {
+            return Ok(None);
+        };
+
+        let request =
+            http::Request::get(format!("/api/v1/nodes/{}/proxy/stats/summary", node_name))
+                .body(vec![])?;
+        let summary = self.client.request_text(request).await?;
+
+        find_pvc_usage(&summary, namespace, pvc_name)
+    }
+
+    /// Capacity requested by a PVC, used when live usage is not available.
+    pub async fn pvc_capacity(&self, namespace: &str, pvc_name: &str) -> Result<Option<u64>> {
+        let pvc_api: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), namespace);
+
+        let pvc = pvc_api.get(pvc_name).await?;
+        let capacity = pvc
+            .status
+            .and_then(|status| status.capacity)
+            .and_then(|capacity| capacity.get("storage").cloned())
+            .or_else(|| {
+                pvc.spec
+                    .and_then(|spec| spec.resources)
+                    .and_then(|resources| resources.requests)
+                    .and_then(|requests| requests.get("storage").cloned())
+            });
+
+        Ok(capacity.and_then(|quantity| parse_quantity(&quantity.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_pvc_usage() {
+        let summary = r#"{
+            "node": {"nodeName": "node-1"},
+            "pods": [
+                {"podRef": {"name": "mariadb-0"}},
+                {"podRef": {"name": "wordpress-1"}, "volume": [
+                    {"name": "nginxconf"},
+                    {"name": "wordpress-persistent-storage", "capacityBytes": 3000, "usedBytes": 1000, "availableBytes": 2000,
+                     "pvcRef": {"name": "wp-pv-claim", "namespace": "kwpm-blog"}}
+                ]}
+            ]
+        }"#;
+
+        assert_eq!(
+            find_pvc_usage(summary, "kwpm-blog", "wp-pv-claim").unwrap(),
+            Some(VolumeUsage {
+                capacity_bytes: 3000,
+                used_bytes: 1000,
+                available_bytes: 2000,
+            })
+        );
+        assert_eq!(
+            find_pvc_usage(summary, "kwpm-shop", "wp-pv-claim").unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::Api;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    bundle::MANIFESTS,
+    error::{bail, KwpmError, Result},
+    KwpmClient,
+};
+
+/// Where customized manifest templates are read from, e.g. to pin another MariaDB image or
+/// change resource limits without rebuilding kwpm. Templates that aren't customized fall back
+/// to the ones embedded in the binary.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ManifestSource {
+    /// Only the embedded templates.
+    #[default]
+    Embedded,
+    /// Files laid out like `kubernetes/`, e.g. `<dir>/mariadb/mariadb-deployment.yaml`.
+    Directory(PathBuf),
+    /// Keys of a ConfigMap named like the template paths with `/` replaced by `.`, e.g.
+    /// `mariadb.mariadb-deployment.yaml`.
+    ConfigMap { namespace: String, name: String },
+}
+
+impl ManifestSource {
+    /// Reads a directory from `KWPM_MANIFEST_DIR` or a `<namespace>/<name>` ConfigMap from
+    /// `KWPM_MANIFEST_CONFIG_MAP`, using the embedded templates if neither is set.
+    pub fn from_env() -> Result<Self> {
+        let dir = std::env::var("KWPM_MANIFEST_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty());
+        let config_map = std::env::var("KWPM_MANIFEST_CONFIG_MAP")
+            .ok()
+            .filter(|config_map| !config_map.is_empty());
+
+        match (dir, config_map) {
+            (Some(_), Some(_)) => {
+                bail!("set either KWPM_MANIFEST_DIR or KWPM_MANIFEST_CONFIG_MAP, not both")
+            }
+            (Some(dir), None) => Ok(ManifestSource::Directory(dir.into())),
+            (None, Some(config_map)) => {
+                let (namespace, name) = config_map
+                    .split_once('/')
+                    .filter(|(namespace, name)| !namespace.is_empty() && !name.is_empty())
+                    .with_context(|| {
+                        format!(
+                            "KWPM_MANIFEST_CONFIG_MAP must be <namespace>/<name>, got {}",
+                            config_map
+                        )
+                    })?;
+                Ok(ManifestSource::ConfigMap {
+                    namespace: namespace.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            (None, None) => Ok(ManifestSource::Embedded),
+        }
+    }
+}
+
+/// The template at `path` below `kubernetes/` as built into kwpm.
+pub fn embedded_manifest(path: &str) -> Option<&'static str> {
+    MANIFESTS
+        .iter()
+        .find(|(template, _)| *template == path)
+        .map(|(_, manifest)| *manifest)
+}
+
+/// The ConfigMap key of the template at `path`, as keys can't contain `/`.
+pub fn config_map_key(path: &str) -> String {
+    path.replace('/', ".")
+}
+
+/// The customized template at `path` below `dir`, if there is one.
+fn read_manifest_file(dir: &Path, path: &str) -> Result<Option<String>> {
+    let file = dir.join(path);
+    match std::fs::read_to_string(&file) {
+        Ok(manifest) => Ok(Some(manifest)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow::Error::from(e)
+            .context(format!("reading {} failed", file.display()))
+            .into()),
+    }
+}
+
+impl KwpmClient {
+    pub fn with_manifest_source(mut self, source: ManifestSource) -> Self {
+        self.manifest_source = source;
+        self
+    }
+
+    /// The template at `path` below `kubernetes/`, customized by the manifest source or the
+    /// embedded one.
+    pub async fn manifest_template(&self, path: &str) -> Result<String> {
+        let embedded = embedded_manifest(path).ok_or_else(|| {
+            KwpmError::invalid_manifest(format!("unknown manifest template {}", path))
+        })?;
+        let customized = match &self.manifest_source {
+            ManifestSource::Embedded => None,
+            ManifestSource::Directory(dir) => read_manifest_file(dir, path)?,
+            ManifestSource::ConfigMap { namespace, name } => {
+                let config_map_api: Api<ConfigMap> =
+                    Api::namespaced(self.client.clone(), namespace);
+                config_map_api
+                    .get_opt(name)
+                    .await?
+                    .and_then(|config_map| config_map.data)
+                    .and_then(|mut data| data.remove(&config_map_key(path)))
+            }
+        };
+
+        Ok(customized.unwrap_or_else(|| embedded.to_string()))
+    }
+
+    /// `manifest_template` parsed as an object.
+    pub(crate) async fn load_manifest<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let manifest = self.manifest_template(path).await?;
+        Ok(serde_yaml::from_str(&manifest)
+            .with_context(|| format!("manifest template {} is invalid", path))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_manifest_file() {
+        let dir = std::env::temp_dir().join(format!("kwpm-manifests-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("mariadb")).unwrap();
+        std::fs::write(
+            dir.join("mariadb/mariadb-svc.yaml"),
+            "apiVersion: v1\nkind: Service\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_manifest_file(&dir, "mariadb/mariadb-svc.yaml")
+                .unwrap()
+                .as_deref(),
+            Some("apiVersion: v1\nkind: Service\n")
+        );
+        assert_eq!(
+            read_manifest_file(&dir, "mariadb/mariadb-pv.yaml").unwrap(),
+            None
+        );
+        assert!(embedded_manifest("mariadb/mariadb-pv.yaml").is_some());
+        assert!(embedded_manifest("mariadb/unknown.yaml").is_none());
+        assert_eq!(
+            config_map_key("mariadb/mariadb-deployment.yaml"),
+            "mariadb.mariadb-deployment.yaml"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
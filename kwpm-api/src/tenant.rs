@@ -0,0 +1,255 @@
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, ResourceExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    access::AdminAccess,
+    error::{bail, Result},
+    quota::{DatabaseUsage, PLAN_LABEL},
+    KwpmClient,
+};
+
+/// Namespace label naming the tenant owning a site, which tenant reports select sites by.
+pub const TENANT_LABEL: &str = "kwpm.io/tenant";
+/// Namespace annotation with the tenants that owned a site and since when, so usage can be
+/// billed to whoever owned the site at the time.
+const TENANT_HISTORY_ANNOTATION: &str = "kwpm.io/tenant-history";
+/// Oldest ownerships are dropped beyond this.
+const MAX_TENANT_HISTORY: usize = 50;
+
+/// Whether `tenant` is a valid label value.
+pub fn is_valid_tenant(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant.len() <= 63
+        && tenant.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && tenant.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// A tenant owning a site from `since` until the next ownership.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantOwnership {
+    pub tenant: Option<String>,
+    pub since: DateTime<Utc>,
+    pub plan: Option<String>,
+}
+
+/// Reads the ownership history annotation, ignoring a malformed one.
+pub fn parse_tenant_history(annotation: Option<&str>) -> Vec<TenantOwnership> {
+    annotation
+        .and_then(|history| serde_json::from_str(history).ok())
+        .unwrap_or_default()
+}
+
+/// The tenant owning the site at `time`, or `None` if it had none or the history doesn't reach
+/// back that far.
+pub fn tenant_at(history: &[TenantOwnership], time: DateTime<Utc>) -> Option<&str> {
+    history
+        .iter()
+        .filter(|ownership| ownership.since <= time)
+        .max_by_key(|ownership| ownership.since)
+        .and_then(|ownership| ownership.tenant.as_deref())
+}
+
+/// Appends the ownership starting at `now`, recording the current owner first when the history
+/// predates it.
+fn append_ownership(
+    mut history: Vec<TenantOwnership>,
+    current: TenantOwnership,
+    next: TenantOwnership,
+) -> Vec<TenantOwnership> {
+    if history.is_empty() {
+        history.push(current);
+    }
+    history.push(next);
+    let skip = history.len().saturating_sub(MAX_TENANT_HISTORY);
+    history.split_off(skip)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantTransfer {
+    pub tenant: String,
+    /// The plan of the site with the new tenant, its current one if unset.
+    #[serde(default)]
+    pub plan: Option<String>,
+    /// Restricts wp-admin to the new tenant's networks. The previous tenant's restriction is
+    /// lifted if unset.
+    #[serde(default)]
+    pub admin_access: Option<AdminAccess>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteTransfer {
+    pub site: String,
+    pub from: Option<String>,
+    pub to: String,
+    pub plan: Option<String>,
+    /// The database usage under the new plan's quota, for sites with a database.
+    pub database: Option<DatabaseUsage>,
+}
+
+impl KwpmClient {
+    /// Hands the site to another tenant. Its tenant label, plan and ownership history change in a
+    /// single update of its namespace, after its admin access was replaced, and its database
+    /// quota is applied under the new plan. The audit log of the site keeps the transfer.
+    pub async fn transfer_site(
+        &self,
+        site: &str,
+        transfer: &TenantTransfer,
+    ) -> Result<SiteTransfer> {
+        if !is_valid_tenant(&transfer.tenant) {
+            bail!("invalid tenant name: {}", transfer.tenant);
+        }
+        if let Some(access) = &transfer.admin_access {
+            access.validate()?;
+        }
+        let namespace = self.managed_site_namespace(site).await?;
+        let from = namespace.labels().get(TENANT_LABEL).cloned();
+        if from.as_deref() == Some(transfer.tenant.as_str()) {
+            bail!("{} already belongs to {}", site, transfer.tenant);
+        }
+        let current_plan = namespace.labels().get(PLAN_LABEL).cloned();
+        let plan = transfer.plan.clone().or(current_plan.clone());
+
+        let lock = self.lock_site(site, "transfer_site").await?;
+        let result: Result<_> = async {
+            self.check_policy(
+                "transfer_site",
+                Some(site),
+                &[json!({ "from": from, "to": transfer.tenant, "plan": plan })],
+            )
+            .await?;
+
+            self.set_admin_access(site, transfer.admin_access.clone())
+                .await?;
+
+            let now = Utc::now();
+            let history = append_ownership(
+                parse_tenant_history(
+                    namespace
+                        .annotations()
+                        .get(TENANT_HISTORY_ANNOTATION)
+                        .map(String::as_str),
+                ),
+                TenantOwnership {
+                    tenant: from.clone(),
+                    since: namespace
+                        .creation_timestamp()
+                        .map_or(now, |created| created.0),
+                    plan: current_plan.clone(),
+                },
+                TenantOwnership {
+                    tenant: Some(transfer.tenant.clone()),
+                    since: now,
+                    plan: plan.clone(),
+                },
+            );
+            let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+            namespace_api
+                .patch(
+                    &self.site_namespace(site),
+                    &PatchParams::default(),
+                    &Patch::Merge(json!({
+                        "metadata": {
+                            "labels": { TENANT_LABEL: transfer.tenant, PLAN_LABEL: plan },
+                            "annotations": {
+                                TENANT_HISTORY_ANNOTATION: serde_json::to_string(&history)?
+                            },
+                        }
+                    })),
+                )
+                .await?;
+
+            self.record_audit(
+                site,
+                "transfer_site",
+                &format!(
+                    "transferred from {} to {}{}",
+                    from.as_deref().unwrap_or("no tenant"),
+                    transfer.tenant,
+                    plan.as_ref()
+                        .map(|plan| format!(" on plan {}", plan))
+                        .unwrap_or_default()
+                ),
+            )
+            .await?;
+
+            let database = if self.get_site_spec(site).await?.app.uses_database() {
+                Some(self.enforce_database_quota_locked(site).await?)
+            } else {
+                None
+            };
+
+            Ok(SiteTransfer {
+                site: site.to_string(),
+                from,
+                to: transfer.tenant.clone(),
+                plan,
+                database,
+            })
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&format!("2024-01-01T{:02}:00:00Z", hour))
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn ownership(tenant: Option<&str>, hour: u32) -> TenantOwnership {
+        TenantOwnership {
+            tenant: tenant.map(str::to_string),
+            since: time(hour),
+            plan: None,
+        }
+    }
+
+    #[test]
+    fn test_is_valid_tenant() {
+        assert!(is_valid_tenant("acme"));
+        assert!(is_valid_tenant("Acme_Corp.2"));
+        assert!(!is_valid_tenant(""));
+        assert!(!is_valid_tenant("-acme"));
+        assert!(!is_valid_tenant("acme corp"));
+        assert!(!is_valid_tenant(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn test_tenant_history() {
+        let history = append_ownership(
+            parse_tenant_history(None),
+            ownership(None, 1),
+            ownership(Some("acme"), 5),
+        );
+        assert_eq!(history.len(), 2);
+        let history = append_ownership(
+            parse_tenant_history(Some(&serde_json::to_string(&history).unwrap())),
+            ownership(Some("acme"), 1),
+            ownership(Some("globex"), 9),
+        );
+        assert_eq!(history.len(), 3);
+
+        assert_eq!(tenant_at(&history, time(0)), None);
+        assert_eq!(tenant_at(&history, time(4)), None);
+        assert_eq!(tenant_at(&history, time(5)), Some("acme"));
+        assert_eq!(tenant_at(&history, time(10)), Some("globex"));
+        assert!(parse_tenant_history(Some("not json")).is_empty());
+    }
+}
@@ -0,0 +1,362 @@
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{Event, Namespace};
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind},
+    Api, ResourceExt,
+};
+use serde::Serialize;
+
+use crate::{
+    audit::AuditEntry,
+    backup::StoredBackup,
+    error::Result,
+    progress::{OperationRecord, OperationStatus},
+    KwpmClient,
+};
+
+/// The certificate a cert-manager CertificateRequest was created for.
+const CERTIFICATE_NAME_ANNOTATION: &str = "cert-manager.io/certificate-name";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TimelineSource {
+    /// A long-running kwpm operation, see `list_operations`.
+    Operation,
+    /// Any other change kwpm made, from the audit log.
+    Audit,
+    Upgrade,
+    /// A Kubernetes event of an object in the site namespace.
+    Event,
+    Backup,
+    /// A certificate cert-manager issued or renewed for the site.
+    Certificate,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineEntry {
+    pub time: DateTime<Utc>,
+    pub source: TimelineSource,
+    /// The operation, audit action or event reason.
+    pub kind: String,
+    pub message: String,
+    /// Failed operations, warning events and failed certificate requests.
+    pub warning: bool,
+}
+
+pub fn operation_entry(operation: &OperationRecord) -> TimelineEntry {
+    let message = match operation.status {
+        OperationStatus::Running => match operation.events.last() {
+            Some(event) => format!("running: {}", event.step),
+            None => "running".to_string(),
+        },
+        OperationStatus::Succeeded => match operation.finished_at {
+            Some(finished_at) => format!(
+                "succeeded after {}s",
+                (finished_at - operation.started_at).num_seconds()
+            ),
+            None => "succeeded".to_string(),
+        },
+        OperationStatus::Failed => format!(
+            "failed: {}",
+            operation.error.as_deref().unwrap_or("unknown error")
+        ),
+    };
+    TimelineEntry {
+        time: operation.started_at,
+        source: TimelineSource::Operation,
+        kind: operation.operation.clone(),
+        message,
+        warning: operation.status == OperationStatus::Failed,
+    }
+}
+
+pub fn audit_entry(entry: &AuditEntry) -> TimelineEntry {
+    TimelineEntry {
+        time: entry.time,
+        source: match entry.action.as_str() {
+            "upgrade_site" => TimelineSource::Upgrade,
+            _ => TimelineSource::Audit,
+        },
+        kind: entry.action.clone(),
+        message: entry.message.clone(),
+        warning: false,
+    }
+}
+
+/// Events are aggregated, so they are placed at their last occurrence.
+pub fn event_entry(event: &Event) -> Option<TimelineEntry> {
+    let time = event
+        .last_timestamp
+        .as_ref()
+        .map(|time| time.0)
+        .or_else(|| event.event_time.as_ref().map(|time| time.0))
+        .or_else(|| event.first_timestamp.as_ref().map(|time| time.0))
+        .or_else(|| {
+            event
+                .metadata
+                .creation_timestamp
+                .as_ref()
+                .map(|time| time.0)
+        })?;
+    let object = &event.involved_object;
+    let mut message = format!(
+        "{}/{}: {}",
+        object.kind.as_deref().unwrap_or_default(),
+        object.name.as_deref().unwrap_or_default(),
+        event.message.as_deref().unwrap_or_default().trim()
+    );
+    if let Some(count) = event.count.filter(|count| *count > 1) {
+        message.push_str(&format!(" (x{})", count));
+    }
+    Some(TimelineEntry {
+        time,
+        source: TimelineSource::Event,
+        kind: event.reason.clone().unwrap_or_default(),
+        message,
+        warning: event.type_.as_deref() == Some("Warning"),
+    })
+}
+
+pub fn backup_entry(backup: &StoredBackup) -> TimelineEntry {
+    TimelineEntry {
+        time: backup.taken_at,
+        source: TimelineSource::Backup,
+        kind: "backup".to_string(),
+        message: format!("backup {} taken", backup.id),
+        warning: false,
+    }
+}
+
+/// A cert-manager CertificateRequest, one of which is created per issuance and renewal.
+pub fn certificate_request_entry(request: &DynamicObject) -> Option<TimelineEntry> {
+    let time = request.metadata.creation_timestamp.as_ref()?.0;
+    let certificate = request
+        .annotations()
+        .get(CERTIFICATE_NAME_ANNOTATION)
+        .cloned()
+        .unwrap_or_else(|| request.name_any());
+    let ready = request.data["status"]["conditions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|condition| condition["type"] == "Ready");
+    let (kind, message, warning) = match ready {
+        Some(condition) if condition["status"] == "True" => (
+            "issued",
+            format!("certificate {} issued", certificate),
+            false,
+        ),
+        Some(condition) if condition["reason"] == "Failed" || condition["reason"] == "Denied" => (
+            "failed",
+            format!(
+                "issuing certificate {} failed: {}",
+                certificate,
+                condition["message"].as_str().unwrap_or_default()
+            ),
+            true,
+        ),
+        _ => (
+            "requested",
+            format!("certificate {} requested", certificate),
+            false,
+        ),
+    };
+    Some(TimelineEntry {
+        time,
+        source: TimelineSource::Certificate,
+        kind: kind.to_string(),
+        message,
+        warning,
+    })
+}
+
+/// Newest first, leaving out entries before `since`.
+pub fn merge_timeline(
+    mut entries: Vec<TimelineEntry>,
+    since: Option<DateTime<Utc>>,
+) -> Vec<TimelineEntry> {
+    entries.retain(|entry| since.is_none_or(|since| entry.time >= since));
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.time));
+    entries
+}
+
+impl KwpmClient {
+    /// The CertificateRequests in the site namespace, none if cert-manager isn't installed.
+    async fn certificate_requests(&self, site: &str) -> Result<Vec<DynamicObject>> {
+        let resource = ApiResource::from_gvk(&GroupVersionKind::gvk(
+            "cert-manager.io",
+            "v1",
+            "CertificateRequest",
+        ));
+        let api: Api<DynamicObject> =
+            Api::namespaced_with(self.client.clone(), &self.site_namespace(site), &resource);
+        match api.list(&Default::default()).await {
+            Ok(requests) => Ok(requests.items),
+            Err(kube::Error::Api(e)) if e.code == 404 => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// What happened to a site, newest first: kwpm's operations and audited changes, the
+    /// Kubernetes events in its namespace, its backups and its certificate issuances.
+    /// Kubernetes keeps events for an hour by default. Backups are only listed with backup
+    /// storage configured, and sources outside the cluster that fail are left out.
+    pub async fn site_timeline(
+        &self,
+        site: &str,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<TimelineEntry>> {
+        let ns_name = self.site_namespace(site);
+        let namespace_api: Api<Namespace> = Api::all(self.client.clone());
+        namespace_api.get(&ns_name).await?;
+        let event_api: Api<Event> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let mut entries: Vec<TimelineEntry> = self
+            .list_operations(site)
+            .await?
+            .iter()
+            .map(operation_entry)
+            .collect();
+        entries.extend(self.get_audit_log(site).await?.iter().map(audit_entry));
+        entries.extend(
+            event_api
+                .list(&Default::default())
+                .await?
+                .iter()
+                .filter_map(event_entry),
+        );
+        entries.extend(
+            self.certificate_requests(site)
+                .await?
+                .iter()
+                .filter_map(certificate_request_entry),
+        );
+        if self.backup_storage.is_some() {
+            match self.list_backups(site).await {
+                Ok(backups) => entries.extend(backups.iter().map(backup_entry)),
+                Err(e) => {
+                    tracing::warn!(site = %site, error = %format!("{:#}", e), "listing backups failed")
+                }
+            }
+        }
+
+        Ok(merge_timeline(entries, since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use serde_json::json;
+
+    use super::*;
+
+    fn time(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_operation_entry() {
+        let mut operation = OperationRecord {
+            id: "upgrade-site-1".to_string(),
+            site: "blog".to_string(),
+            operation: "upgrade_site".to_string(),
+            status: OperationStatus::Succeeded,
+            started_at: time(1),
+            finished_at: Some(time(3)),
+            error: None,
+            events: Vec::new(),
+        };
+        let entry = operation_entry(&operation);
+        assert_eq!(entry.message, "succeeded after 120s");
+        assert!(!entry.warning);
+
+        operation.status = OperationStatus::Failed;
+        operation.error = Some("image not found".to_string());
+        let entry = operation_entry(&operation);
+        assert_eq!(entry.message, "failed: image not found");
+        assert!(entry.warning);
+    }
+
+    #[test]
+    fn test_event_entry() {
+        let event: Event = serde_json::from_value(json!({
+            "metadata": { "name": "wordpress-abc.1" },
+            "involvedObject": { "kind": "Pod", "name": "wordpress-abc" },
+            "reason": "BackOff",
+            "message": "Back-off restarting failed container",
+            "type": "Warning",
+            "count": 4,
+            "firstTimestamp": "2024-01-01T00:01:00Z",
+            "lastTimestamp": "2024-01-01T00:05:00Z",
+        }))
+        .unwrap();
+        let entry = event_entry(&event).unwrap();
+        assert_eq!(entry.time, time(5));
+        assert_eq!(entry.kind, "BackOff");
+        assert_eq!(
+            entry.message,
+            "Pod/wordpress-abc: Back-off restarting failed container (x4)"
+        );
+        assert!(entry.warning);
+    }
+
+    #[test]
+    fn test_certificate_request_entry() {
+        let request = |status: &str, reason: &str| {
+            let mut request: DynamicObject = serde_json::from_value(json!({
+                "apiVersion": "cert-manager.io/v1",
+                "kind": "CertificateRequest",
+                "metadata": {
+                    "name": "blog-tls-1",
+                    "annotations": { CERTIFICATE_NAME_ANNOTATION: "blog-tls" },
+                },
+                "status": { "conditions": [
+                    { "type": "Ready", "status": status, "reason": reason, "message": "rate limited" },
+                ] },
+            }))
+            .unwrap();
+            request.metadata.creation_timestamp = Some(Time(time(2)));
+            request
+        };
+        let issued = certificate_request_entry(&request("True", "Issued")).unwrap();
+        assert_eq!(issued.message, "certificate blog-tls issued");
+        let failed = certificate_request_entry(&request("False", "Failed")).unwrap();
+        assert_eq!(
+            failed.message,
+            "issuing certificate blog-tls failed: rate limited"
+        );
+        assert!(failed.warning);
+        let pending = certificate_request_entry(&request("False", "Pending")).unwrap();
+        assert_eq!(pending.kind, "requested");
+    }
+
+    #[test]
+    fn test_merge_timeline() {
+        let entries = vec![
+            backup_entry(&StoredBackup {
+                id: "20240101-000100".to_string(),
+                taken_at: time(1),
+            }),
+            audit_entry(&AuditEntry {
+                time: time(3),
+                action: "upgrade_site".to_string(),
+                message: "upgraded".to_string(),
+            }),
+            audit_entry(&AuditEntry {
+                time: time(2),
+                action: "set_site_env".to_string(),
+                message: "API_KEY".to_string(),
+            }),
+        ];
+        let timeline = merge_timeline(entries.clone(), None);
+        assert_eq!(
+            timeline.iter().map(|e| e.time).collect::<Vec<_>>(),
+            [time(3), time(2), time(1)]
+        );
+        assert_eq!(timeline[0].source, TimelineSource::Upgrade);
+        assert_eq!(merge_timeline(entries, Some(time(2))).len(), 2);
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    error::{bail, Result},
+    prometheus::ingress_selector,
+    KwpmClient,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SiteTraffic {
+    pub site: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub requests: u64,
+    /// Response bytes sent to clients, the figure bandwidth is billed by.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Query summing the increase of an ingress-nginx counter of the site over `seconds`.
+pub fn traffic_query(metric: &str, namespace: &str, seconds: i64) -> String {
+    format!(
+        "sum(increase(({})[{}s:]))",
+        ingress_selector(metric, namespace, ""),
+        seconds
+    )
+}
+
+impl KwpmClient {
+    /// Requests and bytes served for the site between `start` and `end`, from the ingress-nginx
+    /// metrics in the configured Prometheus.
+    pub async fn get_site_traffic(
+        &self,
+        site: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<SiteTraffic> {
+        let seconds = (end - start).num_seconds();
+        if seconds <= 0 {
+            bail!("traffic range must end after it starts");
+        }
+
+        let ns_name = self.site_namespace(site);
+        let total = |metric: &'static str| {
+            let query = traffic_query(metric, &ns_name, seconds);
+            async move { self.query_prometheus(&query, end).await }
+        };
+
+        Ok(SiteTraffic {
+            site: site.to_string(),
+            start,
+            end,
+            requests: total("nginx_ingress_controller_requests").await?.round() as u64,
+            bytes_sent: total("nginx_ingress_controller_response_size_sum")
+                .await?
+                .round() as u64,
+            bytes_received: total("nginx_ingress_controller_request_size_sum")
+                .await?
+                .round() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_query() {
+        let query = traffic_query("nginx_ingress_controller_requests", "kwpm-blog", 86400);
+
+        assert!(query.starts_with("sum(increase((nginx_ingress_controller_requests{"));
+        assert!(query.ends_with(")[86400s:]))"));
+    }
+}
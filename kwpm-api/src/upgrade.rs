@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::Pod};
+use kube::{
+    api::{ListParams, PostParams},
+    Api, ResourceExt,
+};
+use serde::Serialize;
+
+use crate::{
+    canary::set_wordpress_image,
+    error::{bail, KwpmError, Result},
+    manifest::deployment_pod_spec_mut,
+    restart::is_rollout_complete,
+    wp_cli::{attach_wp_cli, WP_CLI_PATH},
+    KwpmClient,
+};
+
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(600);
+const UPGRADE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const UPDATE_DB_TIMEOUT: Duration = Duration::from_secs(300);
+/// Reasons a container of a new pod waits with that won't resolve by waiting longer.
+const FAILED_WAITING_REASONS: &[&str] = &[
+    "CrashLoopBackOff",
+    "ErrImagePull",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+];
+
+/// Docker image tags like `6.5-fpm-alpine`.
+pub fn is_valid_image_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// `image` with its tag or digest replaced by `tag`, keeping registry and repository.
+pub fn image_with_tag(image: &str, tag: &str) -> String {
+    let name = image.split_once('@').map_or(image, |(name, _)| name);
+    let name = match name.rsplit_once(':') {
+        Some((repository, current)) if !current.contains('/') => repository,
+        _ => name,
+    };
+    format!("{}:{}", name, tag)
+}
+
+pub(crate) fn container_image<'a>(deployment: &'a Deployment, container: &str) -> Option<&'a str> {
+    deployment
+        .spec
+        .as_ref()?
+        .template
+        .spec
+        .as_ref()?
+        .containers
+        .iter()
+        .find(|c| c.name == container)?
+        .image
+        .as_deref()
+}
+
+/// Why the pods running `image` in `container` won't become ready, if any of them is stuck.
+pub fn failed_rollout_reason(pods: &[Pod], container: &str, image: &str) -> Option<String> {
+    pods.iter()
+        .filter(|pod| {
+            pod.spec.as_ref().is_some_and(|spec| {
+                spec.containers
+                    .iter()
+                    .any(|c| c.name == container && c.image.as_deref() == Some(image))
+            })
+        })
+        .find_map(|pod| {
+            let status = pod.status.as_ref()?;
+            status
+                .init_container_statuses
+                .iter()
+                .flatten()
+                .chain(status.container_statuses.iter().flatten())
+                .find_map(|container| {
+                    let reason = container
+                        .state
+                        .as_ref()?
+                        .waiting
+                        .as_ref()?
+                        .reason
+                        .as_deref()?;
+                    FAILED_WAITING_REASONS.contains(&reason).then(|| {
+                        format!("{} of {} is in {}", container.name, pod.name_any(), reason)
+                    })
+                })
+        })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SiteUpgrade {
+    pub site: String,
+    pub previous_image: String,
+    pub image: String,
+    /// What `wp core update-db` printed.
+    pub database_update: String,
+}
+
+impl KwpmClient {
+    /// Waits until `deployment` rolled out, or returns why its new pods running `image` fail.
+    async fn wait_for_upgrade(
+        &self,
+        site: &str,
+        deployment: &Deployment,
+        image: &str,
+    ) -> Result<Option<String>> {
+        let ns_name = self.site_namespace(site);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns_name);
+        let generation = deployment.metadata.generation.unwrap_or(0);
+        let deadline = tokio::time::Instant::now() + UPGRADE_TIMEOUT;
+
+        loop {
+            if is_rollout_complete(&deployment_api.get("wordpress").await?, generation) {
+                return Ok(None);
+            }
+            let pods = pod_api
+                .list(&ListParams::default().labels("app=wordpress,tier=frontend"))
+                .await?;
+            if let Some(reason) = failed_rollout_reason(&pods.items, "wordpress", image) {
+                return Ok(Some(reason));
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Ok(Some(format!(
+                    "not rolled out within {} seconds",
+                    UPGRADE_TIMEOUT.as_secs()
+                )));
+            }
+            tokio::time::sleep(UPGRADE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Rolls the site's WordPress image over to `tag` and runs `wp core update-db` in a new
+    /// pod. If the new pods crash-loop or can't pull the image, the previous image is rolled
+    /// back and the upgrade fails. The database isn't touched before the rollout succeeded.
+    #[tracing::instrument(skip_all, fields(site = %site, tag = %tag))]
+    pub async fn upgrade_site(&self, site: &str, tag: &str) -> Result<SiteUpgrade> {
+        if !is_valid_image_tag(tag) {
+            return Err(KwpmError::invalid_input(format!(
+                "invalid image tag: {}",
+                tag
+            )));
+        }
+        if !self.stored_site_spec(site).await?.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        let deployment_api: Api<Deployment> =
+            Api::namespaced(self.client.clone(), &self.site_namespace(site));
+
+        let lock = self.lock_site(site, "upgrade_site").await?;
+        let result: Result<_> = async {
+            let mut deployment = deployment_api.get("wordpress").await?;
+            let previous_image = container_image(&deployment, "wordpress")
+                .context("deployment has no wordpress image")?
+                .to_string();
+            let image = image_with_tag(&previous_image, tag);
+            if image == previous_image {
+                bail!("{} runs {} already", site, image);
+            }
+            set_wordpress_image(&mut deployment, &image)?;
+            attach_wp_cli(deployment_pod_spec_mut(&mut deployment)?)?;
+            self.check_policy(
+                "upgrade_site",
+                Some(site),
+                &[serde_json::to_value(&deployment)?],
+            )
+            .await?;
+
+            let mut progress = self.start_operation(site, "upgrade_site").await;
+            progress
+                .step(format!("rolling out {}", image), Some(10))
+                .await;
+            let deployment = deployment_api
+                .replace("wordpress", &PostParams::default(), &deployment)
+                .await?;
+
+            if let Some(reason) = self.wait_for_upgrade(site, &deployment, &image).await? {
+                progress
+                    .step(format!("rolling back to {}", previous_image), Some(60))
+                    .await;
+                let mut deployment = deployment_api.get("wordpress").await?;
+                set_wordpress_image(&mut deployment, &previous_image)?;
+                let deployment = deployment_api
+                    .replace("wordpress", &PostParams::default(), &deployment)
+                    .await?;
+                self.wait_for_rollout(site, &deployment, UPGRADE_TIMEOUT)
+                    .await?;
+                self.record_audit(
+                    site,
+                    "upgrade_site",
+                    &format!("upgrade to {} rolled back: {}", image, reason),
+                )
+                .await?;
+                let error: KwpmError = anyhow::anyhow!(
+                    "upgrading {} to {} was rolled back: {}",
+                    site,
+                    image,
+                    reason
+                )
+                .into();
+                progress.fail(&error).await;
+                return Err(error);
+            }
+
+            progress.step("updating the database", Some(80)).await;
+            let output = match self
+                .exec_in_site(
+                    site,
+                    "wordpress",
+                    &[
+                        WP_CLI_PATH,
+                        "--allow-root",
+                        "--path=/var/www/html",
+                        "core",
+                        "update-db",
+                    ],
+                    UPDATE_DB_TIMEOUT,
+                )
+                .await
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    progress.fail(&e).await;
+                    return Err(e);
+                }
+            };
+            self.record_audit(
+                site,
+                "upgrade_site",
+                &format!("upgraded from {} to {}", previous_image, image),
+            )
+            .await?;
+            self.record_site_revision(site, "upgrade_site").await?;
+            progress.succeed().await;
+
+            Ok(SiteUpgrade {
+                site: site.to_string(),
+                previous_image,
+                image,
+                database_update: output.stdout.trim().to_string(),
+            })
+        }
+        .await;
+        lock.release_after(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{
+        Container, ContainerState, ContainerStateWaiting, ContainerStatus, PodSpec, PodStatus,
+    };
+
+    use super::*;
+
+    fn pod(image: &str, reason: Option<&str>) -> Pod {
+        let mut pod = Pod::default();
+        pod.metadata.name = Some("wordpress-abc".to_string());
+        pod.spec = Some(PodSpec {
+            containers: vec![Container {
+                name: "wordpress".to_string(),
+                image: Some(image.to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        pod.status = Some(PodStatus {
+            container_statuses: Some(vec![ContainerStatus {
+                name: "wordpress".to_string(),
+                state: Some(ContainerState {
+                    waiting: reason.map(|reason| ContainerStateWaiting {
+                        reason: Some(reason.to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        pod
+    }
+
+    #[test]
+    fn test_image_with_tag() {
+        assert!(is_valid_image_tag("6.5-fpm-alpine"));
+        assert!(!is_valid_image_tag("-fpm"));
+        assert!(!is_valid_image_tag("6.5 fpm"));
+        assert!(!is_valid_image_tag("latest@sha256:abc"));
+
+        assert_eq!(
+            image_with_tag("wordpress:6-fpm-alpine", "6.5-fpm-alpine"),
+            "wordpress:6.5-fpm-alpine"
+        );
+        assert_eq!(
+            image_with_tag("registry.example.com:5000/wp/wordpress", "6.5"),
+            "registry.example.com:5000/wp/wordpress:6.5"
+        );
+        assert_eq!(
+            image_with_tag("wordpress@sha256:0123", "6.5"),
+            "wordpress:6.5"
+        );
+    }
+
+    #[test]
+    fn test_failed_rollout_reason() {
+        let new = "wordpress:6.5-fpm-alpine";
+        assert_eq!(
+            failed_rollout_reason(&[pod(new, None)], "wordpress", new),
+            None
+        );
+        assert_eq!(
+            failed_rollout_reason(&[pod(new, Some("ContainerCreating"))], "wordpress", new),
+            None
+        );
+        assert_eq!(
+            failed_rollout_reason(
+                &[pod("wordpress:6-fpm-alpine", Some("CrashLoopBackOff"))],
+                "wordpress",
+                new
+            ),
+            None
+        );
+        assert_eq!(
+            failed_rollout_reason(&[pod(new, Some("CrashLoopBackOff"))], "wordpress", new)
+                .as_deref(),
+            Some("wordpress of wordpress-abc is in CrashLoopBackOff")
+        );
+        assert!(
+            failed_rollout_reason(&[pod(new, Some("ImagePullBackOff"))], "wordpress", new)
+                .is_some()
+        );
+        assert_eq!(
+            failed_rollout_reason(&[pod(new, Some("ImagePullBackOff"))], "mysql", new),
+            None
+        );
+    }
+}
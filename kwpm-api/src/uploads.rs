@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::ConfigMap, networking::v1::Ingress};
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use serde_json::json;
+
+use crate::{
+    error::{bail, Result},
+    KwpmClient,
+};
+
+const UPLOAD_LIMIT_ANNOTATION: &str = "kwpm.io/upload-limit-mb";
+
+pub fn uploads_ini_config(limit_mb: u32) -> Result<ConfigMap> {
+    let mut config_map: ConfigMap = serde_yaml::from_str(include_str!(
+        "../../kubernetes/wordpress/wp-uploads-ini-config.yaml"
+    ))?;
+
+    let uploads_ini = config_map
+        .data
+        .as_mut()
+        .and_then(|data| data.get_mut("uploads.ini"))
+        .context("uploads ini config has no uploads.ini")?;
+    *uploads_ini = uploads_ini
+        .lines()
+        .map(
+            |line| match line.split_once('=').map(|(key, _)| key.trim()) {
+                Some(key @ ("upload_max_filesize" | "post_max_size")) => {
+                    format!("{} = {}M", key, limit_mb)
+                }
+                _ => line.to_string(),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n";
+
+    Ok(config_map)
+}
+
+pub fn ingress_body_size_annotations(limit_mb: u32) -> BTreeMap<String, String> {
+    [
+        "nginx.org/client-max-body-size",
+        "nginx.ingress.kubernetes.io/proxy-body-size",
+    ]
+    .iter()
+    .map(|key| (key.to_string(), format!("{}m", limit_mb)))
+    .collect()
+}
+
+impl KwpmClient {
+    /// Sets PHP, nginx and ingress upload limits of a site from a single value, refusing limits
+    /// larger than the space left on the site's volume.
+    pub async fn set_upload_limit(&self, site: &str, limit_mb: u32) -> Result<()> {
+        if limit_mb == 0 {
+            bail!("upload limit must be at least 1 MB");
+        }
+
+        let ns_name = self.site_namespace(site);
+        let limit_bytes = u64::from(limit_mb) * 1024 * 1024;
+
+        let available_bytes = match self.pvc_usage(&ns_name, "wp-pv-claim").await? {
+            Some(usage) => Some(usage.available_bytes),
+            None => self.pvc_capacity(&ns_name, "wp-pv-claim").await?,
+        };
+        if let Some(available_bytes) = available_bytes {
+            if limit_bytes > available_bytes {
+                bail!(
+                    "upload limit of {} MB exceeds the {} MB left on the volume of {}",
+                    limit_mb,
+                    available_bytes / 1024 / 1024,
+                    site
+                );
+            }
+        }
+
+        let config_map_api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &ns_name);
+        let ingress_api: Api<Ingress> = Api::namespaced(self.client.clone(), &ns_name);
+        let deployment_api: Api<Deployment> = Api::namespaced(self.client.clone(), &ns_name);
+
+        let uploads_ini = uploads_ini_config(limit_mb)?;
+        self.check_policy(
+            "set_upload_limit",
+            Some(site),
+            &[serde_json::to_value(&uploads_ini)?],
+        )
+        .await?;
+
+        let mut spec = self.get_site_spec(site).await?;
+        spec.upload_limit_mb = Some(limit_mb);
+        self.save_site_spec(site, &spec).await?;
+
+        config_map_api
+            .patch(
+                "wp-uploads-ini-config",
+                &PatchParams::apply("kwpm").force(),
+                &Patch::Apply(&self.labeled(&uploads_ini)),
+            )
+            .await?;
+        self.apply_nginx_config(site, &spec).await?;
+
+        let annotations = json!({
+            "metadata": { "annotations": ingress_body_size_annotations(limit_mb) }
+        });
+        for ingress in ingress_api.list(&Default::default()).await? {
+            let name = ingress.metadata.name.unwrap_or_default();
+            ingress_api
+                .patch(&name, &Default::default(), &Patch::Merge(&annotations))
+                .await?;
+        }
+
+        // php-fpm only reads its ini files on start, so roll the pods when the limit changed.
+        let restart = json!({
+            "spec": { "template": { "metadata": { "annotations": {
+                UPLOAD_LIMIT_ANNOTATION: limit_mb.to_string()
+            } } } }
+        });
+        deployment_api
+            .patch("wordpress", &Default::default(), &Patch::Merge(&restart))
+            .await?;
+
+        self.record_site_revision(site, "set_upload_limit").await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uploads_ini_config() {
+        let config_map = uploads_ini_config(64).unwrap();
+        let uploads_ini = &config_map.data.unwrap()["uploads.ini"];
+
+        assert!(uploads_ini.contains("upload_max_filesize = 64M\n"));
+        assert!(uploads_ini.contains("post_max_size = 64M\n"));
+        assert!(uploads_ini.contains("memory_limit = 256M\n"));
+    }
+
+    #[test]
+    fn test_ingress_body_size_annotations() {
+        let annotations = ingress_body_size_annotations(64);
+
+        assert_eq!(
+            annotations["nginx.ingress.kubernetes.io/proxy-body-size"],
+            "64m"
+        );
+        assert_eq!(annotations["nginx.org/client-max-body-size"], "64m");
+    }
+}
@@ -0,0 +1,279 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    credentials::token_hash,
+    error::{bail, KwpmError, Result},
+    KwpmClient,
+};
+
+const MAGIC_LOGIN_PARAM: &str = "kwpm_login";
+/// Exchanges a one-time token for an auth cookie. Tokens are stored as transients keyed by
+/// their hash, so neither the database nor the job spec ever contains a usable token.
+const MAGIC_LOGIN_MU_PLUGIN: &str = r#"<?php
+/*
+ * Plugin Name: kwpm magic login
+ * Description: One-time login links issued by kwpm.
+ */
+add_action('init', function () {
+    if (empty($_GET['kwpm_login'])) {
+        return;
+    }
+    $key = 'kwpm_login_' . hash('sha256', (string) $_GET['kwpm_login']);
+    $user_id = get_transient($key);
+    delete_transient($key);
+    if (!$user_id) {
+        wp_die('This login link is invalid or has expired.', '', 403);
+    }
+    wp_set_auth_cookie((int) $user_id);
+    wp_safe_redirect(admin_url());
+    exit;
+});
+"#;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdminUser {
+    pub login: String,
+    pub email: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for AdminUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminUser")
+            .field("login", &self.login)
+            .field("email", &self.email)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MagicLoginLink {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Logins, emails and user ids are passed to WP-CLI and PHP verbatim, so they are restricted
+/// to characters WordPress allows in logins and must not look like options.
+pub fn is_valid_user(user: &str) -> bool {
+    !user.is_empty()
+        && !user.starts_with('-')
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.@-+".contains(c))
+}
+
+fn check_user(user: &str) -> Result<()> {
+    if !is_valid_user(user) {
+        return Err(KwpmError::invalid_input(format!("invalid user: {}", user)));
+    }
+    Ok(())
+}
+
+/// A random password for a new WordPress user. Hex, so it needs no quoting as a WP-CLI
+/// argument. WP-CLI's own `Password: ...` output can't be used, job logs are redacted.
+pub(crate) fn generate_user_password() -> Result<String> {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes).context("failed to generate a password")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The `wp user create` arguments for an administrator with `password`.
+fn admin_user_args(login: &str, email: &str, password: &str) -> Vec<String> {
+    vec![
+        "user".to_string(),
+        "create".to_string(),
+        login.to_string(),
+        email.to_string(),
+        "--role=administrator".to_string(),
+        format!("--user_pass={}", password),
+    ]
+}
+
+/// PHP installing the magic login mu-plugin and storing a token for `user` under its hash,
+/// printing the URL the token has to be appended to.
+pub fn magic_login_php(user: &str, token_hash: &str, ttl: Duration) -> String {
+    format!(
+        "$user = get_user_by(is_numeric('{user}') ? 'id' : 'login', '{user}') ?: get_user_by('email', '{user}');
+if (!$user) {{ WP_CLI::error('no such user'); }}
+wp_mkdir_p(WPMU_PLUGIN_DIR);
+file_put_contents(WPMU_PLUGIN_DIR . '/kwpm-magic-login.php', base64_decode('{plugin}'));
+set_transient('kwpm_login_{token_hash}', $user->ID, {ttl});
+echo home_url('/?{param}=');",
+        user = user,
+        plugin = STANDARD.encode(MAGIC_LOGIN_MU_PLUGIN),
+        token_hash = token_hash,
+        ttl = ttl.as_secs(),
+        param = MAGIC_LOGIN_PARAM,
+    )
+}
+
+impl KwpmClient {
+    /// Creates an administrator with a generated password.
+    pub async fn create_admin_user(
+        &self,
+        site: &str,
+        login: &str,
+        email: &str,
+    ) -> Result<AdminUser> {
+        check_user(login)?;
+        check_user(email)?;
+        self.check_policy(
+            "create_admin_user",
+            Some(site),
+            &[json!({ "login": login, "email": email })],
+        )
+        .await?;
+
+        let password = generate_user_password()?;
+        let args = admin_user_args(login, email, &password);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_wp_cli(site, &args).await?;
+        self.record_audit(site, "create_admin_user", login).await?;
+
+        Ok(AdminUser {
+            login: login.to_string(),
+            email: email.to_string(),
+            password,
+        })
+    }
+
+    /// Sets a new generated password for `user` without emailing it, returning the password.
+    pub async fn reset_user_password(&self, site: &str, user: &str) -> Result<String> {
+        check_user(user)?;
+        self.check_policy(
+            "reset_user_password",
+            Some(site),
+            &[json!({ "user": user })],
+        )
+        .await?;
+
+        let output = self
+            .run_wp_cli(
+                site,
+                &[
+                    "user",
+                    "reset-password",
+                    user,
+                    "--skip-email",
+                    "--porcelain",
+                ],
+            )
+            .await?;
+        let password = output.trim().to_string();
+        if password.is_empty() {
+            bail!("wp user reset-password printed no password");
+        }
+        self.record_audit(site, "reset_user_password", user).await?;
+
+        Ok(password)
+    }
+
+    /// Deletes `user`, handing their posts to `reassign` or deleting them with `None`.
+    pub async fn delete_user(&self, site: &str, user: &str, reassign: Option<&str>) -> Result<()> {
+        check_user(user)?;
+        let mut args = vec![
+            "user".to_string(),
+            "delete".to_string(),
+            user.to_string(),
+            "--yes".to_string(),
+        ];
+        if let Some(reassign) = reassign {
+            check_user(reassign)?;
+            args.push(format!("--reassign={}", reassign));
+        }
+        self.check_policy(
+            "delete_user",
+            Some(site),
+            &[json!({ "user": user, "reassign": reassign })],
+        )
+        .await?;
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_wp_cli(site, &args).await?;
+        self.record_audit(site, "delete_user", user).await?;
+
+        Ok(())
+    }
+
+    /// Issues a link that logs in as `user` once within `ttl`, for regaining access to a site
+    /// whose admins are locked out.
+    pub async fn magic_login_link(
+        &self,
+        site: &str,
+        user: &str,
+        ttl: Duration,
+    ) -> Result<MagicLoginLink> {
+        check_user(user)?;
+        self.check_policy("magic_login_link", Some(site), &[json!({ "user": user })])
+            .await?;
+
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).context("failed to generate a token")?;
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let expires_at = Utc::now() + chrono::Duration::from_std(ttl)?;
+
+        let output = self
+            .run_wp_cli(
+                site,
+                &["eval", &magic_login_php(user, &token_hash(&token), ttl)],
+            )
+            .await?;
+        let base = output.lines().last().unwrap_or_default().trim();
+        if base.is_empty() {
+            bail!("no login url for {} on {}", user, site);
+        }
+        self.record_audit(site, "magic_login_link", user).await?;
+
+        Ok(MagicLoginLink {
+            url: format!("{}{}", base, token),
+            expires_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::redact_credentials;
+
+    #[test]
+    fn test_is_valid_user() {
+        assert!(is_valid_user("admin"));
+        assert!(is_valid_user("ops+blog@example.com"));
+        assert!(is_valid_user("42"));
+        assert!(!is_valid_user("--role=administrator"));
+        assert!(!is_valid_user("admin'); drop"));
+        assert!(!is_valid_user(""));
+    }
+
+    #[test]
+    fn test_admin_user_args() {
+        let password = generate_user_password().unwrap();
+        let args = admin_user_args("admin", "ops@example.com", &password);
+
+        assert!(args.contains(&format!("--user_pass={}", password)));
+        // Failed jobs report their arguments, which must not leak the password.
+        assert!(!redact_credentials(&args.join(" ")).contains(&password));
+    }
+
+    #[test]
+    fn test_magic_login_php_stores_only_the_hash() {
+        let token = "0123456789abcdef";
+        let php = magic_login_php("admin", &token_hash(token), Duration::from_secs(900));
+
+        assert!(!php.contains(token));
+        assert!(php.contains(&format!(
+            "'kwpm_login_{}', $user->ID, 900",
+            token_hash(token)
+        )));
+        assert!(php.contains("home_url('/?kwpm_login=')"));
+    }
+}
@@ -0,0 +1,325 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::{stream::BoxStream, StreamExt};
+use k8s_openapi::api::{
+    batch::v1::Job,
+    core::v1::{Container, EmptyDirVolumeSource, Pod, PodSpec, Volume, VolumeMount},
+};
+use kube::{api::ListParams, Api};
+use serde_json::json;
+
+use crate::{
+    error::{bail, Result},
+    exec::{pick_exec_pod, ExecEvent},
+    job::is_job_succeeded,
+    KwpmClient,
+};
+
+const WP_CLI_TIMEOUT: Duration = Duration::from_secs(600);
+const WP_CLI_IMAGE: &str = "wordpress:cli-php8.2";
+const WP_CLI_VOLUME_NAME: &str = "wp-cli";
+const WP_CLI_DIR: &str = "/opt/kwpm/bin";
+/// WP-CLI in the WordPress container, see `attach_wp_cli`.
+pub(crate) const WP_CLI_PATH: &str = "/opt/kwpm/bin/wp";
+/// Parts of option names whose values stay out of the audit log and policy input.
+const SECRET_OPTION_PARTS: &[&str] = &["pass", "key", "secret", "token", "salt"];
+
+/// Copies WP-CLI from its image into the WordPress container through an init container, since
+/// the php-fpm images don't ship it, so commands can be exec'd in the site's pods.
+pub fn attach_wp_cli(pod_spec: &mut PodSpec) -> Result<()> {
+    let mount = VolumeMount {
+        name: WP_CLI_VOLUME_NAME.to_string(),
+        mount_path: WP_CLI_DIR.to_string(),
+        ..Default::default()
+    };
+    let volumes = pod_spec.volumes.get_or_insert_with(Vec::new);
+    if !volumes.iter().any(|v| v.name == WP_CLI_VOLUME_NAME) {
+        volumes.push(Volume {
+            name: WP_CLI_VOLUME_NAME.to_string(),
+            empty_dir: Some(EmptyDirVolumeSource::default()),
+            ..Default::default()
+        });
+    }
+    let init_containers = pod_spec.init_containers.get_or_insert_with(Vec::new);
+    if !init_containers.iter().any(|c| c.name == WP_CLI_VOLUME_NAME) {
+        init_containers.push(Container {
+            name: WP_CLI_VOLUME_NAME.to_string(),
+            image: Some(WP_CLI_IMAGE.to_string()),
+            command: Some(vec![
+                "cp".to_string(),
+                "/usr/local/bin/wp".to_string(),
+                WP_CLI_PATH.to_string(),
+            ]),
+            volume_mounts: Some(vec![mount.clone()]),
+            ..Default::default()
+        });
+    }
+
+    let wordpress = pod_spec
+        .containers
+        .iter_mut()
+        .find(|c| c.name == "wordpress")
+        .context("deployment has no wordpress container")?;
+    let mounts = wordpress.volume_mounts.get_or_insert_with(Vec::new);
+    if !mounts.iter().any(|m| m.name == WP_CLI_VOLUME_NAME) {
+        mounts.push(VolumeMount {
+            read_only: Some(true),
+            ..mount
+        });
+    }
+    Ok(())
+}
+
+/// `wp <args>` as run in the WordPress container, without a shell.
+pub fn wp_cli_command(args: &[String]) -> Vec<String> {
+    [WP_CLI_PATH, "--allow-root", "--path=/var/www/html"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .chain(args.iter().cloned())
+        .collect()
+}
+
+/// `args` with the values of options like `--user_pass=...` replaced by `***`.
+pub fn redact_wp_cli_args(args: &[String]) -> Vec<String> {
+    args.iter()
+        .map(
+            |arg| match arg.strip_prefix("--").and_then(|o| o.split_once('=')) {
+                Some((name, _))
+                    if SECRET_OPTION_PARTS
+                        .iter()
+                        .any(|part| name.to_lowercase().contains(part)) =>
+                {
+                    format!("--{}=***", name)
+                }
+                _ => arg.clone(),
+            },
+        )
+        .collect()
+}
+
+fn has_wp_cli(pod: &Pod) -> bool {
+    pod.spec.as_ref().is_some_and(|spec| {
+        spec.containers
+            .iter()
+            .filter(|c| c.name == "wordpress")
+            .flat_map(|c| c.volume_mounts.iter().flatten())
+            .any(|m| m.name == WP_CLI_VOLUME_NAME)
+    })
+}
+
+pub fn wp_cli_job(site: &str, args: &[&str]) -> Result<Job> {
+    let mut job: Job =
+        serde_yaml::from_str(include_str!("../../kubernetes/wp-cli/wp-cli-job.yaml"))?;
+
+    job.metadata
+        .labels
+        .get_or_insert_with(Default::default)
+        .insert("kwpm.io/site".to_string(), site.to_string());
+
+    let container = job
+        .spec
+        .as_mut()
+        .and_then(|spec| spec.template.spec.as_mut())
+        .and_then(|pod_spec| pod_spec.containers.first_mut())
+        .context("wp-cli job template has no container")?;
+    container.args = Some(args.iter().map(|arg| arg.to_string()).collect());
+
+    Ok(job)
+}
+
+impl KwpmClient {
+    pub async fn run_wp_cli(&self, site: &str, args: &[&str]) -> Result<String> {
+        if !self.stored_site_spec(site).await?.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        let ns_name = self.site_namespace(site);
+
+        let job = self.create_job(&ns_name, wp_cli_job(site, args)?).await?;
+        let job_name = job.metadata.name.unwrap_or_default();
+
+        let job = self
+            .wait_for_job(&ns_name, &job_name, WP_CLI_TIMEOUT)
+            .await?;
+        let output = self.job_logs(&ns_name, &job_name).await?;
+
+        if !is_job_succeeded(&job) {
+            bail!(
+                "wp {} failed on {}: {}",
+                self.redact(&args.join(" ")),
+                site,
+                output.trim()
+            );
+        }
+
+        Ok(output)
+    }
+
+    /// Runs `wp <args>` in a WordPress pod of the site, without checking the policy or
+    /// recording it.
+    async fn exec_wp_cli(
+        &self,
+        site: &str,
+        args: &[String],
+    ) -> Result<BoxStream<'static, Result<ExecEvent>>> {
+        let ns_name = self.site_namespace(site);
+        let pod_api: Api<Pod> = Api::namespaced(self.client.clone(), &ns_name);
+        let pods = pod_api
+            .list(&ListParams::default().labels("app=wordpress,tier=frontend"))
+            .await?;
+        let pod = pick_exec_pod(&pods.items)
+            .with_context(|| format!("{} has no ready pod to run commands in", site))?;
+        if !pods
+            .items
+            .iter()
+            .any(|p| p.metadata.name.as_ref() == Some(&pod) && has_wp_cli(p))
+        {
+            bail!(
+                "the pods of {} have no WP-CLI, upgrading the site adds it; run_wp_cli works without",
+                site
+            );
+        }
+        self.exec_stream(&ns_name, &pod, "wordpress", wp_cli_command(args))
+            .await
+    }
+
+    /// Runs `wp <args>` in a WordPress pod of the site through the exec subresource, streaming
+    /// stdout and stderr line by line as they're printed, then how it exited. Unlike
+    /// `run_wp_cli` no job is started, but the pods need WP-CLI, see `attach_wp_cli`.
+    #[tracing::instrument(skip_all, fields(site = %site))]
+    pub async fn wp_cli(
+        &self,
+        site: &str,
+        args: &[String],
+    ) -> Result<BoxStream<'static, Result<ExecEvent>>> {
+        if !self.stored_site_spec(site).await?.app.is_wordpress() {
+            bail!("{} is not a WordPress site", site);
+        }
+        let redacted = redact_wp_cli_args(args);
+        self.check_policy("wp_cli", Some(site), &[json!({ "args": redacted })])
+            .await?;
+
+        let events = self.exec_wp_cli(site, args).await?;
+        self.record_audit(site, "wp_cli", &redacted.join(" "))
+            .await?;
+        Ok(events)
+    }
+
+    /// Like `wp_cli`, returning what it printed to stdout and failing with its stderr.
+    pub async fn wp_cli_output(&self, site: &str, args: &[String]) -> Result<String> {
+        let events = self.wp_cli(site, args).await?;
+        collect_wp_cli_output(site, args, events).await
+    }
+
+    /// Like `wp_cli_output` for callers that checked the policy and record their own audit
+    /// entry, such as `install_plugin`.
+    pub(crate) async fn wp_cli_output_unaudited(
+        &self,
+        site: &str,
+        args: &[String],
+    ) -> Result<String> {
+        let events = self.exec_wp_cli(site, args).await?;
+        collect_wp_cli_output(site, args, events).await
+    }
+}
+
+async fn collect_wp_cli_output(
+    site: &str,
+    args: &[String],
+    mut events: BoxStream<'static, Result<ExecEvent>>,
+) -> Result<String> {
+    let (mut stdout, mut stderr) = (String::new(), String::new());
+    while let Some(event) = events.next().await {
+        match event? {
+            ExecEvent::Stdout(line) => {
+                stdout.push_str(&line);
+                stdout.push('\n');
+            }
+            ExecEvent::Stderr(line) => {
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+            ExecEvent::Exit { success: true, .. } => return Ok(stdout),
+            ExecEvent::Exit { message, .. } => bail!(
+                "wp {} failed on {}: {}",
+                redact_wp_cli_args(args).join(" "),
+                site,
+                match stderr.trim() {
+                    "" => message.unwrap_or_default(),
+                    stderr => stderr.to_string(),
+                }
+            ),
+        }
+    }
+    bail!(
+        "wp {} on {} ended without exiting",
+        redact_wp_cli_args(args).join(" "),
+        site
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wp_cli_job_passes_args_without_shell() {
+        let job = wp_cli_job("blog", &["plugin", "update", "akismet; rm -rf /"]).unwrap();
+        let container = &job.spec.unwrap().template.spec.unwrap().containers[0];
+
+        assert_eq!(container.command.as_deref(), Some(&["wp".to_string()][..]));
+        assert_eq!(
+            container.args.as_deref().unwrap(),
+            ["plugin", "update", "akismet; rm -rf /"]
+        );
+    }
+
+    #[test]
+    fn test_wp_cli_command() {
+        let args = vec![
+            "user".to_string(),
+            "update".to_string(),
+            "admin".to_string(),
+            "--user_pass=hunter2".to_string(),
+            "--skip-email".to_string(),
+        ];
+        assert_eq!(
+            wp_cli_command(&args)[..4],
+            [WP_CLI_PATH, "--allow-root", "--path=/var/www/html", "user"]
+        );
+        assert_eq!(
+            redact_wp_cli_args(&args),
+            ["user", "update", "admin", "--user_pass=***", "--skip-email"]
+        );
+        assert_eq!(
+            redact_wp_cli_args(&["--API_KEY=abc".to_string()]),
+            ["--API_KEY=***"]
+        );
+    }
+
+    #[test]
+    fn test_attach_wp_cli() {
+        let mut deployment: k8s_openapi::api::apps::v1::Deployment = serde_yaml::from_str(
+            include_str!("../../kubernetes/wordpress/wp-deployment.yaml"),
+        )
+        .unwrap();
+        let pod_spec = crate::manifest::deployment_pod_spec_mut(&mut deployment).unwrap();
+        attach_wp_cli(pod_spec).unwrap();
+        attach_wp_cli(pod_spec).unwrap();
+
+        let init_containers = pod_spec.init_containers.as_ref().unwrap();
+        assert_eq!(init_containers.len(), 1);
+        assert_eq!(
+            init_containers[0].command.as_ref().unwrap().last().unwrap(),
+            WP_CLI_PATH
+        );
+        let mounts = pod_spec.containers[0].volume_mounts.as_ref().unwrap();
+        assert_eq!(
+            mounts
+                .iter()
+                .filter(|m| m.name == WP_CLI_VOLUME_NAME)
+                .count(),
+            1
+        );
+    }
+}